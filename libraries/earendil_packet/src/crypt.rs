@@ -62,18 +62,28 @@ impl FromStr for DhPublic {
 
 /// A diffie-hellman secret key, based on x25519.
 ///
-/// This is *intentionally* not serializable, and we *intentionally* never expose the underlying bytes representation. This is to ensure we only use them as in-memory ephemeral or mid-term keys.
+/// This is *intentionally* not serializable. This is to ensure we only use them as in-memory ephemeral or mid-term keys. The underlying bytes are only exposed through [DhSecret::to_bytes], for keys that must outlive a process inside something encrypted.
 #[derive(Clone)]
-pub struct DhSecret(x25519_dalek::ReusableSecret);
+pub struct DhSecret(x25519_dalek::StaticSecret);
 
 impl DhSecret {
     /// Generates a secret key.
     pub fn generate() -> Self {
-        Self(x25519_dalek::ReusableSecret::random_from_rng(
+        Self(x25519_dalek::StaticSecret::random_from_rng(
             rand::thread_rng(),
         ))
     }
 
+    /// Returns the raw bytes of this secret key. These must never be stored or sent anywhere without being encrypted first.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    /// Restores a secret key from the bytes [DhSecret::to_bytes] returned.
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        Self(x25519_dalek::StaticSecret::from(*bytes))
+    }
+
     /// Returns the public key of this secret key.
     pub fn public(&self) -> DhPublic {
         DhPublic((&self.0).into())
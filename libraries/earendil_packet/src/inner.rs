@@ -56,6 +56,9 @@ pub enum EncodeError {
 
 const SOURCE_LENGTH: usize = 33;
 
+/// How many bytes the length prefix of a message body takes, once the body is at least 251 bytes long. Bincode's varints take just one byte below that.
+const LONG_LENGTH_PREFIX: usize = 3;
+
 impl InnerPacket {
     /// From a raw payload, deduce the inner packet as well as the source id.
     pub fn decode(raw: &RawBody) -> Result<(Self, RemoteId), DecodeError> {
@@ -103,6 +106,18 @@ impl Message {
     pub fn new(relay_dock: Dock, body: Bytes) -> Self {
         Message { relay_dock, body }
     }
+
+    /// The biggest body a message with the given dock fits in one packet. This is the same for every route length, since onion headers are fixed-size, but bigger docks take more bytes to encode.
+    pub fn max_body_len(relay_dock: Dock) -> usize {
+        let empty = bincode::DefaultOptions::new()
+            .serialized_size(&InnerPacket::Message(Message::new(
+                relay_dock,
+                Bytes::new(),
+            )))
+            .expect("messages always serialize") as usize;
+        // the empty body's length prefix took one byte, but a full one's takes more
+        RAW_BODY_SIZE - SOURCE_LENGTH - (empty - 1) - LONG_LENGTH_PREFIX
+    }
 }
 
 #[cfg(test)]
@@ -136,4 +151,17 @@ mod tests {
             "Decrypted packet does not match the original one"
         );
     }
+
+    #[test]
+    fn max_body_len_is_exact() {
+        let my_id = RemoteId::Anon(AnonEndpoint::random());
+        for dock in [0, 250, 251, 65535, 65536, Dock::MAX] {
+            let max = Message::max_body_len(dock);
+            let message =
+                |len: usize| InnerPacket::Message(Message::new(dock, Bytes::from(vec![0u8; len])));
+            let encoded = message(max).encode(&my_id).unwrap();
+            assert_eq!(InnerPacket::decode(&encoded).unwrap().0, message(max));
+            assert!(message(max + 1).encode(&my_id).is_err(), "dock {dock}");
+        }
+    }
 }
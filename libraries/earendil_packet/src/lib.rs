@@ -57,6 +57,7 @@ mod tests {
             InnerPacket::Message(msg.clone()),
             &[0; 32],
             RemoteId::Relay(my_isk.public().fingerprint()),
+            LOW_LATENCY_MS,
         )?;
 
         let mut peeled_packet = packet;
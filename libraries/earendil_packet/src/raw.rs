@@ -15,8 +15,10 @@ use crate::{
 };
 
 pub const RAW_BODY_SIZE: usize = 20000;
-const MAX_HOPS: usize = 10;
-const LOW_LATENCY_MS: u16 = 15;
+/// Routes must pass through fewer relays than this, counting the destination.
+pub const MAX_HOPS: usize = 10;
+/// The mean of the random delay each relay holds a packet for before passing it on, unless the sender picks another.
+pub const LOW_LATENCY_MS: u16 = 15;
 const METADATA_BUFFER_SIZE: usize = 35;
 const FORWARD_TO_CLIENT_FLAG: u8 = 2;
 const FORWARD_TO_RELAY_FLAG: u8 = 1;
@@ -64,6 +66,9 @@ pub enum PacketPeelError {
 }
 
 fn sample_delay(avg: u16) -> u16 {
+    if avg == 0 {
+        return 0;
+    }
     let exp = Exp::new(1.0 / avg as f64).expect("avg must be greater than zero");
     let mut rng = rand::thread_rng();
     rng.sample(exp) as u16
@@ -76,7 +81,26 @@ impl RawPacket {
         payload: InnerPacket,
         my_id: RemoteId,
     ) -> Result<Self, PacketConstructError> {
-        let (raw, _) = Self::new(route, dest_opk, false, payload, &[0; 32], my_id)?;
+        Self::new_normal_with_delay(route, dest_opk, payload, my_id, LOW_LATENCY_MS)
+    }
+
+    /// Like [RawPacket::new_normal], but each relay along the route holds the packet for a random delay averaging `mean_delay_ms`, rather than [LOW_LATENCY_MS].
+    pub fn new_normal_with_delay(
+        route: &[ForwardInstruction],
+        dest_opk: &DhPublic,
+        payload: InnerPacket,
+        my_id: RemoteId,
+        mean_delay_ms: u16,
+    ) -> Result<Self, PacketConstructError> {
+        let (raw, _) = Self::new(
+            route,
+            dest_opk,
+            false,
+            payload,
+            &[0; 32],
+            my_id,
+            mean_delay_ms,
+        )?;
         Ok(raw)
     }
 
@@ -106,12 +130,13 @@ impl RawPacket {
         payload: InnerPacket,
         metadata: &[u8; 32],
         my_id: RemoteId,
+        mean_delay_ms: u16,
     ) -> Result<(Self, Vec<[u8; 32]>), PacketConstructError> {
         if route.len() >= MAX_HOPS {
             return Err(PacketConstructError::TooManyHops);
         }
 
-        let delay = sample_delay(LOW_LATENCY_MS);
+        let delay = sample_delay(mean_delay_ms);
 
         // Use a recursive algorithm. Base case: the route is empty
         if route.is_empty() {
@@ -177,6 +202,7 @@ impl RawPacket {
                 payload,
                 metadata,
                 my_id,
+                mean_delay_ms,
             )?;
 
             buffer[33..].copy_from_slice(&delay.to_be_bytes());
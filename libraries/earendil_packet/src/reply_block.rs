@@ -7,6 +7,7 @@ use serde::{Deserialize, Serialize};
 use crate::{
     crypt::{stream_dencrypt, DhPublic},
     ForwardInstruction, InnerPacket, Message, PacketConstructError, RawBody, RawHeader, RawPacket,
+    LOW_LATENCY_MS,
 };
 
 /// A reply block. Reply blocks are constructed by endpoints who wish other endpoints to talk to them via an anonymous address, and are single-use, consumed when used to construct a packet going to that anonymous address.
@@ -41,6 +42,7 @@ impl ReplyBlock {
             }),
             &metadata,
             RemoteId::Anon(my_anon_id),
+            LOW_LATENCY_MS,
         )?;
         let header = raw_packet.header;
         let stream_key = rand::thread_rng().gen();
@@ -61,7 +63,8 @@ impl ReplyBlock {
     }
 }
 
-#[derive(Clone)]
+/// What the creator of a reply block keeps, to read replies sent with it. Serializable so that reply blocks handed out ahead of time can outlive a restart.
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ReplyDegarbler {
     shared_secs: Vec<[u8; 32]>,
     my_anon_id: AnonEndpoint,
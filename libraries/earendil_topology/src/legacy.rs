@@ -0,0 +1,104 @@
+//! How relay graphs were laid out when persisted by older versions, so that they can still be read back.
+
+use std::collections::{HashMap, HashSet};
+
+use bytes::Bytes;
+use earendil_crypt::{RelayFingerprint, RelayIdentityPublic};
+use earendil_packet::crypt::DhPublic;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{AdjacencyDescriptor, DescriptorExtensions, IdentityDescriptor, RelayGraph};
+
+/// A relay graph as laid out with descriptors of type `D`.
+#[derive(Deserialize)]
+pub struct RelayGraphLayout<D> {
+    unalloc_id: u64,
+    fp_to_id: HashMap<RelayFingerprint, u64>,
+    id_to_fp: HashMap<u64, RelayFingerprint>,
+    id_to_descriptor: HashMap<u64, D>,
+    adjacency: HashMap<u64, HashSet<u64>>,
+    documents: IndexMap<(u64, u64), AdjacencyDescriptor>,
+}
+
+/// A relay graph from before descriptors had extensions.
+pub type RelayGraphV0 = RelayGraphLayout<IdentityDescriptorV0>;
+
+/// A relay graph from when the overload hint was a field of its own.
+pub type RelayGraphV1 = RelayGraphLayout<IdentityDescriptorV1>;
+
+/// An identity descriptor from before descriptors had extensions. Still what goes over the wire where descriptors are laid out by position, so that relays from before extensions can read it.
+#[derive(Serialize, Deserialize)]
+pub struct IdentityDescriptorV0 {
+    identity_pk: RelayIdentityPublic,
+    onion_pk: DhPublic,
+    sig: Bytes,
+    unix_timestamp: u64,
+}
+
+/// An identity descriptor from when the overload hint was a field of its own, signed along with the rest. Nothing in it is used, since its signature no longer checks out.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+pub struct IdentityDescriptorV1 {
+    identity_pk: RelayIdentityPublic,
+    onion_pk: DhPublic,
+    sig: Bytes,
+    unix_timestamp: u64,
+    overloaded: bool,
+}
+
+impl<D> RelayGraphLayout<D> {
+    fn into_graph(self, upgrade: impl Fn(D) -> Option<IdentityDescriptor>) -> RelayGraph {
+        RelayGraph {
+            unalloc_id: self.unalloc_id,
+            fp_to_id: self.fp_to_id,
+            id_to_fp: self.id_to_fp,
+            id_to_descriptor: self
+                .id_to_descriptor
+                .into_iter()
+                .filter_map(|(id, descr)| Some((id, upgrade(descr)?)))
+                .collect(),
+            adjacency: self.adjacency,
+            documents: self.documents,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<IdentityDescriptorV0> for IdentityDescriptor {
+    fn from(descr: IdentityDescriptorV0) -> Self {
+        IdentityDescriptor {
+            identity_pk: descr.identity_pk,
+            onion_pk: descr.onion_pk,
+            sig: descr.sig,
+            unix_timestamp: descr.unix_timestamp,
+            extensions: DescriptorExtensions::default(),
+        }
+    }
+}
+
+impl From<IdentityDescriptor> for IdentityDescriptorV0 {
+    /// Drops the extensions, which the signature doesn't cover.
+    fn from(descr: IdentityDescriptor) -> Self {
+        IdentityDescriptorV0 {
+            identity_pk: descr.identity_pk,
+            onion_pk: descr.onion_pk,
+            sig: descr.sig,
+            unix_timestamp: descr.unix_timestamp,
+        }
+    }
+}
+
+impl From<RelayGraphV0> for RelayGraph {
+    /// Descriptors are carried over as they are, since their signatures cover the same fields as now.
+    fn from(graph: RelayGraphV0) -> Self {
+        graph.into_graph(|descr| Some(descr.into()))
+    }
+}
+
+impl From<RelayGraphV1> for RelayGraph {
+    /// Descriptors are dropped, since their signatures covered the overload hint and so no longer check out. Gossip fetches them again, and the adjacencies are kept meanwhile.
+    fn from(graph: RelayGraphV1) -> Self {
+        graph.into_graph(|_| None)
+    }
+}
@@ -1,16 +1,35 @@
+pub mod legacy;
+mod limits;
+mod partition;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use bytes::Bytes;
-use earendil_crypt::{RelayFingerprint, RelayIdentityPublic, RelayIdentitySecret, VerifyError};
+use earendil_crypt::{
+    HavenFingerprint, RelayFingerprint, RelayIdentityPublic, RelayIdentitySecret, VerifyError,
+};
 use earendil_packet::crypt::{DhPublic, DhSecret};
 use indexmap::IndexMap;
 use rand::{seq::IteratorRandom, Rng};
 use serde::{Deserialize, Serialize};
 use stdcode::StdcodeSerializeExt;
 
+use limits::EvictionCounters;
+pub use limits::{GraphLimits, GraphStats};
+pub use partition::Partition;
+
+/// How long, in seconds, descriptors stay in the graph without being refreshed.
+pub const ROUTE_TIMEOUT: u64 = 60 * 60;
+
+/// How old, in seconds, an identity descriptor may get before its onion key counts as stale.
+pub const DESCRIPTOR_STALE_AFTER: u64 = ROUTE_TIMEOUT / 2;
+
 /// A full, indexed representation of the Earendil relay graph. Includes info about:
 /// - Which fingerprints are adjacent to which fingerprints
 /// - What signing keys and midterm keys do each fingerprint have
@@ -22,6 +41,22 @@ pub struct RelayGraph {
     id_to_descriptor: HashMap<u64, IdentityDescriptor>,
     adjacency: HashMap<u64, HashSet<u64>>,
     documents: IndexMap<(u64, u64), AdjacencyDescriptor>,
+
+    /// Bumped whenever relays or adjacencies come or go, so that anything derived from the graph's shape knows when to recompute.
+    #[serde(skip)]
+    generation: u64,
+    #[serde(skip)]
+    limits: GraphLimits,
+    /// Adjacencies older than this many seconds stay in the graph, but routes don't go over them.
+    #[serde(skip)]
+    max_routing_age: Option<u64>,
+    #[serde(skip)]
+    anchors: HashMap<RelayFingerprint, usize>,
+    /// Behind a lock of its own, so that routes can be marked as used under a read lock on the whole graph.
+    #[serde(skip)]
+    recently_used: Mutex<HashMap<RelayFingerprint, Instant>>,
+    #[serde(skip)]
+    counters: EvictionCounters,
 }
 
 // Update the AdjacencyError enum with more specific cases
@@ -46,6 +81,11 @@ impl RelayGraph {
         Self::default()
     }
 
+    /// Changes whenever relays or adjacencies are added to or removed from the graph, but not when existing ones are merely refreshed.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     /// Looks up the identity descriptor of a fingerprint.
     pub fn identity(&self, fingerprint: &RelayFingerprint) -> Option<IdentityDescriptor> {
         tracing::trace!(
@@ -65,11 +105,18 @@ impl RelayGraph {
             "inserting an identity into relay graph"
         );
 
-        identity
-            .identity_pk
-            .verify(identity.to_sign().as_bytes(), &identity.sig)?;
+        identity.verify()?;
+        if !self.admit_identity(&identity) {
+            tracing::trace!(
+                identity = debug(identity.identity_pk.fingerprint()),
+                "relay graph full, skipping identity"
+            );
+            return Ok(());
+        }
         let id = self.alloc_id(&identity.identity_pk.fingerprint());
-        self.id_to_descriptor.insert(id, identity);
+        if self.id_to_descriptor.insert(id, identity).is_none() {
+            self.generation += 1;
+        }
         Ok(())
     }
 
@@ -80,6 +127,14 @@ impl RelayGraph {
         adjacency: AdjacencyDescriptor,
     ) -> Result<(), AdjacencyError> {
         self.verify_adjacency(&adjacency)?;
+        if !self.admit_adjacency(&adjacency) {
+            tracing::trace!(
+                left = debug(adjacency.left),
+                right = debug(adjacency.right),
+                "relay graph full, skipping adjacency"
+            );
+            return Ok(());
+        }
 
         let left_fp = &adjacency.left;
         let right_fp = &adjacency.right;
@@ -88,7 +143,9 @@ impl RelayGraph {
 
         self.documents.insert((left_id, right_id), adjacency);
 
-        self.adjacency.entry(left_id).or_default().insert(right_id);
+        if self.adjacency.entry(left_id).or_default().insert(right_id) {
+            self.generation += 1;
+        }
         self.adjacency.entry(right_id).or_default().insert(left_id);
 
         self.cleanup();
@@ -163,8 +220,29 @@ impl RelayGraph {
             .choose_multiple(&mut rand::thread_rng(), num)
     }
 
+    /// Makes routes avoid adjacencies older than `max_age` seconds, preferring fresher ones even if they make the route longer. Unlike [ROUTE_TIMEOUT], this doesn't remove anything from the graph.
+    pub fn set_max_routing_age(&mut self, max_age: Option<u64>) {
+        self.max_routing_age = max_age;
+    }
+
+    /// Whether routes may go over the adjacency between two nodes.
+    fn routable(&self, a: u64, b: u64, now: u64) -> bool {
+        let Some(max_age) = self.max_routing_age else {
+            return true;
+        };
+        self.fresh(a, b, max_age, now)
+    }
+
+    /// Whether the adjacency between two nodes was refreshed within `max_age` seconds of `now`.
+    fn fresh(&self, a: u64, b: u64, max_age: u64, now: u64) -> bool {
+        self.documents
+            .get(&(a, b))
+            .or_else(|| self.documents.get(&(b, a)))
+            .is_some_and(|adj| now.saturating_sub(adj.unix_timestamp) <= max_age)
+    }
+
     /// Returns a Vec of Fingerprint instances representing the shortest path or None if no path exists.
-    /// bypassing any Fingerprint in the blacklist.
+    /// Adjacencies older than the maximum routing age, if one is set, are not used.
     pub fn find_shortest_path(
         &self,
         start_fp: &RelayFingerprint,
@@ -177,6 +255,11 @@ impl RelayGraph {
         let mut queue = VecDeque::new();
         let mut path = HashMap::new();
 
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+
         visited.insert(start_id);
         queue.push_back(start_id);
 
@@ -198,7 +281,9 @@ impl RelayGraph {
 
             if let Some(neighbors) = self.adjacency.get(&current_id) {
                 for neighbor_id in neighbors.iter() {
-                    if !visited.contains(neighbor_id) {
+                    if !visited.contains(neighbor_id)
+                        && self.routable(current_id, *neighbor_id, now)
+                    {
                         visited.insert(*neighbor_id);
                         path.insert(*neighbor_id, current_id);
                         queue.push_back(*neighbor_id);
@@ -212,7 +297,6 @@ impl RelayGraph {
 
     // removes all information more than ROUTE_TIMEOUT ago
     fn cleanup(&mut self) {
-        const ROUTE_TIMEOUT: u64 = 60 * 60; // e.g., 1 hour in seconds
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
@@ -252,6 +336,9 @@ impl RelayGraph {
             })
             .collect();
 
+        if !outdated_identities.is_empty() || !outdated_documents.is_empty() {
+            self.generation += 1;
+        }
         for (left_id, right_id) in outdated_documents {
             self.documents.remove(&(left_id, right_id));
             if let Some(neighbors) = self.adjacency.get_mut(&left_id) {
@@ -264,6 +351,8 @@ impl RelayGraph {
 
         // Cleanup adjacency entries for nodes that have no neighbors left
         self.adjacency.retain(|_, neighbors| !neighbors.is_empty());
+
+        self.enforce_limits();
     }
 
     fn alloc_id(&mut self, fp: &RelayFingerprint) -> u64 {
@@ -347,11 +436,41 @@ pub struct IdentityDescriptor {
     pub sig: Bytes,
 
     pub unix_timestamp: u64,
+
+    /// Signed additions to the descriptor. Relays from before they existed ignore them.
+    #[serde(default)]
+    pub extensions: DescriptorExtensions,
+}
+
+/// The name of the extension saying that the relay is overloaded, and would rather not be picked for new routes. Routes through it still work.
+pub const OVERLOADED_EXTENSION: &str = "overloaded";
+
+/// The name of the extension holding the key that sealed GlobalRpc requests to the relay are sealed to.
+pub const SEALED_SERVICE_EXTENSION: &str = "sealed_service_pk";
+
+/// The name of the extension holding the fingerprint of the haven that takes the relay's messages when no route reaches it.
+pub const FALLBACK_HAVEN_EXTENSION: &str = "fallback_haven";
+
+/// Additions to an identity descriptor, by name, signed apart from the rest of it. Relays that don't know of them verify the descriptor without them and drop them when passing it on, so nothing in here may be needed to use the relay at all.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DescriptorExtensions {
+    pub fields: BTreeMap<String, Bytes>,
+    /// Signs the fields along with the rest of the descriptor. Empty when there are no fields.
+    pub sig: Bytes,
 }
 
 impl IdentityDescriptor {
     /// Creates an IdentityDescriptor from our own IdentitySecret
     pub fn new(my_identity: &RelayIdentitySecret, my_onion: &DhSecret) -> Self {
+        Self::new_with_extensions(my_identity, my_onion, BTreeMap::new())
+    }
+
+    /// Like [IdentityDescriptor::new], but also carries the given extensions.
+    pub fn new_with_extensions(
+        my_identity: &RelayIdentitySecret,
+        my_onion: &DhSecret,
+        extensions: BTreeMap<String, Bytes>,
+    ) -> Self {
         let identity_pk = my_identity.public();
         let onion_pk = my_onion.public();
         let mut descr = IdentityDescriptor {
@@ -362,15 +481,146 @@ impl IdentityDescriptor {
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
+            extensions: DescriptorExtensions::default(),
         };
         descr.sig = my_identity.sign(descr.to_sign().as_bytes());
+        if !extensions.is_empty() {
+            descr.extensions.fields = extensions;
+            descr.extensions.sig = my_identity.sign(descr.extensions_to_sign().as_bytes());
+        }
         descr
     }
 
-    /// The value that the signatures are supposed to be computed against.
+    /// Checks that the owner of the identity signed the descriptor, extensions included.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        self.identity_pk
+            .verify(self.to_sign().as_bytes(), &self.sig)?;
+        if !self.extensions.fields.is_empty() {
+            self.identity_pk
+                .verify(self.extensions_to_sign().as_bytes(), &self.extensions.sig)?;
+        }
+        Ok(())
+    }
+
+    /// Looks up an extension by name.
+    pub fn extension(&self, name: &str) -> Option<&Bytes> {
+        self.extensions.fields.get(name)
+    }
+
+    /// Whether the relay says it's overloaded.
+    pub fn overloaded(&self) -> bool {
+        self.extension(OVERLOADED_EXTENSION)
+            .is_some_and(|value| value[..] == [1])
+    }
+
+    /// The key that sealed GlobalRpc requests to the relay are sealed to, if it publishes one.
+    pub fn sealed_service_pk(&self) -> Option<DhPublic> {
+        let bytes: &[u8; 32] = self
+            .extension(SEALED_SERVICE_EXTENSION)?
+            .as_ref()
+            .try_into()
+            .ok()?;
+        Some(DhPublic::from_bytes(bytes))
+    }
+
+    /// The haven that takes the relay's messages when no route reaches it, if it serves one.
+    pub fn fallback_haven(&self) -> Option<HavenFingerprint> {
+        let bytes: &[u8; 20] = self
+            .extension(FALLBACK_HAVEN_EXTENSION)?
+            .as_ref()
+            .try_into()
+            .ok()?;
+        Some(HavenFingerprint::from_bytes(bytes))
+    }
+
+    /// Whether the descriptor is older than [DESCRIPTOR_STALE_AFTER].
+    pub fn is_stale(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        now.saturating_sub(self.unix_timestamp) > DESCRIPTOR_STALE_AFTER
+    }
+
+    /// The value that the signatures are supposed to be computed against. Only covers the fields descriptors had before extensions, laid out as they were, so that relays from back then can still verify it.
     pub fn to_sign(&self) -> blake3::Hash {
-        let mut this = self.clone();
-        this.sig = Bytes::new();
-        blake3::keyed_hash(b"identity_descriptor_____________", &this.stdcode())
+        let unsigned = (
+            &self.identity_pk,
+            &self.onion_pk,
+            Bytes::new(),
+            self.unix_timestamp,
+        );
+        blake3::keyed_hash(b"identity_descriptor_____________", &unsigned.stdcode())
+    }
+
+    /// The value that the extension signature is computed against, which ties the extensions to the rest of the descriptor.
+    pub fn extensions_to_sign(&self) -> blake3::Hash {
+        blake3::keyed_hash(
+            b"identity_descriptor_extensions__",
+            &(self.to_sign().as_bytes(), &self.extensions.fields).stdcode(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{adjacency, known_relay, now_secs};
+
+    #[test]
+    fn routes_avoid_stale_adjacencies() {
+        let mut graph = RelayGraph::new();
+        let relays: Vec<_> = (0..4).map(|_| known_relay(&mut graph)).collect();
+        let fps: Vec<_> = relays.iter().map(|r| r.public().fingerprint()).collect();
+        let now = now_secs();
+        // a stale shortcut from the first relay to the last, and a fresh detour through the others
+        graph
+            .insert_adjacency(adjacency(&relays[0], &relays[3], now - 1200))
+            .unwrap();
+        for pair in relays.windows(2) {
+            graph
+                .insert_adjacency(adjacency(&pair[0], &pair[1], now))
+                .unwrap();
+        }
+
+        assert_eq!(
+            graph.find_shortest_path(&fps[0], &fps[3]).unwrap(),
+            vec![fps[0], fps[3]]
+        );
+        graph.set_max_routing_age(Some(600));
+        assert_eq!(graph.find_shortest_path(&fps[0], &fps[3]).unwrap(), fps);
+        // the stale adjacency is still in the graph, just not routed over
+        assert_eq!(graph.all_adjacencies().count(), 4);
+    }
+
+    #[test]
+    fn extensions_are_signed_apart_from_the_rest() {
+        let relay = RelayIdentitySecret::generate();
+        let descr = IdentityDescriptor::new_with_extensions(
+            &relay,
+            &DhSecret::generate(),
+            [(OVERLOADED_EXTENSION.to_string(), Bytes::from_static(&[1]))].into(),
+        );
+        descr.verify().unwrap();
+        assert!(descr.overloaded());
+
+        // relays from before extensions see the descriptor without them, and it still verifies for them
+        let before: legacy::IdentityDescriptorV0 =
+            stdcode::deserialize(&legacy::IdentityDescriptorV0::from(descr.clone()).stdcode())
+                .unwrap();
+        let before = IdentityDescriptor::from(before);
+        before.verify().unwrap();
+        assert!(!before.overloaded());
+
+        // but the extensions can't be changed, or grafted onto another descriptor
+        let mut tampered = descr.clone();
+        tampered
+            .extensions
+            .fields
+            .insert(OVERLOADED_EXTENSION.to_string(), Bytes::from_static(&[0]));
+        assert!(tampered.verify().is_err());
+        let mut grafted = IdentityDescriptor::new(&relay, &DhSecret::generate());
+        grafted.extensions = descr.extensions.clone();
+        assert!(grafted.verify().is_err());
     }
 }
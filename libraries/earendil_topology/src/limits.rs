@@ -0,0 +1,443 @@
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    sync::PoisonError,
+    time::{Duration, Instant},
+};
+
+use earendil_crypt::RelayFingerprint;
+use serde::{Deserialize, Serialize};
+
+use crate::{AdjacencyDescriptor, IdentityDescriptor, RelayGraph};
+
+/// How long a node stays protected from eviction after it was last used in a route.
+const RECENTLY_USED: Duration = Duration::from_secs(600);
+
+/// Caps on the size of a [RelayGraph]. Once a cap is reached, new entries only get in by displacing less useful ones.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct GraphLimits {
+    pub max_nodes: usize,
+    pub max_edges: usize,
+}
+
+impl Default for GraphLimits {
+    fn default() -> Self {
+        Self {
+            max_nodes: usize::MAX,
+            max_edges: usize::MAX,
+        }
+    }
+}
+
+/// Occupancy of a [RelayGraph], and the eviction decisions it has taken so far.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GraphStats {
+    pub nodes: usize,
+    pub edges: usize,
+    pub limits: GraphLimits,
+    pub protected_nodes: usize,
+    pub evicted_nodes: u64,
+    pub evicted_edges: u64,
+    pub skipped_inserts: u64,
+    /// How old the identity descriptor of the relay asking for the stats is, in seconds. The graph doesn't know whose it is, so it's up to the caller to fill in.
+    #[serde(default)]
+    pub own_descriptor_age_secs: Option<u64>,
+}
+
+#[derive(Default)]
+pub(crate) struct EvictionCounters {
+    evicted_nodes: u64,
+    evicted_edges: u64,
+    skipped_inserts: u64,
+}
+
+/// How eagerly an entry should be evicted: the farthest from our anchors first, then the least recently refreshed.
+type Score = (usize, Reverse<u64>);
+
+impl RelayGraph {
+    /// Sets the size caps, immediately evicting entries if the graph is now too big.
+    pub fn set_limits(&mut self, limits: GraphLimits) {
+        self.limits = limits;
+        self.enforce_limits();
+    }
+
+    /// Marks a node, such as ourselves or a direct neighbor, as one that is never evicted. Eviction prefers nodes far away from anchors.
+    ///
+    /// Anchors are reference-counted: every call must eventually be matched by a call to [RelayGraph::unanchor].
+    pub fn anchor(&mut self, fp: RelayFingerprint) {
+        *self.anchors.entry(fp).or_default() += 1;
+    }
+
+    /// Undoes one call to [RelayGraph::anchor].
+    pub fn unanchor(&mut self, fp: &RelayFingerprint) {
+        if let Entry::Occupied(mut entry) = self.anchors.entry(*fp) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Protects the nodes of a route from eviction for a while, so that routes in active use keep working however small the graph is. Only needs a shared reference, so that sending doesn't contend for the graph.
+    pub fn mark_used(&self, route: impl IntoIterator<Item = RelayFingerprint>) {
+        let now = Instant::now();
+        let mut recently_used = self
+            .recently_used
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for fp in route {
+            recently_used.insert(fp, now);
+        }
+    }
+
+    /// Returns the current occupancy and eviction counters.
+    pub fn graph_stats(&self) -> GraphStats {
+        GraphStats {
+            nodes: self.id_to_descriptor.len(),
+            edges: self.documents.len(),
+            limits: self.limits,
+            protected_nodes: self
+                .id_to_descriptor
+                .keys()
+                .filter(|id| self.id_protected(**id))
+                .count(),
+            evicted_nodes: self.counters.evicted_nodes,
+            evicted_edges: self.counters.evicted_edges,
+            skipped_inserts: self.counters.skipped_inserts,
+            own_descriptor_age_secs: None,
+        }
+    }
+
+    /// Decides whether a verified identity gets into the graph, evicting the worst resident to make room if needed. Returns false if the identity should be skipped.
+    pub(crate) fn admit_identity(&mut self, identity: &IdentityDescriptor) -> bool {
+        let fp = identity.identity_pk.fingerprint();
+        let known = self
+            .id(&fp)
+            .is_some_and(|id| self.id_to_descriptor.contains_key(&id));
+        if known || self.is_protected(&fp) || self.id_to_descriptor.len() < self.limits.max_nodes {
+            return true;
+        }
+        // a brand new node isn't connected to anything yet
+        let score = (usize::MAX, Reverse(identity.unix_timestamp));
+        let distances = self.anchor_distances();
+        match self.worst_node(&distances) {
+            Some((worst, worst_score)) if worst_score > score => {
+                self.remove_node(worst);
+                self.counters.evicted_nodes += 1;
+                true
+            }
+            _ => {
+                self.counters.skipped_inserts += 1;
+                false
+            }
+        }
+    }
+
+    /// Decides whether a verified adjacency gets into the graph, evicting the worst resident to make room if needed. Returns false if the adjacency should be skipped.
+    pub(crate) fn admit_adjacency(&mut self, adjacency: &AdjacencyDescriptor) -> bool {
+        let (Some(left_id), Some(right_id)) = (self.id(&adjacency.left), self.id(&adjacency.right))
+        else {
+            return true;
+        };
+        if self.documents.contains_key(&(left_id, right_id))
+            || (self.id_protected(left_id) && self.id_protected(right_id))
+            || self.documents.len() < self.limits.max_edges
+        {
+            return true;
+        }
+        let distances = self.anchor_distances();
+        let left_dist = distances.get(&left_id).copied().unwrap_or(usize::MAX);
+        let right_dist = distances.get(&right_id).copied().unwrap_or(usize::MAX);
+        // the new edge would bring the farther endpoint within one hop of the nearer one
+        let dist = left_dist
+            .max(right_dist)
+            .min(left_dist.min(right_dist).saturating_add(1));
+        let score = (dist, Reverse(adjacency.unix_timestamp));
+        match self.worst_edge(&distances) {
+            Some((worst, worst_score)) if worst_score > score => {
+                self.remove_edge(worst);
+                self.counters.evicted_edges += 1;
+                true
+            }
+            _ => {
+                self.counters.skipped_inserts += 1;
+                false
+            }
+        }
+    }
+
+    /// Evicts the least useful entries until the graph fits within its limits, or until only protected entries remain.
+    pub(crate) fn enforce_limits(&mut self) {
+        self.recently_used
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, used| used.elapsed() < RECENTLY_USED);
+
+        // evicting only ever makes distances longer, so computing them once is conservative enough
+        let distances = self.anchor_distances();
+        while self.id_to_descriptor.len() > self.limits.max_nodes {
+            let Some((worst, _)) = self.worst_node(&distances) else {
+                break;
+            };
+            self.remove_node(worst);
+            self.counters.evicted_nodes += 1;
+        }
+        while self.documents.len() > self.limits.max_edges {
+            let Some((worst, _)) = self.worst_edge(&distances) else {
+                break;
+            };
+            self.remove_edge(worst);
+            self.counters.evicted_edges += 1;
+        }
+    }
+
+    fn is_protected(&self, fp: &RelayFingerprint) -> bool {
+        self.anchors.contains_key(fp)
+            || self
+                .recently_used
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(fp)
+                .is_some_and(|used| used.elapsed() < RECENTLY_USED)
+    }
+
+    fn id_protected(&self, id: u64) -> bool {
+        self.id_to_fp
+            .get(&id)
+            .is_some_and(|fp| self.is_protected(fp))
+    }
+
+    /// Hop distances from the nearest anchor. Nodes that no anchor can reach are absent.
+    fn anchor_distances(&self) -> HashMap<u64, usize> {
+        let mut distances = HashMap::new();
+        let mut queue = VecDeque::new();
+        for id in self.anchors.keys().filter_map(|fp| self.id(fp)) {
+            distances.insert(id, 0);
+            queue.push_back(id);
+        }
+        while let Some(current) = queue.pop_front() {
+            let dist = distances[&current];
+            for &neigh in self.adjacency.get(&current).into_iter().flatten() {
+                if let Entry::Vacant(entry) = distances.entry(neigh) {
+                    entry.insert(dist + 1);
+                    queue.push_back(neigh);
+                }
+            }
+        }
+        distances
+    }
+
+    fn worst_node(&self, distances: &HashMap<u64, usize>) -> Option<(u64, Score)> {
+        self.id_to_descriptor
+            .iter()
+            .filter(|(id, _)| !self.id_protected(**id))
+            .map(|(&id, descr)| {
+                let freshness = self
+                    .adjacency
+                    .get(&id)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|&neigh| {
+                        self.documents
+                            .get(&(id, neigh))
+                            .or_else(|| self.documents.get(&(neigh, id)))
+                    })
+                    .map(|adj| adj.unix_timestamp)
+                    .max()
+                    .unwrap_or(descr.unix_timestamp);
+                let dist = distances.get(&id).copied().unwrap_or(usize::MAX);
+                (id, (dist, Reverse(freshness)))
+            })
+            .max_by_key(|(_, score)| *score)
+    }
+
+    fn worst_edge(&self, distances: &HashMap<u64, usize>) -> Option<((u64, u64), Score)> {
+        self.documents
+            .iter()
+            .filter(|((left, right), _)| !(self.id_protected(*left) && self.id_protected(*right)))
+            .map(|(&(left, right), adj)| {
+                let dist = distances
+                    .get(&left)
+                    .copied()
+                    .unwrap_or(usize::MAX)
+                    .max(distances.get(&right).copied().unwrap_or(usize::MAX));
+                ((left, right), (dist, Reverse(adj.unix_timestamp)))
+            })
+            .max_by_key(|(_, score)| *score)
+    }
+
+    fn remove_node(&mut self, id: u64) {
+        tracing::debug!(
+            fp = debug(self.id_to_fp.get(&id)),
+            "evicting node from relay graph"
+        );
+        self.generation += 1;
+        let neighbors = self.adjacency.get(&id).cloned().unwrap_or_default();
+        for neigh in neighbors {
+            self.remove_edge((id, neigh));
+            self.remove_edge((neigh, id));
+        }
+        self.id_to_descriptor.remove(&id);
+        if let Some(fp) = self.id_to_fp.remove(&id) {
+            self.fp_to_id.remove(&fp);
+        }
+    }
+
+    fn remove_edge(&mut self, (left, right): (u64, u64)) {
+        if self.documents.remove(&(left, right)).is_none() {
+            return;
+        }
+        self.generation += 1;
+        for (this, other) in [(left, right), (right, left)] {
+            if let Some(neighbors) = self.adjacency.get_mut(&this) {
+                neighbors.remove(&other);
+                if neighbors.is_empty() {
+                    self.adjacency.remove(&this);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use stdcode::StdcodeSerializeExt;
+
+    use super::*;
+    use crate::test_util::{adjacency, now_secs};
+
+    /// Builds the identities of a chain of relays, plus signed adjacencies between consecutive ones.
+    fn chain(len: usize) -> (Vec<IdentityDescriptor>, Vec<AdjacencyDescriptor>) {
+        let secrets: Vec<RelayIdentitySecret> =
+            (0..len).map(|_| RelayIdentitySecret::generate()).collect();
+        let identities = secrets
+            .iter()
+            .map(|sk| IdentityDescriptor::new(sk, &DhSecret::generate()))
+            .collect();
+        let adjacencies = secrets
+            .windows(2)
+            .map(|pair| adjacency(&pair[0], &pair[1], now_secs()))
+            .collect();
+        (identities, adjacencies)
+    }
+
+    fn fp(identity: &IdentityDescriptor) -> RelayFingerprint {
+        identity.identity_pk.fingerprint()
+    }
+
+    /// Gossips a chain into the graph from its first relay outward, the way it would reach a node sitting at the start of the chain.
+    fn gossip(
+        graph: &mut RelayGraph,
+        identities: &[IdentityDescriptor],
+        adjacencies: &[AdjacencyDescriptor],
+    ) {
+        graph.insert_identity(identities[0].clone()).unwrap();
+        for (identity, adjacency) in identities[1..].iter().zip(adjacencies) {
+            graph.insert_identity(identity.clone()).unwrap();
+            let _ = graph.insert_adjacency(adjacency.clone());
+        }
+    }
+
+    #[test]
+    fn caps_keep_nearest_nodes() {
+        let (identities, adjacencies) = chain(30);
+        let mut graph = RelayGraph::new();
+        graph.anchor(fp(&identities[0]));
+        graph.set_limits(GraphLimits {
+            max_nodes: 10,
+            max_edges: 9,
+        });
+        gossip(&mut graph, &identities, &adjacencies);
+
+        let stats = graph.graph_stats();
+        assert_eq!((stats.nodes, stats.edges), (10, 9));
+        assert_eq!(stats.skipped_inserts, 20);
+        for (i, identity) in identities.iter().enumerate() {
+            assert_eq!(graph.identity(&fp(identity)).is_some(), i < 10);
+        }
+
+        // lowering the limits evicts the farthest nodes first
+        graph.set_limits(GraphLimits {
+            max_nodes: 4,
+            max_edges: 3,
+        });
+        let stats = graph.graph_stats();
+        assert_eq!((stats.nodes, stats.edges), (4, 3));
+        assert_eq!(stats.evicted_nodes, 6);
+        for (i, identity) in identities.iter().enumerate() {
+            assert_eq!(graph.identity(&fp(identity)).is_some(), i < 4);
+        }
+
+        // the graph survives a round trip through the state cache
+        let graph: RelayGraph = stdcode::deserialize(&graph.stdcode()).unwrap();
+        assert_eq!(graph.graph_stats().nodes, 4);
+    }
+
+    #[test]
+    fn protected_nodes_are_never_evicted() {
+        let (identities, adjacencies) = chain(30);
+        let mut graph = RelayGraph::new();
+        graph.anchor(fp(&identities[0]));
+        graph.anchor(fp(&identities[1]));
+        gossip(&mut graph, &identities, &adjacencies);
+        graph.mark_used([fp(&identities[25])]);
+
+        graph.set_limits(GraphLimits {
+            max_nodes: 1,
+            max_edges: 0,
+        });
+        let stats = graph.graph_stats();
+        assert_eq!((stats.nodes, stats.protected_nodes), (3, 3));
+        for i in [0, 1, 25] {
+            assert!(graph.identity(&fp(&identities[i])).is_some());
+        }
+        // the edge between two anchors is protected too
+        assert_eq!(stats.edges, 1);
+
+        // protected nodes get in even when the graph is full
+        let (extra, _) = chain(1);
+        graph.insert_identity(extra[0].clone()).unwrap();
+        assert!(graph.identity(&fp(&extra[0])).is_none());
+        graph.mark_used([fp(&extra[0])]);
+        graph.insert_identity(extra[0].clone()).unwrap();
+        assert!(graph.identity(&fp(&extra[0])).is_some());
+
+        // once unanchored, a node can be evicted
+        graph.unanchor(&fp(&identities[1]));
+        graph.set_limits(GraphLimits {
+            max_nodes: 1,
+            max_edges: 0,
+        });
+        assert!(graph.identity(&fp(&identities[1])).is_none());
+    }
+
+    #[test]
+    fn active_routes_keep_working() {
+        let (identities, adjacencies) = chain(30);
+        let mut graph = RelayGraph::new();
+        graph.anchor(fp(&identities[0]));
+        gossip(&mut graph, &identities, &adjacencies);
+
+        // an active route to a faraway destination
+        let dest = fp(&identities[20]);
+        let route = graph
+            .find_shortest_path(&fp(&identities[0]), &dest)
+            .unwrap();
+        assert_eq!(route.len(), 21);
+        graph.mark_used(route.iter().copied());
+
+        graph.set_limits(GraphLimits {
+            max_nodes: 5,
+            max_edges: 5,
+        });
+        let stats = graph.graph_stats();
+        assert_eq!((stats.nodes, stats.edges), (21, 20));
+        assert_eq!(
+            graph.find_shortest_path(&fp(&identities[0]), &dest),
+            Some(route)
+        );
+        assert!(graph.identity(&fp(&identities[25])).is_none());
+    }
+}
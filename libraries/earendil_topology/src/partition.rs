@@ -0,0 +1,117 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use earendil_crypt::RelayFingerprint;
+use serde::{Deserialize, Serialize};
+
+use crate::RelayGraph;
+
+/// How the graph splits into connected components, as seen from one relay.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Partition {
+    /// How many hops away each relay in the starting relay's component is, counting the starting relay itself at zero.
+    pub hops: HashMap<RelayFingerprint, usize>,
+    /// The sizes of the other components, largest first.
+    pub other_components: Vec<usize>,
+    /// Whether scanning stopped at the node cap, leaving some relays out.
+    pub truncated: bool,
+}
+
+impl RelayGraph {
+    /// Splits the graph into connected components, counting only adjacencies refreshed within `max_age` seconds of `now`. Scans at most `max_nodes` nodes, starting from the component of `start`, so that huge graphs can't make this slow.
+    pub fn partition(
+        &self,
+        start: Option<&RelayFingerprint>,
+        max_age: u64,
+        now: u64,
+        max_nodes: usize,
+    ) -> Partition {
+        let mut partition = Partition::default();
+        let mut visited = HashSet::new();
+        if let Some(start_id) = start.and_then(|start| self.id(start)) {
+            for (id, hops) in self.component(start_id, max_age, now, max_nodes, &mut visited) {
+                partition.hops.insert(self.id_to_fp[&id], hops);
+            }
+        }
+        for &id in self.id_to_fp.keys() {
+            if visited.len() >= max_nodes {
+                break;
+            }
+            if !visited.contains(&id) {
+                let size = self
+                    .component(id, max_age, now, max_nodes, &mut visited)
+                    .len();
+                partition.other_components.push(size);
+            }
+        }
+        partition.other_components.sort_unstable_by(|a, b| b.cmp(a));
+        partition.truncated = visited.len() < self.id_to_fp.len();
+        partition
+    }
+
+    /// Breadth-first search from `start` over fresh adjacencies, returning each node reached and how many hops away it is. Stops once `visited` holds `max_nodes` nodes.
+    fn component(
+        &self,
+        start: u64,
+        max_age: u64,
+        now: u64,
+        max_nodes: usize,
+        visited: &mut HashSet<u64>,
+    ) -> Vec<(u64, usize)> {
+        let mut reached = vec![(start, 0)];
+        let mut queue = VecDeque::from([(start, 0)]);
+        visited.insert(start);
+        while let Some((current, hops)) = queue.pop_front() {
+            let Some(neighbors) = self.adjacency.get(&current) else {
+                continue;
+            };
+            for &neighbor in neighbors {
+                if visited.len() >= max_nodes {
+                    return reached;
+                }
+                if !visited.contains(&neighbor) && self.fresh(current, neighbor, max_age, now) {
+                    visited.insert(neighbor);
+                    reached.push((neighbor, hops + 1));
+                    queue.push_back((neighbor, hops + 1));
+                }
+            }
+        }
+        reached
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{adjacency, known_relay, now_secs};
+
+    use super::*;
+
+    #[test]
+    fn partition_caps_nodes_scanned() {
+        let mut graph = RelayGraph::new();
+        let relays: Vec<_> = (0..6).map(|_| known_relay(&mut graph)).collect();
+        let now = now_secs();
+        // a chain of three, and three relays on their own
+        for pair in relays[..3].windows(2) {
+            graph
+                .insert_adjacency(adjacency(&pair[0], &pair[1], now))
+                .unwrap();
+        }
+        let start = relays[0].public().fingerprint();
+
+        let full = graph.partition(Some(&start), 600, now, 100);
+        assert_eq!(full.hops.len(), 3);
+        assert_eq!(full.hops[&relays[2].public().fingerprint()], 2);
+        assert_eq!(full.other_components, vec![1, 1, 1]);
+        assert!(!full.truncated);
+
+        let capped = graph.partition(Some(&start), 600, now, 4);
+        assert_eq!(capped.hops.len(), 3);
+        assert_eq!(capped.other_components, vec![1]);
+        assert!(capped.truncated);
+
+        // nothing is fresh enough a second later than it was allowed to be
+        let stale = graph.partition(Some(&start), 600, now + 601, 100);
+        assert_eq!(stale.hops.len(), 1);
+        assert_eq!(stale.other_components.len(), 5);
+    }
+}
@@ -0,0 +1,49 @@
+//! Helpers for tests that build relay graphs, shared with crates that depend on this one through the `test-util` feature.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use earendil_crypt::RelayIdentitySecret;
+use earendil_packet::crypt::DhSecret;
+
+use crate::{AdjacencyDescriptor, IdentityDescriptor, RelayGraph};
+
+/// The current time, in seconds since the Unix epoch.
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// An adjacency between two relays, signed by both.
+pub fn adjacency(
+    a: &RelayIdentitySecret,
+    b: &RelayIdentitySecret,
+    unix_timestamp: u64,
+) -> AdjacencyDescriptor {
+    let (left, right) = if a.public().fingerprint() < b.public().fingerprint() {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let mut adj = AdjacencyDescriptor {
+        left: left.public().fingerprint(),
+        right: right.public().fingerprint(),
+        left_sig: Bytes::new(),
+        right_sig: Bytes::new(),
+        unix_timestamp,
+    };
+    adj.left_sig = left.sign(adj.to_sign().as_bytes());
+    adj.right_sig = right.sign(adj.to_sign().as_bytes());
+    adj
+}
+
+/// Adds a relay with a fresh identity to the graph, returning the identity.
+pub fn known_relay(graph: &mut RelayGraph) -> RelayIdentitySecret {
+    let identity = RelayIdentitySecret::generate();
+    graph
+        .insert_identity(IdentityDescriptor::new(&identity, &DhSecret::generate()))
+        .unwrap();
+    identity
+}
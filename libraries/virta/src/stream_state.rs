@@ -14,7 +14,9 @@ use stdcode::StdcodeSerializeExt;
 use crate::{RelKind, Stream, StreamMessage};
 
 use super::{inflight::Inflight, reorderer::Reorderer, StreamQueues};
-const MSS: usize = 19000;
+
+/// The most payload a stream puts in one message. Bigger writes are split across several.
+pub const MSS: usize = 19000;
 
 /// The raw internal state of a stream.
 ///
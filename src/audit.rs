@@ -0,0 +1,694 @@
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use earendil_crypt::{RelayFingerprint, RelayIdentityPublic};
+use moka::sync::{Cache, CacheBuilder};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use smol::channel::{Receiver, Sender};
+use smol_timeout::TimeoutExt;
+use sqlx::Row;
+use stdcode::StdcodeSerializeExt;
+
+use crate::{
+    clock::unix_now,
+    context::{CtxField, DaemonContext, MY_RELAY_IDENTITY},
+    daemon::IdentityRotation,
+    db::{compress_value, db_read, db_write, MiscKey, DATABASE},
+    micromel::Micromel,
+    stats::STATS,
+};
+
+pub const AUDIT_WRITE_FAILED: &str = "audit.write_failed";
+pub const AUDIT_QUEUE_FULL: &str = "audit.queue_full";
+
+/// How many events [audit_soon] holds for the writer before it starts dropping them.
+const QUEUE_LEN: usize = 10_000;
+
+/// How often the writer drops entries older than the configured retention.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Events waiting for [audit_writer_loop] to record them.
+static QUEUE: CtxField<(Sender<AuditEvent>, Receiver<AuditEvent>)> =
+    |_| smol::channel::bounded(QUEUE_LEN);
+
+/// How often, at most, we record that we're dropping a neighbor's packets at its debt limit. Drops come in floods, and one entry tells an auditor as much as a thousand.
+const DEBT_LIMIT_DROP_EVERY: Duration = Duration::from_secs(60);
+
+/// Appending to the audit log reads the last entry and then writes the next, which must not interleave.
+static APPEND_LOCK: CtxField<smol::lock::Mutex<()>> = |_| smol::lock::Mutex::new(());
+
+/// Neighbors whose debt-limit drops were recorded lately.
+static DEBT_LIMIT_DROPS: CtxField<Cache<String, ()>> = |_| {
+    CacheBuilder::default()
+        .time_to_live(DEBT_LIMIT_DROP_EVERY)
+        .build()
+};
+
+/// A security-relevant event, as recorded in the audit log.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// We rotated our relay identity.
+    IdentityRotated {
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        old: RelayFingerprint,
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        new: RelayFingerprint,
+    },
+    /// The relay at the other end of an out route sent an identity it didn't sign.
+    HandshakeFailed { connect: String, reason: String },
+    /// The relay at the other end of an out route has a fingerprint other than the configured or pinned one.
+    FingerprintMismatch {
+        connect: String,
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        expected: RelayFingerprint,
+        #[serde_as(as = "serde_with::DisplayFromStr")]
+        seen: RelayFingerprint,
+    },
+    /// We dropped a neighbor's packets because of its debt. Recorded at most once a minute per neighbor.
+    DebtLimitDrop { neighbor: String },
+    /// A neighbor settled some of its debt with us.
+    SettlementRecorded {
+        neighbor: String,
+        amount: Micromel,
+        settlement: String,
+    },
+    /// A control RPC that changes something was called. Only what identifies its arguments is kept, never what they carry, such as message contents.
+    ControlCall {
+        method: String,
+        args: serde_json::Value,
+        /// Who made the call: the address it came from, or `in-process`.
+        principal: String,
+        ok: bool,
+    },
+}
+
+/// An entry in the audit log.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub unix_secs: u64,
+    pub event: AuditEvent,
+    /// Covers this entry and the hash of the one before it, so that altering or removing any entry breaks every hash after it.
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub hash: [u8; 32],
+}
+
+/// The last entry of the audit log, as vouched for by whoever recorded it. Exported, it lets an auditor check later that the log still holds that entry unchanged; stored, it lets [verify_audit_log] notice entries removed from the end.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditHead {
+    pub seq: u64,
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub hash: [u8; 32],
+    /// The relay identity that signed the head. Clients have none, and leave their heads unsigned.
+    pub signer: Option<RelayIdentityPublic>,
+    pub sig: Bytes,
+}
+
+impl AuditHead {
+    /// Signs the head of the log with our relay identity, if we have one.
+    fn new(ctx: &DaemonContext, seq: u64, hash: [u8; 32]) -> Self {
+        let mut this = Self {
+            seq,
+            hash,
+            signer: None,
+            sig: Bytes::new(),
+        };
+        if let Some(identity) = ctx.get(MY_RELAY_IDENTITY) {
+            this.signer = Some(identity.public());
+            this.sig = identity.sign(this.to_sign().as_bytes());
+        }
+        this
+    }
+
+    /// The value that the signature is supposed to be computed against.
+    pub fn to_sign(&self) -> blake3::Hash {
+        blake3::keyed_hash(
+            b"audit_log_head__________________",
+            &(self.seq, self.hash).stdcode(),
+        )
+    }
+
+    /// Checks that the signer signed this, or that an unsigned head has no signature either.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        match self.signer {
+            Some(signer) => Ok(signer.verify(self.to_sign().as_bytes(), &self.sig)?),
+            None if self.sig.is_empty() => Ok(()),
+            None => anyhow::bail!("unsigned audit log head carries a signature"),
+        }
+    }
+}
+
+/// The result of checking the audit log's hash chain.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditVerification {
+    pub entries: u64,
+    /// The first entry that was altered, follows a removed one, or is missing from the end, if any.
+    pub first_broken: Option<u64>,
+}
+
+/// Chains an entry to the hash of the one before it.
+fn chain_hash(prev: &[u8; 32], seq: u64, unix_secs: u64, event: &str) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key("earendil audit log");
+    hasher.update(prev);
+    hasher.update(&seq.to_le_bytes());
+    hasher.update(&unix_secs.to_le_bytes());
+    hasher.update(event.as_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+/// Appends an event to the audit log in the state cache. Without a state cache, the event is only logged. Failing to record it is logged too, but never fails whatever is being audited.
+pub async fn audit(ctx: &DaemonContext, event: AuditEvent) {
+    tracing::info!(event = debug(&event), "audit");
+    if let Err(err) = append(ctx, &event).await {
+        ctx.get(STATS).incr(AUDIT_WRITE_FAILED);
+        tracing::warn!(
+            err = debug(err),
+            event = debug(&event),
+            "could not record audit event"
+        );
+    }
+}
+
+/// Records that we dropped a neighbor's packets because of its debt, unless we did so within the last minute. Drops happen on the packet path, so the event is queued with [audit_soon] rather than waited for.
+pub fn audit_debt_limit_drop(ctx: &DaemonContext, neighbor: &str) {
+    let recorded = ctx.get(DEBT_LIMIT_DROPS);
+    if recorded.contains_key(neighbor) {
+        return;
+    }
+    recorded.insert(neighbor.to_string(), ());
+    audit_soon(
+        ctx,
+        AuditEvent::DebtLimitDrop {
+            neighbor: neighbor.to_string(),
+        },
+    )
+}
+
+/// Queues an event for [audit_writer_loop] to record, for callers that shouldn't wait on the state cache. Drops the event, counting it, if the writer has fallen far behind.
+pub fn audit_soon(ctx: &DaemonContext, event: AuditEvent) {
+    if ctx.get(QUEUE).0.try_send(event).is_err() {
+        ctx.get(STATS).incr(AUDIT_QUEUE_FULL);
+    }
+}
+
+/// Records the events queued with [audit_soon], and drops entries older than the configured retention.
+pub async fn audit_writer_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let queue = ctx.get(QUEUE).1.clone();
+    prune_expired(&ctx).await?;
+    let mut pruned = Instant::now();
+    loop {
+        if let Some(event) = queue.recv().timeout(PRUNE_INTERVAL).await {
+            audit(&ctx, event?).await;
+        }
+        if pruned.elapsed() >= PRUNE_INTERVAL {
+            prune_expired(&ctx).await?;
+            pruned = Instant::now();
+        }
+    }
+}
+
+async fn prune_expired(ctx: &DaemonContext) -> anyhow::Result<()> {
+    if let Some(retention) = ctx.init().audit_log_retention_secs {
+        prune(ctx, unix_now(ctx).saturating_sub(retention)).await?;
+    }
+    Ok(())
+}
+
+/// Drops the entries recorded before `cutoff`, in seconds since the Unix epoch. The last one dropped, signed like the head, becomes the start of the hash chain that [verify_audit_log] checks, so that nobody without our key can move the start past entries they removed.
+async fn prune(ctx: &DaemonContext, cutoff: u64) -> anyhow::Result<()> {
+    let Some(pool) = ctx.get(DATABASE) else {
+        return Ok(());
+    };
+    let _guard = ctx.get(APPEND_LOCK).lock().await;
+    if let Some(anchor) = pruned_anchor(ctx).await? {
+        // an anchor signed by the identity we rotated away from is only trusted until the next rotation
+        if anchor.signer != ctx.get(MY_RELAY_IDENTITY).map(|id| id.public())
+            && trusted(ctx, &anchor).await?
+        {
+            let anchor = AuditHead::new(ctx, anchor.seq, anchor.hash);
+            db_write(ctx, MiscKey::AuditLogPruned, anchor.stdcode()).await?;
+        }
+    }
+    // the last entry is kept no matter how old, so that the next one has something to chain to
+    let last = sqlx::query(
+        "SELECT seq, hash FROM audit_log WHERE unix_secs < ? AND seq < (SELECT MAX(seq) FROM audit_log) ORDER BY seq DESC LIMIT 1",
+    )
+    .bind(cutoff as i64)
+    .fetch_optional(pool)
+    .await?;
+    let Some(last) = last else {
+        return Ok(());
+    };
+    let seq = last.get::<i64, _>("seq") as u64;
+    let anchor = AuditHead::new(ctx, seq, read_hash(last.get("hash"))?);
+    // the anchor goes first, so that a crash before the delete only leaves entries that verification skips
+    db_write(ctx, MiscKey::AuditLogPruned, anchor.stdcode()).await?;
+    let pruned = sqlx::query("DELETE FROM audit_log WHERE seq <= ?")
+        .bind(seq as i64)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    tracing::debug!(pruned, through = seq, "pruned the audit log");
+    Ok(())
+}
+
+async fn append(ctx: &DaemonContext, event: &AuditEvent) -> anyhow::Result<()> {
+    let Some(pool) = ctx.get(DATABASE) else {
+        return Ok(());
+    };
+    let event = serde_json::to_string(event)?;
+    let unix_secs = unix_now(ctx);
+    let _guard = ctx.get(APPEND_LOCK).lock().await;
+    let last = sqlx::query("SELECT seq, hash FROM audit_log ORDER BY seq DESC LIMIT 1")
+        .fetch_optional(pool)
+        .await?;
+    let (seq, prev) = match last {
+        Some(row) => (
+            row.get::<i64, _>("seq") as u64 + 1,
+            read_hash(row.get("hash"))?,
+        ),
+        None => (0, [0; 32]),
+    };
+    let hash = chain_hash(&prev, seq, unix_secs, &event);
+    // the entry and the head that vouches for it go in together, so that a crash can't leave either looking tampered with
+    let mut txn = pool.begin().await?;
+    sqlx::query("INSERT INTO audit_log (seq, unix_secs, event, hash) VALUES (?, ?, ?, ?)")
+        .bind(seq as i64)
+        .bind(unix_secs as i64)
+        .bind(&event)
+        .bind(hash.to_vec())
+        .execute(&mut *txn)
+        .await?;
+    sqlx::query("INSERT INTO misc (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value")
+        .bind(MiscKey::AuditLogHead.name())
+        .bind(compress_value(
+            ctx.init().state_compression.as_ref(),
+            AuditHead::new(ctx, seq, hash).stdcode(),
+        ))
+        .execute(&mut *txn)
+        .await?;
+    txn.commit().await?;
+    Ok(())
+}
+
+/// The signed head of the audit log, for an auditor to keep somewhere we can't reach and later check the log against with [verify_audit_log].
+pub async fn audit_log_head(ctx: &DaemonContext) -> anyhow::Result<Option<AuditHead>> {
+    Ok(match db_read(ctx, MiscKey::AuditLogHead).await? {
+        Some(bts) => Some(stdcode::deserialize(&bts)?),
+        None => None,
+    })
+}
+
+/// The last entry pruned for being too old, where the hash chain starts.
+async fn pruned_anchor(ctx: &DaemonContext) -> anyhow::Result<Option<AuditHead>> {
+    Ok(match db_read(ctx, MiscKey::AuditLogPruned).await? {
+        Some(bts) => Some(stdcode::deserialize(&bts)?),
+        None => None,
+    })
+}
+
+/// Whether a head or anchor is ours to trust: signed by our identity, or by the one we last rotated away from, until it is next signed with the new one. Clients trust unsigned ones, having nothing to sign with.
+async fn trusted(ctx: &DaemonContext, head: &AuditHead) -> anyhow::Result<bool> {
+    if head.verify().is_err() {
+        return Ok(false);
+    }
+    let me = ctx.get(MY_RELAY_IDENTITY).map(|id| id.public());
+    if head.signer == me {
+        return Ok(true);
+    }
+    let (Some(me), Some(signer)) = (me, head.signer) else {
+        return Ok(false);
+    };
+    let Some(bts) = db_read(ctx, MiscKey::IdentityRotation).await? else {
+        return Ok(false);
+    };
+    let rotation: IdentityRotation = stdcode::deserialize(&bts)?;
+    Ok(rotation.new_pk == me && rotation.old_pk == signer && rotation.verify().is_ok())
+}
+
+fn read_hash(bts: Vec<u8>) -> anyhow::Result<[u8; 32]> {
+    bts.try_into()
+        .map_err(|_| anyhow::anyhow!("audit log hash is not 32 bytes"))
+}
+
+/// Returns up to `limit` entries of the audit log, starting at `after`, oldest first. Entries are not checked against the hash chain; use [verify_audit_log] for that.
+pub async fn audit_log(
+    ctx: &DaemonContext,
+    after: u64,
+    limit: u32,
+) -> anyhow::Result<Vec<AuditEntry>> {
+    let Some(pool) = ctx.get(DATABASE) else {
+        return Ok(vec![]);
+    };
+    let rows = sqlx::query(
+        "SELECT seq, unix_secs, event, hash FROM audit_log WHERE seq >= ? ORDER BY seq LIMIT ?",
+    )
+    .bind(after as i64)
+    .bind(limit as i64)
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|row| {
+            Ok(AuditEntry {
+                seq: row.get::<i64, _>("seq") as u64,
+                unix_secs: row.get::<i64, _>("unix_secs") as u64,
+                event: serde_json::from_str(row.get("event"))?,
+                hash: read_hash(row.get("hash"))?,
+            })
+        })
+        .collect()
+}
+
+/// Walks the audit log's hash chain from the start, or from the last entry pruned for being too old, finding the first entry that was altered or follows a removed one. The chain must end at the signed head we stored, so that removing entries from the end shows too, and must hold `exported`, a head handed out earlier, unless it was pruned since.
+///
+/// Whoever can write the state cache could roll back both the log and the stored head to an earlier state; only an exported head catches that.
+pub async fn verify_audit_log(
+    ctx: &DaemonContext,
+    exported: Option<&AuditHead>,
+) -> anyhow::Result<AuditVerification> {
+    let Some(pool) = ctx.get(DATABASE) else {
+        return Ok(AuditVerification {
+            entries: 0,
+            first_broken: None,
+        });
+    };
+    let anchor = pruned_anchor(ctx).await?;
+    let (mut expected_seq, mut prev) = match &anchor {
+        Some(anchor) => (anchor.seq + 1, anchor.hash),
+        None => (0, [0; 32]),
+    };
+    let start_seq = expected_seq;
+    let mut first_broken = None;
+    if let Some(anchor) = &anchor {
+        if !trusted(ctx, anchor).await? {
+            // someone without our key moved the start of the chain
+            first_broken = Some(start_seq);
+        }
+    }
+    let rows = sqlx::query(
+        "SELECT seq, unix_secs, event, hash FROM audit_log WHERE seq >= ? ORDER BY seq",
+    )
+    .bind(expected_seq as i64)
+    .fetch_all(pool)
+    .await?;
+    let walked: &[_] = match first_broken {
+        None => rows.as_slice(),
+        Some(_) => &[],
+    };
+    for row in walked {
+        let seq = row.get::<i64, _>("seq") as u64;
+        let unix_secs = row.get::<i64, _>("unix_secs") as u64;
+        let event: String = row.get("event");
+        let hash = read_hash(row.get("hash")).unwrap_or_default();
+        let diverges = exported.is_some_and(|head| head.seq == seq && head.hash != hash);
+        if seq != expected_seq || hash != chain_hash(&prev, seq, unix_secs, &event) || diverges {
+            first_broken = Some(seq);
+            break;
+        }
+        prev = hash;
+        expected_seq += 1;
+    }
+    if first_broken.is_none() {
+        first_broken = check_head(ctx, start_seq, expected_seq, prev).await?;
+    }
+    if let (None, Some(exported)) = (first_broken, exported) {
+        let diverges = anchor
+            .as_ref()
+            .is_some_and(|anchor| exported.seq == anchor.seq && exported.hash != anchor.hash);
+        if exported.seq >= expected_seq {
+            first_broken = Some(expected_seq);
+        } else if diverges {
+            first_broken = Some(start_seq);
+        }
+    }
+    Ok(AuditVerification {
+        entries: rows.len() as u64,
+        first_broken,
+    })
+}
+
+/// Checks that the chain, which runs unbroken from `start_seq` up to `end_seq` and ends in `last_hash`, ends at the head we stored, returning the first entry it can't vouch for.
+async fn check_head(
+    ctx: &DaemonContext,
+    start_seq: u64,
+    end_seq: u64,
+    last_hash: [u8; 32],
+) -> anyhow::Result<Option<u64>> {
+    let head = match audit_log_head(ctx).await? {
+        Some(head) if trusted(ctx, &head).await? => head,
+        // a log that was never written to has no head
+        _ if end_seq == 0 => return Ok(None),
+        _ => return Ok(Some(start_seq)),
+    };
+    Ok(if head.seq >= end_seq {
+        // entries were removed from the end
+        Some(end_seq)
+    } else if head.seq + 1 < end_seq {
+        // entries were added that we never signed
+        Some(head.seq + 1)
+    } else if head.hash != last_hash {
+        Some(head.seq)
+    } else {
+        None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+
+    use super::*;
+
+    #[test]
+    fn tampering_breaks_the_chain() {
+        let dir = std::env::temp_dir().join(format!("earendil-audit-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "state_cache": dir.join("state.db"),
+            }))
+            .unwrap(),
+        );
+        let fp = || RelayIdentitySecret::generate().public().fingerprint();
+        smol::future::block_on(async {
+            audit(
+                &ctx,
+                AuditEvent::IdentityRotated {
+                    old: fp(),
+                    new: fp(),
+                },
+            )
+            .await;
+            audit(
+                &ctx,
+                AuditEvent::FingerprintMismatch {
+                    connect: "127.0.0.1:1234".into(),
+                    expected: fp(),
+                    seen: fp(),
+                },
+            )
+            .await;
+            audit_debt_limit_drop(&ctx, "greedy");
+            // drops are only recorded once a minute
+            audit_debt_limit_drop(&ctx, "greedy");
+            let queue = &ctx.get(QUEUE).1;
+            assert_eq!(queue.len(), 1);
+            audit(&ctx, queue.try_recv().unwrap()).await;
+            audit(
+                &ctx,
+                AuditEvent::SettlementRecorded {
+                    neighbor: "greedy".into(),
+                    amount: Micromel(1000),
+                    settlement: "00".into(),
+                },
+            )
+            .await;
+
+            let entries = audit_log(&ctx, 0, 100).await.unwrap();
+            assert_eq!(entries.len(), 4);
+            assert_eq!(
+                entries[2].event,
+                AuditEvent::DebtLimitDrop {
+                    neighbor: "greedy".into()
+                }
+            );
+            assert_eq!(audit_log(&ctx, 3, 100).await.unwrap().len(), 1);
+            assert_eq!(
+                verify_audit_log(&ctx, None).await.unwrap(),
+                AuditVerification {
+                    entries: 4,
+                    first_broken: None
+                }
+            );
+
+            // rewriting an entry is caught at that entry
+            let pool = ctx.get(DATABASE).as_ref().unwrap();
+            sqlx::query("UPDATE audit_log SET event = ? WHERE seq = 2")
+                .bind(r#"{"type":"debt_limit_drop","neighbor":"innocent"}"#)
+                .execute(pool)
+                .await
+                .unwrap();
+            assert_eq!(
+                verify_audit_log(&ctx, None).await.unwrap().first_broken,
+                Some(2)
+            );
+        });
+        drop(ctx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn removing_entries_from_the_end_shows() {
+        let dir = std::env::temp_dir().join(format!("earendil-audit-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "state_cache": dir.join("state.db"),
+                "identity_seed": "audit head",
+            }))
+            .unwrap(),
+        );
+        let event = |neighbor: &str| AuditEvent::DebtLimitDrop {
+            neighbor: neighbor.into(),
+        };
+        smol::future::block_on(async {
+            for neighbor in ["a", "b", "c"] {
+                audit(&ctx, event(neighbor)).await;
+            }
+            let exported = audit_log_head(&ctx).await.unwrap().unwrap();
+            assert_eq!(exported.seq, 2);
+            assert_eq!(
+                exported.signer,
+                Some(ctx.get(MY_RELAY_IDENTITY).unwrap().public())
+            );
+            exported.verify().unwrap();
+            audit(&ctx, event("d")).await;
+            assert_eq!(
+                verify_audit_log(&ctx, Some(&exported)).await.unwrap(),
+                AuditVerification {
+                    entries: 4,
+                    first_broken: None
+                }
+            );
+
+            // dropping the last entry no longer matches the signed head
+            let pool = ctx.get(DATABASE).as_ref().unwrap();
+            sqlx::query("DELETE FROM audit_log WHERE seq = 3")
+                .execute(pool)
+                .await
+                .unwrap();
+            assert_eq!(
+                verify_audit_log(&ctx, None).await.unwrap().first_broken,
+                Some(3)
+            );
+
+            // rolling the head back along with the log only gets past a head exported since
+            db_write(&ctx, MiscKey::AuditLogHead, exported.stdcode())
+                .await
+                .unwrap();
+            assert_eq!(
+                verify_audit_log(&ctx, None).await.unwrap().first_broken,
+                None
+            );
+            let later = AuditHead::new(&ctx, 3, [1; 32]);
+            assert_eq!(
+                verify_audit_log(&ctx, Some(&later))
+                    .await
+                    .unwrap()
+                    .first_broken,
+                Some(3)
+            );
+
+            // rewriting the whole chain needs a head signed with our key
+            sqlx::query("DELETE FROM audit_log")
+                .execute(pool)
+                .await
+                .unwrap();
+            let unsigned = AuditHead {
+                seq: 0,
+                hash: chain_hash(&[0; 32], 0, 0, "{}"),
+                signer: None,
+                sig: Bytes::new(),
+            };
+            sqlx::query(
+                "INSERT INTO audit_log (seq, unix_secs, event, hash) VALUES (0, 0, '{}', ?)",
+            )
+            .bind(unsigned.hash.to_vec())
+            .execute(pool)
+            .await
+            .unwrap();
+            db_write(&ctx, MiscKey::AuditLogHead, unsigned.stdcode())
+                .await
+                .unwrap();
+            assert_eq!(
+                verify_audit_log(&ctx, None).await.unwrap().first_broken,
+                Some(0)
+            );
+        });
+        drop(ctx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pruning_keeps_the_rest_verifiable() {
+        let dir = std::env::temp_dir().join(format!("earendil-audit-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "state_cache": dir.join("state.db"),
+                "identity_seed": "audit pruning",
+            }))
+            .unwrap(),
+        );
+        let event = |neighbor: &str| AuditEvent::DebtLimitDrop {
+            neighbor: neighbor.into(),
+        };
+        smol::future::block_on(async {
+            for neighbor in ["a", "b", "c"] {
+                audit(&ctx, event(neighbor)).await;
+            }
+            prune(&ctx, unix_now(&ctx) + 1).await.unwrap();
+            // the newest entry stays, for the next one to chain to
+            let entries = audit_log(&ctx, 0, 100).await.unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].seq, 2);
+
+            audit(&ctx, event("d")).await;
+            assert_eq!(
+                verify_audit_log(&ctx, None).await.unwrap(),
+                AuditVerification {
+                    entries: 2,
+                    first_broken: None
+                }
+            );
+
+            // moving the start of the chain past entries needs our key too
+            let pool = ctx.get(DATABASE).as_ref().unwrap();
+            let third = audit_log(&ctx, 2, 1).await.unwrap().remove(0);
+            sqlx::query("DELETE FROM audit_log WHERE seq = 2")
+                .execute(pool)
+                .await
+                .unwrap();
+            let forged = AuditHead {
+                seq: third.seq,
+                hash: third.hash,
+                signer: None,
+                sig: Bytes::new(),
+            };
+            db_write(&ctx, MiscKey::AuditLogPruned, forged.stdcode())
+                .await
+                .unwrap();
+            assert_eq!(
+                verify_audit_log(&ctx, None).await.unwrap().first_broken,
+                Some(3)
+            );
+        });
+        drop(ctx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
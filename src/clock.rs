@@ -0,0 +1,186 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::RwLock;
+
+use crate::context::{CtxField, DaemonContext};
+
+mod jump;
+
+#[cfg(test)]
+pub use self::fake::FakeClock;
+pub use self::jump::{
+    check_against_neighbor, clock_jump_loop, clock_jumps, in_jump_grace, last_clock_jump,
+    notice_jumps, wall_clock_trusted, ClockJump, CLOCK_JUMPED,
+};
+
+/// Where the daemon's timed logic, such as expiries, backoffs, and the mix delay queue, gets the time from. Always the real clock, except in tests that swap in a fake one to make time-dependent behavior deterministic.
+#[derive(Clone)]
+pub enum Clock {
+    Real,
+    #[cfg(test)]
+    Fake(FakeClock),
+}
+
+impl Clock {
+    pub fn now(&self) -> Instant {
+        match self {
+            Clock::Real => Instant::now(),
+            #[cfg(test)]
+            Clock::Fake(fake) => fake.now(),
+        }
+    }
+
+    /// The wall-clock time, which unlike [Clock::now] may jump, say when the system resumes from a suspend or gets its time corrected.
+    pub fn system_now(&self) -> SystemTime {
+        match self {
+            Clock::Real => SystemTime::now(),
+            #[cfg(test)]
+            Clock::Fake(fake) => fake.system_now(),
+        }
+    }
+
+    /// The time in seconds since the Unix epoch.
+    pub fn unix_now(&self) -> u64 {
+        self.system_now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Waits until the clock reads `deadline`.
+    pub async fn sleep_until(&self, deadline: Instant) {
+        match self {
+            Clock::Real => {
+                smol::Timer::at(deadline).await;
+            }
+            #[cfg(test)]
+            Clock::Fake(fake) => fake.sleep_until(deadline).await,
+        }
+    }
+
+    pub async fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration).await
+    }
+}
+
+static CLOCK: CtxField<RwLock<Clock>> = |_| RwLock::new(Clock::Real);
+
+/// The clock of the daemon.
+pub fn clock(ctx: &DaemonContext) -> Clock {
+    ctx.get(CLOCK).read().clone()
+}
+
+/// Shorthand for the daemon's clock's [Clock::unix_now].
+pub fn unix_now(ctx: &DaemonContext) -> u64 {
+    clock(ctx).unix_now()
+}
+
+/// Makes the daemon read the time from the given clock, such as a [FakeClock] that a test advances.
+#[cfg(test)]
+pub fn set_clock(ctx: &DaemonContext, clock: Clock) {
+    *ctx.get(CLOCK).write() = clock;
+}
+
+#[cfg(test)]
+mod fake {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use async_event::Event;
+    use parking_lot::Mutex;
+
+    use super::Clock;
+
+    /// A clock that only moves when told to.
+    #[derive(Clone)]
+    pub struct FakeClock {
+        inner: Arc<Inner>,
+    }
+
+    struct Inner {
+        start: Instant,
+        start_system: SystemTime,
+        elapsed: Mutex<Duration>,
+        /// How far the wall clock jumped ahead of the monotonic one.
+        wall_ahead: Mutex<Duration>,
+        advanced: Event,
+    }
+
+    impl FakeClock {
+        /// Creates a fake clock that reads the real time until it's advanced.
+        pub fn new() -> Self {
+            Self {
+                inner: Arc::new(Inner {
+                    start: Instant::now(),
+                    start_system: Clock::Real.system_now(),
+                    elapsed: Mutex::new(Duration::ZERO),
+                    wall_ahead: Mutex::new(Duration::ZERO),
+                    advanced: Event::new(),
+                }),
+            }
+        }
+
+        /// Moves the clock forward, waking whatever was sleeping until then.
+        pub fn advance(&self, by: Duration) {
+            *self.inner.elapsed.lock() += by;
+            self.inner.advanced.notify_all();
+        }
+
+        /// Moves only the wall clock forward, the way it jumps when the system resumes from a suspend that the monotonic clock didn't count.
+        pub fn jump_wall(&self, by: Duration) {
+            *self.inner.wall_ahead.lock() += by;
+        }
+
+        pub(super) fn now(&self) -> Instant {
+            self.inner.start + *self.inner.elapsed.lock()
+        }
+
+        pub(super) fn system_now(&self) -> SystemTime {
+            self.inner.start_system + *self.inner.elapsed.lock() + *self.inner.wall_ahead.lock()
+        }
+
+        pub(super) async fn sleep_until(&self, deadline: Instant) {
+            self.inner
+                .advanced
+                .wait_until(|| (self.now() >= deadline).then_some(()))
+                .await
+        }
+    }
+
+    impl Default for FakeClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::future::FutureExt as _;
+
+    use super::*;
+
+    #[test]
+    fn fake_sleep_waits_for_advance() {
+        let fake = FakeClock::new();
+        let clock = Clock::Fake(fake.clone());
+        let start = clock.now();
+        let unix_start = clock.unix_now();
+        smol::future::block_on(async {
+            let slept = clock.sleep(Duration::from_secs(3600));
+            let advancer = async {
+                for _ in 0..4 {
+                    smol::Timer::after(Duration::from_millis(10)).await;
+                    fake.advance(Duration::from_secs(900));
+                }
+                smol::Timer::after(Duration::from_secs(10)).await;
+                panic!("the sleep never ended");
+            };
+            slept.race(advancer).await;
+        });
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+        assert_eq!(clock.unix_now() - unix_start, 3600);
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_event::Event;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    stats::STATS,
+};
+
+use super::{clock, unix_now};
+
+pub const CLOCK_JUMPED: &str = "clock.jumped";
+
+/// How often the clocks are compared.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far the clocks may disagree between two samples before we take it as a jump. Well above scheduling hiccups, and well below any suspend worth noticing.
+pub const JUMP_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// How long after a jump neighbors that don't answer aren't declared dead yet, since they were as unreachable as we were while we were suspended.
+const JUMP_GRACE: Duration = Duration::from_secs(30);
+
+/// How far our wall clock may be off a neighbor's for us to trust it again after a jump.
+const MAX_NEIGHBOR_SKEW: Duration = Duration::from_secs(60);
+
+/// How long after a jump we wait for a neighbor to confirm our wall clock, before trusting it anyway. Neighbors that don't report their time never would, and we'd stop signing adjacencies for good.
+const MAX_UNVERIFIED: Duration = Duration::from_secs(5 * 60);
+
+const MAX_JUMP_EVENTS: usize = 100;
+
+/// The clocks disagreed about how much time passed, typically because the system was suspended.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClockJump {
+    /// How far the wall clock moved beyond what the monotonic clock did, in milliseconds. Negative if it went back.
+    pub wall_gap_ms: i64,
+    /// How much longer than expected the monotonic clock took between two samples, in milliseconds. On systems whose monotonic clock counts suspends, this is how long we were suspended.
+    pub stall_ms: u64,
+    /// The wall-clock time right after the jump, in seconds since the Unix epoch.
+    pub unix_time: u64,
+}
+
+struct Jumps {
+    last_sample: Mutex<Option<(Instant, SystemTime)>>,
+    /// When the last jump was noticed, on the monotonic clock.
+    last_jump: Mutex<Option<Instant>>,
+    /// When the wall clock last jumped, on the monotonic clock, until a neighbor confirms it.
+    wall_unverified: Mutex<Option<Instant>>,
+    events: Mutex<VecDeque<(u64, ClockJump)>>,
+    new_event: Event,
+}
+
+static JUMPS: CtxField<Jumps> = |_| Jumps {
+    last_sample: Mutex::new(None),
+    last_jump: Mutex::new(None),
+    wall_unverified: Mutex::new(None),
+    events: Mutex::new(VecDeque::new()),
+    new_event: Event::new(),
+};
+
+/// Compares the clocks every second, noticing when they jump.
+pub async fn clock_jump_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    loop {
+        check_clocks(&ctx, SAMPLE_INTERVAL);
+        clock(&ctx).sleep(SAMPLE_INTERVAL).await;
+    }
+}
+
+/// Samples both clocks, comparing them against the last sample, which the caller expects was taken `expected` ago. If they moved apart by more than [JUMP_THRESHOLD], or the monotonic clock moved that much more than expected, records the jump and returns it.
+fn check_clocks(ctx: &DaemonContext, expected: Duration) -> Option<ClockJump> {
+    let clock = clock(ctx);
+    let (mono, wall) = (clock.now(), clock.system_now());
+    let jumps = ctx.get(JUMPS);
+    let (prev_mono, prev_wall) = jumps.last_sample.lock().replace((mono, wall))?;
+    let mono_elapsed = mono.saturating_duration_since(prev_mono);
+    let wall_elapsed_ms = match wall.duration_since(prev_wall) {
+        Ok(elapsed) => elapsed.as_millis() as i64,
+        Err(err) => -(err.duration().as_millis() as i64),
+    };
+    let wall_gap_ms = wall_elapsed_ms - mono_elapsed.as_millis() as i64;
+    let stall = mono_elapsed.saturating_sub(expected);
+    if wall_gap_ms.unsigned_abs() < JUMP_THRESHOLD.as_millis() as u64 && stall < JUMP_THRESHOLD {
+        return None;
+    }
+
+    let jump = ClockJump {
+        wall_gap_ms,
+        stall_ms: stall.as_millis() as u64,
+        unix_time: clock.unix_now(),
+    };
+    tracing::warn!(
+        wall_gap_ms,
+        stall_ms = jump.stall_ms,
+        "the clock jumped, probably across a suspend"
+    );
+    ctx.get(STATS).incr(CLOCK_JUMPED);
+    *jumps.last_jump.lock() = Some(mono);
+    if wall_gap_ms.unsigned_abs() >= JUMP_THRESHOLD.as_millis() as u64 {
+        *jumps.wall_unverified.lock() = Some(mono);
+    }
+    let mut events = jumps.events.lock();
+    let seq = events.back().map(|(seq, _)| seq + 1).unwrap_or(1);
+    events.push_back((seq, jump.clone()));
+    if events.len() > MAX_JUMP_EVENTS {
+        events.pop_front();
+    }
+    drop(events);
+    jumps.new_event.notify_all();
+    Some(jump)
+}
+
+/// Checks the clocks right away, rather than waiting for the next sample, and returns the number of the last jump. For noticing a jump before acting on time that it skipped.
+pub fn notice_jumps(ctx: &DaemonContext) -> u64 {
+    check_clocks(ctx, SAMPLE_INTERVAL);
+    last_clock_jump(ctx)
+}
+
+/// Whether the clock jumped lately enough that silent neighbors may only have been silent because we were away.
+pub fn in_jump_grace(ctx: &DaemonContext) -> bool {
+    let now = clock(ctx).now();
+    ctx.get(JUMPS)
+        .last_jump
+        .lock()
+        .is_some_and(|at| now.saturating_duration_since(at) < JUMP_GRACE)
+}
+
+/// Whether our wall clock can be trusted to timestamp what we sign: always, unless it jumped and no neighbor confirmed it since. If none does within [MAX_UNVERIFIED], it's trusted anyway.
+pub fn wall_clock_trusted(ctx: &DaemonContext) -> bool {
+    let mut unverified = ctx.get(JUMPS).wall_unverified.lock();
+    match *unverified {
+        None => true,
+        Some(since) if clock(ctx).now().saturating_duration_since(since) >= MAX_UNVERIFIED => {
+            tracing::warn!(
+                "no neighbor confirmed our wall clock since it jumped, trusting it anyway"
+            );
+            *unverified = None;
+            true
+        }
+        Some(_) => false,
+    }
+}
+
+/// Checks our wall clock against the time a neighbor reports, in seconds since the Unix epoch, trusting it again if they roughly agree. Returns whether they do.
+pub fn check_against_neighbor(ctx: &DaemonContext, neighbor_unix: u64) -> bool {
+    let agrees = unix_now(ctx).abs_diff(neighbor_unix) <= MAX_NEIGHBOR_SKEW.as_secs();
+    if agrees {
+        *ctx.get(JUMPS).wall_unverified.lock() = None;
+    }
+    agrees
+}
+
+/// The number of the last clock jump, or zero if there weren't any.
+pub fn last_clock_jump(ctx: &DaemonContext) -> u64 {
+    ctx.get(JUMPS)
+        .events
+        .lock()
+        .back()
+        .map(|(seq, _)| *seq)
+        .unwrap_or(0)
+}
+
+/// Waits until there are clock jumps numbered after `after`, then returns them in order.
+pub async fn clock_jumps(ctx: &DaemonContext, after: u64) -> Vec<(u64, ClockJump)> {
+    let jumps = ctx.get(JUMPS);
+    jumps
+        .new_event
+        .wait_until(|| {
+            let events: Vec<_> = jumps
+                .events
+                .lock()
+                .iter()
+                .filter(|(seq, _)| *seq > after)
+                .cloned()
+                .collect();
+            if events.is_empty() {
+                None
+            } else {
+                Some(events)
+            }
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{set_clock, Clock, FakeClock};
+
+    fn ctx_with(fake: &FakeClock) -> DaemonContext {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        set_clock(&ctx, Clock::Fake(fake.clone()));
+        ctx
+    }
+
+    #[test]
+    fn suspends_are_noticed_with_their_gap() {
+        let fake = FakeClock::new();
+        let ctx = ctx_with(&fake);
+        assert_eq!(check_clocks(&ctx, SAMPLE_INTERVAL), None);
+        fake.advance(SAMPLE_INTERVAL);
+        assert_eq!(check_clocks(&ctx, SAMPLE_INTERVAL), None);
+        assert!(wall_clock_trusted(&ctx));
+
+        // the wall clock kept going while we were suspended, but the monotonic clock didn't
+        fake.advance(SAMPLE_INTERVAL);
+        fake.jump_wall(Duration::from_secs(3600));
+        let jump = check_clocks(&ctx, SAMPLE_INTERVAL).expect("the jump went unnoticed");
+        assert_eq!(jump.wall_gap_ms, 3_600_000);
+        assert_eq!(jump.stall_ms, 0);
+        assert_eq!(last_clock_jump(&ctx), 1);
+        assert_eq!(
+            smol::future::block_on(clock_jumps(&ctx, 0)),
+            vec![(1, jump)]
+        );
+        assert!(in_jump_grace(&ctx));
+        assert!(!wall_clock_trusted(&ctx));
+
+        // both clocks counted this one, so only the stall shows
+        fake.advance(SAMPLE_INTERVAL + Duration::from_secs(600));
+        let jump = check_clocks(&ctx, SAMPLE_INTERVAL).expect("the stall went unnoticed");
+        assert_eq!(jump.wall_gap_ms, 0);
+        assert_eq!(jump.stall_ms, 600_000);
+        assert_eq!(ctx.get(STATS).snapshot()[CLOCK_JUMPED], 2);
+
+        fake.advance(JUMP_GRACE);
+        assert!(!in_jump_grace(&ctx));
+    }
+
+    #[test]
+    fn neighbors_restore_trust_in_the_wall_clock() {
+        let fake = FakeClock::new();
+        let ctx = ctx_with(&fake);
+        check_clocks(&ctx, SAMPLE_INTERVAL);
+        fake.jump_wall(Duration::from_secs(3600));
+        check_clocks(&ctx, SAMPLE_INTERVAL).unwrap();
+        assert!(!wall_clock_trusted(&ctx));
+
+        // a neighbor whose clock is an hour behind ours doesn't vouch for it
+        assert!(!check_against_neighbor(&ctx, unix_now(&ctx) - 3600));
+        assert!(!wall_clock_trusted(&ctx));
+        assert!(check_against_neighbor(&ctx, unix_now(&ctx) + 2));
+        assert!(wall_clock_trusted(&ctx));
+    }
+
+    #[test]
+    fn unconfirmed_wall_clock_is_trusted_after_a_while() {
+        let fake = FakeClock::new();
+        let ctx = ctx_with(&fake);
+        check_clocks(&ctx, SAMPLE_INTERVAL);
+        fake.jump_wall(Duration::from_secs(3600));
+        check_clocks(&ctx, SAMPLE_INTERVAL).unwrap();
+
+        // no neighbor ever reports its time
+        fake.advance(MAX_UNVERIFIED - Duration::from_secs(1));
+        assert!(!wall_clock_trusted(&ctx));
+        fake.advance(Duration::from_secs(1));
+        assert!(wall_clock_trusted(&ctx));
+        // and it stays trusted until the next jump
+        fake.advance(Duration::from_secs(1));
+        assert!(wall_clock_trusted(&ctx));
+    }
+
+    #[test]
+    fn jumps_are_noticed_on_demand() {
+        let fake = FakeClock::new();
+        let ctx = ctx_with(&fake);
+        assert_eq!(notice_jumps(&ctx), 0);
+        // long before the next sample would be due
+        fake.advance(Duration::from_millis(10));
+        fake.jump_wall(Duration::from_secs(3600));
+        assert_eq!(notice_jumps(&ctx), 1);
+        assert_eq!(notice_jumps(&ctx), 1);
+        // checking often doesn't make ordinary time look like a stall
+        for _ in 0..20 {
+            fake.advance(Duration::from_millis(500));
+            assert_eq!(notice_jumps(&ctx), 1);
+        }
+    }
+}
@@ -1,74 +1,736 @@
-use clap::{arg, Subcommand};
+use std::{future::Future, io::Write, net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::{arg, CommandFactory, Parser, Subcommand, ValueHint};
+use clap_complete::{
+    engine::{ArgValueCandidates, CompletionCandidate},
+    env::{Bash, Elvish, EnvCompleter, Fish, Powershell, Zsh},
+    Shell,
+};
 use earendil_crypt::{HavenFingerprint, RelayFingerprint};
+use either::Either;
+use smol_timeout::TimeoutExt;
+
+use crate::control_protocol::{
+    ControlClient, ControlEncoding, GraphDumpFormat, GraphExportFormat, ReportFormat,
+};
+use crate::haven::HavenEndpoint;
+use crate::n2r::MessageClass;
+use crate::n2r_socket::RelayEndpoint;
+
+/// Where the daemon's control protocol listens unless configured otherwise.
+pub const DEFAULT_CONTROL_ADDR: &str = "127.0.0.1:18964";
+
+/// How long completion waits on the control port, so that a daemon that isn't running doesn't stall the shell.
+const COMPLETION_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Official implementation of an Earendil node
+#[derive(Parser)]
+#[command(name = "earendil", author, version, about, long_about = None)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: CliCommand,
+}
+
+/// The command line, without running anything, for generating completions and help.
+pub fn cli() -> clap::Command {
+    Cli::command()
+}
+
+#[derive(Subcommand)]
+pub enum CliCommand {
+    /// Runs an Earendil daemon.
+    ///
+    /// Example: `earendil daemon --config earendil.yaml`
+    Daemon {
+        /// Path to the config file, or `-` to read it from stdin. If absent, the config is read from the EARENDIL_CONFIG environment variable instead.
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        config: Option<PathBuf>,
+        /// Start from a snapshot written by `control snapshot`, decrypting it with the passphrase in the EARENDIL_SNAPSHOT_PASSPHRASE environment variable.
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        restore: Option<PathBuf>,
+    },
+
+    /// Runs a control-protocol verb.
+    ///
+    /// Example: `earendil control --connect 127.0.0.1:18964 whoami`
+    Control {
+        #[arg(short, long, default_value = DEFAULT_CONTROL_ADDR)]
+        connect: SocketAddr,
+        #[command(subcommand)]
+        control_command: ControlCommand,
+    },
+
+    /// Prints a new seed phrase for a relay identity.
+    ///
+    /// Example: `earendil generate-seed`
+    GenerateSeed,
+
+    /// Writes a new relay identity to a file, without starting the daemon, and prints its fingerprint.
+    ///
+    /// Example: `earendil gen-identity relay.identity`
+    GenIdentity {
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+
+    /// Migrates the config file, identity, and state cache of a node set up by an older version, keeping the originals with a `.pre-migration` suffix. The daemon refuses to start until this is done. An interrupted migration can be run again to finish it.
+    ///
+    /// Example: `earendil migrate earendil.yaml --dry-run`
+    Migrate {
+        /// Path to the config file.
+        #[arg(value_hint = ValueHint::FilePath)]
+        config: PathBuf,
+        /// Only print what would change.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Checks that a config file is valid, without starting the daemon.
+    ///
+    /// Example: `earendil check-config earendil.yaml --diff`
+    CheckConfig {
+        /// Path to the config file, or `-` to read it from stdin.
+        #[arg(value_hint = ValueHint::FilePath)]
+        config: PathBuf,
+        /// Also print what would change if the running daemon were restarted with this config.
+        #[arg(long)]
+        diff: bool,
+        #[arg(short, long, default_value = DEFAULT_CONTROL_ADDR)]
+        connect: SocketAddr,
+    },
+
+    /// Prints a shell completion script.
+    ///
+    /// The static script completes subcommands, flags, and paths. With `--dynamic`, the script instead calls back into this binary, which also completes neighbor fingerprints and socket ids by asking the daemon on the default control port, if it's running.
+    ///
+    /// Example: `earendil completion bash --dynamic > ~/.local/share/bash-completion/completions/earendil`
+    Completion {
+        #[arg(value_enum)]
+        shell: Shell,
+        /// Complete values the daemon knows about too.
+        #[arg(long)]
+        dynamic: bool,
+    },
+}
+
+/// Writes the completion script for `shell`. Dynamic scripts call back into the binary at `bin`, with [COMPLETE_VAR] set.
+pub fn write_completion(
+    shell: Shell,
+    dynamic: bool,
+    bin: &str,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let mut cmd = cli();
+    let name = cmd.get_name().to_string();
+    if !dynamic {
+        clap_complete::generate(shell, &mut cmd, name, out);
+        return Ok(());
+    }
+    let completer: &dyn EnvCompleter = match shell {
+        Shell::Bash => &Bash,
+        Shell::Elvish => &Elvish,
+        Shell::Fish => &Fish,
+        Shell::PowerShell => &Powershell,
+        Shell::Zsh => &Zsh,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("no dynamic completion for {shell}"),
+            ))
+        }
+    };
+    completer.write_registration(COMPLETE_VAR, &name, bin, bin, out)
+}
+
+/// The environment variable dynamic completion scripts set when calling back into the binary.
+pub const COMPLETE_VAR: &str = "COMPLETE";
+
+/// Asks the daemon on the default control port something, for completion. Gives nothing if it isn't running or doesn't answer quickly.
+fn ask_daemon<T, E, Fut>(ask: impl FnOnce(ControlClient) -> Fut) -> Option<T>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    let control = ControlClient::connect(DEFAULT_CONTROL_ADDR.parse().ok()?, ControlEncoding::Json);
+    smolscale::block_on(ask(control).timeout(COMPLETION_TIMEOUT))?.ok()
+}
+
+fn relay_neighbors() -> Vec<CompletionCandidate> {
+    ask_daemon(|control| async move { control.list_neighbors().await })
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|neighbor| neighbor.right())
+        .map(|fingerprint| CompletionCandidate::new(fingerprint.to_string()))
+        .collect()
+}
+
+fn chat_neighbors() -> Vec<CompletionCandidate> {
+    ask_daemon(|control| async move { control.list_neighbors().await })
+        .unwrap_or_default()
+        .into_iter()
+        .map(|neighbor| match neighbor {
+            Either::Left(client_id) => CompletionCandidate::new(client_id.to_string()),
+            Either::Right(fingerprint) => CompletionCandidate::new(fingerprint.to_string()),
+        })
+        .collect()
+}
+
+fn socket_ids() -> Vec<CompletionCandidate> {
+    ask_daemon(|control| async move { control.skt_info().await })
+        .unwrap_or_default()
+        .into_iter()
+        .map(|socket| {
+            CompletionCandidate::new(socket.id.to_string()).help(Some(socket.name.into()))
+        })
+        .collect()
+}
 
 #[derive(Subcommand)]
 pub enum ControlCommand {
     /// Prints the information of all hosted havens
+    ///
+    /// Example: `earendil control havens-info`
     HavensInfo,
 
+    /// Prints how each haven's beacons have been going, which dial the haven the way a visitor would.
+    ///
+    /// Example: `earendil control haven-beacons`
+    HavenBeacons,
+
+    /// Binds an ephemeral haven under a fresh identity, printing its fingerprint. It stays up until unbound or until the daemon stops.
+    ///
+    /// Example: `earendil control bind-haven --port 8080 --rendezvous <FINGERPRINT> --upstream 127.0.0.1:3000`
+    BindHaven {
+        #[arg(long)]
+        port: u16,
+        /// A rendezvous relay for the haven. Repeat for backups.
+        #[arg(long, required = true, value_name = "FINGERPRINT", add = ArgValueCandidates::new(relay_neighbors))]
+        rendezvous: Vec<RelayFingerprint>,
+        /// Forward connections to this TCP address. Without it, the haven is a simple proxy.
+        #[arg(long)]
+        upstream: Option<SocketAddr>,
+    },
+
+    /// Takes down an ephemeral haven bound with `bind-haven`.
+    ///
+    /// Example: `earendil control unbind-haven --fingerprint <FINGERPRINT>`
+    UnbindHaven {
+        #[arg(long, value_name = "FINGERPRINT")]
+        fingerprint: HavenFingerprint,
+    },
+
     /// Send a GlobalRpc request to a destination.
+    ///
+    /// Example: `earendil control global-rpc --dest <FINGERPRINT> --method ping 42`
     GlobalRpc {
         #[arg(long)]
         id: Option<String>,
-        #[arg(short, long)]
+        #[arg(short, long, value_name = "FINGERPRINT", add = ArgValueCandidates::new(relay_neighbors))]
         dest: RelayFingerprint,
         #[arg(short, long)]
         method: String,
         args: Vec<String>,
+        /// Seal the request end-to-end to the destination's onion key.
+        #[arg(long)]
+        sealed: bool,
+        /// Ask relays to report dropping the request, so that the call fails fast instead of timing out.
+        #[arg(long)]
+        nack: bool,
+        /// The class to send the request as, which decides how long its route is.
+        #[arg(long, value_enum, default_value_t = MessageClass::Normal)]
+        class: MessageClass,
     },
 
     /// Insert a rendezvous haven locator into the dht.
+    ///
+    /// Example: `earendil control insert-rendezvous -i <IDENTITY_SK> -o <ONION_PK> -r <FINGERPRINT>`
     InsertRendezvous {
         #[arg(short, long)]
         identity_sk: String,
         #[arg(short, long)]
         onion_pk: String,
-        #[arg(short, long)]
+        #[arg(short, long, value_name = "FINGERPRINT", add = ArgValueCandidates::new(relay_neighbors))]
         rendezvous_fingerprint: RelayFingerprint,
     },
 
     /// Looks up a rendezvous haven locator.
+    ///
+    /// Example: `earendil control get-rendezvous --key <FINGERPRINT>`
     GetRendezvous {
-        #[arg(short, long)]
+        #[arg(short, long, value_name = "FINGERPRINT")]
+        key: HavenFingerprint,
+    },
+
+    /// Checks that enough DHT replicas hold a haven's current locator, exiting with an error if not.
+    ///
+    /// Example: `earendil control check-dht-replication --key <FINGERPRINT>`
+    CheckDhtReplication {
+        #[arg(short, long, value_name = "FINGERPRINT")]
+        key: HavenFingerprint,
+    },
+
+    /// Lists the relays that would hold a haven's locator under the current relay graph, without contacting them.
+    ///
+    /// Example: `earendil control dht-replicas --key <FINGERPRINT>`
+    DhtReplicas {
+        #[arg(short, long, value_name = "FINGERPRINT")]
         key: HavenFingerprint,
     },
 
     /// Dumps the relay graph in graphviz format.
+    ///
+    /// Example: `earendil control relay-graphviz | dot -Tsvg > graph.svg`
     RelayGraphviz,
 
+    /// Dumps the relay graph, by default as sorted text that can be diffed across runs.
+    ///
+    /// Example: `earendil control graph-dump --format json`
+    GraphDump {
+        #[arg(long, value_enum, default_value = "human")]
+        format: GraphDumpFormat,
+    },
+
+    /// Exports the relay graph for analysis in other tools, such as NetworkX or Gephi.
+    ///
+    /// Example: `earendil control export-graph --format graphml -o graph.graphml`
+    ExportGraph {
+        #[arg(long, value_enum, default_value = "graphml")]
+        format: GraphExportFormat,
+        /// Where to write the export. Defaults to stdout.
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+
+    /// Writes a self-contained HTML report on the relay graph, for sharing with people who don't run a node.
+    ///
+    /// Example: `earendil control graph-report -o report.html`
+    GraphReport {
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: PathBuf,
+        /// Show whole fingerprints, rather than just their first few characters.
+        #[arg(long)]
+        full_fingerprints: bool,
+    },
+
     /// Dumps my own routes.
+    ///
+    /// Example: `earendil control my-routes`
     MyRoutes,
 
+    /// Prints the node's internal counters, one per line.
+    ///
+    /// Example: `earendil control stats`
+    Stats,
+
+    /// Prints how full the relay graph is, and how many entries were evicted to keep it under its limits.
+    ///
+    /// Example: `earendil control graph-stats`
+    GraphStats,
+
+    /// Prints percentiles of how long this relay takes to forward packets, excluding intentional mix delay.
+    ///
+    /// Example: `earendil control forwarding-latency`
+    ForwardingLatency,
+
+    /// Prints the destinations with delayed sends in flight or waiting for a slot, the most backed up first.
+    ///
+    /// Example: `earendil control send-concurrency`
+    SendConcurrency,
+
+    /// Prints why our relays dropped packets we sent them lately.
+    ///
+    /// Example: `earendil control drop-reports`
+    DropReports,
+
+    /// Prints this node's identity and load.
+    ///
+    /// Example: `earendil control whoami`
+    Whoami,
+
+    /// Prints a summary of the network as this node sees it: relays known, neighbors, traffic, debts and DHT caches.
+    ///
+    /// Example: `earendil control network-summary`
+    NetworkSummary,
+
+    /// Prints how the daemon's long-lived tasks have been doing: how often they restarted, their last errors, and which keep failing.
+    ///
+    /// Example: `earendil control daemon-tasks`
+    DaemonTasks,
+
+    /// Prints how the relay graph splits into parts that can't reach each other, and how far away each watched relay is.
+    ///
+    /// Example: `earendil control partition-check`
+    PartitionCheck,
+
+    /// Replaces this relay's identity with a new one, cross-signed by the current one so that neighbors that pinned it follow along. Takes effect when the daemon restarts. Needs an identity file.
+    ///
+    /// Example: `earendil control rotate-identity --grace-secs 86400`
+    RotateIdentity {
+        /// How long, in seconds, neighbors that pinned the current identity accept the new one because of the rotation. At most 30 days.
+        #[arg(long, default_value_t = 7 * 24 * 3600)]
+        grace_secs: u64,
+    },
+
+    /// Prints the audit log of security-relevant events, oldest first.
+    ///
+    /// Example: `earendil control audit-log --after 100 --limit 20`
+    AuditLog {
+        /// The number of the first entry to print.
+        #[arg(long, default_value_t = 0)]
+        after: u64,
+        #[arg(long, default_value_t = 100)]
+        limit: u32,
+    },
+
+    /// Prints the signed head of the audit log as JSON, to keep somewhere the node can't reach and check the log against later.
+    ///
+    /// Example: `earendil control audit-log-head > head.json`
+    AuditLogHead,
+
+    /// Checks that no entry of the audit log was tampered with, and that none was removed from its end.
+    ///
+    /// Example: `earendil control verify-audit-log --against head.json`
+    VerifyAuditLog {
+        /// A head printed by `audit-log-head` earlier, which the log must still hold unless it was pruned since.
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        against: Option<PathBuf>,
+    },
+
+    /// Writes a snapshot of the node's state to a file, encrypted with the passphrase in the EARENDIL_SNAPSHOT_PASSPHRASE environment variable. `earendil daemon --restore` starts from it.
+    ///
+    /// Example: `earendil control snapshot -o node.snapshot`
+    Snapshot {
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        out: PathBuf,
+    },
+
+    /// Sends a message to a relay endpoint over N2R, and prints its reply.
+    ///
+    /// Example: `earendil control send-and-recv -d <FINGERPRINT>:12345 hello`
+    SendAndRecv {
+        #[arg(short, long)]
+        dest: RelayEndpoint,
+        content: String,
+        /// How long to wait for the reply.
+        #[arg(long, default_value_t = 30)]
+        timeout_secs: u64,
+    },
+
+    /// Prints how big messages to a relay endpoint may be, given the route we'd take to it.
+    ///
+    /// Example: `earendil control transport-limits -d <FINGERPRINT>:12345`
+    TransportLimits {
+        #[arg(short, long)]
+        destination: RelayEndpoint,
+    },
+
+    /// Prints how many reply blocks are left in each SURB bundle this node issued or imported.
+    ///
+    /// Example: `earendil control surb-bundles`
+    SurbBundles,
+
+    /// Imports a SURB bundle issued to this relay, so that its relay sockets can send to the bundle's anonymous endpoint. Each bundle can only be imported once.
+    ///
+    /// Example: `earendil control import-surb-bundle -i bundle.surbs`
+    ImportSurbBundle {
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        input: PathBuf,
+    },
+
+    /// Prints the sockets and havens whose sending can be rate limited, with their ids, limits, and how fast they sent lately.
+    ///
+    /// Example: `earendil control skt-info`
+    SktInfo,
+
+    /// Caps how fast a socket or haven sends, by the id `skt-info` prints. Without `--bytes-per-sec`, lifts its cap.
+    ///
+    /// Example: `earendil control set-rate-limit --id 3 --bytes-per-sec 100000`
+    SetRateLimit {
+        #[arg(long, add = ArgValueCandidates::new(socket_ids))]
+        id: u64,
+        #[arg(long)]
+        bytes_per_sec: Option<u64>,
+        /// Bytes that may be sent at once, after not sending any for a while.
+        #[arg(long, default_value_t = 64 * 1024)]
+        burst_bytes: u64,
+    },
+
+    /// Prints the GlobalRPC calls still waiting for their turn or a response, with their ids, destinations, states, and retries.
+    ///
+    /// Example: `earendil control pending-sends --socket-id 3`
+    PendingSends {
+        /// Only lists the sends from the socket with this id, as `skt-info` prints it.
+        #[arg(long, add = ArgValueCandidates::new(socket_ids))]
+        socket_id: Option<u64>,
+    },
+
+    /// Cancels a send by the id `pending-sends` prints, failing it and stopping its retries. Prints how it ended if it already finished.
+    ///
+    /// Example: `earendil control cancel-send --id 12`
+    CancelSend {
+        #[arg(long)]
+        id: u64,
+    },
+
+    /// Cancels every send from a socket, or only those to one destination.
+    ///
+    /// Example: `earendil control cancel-all --socket-id 3 --destination <FINGERPRINT>`
+    CancelAll {
+        #[arg(long, add = ArgValueCandidates::new(socket_ids))]
+        socket_id: u64,
+        #[arg(long, value_name = "FINGERPRINT")]
+        destination: Option<RelayFingerprint>,
+    },
+
+    /// Prints a line whenever a neighbor's debt crosses the warning threshold, in either direction, until interrupted.
+    ///
+    /// Example: `earendil control watch-debts`
+    WatchDebts,
+
+    /// Prints a line whenever we roam away from a relay our reply blocks were anchored at, until interrupted.
+    ///
+    /// Example: `earendil control watch-roaming`
+    WatchRoaming,
+
+    /// Writes a signed report of traffic, debts, and settlements with each neighbor to a file, for bookkeeping.
+    ///
+    /// Example: `earendil control report --start 1700000000 --end 1702592000 --format csv -o report.csv`
+    Report {
+        /// Start of the period, in seconds since the Unix epoch.
+        #[arg(long)]
+        start: u64,
+        /// End of the period, in seconds since the Unix epoch.
+        #[arg(long)]
+        end: u64,
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ReportFormat,
+        /// Also include the text of chat messages exchanged during the period.
+        #[arg(long)]
+        include_chats: bool,
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        out: PathBuf,
+    },
+
+    /// Prints our traffic, neighbor, and settlement totals per period.
+    ///
+    /// Example: `earendil control usage-history --start 1700000000 --resolution-secs 86400`
+    UsageHistory {
+        /// Start of the history, in seconds since the Unix epoch. Defaults to a day before the end.
+        #[arg(long)]
+        start: Option<u64>,
+        /// End of the history, in seconds since the Unix epoch. Defaults to now.
+        #[arg(long)]
+        end: Option<u64>,
+        /// How long each period is, in seconds. Must be a whole number of hours.
+        #[arg(long, default_value = "3600")]
+        resolution_secs: u64,
+    },
+
+    /// Drops an out route's link and stops dialing it, until it's resumed or the daemon restarts.
+    ///
+    /// Example: `earendil control pause-out-route --name main`
+    PauseOutRoute {
+        #[arg(long)]
+        name: String,
+    },
+
+    /// Resumes dialing a paused out route.
+    ///
+    /// Example: `earendil control resume-out-route --name main`
+    ResumeOutRoute {
+        #[arg(long)]
+        name: String,
+    },
+
+    /// Dials an out route once, and prints who answers, without adding it to the running routes.
+    ///
+    /// Example: `earendil control test-out-route --connect 203.0.113.5:19999 --tofu`
+    TestOutRoute {
+        #[arg(long)]
+        connect: String,
+        /// The fingerprint the relay must have.
+        #[arg(long, value_name = "FINGERPRINT", add = ArgValueCandidates::new(relay_neighbors))]
+        fingerprint: Option<RelayFingerprint>,
+        /// The sosistab3 cookie, if the route is obfuscated.
+        #[arg(long)]
+        cookie: Option<String>,
+        /// Check the fingerprint against the one pinned on first use, if no fingerprint is given.
+        #[arg(long)]
+        tofu: bool,
+    },
+
+    /// Streams dummy data to a haven for a while, and prints how fast it got there and how many packets were lost on the way. The haven must have `throughput_sink` on, and discards the data rather than handing it to its application.
+    ///
+    /// Example: `earendil control measure-throughput --dest haven:<FINGERPRINT>:1234 --duration-secs 10`
+    MeasureThroughput {
+        #[arg(long)]
+        dest: HavenEndpoint,
+        /// How long to send for, at most 60 seconds.
+        #[arg(long, default_value = "10")]
+        duration_secs: u64,
+    },
+
+    /// Prints the bootstrap phase, neighbors, routes, queues, and havens in one view. Exits with an error if any route failed or no route can be computed yet.
+    ///
+    /// Example: `earendil control status --watch`
+    Status {
+        /// Keep redrawing the view in place.
+        #[arg(long)]
+        watch: bool,
+        /// Seconds between redraws, at least 1.
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+    },
+
     /// Interactive chat for talking to immediate neighbors
+    ///
+    /// Example: `earendil control chat list`
     Chat {
         #[command(subcommand)]
         chat_command: ChatCommand,
     },
+
+    /// Settles debts with relay neighbors
+    ///
+    /// Example: `earendil control settle list`
+    Settle {
+        #[command(subcommand)]
+        settle_command: SettleCommand,
+    },
 }
 
 #[derive(Subcommand)]
 pub enum ChatCommand {
     /// print a summary of all your conversations
+    ///
+    /// Example: `earendil control chat list`
     List,
 
     /// start an interactive chat session with a neighbor
+    ///
+    /// Example: `earendil control chat start <FINGERPRINT>`
     Start {
         /// The fingerprint or client id of the neighbor to start a chat with.
         /// Accepts prefixes: TODO
+        #[arg(add = ArgValueCandidates::new(chat_neighbors))]
         neighbor: String,
     },
 
     /// Pulls conversation between you and neighbor
+    ///
+    /// Example: `earendil control chat get -s <FINGERPRINT>`
     Get {
-        #[arg(short, long)]
+        #[arg(short, long, add = ArgValueCandidates::new(chat_neighbors))]
         src: String,
     },
 
     /// Sends a single chat message to a neighbor
+    ///
+    /// Example: `earendil control chat send -d <FINGERPRINT> -m hello`
     Send {
-        #[arg(short, long)]
+        #[arg(short, long, add = ArgValueCandidates::new(chat_neighbors))]
         dest: String,
         #[arg(short, long)]
         msg: String,
     },
+
+    /// Lists the chat messages still waiting to be sent, with their ids
+    ///
+    /// Example: `earendil control chat unsent`
+    Unsent,
+
+    /// Cancels a chat message that hasn't been sent yet
+    ///
+    /// Example: `earendil control chat cancel -i 7`
+    Cancel {
+        /// The id shown by `unsent`.
+        #[arg(short, long)]
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SettleCommand {
+    /// Lists the settlements neighbors asked us to accept
+    ///
+    /// Example: `earendil control settle list`
+    List,
+
+    /// Asks a relay neighbor to take what we paid them outside of Earendil off our debt
+    ///
+    /// Example: `earendil control settle send -n 4b1f...c2 -a 1000`
+    Send {
+        #[arg(short, long)]
+        neighbor: RelayFingerprint,
+        /// In micromel.
+        #[arg(short, long)]
+        amount: u64,
+    },
+
+    /// Accepts the settlement a relay neighbor asked for
+    ///
+    /// Example: `earendil control settle accept -n 4b1f...c2`
+    Accept {
+        #[arg(short, long)]
+        neighbor: RelayFingerprint,
+    },
+
+    /// Rejects the settlement a relay neighbor asked for
+    ///
+    /// Example: `earendil control settle reject -n 4b1f...c2`
+    Reject {
+        #[arg(short, long)]
+        neighbor: RelayFingerprint,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+
+    use super::*;
+
+    #[test]
+    fn completions_cover_every_subcommand() {
+        for shell in Shell::value_variants() {
+            for dynamic in [false, true] {
+                let mut script = vec![];
+                write_completion(*shell, dynamic, "/usr/bin/earendil", &mut script).unwrap();
+                let script = String::from_utf8(script).unwrap();
+                if dynamic {
+                    assert!(script.contains("/usr/bin/earendil"), "{shell}");
+                    continue;
+                }
+                for subcommand in [
+                    "daemon",
+                    "control",
+                    "completion",
+                    "bind-haven",
+                    "set-rate-limit",
+                ] {
+                    assert!(script.contains(subcommand), "{shell} lacks {subcommand}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_command_has_an_example() {
+        fn check(cmd: &mut clap::Command, path: &str) {
+            for sub in cmd.get_subcommands_mut() {
+                if sub.get_name() == "help" {
+                    continue;
+                }
+                let path = format!("{path} {}", sub.get_name());
+                let help = sub.render_long_help().to_string();
+                assert!(
+                    help.contains(&format!("Example: `{path}")),
+                    "{path}:\n{help}"
+                );
+                check(sub, &path);
+            }
+        }
+        let mut cmd = cli();
+        cmd.build();
+        check(&mut cmd, "earendil");
+    }
 }
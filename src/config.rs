@@ -1,18 +1,37 @@
-use std::{collections::BTreeMap, io::Write, net::SocketAddr, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    io::Write,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use anyhow::Context;
 use earendil_crypt::{HavenIdentitySecret, RelayFingerprint, RelayIdentitySecret};
+use earendil_topology::GraphLimits;
 
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::fs::OpenOptions;
 use tracing::instrument;
 
-use crate::haven::HavenEndpoint;
+use crate::{
+    haven::HavenEndpoint,
+    micromel::Micromel,
+    n2r::{MessageClass, MAX_ROUTE_HOPS, MIN_ROUTE_HOPS},
+};
 
-/// A YAML-serializable configuration file
+mod builder;
+mod diff;
+pub use builder::ConfigFileBuilder;
+pub use diff::*;
+
+/// A YAML-serializable configuration file, or build one with [`ConfigFile::builder`].
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
+#[non_exhaustive]
 pub struct ConfigFile {
     /// Seed of the long-term identity. Must be long and difficult to guess!
     ///
@@ -45,14 +64,344 @@ pub struct ConfigFile {
     pub tcp_forwards: Vec<TcpForwardConfig>,
     /// where and how to start a socks5 proxy
     pub socks5: Option<Socks5Config>,
+    /// Where to serve a read-only HTTP status page
+    #[serde(default)]
+    pub status_page: Option<StatusPageConfig>,
     /// List of all haven configs
     #[serde(default)]
     pub havens: Vec<HavenConfig>,
+    /// Caps on the size of the in-memory relay graph
+    #[serde(default)]
+    pub relay_graph_limits: Option<GraphLimits>,
+    /// Only route over adjacencies refreshed within this many seconds
+    #[serde(default)]
+    pub max_routing_adjacency_age_secs: Option<u64>,
+    /// Periodically warn when the relay graph splits
+    #[serde(default)]
+    pub partition_watch: Option<PartitionWatchConfig>,
+    /// Ask neighbors about destinations we have no route to
+    #[serde(default)]
+    pub probe_route_misses: bool,
+    /// Forward every packet through the two closest neighbors rather than one
+    #[serde(default)]
+    pub redundant_forwarding: bool,
+    /// How many other routes to try when a hop is unusable or unreachable
+    #[serde(default)]
+    pub forward_route_retries: u32,
+    /// Send peeled packets on one at a time, in the order their mix delays ran out
+    #[serde(default)]
+    pub ordered_forwarding: bool,
+    /// How often, in seconds, a relay signs a fresh identity descriptor
+    #[serde(default = "default_identity_resign_secs")]
+    pub identity_resign_secs: u64,
+    /// Net debt, in micromel, above which we warn about a neighbor
+    #[serde(default)]
+    pub debt_warning_threshold: Option<Micromel>,
+    /// How far, in micromel, a neighbor's ledger may disagree with ours before we warn
+    #[serde(default)]
+    pub debt_divergence_tolerance: Option<Micromel>,
+    /// Only forward for neighbors that owe us nothing
+    #[serde(default)]
+    pub strict_prepay: bool,
+    /// Fraction of a neighbor's debt limit past which we drop a rising share of its packets
+    #[serde(default)]
+    pub graduated_admission: Option<f64>,
+    /// Under graduated admission, delay packets by up to this many milliseconds rather than dropping them
+    #[serde(default)]
+    pub graduated_admission_delay_ms: Option<u64>,
+    /// Prefer routes that got replies before
+    #[serde(default)]
+    pub route_learning: Option<RouteLearningConfig>,
+    /// Shed transit traffic when we can't keep up with peeling
+    #[serde(default)]
+    pub overload_shedding: Option<OverloadConfig>,
+    /// Caps how fast chats go out to each neighbor
+    #[serde(default)]
+    pub chat_rate_limit: Option<ChatRateLimit>,
+    /// Pace what we send to each neighbor at the rate its link can take
+    #[serde(default)]
+    pub link_pacing: Option<PacingConfig>,
+    /// Spill the mix delay queue to disk when traffic spikes
+    #[serde(default)]
+    pub delay_spill: Option<DelaySpillConfig>,
+    /// What to do with anonymous messages for sockets that aren't bound
+    #[serde(default)]
+    pub unhandled_messages: UnhandledPolicy,
+    /// Settings that trade performance for anonymity
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+    /// How many peeled packets may be on their way to any one next hop at once
+    #[serde(default)]
+    pub send_concurrency: SendConcurrencyConfig,
+    /// Compress large values in the state cache
+    #[serde(default)]
+    pub state_compression: Option<StateCompressionConfig>,
+    /// How the relay graph is encoded in the state cache and in snapshots
+    #[serde(default)]
+    pub relay_graph_format: GraphFormat,
+    /// How havens check that visitors can still reach them
+    #[serde(default)]
+    pub haven_beacon: HavenBeaconConfig,
+    /// Timeouts for global RPC calls and gossip
+    #[serde(default)]
+    pub rpc_timeouts: RpcTimeoutsConfig,
+    /// Caps how many packets each of our direct clients may hand us
+    #[serde(default)]
+    pub client_rate_limit: Option<ClientRateLimit>,
+    /// Whether to ask for and give reports of dropped packets
+    #[serde(default)]
+    pub drop_reports: DropReportsConfig,
+    /// How often links probe their neighbors
+    #[serde(default)]
+    pub liveness: LivenessConfig,
+    /// How long, in seconds, to keep audit log entries
+    #[serde(default)]
+    pub audit_log_retention_secs: Option<u64>,
+    /// How long, in milliseconds, a reply block anchor may be gone before we move off it
+    #[serde(default = "default_roaming_settle_ms")]
+    pub roaming_settle_ms: u64,
+    /// How many verified descriptors and locators to remember
+    #[serde(default = "default_verified_cache_capacity")]
+    pub verified_cache_capacity: u64,
+    /// How many days of hourly usage totals to keep
+    #[serde(default = "default_usage_history_days")]
+    pub usage_history_days: u64,
+    /// Latency histogram bucket bounds, in seconds, by histogram name
+    #[serde(default)]
+    pub histogram_buckets: BTreeMap<String, Vec<f64>>,
+    /// Rendezvous relays to register this relay's fallback haven at
+    #[serde(default)]
+    pub fallback_rendezvous: Vec<RelayFingerprint>,
+    /// Whether and how this relay serves as a rendezvous for havens
+    #[serde(default)]
+    pub rendezvous: RendezvousConfig,
+}
+
+impl Default for ConfigFile {
+    /// The config of an empty config file: a client with no routes.
+    fn default() -> Self {
+        serde_json::from_value(serde_json::json!({}))
+            .expect("an empty config file must parse into the defaults")
+    }
 }
 
 impl ConfigFile {
-    pub fn is_client(&self) -> bool {
-        self.identity.is_none()
+    /// Starts building a config from the defaults.
+    pub fn builder() -> ConfigFileBuilder {
+        ConfigFileBuilder::default()
+    }
+
+    /// Parses and validates a YAML config file.
+    pub fn from_yaml(yaml: &[u8]) -> anyhow::Result<Self> {
+        let json: serde_json::Value =
+            serde_yaml::from_slice(yaml).context("syntax error in config file")?;
+        let config: ConfigFile = serde_json::from_value(json)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks for mistakes that parsing alone doesn't catch.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut tcp_listens = HashSet::new();
+        let listens = std::iter::once(("control_listen".to_string(), self.control_listen))
+            .chain(
+                self.in_routes
+                    .iter()
+                    // QUIC in-routes listen over UDP
+                    .filter(|(_, route)| route.quic.is_none())
+                    .map(|(name, route)| {
+                        let what = format!("in_route {name}");
+                        let listen = route.listen.resolve().context(what.clone())?;
+                        anyhow::Ok((what, listen))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+            )
+            .chain(
+                self.tcp_forwards
+                    .iter()
+                    .map(|forward| ("tcp_forward".to_string(), forward.listen)),
+            )
+            .chain(
+                self.socks5
+                    .iter()
+                    .map(|socks5| ("socks5".to_string(), socks5.listen)),
+            )
+            .chain(
+                self.status_page
+                    .iter()
+                    .map(|page| ("status_page".to_string(), page.listen)),
+            );
+        for (what, listen) in listens {
+            if !tcp_listens.insert(listen) {
+                anyhow::bail!("{what} listens on {listen}, which is already taken");
+            }
+        }
+        if self.send_concurrency.per_destination == 0 {
+            anyhow::bail!("send_concurrency must allow at least one send per destination");
+        }
+        if self.haven_beacon.interval_secs > 0
+            && (self.haven_beacon.timeout_secs == 0
+                || self.haven_beacon.failures_before_reregister == 0)
+        {
+            anyhow::bail!(
+                "haven_beacon needs a nonzero timeout_secs and failures_before_reregister"
+            );
+        }
+        let timeouts = self.rpc_timeouts;
+        if [
+            timeouts.dht_insert_ms,
+            timeouts.dht_get_ms,
+            timeouts.forward_ms,
+            timeouts.replica_check_ms,
+            timeouts.gossip_ms,
+        ]
+        .contains(&0)
+        {
+            anyhow::bail!("rpc_timeouts must all be nonzero");
+        }
+        self.liveness
+            .validate()
+            .map_err(|e| anyhow::anyhow!("liveness: {e}"))?;
+        for (name, bounds) in self.histogram_buckets.iter() {
+            if bounds.is_empty()
+                || !bounds.iter().all(|bound| bound.is_finite() && *bound > 0.0)
+                || !bounds.windows(2).all(|pair| pair[0] < pair[1])
+            {
+                anyhow::bail!("histogram_buckets {name} must be positive and strictly increasing");
+            }
+        }
+        if let Some(compression) = &self.state_compression {
+            if !zstd::compression_level_range().contains(&compression.level) {
+                anyhow::bail!(
+                    "state_compression level must be between {} and {}, not {}",
+                    zstd::compression_level_range().start(),
+                    zstd::compression_level_range().end(),
+                    compression.level
+                );
+            }
+        }
+        if let Some(limit) = self.chat_rate_limit {
+            if limit.per_sec.is_nan() || limit.per_sec <= 0.0 || limit.burst == 0 {
+                anyhow::bail!("chat_rate_limit must allow at least some chats");
+            }
+        }
+        if let Some(limit) = self.client_rate_limit {
+            if limit.per_sec.is_nan() || limit.per_sec <= 0.0 || limit.burst == 0 {
+                anyhow::bail!("client_rate_limit must allow at least some packets");
+            }
+        }
+        if !self.fallback_rendezvous.is_empty() && self.identity.is_none() {
+            anyhow::bail!("only relays can register at a fallback_rendezvous");
+        }
+        for haven in self.havens.iter() {
+            if let Some(limit) = haven.rate_limit {
+                if limit.bytes_per_sec == 0 || limit.burst_bytes == 0 {
+                    anyhow::bail!(
+                        "the rate_limit of the haven on port {} must allow at least some bytes",
+                        haven.listen_port
+                    );
+                }
+            }
+        }
+        let route_admissions = self
+            .in_routes
+            .values()
+            .map(|route| route.graduated_admission)
+            .chain(
+                self.out_routes
+                    .values()
+                    .map(|route| route.graduated_admission),
+            );
+        for start in std::iter::once(self.graduated_admission)
+            .chain(route_admissions)
+            .flatten()
+        {
+            if !(0.0..=1.0).contains(&start) {
+                anyhow::bail!("graduated_admission must be between 0 and 1, not {start}");
+            }
+        }
+        let quic_routes = self
+            .in_routes
+            .iter()
+            .filter(|(_, route)| route.quic.is_some())
+            .map(|(name, route)| (format!("in_route {name}"), &route.obfs))
+            .chain(
+                self.out_routes
+                    .iter()
+                    .filter(|(_, route)| route.quic.is_some())
+                    .map(|(name, route)| (format!("out_route {name}"), &route.obfs)),
+            );
+        for (what, obfs) in quic_routes {
+            if !matches!(obfs, ObfsConfig::None { .. }) {
+                anyhow::bail!("{what} uses QUIC, which can't run under sosistab3");
+            }
+        }
+        for (name, route) in self.in_routes.iter() {
+            if let Some(quic) = &route.quic {
+                if quic.cert.is_some() != quic.key.is_some() {
+                    anyhow::bail!("in_route {name} needs both a QUIC cert and key, or neither");
+                }
+            }
+        }
+        if let Some(guards) = self.privacy.entry_guards {
+            if guards.count == 0 || guards.lifetime_secs == 0 {
+                anyhow::bail!("entry_guards needs a nonzero count and lifetime_secs");
+            }
+        }
+        if let Some(watch) = &self.partition_watch {
+            if watch.interval_secs == 0 || watch.max_nodes == 0 {
+                anyhow::bail!("partition_watch needs a nonzero interval_secs and max_nodes");
+            }
+            if !(0.0..=1.0).contains(&watch.shrink_ratio) {
+                anyhow::bail!("partition_watch.shrink_ratio must be between 0 and 1");
+            }
+        }
+        for class in MessageClass::ALL {
+            let routes = self.privacy.message_classes.get(class);
+            if routes.min_hops < MIN_ROUTE_HOPS
+                || routes.min_hops > routes.max_hops
+                || routes.max_hops > MAX_ROUTE_HOPS
+            {
+                anyhow::bail!(
+                    "{class} messages need {MIN_ROUTE_HOPS} <= min_hops <= max_hops <= {MAX_ROUTE_HOPS}"
+                );
+            }
+        }
+        // relays must re-sign before others take their onion keys for stale
+        if self.identity_resign_secs == 0
+            || self.identity_resign_secs >= earendil_topology::DESCRIPTOR_STALE_AFTER
+        {
+            anyhow::bail!(
+                "identity_resign_secs must be between 1 and {}",
+                earendil_topology::DESCRIPTOR_STALE_AFTER - 1
+            );
+        }
+        if self.drop_reports.per_sec.is_nan() || self.drop_reports.per_sec < 0.0 {
+            anyhow::bail!("drop_reports per_sec can't be negative");
+        }
+        if let Some(Identity::IdentityEnv(_)) = &self.identity {
+            // nothing may quietly fall back to writing state next to the identity that isn't there
+            let mut missing = vec![];
+            if self.state_cache.is_none() {
+                missing.push("state_cache (for debts, settlements, and TOFU pins)");
+            }
+            if !missing.is_empty() {
+                anyhow::bail!(
+                    "identity_env keeps the identity off disk, so persistent state must be configured explicitly. missing: {}",
+                    missing.join(", ")
+                );
+            }
+        }
+        let mut udp_listens = HashSet::new();
+        for forward in self.udp_forwards.iter() {
+            if !udp_listens.insert(forward.listen) {
+                anyhow::bail!(
+                    "udp_forward listens on {}, which is already taken",
+                    forward.listen
+                );
+            }
+        }
+        Ok(())
     }
 }
 
@@ -60,26 +409,1048 @@ fn default_control_listen() -> SocketAddr {
     "127.0.0.1:18964".parse().unwrap()
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLearningConfig {
+    /// Fraction of sends that ignore learned routes, so that we keep discovering new ones.
+    #[serde(default = "default_exploration_ratio")]
+    pub exploration_ratio: f64,
+    /// How long it takes for a route's score to decay by half.
+    #[serde(default = "default_score_half_life_secs")]
+    pub score_half_life_secs: u64,
+}
+
+fn default_identity_resign_secs() -> u64 {
+    600
+}
+
+fn default_roaming_settle_ms() -> u64 {
+    8000
+}
+
+fn default_verified_cache_capacity() -> u64 {
+    10_000
+}
+
+fn default_usage_history_days() -> u64 {
+    30
+}
+
+fn default_exploration_ratio() -> f64 {
+    0.1
+}
+
+fn default_score_half_life_secs() -> u64 {
+    3600
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OverloadConfig {
+    /// Processing backlog, in packets, above which we start shedding transit traffic.
+    #[serde(default = "default_yellow_backlog")]
+    pub yellow_backlog: usize,
+    /// Processing backlog, in packets, above which we also ask others to route around us.
+    #[serde(default = "default_red_backlog")]
+    pub red_backlog: usize,
+    /// p90 forwarding latency, in milliseconds, above which we start shedding transit traffic.
+    #[serde(default = "default_yellow_latency_ms")]
+    pub yellow_latency_ms: u64,
+    /// p90 forwarding latency, in milliseconds, above which we also ask others to route around us.
+    #[serde(default = "default_red_latency_ms")]
+    pub red_latency_ms: u64,
+    /// Fraction of transit packets dropped at the yellow thresholds.
+    #[serde(default = "default_min_shed_ratio")]
+    pub min_shed_ratio: f64,
+    /// Fraction of transit packets dropped at or above the red thresholds.
+    #[serde(default = "default_max_shed_ratio")]
+    pub max_shed_ratio: f64,
+    /// Fraction of a state's thresholds that load must drop below to leave it.
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f64,
+}
+
+fn default_yellow_backlog() -> usize {
+    16
+}
+
+fn default_red_backlog() -> usize {
+    64
+}
+
+fn default_yellow_latency_ms() -> u64 {
+    50
+}
+
+fn default_red_latency_ms() -> u64 {
+    200
+}
+
+fn default_min_shed_ratio() -> f64 {
+    0.1
+}
+
+fn default_max_shed_ratio() -> f64 {
+    0.9
+}
+
+fn default_hysteresis() -> f64 {
+    0.8
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PacingConfig {
+    /// How many milliseconds of data may pile up in the buffers below us.
+    #[serde(default = "default_target_queue_ms")]
+    pub target_queue_ms: u64,
+    /// Bursts of up to this many bytes are never delayed, however slow the link seems.
+    #[serde(default = "default_burst_floor_bytes")]
+    pub burst_floor_bytes: usize,
+    /// We never pace a link slower than this, in bytes per second, whatever we measure.
+    #[serde(default = "default_min_rate_bytes_per_sec")]
+    pub min_rate_bytes_per_sec: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DelaySpillConfig {
+    /// Where to keep spilled packets.
+    pub path: PathBuf,
+    /// How many bytes of delayed packets to hold in memory before spilling to disk.
+    pub memory_limit_bytes: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StateCompressionConfig {
+    /// The zstd compression level. Higher levels compress better, but take longer.
+    #[serde(default = "default_compression_level")]
+    pub level: i32,
+    /// Values smaller than this many bytes are stored uncompressed.
+    #[serde(default = "default_compression_min_bytes")]
+    pub min_bytes: usize,
+}
+
+/// An encoding of the persisted relay graph.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphFormat {
+    /// Compact, but only readable by code with the same idea of the graph's layout.
+    #[default]
+    Stdcode,
+    /// Self-describing, so that other tools can read it too.
+    Cbor,
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_compression_min_bytes() -> usize {
+    1024
+}
+
+fn default_target_queue_ms() -> u64 {
+    20
+}
+
+fn default_burst_floor_bytes() -> usize {
+    65536
+}
+
+fn default_min_rate_bytes_per_sec() -> u64 {
+    16000
+}
+
+/// What happens to a message that arrives for a socket that isn't bound.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnhandledPolicy {
+    #[default]
+    Drop,
+    /// Drop the message, but log where it was going.
+    Log,
+    /// Hold on to the message, in case a socket binds to where it was going soon after.
+    Buffer {
+        /// How many messages to hold at most, for docks and endpoints each.
+        max_messages: usize,
+        /// How long to hold each message, in seconds.
+        ttl_secs: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ChatRateLimit {
+    /// Chats sent to each neighbor per second, on average.
+    #[serde(default = "default_chats_per_sec")]
+    pub per_sec: f64,
+    /// Chats that may be sent to a neighbor at once, after not sending it any for a while.
+    #[serde(default = "default_chat_burst")]
+    pub burst: u32,
+}
+
+fn default_chats_per_sec() -> f64 {
+    1.0
+}
+
+fn default_chat_burst() -> u32 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClientRateLimit {
+    /// Packets each direct client may send us per second, on average.
+    #[serde(default = "default_client_packets_per_sec")]
+    pub per_sec: f64,
+    /// Packets a direct client may send us at once, after not sending any for a while.
+    #[serde(default = "default_client_packet_burst")]
+    pub burst: u32,
+}
+
+fn default_client_packets_per_sec() -> f64 {
+    500.0
+}
+
+fn default_client_packet_burst() -> u32 {
+    1000
+}
+
+/// Caps how fast a socket or haven sends.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SendRateLimit {
+    /// Bytes sent per second, on average.
+    pub bytes_per_sec: u64,
+    /// Bytes that may be sent at once, after not sending any for a while.
+    #[serde(default = "default_send_burst_bytes")]
+    pub burst_bytes: u64,
+}
+
+fn default_send_burst_bytes() -> u64 {
+    64 * 1024
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RendezvousConfig {
+    /// Take haven registrations and forward their traffic.
+    #[serde(default = "default_rendezvous_enabled")]
+    pub enabled: bool,
+    /// The most havens we take registrations from.
+    #[serde(default = "default_max_registered_havens")]
+    pub max_registered_havens: usize,
+    /// Caps how many bytes per second of haven traffic we forward.
+    #[serde(default)]
+    pub max_forward_bandwidth: Option<u64>,
+}
+
+impl Default for RendezvousConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_rendezvous_enabled(),
+            max_registered_havens: default_max_registered_havens(),
+            max_forward_bandwidth: None,
+        }
+    }
+}
+
+fn default_rendezvous_enabled() -> bool {
+    true
+}
+
+fn default_max_registered_havens() -> usize {
+    100_000
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DropReportsConfig {
+    /// As a client, ask the relays we connect to for drop reports.
+    #[serde(default)]
+    pub request: bool,
+    /// As a relay, give drop reports to the clients that ask.
+    #[serde(default = "default_serve_drop_reports")]
+    pub serve: bool,
+    /// Drop reports each client may be sent per second, on average.
+    #[serde(default = "default_drop_reports_per_sec")]
+    pub per_sec: f64,
+    /// Drop reports a client may be sent at once, after none for a while.
+    #[serde(default = "default_drop_report_burst")]
+    pub burst: u32,
+}
+
+impl Default for DropReportsConfig {
+    fn default() -> Self {
+        Self {
+            request: false,
+            serve: default_serve_drop_reports(),
+            per_sec: default_drop_reports_per_sec(),
+            burst: default_drop_report_burst(),
+        }
+    }
+}
+
+fn default_serve_drop_reports() -> bool {
+    true
+}
+
+fn default_drop_reports_per_sec() -> f64 {
+    20.0
+}
+
+fn default_drop_report_burst() -> u32 {
+    100
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SendConcurrencyConfig {
+    /// How many sends to one destination may run at once.
+    #[serde(default = "default_per_destination")]
+    pub per_destination: usize,
+    /// How long, in seconds, an idle destination is remembered.
+    #[serde(default = "default_idle_expiry_secs")]
+    pub idle_expiry_secs: u64,
+}
+
+impl Default for SendConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            per_destination: default_per_destination(),
+            idle_expiry_secs: default_idle_expiry_secs(),
+        }
+    }
+}
+
+fn default_per_destination() -> usize {
+    32
+}
+
+fn default_idle_expiry_secs() -> u64 {
+    60
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionWatchConfig {
+    /// How often to check, in seconds.
+    #[serde(default = "default_partition_interval_secs")]
+    pub interval_secs: u64,
+    /// Relays to warn about when we have no route to them.
+    #[serde(default)]
+    #[serde_as(as = "Vec<serde_with::DisplayFromStr>")]
+    pub watch: Vec<RelayFingerprint>,
+    /// Only adjacencies refreshed within this many seconds count as connecting two relays.
+    #[serde(default = "default_partition_adjacency_age_secs")]
+    pub max_adjacency_age_secs: u64,
+    /// Warn when the part of the graph we're in shrinks below this fraction.
+    #[serde(default = "default_partition_shrink_ratio")]
+    pub shrink_ratio: f64,
+    /// The most relays a check scans, so that huge graphs can't make it slow.
+    #[serde(default = "default_partition_max_nodes")]
+    pub max_nodes: usize,
+}
+
+impl Default for PartitionWatchConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_partition_interval_secs(),
+            watch: vec![],
+            max_adjacency_age_secs: default_partition_adjacency_age_secs(),
+            shrink_ratio: default_partition_shrink_ratio(),
+            max_nodes: default_partition_max_nodes(),
+        }
+    }
+}
+
+fn default_partition_interval_secs() -> u64 {
+    60
+}
+
+fn default_partition_adjacency_age_secs() -> u64 {
+    earendil_topology::ROUTE_TIMEOUT / 2
+}
+
+fn default_partition_shrink_ratio() -> f64 {
+    0.5
+}
+
+fn default_partition_max_nodes() -> usize {
+    10_000
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HavenBeaconConfig {
+    /// About how often each haven dials itself, in seconds, or 0 for never.
+    #[serde(default = "default_beacon_interval_secs")]
+    pub interval_secs: u64,
+    /// How long a beacon may take to come back before it counts as failed, in seconds.
+    #[serde(default = "default_beacon_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Failed beacons in a row after which the haven registers again.
+    #[serde(default = "default_failures_before_reregister")]
+    pub failures_before_reregister: u32,
+}
+
+impl Default for HavenBeaconConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_beacon_interval_secs(),
+            timeout_secs: default_beacon_timeout_secs(),
+            failures_before_reregister: default_failures_before_reregister(),
+        }
+    }
+}
+
+/// Timeouts for global RPC calls, in milliseconds.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RpcTimeoutsConfig {
+    /// Inserting a haven locator into each of its DHT replicas.
+    #[serde(default = "default_dht_insert_ms")]
+    pub dht_insert_ms: u64,
+    /// Looking up a haven locator at each of its DHT replicas.
+    #[serde(default = "default_dht_get_ms")]
+    pub dht_get_ms: u64,
+    /// Registering a haven with a rendezvous point, or deregistering it.
+    #[serde(default = "default_forward_ms")]
+    pub forward_ms: u64,
+    /// Asking each DHT replica whether it holds a haven's locator.
+    #[serde(default = "default_replica_check_ms")]
+    pub replica_check_ms: u64,
+    /// Each call a gossip round makes to a neighbor.
+    #[serde(default = "default_gossip_ms")]
+    pub gossip_ms: u64,
+    /// A whole gossip round with a neighbor.
+    #[serde(default = "default_gossip_round_ms")]
+    pub gossip_round_ms: u64,
+}
+
+impl RpcTimeoutsConfig {
+    pub fn dht_insert(&self) -> Duration {
+        Duration::from_millis(self.dht_insert_ms)
+    }
+
+    pub fn dht_get(&self) -> Duration {
+        Duration::from_millis(self.dht_get_ms)
+    }
+
+    pub fn forward(&self) -> Duration {
+        Duration::from_millis(self.forward_ms)
+    }
+
+    pub fn replica_check(&self) -> Duration {
+        Duration::from_millis(self.replica_check_ms)
+    }
+
+    pub fn gossip(&self) -> Duration {
+        Duration::from_millis(self.gossip_ms)
+    }
+
+    pub fn gossip_round(&self) -> Duration {
+        Duration::from_millis(self.gossip_round_ms)
+    }
+}
+
+impl Default for RpcTimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            dht_insert_ms: default_dht_insert_ms(),
+            dht_get_ms: default_dht_get_ms(),
+            forward_ms: default_forward_ms(),
+            replica_check_ms: default_replica_check_ms(),
+            gossip_ms: default_gossip_ms(),
+            gossip_round_ms: default_gossip_round_ms(),
+        }
+    }
+}
+
+/// Probing more often than this adds load without telling us anything new.
+pub const MIN_PROBE_INTERVAL_MS: u64 = 100;
+
+/// Probing less often than this leaves NATs and the link's round-trip time to go stale.
+pub const MAX_PROBE_INTERVAL_MS: u64 = 10 * 60 * 1000;
+
+/// How links probe their neighbors.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LivenessConfig {
+    /// How often to probe the neighbor.
+    #[serde(default = "default_probe_interval_ms")]
+    pub probe_interval_ms: u64,
+    /// How long a probe may go unanswered before it counts as failed.
+    #[serde(default = "default_probe_timeout_ms")]
+    pub probe_timeout_ms: u64,
+    /// How many probes in a row may go unanswered before the neighbor is declared dead.
+    #[serde(default)]
+    pub max_failed_probes: Option<u32>,
+}
+
+impl LivenessConfig {
+    pub fn probe_interval(&self) -> Duration {
+        Duration::from_millis(self.probe_interval_ms)
+    }
+
+    pub fn probe_timeout(&self) -> Duration {
+        Duration::from_millis(self.probe_timeout_ms)
+    }
+
+    /// About the longest a dead neighbor goes unnoticed, if it is ever noticed.
+    pub fn detection_window(&self) -> Option<Duration> {
+        self.max_failed_probes
+            .map(|max| (self.probe_interval() + self.probe_timeout()) * max)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(MIN_PROBE_INTERVAL_MS..=MAX_PROBE_INTERVAL_MS).contains(&self.probe_interval_ms) {
+            return Err(format!(
+                "probe_interval_ms must be between {MIN_PROBE_INTERVAL_MS} and {MAX_PROBE_INTERVAL_MS}, not {}",
+                self.probe_interval_ms
+            ));
+        }
+        if self.probe_timeout_ms == 0 {
+            return Err("probe_timeout_ms must be nonzero".into());
+        }
+        if self.max_failed_probes == Some(0) {
+            return Err("max_failed_probes must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval_ms: default_probe_interval_ms(),
+            probe_timeout_ms: default_probe_timeout_ms(),
+            max_failed_probes: None,
+        }
+    }
+}
+
+fn default_probe_interval_ms() -> u64 {
+    5_000
+}
+
+fn default_probe_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_dht_insert_ms() -> u64 {
+    30_000
+}
+
+fn default_dht_get_ms() -> u64 {
+    30_000
+}
+
+fn default_forward_ms() -> u64 {
+    10_000
+}
+
+fn default_replica_check_ms() -> u64 {
+    10_000
+}
+
+fn default_gossip_ms() -> u64 {
+    5_000
+}
+
+fn default_gossip_round_ms() -> u64 {
+    30_000
+}
+
+fn default_beacon_interval_secs() -> u64 {
+    240
+}
+
+fn default_beacon_timeout_secs() -> u64 {
+    30
+}
+
+fn default_failures_before_reregister() -> u32 {
+    2
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PrivacyConfig {
+    /// Where the reply blocks (SURBs) we hand out end up.
+    pub surb_anchor: Option<SurbAnchor>,
+    /// Start every forward route at one of a few long-lived relays
+    pub entry_guards: Option<EntryGuardConfig>,
+    /// Route lengths and delays for each class of message
+    #[serde(default)]
+    pub message_classes: MessageClassesConfig,
+}
+
+/// Route parameters for each [MessageClass].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MessageClassesConfig {
+    #[serde(default)]
+    pub interactive: ClassRouteConfig,
+    #[serde(default)]
+    pub normal: ClassRouteConfig,
+    #[serde(default)]
+    pub background: ClassRouteConfig,
+}
+
+impl MessageClassesConfig {
+    pub fn get(&self, class: MessageClass) -> ClassRouteConfig {
+        match class {
+            MessageClass::Interactive => self.interactive,
+            MessageClass::Normal => self.normal,
+            MessageClass::Background => self.background,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClassRouteConfig {
+    /// The fewest relays a forward route passes through.
+    #[serde(default = "default_class_hops")]
+    pub min_hops: usize,
+    /// The most relays a forward route passes through.
+    #[serde(default = "default_class_hops")]
+    pub max_hops: usize,
+    /// The mean delay, in milliseconds, each relay holds a message for.
+    #[serde(default = "default_class_mean_delay_ms")]
+    pub mean_delay_ms: u16,
+}
+
+impl Default for ClassRouteConfig {
+    fn default() -> Self {
+        Self {
+            min_hops: default_class_hops(),
+            max_hops: default_class_hops(),
+            mean_delay_ms: default_class_mean_delay_ms(),
+        }
+    }
+}
+
+fn default_class_hops() -> usize {
+    2
+}
+
+fn default_class_mean_delay_ms() -> u16 {
+    earendil_packet::LOW_LATENCY_MS
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EntryGuardConfig {
+    /// How many entry guards to keep.
+    #[serde(default = "default_entry_guard_count")]
+    pub count: usize,
+    /// How long to keep using a guard before picking another.
+    #[serde(default = "default_entry_guard_lifetime_secs")]
+    pub lifetime_secs: u64,
+}
+
+fn default_entry_guard_count() -> usize {
+    3
+}
+
+fn default_entry_guard_lifetime_secs() -> u64 {
+    30 * 86400
+}
+
+/// The relay that the last hop of our reply blocks goes to, before reaching us.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SurbAnchor {
+    /// Ourselves, which is only possible for relays.
+    #[serde(rename = "self")]
+    Myself,
+    /// A randomly chosen relay neighbor.
+    RandomNeighbor,
+    /// A specific relay neighbor.
+    Fixed(#[serde_as(as = "serde_with::DisplayFromStr")] RelayFingerprint),
+}
+
+#[serde_as]
 #[derive(Serialize, Deserialize, Clone)]
 pub struct InRouteConfig {
-    pub listen: SocketAddr,
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub listen: ListenAddr,
     pub obfs: ObfsConfig,
+    /// Whether to pace links accepted on this route, if `link_pacing` is configured.
+    #[serde(default = "default_pacing")]
+    pub pacing: bool,
+    /// Overrides `strict_prepay` for neighbors that connect through this route.
+    #[serde(default)]
+    pub strict_prepay: Option<bool>,
+    /// Overrides `graduated_admission` for neighbors that connect through this route.
+    #[serde(default)]
+    pub graduated_admission: Option<f64>,
+    /// Listen for QUIC rather than TCP, which requires `obfs: none`.
+    #[serde(default)]
+    pub quic: Option<QuicListenConfig>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "snake_case")]
+impl InRouteConfig {
+    /// An in-route on `listen`, with everything else at its default.
+    pub fn new(listen: ListenAddr, obfs: ObfsConfig) -> Self {
+        Self {
+            listen,
+            obfs,
+            pacing: default_pacing(),
+            strict_prepay: None,
+            graduated_admission: None,
+            quic: None,
+        }
+    }
+}
+
+fn default_pacing() -> bool {
+    true
+}
+
+/// How a QUIC in-route proves who it is.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct QuicListenConfig {
+    /// A PEM file with the certificate to present, rather than a self-signed one.
+    #[serde(default)]
+    pub cert: Option<PathBuf>,
+    /// A PEM file with the certificate's private key.
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+}
+
+impl QuicListenConfig {
+    /// The fingerprint of the certificate an in-route with this config presents.
+    pub fn fingerprint(&self, identity: Option<&RelayIdentitySecret>) -> anyhow::Result<[u8; 32]> {
+        crate::daemon::quic_fingerprint(identity, self)
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct QuicConnectConfig {
+    /// The fingerprint of the certificate the in-route presents.
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub server_fingerprint: [u8; 32],
+}
+
+/// Where an in-route listens, possibly naming a network interface.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    /// A socket address.
+    Socket(SocketAddr),
+    Scoped {
+        ip: Ipv6Addr,
+        interface: String,
+        port: u16,
+    },
+    Interface {
+        name: String,
+        port: u16,
+    },
+}
+
+impl ListenAddr {
+    pub fn port(&self) -> u16 {
+        match self {
+            ListenAddr::Socket(addr) => addr.port(),
+            ListenAddr::Scoped { port, .. } | ListenAddr::Interface { port, .. } => *port,
+        }
+    }
+
+    /// Resolves the interfaces this address names to a socket address.
+    pub fn resolve(&self) -> anyhow::Result<SocketAddr> {
+        match self {
+            ListenAddr::Socket(addr) => Ok(*addr),
+            ListenAddr::Scoped {
+                ip,
+                interface,
+                port,
+            } => {
+                let index = interface_addrs(interface)?
+                    .iter()
+                    .find_map(|iface| iface.index)
+                    .with_context(|| format!("interface {interface} has no index"))?;
+                Ok(SocketAddrV6::new(*ip, *port, 0, index).into())
+            }
+            ListenAddr::Interface { name, port } => {
+                let addrs = interface_addrs(name)?;
+                // IPv4 addresses need no scope, so they're the least surprising to listen on
+                let iface = addrs
+                    .iter()
+                    .find(|iface| iface.ip().is_ipv4())
+                    .or_else(|| addrs.first())
+                    .with_context(|| format!("interface {name} has no addresses"))?;
+                Ok(match iface.ip() {
+                    IpAddr::V6(ip) if iface.is_link_local() => {
+                        SocketAddrV6::new(ip, *port, 0, iface.index.unwrap_or_default()).into()
+                    }
+                    ip => SocketAddr::new(ip, *port),
+                })
+            }
+        }
+    }
+}
+
+/// Every address of the named interface, failing if there is no such interface.
+fn interface_addrs(name: &str) -> anyhow::Result<Vec<if_addrs::Interface>> {
+    let addrs: Vec<if_addrs::Interface> = if_addrs::get_if_addrs()
+        .context("cannot list network interfaces")?
+        .into_iter()
+        .filter(|iface| iface.name == name)
+        .collect();
+    if addrs.is_empty() {
+        anyhow::bail!("no network interface named {name}");
+    }
+    Ok(addrs)
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Socket(addr)
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Socket(addr) => write!(f, "{addr}"),
+            ListenAddr::Scoped {
+                ip,
+                interface,
+                port,
+            } => write!(f, "[{ip}%{interface}]:{port}"),
+            ListenAddr::Interface { name, port } => write!(f, "{name}:{port}"),
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(ListenAddr::Socket(addr));
+        }
+        let (host, port) = s
+            .rsplit_once(':')
+            .context("listen address must end in a port")?;
+        let port: u16 = port.parse().context("invalid listen port")?;
+        if let Some(scoped) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            let (ip, interface) = scoped
+                .split_once('%')
+                .context("invalid IPv6 listen address")?;
+            return Ok(ListenAddr::Scoped {
+                ip: ip.parse().context("invalid IPv6 listen address")?,
+                interface: interface.to_string(),
+                port,
+            });
+        }
+        if host.is_empty() || host.contains(|c: char| c == ':' || c == '[' || c == ']') {
+            anyhow::bail!("{s} is neither a socket address nor an interface name and port");
+        }
+        Ok(ListenAddr::Interface {
+            name: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// How a route disguises its links. Written either in short form, as `none` or `sosistab3: <cookie>`, or in full form, which can also override transport parameters:
+///
+/// ```yaml
+/// obfs:
+///   mode: sosistab3
+///   cookie: <cookie>
+///   params:
+///     mtu: 1200
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "ObfsConfigRepr", into = "ObfsConfigRepr")]
 pub enum ObfsConfig {
+    None {
+        params: Option<ObfsParams>,
+    },
+    Sosistab3 {
+        cookie: String,
+        params: Option<ObfsParams>,
+    },
+}
+
+impl ObfsConfig {
+    /// The route's transport parameters, with defaults for whatever it doesn't override.
+    pub fn params(&self) -> ObfsParams {
+        match self {
+            ObfsConfig::None { params } | ObfsConfig::Sosistab3 { params, .. } => {
+                params.clone().unwrap_or_default()
+            }
+        }
+    }
+}
+
+/// The smallest and largest MTU a route may set.
+pub const MIN_OBFS_MTU: usize = 64;
+pub const MAX_OBFS_MTU: usize = 65535;
+
+/// Transport parameters that a route can override.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ObfsParams {
+    /// The most bytes the link hands the transport at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<usize>,
+}
+
+impl ObfsParams {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(mtu) = self.mtu {
+            if !(MIN_OBFS_MTU..=MAX_OBFS_MTU).contains(&mtu) {
+                return Err(format!(
+                    "mtu must be between {MIN_OBFS_MTU} and {MAX_OBFS_MTU}, not {mtu}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum ObfsConfigRepr {
+    Short(ShortObfsConfig),
+    Full {
+        mode: ObfsMode,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cookie: Option<String>,
+        /// Kept as JSON until the mode is known.
+        #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+        params: serde_json::Value,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ShortObfsConfig {
     None,
     Sosistab3(String),
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ObfsMode {
+    None,
+    Sosistab3,
+}
+
+impl TryFrom<ObfsConfigRepr> for ObfsConfig {
+    type Error = String;
+
+    fn try_from(repr: ObfsConfigRepr) -> Result<Self, Self::Error> {
+        match repr {
+            ObfsConfigRepr::Short(ShortObfsConfig::None) => Ok(ObfsConfig::None { params: None }),
+            ObfsConfigRepr::Short(ShortObfsConfig::Sosistab3(cookie)) => {
+                Ok(ObfsConfig::Sosistab3 {
+                    cookie,
+                    params: None,
+                })
+            }
+            ObfsConfigRepr::Full {
+                mode,
+                cookie,
+                params,
+            } => {
+                let params = if params.is_null() {
+                    None
+                } else {
+                    let params: ObfsParams = serde_json::from_value(params)
+                        .map_err(|err| format!("invalid obfs params: {err}"))?;
+                    params
+                        .validate()
+                        .map_err(|err| format!("invalid obfs params: {err}"))?;
+                    Some(params)
+                };
+                match (mode, cookie) {
+                    (ObfsMode::None, None) => Ok(ObfsConfig::None { params }),
+                    (ObfsMode::None, Some(_)) => Err("obfs mode none takes no cookie".into()),
+                    (ObfsMode::Sosistab3, Some(cookie)) => {
+                        Ok(ObfsConfig::Sosistab3 { cookie, params })
+                    }
+                    (ObfsMode::Sosistab3, None) => Err("obfs mode sosistab3 needs a cookie".into()),
+                }
+            }
+        }
+    }
+}
+
+impl From<ObfsConfig> for ObfsConfigRepr {
+    fn from(obfs: ObfsConfig) -> Self {
+        let (mode, cookie, params) = match obfs {
+            ObfsConfig::None { params: None } => {
+                return ObfsConfigRepr::Short(ShortObfsConfig::None)
+            }
+            ObfsConfig::Sosistab3 {
+                cookie,
+                params: None,
+            } => return ObfsConfigRepr::Short(ShortObfsConfig::Sosistab3(cookie)),
+            ObfsConfig::None { params } => (ObfsMode::None, None, params),
+            ObfsConfig::Sosistab3 { cookie, params } => (ObfsMode::Sosistab3, Some(cookie), params),
+        };
+        ObfsConfigRepr::Full {
+            mode,
+            cookie,
+            params: serde_json::to_value(params).expect("obfs params always serialize"),
+        }
+    }
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Clone)]
 pub struct OutRouteConfig {
     pub connect: String,
-    #[serde_as(as = "serde_with::DisplayFromStr")]
-    pub fingerprint: RelayFingerprint,
+    /// The fingerprint the relay at `connect` must have.
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub fingerprint: Option<RelayFingerprint>,
     pub obfs: ObfsConfig,
+    /// Pin the first fingerprint seen at `connect`.
+    #[serde(default)]
+    pub tofu: bool,
+    /// Whether to pace this route's link, if `link_pacing` is configured.
+    #[serde(default = "default_pacing")]
+    pub pacing: bool,
+    /// Overrides `strict_prepay` for the neighbor at the other end of this route.
+    #[serde(default)]
+    pub strict_prepay: Option<bool>,
+    /// Overrides `graduated_admission` for the neighbor at the other end of this route.
+    #[serde(default)]
+    pub graduated_admission: Option<f64>,
+    /// Connect over QUIC rather than TCP, which requires `obfs: none`.
+    #[serde(default)]
+    pub quic: Option<QuicConnectConfig>,
+}
+
+impl OutRouteConfig {
+    /// An out-route that pins whichever relay is at `connect` the first time.
+    pub fn new(connect: impl Into<String>, obfs: ObfsConfig) -> Self {
+        Self {
+            connect: connect.into(),
+            fingerprint: None,
+            obfs,
+            tofu: true,
+            pacing: default_pacing(),
+            strict_prepay: None,
+            graduated_admission: None,
+            quic: None,
+        }
+    }
 }
 
 #[serde_as]
@@ -120,6 +1491,15 @@ pub enum Socks5Fallback {
     },
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StatusPageConfig {
+    pub listen: SocketAddr,
+    /// The token that requests must bear to see the full page.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
 #[serde_as]
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct HavenConfig {
@@ -128,7 +1508,17 @@ pub struct HavenConfig {
     pub listen_port: u16,
     #[serde_as(as = "serde_with::DisplayFromStr")]
     pub rendezvous: RelayFingerprint,
+    /// Further rendezvous points to register with
+    #[serde_as(as = "Vec<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub backup_rendezvous: Vec<RelayFingerprint>,
     pub handler: HavenHandler,
+    /// Caps how fast the haven sends to all of its visitors together
+    #[serde(default)]
+    pub rate_limit: Option<SendRateLimit>,
+    /// Answer throughput tests, discarding their data
+    #[serde(default)]
+    pub throughput_sink: bool,
 }
 
 #[serde_as]
@@ -140,11 +1530,13 @@ pub enum HavenHandler {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-/// A configuration for an identity, specified either as a human-readable seed that will be passed through a KDF, or a file that stores the raw binary bytes of the identity secret.
+/// A configuration for an identity, specified either as a human-readable seed that will be passed through a KDF, a file that stores the raw binary bytes of the identity secret, or an environment variable that stores them hex-encoded.
 #[serde(rename_all = "snake_case")]
 pub enum Identity {
     IdentitySeed(String),
     IdentityFile(PathBuf),
+    /// The name of an environment variable holding the hex-encoded identity secret.
+    IdentityEnv(String),
 }
 
 impl Identity {
@@ -168,20 +1560,13 @@ impl Identity {
                         tracing::info!("identity file {:?} does not exist yet, so creating", file);
                         // create it here
                         let identity = RelayIdentitySecret::generate();
-                        let mut options = OpenOptions::new();
-                        options.create(true).write(true);
-
-                        #[cfg(unix)]
-                        {
-                            use std::os::unix::prelude::OpenOptionsExt;
-                            options.mode(0o600);
-                        }
-
-                        let mut file = options.open(file)?;
-                        file.write_all(identity.as_bytes())?;
+                        write_secret_file(file, identity.as_bytes())?;
                     }
                 }
             }
+            Identity::IdentityEnv(var) => {
+                Ok(RelayIdentitySecret::from_bytes(&read_env_secret(var)?))
+            }
         }
     }
 
@@ -203,30 +1588,56 @@ impl Identity {
                         tracing::info!("identity file {:?} does not exist yet, so creating", file);
                         // create it here
                         let identity = HavenIdentitySecret::generate();
-                        let mut options = OpenOptions::new();
-                        options.create(true).write(true);
-
-                        #[cfg(unix)]
-                        {
-                            use std::os::unix::prelude::OpenOptionsExt;
-                            options.mode(0o600);
-                        }
-
-                        let mut file = options.open(file)?;
-                        file.write_all(identity.as_bytes())?;
+                        write_secret_file(file, identity.as_bytes())?;
                     }
                 }
             }
+            Identity::IdentityEnv(var) => {
+                Ok(HavenIdentitySecret::from_bytes(&read_env_secret(var)?))
+            }
         }
     }
 }
 
+/// Reads a hex-encoded identity secret from an environment variable.
+fn read_env_secret(var: &str) -> anyhow::Result<[u8; 32]> {
+    let hex_secret = std::env::var(var)
+        .with_context(|| format!("identity environment variable {var} not set"))?;
+    let bts = hex::decode(hex_secret.trim())
+        .with_context(|| format!("identity environment variable {var} is not hex"))?;
+    (&bts[..])
+        .try_into()
+        .with_context(|| format!("identity environment variable {var} not of the right length"))
+}
+
+/// Writes a freshly generated relay identity to a new identity file.
+pub fn gen_identity_file(path: &Path) -> anyhow::Result<RelayIdentitySecret> {
+    let identity = RelayIdentitySecret::generate();
+    write_secret_file(path, identity.as_bytes())
+        .with_context(|| format!("cannot create identity file {:?}", path))?;
+    Ok(identity)
+}
+
+/// Creates a file that only its owner can read, and writes a secret to it.
+pub(crate) fn write_secret_file(path: &Path, secret: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create_new(true).write(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::prelude::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(secret)
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, Default)]
 pub struct LinkPrice {
-    /// in micromel
-    pub max_outgoing_price: u64,
-    pub incoming_price: u64,
-    pub incoming_debt_limit: u64,
+    pub max_outgoing_price: Micromel,
+    pub incoming_price: Micromel,
+    pub incoming_debt_limit: Micromel,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy)]
@@ -234,3 +1645,258 @@ pub struct AutoSettle {
     /// number of seconds in between settlements
     pub interval: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gen_identity_file_round_trips() {
+        let path =
+            std::env::temp_dir().join(format!("earendil-identity-{}", rand::random::<u64>()));
+        let generated = gen_identity_file(&path).unwrap();
+        let read = Identity::IdentityFile(path.clone())
+            .actualize_relay()
+            .unwrap();
+        assert_eq!(
+            generated.public().fingerprint(),
+            read.public().fingerprint()
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // never clobber an existing identity
+        assert!(gen_identity_file(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn parses_obfs_params() {
+        let obfs = |yaml: &str| -> Result<ObfsConfig, _> { serde_yaml::from_str(yaml) };
+        assert_eq!(obfs("none").unwrap(), ObfsConfig::None { params: None });
+        assert_eq!(
+            obfs("sosistab3: hello").unwrap(),
+            ObfsConfig::Sosistab3 {
+                cookie: "hello".into(),
+                params: None
+            }
+        );
+        let overridden = obfs("mode: sosistab3\ncookie: hello\nparams:\n  mtu: 1200\n").unwrap();
+        assert_eq!(overridden.params().mtu, Some(1200));
+        // and it survives the trip through JSON that configs take over the control protocol
+        let reparsed: ObfsConfig =
+            serde_json::from_value(serde_json::to_value(&overridden).unwrap()).unwrap();
+        assert_eq!(reparsed, overridden);
+
+        let err = |yaml: &str| obfs(yaml).unwrap_err().to_string();
+        assert!(err("mode: none\nparams:\n  sni: example.com\n").contains("unknown field `sni`"));
+        assert!(err("mode: none\nparams:\n  mtu: 10\n").contains("mtu must be between"));
+        assert!(err("mode: sosistab3\n").contains("needs a cookie"));
+        assert!(obfs("mode: none\nmtu: 1200\n").is_err());
+    }
+
+    #[test]
+    fn rejects_clashing_listeners() {
+        let config = |yaml: &str| ConfigFile::from_yaml(yaml.as_bytes());
+        assert!(config("in_routes:\n  main:\n    listen: 0.0.0.0:19999\n    obfs: none\n").is_ok());
+        assert!(
+            config("in_routes:\n  main:\n    listen: 127.0.0.1:18964\n    obfs: none\n").is_err()
+        );
+        assert!(config("socks5:\n  listen: 0.0.0.0:19999\n  fallback: block\nin_routes:\n  main:\n    listen: 0.0.0.0:19999\n    obfs: none\n").is_err());
+    }
+
+    #[test]
+    fn quic_routes_replace_obfs() {
+        let config = |yaml: &str| ConfigFile::from_yaml(yaml.as_bytes());
+        // QUIC listens over UDP, so it doesn't clash with the control port over TCP
+        assert!(config(
+            "in_routes:\n  main:\n    listen: 127.0.0.1:18964\n    obfs: none\n    quic: {}\n"
+        )
+        .is_ok());
+        assert!(config("in_routes:\n  main:\n    listen: 0.0.0.0:19999\n    obfs:\n      sosistab3: hello\n    quic: {}\n").is_err());
+        assert!(config("in_routes:\n  main:\n    listen: 0.0.0.0:19999\n    obfs: none\n    quic:\n      cert: cert.pem\n").is_err());
+        let fingerprint = hex::encode([7u8; 32]);
+        let out_route = |obfs: &str| {
+            config(&format!("out_routes:\n  main:\n    connect: 127.0.0.1:19999\n    obfs: {obfs}\n    quic:\n      server_fingerprint: {fingerprint}\n"))
+        };
+        assert_eq!(
+            out_route("none").unwrap().out_routes["main"]
+                .quic
+                .as_ref()
+                .unwrap()
+                .server_fingerprint,
+            [7u8; 32]
+        );
+        assert!(out_route("\n      sosistab3: hello").is_err());
+    }
+
+    #[test]
+    fn liveness_probes_stay_in_range() {
+        let config = |yaml: &str| ConfigFile::from_yaml(yaml.as_bytes());
+        let liveness = config("liveness:\n  probe_interval_ms: 1000\n")
+            .unwrap()
+            .liveness;
+        assert_eq!(liveness.probe_interval(), Duration::from_secs(1));
+        // probes alone never drop a link
+        assert_eq!(liveness.detection_window(), None);
+        let err = |yaml: &str| config(yaml).unwrap_err().to_string();
+        assert!(err("liveness:\n  probe_interval_ms: 10\n").contains("must be between"));
+        assert!(err("liveness:\n  max_failed_probes: 0\n").contains("at least 1"));
+    }
+
+    #[test]
+    fn message_classes_keep_the_hop_floor() {
+        let config = |yaml: &str| ConfigFile::from_yaml(yaml.as_bytes());
+        assert!(config(
+            "privacy:\n  message_classes:\n    background:\n      min_hops: 3\n      max_hops: 5\n"
+        )
+        .is_ok());
+        assert!(config("privacy:\n  message_classes:\n    interactive:\n      min_hops: 0\n      max_hops: 1\n").is_err());
+        assert!(config("privacy:\n  message_classes:\n    interactive:\n      min_hops: 1\n      max_hops: 2\n").is_err());
+        assert!(config("privacy:\n  message_classes:\n    normal:\n      min_hops: 3\n").is_err());
+        assert!(
+            config("privacy:\n  message_classes:\n    background:\n      max_hops: 20\n").is_err()
+        );
+    }
+
+    #[test]
+    fn parses_listen_addrs() {
+        for (s, parsed) in [
+            (
+                "0.0.0.0:19999",
+                ListenAddr::Socket("0.0.0.0:19999".parse().unwrap()),
+            ),
+            (
+                "[fe80::1%2]:19999",
+                ListenAddr::Socket("[fe80::1%2]:19999".parse().unwrap()),
+            ),
+            (
+                "[fe80::1%eth0]:19999",
+                ListenAddr::Scoped {
+                    ip: "fe80::1".parse().unwrap(),
+                    interface: "eth0".into(),
+                    port: 19999,
+                },
+            ),
+            (
+                "eth0:19999",
+                ListenAddr::Interface {
+                    name: "eth0".into(),
+                    port: 19999,
+                },
+            ),
+        ] {
+            assert_eq!(s.parse::<ListenAddr>().unwrap(), parsed);
+            assert_eq!(parsed.to_string(), s);
+        }
+        for bad in [
+            "eth0",
+            "eth0:http",
+            "[fe80::1]:x",
+            "[nonsense%eth0]:19999",
+            ":19999",
+        ] {
+            assert!(bad.parse::<ListenAddr>().is_err(), "{bad} parsed");
+        }
+        // interfaces that don't exist are caught when the config is loaded
+        assert!(ConfigFile::from_yaml(
+            b"in_routes:\n  main:\n    listen: nonexistent0:19999\n    obfs: none\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn listens_on_interface_by_name() {
+        let Some(loopback) = if_addrs::get_if_addrs()
+            .unwrap()
+            .into_iter()
+            .find(|iface| iface.is_loopback())
+        else {
+            // nothing to bind to in this environment
+            return;
+        };
+        let listen: ListenAddr = format!("{}:0", loopback.name).parse().unwrap();
+        let addr = listen.resolve().unwrap();
+        assert!(addr.ip().is_loopback());
+
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        let mut dialed = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut accepted, _) = listener.accept().unwrap();
+        std::io::Write::write_all(&mut dialed, b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        std::io::Read::read_exact(&mut accepted, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn builder_matches_yaml() {
+        let as_json = |config: &ConfigFile| serde_json::to_value(config).unwrap();
+        assert_eq!(
+            as_json(&ConfigFile::default()),
+            as_json(&ConfigFile::from_yaml(b"{}").unwrap())
+        );
+        assert_eq!(
+            as_json(&ConfigFile::builder().build().unwrap()),
+            as_json(&ConfigFile::default())
+        );
+
+        let yaml = br#"
+identity_seed: a long and difficult to guess seed
+state_cache: /tmp/earendil-state.db
+control_listen: 127.0.0.1:18965
+in_routes:
+  main:
+    listen: 0.0.0.0:19999
+    obfs: none
+out_routes:
+  upstream:
+    connect: relay.example.com:19999
+    obfs: none
+    tofu: true
+auto_settle:
+  interval: 60
+"#;
+        let built = ConfigFile::builder()
+            .relay(Identity::IdentitySeed(
+                "a long and difficult to guess seed".into(),
+            ))
+            .state_cache("/tmp/earendil-state.db")
+            .control_listen("127.0.0.1:18965".parse().unwrap())
+            .in_route(
+                "main",
+                InRouteConfig::new(
+                    "0.0.0.0:19999".parse().unwrap(),
+                    ObfsConfig::None { params: None },
+                ),
+            )
+            .out_route(
+                "upstream",
+                OutRouteConfig::new("relay.example.com:19999", ObfsConfig::None { params: None }),
+            )
+            .auto_settle(AutoSettle { interval: 60 })
+            .build()
+            .unwrap();
+        assert_eq!(
+            as_json(&built),
+            as_json(&ConfigFile::from_yaml(yaml).unwrap())
+        );
+    }
+
+    #[test]
+    fn builder_validates() {
+        let route = InRouteConfig::new(
+            "127.0.0.1:18964".parse().unwrap(),
+            ObfsConfig::None { params: None },
+        );
+        // collides with the default control_listen
+        assert!(ConfigFile::builder()
+            .in_route("main", route)
+            .build()
+            .is_err());
+    }
+}
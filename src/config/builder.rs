@@ -0,0 +1,116 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use super::{
+    AutoSettle, ConfigFile, HavenConfig, Identity, InRouteConfig, OutRouteConfig, Socks5Config,
+    TcpForwardConfig, UdpForwardConfig,
+};
+
+/// Builds a [`ConfigFile`] in code, starting from the same defaults as an empty YAML config.
+///
+/// A client that connects to one relay:
+///
+/// ```
+/// use earendil::config::{ConfigFile, ObfsConfig, OutRouteConfig};
+///
+/// let config = ConfigFile::builder()
+///     .state_cache("/var/lib/earendil/state.db")
+///     .out_route(
+///         "bootstrap",
+///         OutRouteConfig::new("relay.example.com:19999", ObfsConfig::None { params: None }),
+///     )
+///     .build()
+///     .unwrap();
+/// assert!(config.identity.is_none());
+/// ```
+///
+/// A relay that takes links on one port and connects to another relay:
+///
+/// ```
+/// use earendil::config::{ConfigFile, Identity, InRouteConfig, ObfsConfig, OutRouteConfig};
+///
+/// let config = ConfigFile::builder()
+///     .relay(Identity::IdentitySeed("a long and difficult to guess seed".into()))
+///     .control_listen("127.0.0.1:18965".parse().unwrap())
+///     .in_route(
+///         "main",
+///         InRouteConfig::new("0.0.0.0:19999".parse().unwrap(), ObfsConfig::None { params: None }),
+///     )
+///     .out_route(
+///         "upstream",
+///         OutRouteConfig::new("relay.example.com:19999", ObfsConfig::None { params: None }),
+///     )
+///     .build()
+///     .unwrap();
+/// assert_eq!(config.in_routes.len(), 1);
+/// ```
+#[derive(Clone, Default)]
+pub struct ConfigFileBuilder {
+    config: ConfigFile,
+}
+
+impl ConfigFileBuilder {
+    /// Gives the node a long-term identity, which makes it a relay whether or not it has in-routes. Without in-routes, other relays can only reach it over links it dials itself.
+    pub fn relay(mut self, identity: Identity) -> Self {
+        self.config.identity = Some(identity);
+        self
+    }
+
+    pub fn state_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.state_cache = Some(path.into());
+        self
+    }
+
+    pub fn control_listen(mut self, addr: SocketAddr) -> Self {
+        self.config.control_listen = addr;
+        self
+    }
+
+    /// Adds a listener for incoming links, replacing any earlier one of the same name.
+    pub fn in_route(mut self, name: impl Into<String>, route: InRouteConfig) -> Self {
+        self.config.in_routes.insert(name.into(), route);
+        self
+    }
+
+    /// Adds a link to another relay, replacing any earlier one of the same name.
+    pub fn out_route(mut self, name: impl Into<String>, route: OutRouteConfig) -> Self {
+        self.config.out_routes.insert(name.into(), route);
+        self
+    }
+
+    pub fn auto_settle(mut self, auto_settle: AutoSettle) -> Self {
+        self.config.auto_settle = Some(auto_settle);
+        self
+    }
+
+    pub fn udp_forward(mut self, forward: UdpForwardConfig) -> Self {
+        self.config.udp_forwards.push(forward);
+        self
+    }
+
+    pub fn tcp_forward(mut self, forward: TcpForwardConfig) -> Self {
+        self.config.tcp_forwards.push(forward);
+        self
+    }
+
+    pub fn socks5(mut self, socks5: Socks5Config) -> Self {
+        self.config.socks5 = Some(socks5);
+        self
+    }
+
+    pub fn haven(mut self, haven: HavenConfig) -> Self {
+        self.config.havens.push(haven);
+        self
+    }
+
+    /// Sets anything else the builder has no method for. The fields start out at their defaults.
+    pub fn customize(mut self, f: impl FnOnce(&mut ConfigFile)) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Checks the config the same way a parsed config file is checked, and returns it.
+    pub fn build(self) -> anyhow::Result<ConfigFile> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
@@ -0,0 +1,366 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use earendil_crypt::RelayIdentitySecret;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{ConfigFile, Identity};
+
+/// Settings that are only read when the daemon starts, and so could never be applied to a running daemon.
+const RESTART_SETTINGS: &[&str] = &[
+    "identity",
+    "state_cache",
+    "control_listen",
+    "status_page",
+    "histogram_buckets",
+    "fallback_rendezvous",
+    "relay_graph_format",
+    "rendezvous",
+];
+
+/// Config fields whose values are secrets, and so never show up in a diff as-is.
+const SECRET_FIELDS: &[&str] = &["identity_seed", "sosistab3", "token"];
+
+/// What would change if the running daemon were restarted with another config.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    pub routes: Vec<RouteChange>,
+    /// Every changed setting other than routes, by top-level config field.
+    pub settings: Vec<SettingChange>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RouteChange {
+    pub direction: RouteDirection,
+    pub name: String,
+    pub change: Change,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteDirection {
+    In,
+    Out,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+    /// The route is still there, but with different values for these fields.
+    Modified {
+        fields: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SettingChange {
+    pub setting: String,
+    /// The old value as JSON, with secrets replaced by hashes or fingerprints.
+    pub old: String,
+    /// The new value as JSON, with secrets replaced by hashes or fingerprints.
+    pub new: String,
+    /// Whether the setting is one the daemon only reads as it starts, such as its identity. Running daemons don't switch configs, so every change takes a restart for now, but these always will.
+    pub requires_restart: bool,
+}
+
+impl ConfigDiff {
+    /// Compares the running config against a candidate one.
+    pub fn between(old: &ConfigFile, new: &ConfigFile) -> Self {
+        let mut routes = diff_routes(RouteDirection::In, &old.in_routes, &new.in_routes);
+        routes.extend(diff_routes(
+            RouteDirection::Out,
+            &old.out_routes,
+            &new.out_routes,
+        ));
+
+        let old_settings = settings(old);
+        let new_settings = settings(new);
+        let names: BTreeSet<&String> = old_settings.keys().chain(new_settings.keys()).collect();
+        let settings = names
+            .into_iter()
+            .filter_map(|name| {
+                let old = old_settings.get(name).unwrap_or(&Value::Null);
+                let new = new_settings.get(name).unwrap_or(&Value::Null);
+                (old != new).then(|| SettingChange {
+                    setting: name.clone(),
+                    old: old.to_string(),
+                    new: new.to_string(),
+                    requires_restart: RESTART_SETTINGS.contains(&name.as_str()),
+                })
+            })
+            .collect();
+
+        Self { routes, settings }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty() && self.settings.is_empty()
+    }
+
+    /// Whether any of the changed settings are ones the daemon only reads as it starts.
+    pub fn requires_restart(&self) -> bool {
+        self.settings.iter().any(|setting| setting.requires_restart)
+    }
+}
+
+impl Display for ConfigDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "no changes");
+        }
+        for route in self.routes.iter() {
+            let direction = match route.direction {
+                RouteDirection::In => "in_route",
+                RouteDirection::Out => "out_route",
+            };
+            match &route.change {
+                Change::Added => writeln!(f, "+ {direction} {}", route.name)?,
+                Change::Removed => writeln!(f, "- {direction} {}", route.name)?,
+                Change::Modified { fields } => {
+                    writeln!(f, "~ {direction} {}: {}", route.name, fields.join(", "))?
+                }
+            }
+        }
+        for setting in self.settings.iter() {
+            write!(
+                f,
+                "~ {}: {} -> {}",
+                setting.setting, setting.old, setting.new
+            )?;
+            if setting.requires_restart {
+                write!(f, " (requires restart)")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn diff_routes<T: Serialize>(
+    direction: RouteDirection,
+    old: &BTreeMap<String, T>,
+    new: &BTreeMap<String, T>,
+) -> Vec<RouteChange> {
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let change = match (old.get(name), new.get(name)) {
+                (Some(_), None) => Change::Removed,
+                (None, Some(_)) => Change::Added,
+                (Some(old), Some(new)) => {
+                    let (old, new) = (redacted_value(old), redacted_value(new));
+                    let fields = changed_fields(&old, &new);
+                    if fields.is_empty() {
+                        return None;
+                    }
+                    Change::Modified { fields }
+                }
+                (None, None) => return None,
+            };
+            Some(RouteChange {
+                direction,
+                name: name.clone(),
+                change,
+            })
+        })
+        .collect()
+}
+
+fn changed_fields(old: &Value, new: &Value) -> Vec<String> {
+    let empty = serde_json::Map::new();
+    let old = old.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    names
+        .into_iter()
+        .filter(|name| old.get(*name) != new.get(*name))
+        .cloned()
+        .collect()
+}
+
+/// Every top-level setting other than routes, with secrets redacted.
+fn settings(config: &ConfigFile) -> BTreeMap<String, Value> {
+    let Value::Object(mut settings) = redacted_value(config) else {
+        unreachable!("configs serialize to maps")
+    };
+    settings.remove("in_routes");
+    settings.remove("out_routes");
+    // the identity is flattened into the top level, under a different field depending on its kind
+    settings.remove("identity_seed");
+    settings.remove("identity_file");
+    settings.remove("identity_env");
+    settings.insert(
+        "identity".into(),
+        match &config.identity {
+            None => Value::Null,
+            Some(Identity::IdentitySeed(seed)) => json!(format!(
+                "seed of {}",
+                RelayIdentitySecret::from_seed(seed).public().fingerprint()
+            )),
+            Some(Identity::IdentityFile(path)) => json!(format!("file {}", path.display())),
+            Some(Identity::IdentityEnv(var)) => json!(format!("environment variable {var}")),
+        },
+    );
+    settings.into_iter().collect()
+}
+
+fn redacted_value(value: &impl Serialize) -> Value {
+    let mut value = serde_json::to_value(value).expect("configs always serialize");
+    redact(&mut value);
+    value
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                match &*value {
+                    Value::String(secret) if SECRET_FIELDS.contains(&name.as_str()) => {
+                        let hash = blake3::hash(secret.as_bytes()).to_hex();
+                        *value = json!(format!("redacted:{}", &hash[..16]));
+                    }
+                    _ => redact(value),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(value: Value) -> ConfigFile {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn diffs_routes_by_field() {
+        let old = config(json!({
+            "in_routes": {
+                "main": { "listen": "0.0.0.0:19999", "obfs": "none" },
+                "old": { "listen": "0.0.0.0:20000", "obfs": "none" },
+            },
+            "out_routes": {
+                "peer": { "connect": "1.2.3.4:19999", "obfs": "none" },
+            },
+        }));
+        let new = config(json!({
+            "in_routes": {
+                "main": { "listen": "0.0.0.0:19999", "obfs": { "sosistab3": "hunter2" } },
+                "new": { "listen": "0.0.0.0:20001", "obfs": "none" },
+            },
+            "out_routes": {
+                "peer": { "connect": "5.6.7.8:19999", "obfs": "none", "tofu": true },
+            },
+        }));
+        let diff = ConfigDiff::between(&old, &new);
+        assert_eq!(
+            diff.routes,
+            vec![
+                RouteChange {
+                    direction: RouteDirection::In,
+                    name: "main".into(),
+                    change: Change::Modified {
+                        fields: vec!["obfs".into()]
+                    },
+                },
+                RouteChange {
+                    direction: RouteDirection::In,
+                    name: "new".into(),
+                    change: Change::Added,
+                },
+                RouteChange {
+                    direction: RouteDirection::In,
+                    name: "old".into(),
+                    change: Change::Removed,
+                },
+                RouteChange {
+                    direction: RouteDirection::Out,
+                    name: "peer".into(),
+                    change: Change::Modified {
+                        fields: vec!["connect".into(), "tofu".into()]
+                    },
+                },
+            ]
+        );
+        assert!(diff.settings.is_empty());
+        assert!(!diff.requires_restart());
+        assert!(ConfigDiff::between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn flags_settings_that_require_restart() {
+        let old = config(json!({ "identity_seed": "old seed" }));
+        let new = config(json!({
+            "identity_seed": "new seed",
+            "control_listen": "127.0.0.1:20000",
+            "state_cache": "/tmp/earendil.db",
+            "debt_warning_threshold": 1000,
+        }));
+        let diff = ConfigDiff::between(&old, &new);
+        let flags: Vec<(&str, bool)> = diff
+            .settings
+            .iter()
+            .map(|setting| (setting.setting.as_str(), setting.requires_restart))
+            .collect();
+        assert_eq!(
+            flags,
+            vec![
+                ("control_listen", true),
+                ("debt_warning_threshold", false),
+                ("identity", true),
+                ("state_cache", true),
+            ]
+        );
+        assert!(diff.requires_restart());
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let old = config(json!({
+            "identity_seed": "correct horse",
+            "in_routes": { "main": { "listen": "0.0.0.0:19999", "obfs": { "sosistab3": "hunter2" } } },
+            "havens": [{
+                "identity_seed": "haven battery",
+                "listen_port": 80,
+                "rendezvous": RelayIdentitySecret::generate().public().fingerprint().to_string(),
+                "handler": { "type": "simple_proxy" },
+            }],
+        }));
+        let new = config(json!({
+            "identity_seed": "staple horse",
+            "in_routes": { "main": { "listen": "0.0.0.0:19999", "obfs": { "sosistab3": "hunter3" } } },
+        }));
+        let diff = ConfigDiff::between(&old, &new);
+        let printed = format!("{diff}{}", serde_json::to_string(&diff).unwrap());
+        for secret in [
+            "correct horse",
+            "staple horse",
+            "hunter2",
+            "hunter3",
+            "haven battery",
+        ] {
+            assert!(!printed.contains(secret), "{secret} leaked into {printed}");
+        }
+        // but the identities are still recognizable
+        assert!(printed.contains(
+            &RelayIdentitySecret::from_seed("staple horse")
+                .public()
+                .fingerprint()
+                .to_string()
+        ));
+        assert!(diff
+            .settings
+            .iter()
+            .any(|setting| setting.setting == "havens"));
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use earendil_crypt::{ClientId, RelayIdentitySecret};
 use earendil_packet::crypt::DhSecret;
 use earendil_topology::RelayGraph;
@@ -7,8 +9,9 @@ use stdcode::{deserialize, StdcodeSerializeExt};
 
 use crate::{
     config::ConfigFile,
-    db::{db_read, db_write},
+    db::{db_read, db_write, decode_graph, DecodedGraph, MiscKey, GRAPH_VERSION},
     debts::Debts,
+    snapshot::take_restored,
 };
 
 pub type DaemonContext = anyctx::AnyCtx<ConfigFile>;
@@ -22,28 +25,92 @@ pub static MY_RELAY_IDENTITY: CtxField<Option<RelayIdentitySecret>> = |ctx| {
     })
 };
 
-pub static MY_RELAY_ONION_SK: CtxField<DhSecret> = |_| DhSecret::generate();
+/// Whether we are a relay, i.e. whether we have a relay identity.
+pub fn is_relay(ctx: &DaemonContext) -> bool {
+    ctx.get(MY_RELAY_IDENTITY).is_some()
+}
+
+/// Whether we are a client, i.e. not a relay.
+pub fn is_client(ctx: &DaemonContext) -> bool {
+    !is_relay(ctx)
+}
+
+/// Our relay identity, or an error saying that `what` only works on relays.
+pub fn require_relay(ctx: &DaemonContext, what: &str) -> anyhow::Result<RelayIdentitySecret> {
+    match ctx.get(MY_RELAY_IDENTITY) {
+        Some(identity) => Ok(*identity),
+        None => anyhow::bail!("{what} only works on relays, and we are a client"),
+    }
+}
+
+pub static MY_RELAY_ONION_SK: CtxField<DhSecret> =
+    |ctx| match take_restored(ctx, |snapshot| snapshot.onion_sk.take()) {
+        Some(bytes) => DhSecret::from_bytes(&bytes),
+        None => DhSecret::generate(),
+    };
+/// The key that GlobalRpc requests to us are sealed to, published in our descriptor. Kept apart from the onion key, which only ever peels packets, and derived from our identity so that it survives restarts.
+pub static MY_SEALED_SERVICE_SK: CtxField<DhSecret> = |ctx| {
+    let identity = ctx
+        .get(MY_RELAY_IDENTITY)
+        .expect("only relays serve sealed GlobalRpc");
+    DhSecret::from_bytes(&blake3::derive_key(
+        "earendil sealed GlobalRpc service key",
+        identity.as_bytes(),
+    ))
+};
 pub static RELAY_GRAPH: CtxField<RwLock<RelayGraph>> = |ctx| {
     let ctx = ctx.clone();
     smol::future::block_on(async move {
-        match db_read(&ctx, "relay_graph")
-            .await
-            .ok()
-            .flatten()
-            .and_then(|s| stdcode::deserialize(&s).ok())
-        {
-            Some(g) => RwLock::new(g),
+        let bytes = match take_restored(&ctx, |snapshot| snapshot.relay_graph.take()) {
+            Some(bytes) => Some(bytes),
+            None => db_read(&ctx, MiscKey::RelayGraph).await.ok().flatten(),
+        };
+        let mut graph = match bytes.map(|bytes| decode_graph(&bytes)) {
+            Some(Ok(DecodedGraph {
+                graph,
+                migrated_from: None,
+            })) => graph,
+            Some(Ok(DecodedGraph {
+                graph,
+                migrated_from: Some(version),
+            })) => {
+                tracing::info!(
+                    from = version,
+                    to = GRAPH_VERSION,
+                    "migrated the persisted relay graph"
+                );
+                graph
+            }
+            Some(Err(err)) => {
+                tracing::warn!(
+                    "discarding the persisted relay graph, since {err}. It will be learned again from neighbors"
+                );
+                RelayGraph::new()
+            }
             None => {
                 tracing::debug!("**** INIT RELAY GRAPH****");
-                RwLock::new(RelayGraph::new())
+                RelayGraph::new()
             }
+        };
+        // we must never evict ourselves
+        if let Some(my_id) = ctx.get(MY_RELAY_IDENTITY) {
+            graph.anchor(my_id.public().fingerprint());
+        }
+        if let Some(limits) = ctx.init().relay_graph_limits {
+            graph.set_limits(limits);
         }
+        graph.set_max_routing_age(ctx.init().max_routing_adjacency_age_secs);
+        RwLock::new(graph)
     })
 };
 
 pub static DEBTS: CtxField<Debts> = |ctx| {
     smol::future::block_on(async move {
-        match db_read(&ctx, "debts").await {
+        let stored = match take_restored(ctx, |snapshot| snapshot.debts.take()) {
+            Some(debts) => Ok(Some(debts)),
+            None => db_read(ctx, MiscKey::Debts).await,
+        };
+        let debts = match stored {
             Ok(Some(debts)) => {
                 tracing::debug!("retrieving persisted debts");
                 match Debts::from_bytes(debts) {
@@ -58,13 +125,30 @@ pub static DEBTS: CtxField<Debts> = |ctx| {
                 tracing::debug!("initializing debts");
                 Debts::new()
             }
-        }
+        };
+        debts
+            .with_warning_threshold(ctx.init().debt_warning_threshold)
+            .with_divergence_tolerance(ctx.init().debt_divergence_tolerance)
+            .with_strict_prepay(ctx.init().strict_prepay)
+            .with_graduated_admission(ctx.init().graduated_admission)
+            .with_graduated_delay(
+                ctx.init()
+                    .graduated_admission_delay_ms
+                    .map(Duration::from_millis),
+            )
     })
 };
 
 pub static MY_CLIENT_ID: CtxField<ClientId> = |ctx| {
     smol::future::block_on(async {
-        match db_read(ctx, "client_id").await {
+        if let Some(client_id) = take_restored(ctx, |snapshot| snapshot.client_id.take()) {
+            tracing::debug!("restored client id {client_id}");
+            if let Err(e) = db_write(ctx, MiscKey::ClientId, client_id.stdcode()).await {
+                tracing::warn!("error saving client id: {e}");
+            }
+            return client_id;
+        }
+        match db_read(ctx, MiscKey::ClientId).await {
             Ok(Some(id)) => {
                 let client_id = deserialize(&id).unwrap_or(generate_client_id(ctx).await);
                 tracing::debug!("retrieved client id {client_id}");
@@ -83,7 +167,7 @@ async fn generate_client_id(ctx: &DaemonContext) -> ClientId {
     let id = rand::random::<u64>() / 100000 * 100000;
     tracing::debug!("generated new client id: {id}");
 
-    if let Err(e) = db_write(ctx, "client_id", id.stdcode()).await {
+    if let Err(e) = db_write(ctx, MiscKey::ClientId, id.stdcode()).await {
         tracing::warn!("error saving client id: {e}");
     }
 
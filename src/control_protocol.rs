@@ -1,53 +1,121 @@
 use crate::{
-    commands::{ChatCommand, ControlCommand},
-    daemon::ChatEntry,
-    haven::HavenLocator,
+    audit::{AuditEntry, AuditHead, AuditVerification},
+    commands::{ChatCommand, ControlCommand, SettleCommand},
+    config::{ConfigDiff, ConfigFile, HavenHandler, ObfsConfig, OutRouteConfig, SendRateLimit},
+    daemon::{ChatEntry, IdentityFreshness, IdentityRotation, PartitionReport, UnsentChat},
+    debts::DebtEvent,
+    dht::ReplicationReport,
+    global_rpc::{
+        pending::{PendingSend, SendOutcome},
+        GlobalRpcProgress,
+    },
+    haven::{BeaconStatus, HavenEndpoint, HavenLocator, RendezvousOccupancy},
+    ledger::unix_now,
+    limits::TransportLimits,
+    n2r::{
+        EntryGuard, LearnedRoute, MessageClass, RoamingEvent, SurbBundleError, SurbBundleStock,
+        SurbBundles,
+    },
+    n2r_socket::{shaper::SocketInfo, RelayEndpoint},
+    network::{ClassLatency, LoadState, ObservedDrop, SendConcurrency},
+    scope::TaskHealth,
+    snapshot::{seal_received_snapshot, SnapshotError, SNAPSHOT_PASSPHRASE_VAR},
+    throughput::ThroughputReport,
+    usage::USAGE_HOUR_SECS,
 };
 use anyhow::Context;
 use async_trait::async_trait;
-
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use colored::{ColoredString, Colorize};
 use earendil_crypt::{
     AnonEndpoint, ClientId, HavenFingerprint, HavenIdentitySecret, RelayFingerprint,
 };
-use earendil_packet::{crypt::DhPublic, PacketConstructError};
+use earendil_packet::{
+    crypt::{DhPublic, DhSecret},
+    PacketConstructError,
+};
+use earendil_topology::GraphStats;
 use either::Either;
 use nanorpc::nanorpc_derive;
-use nanorpc_http::client::HttpRpcTransport;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use smol::Timer;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use std::{io::Write, marker::Send};
 use std::{net::SocketAddr, str::FromStr};
 use thiserror::Error;
 
+mod audited;
+mod graph_report;
+mod http;
+mod status;
+
+pub use self::audited::AuditedService;
+pub use self::graph_report::{GraphReport, GraphSnapshot};
+pub use self::http::{ControlEncoding, ControlHttpServer, ControlHttpTransport};
+pub use self::status::{
+    BootstrapPhase, HavenStatus, NeighborKind, NeighborStatus, NetworkSummary, NodeMode,
+    NodeStatus, QueueStatus, RouteState, RouteStatus, StatusView,
+};
+pub use crate::usage::UsagePeriod;
+
+/// How far back graph reports plot our traffic.
+const REPORT_USAGE_SECS: u64 = 7 * 86400;
+
 pub async fn main_control(
     control_command: ControlCommand,
     connect: SocketAddr,
 ) -> anyhow::Result<()> {
-    let control = ControlClient::from(HttpRpcTransport::new(connect));
+    let control = ControlClient::connect(connect, ControlEncoding::Json);
     match control_command {
         ControlCommand::GlobalRpc {
             id,
             dest: destination,
             method,
             args,
+            sealed,
+            nack,
+            class,
         } => {
             let args: Result<Vec<serde_json::Value>, _> =
                 args.into_iter().map(|a| serde_yaml::from_str(&a)).collect();
             let args = args.context("arguments not YAML")?;
-            let res = control
-                .send_global_rpc(GlobalRpcArgs {
+            let job_id = control
+                .start_global_rpc(GlobalRpcArgs {
                     id,
                     destination,
                     method,
                     args,
+                    sealed,
+                    nack,
+                    class,
                 })
-                .await??;
+                .await?;
+            let mut last_seq = 0;
+            let res = loop {
+                let job = control
+                    .global_rpc_job(job_id)
+                    .await?
+                    .context("GlobalRpc job disappeared")?;
+                if let Some(progress) = job.progress.filter(|progress| progress.seq > last_seq) {
+                    last_seq = progress.seq;
+                    let percent = progress
+                        .percent
+                        .map(|percent| format!("{percent:.0}%"))
+                        .unwrap_or_else(|| "...".into());
+                    eprintln!(
+                        "[{percent}] {}",
+                        progress.message.as_deref().unwrap_or_default()
+                    );
+                }
+                if let Some(res) = job.result {
+                    break res?;
+                }
+                Timer::after(Duration::from_millis(500)).await;
+            };
             println!("{res}");
         }
         ControlCommand::InsertRendezvous {
@@ -59,6 +127,8 @@ pub async fn main_control(
                 HavenIdentitySecret::from_str(&identity_sk)?,
                 DhPublic::from_str(&onion_pk)?,
                 rendezvous_fingerprint,
+                // we don't know what handshake the haven understands, so assume the oldest
+                0,
             );
             control.insert_rendezvous(locator).await??;
         }
@@ -70,19 +140,484 @@ pub async fn main_control(
                 println!("No haven locator found for fingerprint {key}")
             }
         }
+        ControlCommand::CheckDhtReplication { key } => {
+            let report = control.check_dht_replication(key).await?;
+            for (replica, state) in report.replicas.iter() {
+                println!("{replica}: {state:?}");
+            }
+            if !report.meets_quorum {
+                anyhow::bail!(
+                    "fewer than {} replicas hold the current locator",
+                    report.quorum
+                );
+            }
+        }
+        ControlCommand::DhtReplicas { key } => {
+            for replica in control.dht_replicas(key).await? {
+                println!("{replica}");
+            }
+        }
         ControlCommand::RelayGraphviz => {
             let res = control.relay_graphviz().await?;
             println!("{res}");
         }
+        ControlCommand::GraphDump { format } => {
+            print!("{}", control.graph_dump(format).await?);
+        }
+        ControlCommand::ExportGraph { format, output } => {
+            let mut out: Box<dyn Write + Send> = match &output {
+                Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+                None => Box::new(std::io::stdout()),
+            };
+            // written page by page, so that big graphs never sit in memory whole
+            let mut after = None;
+            loop {
+                let page = control.export_graph(format, after).await?;
+                out.write_all(page.text.as_bytes())?;
+                match page.next {
+                    Some(next) => after = Some(next),
+                    None => break,
+                }
+            }
+            out.flush()?;
+        }
+        ControlCommand::GraphReport {
+            output,
+            full_fingerprints,
+        } => {
+            let snapshot = control.relay_graph().await?;
+            let neighbors = control.status().await?.neighbors;
+            let now = unix_now();
+            let usage = control
+                .usage_history(now.saturating_sub(REPORT_USAGE_SECS), now, USAGE_HOUR_SECS)
+                .await?
+                .unwrap_or_default();
+            let report =
+                GraphReport::new(&snapshot, &neighbors, full_fingerprints).with_usage(usage);
+            std::fs::write(&output, report.to_html())
+                .with_context(|| format!("could not write {}", output.display()))?;
+            println!(
+                "wrote a report on {} relays to {}",
+                report.summary.relays,
+                output.display()
+            );
+            if !report.has_layout {
+                println!("the graph is too big to draw, so the report only has the table");
+            }
+        }
         ControlCommand::MyRoutes => {
             let routes = control.my_routes().await?;
             println!("{}", serde_yaml::to_string(&routes)?);
         }
+        ControlCommand::Stats => {
+            for (name, value) in control.stats().await? {
+                println!("{name} {value}");
+            }
+        }
+        ControlCommand::GraphStats => {
+            let stats = control.graph_stats().await?;
+            println!("{}", serde_yaml::to_string(&stats)?);
+        }
+        ControlCommand::Status { watch, interval } => {
+            if watch {
+                return watch_status(&control, Duration::from_secs(interval)).await;
+            }
+            let status = control.status().await?;
+            print!("{}", StatusView::new(&status, None));
+            let problems = status.problems();
+            if !problems.is_empty() {
+                anyhow::bail!("unhealthy: {}", problems.join("; "));
+            }
+        }
+        ControlCommand::Whoami => {
+            let whoami = control.whoami().await?;
+            println!("{}", serde_yaml::to_string(&whoami)?);
+        }
+        ControlCommand::NetworkSummary => {
+            let summary = control.network_summary().await?;
+            println!("{}", serde_yaml::to_string(&summary)?);
+        }
+        ControlCommand::DaemonTasks => {
+            let tasks = control.daemon_tasks().await?;
+            println!("{}", serde_yaml::to_string(&tasks)?);
+        }
+        ControlCommand::PartitionCheck => {
+            let report = control.partition_check().await?;
+            println!("{}", serde_yaml::to_string(&report)?);
+        }
+        ControlCommand::RotateIdentity { grace_secs } => {
+            let rotation = control.rotate_identity(grace_secs).await??;
+            println!(
+                "rotated identity {} to {}. restart the daemon to start using it; neighbors that pinned the old one accept the new one until {}",
+                rotation.old_pk.fingerprint(),
+                rotation.new_pk.fingerprint(),
+                rotation.grace_until
+            );
+        }
+        ControlCommand::AuditLog { after, limit } => {
+            for entry in control.audit_log(after, limit).await?? {
+                println!(
+                    "{}\t{}\t{}",
+                    entry.seq,
+                    entry.unix_secs,
+                    serde_json::to_string(&entry.event)?
+                );
+            }
+        }
+        ControlCommand::AuditLogHead => match control.audit_log_head().await?? {
+            Some(head) => println!("{}", serde_json::to_string(&head)?),
+            None => println!("the audit log is empty"),
+        },
+        ControlCommand::VerifyAuditLog { against } => {
+            let exported = match against {
+                Some(path) => Some(serde_json::from_slice(&std::fs::read(path)?)?),
+                None => None,
+            };
+            let verification = control.verify_audit_log(exported).await??;
+            match verification.first_broken {
+                Some(seq) => anyhow::bail!(
+                    "audit log was tampered with at entry {seq} of {}",
+                    verification.entries
+                ),
+                None => println!("audit log is intact: {} entries", verification.entries),
+            }
+        }
+        ControlCommand::Snapshot { out } => {
+            let passphrase = std::env::var(SNAPSHOT_PASSPHRASE_VAR)
+                .with_context(|| format!("{SNAPSHOT_PASSPHRASE_VAR} must hold the passphrase"))?;
+            // the daemon seals the snapshot to a key of ours, and we seal it with the passphrase, which never leaves this process
+            let our_sk = DhSecret::generate();
+            let received = control.snapshot_state(our_sk.public()).await??;
+            let snapshot = seal_received_snapshot(&received, &our_sk, passphrase).await?;
+            std::fs::write(&out, snapshot)?;
+            println!("snapshot written to {}", out.display());
+        }
+        ControlCommand::SendAndRecv {
+            dest: destination,
+            content,
+            timeout_secs,
+        } => {
+            let reply = control
+                .send_and_recv(SendAndRecvArgs {
+                    destination,
+                    content: content.into(),
+                    timeout_ms: timeout_secs * 1000,
+                })
+                .await??;
+            println!("{}", String::from_utf8_lossy(&reply.content));
+        }
+        ControlCommand::TransportLimits { destination } => {
+            let limits = control.transport_limits(destination).await?;
+            println!("{}", serde_yaml::to_string(&limits)?);
+        }
+        ControlCommand::SurbBundles => {
+            let bundles = control.surb_bundles().await?;
+            for (role, bundles) in [("issued", bundles.issued), ("imported", bundles.imported)] {
+                for bundle in bundles {
+                    println!(
+                        "{role} {:x} for {} via {}: {}/{} left, expires {}",
+                        bundle.id,
+                        bundle.anon_dest,
+                        bundle.anchor,
+                        bundle.remaining,
+                        bundle.total,
+                        pretty_time(
+                            SystemTime::UNIX_EPOCH + Duration::from_secs(bundle.expires_at)
+                        )
+                    );
+                }
+            }
+        }
+        ControlCommand::ImportSurbBundle { input } => {
+            let bundle = std::fs::read(&input)
+                .with_context(|| format!("could not read {}", input.display()))?;
+            let stock = control.import_surb_bundle(bundle.into()).await??;
+            println!(
+                "imported {:x} for {} via {}: {} reply blocks, expires {}",
+                stock.id,
+                stock.anon_dest,
+                stock.anchor,
+                stock.total,
+                pretty_time(SystemTime::UNIX_EPOCH + Duration::from_secs(stock.expires_at))
+            );
+        }
+        ControlCommand::SktInfo => {
+            for socket in control.skt_info().await? {
+                let limit = match socket.rate_limit {
+                    Some(limit) => format!(
+                        "limited to {} B/s, bursts of {} B",
+                        limit.bytes_per_sec, limit.burst_bytes
+                    ),
+                    None => "unlimited".into(),
+                };
+                println!(
+                    "{}\t{}\t{}\tsending {} B/s",
+                    socket.id, socket.name, limit, socket.observed_bytes_per_sec
+                );
+            }
+        }
+        ControlCommand::SetRateLimit {
+            id,
+            bytes_per_sec,
+            burst_bytes,
+        } => {
+            let limit = bytes_per_sec.map(|bytes_per_sec| SendRateLimit {
+                bytes_per_sec,
+                burst_bytes,
+            });
+            control.set_socket_rate_limit(id, limit).await??;
+        }
+        ControlCommand::PendingSends { socket_id } => {
+            for send in control.pending_sends(socket_id).await? {
+                println!(
+                    "{}\tsocket {}\t{}/{}\t{:?}\t{} retries\t{:.1}s old",
+                    send.id,
+                    send.socket_id,
+                    send.destination,
+                    send.method,
+                    send.state,
+                    send.retries,
+                    send.age_ms as f64 / 1000.0
+                );
+            }
+        }
+        ControlCommand::CancelSend { id } => match control.cancel_send(id).await? {
+            Some(SendOutcome::Cancelled) => println!("cancelled"),
+            Some(outcome) => println!("already finished: {outcome:?}"),
+            None => anyhow::bail!("no send with id {id}"),
+        },
+        ControlCommand::CancelAll {
+            socket_id,
+            destination,
+        } => {
+            let cancelled = control.cancel_all(socket_id, destination).await?;
+            println!("cancelled {} sends", cancelled.len());
+            for id in cancelled {
+                println!("{id}");
+            }
+        }
+        ControlCommand::WatchDebts => {
+            let mut after = 0;
+            loop {
+                for (seq, event) in control.debt_events(after).await? {
+                    after = seq;
+                    match event {
+                        DebtEvent::DebtWarning(neighbor, balance) => {
+                            println!(
+                                "{neighbor} owes {balance} micromel, over the warning threshold"
+                            )
+                        }
+                        DebtEvent::DebtRecovered(neighbor, balance) => {
+                            println!("{neighbor} owes {balance} micromel, back under the warning threshold")
+                        }
+                        DebtEvent::BalanceDiverged(neighbor, ours, theirs) => {
+                            println!("{neighbor} owes {ours} micromel by our ledger, but {theirs} micromel by theirs")
+                        }
+                        DebtEvent::BalanceAgreed(neighbor) => {
+                            println!("{neighbor}'s ledger agrees with ours again")
+                        }
+                    }
+                }
+            }
+        }
+        ControlCommand::WatchRoaming => {
+            let mut after = 0;
+            loop {
+                for (seq, event) in control.roaming_events(after).await? {
+                    after = seq;
+                    println!(
+                        "roamed away from {}, invalidating {} reply blocks and moving {} endpoints to another anchor",
+                        event.from,
+                        event.invalidated,
+                        event.endpoints.len()
+                    );
+                }
+            }
+        }
+        ControlCommand::ForwardingLatency => {
+            let latency = control.forwarding_latency().await?;
+            println!("{}", serde_yaml::to_string(&latency)?);
+        }
+        ControlCommand::SendConcurrency => {
+            let sends = control.send_concurrency().await?;
+            if sends.is_empty() {
+                println!("no delayed sends in flight");
+            }
+            for dest in sends {
+                println!(
+                    "{}\t{}/{} in flight\t{} waiting",
+                    dest.destination, dest.in_use, dest.limit, dest.waiting
+                );
+            }
+        }
+        ControlCommand::DropReports => {
+            let drops = control.observed_drops().await?;
+            if drops.is_empty() {
+                println!("no drop reports from our relays");
+            }
+            for drop in drops {
+                println!(
+                    "{}\t{}\t{:?}\tpacket {:016x}",
+                    drop.at, drop.relay, drop.reason, drop.packet_hash
+                );
+            }
+        }
+        ControlCommand::Report {
+            start,
+            end,
+            format,
+            include_chats,
+            out,
+        } => {
+            let report_id = control
+                .generate_report(ReportArgs {
+                    period_start: start,
+                    period_end: end,
+                    format,
+                    include_chats,
+                })
+                .await?;
+            let mut file = std::fs::File::create(&out)?;
+            while let Some(chunk) = control.report_chunk(report_id).await?? {
+                file.write_all(chunk.as_bytes())?;
+            }
+            println!("report written to {}", out.display());
+        }
+        ControlCommand::UsageHistory {
+            start,
+            end,
+            resolution_secs,
+        } => {
+            let end = end.unwrap_or_else(unix_now);
+            let start = start.unwrap_or(end.saturating_sub(86400));
+            let periods = control.usage_history(start, end, resolution_secs).await??;
+            if periods.is_empty() {
+                println!("no usage recorded between {start} and {end}");
+            }
+            for period in periods {
+                println!(
+                    "{} transit {} pkts / {} B, terminated {} pkts / {} B, {} relay and {} client peers, {} settlements of {} micromel",
+                    pretty_time(SystemTime::UNIX_EPOCH + Duration::from_secs(period.start)),
+                    period.transit_packets,
+                    period.transit_bytes,
+                    period.terminated_packets,
+                    period.terminated_bytes,
+                    period.relay_peers,
+                    period.client_peers,
+                    period.settlements,
+                    period.settled
+                );
+            }
+        }
+        ControlCommand::TestOutRoute {
+            connect,
+            fingerprint,
+            cookie,
+            tofu,
+        } => {
+            let result = control
+                .test_out_route(OutRouteConfig {
+                    connect,
+                    fingerprint,
+                    obfs: match cookie {
+                        Some(cookie) => ObfsConfig::Sosistab3 {
+                            cookie,
+                            params: None,
+                        },
+                        None => ObfsConfig::None { params: None },
+                    },
+                    tofu,
+                    pacing: false,
+                    strict_prepay: None,
+                    graduated_admission: None,
+                    quic: None,
+                })
+                .await?;
+            if let Some(fingerprint) = result.fingerprint {
+                println!("fingerprint: {fingerprint}");
+            }
+            if let Some(version) = result.version {
+                println!("version: {version}");
+            }
+            if let Some(rtt_ms) = result.rtt_ms {
+                println!("rtt: {rtt_ms} ms");
+            }
+            match result.outcome {
+                RouteTestOutcome::Success => println!("out route works"),
+                RouteTestOutcome::FingerprintMismatch { expected } => {
+                    anyhow::bail!("fingerprint mismatch: expected {expected}")
+                }
+                RouteTestOutcome::NotARelay => anyhow::bail!("other side is not a relay"),
+                RouteTestOutcome::Failed(err) => anyhow::bail!("out route failed: {err}"),
+            }
+        }
+        ControlCommand::MeasureThroughput {
+            dest,
+            duration_secs,
+        } => {
+            println!("sending to {dest} for {duration_secs} seconds...");
+            let report = control.measure_throughput(dest, duration_secs).await??;
+            println!(
+                "{} bytes in {} ms: {} bytes/s, {:.1}% of {} packets lost",
+                report.bytes_delivered,
+                report.elapsed_ms,
+                report.bytes_per_sec,
+                report.loss * 100.0,
+                report.packets_sent
+            );
+        }
+        ControlCommand::PauseOutRoute { name } => {
+            control.pause_out_route(name).await??;
+        }
+        ControlCommand::ResumeOutRoute { name } => {
+            control.resume_out_route(name).await??;
+        }
         ControlCommand::HavensInfo => {
             for info in control.havens_info().await?? {
                 println!("{} - {}", info.0, info.1);
             }
         }
+        ControlCommand::HavenBeacons => {
+            let beacons = control.haven_beacons().await?;
+            if beacons.is_empty() {
+                println!("no havens with beacons");
+            }
+            for status in beacons {
+                let last = match (&status.last_error, status.last_rtt_ms, status.last_at) {
+                    (_, _, None) => "no beacon yet".to_string(),
+                    (Some(err), _, Some(_)) => format!("last beacon failed: {err}"),
+                    (None, Some(rtt_ms), Some(_)) => {
+                        format!("last beacon came back in {rtt_ms} ms")
+                    }
+                    (None, None, Some(_)) => "last beacon came back".to_string(),
+                };
+                println!(
+                    "{}{}\t{last}\t{} ok, {} failed, {} re-registrations",
+                    status.haven,
+                    if status.degraded { " (degraded)" } else { "" },
+                    status.successes,
+                    status.failures,
+                    status.reregistrations
+                );
+            }
+        }
+        ControlCommand::BindHaven {
+            port,
+            rendezvous,
+            upstream,
+        } => {
+            let handler = match upstream {
+                Some(upstream) => HavenHandler::TcpService { upstream },
+                None => HavenHandler::SimpleProxy,
+            };
+            let fingerprint = control
+                .bind_ephemeral_haven(port, rendezvous, handler)
+                .await??;
+            println!("{fingerprint}");
+        }
+        ControlCommand::UnbindHaven { fingerprint } => {
+            control.unbind_haven(fingerprint).await??;
+        }
         ControlCommand::Chat { chat_command } => match chat_command {
             ChatCommand::List => {
                 let divider = "+-------------------------------------+---------------+-----------------------------------+";
@@ -173,11 +708,84 @@ pub async fn main_control(
             ChatCommand::Send { dest, msg } => {
                 control.send_chat(dest, msg).await??;
             }
+            ChatCommand::Unsent => {
+                for chat in control.list_unsent().await? {
+                    println!(
+                        "{:016x} {} ({}s ago): {}",
+                        chat.id, chat.neighbor, chat.age_secs, chat.text
+                    );
+                }
+            }
+            ChatCommand::Cancel { id } => {
+                let id = u64::from_str_radix(&id, 16).context("chat ids are hexadecimal")?;
+                control.cancel_unsent(id).await??;
+            }
+        },
+        ControlCommand::Settle { settle_command } => match settle_command {
+            SettleCommand::List => {
+                for settlement in control.list_settlements().await? {
+                    println!("{settlement}");
+                }
+            }
+            SettleCommand::Send { neighbor, amount } => {
+                control.send_settlement(neighbor, amount).await??;
+            }
+            SettleCommand::Accept { neighbor } => {
+                control.accept_settlement(neighbor).await??;
+            }
+            SettleCommand::Reject { neighbor } => {
+                control.reject_settlement(neighbor).await??;
+            }
         },
     }
     Ok(())
 }
 
+/// Redraws the status in place every `interval`, until interrupted.
+async fn watch_status(control: &ControlClient, interval: Duration) -> anyhow::Result<()> {
+    // clear the screen once, and from then on only ever overwrite it, so that it never scrolls
+    print!("\x1b[2J");
+    let mut previous: Option<(NodeStatus, Instant)> = None;
+    loop {
+        let status = control.status().await?;
+        let now = Instant::now();
+        let view = StatusView::new(
+            &status,
+            previous
+                .as_ref()
+                .map(|(previous, at)| (previous, now.duration_since(*at))),
+        );
+        let mut screen = String::from("\x1b[H");
+        for line in view.to_string().lines() {
+            screen.push_str(line);
+            // clear whatever the previous frame left on the rest of the line
+            screen.push_str("\x1b[K\n");
+        }
+        // and below the last line
+        screen.push_str("\x1b[J");
+        print!("{screen}");
+        std::io::stdout().flush()?;
+        previous = Some((status, now));
+        Timer::after(interval).await;
+    }
+}
+
+/// Checks that a config file parses and is valid, and optionally prints how it differs from the config of the daemon at `diff_against`.
+pub async fn check_config(yaml: Vec<u8>, diff_against: Option<SocketAddr>) -> anyhow::Result<()> {
+    ConfigFile::from_yaml(&yaml)?;
+    match diff_against {
+        None => println!("config is valid"),
+        Some(connect) => {
+            let control = ControlClient::connect(connect, ControlEncoding::Json);
+            let diff = control
+                .preview_config(String::from_utf8(yaml).context("config file not UTF-8")?)
+                .await??;
+            print!("{diff}");
+        }
+    }
+    Ok(())
+}
+
 fn earendil_blue(string: &str) -> ColoredString {
     string
         .custom_color(colored::CustomColor {
@@ -208,18 +816,64 @@ fn pretty_time(time: SystemTime) -> ColoredString {
     format!("[{}]", datetime.format("%Y-%m-%d %H:%M:%S")).bright_yellow()
 }
 
+impl ControlClient {
+    /// Connects to the control protocol of the daemon at `connect`, asking for responses in the given encoding. Daemons that only speak JSON answer in JSON regardless, which the client handles just the same.
+    pub fn connect(connect: SocketAddr, encoding: ControlEncoding) -> Self {
+        Self::from(ControlHttpTransport::new(connect, encoding))
+    }
+}
+
 #[nanorpc_derive]
 #[async_trait]
 pub trait ControlProtocol {
-    async fn havens_info(&self) -> Result<Vec<(String, String)>, ConfigError>;
+    async fn havens_info(&self) -> Result<Vec<(String, HavenEndpoint)>, ConfigError>;
+
+    /// Returns how the beacons of each haven we serve have been going. Beacons dial the haven the way any visitor would, to catch a rendezvous that silently stopped forwarding to it.
+    async fn haven_beacons(&self) -> Vec<BeaconStatus>;
+
+    /// Binds an ephemeral haven under a fresh identity, returning its fingerprint. The haven isn't part of the config, and goes away when unbound or when the daemon stops.
+    async fn bind_ephemeral_haven(
+        &self,
+        listen_port: u16,
+        rendezvous: Vec<RelayFingerprint>,
+        handler: HavenHandler,
+    ) -> Result<HavenFingerprint, ConfigError>;
+
+    /// Takes down an ephemeral haven, deregistering it from its rendezvous points.
+    async fn unbind_haven(&self, fingerprint: HavenFingerprint) -> Result<(), ConfigError>;
 
     async fn send_global_rpc(
         &self,
         args: GlobalRpcArgs,
     ) -> Result<serde_json::Value, GlobalRpcError>;
 
+    /// Sends a request over N2R from a fresh anonymous endpoint, and waits for the destination's reply to it.
+    async fn send_and_recv(
+        &self,
+        args: SendAndRecvArgs,
+    ) -> Result<SendAndRecvReply, SendAndRecvError>;
+
+    /// Starts a GlobalRpc call in the background, asking the destination to report its progress. Returns a job id for [ControlProtocol::global_rpc_job].
+    async fn start_global_rpc(&self, args: GlobalRpcArgs) -> u64;
+
+    /// How a call started with [ControlProtocol::start_global_rpc] is going, or `None` if the job is unknown or expired.
+    async fn global_rpc_job(&self, job: u64) -> Option<GlobalRpcJob>;
+
     async fn relay_graphviz(&self) -> String; // graphviz
 
+    /// Dumps the relay graph as we see it, in the given format.
+    async fn graph_dump(&self, format: GraphDumpFormat) -> String;
+
+    /// Exports the relay graph for other tools to analyze, one page at a time. Start with `after` set to None, and pass each page's `next` to get the one after it.
+    async fn export_graph(
+        &self,
+        format: GraphExportFormat,
+        after: Option<RelayFingerprint>,
+    ) -> GraphExportPage;
+
+    /// Returns the relay graph as structured data, with descriptor timestamps.
+    async fn relay_graph(&self) -> GraphSnapshot;
+
     async fn my_routes(&self) -> serde_json::Value;
 
     async fn insert_rendezvous(&self, locator: HavenLocator) -> Result<(), DhtError>;
@@ -229,6 +883,15 @@ pub trait ControlProtocol {
         fingerprint: HavenFingerprint,
     ) -> Result<Option<HavenLocator>, DhtError>;
 
+    /// Checks whether the relay graph split into parts that can't reach each other, and whether we can reach the relays `partition_watch` watches.
+    async fn partition_check(&self) -> PartitionReport;
+
+    /// Asks every DHT replica responsible for a haven whether it holds the haven's current locator.
+    async fn check_dht_replication(&self, fingerprint: HavenFingerprint) -> ReplicationReport;
+
+    /// The relays that hold a haven's locator under our current relay graph, in the order lookups try them. Doesn't contact them.
+    async fn dht_replicas(&self, fingerprint: HavenFingerprint) -> Vec<RelayFingerprint>;
+
     async fn list_neighbors(&self) -> Vec<Either<ClientId, RelayFingerprint>>;
 
     async fn list_chats(&self) -> HashMap<String, (Option<ChatEntry>, u32)>;
@@ -237,6 +900,198 @@ pub trait ControlProtocol {
     async fn get_chat(&self, src: String) -> Result<Vec<(bool, String, SystemTime)>, ChatError>;
 
     async fn send_chat(&self, dest: String, msg: String) -> Result<(), ChatError>;
+
+    /// Lists the outgoing chats still waiting to be sent, by neighbor and then oldest first.
+    async fn list_unsent(&self) -> Vec<UnsentChat>;
+
+    /// Drops an outgoing chat that hasn't been sent yet, so that it never is.
+    async fn cancel_unsent(&self, id: u64) -> Result<(), ChatError>;
+
+    /// Returns a snapshot of the node's internal counters.
+    async fn stats(&self) -> BTreeMap<String, u64>;
+
+    /// Returns the occupancy and eviction counters of the relay graph.
+    async fn graph_stats(&self) -> GraphStats;
+
+    /// Returns percentiles of how long this relay takes to forward packets, by traffic class.
+    async fn forwarding_latency(&self) -> BTreeMap<String, ClassLatency>;
+
+    /// Returns the destinations with delayed sends in flight or waiting for a slot, the most backed up first.
+    async fn send_concurrency(&self) -> Vec<SendConcurrency>;
+
+    /// Returns the drop reports our relays sent us lately, oldest first.
+    async fn observed_drops(&self) -> Vec<ObservedDrop>;
+
+    /// Returns the routes we learned to each destination, with their current scores. For debugging.
+    async fn learned_routes(&self) -> BTreeMap<String, Vec<LearnedRoute>>;
+
+    /// Starts generating a signed bookkeeping report of traffic, debts, and settlements with each neighbor over a period. Returns a report id for [ControlProtocol::report_chunk].
+    async fn generate_report(&self, args: ReportArgs) -> u64;
+
+    /// Returns the next chunk of a report, or `None` once it's all been read.
+    async fn report_chunk(&self, report: u64) -> Result<Option<String>, ReportError>;
+
+    /// Returns the usage totals between `start` and `end`, in periods of `resolution_secs`.
+    async fn usage_history(
+        &self,
+        start: u64,
+        end: u64,
+        resolution_secs: u64,
+    ) -> Result<Vec<UsagePeriod>, ReportError>;
+
+    /// Waits for debt warning and recovery events numbered after `after`, returning them in order. Returns nothing if none happen for a while, so callers should just call again.
+    async fn debt_events(&self, after: u64) -> Vec<(u64, DebtEvent)>;
+
+    /// Waits for events numbered after `after` of us roaming away from a lost SURB anchor, returning them in order. Returns nothing if none happen for a while, so callers should just call again.
+    async fn roaming_events(&self, after: u64) -> Vec<(u64, RoamingEvent)>;
+
+    /// Returns who this node is, and how it is doing.
+    async fn whoami(&self) -> WhoAmI;
+
+    /// Returns how big messages to the destination may be, given the route we'd take to it right now.
+    async fn transport_limits(&self, destination: RelayEndpoint) -> TransportLimits;
+
+    /// Returns how many reply blocks are left in each SURB bundle we issued or imported.
+    async fn surb_bundles(&self) -> SurbBundles;
+
+    /// Imports a SURB bundle issued to this relay, encoded as [crate::SurbBundle::to_bytes], so that its relay sockets can send to the bundle's anonymous endpoint.
+    async fn import_surb_bundle(&self, bundle: Bytes) -> Result<SurbBundleStock, SurbBundleError>;
+
+    /// Returns the sockets and havens whose sending can be rate limited, with their limits and how fast they sent lately.
+    async fn skt_info(&self) -> Vec<SocketInfo>;
+
+    /// Caps how fast the socket or haven with the given id sends, or lifts its cap given `None`.
+    async fn set_socket_rate_limit(
+        &self,
+        id: u64,
+        limit: Option<SendRateLimit>,
+    ) -> Result<(), ConfigError>;
+
+    /// Returns the GlobalRPC calls still waiting for their turn or a response, oldest first, optionally only those from the socket with the given id.
+    async fn pending_sends(&self, socket_id: Option<u64>) -> Vec<PendingSend>;
+
+    /// Cancels the send with the given id, failing it and stopping its retries. Returns how the send ended, even if it finished before it could be cancelled, or `None` if it's unknown.
+    async fn cancel_send(&self, id: u64) -> Option<SendOutcome>;
+
+    /// Cancels every send from the socket with the given id, or only those to `destination`, returning the ids of the sends cancelled.
+    async fn cancel_all(&self, socket_id: u64, destination: Option<RelayFingerprint>) -> Vec<u64>;
+
+    /// Validates a candidate YAML config, and returns what would change if the daemon were restarted with it. Running daemons never switch configs.
+    async fn preview_config(&self, yaml: String) -> Result<ConfigDiff, ConfigError>;
+
+    /// Pauses the out route with the given name, dropping its link, until it's resumed. Lasts until the daemon restarts.
+    async fn pause_out_route(&self, name: String) -> Result<(), ConfigError>;
+
+    /// Resumes dialing an out route paused with `pause_out_route`.
+    async fn resume_out_route(&self, name: String) -> Result<(), ConfigError>;
+
+    /// Dials an out route once to check that it works and leads to the right relay, without adding it to the running routes.
+    async fn test_out_route(&self, cfg: OutRouteConfig) -> RouteTestResult;
+
+    /// Streams dummy data to a haven for `duration_secs`, at most a minute, and reports the goodput and loss. The haven discards the data rather than handing it to its application.
+    async fn measure_throughput(
+        &self,
+        dest: HavenEndpoint,
+        duration_secs: u64,
+    ) -> Result<ThroughputReport, ThroughputError>;
+
+    /// A snapshot of the bootstrap phase, neighbors, routes, queues, and havens, all in one.
+    async fn status(&self) -> NodeStatus;
+
+    /// Returns counts describing the whole network as we see it, such as how many relays we know of and how much traffic we carried, in one call.
+    async fn network_summary(&self) -> NetworkSummary;
+
+    /// How each of the daemon's respawned tasks has been doing, including which keep failing and how long they wait to retry.
+    async fn daemon_tasks(&self) -> Vec<TaskHealth>;
+
+    /// Replaces our relay identity with a new one, cross-signed by the current one, taking effect when the daemon restarts. Neighbors that pinned the current identity accept the new one for `grace_secs`.
+    async fn rotate_identity(
+        &self,
+        grace_secs: u64,
+    ) -> Result<IdentityRotation, RotateIdentityError>;
+
+    /// Returns up to `limit` entries of the audit log of security-relevant events, starting at entry number `after`, oldest first.
+    async fn audit_log(&self, after: u64, limit: u32) -> Result<Vec<AuditEntry>, AuditError>;
+
+    /// The last entry of the audit log, signed by our relay identity.
+    async fn audit_log_head(&self) -> Result<Option<AuditHead>, AuditError>;
+
+    /// Checks the audit log's hash chain and that it ends at the head we signed, returning the first entry that was tampered with, if any. The log must also still hold `exported`, a head returned by `audit_log_head` earlier, unless it was pruned since.
+    async fn verify_audit_log(
+        &self,
+        exported: Option<AuditHead>,
+    ) -> Result<AuditVerification, AuditError>;
+
+    /// Captures the relay graph, onion key, client id, entry guards, cached DHT locators, and debts into one blob sealed to `recipient`, for the caller to seal with a passphrase of its own; see [crate::seal_received_snapshot]. A daemon can be restarted from the result.
+    async fn snapshot_state(&self, recipient: DhPublic) -> Result<Bytes, SnapshotError>;
+
+    /// Lists the settlements neighbors asked us to accept.
+    async fn list_settlements(&self) -> Vec<String>;
+
+    /// Asks a relay neighbor to take `amount` micromel off what we owe them, for something we paid them outside of Earendil.
+    async fn send_settlement(
+        &self,
+        neighbor: RelayFingerprint,
+        amount: u64,
+    ) -> Result<(), SettlementError>;
+
+    /// Accepts the settlement a relay neighbor asked for, taking it off what they owe us.
+    async fn accept_settlement(&self, neighbor: RelayFingerprint) -> Result<(), SettlementError>;
+
+    /// Rejects the settlement a relay neighbor asked for.
+    async fn reject_settlement(&self, neighbor: RelayFingerprint) -> Result<(), SettlementError>;
+}
+
+/// What happened when an out route was dialed once, to test it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RouteTestResult {
+    /// The fingerprint the other side presented.
+    pub fingerprint: Option<RelayFingerprint>,
+    /// The version of earendil the other side runs.
+    pub version: Option<String>,
+    /// The round-trip time of a request over the link, in milliseconds.
+    pub rtt_ms: Option<u64>,
+    pub outcome: RouteTestOutcome,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteTestOutcome {
+    /// The route connects, to the configured or pinned relay if there is one.
+    Success,
+    /// The route connects, but to a relay other than the configured or pinned one.
+    FingerprintMismatch { expected: RelayFingerprint },
+    /// The other side is a client, so it can't be on the other end of an out route.
+    NotARelay,
+    /// Dialing or the handshake failed.
+    Failed(String),
+}
+
+impl RouteTestResult {
+    /// A result where we didn't learn anything about the other side.
+    pub fn failed(outcome: RouteTestOutcome) -> Self {
+        Self {
+            fingerprint: None,
+            version: None,
+            rtt_ms: None,
+            outcome,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WhoAmI {
+    pub client_id: ClientId,
+    /// Only relays have a relay fingerprint.
+    pub relay_fingerprint: Option<RelayFingerprint>,
+    /// How loaded we are, and how much transit traffic we are shedding because of it.
+    pub load: LoadState,
+    /// The relays our forward routes start at, if entry guards are on.
+    pub entry_guards: Vec<EntryGuard>,
+    /// How fresh our identity descriptor is. Only relays have one.
+    pub identity: Option<IdentityFreshness>,
+    /// How many havens use us as their rendezvous, and how much of their traffic we forward. Only relays serve as rendezvous.
+    pub rendezvous: Option<RendezvousOccupancy>,
 }
 
 #[derive(Error, Serialize, Deserialize, Debug)]
@@ -275,9 +1130,101 @@ pub struct GlobalRpcArgs {
     pub destination: RelayFingerprint,
     pub method: String,
     pub args: Vec<serde_json::Value>,
+    /// Whether to seal the request end-to-end to the destination's onion key.
+    #[serde(default)]
+    pub sealed: bool,
+    /// Whether to ask relays to NACK the request if they drop it, so that the call fails fast instead of timing out.
+    #[serde(default)]
+    pub nack: bool,
+    /// The class to send the request as, which decides how long its route is.
+    #[serde(default)]
+    pub class: MessageClass,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SendAndRecvArgs {
+    pub destination: RelayEndpoint,
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub content: Bytes,
+    /// How long to wait for the reply, in milliseconds.
+    pub timeout_ms: u64,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SendAndRecvReply {
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub content: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReportArgs {
+    /// Start of the period, in seconds since the Unix epoch, inclusive.
+    pub period_start: u64,
+    /// End of the period, in seconds since the Unix epoch, exclusive.
+    pub period_end: u64,
+    pub format: ReportFormat,
+    /// Whether to include the text of chat messages exchanged during the period.
+    #[serde(default)]
+    pub include_chats: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    /// One comma-separated row per record.
+    Csv,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphDumpFormat {
+    /// Relays and their adjacencies, then our clients, sorted so that dumps can be diffed.
+    Human,
+    /// A graph for graphviz to draw.
+    Graphviz,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphExportFormat {
+    /// GraphML, which NetworkX, Gephi and most other graph tools read.
+    Graphml,
+    /// One `source,target,unix_timestamp` row per adjacency.
+    EdgeCsv,
+}
+
+/// One page of a relay graph export. The pages put together, in order, make up the whole export.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GraphExportPage {
+    pub text: String,
+    /// What to pass as `after` to get the next page, unless this was the last one.
+    pub next: Option<RelayFingerprint>,
 }
 
 #[derive(Error, Serialize, Deserialize, Debug)]
+pub enum ReportError {
+    #[error("failed to generate report: {0}")]
+    Generate(String),
+    #[error("no report {0} is being read")]
+    UnknownReport(u64),
+}
+
+/// A GlobalRpc call running in the background.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GlobalRpcJob {
+    /// The latest progress the destination reported, if any.
+    pub progress: Option<GlobalRpcProgress>,
+    /// How many progress updates arrived, including ones superseded by later updates that arrived first.
+    pub updates: u64,
+    /// The outcome, once the call is over.
+    pub result: Option<Result<serde_json::Value, GlobalRpcError>>,
+}
+
+#[derive(Error, Serialize, Deserialize, Clone, Debug)]
 pub enum GlobalRpcError {
     #[error("error sending GlobalRpc request")]
     SendError,
@@ -289,6 +1236,42 @@ pub enum ChatError {
     Get(String),
     #[error("error sending chat message {0}")]
     Send(String),
+    #[error("error cancelling chat message {0}")]
+    Cancel(String),
+}
+
+#[derive(Error, Serialize, Deserialize, Debug)]
+pub enum SendAndRecvError {
+    #[error("request failed: {0}")]
+    Failed(String),
+}
+
+#[derive(Error, Serialize, Deserialize, Debug)]
+pub enum RotateIdentityError {
+    #[error("failed to rotate identity: {0}")]
+    Rotate(String),
+}
+
+#[derive(Error, Serialize, Deserialize, Debug)]
+pub enum ThroughputError {
+    #[error("failed to measure throughput: {0}")]
+    Measure(String),
+}
+
+#[derive(Error, Serialize, Deserialize, Debug)]
+pub enum AuditError {
+    #[error("failed to read the audit log: {0}")]
+    Read(String),
+}
+
+#[derive(Error, Serialize, Deserialize, Debug)]
+pub enum SettlementError {
+    #[error("error sending settlement {0}")]
+    Send(String),
+    #[error("error accepting settlement {0}")]
+    Accept(String),
+    #[error("error rejecting settlement {0}")]
+    Reject(String),
 }
 
 #[derive(Error, Serialize, Deserialize, Debug)]
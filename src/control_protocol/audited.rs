@@ -0,0 +1,317 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use nanorpc::{RpcService, ServerError};
+use serde_json::Value;
+
+use crate::{
+    audit::{audit_soon, AuditEvent},
+    context::DaemonContext,
+};
+
+use self::Kept::*;
+
+/// What the audit log keeps of an argument to a control call.
+enum Kept {
+    /// All of it, for arguments like fingerprints and names.
+    Whole(&'static str),
+    /// Only some fields of an object, leaving out what it carries.
+    Fields(&'static str, &'static [&'static str]),
+    /// Only how big it is, for message contents and configs, which may hold secrets.
+    Size(&'static str),
+}
+
+/// The control calls that only read, which aren't audited. Every other call is, including ones added later and never listed anywhere here.
+const READ_ONLY_CALLS: &[&str] = &[
+    "havens_info",
+    "haven_beacons",
+    "global_rpc_job",
+    "report_chunk",
+    "relay_graphviz",
+    "graph_dump",
+    "export_graph",
+    "relay_graph",
+    "my_routes",
+    "get_rendezvous",
+    "partition_check",
+    "check_dht_replication",
+    "dht_replicas",
+    "list_neighbors",
+    "list_chats",
+    "get_chat",
+    "list_unsent",
+    "stats",
+    "graph_stats",
+    "forwarding_latency",
+    "send_concurrency",
+    "observed_drops",
+    "learned_routes",
+    "usage_history",
+    "debt_events",
+    "roaming_events",
+    "whoami",
+    "transport_limits",
+    "surb_bundles",
+    "skt_info",
+    "pending_sends",
+    "preview_config",
+    "status",
+    "network_summary",
+    "daemon_tasks",
+    "audit_log",
+    "audit_log_head",
+    "verify_audit_log",
+    "list_settlements",
+];
+
+/// What the audit log keeps of each argument of the audited calls, in order. A call missing here keeps only the size of each argument.
+const KEPT_ARGS: &[(&str, &[Kept])] = &[
+    (
+        "bind_ephemeral_haven",
+        &[Whole("listen_port"), Whole("rendezvous"), Whole("handler")],
+    ),
+    ("unbind_haven", &[Whole("fingerprint")]),
+    (
+        "send_global_rpc",
+        &[Fields("args", &["destination", "method", "sealed"])],
+    ),
+    (
+        "start_global_rpc",
+        &[Fields("args", &["destination", "method", "sealed"])],
+    ),
+    (
+        "send_and_recv",
+        &[Fields("args", &["destination", "timeout_ms"])],
+    ),
+    (
+        "measure_throughput",
+        &[Whole("dest"), Whole("duration_secs")],
+    ),
+    (
+        "insert_rendezvous",
+        &[Fields("locator", &["identity_pk", "rendezvous_point"])],
+    ),
+    ("send_chat", &[Whole("dest"), Size("msg")]),
+    ("cancel_unsent", &[Whole("id")]),
+    ("set_socket_rate_limit", &[Whole("id"), Whole("limit")]),
+    ("cancel_send", &[Whole("id")]),
+    ("cancel_all", &[Whole("socket_id"), Whole("destination")]),
+    ("pause_out_route", &[Whole("name")]),
+    ("resume_out_route", &[Whole("name")]),
+    ("rotate_identity", &[Whole("grace_secs")]),
+    ("send_settlement", &[Whole("neighbor"), Whole("amount")]),
+    ("accept_settlement", &[Whole("neighbor")]),
+    ("reject_settlement", &[Whole("neighbor")]),
+    ("import_surb_bundle", &[Size("bundle")]),
+    ("snapshot_state", &[Whole("recipient")]),
+    (
+        "test_out_route",
+        &[Fields("cfg", &["connect", "fingerprint"])],
+    ),
+    (
+        "generate_report",
+        &[Fields(
+            "args",
+            &["period_start", "period_end", "format", "include_chats"],
+        )],
+    ),
+];
+
+/// Wraps a control service, recording every call to it that isn't known to only read in the audit log, along with who made it. Entries are written in the background, so calls don't wait on the state cache.
+pub struct AuditedService<S> {
+    ctx: DaemonContext,
+    inner: Arc<S>,
+    principal: String,
+}
+
+impl<S> AuditedService<S> {
+    pub fn new(ctx: &DaemonContext, inner: Arc<S>, principal: String) -> Self {
+        Self {
+            ctx: ctx.clone(),
+            inner,
+            principal,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: RpcService> RpcService for AuditedService<S> {
+    async fn respond(
+        &self,
+        method: &str,
+        params: Vec<Value>,
+    ) -> Option<Result<Value, ServerError>> {
+        if READ_ONLY_CALLS.contains(&method) {
+            return self.inner.respond(method, params).await;
+        }
+        let args = match KEPT_ARGS.iter().find(|(name, _)| *name == method) {
+            Some((_, kept)) => redact(kept, &params),
+            None => redact_all(&params),
+        };
+        let response = self.inner.respond(method, params).await;
+        audit_soon(
+            &self.ctx,
+            AuditEvent::ControlCall {
+                method: method.to_string(),
+                args,
+                principal: self.principal.clone(),
+                ok: matches!(response, Some(Ok(_))),
+            },
+        );
+        response
+    }
+}
+
+/// Summarizes a call's arguments as an object, keeping only what `kept` allows of each.
+fn redact(kept: &[Kept], params: &[Value]) -> Value {
+    let mut summary = serde_json::Map::new();
+    for (kept, param) in kept.iter().zip(params) {
+        let (name, value) = match kept {
+            Whole(name) => (name, param.clone()),
+            Fields(name, fields) => (
+                name,
+                Value::Object(
+                    fields
+                        .iter()
+                        .filter_map(|field| Some((field.to_string(), param.get(field)?.clone())))
+                        .collect(),
+                ),
+            ),
+            Size(name) => (name, size(param)),
+        };
+        summary.insert(name.to_string(), value);
+    }
+    Value::Object(summary)
+}
+
+/// Summarizes the arguments of a call we know nothing about by their sizes alone, keyed by position.
+fn redact_all(params: &[Value]) -> Value {
+    Value::Object(
+        params
+            .iter()
+            .enumerate()
+            .map(|(i, param)| (i.to_string(), size(param)))
+            .collect(),
+    )
+}
+
+fn size(param: &Value) -> Value {
+    let len = match param {
+        Value::String(s) => s.len(),
+        other => other.to_string().len(),
+    };
+    Value::String(format!("<{len} bytes>"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        audit::{audit_log, audit_writer_loop},
+        control_protocol::ControlService,
+        daemon::ControlProtocolImpl,
+    };
+
+    #[test]
+    fn calls_are_audited_without_their_contents() {
+        let dir = std::env::temp_dir().join(format!("earendil-audited-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ctx = DaemonContext::new(
+            serde_json::from_value(json!({
+                "state_cache": dir.join("state.db"),
+                "out_routes": { "main": { "connect": "127.0.0.1:1", "obfs": "none" } },
+            }))
+            .unwrap(),
+        );
+        let inner = Arc::new(ControlService(ControlProtocolImpl::new(ctx.clone())));
+        let service = AuditedService::new(&ctx, inner, "127.0.0.1:5555".into());
+        smol::future::block_on(async {
+            let _writer = smol::spawn(audit_writer_loop(ctx.clone()));
+            assert!(matches!(
+                service
+                    .respond("pause_out_route", vec![json!("main")])
+                    .await,
+                Some(Ok(_))
+            ));
+            // reading isn't audited
+            service
+                .respond("list_unsent", vec![])
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(matches!(
+                service
+                    .respond("send_chat", vec![json!("nobody"), json!("attack at dawn")])
+                    .await,
+                Some(Err(_))
+            ));
+            assert!(matches!(
+                service.respond("cancel_unsent", vec![json!(42)]).await,
+                Some(Err(_))
+            ));
+            // calls nobody listed are audited too, keeping only sizes
+            assert!(service
+                .respond("frobnicate", vec![json!("secret stuff")])
+                .await
+                .is_none());
+
+            let start = Instant::now();
+            let entries = loop {
+                let entries = audit_log(&ctx, 0, 100).await.unwrap();
+                if entries.len() >= 4 || start.elapsed() > Duration::from_secs(5) {
+                    break entries;
+                }
+                smol::Timer::after(Duration::from_millis(10)).await;
+            };
+            let events: Vec<AuditEvent> = entries.into_iter().map(|entry| entry.event).collect();
+            assert_eq!(
+                events,
+                vec![
+                    AuditEvent::ControlCall {
+                        method: "pause_out_route".into(),
+                        args: json!({ "name": "main" }),
+                        principal: "127.0.0.1:5555".into(),
+                        ok: true,
+                    },
+                    AuditEvent::ControlCall {
+                        method: "send_chat".into(),
+                        args: json!({ "dest": "nobody", "msg": "<14 bytes>" }),
+                        principal: "127.0.0.1:5555".into(),
+                        ok: false,
+                    },
+                    AuditEvent::ControlCall {
+                        method: "cancel_unsent".into(),
+                        args: json!({ "id": 42 }),
+                        principal: "127.0.0.1:5555".into(),
+                        ok: false,
+                    },
+                    AuditEvent::ControlCall {
+                        method: "frobnicate".into(),
+                        args: json!({ "0": "<12 bytes>" }),
+                        principal: "127.0.0.1:5555".into(),
+                        ok: false,
+                    },
+                ]
+            );
+        });
+        drop(service);
+        drop(ctx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn objects_keep_only_the_listed_fields() {
+        let args = redact(
+            &[Fields("args", &["destination", "timeout_ms"])],
+            &[json!({ "destination": "abcd", "content": "deadbeef", "timeout_ms": 1000 })],
+        );
+        assert_eq!(
+            args,
+            json!({ "args": { "destination": "abcd", "timeout_ms": 1000 } })
+        );
+    }
+}
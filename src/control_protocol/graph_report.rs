@@ -0,0 +1,511 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use earendil_crypt::RelayFingerprint;
+use earendil_topology::RelayGraph;
+use serde::{Deserialize, Serialize};
+
+use crate::{ledger::unix_now, usage::UsagePeriod};
+
+use super::{NeighborKind, NeighborStatus};
+
+/// Beyond this many relays, reports only have the table, since laying out the graph would take too long to be worth it.
+pub const MAX_LAYOUT_RELAYS: usize = 5000;
+
+const LAYOUT_ITERATIONS: usize = 60;
+
+/// At most how many other nodes push each node away in one layout iteration.
+const LAYOUT_REPULSIONS: usize = 32;
+
+/// A grid cell and the ones around it, own cell first.
+const NEARBY_CELLS: [(i32, i32); 9] = [
+    (0, 0),
+    (-1, -1),
+    (-1, 0),
+    (-1, 1),
+    (0, -1),
+    (0, 1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+];
+
+/// How many times the diameter estimate sweeps each component. Each sweep can only raise the estimate, which never exceeds the real diameter.
+const DIAMETER_SWEEPS: usize = 4;
+
+/// How many characters of a fingerprint reports show, unless asked for whole ones.
+const SHORT_FP_LEN: usize = 8;
+
+const HTML_TEMPLATE: &str = include_str!("graph_report.html");
+
+/// The relay graph as structured data, for tools that draw or analyze it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GraphSnapshot {
+    pub me: Option<RelayFingerprint>,
+    /// When the snapshot was taken, in seconds since the Unix epoch.
+    pub taken_at: u64,
+    /// Every relay we know of, in fingerprint order.
+    pub relays: Vec<RelaySnapshot>,
+    pub adjacencies: Vec<AdjacencySnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RelaySnapshot {
+    pub fingerprint: RelayFingerprint,
+    /// When the relay signed the identity descriptor we have, if we have one.
+    pub descriptor_timestamp: Option<u64>,
+    pub overloaded: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AdjacencySnapshot {
+    pub left: RelayFingerprint,
+    pub right: RelayFingerprint,
+    /// When the adjacency was signed.
+    pub timestamp: u64,
+}
+
+impl GraphSnapshot {
+    pub fn new(graph: &RelayGraph, me: Option<RelayFingerprint>) -> Self {
+        let adjacencies: Vec<AdjacencySnapshot> = graph
+            .all_adjacencies()
+            .map(|adj| AdjacencySnapshot {
+                left: adj.left,
+                right: adj.right,
+                timestamp: adj.unix_timestamp,
+            })
+            .collect();
+        // adjacencies can outlive the identities of their ends, and those relays still belong in the picture
+        let mut relays: BTreeMap<RelayFingerprint, Option<(u64, bool)>> = BTreeMap::new();
+        for fp in graph
+            .all_nodes()
+            .chain(adjacencies.iter().flat_map(|adj| [adj.left, adj.right]))
+        {
+            relays.entry(fp).or_insert_with(|| {
+                graph
+                    .identity(&fp)
+                    .map(|descr| (descr.unix_timestamp, descr.overloaded()))
+            });
+        }
+        Self {
+            me,
+            taken_at: unix_now(),
+            relays: relays
+                .into_iter()
+                .map(|(fingerprint, descr)| RelaySnapshot {
+                    fingerprint,
+                    descriptor_timestamp: descr.map(|(timestamp, _)| timestamp),
+                    overloaded: descr.map_or(false, |(_, overloaded)| overloaded),
+                })
+                .collect(),
+            adjacencies,
+        }
+    }
+}
+
+/// A report on the relay graph for sharing with people who don't run a node. It renders to one HTML page, with everything it needs inline, including itself as JSON for the page's scripts to read.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GraphReport {
+    pub summary: GraphSummary,
+    pub relays: Vec<ReportRelay>,
+    /// Adjacencies, as pairs of indices into `relays`.
+    pub edges: Vec<(usize, usize)>,
+    pub neighbors: Vec<ReportNeighbor>,
+    /// Whether relays have positions to draw them at, which they don't in graphs too big to lay out.
+    pub has_layout: bool,
+    /// Our hourly usage, for the traffic plot. Empty if we have no usage history.
+    #[serde(default)]
+    pub usage: Vec<UsagePeriod>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GraphSummary {
+    pub taken_at: u64,
+    pub relays: usize,
+    pub adjacencies: usize,
+    pub components: usize,
+    /// A lower bound on the longest shortest path within any component, in hops.
+    pub diameter_estimate: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReportRelay {
+    pub id: String,
+    pub adjacencies: usize,
+    /// How old the relay's identity descriptor was when the snapshot was taken.
+    pub descriptor_age_secs: Option<u64>,
+    pub overloaded: bool,
+    pub me: bool,
+    /// Whether the relay is one of our neighbors.
+    pub neighbor: bool,
+    /// Where to draw the relay, between 0 and 1 on both axes.
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReportNeighbor {
+    pub id: String,
+    pub kind: NeighborKind,
+    pub rtt_ms: Option<u64>,
+    pub packets_in: u64,
+    pub packets_out: u64,
+}
+
+impl GraphReport {
+    pub fn new(
+        snapshot: &GraphSnapshot,
+        neighbors: &[NeighborStatus],
+        full_fingerprints: bool,
+    ) -> Self {
+        let label = |id: String| {
+            if full_fingerprints {
+                id
+            } else {
+                id.chars().take(SHORT_FP_LEN).collect()
+            }
+        };
+        let index: HashMap<RelayFingerprint, usize> = snapshot
+            .relays
+            .iter()
+            .enumerate()
+            .map(|(i, relay)| (relay.fingerprint, i))
+            .collect();
+        let edges: Vec<(usize, usize)> = snapshot
+            .adjacencies
+            .iter()
+            .filter_map(|adj| Some((*index.get(&adj.left)?, *index.get(&adj.right)?)))
+            .collect();
+        let mut adjacent = vec![vec![]; snapshot.relays.len()];
+        for &(a, b) in edges.iter() {
+            adjacent[a].push(b);
+            adjacent[b].push(a);
+        }
+        let has_layout = snapshot.relays.len() <= MAX_LAYOUT_RELAYS;
+        let positions = if has_layout {
+            layout(snapshot.relays.len(), &edges)
+        } else {
+            vec![]
+        };
+        let (components, diameter_estimate) = components_and_diameter(&adjacent);
+
+        let relays = snapshot
+            .relays
+            .iter()
+            .enumerate()
+            .map(|(i, relay)| {
+                let id = relay.fingerprint.to_string();
+                ReportRelay {
+                    me: snapshot.me == Some(relay.fingerprint),
+                    neighbor: neighbors.iter().any(|neigh| neigh.id == id),
+                    id: label(id),
+                    adjacencies: adjacent[i].len(),
+                    descriptor_age_secs: relay
+                        .descriptor_timestamp
+                        .map(|timestamp| snapshot.taken_at.saturating_sub(timestamp)),
+                    overloaded: relay.overloaded,
+                    x: positions.get(i).map(|pos| pos.0),
+                    y: positions.get(i).map(|pos| pos.1),
+                }
+            })
+            .collect();
+        Self {
+            summary: GraphSummary {
+                taken_at: snapshot.taken_at,
+                relays: snapshot.relays.len(),
+                adjacencies: edges.len(),
+                components,
+                diameter_estimate,
+            },
+            relays,
+            edges,
+            neighbors: neighbors
+                .iter()
+                .map(|neigh| ReportNeighbor {
+                    // client IDs identify nobody outside this node, so they're shown whole
+                    id: match neigh.kind {
+                        NeighborKind::Relay => label(neigh.id.clone()),
+                        NeighborKind::Client => neigh.id.clone(),
+                    },
+                    kind: neigh.kind,
+                    rtt_ms: neigh.rtt_ms,
+                    packets_in: neigh.packets_in,
+                    packets_out: neigh.packets_out,
+                })
+                .collect(),
+            has_layout,
+            usage: vec![],
+        }
+    }
+
+    /// Adds our usage over the last while, plotted as hourly traffic.
+    pub fn with_usage(mut self, usage: Vec<UsagePeriod>) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Renders the report as a self-contained HTML page.
+    pub fn to_html(&self) -> String {
+        let json = serde_json::to_string(self).expect("graph reports always serialize");
+        // nothing in the data may close the script tag it's embedded in
+        HTML_TEMPLATE.replace("{{GRAPH_DATA}}", &json.replace("</", "<\\/"))
+    }
+}
+
+/// Lays out a graph with the Fruchterman-Reingold algorithm, returning positions between 0 and 1.
+fn layout(n: usize, edges: &[(usize, usize)]) -> Vec<(f32, f32)> {
+    layout_counting(n, edges).0
+}
+
+/// Like [layout], but also returns how many node pairs repulsion looked at.
+fn layout_counting(n: usize, edges: &[(usize, usize)]) -> (Vec<(f32, f32)>, usize) {
+    if n == 0 {
+        return (vec![], 0);
+    }
+    let k = (1.0 / n as f32).sqrt();
+    let cell = 2.0 * k;
+    // start on a sunflower spiral, so that layouts are reproducible and no two nodes start in the same place
+    let mut pos: Vec<(f32, f32)> = (0..n)
+        .map(|i| {
+            let r = ((i as f32 + 0.5) / n as f32).sqrt() * 0.5;
+            let angle = i as f32 * 2.399_963;
+            (0.5 + r * angle.cos(), 0.5 + r * angle.sin())
+        })
+        .collect();
+    let mut temperature = 0.1;
+    let mut pairs_checked = 0;
+    for _ in 0..LAYOUT_ITERATIONS {
+        let mut disp = vec![(0.0f32, 0.0f32); n];
+        let mut grid: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+        for (i, &(x, y)) in pos.iter().enumerate() {
+            grid.entry(((x / cell).floor() as i32, (y / cell).floor() as i32))
+                .or_default()
+                .push(i);
+        }
+        // repulsion only acts between nearby nodes, and only so many of them, so that nodes
+        // crowding into one cell can't make an iteration quadratic
+        for (&(cx, cy), members) in grid.iter() {
+            for &i in members {
+                let mut checked = 0;
+                'cells: for (dx, dy) in NEARBY_CELLS {
+                    let Some(others) = grid.get(&(cx + dx, cy + dy)) else {
+                        continue;
+                    };
+                    for &j in others {
+                        if i == j {
+                            continue;
+                        }
+                        if checked == LAYOUT_REPULSIONS {
+                            break 'cells;
+                        }
+                        checked += 1;
+                        let (ddx, ddy) = (pos[i].0 - pos[j].0, pos[i].1 - pos[j].1);
+                        let dist = (ddx * ddx + ddy * ddy).sqrt().max(1e-4);
+                        if dist > cell {
+                            continue;
+                        }
+                        let force = k * k / dist;
+                        disp[i].0 += ddx / dist * force;
+                        disp[i].1 += ddy / dist * force;
+                    }
+                }
+                pairs_checked += checked;
+            }
+        }
+        for &(a, b) in edges {
+            let (ddx, ddy) = (pos[a].0 - pos[b].0, pos[a].1 - pos[b].1);
+            let dist = (ddx * ddx + ddy * ddy).sqrt().max(1e-4);
+            let force = dist * dist / k;
+            disp[a].0 -= ddx / dist * force;
+            disp[a].1 -= ddy / dist * force;
+            disp[b].0 += ddx / dist * force;
+            disp[b].1 += ddy / dist * force;
+        }
+        for (p, d) in pos.iter_mut().zip(disp) {
+            let len = (d.0 * d.0 + d.1 * d.1).sqrt().max(1e-6);
+            let step = len.min(temperature);
+            p.0 += d.0 / len * step;
+            p.1 += d.1 / len * step;
+        }
+        temperature *= 0.93;
+    }
+
+    let (min_x, max_x, min_y, max_y) = pos.iter().fold(
+        (f32::MAX, f32::MIN, f32::MAX, f32::MIN),
+        |(min_x, max_x, min_y, max_y), &(x, y)| {
+            (min_x.min(x), max_x.max(x), min_y.min(y), max_y.max(y))
+        },
+    );
+    let scale = (max_x - min_x).max(max_y - min_y).max(1e-6);
+    let pos = pos
+        .into_iter()
+        .map(|(x, y)| ((x - min_x) / scale, (y - min_y) / scale))
+        .collect();
+    (pos, pairs_checked)
+}
+
+/// Counts the connected components, and estimates the diameter with repeated BFS sweeps from the farthest node found so far.
+fn components_and_diameter(adjacent: &[Vec<usize>]) -> (usize, usize) {
+    let bfs = |start: usize| {
+        let mut dist = vec![usize::MAX; adjacent.len()];
+        dist[start] = 0;
+        let mut queue = VecDeque::from([start]);
+        let mut farthest = (start, 0);
+        while let Some(node) = queue.pop_front() {
+            for &next in adjacent[node].iter() {
+                if dist[next] == usize::MAX {
+                    dist[next] = dist[node] + 1;
+                    if dist[next] > farthest.1 {
+                        farthest = (next, dist[next]);
+                    }
+                    queue.push_back(next);
+                }
+            }
+        }
+        (dist, farthest)
+    };
+
+    let mut seen = vec![false; adjacent.len()];
+    let mut components = 0;
+    let mut diameter = 0;
+    for start in 0..adjacent.len() {
+        if seen[start] {
+            continue;
+        }
+        components += 1;
+        let (dist, (mut from, _)) = bfs(start);
+        for (node, d) in dist.iter().enumerate() {
+            if *d != usize::MAX {
+                seen[node] = true;
+            }
+        }
+        let mut longest = 0;
+        for _ in 0..DIAMETER_SWEEPS {
+            let (_, (next, d)) = bfs(from);
+            if d <= longest {
+                break;
+            }
+            longest = d;
+            from = next;
+        }
+        diameter = diameter.max(longest);
+    }
+    (components, diameter)
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_topology::test_util::{adjacency, known_relay};
+
+    use super::*;
+
+    /// A path of relays, plus one more off to the side that nothing connects to.
+    fn path_graph(len: usize) -> (RelayGraph, Vec<RelayIdentitySecret>) {
+        let mut graph = RelayGraph::new();
+        let relays: Vec<_> = (0..=len).map(|_| known_relay(&mut graph)).collect();
+        for pair in relays[..len].windows(2) {
+            graph
+                .insert_adjacency(adjacency(&pair[0], &pair[1], unix_now()))
+                .unwrap();
+        }
+        (graph, relays)
+    }
+
+    fn embedded(html: &str) -> GraphReport {
+        let start = r#"<script id="graph-data" type="application/json">"#;
+        let json = html
+            .split(start)
+            .nth(1)
+            .unwrap()
+            .split("</script>")
+            .next()
+            .unwrap();
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn embedded_json_matches_graph() {
+        let (graph, relays) = path_graph(5);
+        let me = relays[0].public().fingerprint();
+        let snapshot = GraphSnapshot::new(&graph, Some(me));
+        let neighbor = NeighborStatus {
+            id: relays[1].public().fingerprint().to_string(),
+            kind: NeighborKind::Relay,
+            rtt_ms: Some(12),
+            packets_in: 3,
+            packets_out: 4,
+            net_debt: None,
+            remote_net_debt: None,
+            debt_divergence: None,
+        };
+
+        let usage = UsagePeriod {
+            start: 3600,
+            transit_bytes: 2048,
+            ..Default::default()
+        };
+        let html = GraphReport::new(&snapshot, &[neighbor.clone()], true)
+            .with_usage(vec![usage.clone()])
+            .to_html();
+        assert!(!html.contains("http://") && !html.contains("https://"));
+        let report = embedded(&html);
+        assert_eq!(report.summary.relays, 6);
+        assert_eq!(report.summary.adjacencies, 4);
+        assert_eq!(report.summary.components, 2);
+        assert_eq!(report.summary.diameter_estimate, 4);
+        assert!(report.has_layout);
+        assert_eq!(report.usage, vec![usage]);
+        for relay in report.relays.iter() {
+            let fp: RelayFingerprint = relay.id.parse().unwrap();
+            assert_eq!(
+                relay.adjacencies,
+                graph.neighbors(&fp).into_iter().flatten().count()
+            );
+            assert_eq!(relay.me, fp == me);
+            assert_eq!(relay.neighbor, relay.id == neighbor.id);
+            assert!(relay.descriptor_age_secs.is_some());
+            assert!((0.0..=1.0).contains(&relay.x.unwrap()));
+        }
+        for &(a, b) in report.edges.iter() {
+            let (a, b) = (&report.relays[a].id, &report.relays[b].id);
+            assert!(graph.all_adjacencies().any(|adj| {
+                let ends = [adj.left.to_string(), adj.right.to_string()];
+                ends.contains(a) && ends.contains(b)
+            }));
+        }
+
+        // by default, fingerprints are cut short, in the table and in the data alike
+        let report = embedded(&GraphReport::new(&snapshot, &[neighbor], false).to_html());
+        for relay in report.relays.iter() {
+            assert_eq!(relay.id.len(), SHORT_FP_LEN);
+            assert!(relays.iter().any(|r| r
+                .public()
+                .fingerprint()
+                .to_string()
+                .starts_with(&relay.id)));
+        }
+        assert_eq!(report.neighbors[0].id.len(), SHORT_FP_LEN);
+    }
+
+    #[test]
+    fn layout_work_is_bounded() {
+        // a ring with chords, which is about as many relays as we lay out
+        let n = MAX_LAYOUT_RELAYS;
+        let edges: Vec<(usize, usize)> = (0..n)
+            .flat_map(|i| [(i, (i + 1) % n), (i, (i * 7 + 3) % n)])
+            .filter(|(a, b)| a != b)
+            .collect();
+        let (positions, pairs) = layout_counting(n, &edges);
+        assert_eq!(positions.len(), n);
+        assert!(pairs <= LAYOUT_ITERATIONS * n * LAYOUT_REPULSIONS);
+
+        // a clique pulls all of its nodes into a single cell
+        let n = 300;
+        let edges: Vec<(usize, usize)> = (0..n)
+            .flat_map(|a| (a + 1..n).map(move |b| (a, b)))
+            .collect();
+        let (positions, pairs) = layout_counting(n, &edges);
+        assert_eq!(positions.len(), n);
+        assert!(pairs <= LAYOUT_ITERATIONS * n * LAYOUT_REPULSIONS);
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use async_compat::CompatExt;
+use async_trait::async_trait;
+use bytes::Bytes;
+use concurrent_queue::ConcurrentQueue;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::Incoming, client::conn::http1::SendRequest, header, service::service_fn, Request,
+    Response,
+};
+use hyper_util::rt::TokioIo;
+use nanorpc::{JrpcRequest, JrpcResponse, RpcService, RpcTransport};
+use smol::{future::FutureExt as _, net::TcpListener};
+
+const JSON: &str = "application/json";
+const CBOR: &str = "application/cbor";
+
+/// How control protocol responses are encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ControlEncoding {
+    #[default]
+    Json,
+    Cbor,
+}
+
+impl ControlEncoding {
+    fn content_type(self) -> &'static str {
+        match self {
+            ControlEncoding::Json => JSON,
+            ControlEncoding::Cbor => CBOR,
+        }
+    }
+
+    /// The encoding a client asked for with its `Accept` header. Anything but an explicit request for CBOR gets JSON.
+    fn from_accept(accept: Option<&str>) -> Self {
+        let wants_cbor = accept.is_some_and(|accept| {
+            accept
+                .split(',')
+                .any(|kind| kind.split(';').next().unwrap_or_default().trim() == CBOR)
+        });
+        if wants_cbor {
+            ControlEncoding::Cbor
+        } else {
+            ControlEncoding::Json
+        }
+    }
+
+    /// The encoding of a response body, going by its `Content-Type`. Servers that predate CBOR don't send one, and always answer in JSON.
+    fn from_content_type(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(content_type) if content_type.trim().starts_with(CBOR) => ControlEncoding::Cbor,
+            _ => ControlEncoding::Json,
+        }
+    }
+
+    pub fn encode_response(self, response: &JrpcResponse) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            ControlEncoding::Json => serde_json::to_vec(response)?,
+            ControlEncoding::Cbor => serde_cbor::to_vec(response)?,
+        })
+    }
+
+    pub fn decode_response(self, body: &[u8]) -> anyhow::Result<JrpcResponse> {
+        Ok(match self {
+            ControlEncoding::Json => serde_json::from_slice(body)?,
+            ControlEncoding::Cbor => serde_cbor::from_slice(body)?,
+        })
+    }
+}
+
+/// Serves the control protocol as JSON-RPC over HTTP, answering in CBOR to clients that ask for it.
+pub struct ControlHttpServer {
+    listener: TcpListener,
+}
+
+impl ControlHttpServer {
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+        })
+    }
+
+    /// Answers requests until accepting connections fails, with a service made for each connection from the address it comes from.
+    pub async fn run<S: RpcService>(
+        &self,
+        make_service: impl Fn(SocketAddr) -> S,
+    ) -> std::io::Result<()> {
+        let exec = smol::Executor::new();
+        exec.run(async {
+            loop {
+                let (conn, remote) = self.listener.accept().await?;
+                let service = make_service(remote);
+                exec.spawn(async move {
+                    let service = &service;
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .keep_alive(true)
+                        .serve_connection(
+                            TokioIo::new(conn.compat()),
+                            service_fn(|req: Request<Incoming>| async move {
+                                Ok::<_, Infallible>(match respond(service, req).await {
+                                    Ok(response) => response,
+                                    Err(err) => Response::builder()
+                                        .status(500)
+                                        .body(Full::new(Bytes::from(err.to_string())))
+                                        .unwrap(),
+                                })
+                            }),
+                        )
+                        .await;
+                })
+                .detach();
+            }
+        })
+        .await
+    }
+}
+
+async fn respond(
+    service: &impl RpcService,
+    req: Request<Incoming>,
+) -> anyhow::Result<Response<Full<Bytes>>> {
+    let encoding = ControlEncoding::from_accept(
+        req.headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let body = req.into_body().collect().await?.to_bytes();
+    let jrpc_req: JrpcRequest = serde_json::from_slice(&body)?;
+    let jrpc_response = service.respond_raw(jrpc_req).await;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, encoding.content_type())
+        .body(Full::new(encoding.encode_response(&jrpc_response)?.into()))?)
+}
+
+type Conn = SendRequest<Full<Bytes>>;
+
+/// An HTTP transport for the control protocol, which understands responses in either encoding.
+pub struct ControlHttpTransport {
+    exec: smol::Executor<'static>,
+    remote: SocketAddr,
+    encoding: ControlEncoding,
+    pool: ConcurrentQueue<(Conn, Instant)>,
+    idle_timeout: Duration,
+    timeout: Duration,
+}
+
+impl ControlHttpTransport {
+    pub fn new(remote: SocketAddr, encoding: ControlEncoding) -> Self {
+        Self {
+            exec: smol::Executor::new(),
+            remote,
+            encoding,
+            pool: ConcurrentQueue::bounded(64),
+            idle_timeout: Duration::from_secs(60),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    async fn open_conn(&self) -> std::io::Result<Conn> {
+        while let Ok((conn, last_used)) = self.pool.pop() {
+            if last_used.elapsed() < self.idle_timeout {
+                return Ok(conn);
+            }
+        }
+        let conn = smol::net::TcpStream::connect(self.remote).await?;
+        let (conn, handle) = hyper::client::conn::http1::handshake(TokioIo::new(conn.compat()))
+            .await
+            .map_err(broken_pipe)?;
+        self.exec
+            .spawn(async move {
+                let _ = handle.await;
+            })
+            .detach();
+        Ok(conn)
+    }
+
+    async fn call_once(&self, req: JrpcRequest) -> std::io::Result<JrpcResponse> {
+        let mut conn = self.open_conn().await?;
+        let response = conn
+            .send_request(
+                Request::builder()
+                    .method("POST")
+                    .header(header::CONTENT_TYPE, JSON)
+                    .header(header::ACCEPT, self.encoding.content_type())
+                    .body(Full::new(serde_json::to_vec(&req)?.into()))
+                    .expect("could not build request"),
+            )
+            .await
+            .map_err(broken_pipe)?;
+        let encoding = ControlEncoding::from_content_type(
+            response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok()),
+        );
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(broken_pipe)?
+            .to_bytes();
+        let response = encoding
+            .decode_response(&body)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let _ = self.pool.push((conn, Instant::now()));
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl RpcTransport for ControlHttpTransport {
+    type Error = std::io::Error;
+
+    async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        self.call_once(req)
+            .or(async {
+                smol::Timer::after(self.timeout).await;
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "control protocol request timed out",
+                ))
+            })
+            .or(self.exec.run(smol::future::pending()))
+            .await
+    }
+}
+
+fn broken_pipe(e: impl std::error::Error + Send + Sync + 'static) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_topology::{
+        test_util::{adjacency, known_relay, now_secs},
+        RelayGraph,
+    };
+    use nanorpc::{JrpcError, JrpcId};
+
+    use super::*;
+    use crate::control_protocol::GraphSnapshot;
+
+    #[test]
+    fn responses_round_trip() {
+        let ok = JrpcResponse {
+            jsonrpc: "2.0".into(),
+            result: Some(serde_json::json!({
+                "fingerprint": [0, 1, 255],
+                "taken_at": u64::MAX,
+                "offset": -5,
+                "shed_ratio": 0.25,
+                "me": null,
+                "name": "relay \"1\"\n",
+            })),
+            error: None,
+            id: JrpcId::Number(7),
+        };
+        let failed = JrpcResponse {
+            jsonrpc: "2.0".into(),
+            result: None,
+            error: Some(JrpcError {
+                code: -1,
+                message: "no such chat".into(),
+                data: serde_json::json!({ "NoSuchChat": "nobody" }),
+            }),
+            id: JrpcId::String("a".into()),
+        };
+        for encoding in [ControlEncoding::Json, ControlEncoding::Cbor] {
+            for response in [&ok, &failed] {
+                let decoded = encoding
+                    .decode_response(&encoding.encode_response(response).unwrap())
+                    .unwrap();
+                assert_eq!(
+                    serde_json::to_value(&decoded).unwrap(),
+                    serde_json::to_value(response).unwrap(),
+                    "{encoding:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn negotiates_encodings() {
+        assert_eq!(ControlEncoding::from_accept(None), ControlEncoding::Json);
+        assert_eq!(
+            ControlEncoding::from_accept(Some("*/*")),
+            ControlEncoding::Json
+        );
+        assert_eq!(
+            ControlEncoding::from_accept(Some("application/json, application/cbor;q=0.9")),
+            ControlEncoding::Cbor
+        );
+        assert_eq!(
+            ControlEncoding::from_content_type(Some("application/cbor")),
+            ControlEncoding::Cbor
+        );
+        // what servers that predate CBOR send
+        assert_eq!(
+            ControlEncoding::from_content_type(None),
+            ControlEncoding::Json
+        );
+    }
+
+    #[test]
+    fn cbor_shrinks_graph_snapshots() {
+        let mut graph = RelayGraph::new();
+        let relays: Vec<_> = (0..1000).map(|_| known_relay(&mut graph)).collect();
+        for (i, relay) in relays.iter().enumerate() {
+            for step in [1, 7, 31] {
+                let other = &relays[(i + step) % relays.len()];
+                graph
+                    .insert_adjacency(adjacency(relay, other, now_secs()))
+                    .unwrap();
+            }
+        }
+        let snapshot = GraphSnapshot::new(&graph, Some(relays[0].public().fingerprint()));
+        let response = JrpcResponse {
+            jsonrpc: "2.0".into(),
+            result: Some(serde_json::to_value(snapshot).unwrap()),
+            error: None,
+            id: JrpcId::Number(1),
+        };
+        let json = ControlEncoding::Json.encode_response(&response).unwrap();
+        let cbor = ControlEncoding::Cbor.encode_response(&response).unwrap();
+        assert!(
+            cbor.len() * 10 < json.len() * 6,
+            "cbor {} bytes, json {} bytes",
+            cbor.len(),
+            json.len()
+        );
+    }
+}
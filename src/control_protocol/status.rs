@@ -0,0 +1,391 @@
+use std::{collections::BTreeMap, fmt::Display, time::Duration};
+
+use earendil_crypt::{ClientId, RelayFingerprint};
+use serde::{Deserialize, Serialize};
+
+pub use crate::network::BootstrapPhase;
+
+use crate::config::RouteDirection;
+
+/// How many of the deepest queues the status view shows.
+const TOP_QUEUES: usize = 5;
+
+/// Everything an operator looks at to tell what a node is doing, in one snapshot.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NodeStatus {
+    pub bootstrap: BootstrapPhase,
+    pub neighbors: Vec<NeighborStatus>,
+    pub routes: Vec<RouteStatus>,
+    /// Outgoing queues that have anything in them.
+    pub queues: Vec<QueueStatus>,
+    pub havens: Vec<HavenStatus>,
+}
+
+/// Whether a node relays for others, or only uses the network.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeMode {
+    Client,
+    Relay,
+}
+
+/// A node's view of the network as a whole, in one snapshot for dashboards. Traffic counts from the start, so rates come from comparing two snapshots.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NetworkSummary {
+    pub mode: NodeMode,
+    pub client_id: ClientId,
+    /// Only relays have a relay fingerprint.
+    pub relay_fingerprint: Option<RelayFingerprint>,
+    /// Relays in our relay graph, including ourselves if we are one.
+    pub known_relays: usize,
+    pub relay_neighbors: usize,
+    pub client_neighbors: usize,
+    /// Packets received from all neighbors since the daemon started.
+    pub packets_in: u64,
+    /// Packets sent to all neighbors since the daemon started.
+    pub packets_out: u64,
+    /// The net debt of all our neighbors together, in micromel. Positive means that on balance, they owe us.
+    pub net_debt: i128,
+    /// Haven locators cached from our own DHT lookups.
+    pub dht_cached: u64,
+    /// Haven locators we hold as a DHT replica for others.
+    pub dht_replicated: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NeighborKind {
+    Client,
+    Relay,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NeighborStatus {
+    pub id: String,
+    pub kind: NeighborKind,
+    /// Round-trip time of the last ping over the link, if one finished yet.
+    pub rtt_ms: Option<u64>,
+    /// Packets received from the neighbor since the daemon started.
+    pub packets_in: u64,
+    /// Packets sent to the neighbor since the daemon started.
+    pub packets_out: u64,
+    pub net_debt: Option<i128>,
+    /// How much the neighbor owes us by its own ledger, as of the last time we compared.
+    #[serde(default)]
+    pub remote_net_debt: Option<i128>,
+    /// How much more the neighbor owes us by our ledger than by its own.
+    #[serde(default)]
+    pub debt_divergence: Option<i128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteState {
+    Connecting,
+    Up,
+    /// The last attempt failed, and the route is waiting to retry.
+    Failed,
+    /// An operator paused the route, so it isn't dialing until resumed.
+    Paused,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RouteStatus {
+    pub name: String,
+    pub direction: RouteDirection,
+    pub state: RouteState,
+    /// Links currently up through the route.
+    pub links: usize,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct QueueStatus {
+    pub name: String,
+    pub depth: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HavenStatus {
+    pub fingerprint: String,
+    pub listen_port: u16,
+    /// Whether the haven is registered with its rendezvous and accepting connections.
+    pub serving: bool,
+}
+
+impl NodeStatus {
+    /// Why the node should not be considered healthy, if it shouldn't.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.bootstrap < BootstrapPhase::FirstRouteComputed {
+            problems.push(format!(
+                "bootstrap only reached {}",
+                phase_name(self.bootstrap)
+            ));
+        }
+        for route in self.routes.iter() {
+            if route.state == RouteState::Failed {
+                problems.push(format!(
+                    "{} route {} failed: {}",
+                    direction_name(route.direction),
+                    route.name,
+                    route.last_error.as_deref().unwrap_or("unknown error")
+                ));
+            }
+        }
+        problems
+    }
+}
+
+/// A [NodeStatus] rendered for the terminal. Given an earlier snapshot, traffic shows as rates since that snapshot.
+pub struct StatusView<'a> {
+    status: &'a NodeStatus,
+    previous: Option<(&'a NodeStatus, Duration)>,
+}
+
+impl<'a> StatusView<'a> {
+    pub fn new(status: &'a NodeStatus, previous: Option<(&'a NodeStatus, Duration)>) -> Self {
+        Self { status, previous }
+    }
+
+    /// Packets per second in and out of each neighbor since the previous snapshot.
+    fn rates(&self) -> BTreeMap<&str, (f64, f64)> {
+        let Some((previous, elapsed)) = self.previous else {
+            return BTreeMap::new();
+        };
+        let secs = elapsed.as_secs_f64().max(0.001);
+        let before: BTreeMap<&str, (u64, u64)> = previous
+            .neighbors
+            .iter()
+            .map(|neigh| (neigh.id.as_str(), (neigh.packets_in, neigh.packets_out)))
+            .collect();
+        self.status
+            .neighbors
+            .iter()
+            .filter_map(|neigh| {
+                let (packets_in, packets_out) = before.get(neigh.id.as_str())?;
+                Some((
+                    neigh.id.as_str(),
+                    (
+                        neigh.packets_in.saturating_sub(*packets_in) as f64 / secs,
+                        neigh.packets_out.saturating_sub(*packets_out) as f64 / secs,
+                    ),
+                ))
+            })
+            .collect()
+    }
+}
+
+impl Display for StatusView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = self.status;
+        writeln!(f, "bootstrap: {}", phase_name(status.bootstrap))?;
+
+        writeln!(f, "\nneighbors ({}):", status.neighbors.len())?;
+        writeln!(
+            f,
+            "  {:<16} {:<6} {:>8} {:>10} {:>10} {:>12}",
+            "ID", "TYPE", "RTT", "IN pkt/s", "OUT pkt/s", "DEBT"
+        )?;
+        let rates = self.rates();
+        for neigh in status.neighbors.iter() {
+            let kind = match neigh.kind {
+                NeighborKind::Client => "client",
+                NeighborKind::Relay => "relay",
+            };
+            let (rate_in, rate_out) = match rates.get(neigh.id.as_str()) {
+                Some((rate_in, rate_out)) => (format!("{rate_in:.1}"), format!("{rate_out:.1}")),
+                None => ("-".into(), "-".into()),
+            };
+            writeln!(
+                f,
+                "  {:<16} {:<6} {:>8} {:>10} {:>10} {:>12}",
+                truncate(&neigh.id, 16),
+                kind,
+                neigh
+                    .rtt_ms
+                    .map_or("-".into(), |rtt_ms| format!("{rtt_ms}ms")),
+                rate_in,
+                rate_out,
+                neigh.net_debt.map_or("-".into(), |debt| debt.to_string()),
+            )?;
+        }
+
+        writeln!(f, "\nroutes ({}):", status.routes.len())?;
+        for route in status.routes.iter() {
+            let state = match route.state {
+                RouteState::Connecting => "connecting",
+                RouteState::Up => "up",
+                RouteState::Failed => "failed",
+                RouteState::Paused => "paused",
+            };
+            write!(
+                f,
+                "  {:<3} {:<16} {:<10} {} links",
+                direction_name(route.direction),
+                truncate(&route.name, 16),
+                state,
+                route.links
+            )?;
+            if let Some(err) = route.last_error.as_ref() {
+                write!(f, "  last error: {}", truncate(err, 60))?;
+            }
+            writeln!(f)?;
+        }
+
+        let mut queues: Vec<&QueueStatus> = status.queues.iter().collect();
+        queues.sort_by(|a, b| b.depth.cmp(&a.depth).then_with(|| a.name.cmp(&b.name)));
+        writeln!(f, "\ntop queues:")?;
+        for queue in queues.into_iter().take(TOP_QUEUES) {
+            writeln!(f, "  {:<30} {:>6}", truncate(&queue.name, 30), queue.depth)?;
+        }
+
+        writeln!(f, "\nhavens ({}):", status.havens.len())?;
+        for haven in status.havens.iter() {
+            writeln!(
+                f,
+                "  {}:{} {}",
+                haven.fingerprint,
+                haven.listen_port,
+                if haven.serving { "serving" } else { "starting" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn phase_name(phase: BootstrapPhase) -> &'static str {
+    match phase {
+        BootstrapPhase::Starting => "starting",
+        BootstrapPhase::NeighborConnected => "neighbor connected",
+        BootstrapPhase::GraphLearned => "graph learned",
+        BootstrapPhase::FirstRouteComputed => "first route computed",
+    }
+}
+
+fn direction_name(direction: RouteDirection) -> &'static str {
+    match direction {
+        RouteDirection::In => "in",
+        RouteDirection::Out => "out",
+    }
+}
+
+/// Cuts a string down to at most `max` characters, so that long names and errors don't wrap.
+fn truncate(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        s.to_string()
+    } else {
+        s.chars()
+            .take(max - 1)
+            .chain(std::iter::once('…'))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> NodeStatus {
+        NodeStatus {
+            bootstrap: BootstrapPhase::FirstRouteComputed,
+            neighbors: vec![
+                NeighborStatus {
+                    id: "c0ffee00c0ffee00c0ffee00".into(),
+                    kind: NeighborKind::Relay,
+                    rtt_ms: Some(42),
+                    packets_in: 100,
+                    packets_out: 50,
+                    net_debt: Some(-3),
+                    remote_net_debt: Some(-3),
+                    debt_divergence: Some(0),
+                },
+                NeighborStatus {
+                    id: "1234".into(),
+                    kind: NeighborKind::Client,
+                    rtt_ms: None,
+                    packets_in: 0,
+                    packets_out: 0,
+                    net_debt: None,
+                    remote_net_debt: None,
+                    debt_divergence: None,
+                },
+            ],
+            routes: vec![RouteStatus {
+                name: "main".into(),
+                direction: RouteDirection::Out,
+                state: RouteState::Up,
+                links: 1,
+                last_error: None,
+            }],
+            queues: (0..8)
+                .map(|i| QueueStatus {
+                    name: format!("relay:{i}"),
+                    depth: i,
+                })
+                .collect(),
+            havens: vec![],
+        }
+    }
+
+    #[test]
+    fn renders_every_section_in_order() {
+        let status = status();
+        let rendered = StatusView::new(&status, None).to_string();
+        let sections: Vec<&str> = rendered
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with(' '))
+            .collect();
+        assert_eq!(
+            sections,
+            vec![
+                "bootstrap: first route computed",
+                "neighbors (2):",
+                "routes (1):",
+                "top queues:",
+                "havens (0):",
+            ]
+        );
+        // ids are cut to the column width, and there are no rates without an earlier snapshot
+        assert!(rendered
+            .contains("c0ffee00c0ffee0… relay      42ms          -          -           -3"));
+        // only the deepest queues, deepest first
+        let queues: Vec<&str> = rendered
+            .lines()
+            .skip_while(|line| *line != "top queues:")
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(
+            queues,
+            vec!["relay:7", "relay:6", "relay:5", "relay:4", "relay:3"]
+        );
+
+        // with an earlier snapshot, traffic shows as rates
+        let mut later = status.clone();
+        later.neighbors[0].packets_in += 20;
+        let rendered = StatusView::new(&later, Some((&status, Duration::from_secs(2)))).to_string();
+        assert!(rendered.contains("42ms       10.0        0.0"));
+    }
+
+    #[test]
+    fn problems_decide_the_exit_code() {
+        let mut status = status();
+        assert!(status.problems().is_empty());
+
+        status.bootstrap = BootstrapPhase::GraphLearned;
+        assert_eq!(
+            status.problems(),
+            vec!["bootstrap only reached graph learned"]
+        );
+
+        status.bootstrap = BootstrapPhase::FirstRouteComputed;
+        status.routes[0].state = RouteState::Failed;
+        status.routes[0].last_error = Some("connection refused".into());
+        assert_eq!(
+            status.problems(),
+            vec!["out route main failed: connection refused"]
+        );
+    }
+}
@@ -1,26 +1,35 @@
 mod control_protocol_impl;
+mod global_rpc_jobs;
+mod graph_dump;
+mod graph_export;
+mod identity_refresh;
+mod identity_rotation;
+mod partition;
 
 mod inout_route;
 mod link;
+mod report;
 mod serve_haven;
 mod socks5;
+mod status_page;
+use anyhow::Context as _;
 use async_trait::async_trait;
 use bytes::Bytes;
 use clone_macro::clone;
-use earendil_crypt::{AnonEndpoint, ClientId, RelayFingerprint, RelayIdentitySecret};
+use earendil_crypt::{ClientId, RelayFingerprint, RelayIdentitySecret};
 use earendil_packet::ForwardInstruction;
 
-use earendil_topology::{IdentityDescriptor, RelayGraph};
+use earendil_topology::RelayGraph;
 use futures::future::Shared;
 use futures::task::noop_waker;
 use futures_util::{stream::FuturesUnordered, FutureExt, StreamExt, TryFutureExt};
 use nanorpc::{JrpcRequest, JrpcResponse, RpcService, RpcTransport};
-use nanorpc_http::server::HttpRpcServer;
 
 use nursery_macro::nursery;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
-use smolscale::immortal::{Immortal, RespawnStrategy};
+use smol::future::FutureExt as _;
+use smol_timeout::TimeoutExt;
 mod chat;
 use stdcode::StdcodeSerializeExt;
 use tracing::instrument;
@@ -34,67 +43,165 @@ use crate::{
     context::MY_CLIENT_ID,
     daemon::inout_route::{dial_out_route, listen_in_route},
     haven::rendezvous_forward_loop,
-    n2r_socket::{n2r_socket_shuttle, N2rClientSocket},
+    n2r_socket::{fallback_haven_loop, n2r_socket_shuttle},
+};
+use crate::{
+    context::{self, MY_RELAY_IDENTITY},
+    n2r_socket::{N2rRelaySocket, SealedReceiver, SealedRelaySocket},
 };
-use crate::{context::MY_RELAY_IDENTITY, n2r_socket::N2rRelaySocket};
 
-use crate::control_protocol::ControlClient;
-use crate::db::db_write;
+use crate::audit;
+use crate::clock;
+use crate::control_protocol::{ControlClient, ControlHttpServer};
+use crate::db::{db_write, encode_graph, MiscKey, StateCacheClaim};
+use crate::ledger;
+use crate::n2r::{self, ENTRY_GUARDS, ROUTE_MEMORY};
+use crate::network;
+use crate::scope::{self, respawn_scoped, Stage};
+use crate::snapshot;
+use crate::usage;
 
-use crate::control_protocol::ControlService;
-use crate::{log_error, OutRouteConfig};
+use crate::control_protocol::{AuditedService, ControlService};
+use crate::{OutRouteConfig, QuicConnectConfig};
 
 use crate::{
     config::ConfigFile,
-    context::{MY_RELAY_ONION_SK, RELAY_GRAPH},
-    global_rpc::GLOBAL_RPC_DOCK,
+    context::{DEBTS, MY_SEALED_SERVICE_SK, RELAY_GRAPH},
+    global_rpc::{GLOBAL_RPC_DOCK, GLOBAL_RPC_SEALED_DOCK},
 };
-use crate::{context::DaemonContext, global_rpc::server::GlobalRpcImpl};
-use crate::{control_protocol::SendMessageError, global_rpc::GlobalRpcService};
+use crate::{context::DaemonContext, global_rpc::server::respond_with_progress};
+use crate::{control_protocol::SendMessageError, global_rpc::GlobalRpcRequest};
 
-pub use self::chat::ChatEntry;
-use self::control_protocol_impl::ControlProtocolImpl;
+pub use self::chat::{ChatEntry, UnsentChat};
+pub(crate) use self::control_protocol_impl::ControlProtocolImpl;
+pub use self::identity_refresh::IdentityFreshness;
+pub use self::identity_rotation::IdentityRotation;
+pub(crate) use self::inout_route::quic::listen_fingerprint as quic_fingerprint;
+pub use self::partition::{PartitionReport, WatchedRelay};
 
 pub struct Daemon {
     pub(crate) ctx: DaemonContext,
     task: Shared<smol::Task<Result<(), Arc<anyhow::Error>>>>,
+    send_stop: smol::channel::Sender<Duration>,
 }
 
 impl Daemon {
-    /// Initializes the daemon and starts all background loops
-    pub fn init(mut config: ConfigFile) -> anyhow::Result<Daemon> {
+    /// Initializes the daemon and starts all background loops, returning a handle to it. Any number of daemons can run in one process, as long as they don't share a state cache.
+    pub fn start(config: ConfigFile) -> anyhow::Result<Daemon> {
+        Self::start_from(config, None)
+    }
+
+    /// Like [Daemon::start], but resuming from a snapshot taken with [Daemon::snapshot_state] and the same passphrase, rather than rebuilding the relay graph and the rest from scratch.
+    pub fn restore_state(
+        config: ConfigFile,
+        snapshot: &[u8],
+        passphrase: &str,
+    ) -> anyhow::Result<Daemon> {
+        Self::start_from(config, Some((snapshot, passphrase)))
+    }
+
+    fn start_from(
+        mut config: ConfigFile,
+        snapshot: Option<(&[u8], &str)>,
+    ) -> anyhow::Result<Daemon> {
+        let state_cache_claim = config
+            .state_cache
+            .as_deref()
+            .map(StateCacheClaim::new)
+            .transpose()?;
+        // fail to start, rather than panic later in whatever first needs the identity
+        let my_identity = config
+            .identity
+            .as_ref()
+            .map(|identity| identity.actualize_relay())
+            .transpose()
+            .context("failed to initialize global identity")?;
+
         // If we are a relay, add ourselves into out_routes
         if let Some((_k, v)) = config.in_routes.first_key_value() {
-            let my_relay_fp = config
-                .identity
-                .clone()
-                .unwrap()
-                .actualize_relay()
-                .expect("failed to initialize global identity")
+            let my_relay_fp = my_identity
+                .context("relays must have an identity")?
                 .public()
                 .fingerprint();
             let mut rng = rand::thread_rng();
             let key: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
             let self_outroute_cfg = OutRouteConfig {
-                connect: v.listen.to_string(),
-                fingerprint: my_relay_fp,
+                connect: v
+                    .listen
+                    .resolve()
+                    .context("cannot resolve in-route listen address")?
+                    .to_string(),
+                fingerprint: Some(my_relay_fp),
                 obfs: v.obfs.clone(),
+                tofu: false,
+                pacing: v.pacing,
+                strict_prepay: v.strict_prepay,
+                graduated_admission: v.graduated_admission,
+                quic: v
+                    .quic
+                    .as_ref()
+                    .map(|quic| {
+                        anyhow::Ok(QuicConnectConfig {
+                            server_fingerprint: quic.fingerprint(my_identity.as_ref())?,
+                        })
+                    })
+                    .transpose()?,
             };
             config.out_routes.insert(key, self_outroute_cfg);
         }
 
         let ctx = DaemonContext::new(config);
+        if let Some((snapshot, passphrase)) = snapshot {
+            snapshot::restore_state(&ctx, snapshot, passphrase)?;
+        }
 
         tracing::info!("starting background task for main_daemon");
-        let task = smolscale::spawn(main_daemon(ctx.clone()).map_err(Arc::new));
+        let (send_stop, recv_stop) = smol::channel::bounded(1);
+        let task = smolscale::spawn(
+            clone!([ctx], async move {
+                let _state_cache_claim = state_cache_claim;
+                main_daemon(ctx.clone())
+                    .race(async {
+                        let grace = recv_stop.recv().await?;
+                        tracing::info!("stopping daemon");
+                        let left = scope::shutdown(&ctx, grace / 4).await;
+                        if !left.is_empty() {
+                            tracing::warn!(left = debug(left), "some tasks did not stop in time");
+                        }
+                        if sync_db(&ctx).timeout(grace).await.is_none() {
+                            tracing::warn!("could not sync state before the grace period ran out");
+                        }
+                        anyhow::Ok(())
+                    })
+                    .await
+            })
+            .map_err(Arc::new),
+        );
         Ok(Self {
             ctx,
             task: task.shared(),
+            send_stop,
         })
     }
 
+    /// Captures the relay graph, onion key, client id, entry guards, cached DHT locators, and debts into one blob encrypted with `passphrase`, for [Daemon::restore_state].
+    pub fn snapshot_state(&self, passphrase: &str) -> anyhow::Result<Bytes> {
+        Ok(snapshot::snapshot_state(&self.ctx, passphrase)?)
+    }
+
+    /// Stops all background loops, giving the daemon up to `grace` to persist its state first.
+    pub async fn stop(self, grace: Duration) -> anyhow::Result<()> {
+        let _ = self.send_stop.try_send(grace);
+        self.wait_until_dead().await
+    }
+
+    /// Blocks until the daemon dies, returning the fatal error, or `Ok` if it was stopped.
+    pub fn join(self) -> anyhow::Result<()> {
+        smol::future::block_on(self.wait_until_dead())
+    }
+
     pub fn is_client(&self) -> bool {
-        self.ctx.init().in_routes.is_empty()
+        context::is_client(&self.ctx)
     }
 
     pub fn identity(&self) -> Option<RelayIdentitySecret> {
@@ -107,7 +214,11 @@ impl Daemon {
 
     pub fn control_client(&self) -> ControlClient {
         ControlClient::from(DummyControlProtocolTransport {
-            inner: ControlService(ControlProtocolImpl::new(self.ctx.clone())),
+            inner: AuditedService::new(
+                &self.ctx,
+                Arc::new(ControlService(ControlProtocolImpl::new(self.ctx.clone()))),
+                "in-process".into(),
+            ),
         })
     }
 
@@ -119,6 +230,11 @@ impl Daemon {
         self.ctx.clone()
     }
 
+    /// Moves every QUIC out-route onto a fresh local UDP socket, as if our address had changed. Their links migrate to the new address rather than reconnecting.
+    pub fn rebind_quic_out_routes(&self) -> anyhow::Result<()> {
+        inout_route::quic::rebind_out_routes(&self.ctx)
+    }
+
     /// Check for an error.
     pub fn check_dead(&self) -> anyhow::Result<()> {
         match smol::future::FutureExt::poll(
@@ -133,7 +249,7 @@ impl Daemon {
 }
 
 struct DummyControlProtocolTransport {
-    inner: ControlService<ControlProtocolImpl>,
+    inner: AuditedService<ControlService<ControlProtocolImpl>>,
 }
 
 #[async_trait]
@@ -147,12 +263,14 @@ impl RpcTransport for DummyControlProtocolTransport {
 
 #[tracing::instrument(skip_all, fields(client_id=ctx.get(MY_CLIENT_ID), relay_fp=debug(ctx.get(MY_RELAY_IDENTITY).map(|id| id.public().fingerprint().to_string()[..6].to_string()))))]
 pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
-    let is_client = ctx.init().is_client();
+    let is_client = context::is_client(&ctx);
 
     scopeguard::defer!(tracing::info!(is_client, "daemon is now DROPPED!"));
+    // scoped tasks don't outlive the daemon, even if it dies rather than being stopped
+    scopeguard::defer!(scope::abort_all(&ctx));
 
     // Run the loops
-    let _relay_loops = if !is_client {
+    if !is_client {
         tracing::info!(
             "daemon starting with fingerprint {:?}",
             ctx.get(MY_RELAY_IDENTITY)
@@ -161,61 +279,109 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
                 .fingerprint()
         );
 
-        let identity_refresh_loop = Immortal::respawn(
-            RespawnStrategy::Immediate,
-            clone!([ctx], move || clone!([ctx], async move {
-                tracing::trace!("WE ARE INSERTING OURSELVES");
-                // first insert ourselves
-                let us = IdentityDescriptor::new(
-                    &ctx.get(MY_RELAY_IDENTITY)
-                        .expect("only relays have global identities"),
-                    ctx.get(MY_RELAY_ONION_SK),
-                );
-                ctx.get(RELAY_GRAPH).write().insert_identity(us)?;
-                smol::Timer::after(Duration::from_secs(1)).await;
-                anyhow::Ok(())
-            })),
+        identity_refresh::check_persisted(&ctx);
+        respawn_scoped(
+            &ctx,
+            Stage::Upkeep,
+            "identity_refresh_loop",
+            clone!([ctx], move || identity_refresh::identity_refresh_loop(
+                ctx.clone()
+            )),
         );
 
-        let global_rpc_loop = Immortal::respawn(
-            RespawnStrategy::Immediate,
-            clone!([ctx], move || global_rpc_loop(ctx.clone())
-                .map_err(log_error("global_rpc_loop"))),
+        respawn_scoped(
+            &ctx,
+            Stage::Intake,
+            "global_rpc_loop",
+            clone!([ctx], move || global_rpc_loop(ctx.clone())),
         );
 
-        let rendezvous_forward_loop = Immortal::respawn(
-            RespawnStrategy::Immediate,
-            clone!([ctx], move || rendezvous_forward_loop(ctx.clone())
-                .map_err(log_error("rendezvous_forward_loop"))),
+        respawn_scoped(
+            &ctx,
+            Stage::Intake,
+            "sealed_global_rpc_loop",
+            clone!([ctx], move || sealed_global_rpc_loop(ctx.clone())),
         );
 
-        Some((
-            identity_refresh_loop,
-            global_rpc_loop,
-            rendezvous_forward_loop,
-        ))
-    } else {
-        None
-    };
-
-    let _state_cache_sync_loop = ctx.init().state_cache.clone().map(|_| {
-        Immortal::respawn(
-            RespawnStrategy::Immediate,
-            clone!([ctx], move || db_sync_loop(ctx.clone())
-                .map_err(log_error("db_sync_loop"))),
-        )
-    });
-
-    let _control_protocol = Immortal::respawn(
-        RespawnStrategy::Immediate,
-        clone!([ctx], move || control_protocol_loop(ctx.clone())
-            .map_err(log_error("control_protocol"))),
+        if ctx.init().rendezvous.enabled {
+            respawn_scoped(
+                &ctx,
+                Stage::Intake,
+                "rendezvous_forward_loop",
+                clone!([ctx], move || rendezvous_forward_loop(ctx.clone())),
+            );
+        }
+
+        respawn_scoped(
+            &ctx,
+            Stage::Inflight,
+            "delay_queue_loop",
+            clone!([ctx], move || network::delay_queue_loop(ctx.clone())),
+        );
+
+        if ctx.init().delay_spill.is_some() {
+            respawn_scoped(
+                &ctx,
+                Stage::Inflight,
+                "delay_spill_loop",
+                clone!([ctx], move || network::delay_spill_loop(ctx.clone())),
+            );
+        }
+
+        if !ctx.init().fallback_rendezvous.is_empty() {
+            respawn_scoped(
+                &ctx,
+                Stage::Intake,
+                "fallback_haven_loop",
+                clone!([ctx], move || fallback_haven_loop(ctx.clone())),
+            );
+        }
+    }
+
+    if ctx.init().state_cache.is_some() {
+        respawn_scoped(
+            &ctx,
+            Stage::Upkeep,
+            "db_sync_loop",
+            clone!([ctx], move || db_sync_loop(ctx.clone())),
+        );
+    }
+
+    if ctx.init().partition_watch.is_some() {
+        respawn_scoped(
+            &ctx,
+            Stage::Upkeep,
+            "partition_watch_loop",
+            clone!([ctx], move || partition::partition_watch_loop(ctx.clone())),
+        );
+    }
+
+    respawn_scoped(
+        &ctx,
+        Stage::Upkeep,
+        "clock_jump_loop",
+        clone!([ctx], move || clock::clock_jump_loop(ctx.clone())),
     );
 
-    let _n2r_shuttle_loop = Immortal::respawn(
-        RespawnStrategy::Immediate,
-        clone!([ctx], move || n2r_socket_shuttle(ctx.clone())
-            .map_err(log_error("n2r_socket_shuttle"))),
+    respawn_scoped(
+        &ctx,
+        Stage::Upkeep,
+        "audit_writer_loop",
+        clone!([ctx], move || audit::audit_writer_loop(ctx.clone())),
+    );
+
+    respawn_scoped(
+        &ctx,
+        Stage::Upkeep,
+        "control_protocol",
+        clone!([ctx], move || control_protocol_loop(ctx.clone())),
+    );
+
+    respawn_scoped(
+        &ctx,
+        Stage::Inflight,
+        "n2r_socket_shuttle",
+        clone!([ctx], move || n2r_socket_shuttle(ctx.clone())),
     );
 
     if ctx.init().in_routes.is_empty() && ctx.init().out_routes.is_empty() {
@@ -226,13 +392,13 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
         let mut fallible_tasks = FuturesUnordered::new();
 
         // For every in_routes block, spawn a task to handle incoming stuff
-        for (_in_route_name, config) in ctx.init().in_routes.iter() {
-            fallible_tasks.push(spawn!(listen_in_route(&ctx, config)));
+        for (in_route_name, config) in ctx.init().in_routes.iter() {
+            fallible_tasks.push(spawn!(listen_in_route(&ctx, in_route_name, config)));
         }
 
         // For every out_routes block, spawn a task to handle outgoing stuff
-        for (_out_route_name, config) in ctx.init().out_routes.iter() {
-            fallible_tasks.push(spawn!(dial_out_route(&ctx, config)));
+        for (out_route_name, config) in ctx.init().out_routes.iter() {
+            fallible_tasks.push(spawn!(dial_out_route(&ctx, out_route_name, config)));
         }
 
         // For every haven, serve the haven
@@ -244,6 +410,10 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
             fallible_tasks.push(spawn!(socks5::socks5_loop(&ctx, socks5_cfg)));
         }
 
+        if let Some(status_page_cfg) = ctx.init().status_page.clone() {
+            fallible_tasks.push(spawn!(status_page::status_page_loop(&ctx, status_page_cfg)));
+        }
+
         // Join all the tasks. If any of the tasks terminate with an error, that's fatal!
         while let Some(next) = fallible_tasks.next().await {
             next?;
@@ -256,25 +426,41 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
 /// Loop that handles the persistence of contex state
 async fn db_sync_loop(ctx: DaemonContext) -> anyhow::Result<()> {
     loop {
-        tracing::trace!("syncing DB...");
-        let global_id = ctx.get(MY_RELAY_IDENTITY).stdcode();
-        let graph = ctx.clone().get(RELAY_GRAPH).read().stdcode();
-        let chats = ctx.get(CHATS).stdcode();
-
-        db_write(&ctx, "global_identity", global_id).await?;
-        db_write(&ctx, "relay_graph", graph).await?;
-        db_write(&ctx, "chats", chats).await?;
-
+        sync_db(&ctx).await?;
         smol::Timer::after(Duration::from_secs(10)).await;
     }
 }
 
+/// Persists context state to the state cache
+async fn sync_db(ctx: &DaemonContext) -> anyhow::Result<()> {
+    tracing::trace!("syncing DB...");
+    let graph = encode_graph(&ctx.get(RELAY_GRAPH).read(), ctx.init().relay_graph_format)?;
+    let chats = ctx.get(CHATS).stdcode();
+
+    db_write(ctx, MiscKey::RelayGraph, graph).await?;
+    db_write(ctx, MiscKey::Chats, chats).await?;
+    let chat_nonces = ctx.get(CHATS).seen_nonces_bytes();
+    db_write(ctx, MiscKey::ChatNonces, chat_nonces).await?;
+    db_write(ctx, MiscKey::Debts, ctx.get(DEBTS).as_bytes()?).await?;
+    let route_memory = ctx.get(ROUTE_MEMORY).lock().stdcode();
+    db_write(ctx, MiscKey::RouteMemory, route_memory).await?;
+    let entry_guards = ctx.get(ENTRY_GUARDS).lock().stdcode();
+    db_write(ctx, MiscKey::EntryGuards, entry_guards).await?;
+    let (issued, imported) = n2r::persisted_surb_bundles(ctx);
+    db_write(ctx, MiscKey::IssuedSurbBundles, issued).await?;
+    db_write(ctx, MiscKey::ImportedSurbBundles, imported).await?;
+    ledger::flush_traffic(ctx).await?;
+    usage::roll_up_usage(ctx).await?;
+    Ok(())
+}
+
 #[instrument(skip(ctx))]
 /// Loop that handles the control protocol
 async fn control_protocol_loop(ctx: DaemonContext) -> anyhow::Result<()> {
-    let http = HttpRpcServer::bind(ctx.init().control_listen).await?;
-    let service = ControlService(ControlProtocolImpl::new(ctx));
-    http.run(service).await?;
+    let http = ControlHttpServer::bind(ctx.init().control_listen).await?;
+    let service = Arc::new(ControlService(ControlProtocolImpl::new(ctx.clone())));
+    http.run(|remote| AuditedService::new(&ctx, service.clone(), remote.to_string()))
+        .await?;
     Ok(())
 }
 
@@ -283,30 +469,52 @@ async fn control_protocol_loop(ctx: DaemonContext) -> anyhow::Result<()> {
 async fn global_rpc_loop(ctx: DaemonContext) -> anyhow::Result<()> {
     let relay_skt = Arc::new(N2rRelaySocket::bind(ctx.clone(), Some(GLOBAL_RPC_DOCK))?);
 
-    let my_anon_ep = AnonEndpoint::random();
-    let n2r_skt = N2rClientSocket::bind(ctx.clone(), my_anon_ep)?;
-    let service = Arc::new(GlobalRpcService(GlobalRpcImpl::new(ctx, n2r_skt)));
     nursery!(loop {
         let socket = relay_skt.clone();
         let (req, endpoint) = socket.recv_from().await?;
         tracing::debug!(endpoint = debug(endpoint), "incoming GlobalRpc server");
-        let service = service.clone();
+        let ctx = ctx.clone();
         spawn!(async move {
-            let req: JrpcRequest = serde_json::from_str(&String::from_utf8(req.to_vec())?)?;
+            let req: GlobalRpcRequest = serde_json::from_str(&String::from_utf8(req.to_vec())?)?;
             tracing::debug!(
                 endpoint = debug(endpoint),
-                method = req.method,
+                method = req.req.method,
                 "incoming GlobalRpc call"
             );
-            let resp = service.respond_raw(req).await;
-            socket
-                .send_to(
-                    Bytes::from(serde_json::to_string(&resp)?.into_bytes()),
-                    endpoint,
-                )
-                .await?;
-
-            anyhow::Ok(())
+            respond_with_progress(&ctx, req, |msg| socket.send_to(Bytes::from(msg), endpoint)).await
+        })
+        .detach();
+    })
+}
+
+#[instrument(skip(ctx))]
+/// Loop that listens to and handles incoming GlobalRpc requests sealed to our service key
+async fn sealed_global_rpc_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let relay_skt = Arc::new(SealedRelaySocket::new(
+        N2rRelaySocket::bind(ctx.clone(), Some(GLOBAL_RPC_SEALED_DOCK))?,
+        SealedReceiver::new(ctx.get(MY_SEALED_SERVICE_SK).clone()),
+    ));
+
+    nursery!(loop {
+        let socket = relay_skt.clone();
+        let (req, endpoint, reply_key) = socket.recv_from().await?;
+        tracing::debug!(
+            endpoint = debug(endpoint),
+            "incoming sealed GlobalRpc server"
+        );
+        let ctx = ctx.clone();
+        spawn!(async move {
+            let req: GlobalRpcRequest = serde_json::from_slice(&req)?;
+            tracing::debug!(
+                endpoint = debug(endpoint),
+                method = req.req.method,
+                "incoming sealed GlobalRpc call"
+            );
+            let (socket, reply_key) = (&socket, &reply_key);
+            respond_with_progress(&ctx, req, |msg| async move {
+                socket.send_to(&msg, endpoint, reply_key).await
+            })
+            .await
         })
         .detach();
     })
@@ -333,3 +541,92 @@ fn route_to_instructs(
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::IdentityDescriptor;
+
+    use super::*;
+    use crate::Micromel;
+
+    #[test]
+    fn relay_graph_survives_restart() {
+        let state_cache =
+            std::env::temp_dir().join(format!("earendil-restart-{}.db", rand::random::<u64>()));
+        let config = serde_json::json!({ "state_cache": state_cache });
+        let relays: Vec<_> = (0..10).map(|_| RelayIdentitySecret::generate()).collect();
+
+        let ctx = DaemonContext::new(serde_json::from_value(config.clone()).unwrap());
+        for relay in &relays {
+            ctx.get(RELAY_GRAPH)
+                .write()
+                .insert_identity(IdentityDescriptor::new(relay, &DhSecret::generate()))
+                .unwrap();
+        }
+        smol::future::block_on(sync_db(&ctx)).unwrap();
+        drop(ctx);
+
+        let ctx = DaemonContext::new(serde_json::from_value(config).unwrap());
+        let graph = ctx.get(RELAY_GRAPH).read();
+        for relay in &relays {
+            assert!(graph.identity(&relay.public().fingerprint()).is_some());
+        }
+        drop(graph);
+        drop(ctx);
+        let _ = std::fs::remove_file(state_cache);
+    }
+
+    #[test]
+    fn restarts_from_snapshot() {
+        // without a state cache, only the snapshot carries anything over
+        let config = || -> ConfigFile {
+            serde_json::from_value(serde_json::json!({
+                "control_listen": "127.0.0.1:0",
+                "out_routes": { "nowhere": { "connect": "127.0.0.1:1", "obfs": "none" } },
+                "privacy": { "entry_guards": {} },
+            }))
+            .unwrap()
+        };
+        let relays: Vec<_> = (0..10).map(|_| RelayIdentitySecret::generate()).collect();
+
+        let daemon = Daemon::start(config()).unwrap();
+        let ctx = daemon.ctx();
+        for relay in &relays {
+            ctx.get(RELAY_GRAPH)
+                .write()
+                .insert_identity(IdentityDescriptor::new(relay, &DhSecret::generate()))
+                .unwrap();
+        }
+        let guards = crate::n2r::current_guards(&ctx).unwrap();
+        assert!(!guards.is_empty());
+        let neigh = relays[0].public().fingerprint();
+        ctx.get(DEBTS)
+            .insert_relay_incoming_price(neigh, Micromel(10), Micromel(1000));
+        for _ in 0..3 {
+            ctx.get(DEBTS).incr_relay_incoming(neigh);
+        }
+        let debts = ctx.get(DEBTS).net_debts();
+        assert_eq!(debts, vec![(neigh.to_string(), 30)]);
+        let client_id = daemon.client_id();
+
+        let snapshot = daemon.snapshot_state("correct horse").unwrap();
+        smol::future::block_on(daemon.stop(Duration::from_secs(1))).unwrap();
+        drop(ctx);
+
+        let daemon = Daemon::restore_state(config(), &snapshot, "correct horse").unwrap();
+        let ctx = daemon.ctx();
+        assert_eq!(daemon.client_id(), client_id);
+        for relay in &relays {
+            assert!(ctx
+                .get(RELAY_GRAPH)
+                .read()
+                .identity(&relay.public().fingerprint())
+                .is_some());
+        }
+        assert_eq!(crate::n2r::current_guards(&ctx), Some(guards));
+        assert_eq!(ctx.get(DEBTS).net_debts(), debts);
+        smol::future::block_on(daemon.stop(Duration::from_secs(1))).unwrap();
+    }
+}
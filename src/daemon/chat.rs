@@ -6,19 +6,26 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, VecDeque},
     sync::Arc,
-    time::SystemTime,
+    time::{Duration, Instant, SystemTime},
 };
-use stdcode::deserialize;
+use stdcode::{deserialize, StdcodeSerializeExt};
 
-use crate::{context::CtxField, db::db_read};
+use crate::{
+    config::ChatRateLimit,
+    context::CtxField,
+    db::{db_read, MiscKey},
+};
 
 const MAX_CHAT_LEN: usize = usize::MAX;
 
+/// How many nonces of incoming chats we remember per neighbor, to drop retried pushes of chats we already have.
+const SEEN_NONCES: usize = 1024;
+
 pub static CHATS: CtxField<Chats> = |ctx| {
     smol::future::block_on(async move {
         let mut chats: Option<Chats> = None;
 
-        match db_read(ctx, "chats").await {
+        match db_read(ctx, MiscKey::Chats).await {
             Ok(Some(c)) => {
                 tracing::debug!("retrieving chats");
                 chats = deserialize(&c).ok();
@@ -31,7 +38,17 @@ pub static CHATS: CtxField<Chats> = |ctx| {
             }
         }
 
-        chats.unwrap_or(Chats::new(MAX_CHAT_LEN))
+        let mut chats = chats.unwrap_or(Chats::new(MAX_CHAT_LEN));
+        // so that a push retried across our restart still shows up only once
+        match db_read(ctx, MiscKey::ChatNonces).await {
+            Ok(Some(seen)) => match deserialize(&seen) {
+                Ok(seen) => chats.seen_nonces = seen,
+                Err(e) => tracing::warn!("error decoding chat nonces: {e}"),
+            },
+            Ok(None) => {}
+            Err(e) => tracing::warn!("error retrieving chat nonces: {e}"),
+        }
+        chats
     })
 };
 
@@ -41,6 +58,34 @@ pub struct Chats {
     max_chat_len: usize,
     #[serde(skip)]
     unsent: Arc<Event>,
+    #[serde(skip)]
+    send_budgets: DashMap<either::Either<ClientId, RelayFingerprint>, SendBudget>,
+    /// The nonces of the latest incoming chats from each neighbor, oldest first. Saved under a key of their own, since older daemons saved chats without them.
+    #[serde(skip)]
+    seen_nonces: DashMap<either::Either<ClientId, RelayFingerprint>, VecDeque<u64>>,
+}
+
+/// How many chats we may send to a neighbor right now, under a [ChatRateLimit]. Refills continuously, up to the burst size.
+struct SendBudget {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl SendBudget {
+    fn full(limit: &ChatRateLimit) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: &ChatRateLimit) -> f64 {
+        let now = Instant::now();
+        let refilled = now.saturating_duration_since(self.refilled).as_secs_f64() * limit.per_sec;
+        self.tokens = (self.tokens + refilled).min(limit.burst as f64);
+        self.refilled = now;
+        self.tokens
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -51,12 +96,24 @@ pub struct ChatEntry {
     pub is_sent: bool,
 }
 
+/// An outgoing chat that is still waiting to be sent, most likely because its neighbor is offline.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UnsentChat {
+    /// Identifies the chat to [Chats::cancel_unsent]. Derived from the chat itself, so it stays the same across restarts.
+    pub id: u64,
+    pub neighbor: String,
+    pub text: String,
+    pub age_secs: u64,
+}
+
 impl Chats {
     pub fn new(max_chat_len: usize) -> Self {
         Self {
             history: DashMap::new(),
             max_chat_len,
             unsent: Arc::new(Event::new()),
+            send_budgets: DashMap::new(),
+            seen_nonces: DashMap::new(),
         }
     }
 
@@ -70,28 +127,154 @@ impl Chats {
         self.unsent.notify_all();
     }
 
+    /// Records a chat pushed by the neighbor, unless one with the same nonce was already recorded. Returns whether the chat was new. A push whose response got lost is retried with the same nonce, so this keeps it from showing up twice.
+    pub fn record_incoming(
+        &self,
+        neighbor: either::Either<ClientId, RelayFingerprint>,
+        nonce: u64,
+        text: String,
+    ) -> bool {
+        {
+            let mut seen = self.seen_nonces.entry(neighbor).or_default();
+            if seen.contains(&nonce) {
+                return false;
+            }
+            if seen.len() >= SEEN_NONCES {
+                seen.pop_front();
+            }
+            seen.push_back(nonce);
+        }
+        self.record(neighbor, ChatEntry::new_incoming(text));
+        true
+    }
+
+    /// Encodes the nonces of the latest incoming chats, for saving under [MiscKey::ChatNonces].
+    pub fn seen_nonces_bytes(&self) -> Vec<u8> {
+        self.seen_nonces.stdcode()
+    }
+
+    /// Marks chats returned by [Chats::wait_unsent] as unsent again, because pushing them failed, so that they get retried.
+    pub fn requeue(
+        &self,
+        neighbor: either::Either<ClientId, RelayFingerprint>,
+        entries: &[ChatEntry],
+    ) {
+        if let Some(mut chat) = self.history.get_mut(&neighbor) {
+            let ids: Vec<u64> = entries.iter().map(|entry| entry.id(neighbor)).collect();
+            for entry in chat.iter_mut() {
+                if entry.is_outgoing && entry.is_sent && ids.contains(&entry.id(neighbor)) {
+                    entry.is_sent = false;
+                }
+            }
+        }
+        self.unsent.notify_all();
+    }
+
+    /// Waits for chats to the neighbor that still need sending, and marks them as sent. With a rate limit, returns no more chats than the neighbor's send budget allows, leaving the rest for later calls.
     pub async fn wait_unsent(
         &self,
         neighbor: either::Either<ClientId, RelayFingerprint>,
+        limit: Option<ChatRateLimit>,
     ) -> Vec<ChatEntry> {
-        self.unsent
-            .wait_until(move || {
-                let mut unsent = vec![];
-                if let Some(mut chat) = self.history.get_mut(&neighbor) {
-                    for entry in chat.iter_mut() {
-                        if entry.is_outgoing && !entry.is_sent {
-                            entry.is_sent = true;
-                            unsent.push(entry.clone());
-                        }
-                    }
+        loop {
+            // new chats wake us up, but a refilling budget doesn't, so we wait for budget first
+            if let Some(limit) = limit {
+                let tokens = self
+                    .send_budgets
+                    .entry(neighbor)
+                    .or_insert_with(|| SendBudget::full(&limit))
+                    .refill(&limit);
+                if tokens < 1.0 {
+                    smol::Timer::after(Duration::from_secs_f64((1.0 - tokens) / limit.per_sec))
+                        .await;
                 }
-                if unsent.is_empty() {
-                    None
-                } else {
-                    Some(unsent)
+            }
+            let unsent = self
+                .unsent
+                .wait_until(move || self.take_unsent(neighbor, limit))
+                .await;
+            if !unsent.is_empty() {
+                return unsent;
+            }
+        }
+    }
+
+    /// Marks as many unsent chats to the neighbor as sent as its budget allows, and returns them. Returns nothing only if there are no unsent chats at all.
+    fn take_unsent(
+        &self,
+        neighbor: either::Either<ClientId, RelayFingerprint>,
+        limit: Option<ChatRateLimit>,
+    ) -> Option<Vec<ChatEntry>> {
+        let mut chat = self.history.get_mut(&neighbor)?;
+        let mut budget = limit.map(|limit| {
+            let budget = self
+                .send_budgets
+                .entry(neighbor)
+                .or_insert_with(|| SendBudget::full(&limit));
+            (budget, limit)
+        });
+        let allowed = match &mut budget {
+            Some((budget, limit)) => budget.refill(limit).floor() as usize,
+            None => usize::MAX,
+        };
+
+        let mut any_unsent = false;
+        let mut unsent = vec![];
+        for entry in chat.iter_mut() {
+            if entry.is_outgoing && !entry.is_sent {
+                any_unsent = true;
+                if unsent.len() == allowed {
+                    break;
                 }
+                entry.is_sent = true;
+                unsent.push(entry.clone());
+            }
+        }
+        if let Some((budget, _)) = &mut budget {
+            budget.tokens -= unsent.len() as f64;
+        }
+        any_unsent.then_some(unsent)
+    }
+
+    /// Returns every outgoing chat that is still waiting to be sent, by neighbor and then oldest first.
+    pub fn list_unsent(&self) -> Vec<UnsentChat> {
+        let mut unsent: Vec<(String, SystemTime, UnsentChat)> = self
+            .history
+            .iter()
+            .flat_map(|x| {
+                let (neigh, deq) = x.pair();
+                deq.iter()
+                    .filter(|entry| entry.is_outgoing && !entry.is_sent)
+                    .map(|entry| {
+                        let chat = UnsentChat {
+                            id: entry.id(*neigh),
+                            neighbor: neigh.to_string(),
+                            text: entry.text.clone(),
+                            age_secs: entry.time.elapsed().unwrap_or_default().as_secs(),
+                        };
+                        (neigh.to_string(), entry.time, chat)
+                    })
+                    .collect::<Vec<_>>()
             })
-            .await
+            .collect();
+        unsent.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        unsent.into_iter().map(|(_, _, chat)| chat).collect()
+    }
+
+    /// Drops an unsent chat from the history, so that it never gets sent. Returns whether there was such a chat.
+    pub fn cancel_unsent(&self, id: u64) -> bool {
+        for mut x in self.history.iter_mut() {
+            let (neigh, deq) = x.pair_mut();
+            let neigh = *neigh;
+            if let Some(pos) = deq
+                .iter()
+                .position(|entry| entry.is_outgoing && !entry.is_sent && entry.id(neigh) == id)
+            {
+                deq.remove(pos);
+                return true;
+            }
+        }
+        false
     }
 
     pub fn dump_convo(
@@ -101,6 +284,20 @@ impl Chats {
         self.history.entry(neighbor).or_default().clone().into()
     }
 
+    /// Returns every chat entry with a timestamp in the given range, along with the neighbor it was exchanged with.
+    pub fn entries_between(&self, start: SystemTime, end: SystemTime) -> Vec<(String, ChatEntry)> {
+        self.history
+            .iter()
+            .flat_map(|x| {
+                let (neigh, deq) = x.pair();
+                deq.iter()
+                    .filter(|entry| entry.time >= start && entry.time < end)
+                    .map(|entry| (neigh.to_string(), entry.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
     pub fn all_chats(
         &self,
     ) -> HashMap<either::Either<ClientId, RelayFingerprint>, (Option<ChatEntry>, u32)> {
@@ -137,4 +334,104 @@ impl ChatEntry {
             is_sent: false,
         }
     }
+
+    /// Identifies the chat among those exchanged with the neighbor. Outgoing chats are pushed with their id as the nonce.
+    pub fn id(&self, neighbor: either::Either<ClientId, RelayFingerprint>) -> u64 {
+        let hash = blake3::hash(&(neighbor.to_string(), &self.text, self.time).stdcode());
+        u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_spreads_out_bursts() {
+        let chats = Chats::new(MAX_CHAT_LEN);
+        let neighbor = either::Either::Left(1234);
+        let limit = ChatRateLimit {
+            per_sec: 50.0,
+            burst: 5,
+        };
+        for i in 0..20 {
+            chats.record(neighbor, ChatEntry::new_outgoing(i.to_string()));
+        }
+
+        let start = Instant::now();
+        let mut sent = vec![];
+        smol::future::block_on(async {
+            while sent.len() < 20 {
+                sent.extend(chats.wait_unsent(neighbor, Some(limit)).await);
+                let allowed = limit.burst as f64 + start.elapsed().as_secs_f64() * limit.per_sec;
+                assert!(
+                    sent.len() as f64 <= allowed,
+                    "sent {} chats, but only {allowed} were allowed",
+                    sent.len()
+                );
+            }
+        });
+        // everything still went out, in order, but the chats past the burst had to wait their turn
+        let texts: Vec<String> = sent.into_iter().map(|entry| entry.text).collect();
+        assert_eq!(texts, (0..20).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert!(start.elapsed() >= Duration::from_secs_f64(15.0 / limit.per_sec));
+        assert!(chats.take_unsent(neighbor, Some(limit)).is_none());
+    }
+
+    #[test]
+    fn unsent_chats_can_be_listed_and_cancelled() {
+        let chats = Chats::new(MAX_CHAT_LEN);
+        let (offline, online) = (either::Either::Left(1), either::Either::Left(2));
+        for text in ["hello", "anyone there?"] {
+            chats.record(offline, ChatEntry::new_outgoing(text.into()));
+        }
+        chats.record(offline, ChatEntry::new_incoming("an old reply".into()));
+        chats.record(online, ChatEntry::new_outgoing("delivered".into()));
+        assert!(chats.take_unsent(online, None).is_some());
+
+        // only the outgoing chats that never went out are listed
+        let unsent = chats.list_unsent();
+        let texts: Vec<(&str, &str)> = unsent
+            .iter()
+            .map(|chat| (chat.neighbor.as_str(), chat.text.as_str()))
+            .collect();
+        assert_eq!(texts, vec![("1", "hello"), ("1", "anyone there?")]);
+
+        assert!(chats.cancel_unsent(unsent[0].id));
+        assert!(!chats.cancel_unsent(unsent[0].id));
+        assert_eq!(chats.list_unsent(), vec![unsent[1].clone()]);
+        // and a cancelled chat never gets sent
+        let sent = chats.take_unsent(offline, None).unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].text, "anyone there?");
+        assert_eq!(chats.dump_convo(offline).len(), 2);
+    }
+
+    #[test]
+    fn retried_pushes_are_recorded_once() {
+        let chats = Chats::new(MAX_CHAT_LEN);
+        let neighbor = either::Either::Left(1234);
+        assert!(chats.record_incoming(neighbor, 42, "hi".into()));
+        assert!(!chats.record_incoming(neighbor, 42, "hi".into()));
+        assert_eq!(chats.dump_convo(neighbor).len(), 1);
+        // the same text under another nonce is a different chat
+        assert!(chats.record_incoming(neighbor, 43, "hi".into()));
+        assert_eq!(chats.dump_convo(neighbor).len(), 2);
+        // and nonces are per neighbor
+        assert!(chats.record_incoming(either::Either::Left(5678), 42, "hi".into()));
+        // and survive a restart
+        let mut restarted: Chats = deserialize(&chats.stdcode()).unwrap();
+        restarted.seen_nonces = deserialize(&chats.seen_nonces_bytes()).unwrap();
+        assert!(!restarted.record_incoming(neighbor, 43, "hi".into()));
+        assert_eq!(restarted.dump_convo(neighbor).len(), 2);
+
+        // chats whose push failed get sent again, under the same nonce
+        chats.record(neighbor, ChatEntry::new_outgoing("bye".into()));
+        let sent = chats.take_unsent(neighbor, None).unwrap();
+        chats.requeue(neighbor, &sent);
+        let resent = chats.take_unsent(neighbor, None).unwrap();
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].id(neighbor), sent[0].id(neighbor));
+        assert!(chats.take_unsent(neighbor, None).is_none());
+    }
 }
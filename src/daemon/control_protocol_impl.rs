@@ -4,32 +4,79 @@ use std::{
 };
 
 use async_trait::async_trait;
+use bytes::Bytes;
 
-use earendil_crypt::{AnonEndpoint, ClientId, HavenFingerprint, RelayFingerprint};
+use earendil_crypt::{ClientId, HavenFingerprint, RelayFingerprint};
+use earendil_packet::crypt::DhPublic;
+use earendil_topology::GraphStats;
 use either::Either;
 use itertools::Itertools;
 
-use nanorpc::RpcTransport;
-
 use serde_json::json;
 use smol_timeout::TimeoutExt;
 
 use crate::{
-    context::{MY_CLIENT_ID, MY_RELAY_IDENTITY, RELAY_GRAPH},
-    control_protocol::ConfigError,
-    dht::{dht_get, dht_insert},
-    haven::HavenLocator,
-    n2r_socket::N2rClientSocket,
-    network::{all_client_neighs, all_relay_neighs},
+    audit::{self, AuditEntry, AuditHead, AuditVerification},
+    config::{ConfigDiff, ConfigFile, HavenHandler, OutRouteConfig, SendRateLimit},
+    context::{is_relay, require_relay, DEBTS, MY_CLIENT_ID, MY_RELAY_IDENTITY, RELAY_GRAPH},
+    control_protocol::{
+        ConfigError, GraphDumpFormat, GraphExportFormat, GraphExportPage, GraphSnapshot,
+        HavenStatus, NeighborKind, NeighborStatus, NetworkSummary, NodeMode, NodeStatus,
+        QueueStatus, RouteTestResult, SettlementError, WhoAmI,
+    },
+    debts::DebtEvent,
+    dht::{
+        check_dht_replication, dht_cache_size, dht_get, dht_insert, dht_replicas, ReplicationReport,
+    },
+    global_rpc::{
+        pending::{self, PendingSend, SendOutcome},
+        server::local_dht_shard_size,
+    },
+    haven::{self, BeaconStatus, HavenEndpoint, HavenLocator},
+    ledger,
+    limits::{self, TransportLimits},
+    micromel::Micromel,
+    n2r::{
+        self, LearnedRoute, RoamingEvent, SurbBundle, SurbBundleError, SurbBundleStock, SurbBundles,
+    },
+    n2r_socket::{
+        shaper::{self, SocketInfo},
+        N2rClientSocket, RelayEndpoint,
+    },
+    network::{
+        all_client_neighs, all_relay_neighs, bootstrap_phase, delay_queue_stats,
+        forwarding_latency, load_state, observed_drops, queue_depths, send_concurrency,
+        tracked_destinations, ClassLatency, LoadLevel, ObservedDrop, SendConcurrency,
+    },
+    scope::{self, TaskHealth},
+    settlement::{SettlementProof, SettlementRequest, SETTLEMENTS},
+    snapshot::{self, SnapshotError},
+    stats::STATS,
+    throughput::{measure_throughput, ThroughputReport},
+    usage::{self, UsagePeriod},
     InRouteConfig,
 };
 use crate::{
-    control_protocol::{ChatError, ControlProtocol, DhtError, GlobalRpcArgs, GlobalRpcError},
-    daemon::DaemonContext,
-    global_rpc::transport::GlobalRpcTransport,
+    control_protocol::{
+        AuditError, ChatError, ControlProtocol, DhtError, GlobalRpcArgs, GlobalRpcError,
+        GlobalRpcJob, ReportArgs, ReportError, RotateIdentityError, SendAndRecvArgs,
+        SendAndRecvError, SendAndRecvReply, ThroughputError,
+    },
+    daemon::{DaemonContext, IdentityRotation, PartitionReport},
 };
 
-use super::chat::{ChatEntry, CHATS};
+use super::{
+    chat::{ChatEntry, UnsentChat, CHATS},
+    global_rpc_jobs,
+    graph_dump::GraphDump,
+    graph_export::{export_page, EXPORT_PAGE_RELAYS},
+    identity_refresh, identity_rotation,
+    inout_route::{
+        gossip_health, is_out_route_paused, link_rtt, pacing_stats, route_statuses,
+        set_out_route_paused, test_out_route,
+    },
+    partition, report, serve_haven,
+};
 
 pub struct ControlProtocolImpl {
     ctx: DaemonContext,
@@ -43,38 +90,73 @@ impl ControlProtocolImpl {
 
 #[async_trait]
 impl ControlProtocol for ControlProtocolImpl {
-    async fn havens_info(&self) -> Result<Vec<(String, String)>, ConfigError> {
-        self.ctx
+    async fn havens_info(&self) -> Result<Vec<(String, HavenEndpoint)>, ConfigError> {
+        let mut havens = self
+            .ctx
             .init()
             .havens
             .iter()
             .map(|haven_cfg| match haven_cfg.identity.actualize_haven() {
                 Ok(secret) => {
-                    let fp = secret.public().fingerprint();
-                    match haven_cfg.handler {
-                        crate::config::HavenHandler::TcpService { upstream: _ } => Ok((
-                            "TcpService".to_string(),
-                            fp.to_string() + ":" + &haven_cfg.listen_port.to_string(),
-                        )),
-                        crate::config::HavenHandler::SimpleProxy => Ok((
-                            "SimpleProxy".to_string(),
-                            fp.to_string() + ":" + &haven_cfg.listen_port.to_string(),
-                        )),
-                    }
+                    let endpoint =
+                        HavenEndpoint::from_identity(&secret.public(), haven_cfg.listen_port);
+                    Ok((
+                        with_beacon_state(&self.ctx, handler_kind(&haven_cfg.handler), &endpoint),
+                        endpoint,
+                    ))
                 }
                 Err(err) => Err(ConfigError::Error(err.to_string())),
             })
-            .collect()
+            .collect::<Result<Vec<_>, _>>()?;
+        havens.extend(serve_haven::ephemeral_havens(&self.ctx).into_iter().map(
+            |(fingerprint, listen_port, handler)| {
+                let endpoint = HavenEndpoint::new(fingerprint, listen_port);
+                (
+                    with_beacon_state(
+                        &self.ctx,
+                        &format!("{} (ephemeral)", handler_kind(&handler)),
+                        &endpoint,
+                    ),
+                    endpoint,
+                )
+            },
+        ));
+        Ok(havens)
+    }
+
+    async fn haven_beacons(&self) -> Vec<BeaconStatus> {
+        haven::haven_beacons(&self.ctx)
+    }
+
+    async fn bind_ephemeral_haven(
+        &self,
+        listen_port: u16,
+        rendezvous: Vec<RelayFingerprint>,
+        handler: HavenHandler,
+    ) -> Result<HavenFingerprint, ConfigError> {
+        serve_haven::bind_ephemeral_haven(&self.ctx, listen_port, rendezvous, handler)
+            .await
+            .map_err(|err| ConfigError::Error(format!("could not bind haven: {err:#}")))
+    }
+
+    async fn unbind_haven(&self, fingerprint: HavenFingerprint) -> Result<(), ConfigError> {
+        if serve_haven::unbind_ephemeral_haven(&self.ctx, &fingerprint) {
+            Ok(())
+        } else {
+            Err(ConfigError::Error(format!(
+                "no ephemeral haven with fingerprint {fingerprint}"
+            )))
+        }
     }
 
     async fn my_routes(&self) -> serde_json::Value {
-        if let Some(my_relay_id) = self.ctx.get(MY_RELAY_IDENTITY) {
+        let in_routes = if let Some(my_relay_id) = self.ctx.get(MY_RELAY_IDENTITY) {
             let lala: BTreeMap<String, serde_json::Value> = self
                 .ctx
                 .init()
                 .in_routes
                 .iter()
-                .map(|(k, InRouteConfig { listen, obfs })| {
+                .map(|(k, InRouteConfig { listen, obfs, .. })| {
                     (
                         k.clone(),
                         json!({
@@ -88,7 +170,26 @@ impl ControlProtocol for ControlProtocolImpl {
             serde_json::to_value(lala).unwrap()
         } else {
             "This is a client node. Client nodes do not have in-routes.".into()
-        }
+        };
+        let out_routes: BTreeMap<String, serde_json::Value> = self
+            .ctx
+            .init()
+            .out_routes
+            .iter()
+            .map(|(name, cfg)| {
+                (
+                    name.clone(),
+                    json!({
+                        "connect": cfg.connect,
+                        "paused": is_out_route_paused(&self.ctx, name),
+                    }),
+                )
+            })
+            .collect();
+        json!({
+            "in_routes": in_routes,
+            "out_routes": out_routes,
+        })
     }
 
     async fn relay_graphviz(&self) -> String {
@@ -155,30 +256,35 @@ impl ControlProtocol for ControlProtocolImpl {
         &self,
         send_args: GlobalRpcArgs,
     ) -> Result<serde_json::Value, GlobalRpcError> {
-        let n2r_skt = N2rClientSocket::bind(self.ctx.clone(), AnonEndpoint::random())
-            .expect("failed to bind n2r socket");
-        let client = GlobalRpcTransport::new(self.ctx.clone(), send_args.destination, n2r_skt);
-        let res = if let Some(res) = client
-            .call(&send_args.method, &send_args.args)
-            .await
-            .map_err(|e| {
-                tracing::warn!("send_global_rpc transport failed with {:?}", e);
-                GlobalRpcError::SendError
-            })? {
-            res.map_err(|e| {
-                tracing::warn!("send_global_rpc remote failed with {:?}", e);
-                GlobalRpcError::SendError
-            })?
-        } else {
-            return Err(GlobalRpcError::SendError);
-        };
-        Ok(res)
+        global_rpc_jobs::call_global_rpc(&self.ctx, send_args, None).await
+    }
+
+    async fn send_and_recv(
+        &self,
+        args: SendAndRecvArgs,
+    ) -> Result<SendAndRecvReply, SendAndRecvError> {
+        let content = N2rClientSocket::send_and_recv(
+            &self.ctx,
+            args.destination,
+            args.content,
+            Duration::from_millis(args.timeout_ms),
+        )
+        .await
+        .map_err(|e| SendAndRecvError::Failed(format!("{e:#}")))?;
+        Ok(SendAndRecvReply { content })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn start_global_rpc(&self, args: GlobalRpcArgs) -> u64 {
+        global_rpc_jobs::start_global_rpc(&self.ctx, args)
+    }
+
+    async fn global_rpc_job(&self, job: u64) -> Option<GlobalRpcJob> {
+        global_rpc_jobs::global_rpc_job(&self.ctx, job)
     }
 
     async fn insert_rendezvous(&self, locator: HavenLocator) -> Result<(), DhtError> {
-        let n2r_skt = N2rClientSocket::bind(self.ctx.clone(), AnonEndpoint::random())
-            .expect("failed to bind n2r client socket");
-        dht_insert(&self.ctx, locator, &n2r_skt).await;
+        dht_insert(&self.ctx, locator).await;
         Ok(())
     }
 
@@ -186,17 +292,19 @@ impl ControlProtocol for ControlProtocolImpl {
         &self,
         fingerprint: HavenFingerprint,
     ) -> Result<Option<HavenLocator>, DhtError> {
-        let n2r_skt = N2rClientSocket::bind(self.ctx.clone(), AnonEndpoint::random())
-            .expect("failed to bind n2r client socket");
-        dht_get(&self.ctx, fingerprint, &n2r_skt)
-            .timeout(Duration::from_secs(30))
-            .await
-            .map_or(
-                Err(DhtError::NetworkFailure(
-                    "dht_get({key}) timed out".to_owned(),
-                )),
-                |res| res,
-            )
+        dht_get(&self.ctx, fingerprint).await
+    }
+
+    async fn check_dht_replication(&self, fingerprint: HavenFingerprint) -> ReplicationReport {
+        check_dht_replication(&self.ctx, fingerprint).await
+    }
+
+    async fn dht_replicas(&self, fingerprint: HavenFingerprint) -> Vec<RelayFingerprint> {
+        dht_replicas(&self.ctx, fingerprint)
+    }
+
+    async fn partition_check(&self) -> PartitionReport {
+        partition::partition_check(&self.ctx)
     }
 
     async fn list_neighbors(&self) -> Vec<Either<ClientId, RelayFingerprint>> {
@@ -254,6 +362,558 @@ impl ControlProtocol for ControlProtocolImpl {
         self.ctx.get(CHATS).record(neighbor, entry);
         Ok(())
     }
+
+    async fn list_unsent(&self) -> Vec<UnsentChat> {
+        self.ctx.get(CHATS).list_unsent()
+    }
+
+    async fn cancel_unsent(&self, id: u64) -> Result<(), ChatError> {
+        if self.ctx.get(CHATS).cancel_unsent(id) {
+            Ok(())
+        } else {
+            Err(ChatError::Cancel(format!("no unsent chat with id {id}")))
+        }
+    }
+
+    async fn stats(&self) -> BTreeMap<String, u64> {
+        let mut stats = self.ctx.get(STATS).snapshot();
+        // also export latency percentiles as gauges, so that they can be graphed alongside the counters
+        for (class, latency) in forwarding_latency(&self.ctx) {
+            for (kind, percentiles) in [
+                ("processing", latency.processing),
+                ("delay_queue", latency.delay_queue),
+            ] {
+                if let Some(percentiles) = percentiles {
+                    let prefix = format!("forwarding_latency_us.{class}.{kind}");
+                    stats.insert(format!("{prefix}.p50"), percentiles.p50_us);
+                    stats.insert(format!("{prefix}.p90"), percentiles.p90_us);
+                    stats.insert(format!("{prefix}.p99"), percentiles.p99_us);
+                }
+            }
+        }
+        let load = load_state(&self.ctx);
+        let level = match load.level {
+            LoadLevel::Green => 0,
+            LoadLevel::Yellow => 1,
+            LoadLevel::Red => 2,
+        };
+        stats.insert("overload.level".into(), level);
+        stats.insert("overload.backlog".into(), load.backlog as u64);
+        stats.insert(
+            "overload.shed_permille".into(),
+            (load.shed_ratio * 1000.0) as u64,
+        );
+        if is_relay(&self.ctx) {
+            let rendezvous = haven::rendezvous_occupancy(&self.ctx);
+            stats.insert(
+                "rendezvous.registered_havens".into(),
+                rendezvous.registered_havens,
+            );
+            stats.insert(
+                "rendezvous.max_registered_havens".into(),
+                rendezvous.max_registered_havens as u64,
+            );
+            stats.insert(
+                "rendezvous.forward_bytes_per_sec".into(),
+                rendezvous.forward_bytes_per_sec,
+            );
+            if let Some(cap) = rendezvous.max_forward_bandwidth {
+                stats.insert("rendezvous.max_forward_bandwidth".into(), cap);
+            }
+        }
+        let delay_queue = delay_queue_stats(&self.ctx);
+        stats.insert(
+            "delay_queue.memory_bytes".into(),
+            delay_queue.memory_bytes as u64,
+        );
+        stats.insert(
+            "delay_queue.spilled_packets".into(),
+            delay_queue.spilled_packets as u64,
+        );
+        stats.insert("delay_queue.spill_bytes".into(), delay_queue.spill_bytes);
+        let sends = send_concurrency(&self.ctx);
+        stats.insert(
+            "send_concurrency.in_use".into(),
+            sends.iter().map(|dest| dest.in_use as u64).sum(),
+        );
+        stats.insert(
+            "send_concurrency.waiting".into(),
+            sends.iter().map(|dest| dest.waiting as u64).sum(),
+        );
+        stats.insert(
+            "send_concurrency.destinations".into(),
+            tracked_destinations(&self.ctx) as u64,
+        );
+        for dest in sends {
+            let prefix = format!("send_concurrency.{}", dest.destination);
+            stats.insert(format!("{prefix}.in_use"), dest.in_use as u64);
+            stats.insert(format!("{prefix}.waiting"), dest.waiting as u64);
+        }
+        for (neighbor, health) in gossip_health(&self.ctx) {
+            stats.insert(
+                format!("gossip.{neighbor}.health_percent"),
+                (health * 100.0).round() as u64,
+            );
+        }
+        for (neighbor, pacing) in pacing_stats(&self.ctx) {
+            if let Some(rate) = pacing.rate_bytes_per_sec {
+                stats.insert(format!("pacing.{neighbor}.rate_bytes_per_sec"), rate);
+            }
+            stats.insert(
+                format!("pacing.{neighbor}.queued_bytes"),
+                pacing.queued_bytes,
+            );
+            stats.insert(
+                format!("pacing.{neighbor}.paced_writes"),
+                pacing.paced_writes,
+            );
+            stats.insert(
+                format!("pacing.{neighbor}.paced_delay_ms"),
+                pacing.paced_delay_ms,
+            );
+        }
+        stats
+    }
+
+    async fn graph_dump(&self, format: GraphDumpFormat) -> String {
+        match format {
+            GraphDumpFormat::Human => GraphDump::new(
+                &self.ctx.get(RELAY_GRAPH).read(),
+                self.ctx
+                    .get(MY_RELAY_IDENTITY)
+                    .map(|id| id.public().fingerprint()),
+                all_client_neighs(&self.ctx),
+            )
+            .to_string(),
+            // graphviz output ends without a newline
+            GraphDumpFormat::Graphviz => self.relay_graphviz().await + "\n",
+        }
+    }
+
+    async fn export_graph(
+        &self,
+        format: GraphExportFormat,
+        after: Option<RelayFingerprint>,
+    ) -> GraphExportPage {
+        export_page(
+            &self.ctx.get(RELAY_GRAPH).read(),
+            format,
+            after,
+            EXPORT_PAGE_RELAYS,
+        )
+    }
+
+    async fn relay_graph(&self) -> GraphSnapshot {
+        GraphSnapshot::new(
+            &self.ctx.get(RELAY_GRAPH).read(),
+            self.ctx
+                .get(MY_RELAY_IDENTITY)
+                .map(|id| id.public().fingerprint()),
+        )
+    }
+
+    async fn graph_stats(&self) -> GraphStats {
+        let mut stats = self.ctx.get(RELAY_GRAPH).read().graph_stats();
+        stats.own_descriptor_age_secs = identity_refresh::own_descriptor_age(&self.ctx);
+        stats
+    }
+
+    async fn forwarding_latency(&self) -> BTreeMap<String, ClassLatency> {
+        forwarding_latency(&self.ctx)
+    }
+
+    async fn send_concurrency(&self) -> Vec<SendConcurrency> {
+        send_concurrency(&self.ctx)
+    }
+
+    async fn observed_drops(&self) -> Vec<ObservedDrop> {
+        observed_drops(&self.ctx)
+    }
+
+    async fn learned_routes(&self) -> BTreeMap<String, Vec<LearnedRoute>> {
+        n2r::learned_routes(&self.ctx)
+    }
+
+    async fn generate_report(&self, args: ReportArgs) -> u64 {
+        report::start_report(&self.ctx, args)
+    }
+
+    async fn report_chunk(&self, report: u64) -> Result<Option<String>, ReportError> {
+        report::report_chunk(&self.ctx, report).await
+    }
+
+    async fn usage_history(
+        &self,
+        start: u64,
+        end: u64,
+        resolution_secs: u64,
+    ) -> Result<Vec<UsagePeriod>, ReportError> {
+        usage::usage_history(&self.ctx, start, end, resolution_secs)
+            .await
+            .map_err(|e| ReportError::Generate(format!("{e:?}")))
+    }
+
+    async fn whoami(&self) -> WhoAmI {
+        WhoAmI {
+            client_id: *self.ctx.get(MY_CLIENT_ID),
+            relay_fingerprint: self
+                .ctx
+                .get(MY_RELAY_IDENTITY)
+                .map(|id| id.public().fingerprint()),
+            load: load_state(&self.ctx),
+            entry_guards: n2r::entry_guards(&self.ctx),
+            identity: identity_refresh::identity_freshness(&self.ctx),
+            rendezvous: is_relay(&self.ctx).then(|| haven::rendezvous_occupancy(&self.ctx)),
+        }
+    }
+
+    async fn transport_limits(&self, destination: RelayEndpoint) -> TransportLimits {
+        limits::transport_limits(&self.ctx, destination, None)
+    }
+
+    async fn surb_bundles(&self) -> SurbBundles {
+        n2r::surb_bundles(&self.ctx)
+    }
+
+    async fn import_surb_bundle(&self, bundle: Bytes) -> Result<SurbBundleStock, SurbBundleError> {
+        let bundle = SurbBundle::from_bytes(&bundle).map_err(|_| SurbBundleError::Malformed)?;
+        let stock = SurbBundleStock {
+            id: bundle.id,
+            anon_dest: bundle.anon_dest,
+            anchor: bundle.anchor,
+            expires_at: bundle.expires_at,
+            total: bundle.reply_blocks.len(),
+            remaining: bundle.reply_blocks.len(),
+        };
+        n2r::import_surb_bundle(&self.ctx, bundle)?;
+        Ok(stock)
+    }
+
+    async fn skt_info(&self) -> Vec<SocketInfo> {
+        shaper::socket_info(&self.ctx)
+    }
+
+    async fn set_socket_rate_limit(
+        &self,
+        id: u64,
+        limit: Option<SendRateLimit>,
+    ) -> Result<(), ConfigError> {
+        if limit.is_some_and(|limit| limit.bytes_per_sec == 0 || limit.burst_bytes == 0) {
+            return Err(ConfigError::Error(
+                "a rate limit must allow at least some bytes".into(),
+            ));
+        }
+        if shaper::set_socket_rate_limit(&self.ctx, id, limit) {
+            Ok(())
+        } else {
+            Err(ConfigError::Error(format!("no socket with id {id}")))
+        }
+    }
+
+    async fn pending_sends(&self, socket_id: Option<u64>) -> Vec<PendingSend> {
+        pending::pending_sends(&self.ctx, socket_id)
+    }
+
+    async fn cancel_send(&self, id: u64) -> Option<SendOutcome> {
+        pending::cancel_send(&self.ctx, id)
+    }
+
+    async fn cancel_all(&self, socket_id: u64, destination: Option<RelayFingerprint>) -> Vec<u64> {
+        pending::cancel_all(&self.ctx, socket_id, destination)
+    }
+
+    async fn debt_events(&self, after: u64) -> Vec<(u64, DebtEvent)> {
+        self.ctx
+            .get(DEBTS)
+            .wait_events(after)
+            .timeout(Duration::from_secs(30))
+            .await
+            .unwrap_or_default()
+    }
+
+    async fn roaming_events(&self, after: u64) -> Vec<(u64, RoamingEvent)> {
+        n2r::roaming_events(&self.ctx, after)
+            .timeout(Duration::from_secs(30))
+            .await
+            .unwrap_or_default()
+    }
+
+    async fn preview_config(&self, yaml: String) -> Result<ConfigDiff, ConfigError> {
+        let candidate = ConfigFile::from_yaml(yaml.as_bytes())
+            .map_err(|e| ConfigError::Error(format!("invalid config: {e:#}")))?;
+        Ok(ConfigDiff::between(self.ctx.init(), &candidate))
+    }
+
+    async fn pause_out_route(&self, name: String) -> Result<(), ConfigError> {
+        set_out_route_paused(&self.ctx, &name, true).map_err(|e| ConfigError::Error(e.to_string()))
+    }
+
+    async fn resume_out_route(&self, name: String) -> Result<(), ConfigError> {
+        set_out_route_paused(&self.ctx, &name, false).map_err(|e| ConfigError::Error(e.to_string()))
+    }
+
+    async fn test_out_route(&self, cfg: OutRouteConfig) -> RouteTestResult {
+        test_out_route(&self.ctx, &cfg).await
+    }
+
+    async fn measure_throughput(
+        &self,
+        dest: HavenEndpoint,
+        duration_secs: u64,
+    ) -> Result<ThroughputReport, ThroughputError> {
+        measure_throughput(&self.ctx, dest, Duration::from_secs(duration_secs))
+            .await
+            .map_err(|e| ThroughputError::Measure(format!("{e:?}")))
+    }
+
+    async fn status(&self) -> NodeStatus {
+        let debts = self.ctx.get(DEBTS);
+        let neighbor = |id: String, kind: NeighborKind, net_debt: Option<i128>| {
+            let (packets_in, packets_out) = ledger::traffic_totals(&self.ctx, &id);
+            let remote_view = debts.remote_view(&id);
+            NeighborStatus {
+                rtt_ms: link_rtt(&self.ctx, &id).map(|rtt| rtt.as_millis() as u64),
+                id,
+                kind,
+                packets_in,
+                packets_out,
+                net_debt,
+                remote_net_debt: remote_view.map(|view| view.net_debt),
+                debt_divergence: remote_view.map(|view| view.divergence),
+            }
+        };
+        let relays = all_relay_neighs(&self.ctx).into_iter().sorted().map(|fp| {
+            neighbor(
+                fp.to_string(),
+                NeighborKind::Relay,
+                debts.relay_net_debt_est(&fp),
+            )
+        });
+        let clients = all_client_neighs(&self.ctx).into_iter().sorted().map(|id| {
+            neighbor(
+                id.to_string(),
+                NeighborKind::Client,
+                debts.client_net_debt_est(&id),
+            )
+        });
+        let havens = self
+            .ctx
+            .init()
+            .havens
+            .iter()
+            .filter_map(|haven_cfg| {
+                let fingerprint = haven_cfg
+                    .identity
+                    .actualize_haven()
+                    .ok()?
+                    .public()
+                    .fingerprint();
+                Some(HavenStatus {
+                    fingerprint: fingerprint.to_string(),
+                    listen_port: haven_cfg.listen_port,
+                    serving: serve_haven::is_serving(&self.ctx, &fingerprint),
+                })
+            })
+            .chain(serve_haven::ephemeral_havens(&self.ctx).into_iter().map(
+                |(fingerprint, listen_port, _)| HavenStatus {
+                    fingerprint: fingerprint.to_string(),
+                    listen_port,
+                    serving: true,
+                },
+            ))
+            .collect();
+        NodeStatus {
+            bootstrap: bootstrap_phase(&self.ctx),
+            neighbors: relays.chain(clients).collect(),
+            routes: route_statuses(&self.ctx),
+            queues: queue_depths(&self.ctx)
+                .into_iter()
+                .map(|(name, depth)| QueueStatus { name, depth })
+                .collect(),
+            havens,
+        }
+    }
+
+    async fn network_summary(&self) -> NetworkSummary {
+        let (packets_in, packets_out) = ledger::all_traffic_totals(&self.ctx);
+        NetworkSummary {
+            mode: if is_relay(&self.ctx) {
+                NodeMode::Relay
+            } else {
+                NodeMode::Client
+            },
+            client_id: *self.ctx.get(MY_CLIENT_ID),
+            relay_fingerprint: self
+                .ctx
+                .get(MY_RELAY_IDENTITY)
+                .map(|id| id.public().fingerprint()),
+            known_relays: self.ctx.get(RELAY_GRAPH).read().all_nodes().count(),
+            relay_neighbors: all_relay_neighs(&self.ctx).len(),
+            client_neighbors: all_client_neighs(&self.ctx).len(),
+            packets_in,
+            packets_out,
+            net_debt: self
+                .ctx
+                .get(DEBTS)
+                .net_debts()
+                .into_iter()
+                .map(|(_, debt)| debt)
+                .sum(),
+            dht_cached: dht_cache_size(&self.ctx),
+            dht_replicated: local_dht_shard_size(&self.ctx),
+        }
+    }
+
+    async fn daemon_tasks(&self) -> Vec<TaskHealth> {
+        scope::daemon_tasks(&self.ctx)
+    }
+
+    async fn rotate_identity(
+        &self,
+        grace_secs: u64,
+    ) -> Result<IdentityRotation, RotateIdentityError> {
+        identity_rotation::rotate_identity(&self.ctx, grace_secs)
+            .await
+            .map_err(|e| RotateIdentityError::Rotate(format!("{e:#}")))
+    }
+
+    async fn audit_log(&self, after: u64, limit: u32) -> Result<Vec<AuditEntry>, AuditError> {
+        audit::audit_log(&self.ctx, after, limit)
+            .await
+            .map_err(|e| AuditError::Read(format!("{e:#}")))
+    }
+
+    async fn audit_log_head(&self) -> Result<Option<AuditHead>, AuditError> {
+        audit::audit_log_head(&self.ctx)
+            .await
+            .map_err(|e| AuditError::Read(format!("{e:#}")))
+    }
+
+    async fn verify_audit_log(
+        &self,
+        exported: Option<AuditHead>,
+    ) -> Result<AuditVerification, AuditError> {
+        audit::verify_audit_log(&self.ctx, exported.as_ref())
+            .await
+            .map_err(|e| AuditError::Read(format!("{e:#}")))
+    }
+
+    async fn snapshot_state(&self, recipient: DhPublic) -> Result<Bytes, SnapshotError> {
+        snapshot::snapshot_state_for(&self.ctx, &recipient)
+    }
+
+    async fn list_settlements(&self) -> Vec<String> {
+        self.ctx.get(SETTLEMENTS).list()
+    }
+
+    async fn send_settlement(
+        &self,
+        neighbor: RelayFingerprint,
+        amount: u64,
+    ) -> Result<(), SettlementError> {
+        let my_sk = require_relay(&self.ctx, "settling debts")
+            .map_err(|e| SettlementError::Send(format!("{e:#}")))?;
+        if !all_relay_neighs(&self.ctx).contains(&neighbor) {
+            return Err(SettlementError::Send(format!(
+                "{neighbor} is not a relay neighbor"
+            )));
+        }
+        let request =
+            SettlementRequest::new(my_sk, Micromel(amount), vec![SettlementProof::Manual]);
+        self.ctx.get(SETTLEMENTS).queue_outgoing(neighbor, request);
+        Ok(())
+    }
+
+    async fn accept_settlement(&self, neighbor: RelayFingerprint) -> Result<(), SettlementError> {
+        self.ctx
+            .get(SETTLEMENTS)
+            .accept_response(&self.ctx, neighbor)
+            .await
+            .map_err(|e| SettlementError::Accept(format!("{e:#}")))
+    }
+
+    async fn reject_settlement(&self, neighbor: RelayFingerprint) -> Result<(), SettlementError> {
+        self.ctx
+            .get(SETTLEMENTS)
+            .reject_response(&neighbor)
+            .await
+            .map_err(|e| SettlementError::Reject(format!("{e:#}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preview_leaves_config_alone() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(json!({
+                "identity_seed": "reload test",
+                "debt_warning_threshold": 1000,
+            }))
+            .unwrap(),
+        );
+        let control = ControlProtocolImpl::new(ctx.clone());
+        let running = || serde_json::to_value(ctx.init()).unwrap();
+        let before = running();
+
+        smol::future::block_on(async {
+            let yaml = "identity_seed: reload test\ndebt_warning_threshold: 2000\n".to_string();
+            let preview = control.preview_config(yaml).await.unwrap();
+            assert_eq!(preview.settings.len(), 1);
+            assert_eq!(preview.settings[0].setting, "debt_warning_threshold");
+            assert!(!preview.requires_restart());
+            assert_eq!(running(), before);
+
+            // invalid configs are rejected before anything gets compared
+            assert!(control
+                .preview_config("no_such_setting: true\n".into())
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn network_summary_counts_the_graph() {
+        let relay = DaemonContext::new(
+            serde_json::from_value(json!({ "identity_seed": "summary test" })).unwrap(),
+        );
+        for _ in 0..3 {
+            let descr = earendil_topology::IdentityDescriptor::new(
+                &earendil_crypt::RelayIdentitySecret::generate(),
+                &earendil_packet::crypt::DhSecret::generate(),
+            );
+            relay
+                .get(RELAY_GRAPH)
+                .write()
+                .insert_identity(descr)
+                .unwrap();
+        }
+        let summary =
+            smol::future::block_on(ControlProtocolImpl::new(relay.clone()).network_summary());
+        assert_eq!(
+            summary.known_relays,
+            relay.get(RELAY_GRAPH).read().all_nodes().count()
+        );
+        assert!(summary.known_relays >= 3);
+        assert_eq!(summary.mode, NodeMode::Relay);
+        assert_eq!(
+            summary.relay_fingerprint,
+            Some(
+                earendil_crypt::RelayIdentitySecret::from_seed("summary test")
+                    .public()
+                    .fingerprint()
+            )
+        );
+
+        let client = DaemonContext::new(serde_json::from_value(json!({})).unwrap());
+        let summary = smol::future::block_on(ControlProtocolImpl::new(client).network_summary());
+        assert_eq!(summary.mode, NodeMode::Client);
+        assert_eq!(summary.relay_fingerprint, None);
+        assert_eq!(summary.known_relays, 0);
+        assert_eq!((summary.packets_in, summary.packets_out), (0, 0));
+    }
 }
 
 fn get_node_label(fp: &RelayFingerprint) -> String {
@@ -280,3 +940,19 @@ fn neigh_by_prefix(
         anyhow::bail!("Prefix matches multiple neighbors! Try a longer prefix.")
     }
 }
+
+fn handler_kind(handler: &HavenHandler) -> &'static str {
+    match handler {
+        HavenHandler::TcpService { .. } => "TcpService",
+        HavenHandler::SimpleProxy => "SimpleProxy",
+    }
+}
+
+/// Flags a haven's description when its beacons say visitors likely can't reach it.
+fn with_beacon_state(ctx: &DaemonContext, description: &str, endpoint: &HavenEndpoint) -> String {
+    if haven::is_degraded(ctx, &endpoint.fingerprint) {
+        format!("{description} (degraded)")
+    } else {
+        description.to_string()
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use earendil_crypt::AnonEndpoint;
+use moka::{sync::Cache, Expiry};
+use nanorpc::RpcTransport;
+use parking_lot::Mutex;
+use smol::channel::Sender;
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    control_protocol::{GlobalRpcArgs, GlobalRpcError, GlobalRpcJob},
+    global_rpc::{transport::GlobalRpcTransport, GlobalRpcProgress},
+    n2r_socket::N2rClientSocket,
+    scope::{spawn_scoped, Stage},
+};
+
+/// How long a finished job can still be polled for. Running jobs stay until they finish, however long that takes.
+const JOB_TTL: Duration = Duration::from_secs(600);
+
+/// How many progress updates may wait to be recorded before newer ones are dropped.
+const PROGRESS_BACKLOG: usize = 16;
+
+static JOBS: CtxField<Cache<u64, Arc<Mutex<GlobalRpcJob>>>> =
+    |_| Cache::builder().expire_after(FinishedJobExpiry).build();
+
+/// Expires jobs [JOB_TTL] after they finish, which is when they're inserted again.
+struct FinishedJobExpiry;
+
+impl FinishedJobExpiry {
+    fn ttl(job: &Mutex<GlobalRpcJob>) -> Option<Duration> {
+        job.lock().result.is_some().then_some(JOB_TTL)
+    }
+}
+
+impl Expiry<u64, Arc<Mutex<GlobalRpcJob>>> for FinishedJobExpiry {
+    fn expire_after_create(
+        &self,
+        _: &u64,
+        job: &Arc<Mutex<GlobalRpcJob>>,
+        _: Instant,
+    ) -> Option<Duration> {
+        Self::ttl(job)
+    }
+
+    fn expire_after_update(
+        &self,
+        _: &u64,
+        job: &Arc<Mutex<GlobalRpcJob>>,
+        _: Instant,
+        _: Option<Duration>,
+    ) -> Option<Duration> {
+        Self::ttl(job)
+    }
+}
+
+static NEXT_JOB: CtxField<AtomicU64> = |_| AtomicU64::new(1);
+
+/// Calls a GlobalRpc method, passing progress updates on it to `progress` if given.
+pub async fn call_global_rpc(
+    ctx: &DaemonContext,
+    args: GlobalRpcArgs,
+    progress: Option<Sender<GlobalRpcProgress>>,
+) -> Result<serde_json::Value, GlobalRpcError> {
+    let n2r_skt = N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())
+        .expect("failed to bind n2r socket")
+        .with_class(args.class)
+        .without_fallback();
+    let mut client = if args.sealed {
+        GlobalRpcTransport::new_sealed(ctx.clone(), args.destination, n2r_skt)
+    } else {
+        GlobalRpcTransport::new(ctx.clone(), args.destination, n2r_skt)
+    };
+    if args.nack {
+        client = client.with_nacks();
+    }
+    if let Some(progress) = progress {
+        client = client.with_progress(progress);
+    }
+    let res = if let Some(res) = client.call(&args.method, &args.args).await.map_err(|e| {
+        tracing::warn!("send_global_rpc transport failed with {:?}", e);
+        GlobalRpcError::SendError
+    })? {
+        res.map_err(|e| {
+            tracing::warn!("send_global_rpc remote failed with {:?}", e);
+            GlobalRpcError::SendError
+        })?
+    } else {
+        return Err(GlobalRpcError::SendError);
+    };
+    Ok(res)
+}
+
+/// Starts a GlobalRpc call in the background, returning the id to poll it with through [global_rpc_job].
+pub fn start_global_rpc(ctx: &DaemonContext, args: GlobalRpcArgs) -> u64 {
+    let job_id = ctx.get(NEXT_JOB).fetch_add(1, Ordering::Relaxed);
+    let job = Arc::new(Mutex::new(GlobalRpcJob::default()));
+    ctx.get(JOBS).insert(job_id, job.clone());
+
+    let (send_progress, recv_progress) = smol::channel::bounded(PROGRESS_BACKLOG);
+    spawn_scoped(ctx, Stage::Inflight, "global_rpc_job", {
+        let ctx = ctx.clone();
+        async move {
+            // progress stops once the call drops its transport, so both finish together
+            let (result, ()) =
+                smol::future::zip(call_global_rpc(&ctx, args, Some(send_progress)), async {
+                    while let Ok(progress) = recv_progress.recv().await {
+                        job.lock().record_progress(progress);
+                    }
+                })
+                .await;
+            job.lock().result = Some(result);
+            // so that it expires from now, rather than from when it started
+            ctx.get(JOBS).insert(job_id, job);
+        }
+    });
+    job_id
+}
+
+/// How a job started through [start_global_rpc] is going, or `None` if there's no such job, or it finished too long ago.
+pub fn global_rpc_job(ctx: &DaemonContext, job_id: u64) -> Option<GlobalRpcJob> {
+    ctx.get(JOBS).get(&job_id).map(|job| job.lock().clone())
+}
+
+impl GlobalRpcJob {
+    /// Records an update, unless a later one already arrived.
+    fn record_progress(&mut self, progress: GlobalRpcProgress) {
+        self.updates += 1;
+        if self
+            .progress
+            .as_ref()
+            .map_or(true, |latest| latest.seq < progress.seq)
+        {
+            self.progress = Some(progress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(seq: u64) -> GlobalRpcProgress {
+        GlobalRpcProgress {
+            seq,
+            percent: Some(seq as f32 * 25.0),
+            message: None,
+        }
+    }
+
+    #[test]
+    fn late_progress_does_not_go_backwards() {
+        let mut job = GlobalRpcJob::default();
+        job.record_progress(progress(1));
+        job.record_progress(progress(3));
+        job.record_progress(progress(2));
+        assert_eq!(job.progress, Some(progress(3)));
+        assert_eq!(job.updates, 3);
+    }
+
+    #[test]
+    fn only_finished_jobs_expire() {
+        let job = Mutex::new(GlobalRpcJob::default());
+        assert_eq!(FinishedJobExpiry::ttl(&job), None);
+        job.lock().result = Some(Err(GlobalRpcError::SendError));
+        assert_eq!(FinishedJobExpiry::ttl(&job), Some(JOB_TTL));
+    }
+}
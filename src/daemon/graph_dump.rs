@@ -0,0 +1,146 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+use earendil_crypt::{ClientId, RelayFingerprint};
+use earendil_topology::RelayGraph;
+
+/// The relay graph as seen from one node, in a stable order, so that dumps taken at different times can be diffed.
+pub struct GraphDump {
+    me: Option<RelayFingerprint>,
+    /// Every relay, with the relays it's adjacent to and when each adjacency was signed, all in fingerprint order.
+    relays: BTreeMap<RelayFingerprint, BTreeMap<RelayFingerprint, u64>>,
+    adjacency_count: usize,
+    /// Our own client neighbors, which are not part of the relay graph.
+    clients: BTreeSet<ClientId>,
+}
+
+impl GraphDump {
+    pub fn new(
+        graph: &RelayGraph,
+        me: Option<RelayFingerprint>,
+        clients: impl IntoIterator<Item = ClientId>,
+    ) -> Self {
+        let mut relays: BTreeMap<RelayFingerprint, BTreeMap<RelayFingerprint, u64>> = graph
+            .all_nodes()
+            .map(|node| (node, BTreeMap::new()))
+            .collect();
+        let mut adjacency_count = 0;
+        for adj in graph.all_adjacencies() {
+            adjacency_count += 1;
+            relays
+                .entry(adj.left)
+                .or_default()
+                .insert(adj.right, adj.unix_timestamp);
+            relays
+                .entry(adj.right)
+                .or_default()
+                .insert(adj.left, adj.unix_timestamp);
+        }
+        Self {
+            me,
+            relays,
+            adjacency_count,
+            clients: clients.into_iter().collect(),
+        }
+    }
+}
+
+impl Display for GraphDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} relays, {} adjacencies, {} clients",
+            self.relays.len(),
+            self.adjacency_count,
+            self.clients.len()
+        )?;
+        writeln!(f, "\nrelays:")?;
+        for (relay, adjacencies) in self.relays.iter() {
+            if Some(*relay) == self.me {
+                writeln!(f, "  {} (me)", short_fp(relay))?;
+            } else {
+                writeln!(f, "  {}", short_fp(relay))?;
+            }
+            for (neigh, timestamp) in adjacencies {
+                let signed: DateTime<Utc> = (UNIX_EPOCH + Duration::from_secs(*timestamp)).into();
+                writeln!(
+                    f,
+                    "    -- {} (signed {})",
+                    short_fp(neigh),
+                    signed.format("%Y-%m-%d %H:%M:%S")
+                )?;
+            }
+        }
+        writeln!(f, "\nclients:")?;
+        for client in self.clients.iter() {
+            writeln!(f, "  {client}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The first 8 characters of a fingerprint, which are plenty to tell relays apart by eye.
+fn short_fp(fp: &RelayFingerprint) -> String {
+    fp.to_string().chars().take(8).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::IdentityDescriptor;
+
+    use super::*;
+    use crate::test_util::adjacency;
+
+    #[test]
+    fn dump_is_stably_ordered() {
+        let relays = [(); 4].map(|_| RelayIdentitySecret::generate());
+        let now = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let adjacencies = [(0, 1), (1, 2), (2, 3), (3, 0), (0, 2)]
+            .map(|(a, b)| adjacency(&relays[a], &relays[b], now - a as u64));
+        let build = |order: &[usize]| {
+            let mut graph = RelayGraph::new();
+            for &i in order {
+                graph
+                    .insert_identity(IdentityDescriptor::new(&relays[i], &DhSecret::generate()))
+                    .unwrap();
+            }
+            for &i in order.iter().chain([4].iter()) {
+                graph.insert_adjacency(adjacencies[i].clone()).unwrap();
+            }
+            graph
+        };
+        let me = Some(relays[0].public().fingerprint());
+        let graph = build(&[0, 1, 2, 3]);
+        let dump = GraphDump::new(&graph, me, [3, 1, 2]).to_string();
+
+        // the same graph, and the same graph built in another order, dump the same
+        assert_eq!(dump, GraphDump::new(&graph, me, [2, 3, 1]).to_string());
+        assert_eq!(
+            dump,
+            GraphDump::new(&build(&[3, 1, 0, 2]), me, [1, 2, 3]).to_string()
+        );
+
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "4 relays, 5 adjacencies, 3 clients");
+        let relay_lines: Vec<&str> = lines
+            .iter()
+            .filter(|line| line.starts_with("  ") && !line.starts_with("    "))
+            .copied()
+            .take(4)
+            .collect();
+        let mut sorted = relay_lines.clone();
+        sorted.sort();
+        assert_eq!(relay_lines, sorted);
+        assert!(dump.contains(&format!("{} (me)", short_fp(&me.unwrap()))));
+        assert!(dump.ends_with("clients:\n  1\n  2\n  3\n"));
+    }
+}
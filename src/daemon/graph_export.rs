@@ -0,0 +1,164 @@
+use std::fmt::Write as _;
+
+use earendil_crypt::RelayFingerprint;
+use earendil_topology::RelayGraph;
+
+use crate::control_protocol::{GraphExportFormat, GraphExportPage};
+
+/// How many relays one page of a graph export covers, so that exporting a big graph never builds it all up as text.
+pub const EXPORT_PAGE_RELAYS: usize = 1000;
+
+const GRAPHML_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+  <key id="timestamp" for="edge" attr.name="unix_timestamp" attr.type="long"/>
+  <graph id="earendil" edgedefault="undirected">
+"#;
+const GRAPHML_FOOTER: &str = "  </graph>\n</graphml>\n";
+const CSV_HEADER: &str = "source,target,unix_timestamp\n";
+
+/// Exports the relays that come after `after` in fingerprint order, up to `max_relays` of them, along with the adjacencies that start at them. Every adjacency is exported once, from the smaller of its two fingerprints, so that the pages put together give the whole graph.
+///
+/// Pages are cut by fingerprint rather than by position, so a graph that changes between pages still never repeats a relay or an adjacency.
+pub fn export_page(
+    graph: &RelayGraph,
+    format: GraphExportFormat,
+    after: Option<RelayFingerprint>,
+    max_relays: usize,
+) -> GraphExportPage {
+    let mut relays: Vec<RelayFingerprint> = graph
+        .all_nodes()
+        .filter(|fp| after.map_or(true, |after| *fp > after))
+        .collect();
+    relays.sort_unstable();
+    let next = (relays.len() > max_relays).then(|| relays[max_relays - 1]);
+    relays.truncate(max_relays);
+
+    let mut text = String::new();
+    if after.is_none() {
+        text.push_str(match format {
+            GraphExportFormat::Graphml => GRAPHML_HEADER,
+            GraphExportFormat::EdgeCsv => CSV_HEADER,
+        });
+    }
+    for relay in relays.iter() {
+        if format == GraphExportFormat::Graphml {
+            let _ = writeln!(text, "    <node id=\"{relay}\"/>");
+        }
+        let Some(adjacencies) = graph.adjacencies(relay) else {
+            continue;
+        };
+        let mut adjacencies: Vec<_> = adjacencies.filter(|adj| adj.left == *relay).collect();
+        adjacencies.sort_unstable_by_key(|adj| adj.right);
+        for adj in adjacencies {
+            let _ = match format {
+                GraphExportFormat::Graphml => writeln!(
+                    text,
+                    "    <edge source=\"{}\" target=\"{}\"><data key=\"timestamp\">{}</data></edge>",
+                    adj.left, adj.right, adj.unix_timestamp
+                ),
+                GraphExportFormat::EdgeCsv => writeln!(
+                    text,
+                    "{},{},{}",
+                    adj.left, adj.right, adj.unix_timestamp
+                ),
+            };
+        }
+    }
+    if next.is_none() && format == GraphExportFormat::Graphml {
+        text.push_str(GRAPHML_FOOTER);
+    }
+    GraphExportPage { text, next }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use earendil_topology::test_util::{adjacency, known_relay};
+
+    use super::*;
+
+    /// Puts every page of an export together, the way the CLI does.
+    fn export_all(graph: &RelayGraph, format: GraphExportFormat) -> String {
+        let mut out = String::new();
+        let mut after = None;
+        loop {
+            let page = export_page(graph, format, after, 2);
+            out.push_str(&page.text);
+            match page.next {
+                Some(next) => after = Some(next),
+                None => return out,
+            }
+        }
+    }
+
+    /// The value of an XML attribute on a line, for the simple lines we write.
+    fn attr<'a>(line: &'a str, name: &str) -> &'a str {
+        let start = line.find(&format!("{name}=\"")).unwrap() + name.len() + 2;
+        &line[start..][..line[start..].find('"').unwrap()]
+    }
+
+    #[test]
+    fn export_parses_back_into_the_graph() {
+        let mut graph = RelayGraph::new();
+        let relays = [(); 5].map(|_| known_relay(&mut graph));
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut edges = BTreeSet::new();
+        for (i, (a, b)) in [(0, 1), (1, 2), (2, 3), (3, 0), (0, 2)]
+            .into_iter()
+            .enumerate()
+        {
+            let adj = adjacency(&relays[a], &relays[b], now - i as u64);
+            edges.insert((adj.left, adj.right, adj.unix_timestamp));
+            graph.insert_adjacency(adj).unwrap();
+        }
+        let nodes: BTreeSet<RelayFingerprint> = graph.all_nodes().collect();
+
+        let graphml = export_all(&graph, GraphExportFormat::Graphml);
+        assert!(graphml.starts_with("<?xml"));
+        assert!(graphml.ends_with("</graphml>\n"));
+        let parsed_nodes: BTreeSet<RelayFingerprint> = graphml
+            .lines()
+            .filter(|line| line.trim_start().starts_with("<node "))
+            .map(|line| attr(line, "id").parse().unwrap())
+            .collect();
+        let parsed_edges: BTreeSet<(RelayFingerprint, RelayFingerprint, u64)> = graphml
+            .lines()
+            .filter(|line| line.trim_start().starts_with("<edge "))
+            .map(|line| {
+                let timestamp = line
+                    .split("<data key=\"timestamp\">")
+                    .nth(1)
+                    .and_then(|rest| rest.split('<').next())
+                    .unwrap();
+                (
+                    attr(line, "source").parse().unwrap(),
+                    attr(line, "target").parse().unwrap(),
+                    timestamp.parse().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(parsed_nodes, nodes);
+        assert_eq!(parsed_edges, edges);
+        assert_eq!(graphml.matches("<edge ").count(), edges.len());
+
+        let csv = export_all(&graph, GraphExportFormat::EdgeCsv);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(CSV_HEADER.trim_end()));
+        let parsed_edges: Vec<(RelayFingerprint, RelayFingerprint, u64)> = lines
+            .map(|line| {
+                let fields: Vec<&str> = line.split(',').collect();
+                (
+                    fields[0].parse().unwrap(),
+                    fields[1].parse().unwrap(),
+                    fields[2].parse().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(parsed_edges.len(), edges.len());
+        assert_eq!(parsed_edges.into_iter().collect::<BTreeSet<_>>(), edges);
+    }
+}
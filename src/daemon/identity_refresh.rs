@@ -0,0 +1,317 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use bytes::Bytes;
+use earendil_crypt::RelayIdentitySecret;
+use earendil_topology::{
+    IdentityDescriptor, FALLBACK_HAVEN_EXTENSION, OVERLOADED_EXTENSION, ROUTE_TIMEOUT,
+    SEALED_SERVICE_EXTENSION,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{self, unix_now},
+    context::{
+        CtxField, DaemonContext, MY_RELAY_IDENTITY, MY_RELAY_ONION_SK, MY_SEALED_SERVICE_SK,
+        RELAY_GRAPH,
+    },
+    n2r_socket::fallback_identity,
+    network,
+    stats::STATS,
+};
+
+pub const IDENTITY_RESIGNED: &str = "identity.resigned";
+pub const IDENTITY_STALE: &str = "identity.stale";
+pub const IDENTITY_CLOCK_ROLLBACK: &str = "identity.clock_rollback";
+
+/// How far in the future a persisted descriptor may be dated before we blame our clock rather than ordinary skew.
+const CLOCK_SKEW_SECS: u64 = 300;
+
+/// Once the freshest descriptor of ours that the network has is this old, it's about to be dropped network-wide.
+const STALE_AFTER_SECS: u64 = ROUTE_TIMEOUT / 2;
+
+static LIFECYCLE: CtxField<Mutex<Lifecycle>> = |ctx| Mutex::new(Lifecycle::new(unix_now(ctx)));
+
+/// Where our identity descriptor is in its life: when we last signed one, and when the network last picked one up.
+struct Lifecycle {
+    started_unix: u64,
+    signed_unix: Option<u64>,
+    signed_overloaded: bool,
+    /// The timestamp of the freshest descriptor of ours that a relay neighbor fetched.
+    gossiped_unix: Option<u64>,
+    stale: bool,
+}
+
+impl Lifecycle {
+    fn new(now_unix: u64) -> Self {
+        Self {
+            started_unix: now_unix,
+            signed_unix: None,
+            signed_overloaded: false,
+            gossiped_unix: None,
+            stale: false,
+        }
+    }
+
+    /// Whether to sign a new descriptor, because the last one is `resign_secs` old or no longer says whether we're overloaded.
+    fn resign_due(&self, now_unix: u64, resign_secs: u64, overloaded: bool) -> bool {
+        match self.signed_unix {
+            None => true,
+            Some(signed) => {
+                now_unix.saturating_sub(signed) >= resign_secs
+                    || overloaded != self.signed_overloaded
+            }
+        }
+    }
+
+    fn signed(&mut self, now_unix: u64, overloaded: bool) {
+        self.signed_unix = Some(now_unix);
+        self.signed_overloaded = overloaded;
+    }
+
+    fn gossiped(&mut self, descr_unix: u64) {
+        self.gossiped_unix = Some(self.gossiped_unix.map_or(descr_unix, |g| g.max(descr_unix)));
+    }
+
+    /// Whether the network is about to drop our identity, because no neighbor has picked up a descriptor of ours for too long. Counts from startup until the first one is picked up.
+    fn is_stale(&self, now_unix: u64) -> bool {
+        let since = self.gossiped_unix.unwrap_or(self.started_unix);
+        now_unix.saturating_sub(since) > STALE_AFTER_SECS
+    }
+}
+
+/// How fresh our identity descriptor is, as far as we and the rest of the network are concerned.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdentityFreshness {
+    /// When we last signed a descriptor, in seconds since the Unix epoch.
+    pub signed_unix: Option<u64>,
+    /// The timestamp of the freshest descriptor of ours that a relay neighbor picked up.
+    pub gossiped_unix: Option<u64>,
+    /// Whether that's so old that other relays are about to drop us from their graphs.
+    pub stale: bool,
+}
+
+/// How fresh our identity descriptor is, or None if we're a client and have none.
+pub fn identity_freshness(ctx: &DaemonContext) -> Option<IdentityFreshness> {
+    ctx.get(MY_RELAY_IDENTITY).as_ref()?;
+    let lifecycle = ctx.get(LIFECYCLE).lock();
+    Some(IdentityFreshness {
+        signed_unix: lifecycle.signed_unix,
+        gossiped_unix: lifecycle.gossiped_unix,
+        stale: lifecycle.stale,
+    })
+}
+
+/// How old our own descriptor in the relay graph is, in seconds.
+pub fn own_descriptor_age(ctx: &DaemonContext) -> Option<u64> {
+    let my_fp = ctx.get(MY_RELAY_IDENTITY).as_ref()?.public().fingerprint();
+    let descr = ctx.get(RELAY_GRAPH).read().identity(&my_fp)?;
+    Some(unix_now(ctx).saturating_sub(descr.unix_timestamp))
+}
+
+/// Notes that a relay neighbor fetched our descriptor, which puts it into the network's graphs.
+pub fn gossiped(ctx: &DaemonContext, descr: &IdentityDescriptor) {
+    ctx.get(LIFECYCLE).lock().gossiped(descr.unix_timestamp);
+}
+
+/// Checks the descriptor of ours that the state cache brought back, before it's replaced. One dated in the future means our clock went backwards since, so that every descriptor we sign looks older than the one the network already has.
+pub fn check_persisted(ctx: &DaemonContext) {
+    let Some(my_fp) = ctx
+        .get(MY_RELAY_IDENTITY)
+        .as_ref()
+        .map(|id| id.public().fingerprint())
+    else {
+        return;
+    };
+    let Some(persisted) = ctx.get(RELAY_GRAPH).read().identity(&my_fp) else {
+        return;
+    };
+    if let Some(ahead) = clock_rolled_back(persisted.unix_timestamp, unix_now(ctx)) {
+        ctx.get(STATS).incr(IDENTITY_CLOCK_ROLLBACK);
+        tracing::error!(
+            ahead_secs = ahead,
+            "our persisted identity descriptor is dated {ahead}s in the future. the system clock went backwards, so check it"
+        );
+    }
+}
+
+/// How far in the future a persisted descriptor is dated, if it's further than clock skew explains.
+fn clock_rolled_back(persisted_unix: u64, now_unix: u64) -> Option<u64> {
+    let ahead = persisted_unix.saturating_sub(now_unix);
+    (ahead > CLOCK_SKEW_SECS).then_some(ahead)
+}
+
+/// Signs a fresh identity descriptor of ours, with the extensions saying what others should know about us right now.
+pub fn sign_descriptor(ctx: &DaemonContext, identity: &RelayIdentitySecret) -> IdentityDescriptor {
+    sign_descriptor_with(ctx, identity, network::is_overloaded(ctx))
+}
+
+fn sign_descriptor_with(
+    ctx: &DaemonContext,
+    identity: &RelayIdentitySecret,
+    overloaded: bool,
+) -> IdentityDescriptor {
+    let mut extensions = BTreeMap::new();
+    extensions.insert(
+        SEALED_SERVICE_EXTENSION.to_string(),
+        Bytes::copy_from_slice(ctx.get(MY_SEALED_SERVICE_SK).public().as_bytes()),
+    );
+    if overloaded {
+        extensions.insert(OVERLOADED_EXTENSION.to_string(), Bytes::from_static(&[1]));
+    }
+    if !ctx.init().fallback_rendezvous.is_empty() {
+        let haven = fallback_identity(identity).public().fingerprint();
+        extensions.insert(
+            FALLBACK_HAVEN_EXTENSION.to_string(),
+            Bytes::copy_from_slice(haven.as_bytes()),
+        );
+    }
+    IdentityDescriptor::new_with_extensions(identity, ctx.get(MY_RELAY_ONION_SK), extensions)
+}
+
+/// Keeps our identity descriptor fresh in the relay graph, where neighbors pick it up as they gossip, and warns when they stop picking it up.
+pub async fn identity_refresh_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    loop {
+        let clock = clock::clock(&ctx);
+        refresh_once(&ctx, clock.unix_now())?;
+        clock.sleep(Duration::from_secs(1)).await;
+    }
+}
+
+fn refresh_once(ctx: &DaemonContext, now_unix: u64) -> anyhow::Result<()> {
+    let overloaded = network::is_overloaded(ctx);
+    let mut lifecycle = ctx.get(LIFECYCLE).lock();
+    if lifecycle.resign_due(now_unix, ctx.init().identity_resign_secs, overloaded) {
+        let us = sign_descriptor_with(
+            ctx,
+            &ctx.get(MY_RELAY_IDENTITY)
+                .expect("only relays have global identities"),
+            overloaded,
+        );
+        ctx.get(RELAY_GRAPH).write().insert_identity(us)?;
+        lifecycle.signed(now_unix, overloaded);
+        ctx.get(STATS).incr(IDENTITY_RESIGNED);
+        tracing::debug!(overloaded, "signed a fresh identity descriptor");
+    }
+
+    let stale = lifecycle.is_stale(now_unix);
+    if stale && !lifecycle.stale {
+        ctx.get(STATS).incr(IDENTITY_STALE);
+        tracing::warn!(
+            gossiped_unix = lifecycle.gossiped_unix,
+            relay_neighbors = network::all_relay_neighs(ctx).len(),
+            "IDENTITY GOING STALE: no relay neighbor picked up our identity descriptor in {STALE_AFTER_SECS}s, so other relays will soon drop us from their graphs"
+        );
+    } else if !stale && lifecycle.stale {
+        tracing::info!("relay neighbors are picking up our identity descriptor again");
+    }
+    lifecycle.stale = stale;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+
+    use super::*;
+
+    #[test]
+    fn resigns_and_warns_when_gossip_stops() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "identity refresh",
+                "identity_resign_secs": 600,
+            }))
+            .unwrap(),
+        );
+        let my_fp = RelayIdentitySecret::from_seed("identity refresh")
+            .public()
+            .fingerprint();
+        let start = ctx.get(LIFECYCLE).lock().started_unix;
+        let resigned = || {
+            ctx.get(STATS)
+                .snapshot()
+                .get(IDENTITY_RESIGNED)
+                .copied()
+                .unwrap_or(0)
+        };
+
+        // a day, a minute at a time, with neighbors picking up every descriptor for the first hour
+        for minute in 0..24 * 60 {
+            let now = start + minute * 60;
+            refresh_once(&ctx, now).unwrap();
+            if minute < 60 {
+                ctx.get(LIFECYCLE).lock().gossiped(now);
+            }
+            let freshness = identity_freshness(&ctx).unwrap();
+            assert!(freshness.signed_unix.unwrap() + 600 > now);
+            // the last pickup was at minute 59
+            assert_eq!(
+                freshness.stale,
+                now > start + 59 * 60 + STALE_AFTER_SECS,
+                "minute {minute}"
+            );
+        }
+        assert!(ctx.get(RELAY_GRAPH).read().identity(&my_fp).is_some());
+        assert_eq!(resigned(), 24 * 6);
+        assert_eq!(ctx.get(STATS).snapshot().get(IDENTITY_STALE), Some(&1));
+
+        // once gossip resumes, the warning goes away
+        let now = start + 24 * 3600;
+        ctx.get(LIFECYCLE).lock().gossiped(now);
+        refresh_once(&ctx, now).unwrap();
+        assert!(!identity_freshness(&ctx).unwrap().stale);
+    }
+
+    #[test]
+    fn descriptors_publish_a_sealing_key_apart_from_the_onion_key() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "identity_seed": "sealing key" })).unwrap(),
+        );
+        let identity = RelayIdentitySecret::from_seed("sealing key");
+        let descr = sign_descriptor(&ctx, &identity);
+        descr.verify().unwrap();
+        let service_pk = descr.sealed_service_pk().unwrap();
+        assert_eq!(service_pk, ctx.get(MY_SEALED_SERVICE_SK).public());
+        assert_ne!(service_pk, descr.onion_pk);
+
+        // the same identity always gets the same key
+        let restarted = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "identity_seed": "sealing key" })).unwrap(),
+        );
+        assert_eq!(restarted.get(MY_SEALED_SERVICE_SK).public(), service_pk);
+    }
+
+    #[test]
+    fn descriptors_name_the_fallback_haven_only_if_we_serve_one() {
+        let identity = RelayIdentitySecret::from_seed("fallback haven");
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "identity_seed": "fallback haven" }))
+                .unwrap(),
+        );
+        assert_eq!(sign_descriptor(&ctx, &identity).fallback_haven(), None);
+
+        let rendezvous = RelayIdentitySecret::generate().public().fingerprint();
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "fallback haven",
+                "fallback_rendezvous": [rendezvous],
+            }))
+            .unwrap(),
+        );
+        let descr = sign_descriptor(&ctx, &identity);
+        descr.verify().unwrap();
+        assert_eq!(
+            descr.fallback_haven(),
+            Some(fallback_identity(&identity).public().fingerprint())
+        );
+    }
+
+    #[test]
+    fn future_descriptor_means_clock_rollback() {
+        let now = crate::ledger::unix_now();
+        assert_eq!(clock_rolled_back(now - 3600, now), None);
+        assert_eq!(clock_rolled_back(now + 60, now), None);
+        assert_eq!(clock_rolled_back(now + 3600, now), Some(3600));
+    }
+}
@@ -0,0 +1,234 @@
+use std::path::Path;
+
+use anyhow::Context;
+use bytes::Bytes;
+use earendil_crypt::{RelayFingerprint, RelayIdentityPublic, RelayIdentitySecret};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use stdcode::StdcodeSerializeExt;
+
+use crate::{
+    audit::{audit, AuditEvent},
+    clock::unix_now,
+    config::{write_secret_file, Identity},
+    context::{require_relay, DaemonContext, MY_RELAY_IDENTITY},
+    db::{db_read, db_write, MiscKey},
+};
+
+/// The longest grace period a rotation can have. Neighbors hold rotations claiming more to this, counted from when they were made, so that an old identity that leaks can't vouch for new ones indefinitely.
+pub const MAX_GRACE_SECS: u64 = 30 * 24 * 3600;
+
+/// How far in the future a rotation may claim to have been made, to allow for clocks that disagree.
+const MAX_CLOCK_SKEW_SECS: u64 = 3600;
+
+/// A relay's announcement that it moved from one identity to another, signed by both. Neighbors that pinned the old fingerprint accept the new one on the strength of it, until the grace period is over.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdentityRotation {
+    pub old_pk: RelayIdentityPublic,
+    pub new_pk: RelayIdentityPublic,
+    pub unix_timestamp: u64,
+    /// Until when, in seconds since the Unix epoch, the old identity vouches for the new one. Never trusted past [MAX_GRACE_SECS] after `unix_timestamp`.
+    pub grace_until: u64,
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub old_sig: Bytes,
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub new_sig: Bytes,
+}
+
+impl IdentityRotation {
+    /// Creates a rotation from `old` to `new`, cross-signed by both.
+    pub fn new(
+        old: &RelayIdentitySecret,
+        new: &RelayIdentitySecret,
+        now_unix: u64,
+        grace_secs: u64,
+    ) -> Self {
+        let mut this = Self {
+            old_pk: old.public(),
+            new_pk: new.public(),
+            unix_timestamp: now_unix,
+            grace_until: now_unix.saturating_add(grace_secs),
+            old_sig: Bytes::new(),
+            new_sig: Bytes::new(),
+        };
+        let to_sign = this.to_sign();
+        this.old_sig = old.sign(to_sign.as_bytes());
+        this.new_sig = new.sign(to_sign.as_bytes());
+        this
+    }
+
+    /// The value that both signatures are supposed to be computed against.
+    pub fn to_sign(&self) -> blake3::Hash {
+        let mut this = self.clone();
+        this.old_sig = Bytes::new();
+        this.new_sig = Bytes::new();
+        blake3::keyed_hash(b"identity_rotation_______________", &this.stdcode())
+    }
+
+    /// Checks that both identities signed this.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let to_sign = self.to_sign();
+        self.old_pk
+            .verify(to_sign.as_bytes(), &self.old_sig)
+            .context("old identity did not sign the rotation")?;
+        self.new_pk
+            .verify(to_sign.as_bytes(), &self.new_sig)
+            .context("new identity did not sign the rotation")?;
+        Ok(())
+    }
+
+    /// Until when the old identity vouches for the new one, as far as anyone should trust it: its own grace period, held to [MAX_GRACE_SECS] from when it was made.
+    pub fn trusted_until(&self) -> u64 {
+        self.grace_until
+            .min(self.unix_timestamp.saturating_add(MAX_GRACE_SECS))
+    }
+
+    /// Whether this lets a relay we know as `old_fp` now go by `new_fp`.
+    pub fn vouches_for(
+        &self,
+        old_fp: RelayFingerprint,
+        new_fp: RelayFingerprint,
+        now_unix: u64,
+    ) -> bool {
+        self.old_pk.fingerprint() == old_fp
+            && self.new_pk.fingerprint() == new_fp
+            && self.unix_timestamp <= now_unix.saturating_add(MAX_CLOCK_SKEW_SECS)
+            && now_unix <= self.trusted_until()
+            && self.verify().is_ok()
+    }
+}
+
+/// Replaces our relay identity with a freshly generated one, cross-signed with the current one so that neighbors that pinned us can trust the change. The identity file is replaced atomically, and the daemon goes by the new identity, gossiping a descriptor for it, once it restarts. Until `grace_secs` from now, we show the rotation to neighbors that still expect the old identity.
+pub async fn rotate_identity(
+    ctx: &DaemonContext,
+    grace_secs: u64,
+) -> anyhow::Result<IdentityRotation> {
+    let old = require_relay(ctx, "rotating the identity")?;
+    anyhow::ensure!(
+        grace_secs <= MAX_GRACE_SECS,
+        "neighbors accept a rotation for at most {MAX_GRACE_SECS} seconds"
+    );
+    let Some(Identity::IdentityFile(path)) = &ctx.init().identity else {
+        anyhow::bail!("only an identity kept in an identity file can be rotated");
+    };
+    let new = RelayIdentitySecret::generate();
+    let rotation = IdentityRotation::new(&old, &new, unix_now(ctx), grace_secs);
+    // record the rotation first, so that we can always vouch for an identity that made it into the file
+    db_write(ctx, MiscKey::IdentityRotation, rotation.stdcode()).await?;
+    replace_secret_file(path, new.as_bytes())?;
+    tracing::warn!(
+        old = display(old.public().fingerprint()),
+        new = display(new.public().fingerprint()),
+        grace_until = rotation.grace_until,
+        "rotated our relay identity. it takes effect when the daemon restarts"
+    );
+    audit(
+        ctx,
+        AuditEvent::IdentityRotated {
+            old: old.public().fingerprint(),
+            new: new.public().fingerprint(),
+        },
+    )
+    .await;
+    Ok(rotation)
+}
+
+/// Writes a secret to a file next to `path`, then renames it over `path`, so that a crash leaves either the old secret or the new one, never half of either.
+fn replace_secret_file(path: &Path, secret: &[u8]) -> anyhow::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".rotating");
+    let temp = Path::new(&temp);
+    // left over from a rotation that crashed
+    let _ = std::fs::remove_file(temp);
+    write_secret_file(temp, secret).context("could not write the new identity")?;
+    std::fs::File::open(temp)?.sync_all()?;
+    std::fs::rename(temp, path).context("could not replace the identity file")?;
+    Ok(())
+}
+
+/// The rotation to show neighbors that still expect our old identity: the last one we made, if we already go by its new identity and its grace period isn't over.
+pub async fn current_rotation(ctx: &DaemonContext) -> anyhow::Result<Option<IdentityRotation>> {
+    let Some(me) = ctx.get(MY_RELAY_IDENTITY) else {
+        return Ok(None);
+    };
+    let Some(bts) = db_read(ctx, MiscKey::IdentityRotation).await? else {
+        return Ok(None);
+    };
+    let rotation: IdentityRotation = stdcode::deserialize(&bts)?;
+    let current = rotation.new_pk == me.public() && unix_now(ctx) <= rotation.trusted_until();
+    Ok(current.then_some(rotation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_moves_to_a_new_vouched_for_identity() {
+        let dir = std::env::temp_dir().join(format!("earendil-rotation-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let identity_file = dir.join("identity.secret");
+        let config = serde_json::json!({
+            "identity_file": identity_file,
+            "state_cache": dir.join("state.db"),
+        });
+        let ctx = DaemonContext::new(serde_json::from_value(config.clone()).unwrap());
+        let old_fp = ctx.get(MY_RELAY_IDENTITY).unwrap().public().fingerprint();
+
+        let rotation = smol::future::block_on(rotate_identity(&ctx, 3600)).unwrap();
+        // the running daemon keeps its identity, and doesn't show the rotation to anyone yet
+        assert_eq!(
+            ctx.get(MY_RELAY_IDENTITY).unwrap().public().fingerprint(),
+            old_fp
+        );
+        assert!(smol::future::block_on(current_rotation(&ctx))
+            .unwrap()
+            .is_none());
+        drop(ctx);
+
+        // once restarted, it goes by the new identity, and peers that pinned the old one accept it
+        let ctx = DaemonContext::new(serde_json::from_value(config).unwrap());
+        let new_fp = ctx.get(MY_RELAY_IDENTITY).unwrap().public().fingerprint();
+        assert_ne!(new_fp, old_fp);
+        let shown = smol::future::block_on(current_rotation(&ctx))
+            .unwrap()
+            .unwrap();
+        let now = unix_now(&ctx);
+        assert!(shown.vouches_for(old_fp, new_fp, now));
+        assert_eq!(shown.new_pk, rotation.new_pk);
+
+        // but not once the grace period is over, for other fingerprints, or if tampered with
+        assert!(!shown.vouches_for(old_fp, new_fp, now + 3601));
+        assert!(!shown.vouches_for(new_fp, old_fp, now));
+        let mut tampered = shown.clone();
+        tampered.grace_until += 3600;
+        assert!(!tampered.vouches_for(old_fp, new_fp, now));
+        let mut forged = IdentityRotation::new(
+            &RelayIdentitySecret::generate(),
+            &RelayIdentitySecret::generate(),
+            now,
+            3600,
+        );
+        forged.old_pk = shown.old_pk;
+        assert!(!forged.vouches_for(old_fp, forged.new_pk.fingerprint(), now));
+
+        // grace periods past the cap are held to it, and rotations made in the future aren't trusted yet
+        let (old, new) = (
+            RelayIdentitySecret::generate(),
+            RelayIdentitySecret::generate(),
+        );
+        let (old_fp, new_fp) = (old.public().fingerprint(), new.public().fingerprint());
+        let long = IdentityRotation::new(&old, &new, now, u64::MAX);
+        assert!(long.vouches_for(old_fp, new_fp, now + MAX_GRACE_SECS));
+        assert!(!long.vouches_for(old_fp, new_fp, now + MAX_GRACE_SECS + 1));
+        let early = IdentityRotation::new(&old, &new, now + 10 * MAX_CLOCK_SKEW_SECS, 3600);
+        assert!(!early.vouches_for(old_fp, new_fp, now));
+        assert!(smol::future::block_on(rotate_identity(&ctx, MAX_GRACE_SECS + 1)).is_err());
+
+        // no temp file is left behind
+        assert!(!dir.join("identity.secret.rotating").exists());
+        drop(ctx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,29 +1,53 @@
 use std::{
+    collections::{BTreeMap, HashSet},
     net::{SocketAddr, ToSocketAddrs},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use self::{gossip::gossip_once, link_protocol::LinkService};
+use self::{
+    capped_write::CappedWrite,
+    gossip::{gossip_loop, probe_toward},
+    link_protocol::LinkService,
+    liveness::LINK_DECLARED_DEAD,
+    quic::QuicListener,
+};
 
 use super::link::LinkMessage;
 use crate::{
-    config::InRouteConfig,
-    context::{DaemonContext, MY_RELAY_IDENTITY, MY_RELAY_ONION_SK, RELAY_GRAPH},
-    daemon::{chat::CHATS, inout_route::link_protocol::LinkClient, link::Link},
-    n2r, network,
+    audit::{audit, audit_debt_limit_drop, AuditEvent},
+    clock,
+    config::{InRouteConfig, PacingConfig, RouteDirection},
+    context::{is_client, CtxField, DaemonContext, DEBTS, MY_RELAY_IDENTITY, RELAY_GRAPH},
+    control_protocol::{RouteState, RouteStatus, RouteTestOutcome, RouteTestResult},
+    daemon::{
+        chat::CHATS,
+        identity_refresh,
+        inout_route::link_protocol::{LinkClient, LinkError},
+        link::{Link, PacingStats},
+    },
+    debts::{Admission, LinkDebtPolicy},
+    ledger, n2r,
+    network::{self, DropReason, NackOrigin, NackReason, NeighborId, SentPackets},
     pascal::{read_pascal, write_pascal},
+    settlement::{SETTLEMENTS, SETTLEMENT_WAIT},
+    stats::STATS,
 };
 use crate::{
-    config::{ObfsConfig, OutRouteConfig},
+    config::{ObfsConfig, ObfsParams, OutRouteConfig},
     context::MY_CLIENT_ID,
 };
 use anyhow::Context;
+use async_event::Event;
 use bytes::Bytes;
-use earendil_crypt::ClientId;
+use dashmap::DashMap;
+use earendil_crypt::{ClientId, RelayFingerprint};
 use earendil_packet::{RawBody, RawPacket};
-use earendil_topology::IdentityDescriptor;
+use earendil_topology::{legacy::IdentityDescriptorV0, IdentityDescriptor};
+use either::Either;
 use futures::AsyncReadExt as _;
 use nursery_macro::nursery;
+use parking_lot::Mutex;
 use picomux::PicoMux;
 use sillad::{
     dialer::Dialer,
@@ -33,11 +57,18 @@ use sillad::{
 };
 use sillad_sosistab3::{dialer::SosistabDialer, Cookie};
 use smol::future::FutureExt;
+use smol_timeout::TimeoutExt;
 use stdcode::StdcodeSerializeExt as _;
 
+mod capped_write;
 mod gossip;
 mod link_protocol;
 mod link_protocol_impl;
+mod liveness;
+pub(super) mod quic;
+mod tofu;
+
+pub use gossip::gossip_health;
 
 /*
 Links aren't inherently client-relay or relay-relay.
@@ -51,28 +82,100 @@ This means that the link-maintaining code *always* calls subscribe_outgoing_clie
 Relay and client messages are then put on the same link.
 */
 
-#[tracing::instrument(skip_all, fields(listen=debug(cfg.listen)))]
-pub async fn listen_in_route(ctx: &DaemonContext, cfg: &InRouteConfig) -> anyhow::Result<()> {
-    async fn manage_pipe(ctx: &DaemonContext, pipe: impl Pipe) -> anyhow::Result<()> {
-        let (mux, their_client_id, their_relay_descr) = pipe_to_mux(ctx, pipe).await?;
-        let link = Link::new_listen(mux).await?;
-        manage_mux(ctx, link, their_client_id, their_relay_descr).await
+#[tracing::instrument(skip_all, fields(listen=display(&cfg.listen)))]
+pub async fn listen_in_route(
+    ctx: &DaemonContext,
+    name: &str,
+    cfg: &InRouteConfig,
+) -> anyhow::Result<()> {
+    async fn manage_pipe(
+        ctx: &DaemonContext,
+        name: &str,
+        cfg: &InRouteConfig,
+        pipe: impl Pipe,
+    ) -> anyhow::Result<()> {
+        let result = async {
+            let (mux, their_client_id, their_relay_descr) =
+                pipe_to_mux(ctx, pipe, &cfg.obfs.params()).await?;
+            let link = Link::new_listen(mux)
+                .await?
+                .with_pacing(pacing_config(ctx, cfg.pacing));
+            update_route(ctx, RouteDirection::In, name, |route| route.links += 1);
+            scopeguard::defer!(update_route(ctx, RouteDirection::In, name, |route| {
+                route.links -= 1
+            }));
+            manage_mux(
+                ctx,
+                link,
+                their_client_id,
+                their_relay_descr,
+                LinkDebtPolicy {
+                    strict_prepay: cfg.strict_prepay,
+                    graduated_admission: cfg.graduated_admission,
+                },
+            )
+            .await
+        }
+        .await;
+        if let Err(err) = result.as_ref() {
+            update_route(ctx, RouteDirection::In, name, |route| {
+                route.last_error = Some(format!("{err:#}"))
+            });
+        }
+        result
     }
 
-    let mut listener = TcpListener::bind(cfg.listen).await?;
+    update_route(ctx, RouteDirection::In, name, |_| {});
+    if let Some(quic) = &cfg.quic {
+        let bound = cfg.listen.resolve().and_then(|listen| {
+            QuicListener::bind(ctx.get(MY_RELAY_IDENTITY).as_ref(), listen, quic)
+        });
+        let listener = route_bound(ctx, name, bound)?;
+        tracing::info!(
+            fingerprint = hex::encode(listener.fingerprint()),
+            "QUIC in-route up. out-routes to it set this as their server_fingerprint"
+        );
+        nursery!({
+            loop {
+                let incoming = listener.accept().await?;
+                tracing::debug!(
+                    remote_addr = display(incoming.remote_address()),
+                    "accepted a QUIC connection"
+                );
+                spawn!(async move {
+                    match quic::accept_pipe(incoming).await {
+                        Ok(quic_pipe) => manage_pipe(ctx, name, cfg, quic_pipe).await,
+                        Err(err) => {
+                            tracing::debug!(err = debug(&err), "QUIC handshake failed");
+                            Err(err)
+                        }
+                    }
+                })
+                .detach();
+            }
+            anyhow::Ok(())
+        });
+        return Ok(());
+    }
+    let bound = async {
+        TcpListener::bind(cfg.listen.resolve()?)
+            .await
+            .map_err(anyhow::Error::from)
+    };
+    let mut listener = route_bound(ctx, name, bound.await)?;
     nursery!(match &cfg.obfs {
-        ObfsConfig::None => {
+        ObfsConfig::None { .. } => {
             loop {
                 let tcp_pipe = listener.accept().await?;
                 tracing::debug!(
                     remote_addr = debug(tcp_pipe.remote_addr()),
                     "accepted a TCP connection"
                 );
-                spawn!(manage_pipe(ctx, tcp_pipe)).detach();
+                spawn!(manage_pipe(ctx, name, cfg, tcp_pipe)).detach();
             }
             anyhow::Ok(())
         }
-        ObfsConfig::Sosistab3(cookie) => {
+        ObfsConfig::Sosistab3 { cookie, .. } => {
             let mut sosistab_listener =
                 sillad_sosistab3::listener::SosistabListener::new(listener, Cookie::new(cookie));
             loop {
@@ -81,7 +184,7 @@ pub async fn listen_in_route(ctx: &DaemonContext, cfg: &InRouteConfig) -> anyhow
                     remote_addr = debug(sosistab_pipe.remote_addr()),
                     "accepted a SOSISTAB connection"
                 );
-                spawn!(manage_pipe(ctx, sosistab_pipe)).detach();
+                spawn!(manage_pipe(ctx, name, cfg, sosistab_pipe)).detach();
             }
             anyhow::Ok(())
         }
@@ -90,51 +193,108 @@ pub async fn listen_in_route(ctx: &DaemonContext, cfg: &InRouteConfig) -> anyhow
 }
 
 #[tracing::instrument(skip_all, fields(connect=debug(&cfg.connect)))]
-pub async fn dial_out_route(ctx: &DaemonContext, cfg: &OutRouteConfig) -> anyhow::Result<()> {
-    async fn manage_out_pipe(ctx: &DaemonContext, pipe: impl Pipe) -> anyhow::Result<()> {
-        let (mux, their_client_id, their_relay_descr) = pipe_to_mux(ctx, pipe).await?;
-        let link = Link::new_dial(mux).await?;
+pub async fn dial_out_route(
+    ctx: &DaemonContext,
+    name: &str,
+    cfg: &OutRouteConfig,
+) -> anyhow::Result<()> {
+    async fn manage_out_pipe(
+        ctx: &DaemonContext,
+        name: &str,
+        cfg: &OutRouteConfig,
+        pipe: impl Pipe,
+    ) -> anyhow::Result<()> {
+        let (mux, their_client_id, their_relay_descr) =
+            pipe_to_mux(ctx, pipe, &cfg.obfs.params()).await?;
+        let link = Link::new_dial(mux)
+            .await?
+            .with_pacing(pacing_config(ctx, cfg.pacing));
+        verify_out_route(ctx, cfg, their_relay_descr.as_ref(), &link).await?;
         tracing::debug!("link connected to other side");
-        manage_mux(ctx, link, their_client_id, their_relay_descr).await?;
+        update_route(ctx, RouteDirection::Out, name, |route| {
+            route.state = RouteState::Up;
+            route.links = 1;
+        });
+        manage_mux(
+            ctx,
+            link,
+            their_client_id,
+            their_relay_descr,
+            LinkDebtPolicy {
+                strict_prepay: cfg.strict_prepay,
+                graduated_admission: cfg.graduated_admission,
+            },
+        )
+        .await?;
         anyhow::Ok(())
     }
 
+    update_route(ctx, RouteDirection::Out, name, |_| {});
+    let paused = ctx.get(PAUSED_OUT_ROUTES);
     loop {
+        if paused.contains(name) {
+            update_route(ctx, RouteDirection::Out, name, |route| {
+                route.state = RouteState::Paused;
+                route.links = 0;
+            });
+            tracing::info!(name, "out route paused");
+            paused
+                .changed
+                .wait_until(|| (!paused.contains(name)).then_some(()))
+                .await;
+            tracing::info!(name, "out route resumed");
+            update_route(ctx, RouteDirection::Out, name, |route| {
+                route.state = RouteState::Connecting
+            });
+        }
         let fallible = async {
-            let dest_addr = if let Ok(socket_addr) = cfg.connect.parse() {
-                socket_addr
-            } else {
-                let addrs: Vec<SocketAddr> = cfg
-                    .connect
-                    .clone()
-                    .to_socket_addrs()
-                    .map(|iter| iter.collect())
-                    .map_err(|e| {
-                        anyhow::anyhow!("unable to resolve domain {}: {}", &cfg.connect, e)
-                    })?;
-                let addr = addrs.get(0).context("empty list of resolved domains")?;
-
-                *addr
-            };
+            let dest_addr = resolve_connect(&cfg.connect)?;
+            if let Some(quic) = &cfg.quic {
+                let quic_pipe = quic::dial(ctx, Some(name), dest_addr, quic).await?;
+                tracing::debug!("QUIC connected to other side");
+                return manage_out_pipe(ctx, name, cfg, quic_pipe).await;
+            }
             let tcp_dialer = TcpDialer { dest_addr };
             match &cfg.obfs {
-                ObfsConfig::None => {
+                ObfsConfig::None { .. } => {
                     let tcp_pipe = tcp_dialer.dial().await?;
                     tracing::debug!("TCP connected to other side");
-                    manage_out_pipe(ctx, tcp_pipe).await
+                    manage_out_pipe(ctx, name, cfg, tcp_pipe).await
                 }
-                ObfsConfig::Sosistab3(cookie) => {
+                ObfsConfig::Sosistab3 { cookie, .. } => {
                     let sosistab_dialer = SosistabDialer {
                         inner: tcp_dialer,
                         cookie: Cookie::new(cookie),
                     };
                     let sosistab_pipe = sosistab_dialer.dial().await?;
                     tracing::debug!("SOSISTAB connected to other side");
-                    manage_out_pipe(ctx, sosistab_pipe).await
+                    manage_out_pipe(ctx, name, cfg, sosistab_pipe).await
                 }
             }
         };
-        if let Err(err) = fallible.await {
+        // pausing drops the link along with everything else in flight
+        let until_paused = async {
+            paused
+                .changed
+                .wait_until(|| paused.contains(name).then_some(()))
+                .await;
+            anyhow::Ok(())
+        };
+        let result = fallible.or(until_paused).await;
+        if paused.contains(name) {
+            continue;
+        }
+        update_route(ctx, RouteDirection::Out, name, |route| {
+            route.links = 0;
+            match result.as_ref() {
+                Ok(()) => route.state = RouteState::Connecting,
+                Err(err) => {
+                    route.state = RouteState::Failed;
+                    route.last_error = Some(format!("{err:#}"));
+                }
+            }
+        });
+        if let Err(err) = result {
             tracing::warn!(
                 err = debug(err),
                 connect = debug(&cfg.connect),
@@ -145,18 +305,315 @@ pub async fn dial_out_route(ctx: &DaemonContext, cfg: &OutRouteConfig) -> anyhow
     }
 }
 
+/// Dials an out route once, and reports who answers and how fast, without adding it to the running routes. TOFU pins are checked, but nothing gets pinned.
+pub async fn test_out_route(ctx: &DaemonContext, cfg: &OutRouteConfig) -> RouteTestResult {
+    async fn probe_out_pipe(
+        ctx: &DaemonContext,
+        cfg: &OutRouteConfig,
+        pipe: impl Pipe,
+    ) -> anyhow::Result<RouteTestResult> {
+        let (mux, _, their_relay_descr) = pipe_to_mux(ctx, pipe, &cfg.obfs.params()).await?;
+        let Some(descr) = their_relay_descr else {
+            return Ok(RouteTestResult::failed(RouteTestOutcome::NotARelay));
+        };
+        descr.verify()?;
+        let seen = descr.identity_pk.fingerprint();
+        let expected = match cfg.fingerprint {
+            Some(fingerprint) => Some(fingerprint),
+            None if cfg.tofu => tofu::pinned(ctx, &cfg.connect).await?,
+            None => None,
+        };
+
+        let link = Link::new_dial(mux).await?;
+        let start = Instant::now();
+        let info = LinkClient(link.rpc_transport()).info().await?;
+        let rtt = start.elapsed();
+        Ok(RouteTestResult {
+            fingerprint: Some(seen),
+            version: Some(info.version),
+            rtt_ms: Some(rtt.as_millis() as u64),
+            outcome: match expected {
+                Some(expected) if expected != seen => {
+                    RouteTestOutcome::FingerprintMismatch { expected }
+                }
+                _ => RouteTestOutcome::Success,
+            },
+        })
+    }
+
+    let fallible = async {
+        let dest_addr = resolve_connect(&cfg.connect)?;
+        if let Some(quic) = &cfg.quic {
+            return probe_out_pipe(ctx, cfg, quic::dial(ctx, None, dest_addr, quic).await?).await;
+        }
+        let tcp_dialer = TcpDialer { dest_addr };
+        match &cfg.obfs {
+            ObfsConfig::None { .. } => probe_out_pipe(ctx, cfg, tcp_dialer.dial().await?).await,
+            ObfsConfig::Sosistab3 { cookie, .. } => {
+                let sosistab_dialer = SosistabDialer {
+                    inner: tcp_dialer,
+                    cookie: Cookie::new(cookie),
+                };
+                probe_out_pipe(ctx, cfg, sosistab_dialer.dial().await?).await
+            }
+        }
+    };
+    match fallible.timeout(ROUTE_TEST_TIMEOUT).await {
+        Some(Ok(result)) => result,
+        Some(Err(err)) => RouteTestResult::failed(RouteTestOutcome::Failed(format!("{err:#}"))),
+        None => RouteTestResult::failed(RouteTestOutcome::Failed("timed out".into())),
+    }
+}
+
+/// How long testing an out route may take before we give up on it.
+const ROUTE_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn resolve_connect(connect: &str) -> anyhow::Result<SocketAddr> {
+    if let Ok(socket_addr) = connect.parse() {
+        return Ok(socket_addr);
+    }
+    let addrs: Vec<SocketAddr> = connect
+        .to_socket_addrs()
+        .map(|iter| iter.collect())
+        .map_err(|e| anyhow::anyhow!("unable to resolve domain {}: {}", connect, e))?;
+    addrs
+        .first()
+        .copied()
+        .context("empty list of resolved domains")
+}
+
+/// Marks an in-route up once it's bound, or failed if it couldn't be.
+fn route_bound<T>(ctx: &DaemonContext, name: &str, bound: anyhow::Result<T>) -> anyhow::Result<T> {
+    update_route(ctx, RouteDirection::In, name, |route| match &bound {
+        Ok(_) => route.state = RouteState::Up,
+        Err(err) => {
+            route.state = RouteState::Failed;
+            route.last_error = Some(format!("{err:#}"));
+        }
+    });
+    bound
+}
+
+/// The pacing config for a route's links, if the route has pacing on at all.
+fn pacing_config(ctx: &DaemonContext, route_pacing: bool) -> Option<PacingConfig> {
+    ctx.init().link_pacing.filter(|_| route_pacing)
+}
+
+/// How often the pacing stats of each neighbor's link are refreshed.
+const PACING_STATS_REFRESH: Duration = Duration::from_secs(1);
+
+/// How long to wait for the answer to a pacing probe. One that takes longer leaves the pacer waiting for the next.
+const PACING_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How each neighbor's link is being paced, refreshed every second.
+static PACING_STATS: CtxField<DashMap<String, PacingStats>> = |_| DashMap::new();
+
+/// Returns how each neighbor's link is being paced, for the neighbors whose links are paced.
+pub fn pacing_stats(ctx: &DaemonContext) -> BTreeMap<String, PacingStats> {
+    ctx.get(PACING_STATS)
+        .iter()
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect()
+}
+
+/// The state of every route, by direction and name.
+static ROUTE_STATUS: CtxField<DashMap<(RouteDirection, String), RouteStatus>> = |_| DashMap::new();
+
+fn update_route(
+    ctx: &DaemonContext,
+    direction: RouteDirection,
+    name: &str,
+    update: impl FnOnce(&mut RouteStatus),
+) {
+    let mut route = ctx
+        .get(ROUTE_STATUS)
+        .entry((direction, name.to_string()))
+        .or_insert_with(|| RouteStatus {
+            name: name.to_string(),
+            direction,
+            state: RouteState::Connecting,
+            links: 0,
+            last_error: None,
+        });
+    update(&mut route);
+}
+
+/// Returns the state of every route, in direction and then name order.
+pub fn route_statuses(ctx: &DaemonContext) -> Vec<RouteStatus> {
+    ctx.get(ROUTE_STATUS)
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect::<BTreeMap<_, _>>()
+        .into_values()
+        .collect()
+}
+
+/// Out routes that were paused at runtime. Paused routes drop their link and stop dialing until resumed.
+static PAUSED_OUT_ROUTES: CtxField<PausedRoutes> = |_| PausedRoutes {
+    names: Mutex::new(HashSet::new()),
+    changed: Event::new(),
+};
+
+struct PausedRoutes {
+    names: Mutex<HashSet<String>>,
+    changed: Event,
+}
+
+impl PausedRoutes {
+    fn contains(&self, name: &str) -> bool {
+        self.names.lock().contains(name)
+    }
+}
+
+/// Pauses or resumes the out route with the given name. Pausing an already paused route, or resuming one that isn't, does nothing.
+pub fn set_out_route_paused(ctx: &DaemonContext, name: &str, paused: bool) -> anyhow::Result<()> {
+    if !ctx.init().out_routes.contains_key(name) {
+        anyhow::bail!("no out route named {name}");
+    }
+    let routes = ctx.get(PAUSED_OUT_ROUTES);
+    if paused {
+        routes.names.lock().insert(name.to_string());
+    } else {
+        routes.names.lock().remove(name);
+    }
+    routes.changed.notify_all();
+    Ok(())
+}
+
+/// Returns whether the out route with the given name is paused.
+pub fn is_out_route_paused(ctx: &DaemonContext, name: &str) -> bool {
+    ctx.get(PAUSED_OUT_ROUTES).contains(name)
+}
+
+/// Histogram of the round-trip times of liveness probes.
+pub const LINK_RTT_SECONDS: &str = "link.rtt_seconds";
+
+/// The round-trip time of each neighbor's link, refreshed every few seconds.
+static LINK_RTT: CtxField<DashMap<String, Duration>> = |_| DashMap::new();
+
+/// Returns the last measured round-trip time of the link to the given neighbor.
+pub fn link_rtt(ctx: &DaemonContext, neighbor: &str) -> Option<Duration> {
+    ctx.get(LINK_RTT).get(neighbor).map(|rtt| *rtt)
+}
+
+/// Compares the balance a neighbor hinted at, which is how much we owe it by its ledger, with ours.
+fn compare_balance(
+    ctx: &DaemonContext,
+    neighbor_id: NeighborId,
+    neighbor: &str,
+    theirs: Option<i128>,
+) {
+    let debts = ctx.get(DEBTS);
+    let ours = match neighbor_id {
+        Either::Left(client_id) => debts.client_net_debt_est(&client_id),
+        Either::Right(relay_fp) => debts.relay_net_debt_est(&relay_fp),
+    };
+    debts.compare_remote_view(neighbor.to_string(), ours, theirs);
+}
+
+/// Checks that the other side of an out route is the relay we expect, either because its fingerprint is configured or because it was pinned on first use. A relay that rotated its identity is still the one we expect, if the identity we expect vouches for its new one.
+async fn verify_out_route(
+    ctx: &DaemonContext,
+    cfg: &OutRouteConfig,
+    their_relay_descr: Option<&IdentityDescriptor>,
+    link: &Link,
+) -> anyhow::Result<()> {
+    let descr = their_relay_descr.context("other side of out route is not a relay")?;
+    if let Err(err) = descr.verify() {
+        audit(
+            ctx,
+            AuditEvent::HandshakeFailed {
+                connect: cfg.connect.clone(),
+                reason: format!("identity descriptor has a bad signature: {err}"),
+            },
+        )
+        .await;
+        return Err(err.into());
+    }
+    let their_fp = descr.identity_pk.fingerprint();
+    match cfg.fingerprint {
+        Some(fingerprint) if fingerprint != their_fp => {
+            if rotated_from(ctx, link, fingerprint, their_fp).await {
+                tracing::warn!(
+                    connect = debug(&cfg.connect),
+                    old = display(fingerprint),
+                    new = display(their_fp),
+                    "out route rotated its identity. update its configured fingerprint before the rotation's grace period is over"
+                );
+                return Ok(());
+            }
+            audit(
+                ctx,
+                AuditEvent::FingerprintMismatch {
+                    connect: cfg.connect.clone(),
+                    expected: fingerprint,
+                    seen: their_fp,
+                },
+            )
+            .await;
+            anyhow::bail!("out route has fingerprint {their_fp}, but {fingerprint} was configured")
+        }
+        Some(_) => Ok(()),
+        None if cfg.tofu => {
+            if let Some(pinned) = tofu::pinned(ctx, &cfg.connect).await? {
+                if pinned != their_fp && rotated_from(ctx, link, pinned, their_fp).await {
+                    return tofu::repin(ctx, &cfg.connect, their_fp).await;
+                }
+            }
+            tofu::check_pin(ctx, &cfg.connect, their_fp).await
+        }
+        None => {
+            audit(
+                ctx,
+                AuditEvent::HandshakeFailed {
+                    connect: cfg.connect.clone(),
+                    reason: "neither a fingerprint nor tofu is configured".into(),
+                },
+            )
+            .await;
+            anyhow::bail!(
+                "out route has neither a fingerprint nor tofu configured, so there is no telling whether {their_fp} is the relay we want"
+            )
+        }
+    }
+}
+
+/// Whether the other side of a link showed us that it rotated from the identity `old` to `new`, recently enough.
+async fn rotated_from(
+    ctx: &DaemonContext,
+    link: &Link,
+    old: RelayFingerprint,
+    new: RelayFingerprint,
+) -> bool {
+    match LinkClient(link.rpc_transport())
+        .identity_rotation()
+        .timeout(ROUTE_TEST_TIMEOUT)
+        .await
+    {
+        Some(Ok(Some(rotation))) => rotation.vouches_for(old, new, clock::unix_now(ctx)),
+        Some(Ok(None)) | None => false,
+        Some(Err(err)) => {
+            tracing::debug!(err = debug(err), "could not ask for an identity rotation");
+            false
+        }
+    }
+}
+
 async fn pipe_to_mux(
     ctx: &DaemonContext,
     pipe: impl Pipe,
+    params: &ObfsParams,
 ) -> anyhow::Result<(PicoMux, ClientId, Option<IdentityDescriptor>)> {
-    let (mut read, mut write) = pipe.split();
+    let (mut read, write) = pipe.split();
+    let mut write = CappedWrite::new(write, params);
 
     let send_auth = async {
         let my_client_id = *ctx.get(MY_CLIENT_ID);
         let my_relay_descr = ctx
             .get(MY_RELAY_IDENTITY)
             .as_ref()
-            .map(|id| IdentityDescriptor::new(id, ctx.get(MY_RELAY_ONION_SK)));
+            .map(|id| IdentityDescriptorV0::from(identity_refresh::sign_descriptor(ctx, id)));
+        // laid out as before extensions, which go around by gossip instead, so that older relays can still read it
         let auth_msg = (my_client_id, my_relay_descr).stdcode();
         write_pascal(&auth_msg, &mut write).await?;
         anyhow::Ok(())
@@ -164,31 +621,107 @@ async fn pipe_to_mux(
 
     let recv_auth = async {
         let bts = read_pascal(&mut read).await?;
-        let (their_client_id, their_relay_descr): (ClientId, Option<IdentityDescriptor>) =
+        let (their_client_id, their_relay_descr): (ClientId, Option<IdentityDescriptorV0>) =
             stdcode::deserialize(&bts)?;
-        anyhow::Ok((their_client_id, their_relay_descr))
+        anyhow::Ok((
+            their_client_id,
+            their_relay_descr.map(IdentityDescriptor::from),
+        ))
     };
 
     let (a, b) = futures::join!(send_auth, recv_auth);
     a?;
     let (their_client_id, their_relay_descr) = b?;
+    // picomux 0.1.1 fixes each stream's window itself, so there is none to configure here
     let mux = PicoMux::new(read, write);
     Ok((mux, their_client_id, their_relay_descr))
 }
 
+/// Decides whether to forward a packet a neighbor sent over a link with the given debt policy, handing back where it came from, and how long to hold it first, if so. Refused packets are reported to the neighbor, and NACKed if it asked for that.
+fn admit_incoming(
+    ctx: &DaemonContext,
+    neighbor_id: NeighborId,
+    debt_policy: &LinkDebtPolicy,
+    pkt: &RawPacket,
+    origin: Option<NackOrigin>,
+) -> Result<(Option<NackOrigin>, Duration), DropReason> {
+    let refused = match neighbor_id {
+        Either::Left(client_id) if !network::within_client_rate_limit(ctx, client_id) => {
+            tracing::trace!(
+                neighbor = display(neighbor_id),
+                "refusing to forward for a client over its rate limit"
+            );
+            DropReason::RateLimited
+        }
+        _ => {
+            let admission = match neighbor_id {
+                Either::Left(client_id) => ctx.get(DEBTS).client_admission(&client_id, debt_policy),
+                Either::Right(relay_fp) => ctx.get(DEBTS).relay_admission(&relay_fp, debt_policy),
+            };
+            match admission {
+                Admission::Admit => return Ok((origin, Duration::ZERO)),
+                Admission::Delay(delay) => return Ok((origin, delay)),
+                Admission::Refuse => DropReason::DebtLimit,
+            }
+        }
+    };
+    if refused == DropReason::DebtLimit {
+        tracing::trace!(
+            neighbor = display(neighbor_id),
+            "refusing to forward for a neighbor near or over its debt limit"
+        );
+    }
+    network::report_drop(ctx, neighbor_id, pkt, refused);
+    network::dropped(ctx, origin, NackReason::Policy);
+    Err(refused)
+}
+
 async fn manage_mux(
     ctx: &DaemonContext,
     link: Link,
     their_client_id: ClientId,
     their_relay_descr: Option<IdentityDescriptor>,
+    debt_policy: LinkDebtPolicy,
 ) -> anyhow::Result<()> {
     scopeguard::defer!(tracing::debug!("manage_mux died"));
 
     if let Some(descr) = their_relay_descr.as_ref() {
+        let mut graph = ctx.get(RELAY_GRAPH).write();
+        // direct neighbors must never be evicted from the graph, so they are anchored before they go in
+        graph.anchor(descr.identity_pk.fingerprint());
+        if let Err(err) = graph.insert_identity(descr.clone()) {
+            graph.unanchor(&descr.identity_pk.fingerprint());
+            return Err(err.into());
+        }
+    }
+    scopeguard::defer!(if let Some(descr) = their_relay_descr.as_ref() {
         ctx.get(RELAY_GRAPH)
             .write()
-            .insert_identity(descr.clone())?;
-    }
+            .unanchor(&descr.identity_pk.fingerprint());
+    });
+    // reply blocks anchored at the relay stop working if we don't get a link back to it soon
+    scopeguard::defer!(if let Some(descr) = their_relay_descr.as_ref() {
+        n2r::link_down(ctx, descr.identity_pk.fingerprint());
+    });
+    // the name this neighbor goes by in the ledger
+    let neighbor = their_relay_descr
+        .as_ref()
+        .map(|descr| descr.identity_pk.fingerprint().to_string())
+        .unwrap_or_else(|| their_client_id.to_string());
+
+    let neighbor_id: NeighborId = match their_relay_descr.as_ref() {
+        Some(descr) => Either::Right(descr.identity_pk.fingerprint()),
+        None => Either::Left(their_client_id),
+    };
+    scopeguard::defer!(if their_relay_descr.is_none() {
+        network::disable_drop_reports(ctx, their_client_id);
+        network::forget_client(ctx, their_client_id);
+    });
+    // whether we ask the other side for drop reports, which only relays give, and only to clients
+    let wants_drop_reports =
+        ctx.init().drop_reports.request && is_client(ctx) && their_relay_descr.is_some();
+    let sent_packets = Arc::new(SentPackets::default());
+
     // subscribe to the right outgoing stuff and stuff them into the link
     let recv_outgoing_client = network::subscribe_outgoing_client(ctx, their_client_id);
     println!("ADDED CLIENT_ID: {their_client_id}");
@@ -200,6 +733,7 @@ async fn manage_mux(
                 rb_id: msg.1,
             })
             .await?;
+            ledger::count_outgoing(ctx, &neighbor);
         }
     };
 
@@ -208,21 +742,86 @@ async fn manage_mux(
             let recv_relay_msg =
                 network::subscribe_outgoing_relay(ctx, relay_descr.identity_pk.fingerprint());
             loop {
-                let (pkt, next_peeler) = recv_relay_msg.recv().await?;
-                link.send_msg(LinkMessage::ToRelay {
-                    packet: Bytes::copy_from_slice(bytemuck::bytes_of(&pkt)),
-                    next_peeler,
+                let (pkt, next_peeler, nack_tag) = recv_relay_msg.recv().await?;
+                if let (true, Some(nack_tag)) = (wants_drop_reports, nack_tag) {
+                    sent_packets.record(&pkt, nack_tag);
+                }
+                let packet = Bytes::copy_from_slice(bytemuck::bytes_of(&pkt));
+                link.send_msg(match nack_tag {
+                    None => LinkMessage::ToRelay {
+                        packet,
+                        next_peeler,
+                    },
+                    Some(nack_tag) => LinkMessage::ToRelayNackable {
+                        packet,
+                        next_peeler,
+                        nack_tag,
+                    },
                 })
                 .await?;
+                ledger::count_outgoing(ctx, &neighbor);
             }
         } else {
             smol::future::pending().await
         }
     };
 
+    let recv_nacks = network::subscribe_nacks(ctx, neighbor_id);
+    let send_nacks = async {
+        loop {
+            let (tag, reason) = recv_nacks.recv().await?;
+            link.send_msg(LinkMessage::Nack { tag, reason }).await?;
+        }
+    };
+
+    let incoming_to_relay = |packet: Bytes,
+                             next_peeler: RelayFingerprint,
+                             nack_tag: Option<u64>| {
+        let their_relay_descr = &their_relay_descr;
+        async move {
+            tracing::trace!(next_peeler = debug(next_peeler), "incoming ToRelay");
+            let pkt: RawPacket = *bytemuck::try_from_bytes(&packet)
+                .ok()
+                .context("failed to deserialize incoming RawPacket")?;
+            network::mark_ingress(ctx, &pkt);
+            let origin = nack_tag.map(|tag| NackOrigin::Neighbor {
+                neighbor: neighbor_id,
+                tag,
+            });
+            let origin = match admit_incoming(ctx, neighbor_id, &debt_policy, &pkt, origin) {
+                Ok((origin, delay)) => {
+                    if !delay.is_zero() {
+                        // holding the packet holds up everything else the neighbor sends too, which is the point
+                        smol::Timer::after(delay).await;
+                    }
+                    origin
+                }
+                Err(DropReason::DebtLimit) => {
+                    audit_debt_limit_drop(ctx, &neighbor_id.to_string());
+                    return anyhow::Ok(());
+                }
+                Err(_) => return anyhow::Ok(()),
+            };
+            // under overload, we keep fully serving our direct clients, and shed traffic from other relays
+            let prev_hop = their_relay_descr
+                .as_ref()
+                .map(|descr| descr.identity_pk.fingerprint());
+            if let Err(err) = network::incoming_raw(ctx, next_peeler, pkt, prev_hop, origin).await {
+                network::report_drop(ctx, neighbor_id, &pkt, DropReason::Undeliverable);
+                tracing::debug!(
+                    err = debug(err),
+                    next_peeler = debug(next_peeler),
+                    "failed to process incoming raw",
+                );
+            }
+            anyhow::Ok(())
+        }
+    };
+
     let recv_incoming = async {
         loop {
             let in_msg = link.recv_msg().await?;
+            ledger::count_incoming(ctx, &neighbor);
             match in_msg {
                 LinkMessage::ToClient { body, rb_id } => {
                     tracing::trace!(rb_id, "incoming ToClient");
@@ -236,18 +835,15 @@ async fn manage_mux(
                 LinkMessage::ToRelay {
                     packet,
                     next_peeler,
-                } => {
-                    tracing::trace!(next_peeler = debug(next_peeler), "incoming ToRelay");
-                    let pkt: RawPacket = *bytemuck::try_from_bytes(&packet)
-                        .ok()
-                        .context("failed to deserialize incoming RawPacket")?;
-                    if let Err(err) = network::incoming_raw(ctx, next_peeler, pkt).await {
-                        tracing::debug!(
-                            err = debug(err),
-                            next_peeler = debug(next_peeler),
-                            "failed to process incoming raw",
-                        );
-                    }
+                } => incoming_to_relay(packet, next_peeler, None).await?,
+                LinkMessage::ToRelayNackable {
+                    packet,
+                    next_peeler,
+                    nack_tag,
+                } => incoming_to_relay(packet, next_peeler, Some(nack_tag)).await?,
+                LinkMessage::Nack { tag, reason } => {
+                    tracing::trace!(tag, reason = debug(reason), "incoming Nack");
+                    network::incoming_nack(ctx, neighbor_id, tag, reason);
                 }
             }
         }
@@ -261,44 +857,486 @@ async fn manage_mux(
         ctx: ctx.clone(),
         remote_client_id: their_client_id,
         remote_relay_fp,
+        sent_packets: sent_packets.clone(),
     });
     let rpc_serve = link.rpc_serve(service);
 
-    // gossip
-    let gossip_loop = async {
+    // gossip, whose calls all time out, so that a neighbor that never answers only holds up gossip
+    let gossip_client = LinkClient(link.rpc_transport());
+    let gossip_loop = gossip_loop(ctx, &gossip_client, remote_relay_fp, &neighbor);
+
+    // answer route probes from routing misses
+    let probe_loop = async {
+        let mut seen = 0;
+        loop {
+            let (newest, dests) = network::wanted_probes(ctx, seen).await;
+            seen = newest;
+            for dest in dests {
+                if let Err(err) = probe_toward(ctx, &gossip_client, dest).await {
+                    tracing::debug!(dest = display(dest), err = debug(err), "route probe failed");
+                }
+            }
+        }
+    };
+
+    // pings paced links back to back, since every answer tells the pacer what arrived, and refreshes pacing stats
+    let pacing_loop = async {
+        if link.pacing_stats().is_none() {
+            return smol::future::pending().await;
+        }
+        scopeguard::defer!({
+            ctx.get(PACING_STATS).remove(&neighbor);
+        });
+        let mut refreshed: Option<Instant> = None;
+        loop {
+            let ping = LinkClient(link.rpc_transport()).ping(rand::random());
+            if link
+                .probe_pacing(ping)
+                .timeout(PACING_PROBE_TIMEOUT)
+                .await
+                .is_none()
+            {
+                tracing::debug!(neighbor, "pacing probe timed out");
+            }
+            if refreshed.map_or(true, |at| at.elapsed() >= PACING_STATS_REFRESH) {
+                if let Some(stats) = link.pacing_stats() {
+                    ctx.get(PACING_STATS).insert(neighbor.clone(), stats);
+                }
+                refreshed = Some(Instant::now());
+            }
+        }
+    };
+
+    // whether the neighbor's ledger agrees with ours
+    let balance_loop = async {
+        let liveness = ctx.init().liveness;
         loop {
-            let _ = gossip_once(ctx, &link, remote_relay_fp).await;
-            smol::Timer::after(Duration::from_secs(1)).await;
+            smol::Timer::after(liveness.probe_interval()).await;
+            // neighbors that predate balance hints fail the call, and aren't compared
+            let hint = LinkClient(link.rpc_transport())
+                .balance_hint()
+                .timeout(liveness.probe_timeout())
+                .await;
+            if let Some(Ok(theirs)) = hint {
+                compare_balance(ctx, neighbor_id, &neighbor, theirs);
+            }
         }
     };
 
+    // probes the neighbor, which measures round-trip time, and declares it dead if it stops answering, which drops the link
+    let liveness_loop = async {
+        let err = liveness::watch_neighbor(ctx, &link, &neighbor).await;
+        tracing::warn!(
+            neighbor = display(&neighbor),
+            err = debug(&err),
+            "declaring neighbor dead"
+        );
+        ctx.get(STATS).incr(LINK_DECLARED_DEAD);
+        Err(err.context(format!("{neighbor} declared dead")))
+    };
+
     // chat
     let chat_loop = async {
         loop {
             let unsent = ctx
                 .get(CHATS)
-                .wait_unsent(
-                    their_relay_descr
-                        .as_ref()
-                        .map(|r| either::Either::Right(r.identity_pk.fingerprint()))
-                        .unwrap_or_else(|| either::Either::Left(their_client_id)),
-                )
+                .wait_unsent(neighbor_id, ctx.init().chat_rate_limit)
                 .await;
             tracing::debug!(len = unsent.len(), "sending batch of chats");
-            for unsent in unsent {
-                tracing::debug!(text = &unsent.text, "sending a chat");
-                LinkClient(link.rpc_transport())
-                    .push_chat(unsent.text)
-                    .await?;
+            for (i, entry) in unsent.iter().enumerate() {
+                tracing::debug!(text = &entry.text, "sending a chat");
+                let client = LinkClient(link.rpc_transport());
+                let pushed = match client
+                    .push_chat_once(entry.id(neighbor_id), entry.text.clone())
+                    .await
+                {
+                    // the neighbor predates nonces
+                    Err(LinkError::NotFound) => client.push_chat(entry.text.clone()).await,
+                    pushed => pushed,
+                };
+                if let Err(err) = pushed {
+                    // the chat may have arrived anyway, but its nonce keeps the retry from duplicating it
+                    ctx.get(CHATS).requeue(neighbor_id, &unsent[i..]);
+                    return Err(err.into());
+                }
             }
         }
     };
 
+    // settlements we started with the relay at the other end
+    let settlement_loop = async {
+        let Some(descr) = their_relay_descr.as_ref() else {
+            return smol::future::pending().await;
+        };
+        let settlements = ctx.get(SETTLEMENTS);
+        loop {
+            let request = settlements
+                .wait_outgoing(descr.identity_pk.fingerprint())
+                .await;
+            // a settlement that doesn't go through is the operator's to retry, and no reason to drop the link
+            let response = LinkClient(link.rpc_transport())
+                .start_settlement(request.clone())
+                .timeout(SETTLEMENT_WAIT + Duration::from_secs(30))
+                .await;
+            match response {
+                Some(Ok(Some(response)))
+                    if response.request == request
+                        && response.verify(&descr.identity_pk).is_ok() =>
+                {
+                    tracing::info!(
+                        neighbor = display(&neighbor),
+                        current_debt = response.current_debt,
+                        "neighbor accepted our settlement"
+                    );
+                    ctx.get(DEBTS).deduct_relay_outgoing_settlement(
+                        descr.identity_pk.fingerprint(),
+                        request.decrease,
+                    );
+                    if let Err(err) = settlements.record_proofs(ctx, &neighbor, &request).await {
+                        tracing::warn!(err = debug(err), "could not record an accepted settlement");
+                    }
+                }
+                _ => tracing::warn!(
+                    neighbor = display(&neighbor),
+                    "neighbor did not accept our settlement"
+                ),
+            }
+        }
+    };
+
+    // drop reports, which we ask our relay for as a client, or give our clients as a relay
+    let drop_report_loop = async {
+        if wants_drop_reports {
+            match LinkClient(link.rpc_transport())
+                .request_drop_reports()
+                .await
+            {
+                Ok(true) => {
+                    tracing::debug!(
+                        neighbor = display(&neighbor),
+                        "relay agreed to report our drops"
+                    )
+                }
+                // including relays too old to know about drop reports
+                _ => tracing::debug!(
+                    neighbor = display(&neighbor),
+                    "relay won't report our drops"
+                ),
+            }
+            return smol::future::pending().await;
+        }
+        if their_relay_descr.is_some() || !ctx.init().drop_reports.serve {
+            return smol::future::pending().await;
+        }
+        let mut reports = network::subscribe_drop_reports(ctx, their_client_id);
+        loop {
+            let batch = reports.next_batch(ctx).await?;
+            LinkClient(link.rpc_transport()).drop_reports(batch).await?;
+        }
+    };
+
     send_outgoing_client
         .race(send_outgoing_relay)
         .race(rpc_serve)
         .race(gossip_loop)
+        .race(probe_loop)
         .race(recv_incoming)
         .race(chat_loop)
+        .race(settlement_loop)
+        .race(pacing_loop)
+        .race(balance_loop)
+        .race(liveness_loop)
+        .race(send_nacks)
+        .race(drop_report_loop)
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use clone_macro::clone;
+    use earendil_crypt::{RelayFingerprint, RelayIdentitySecret};
+    use serde_json::json;
+
+    use super::{link_protocol::LinkProtocol, link_protocol_impl::LinkProtocolImpl, *};
+    use crate::{
+        debts::{DebtEvent, RemoteView},
+        micromel::Micromel,
+    };
+
+    #[test]
+    fn test_out_route_checks_fingerprint() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let relay = DaemonContext::new(
+            serde_json::from_value(json!({
+                "identity_seed": "test out route",
+                "in_routes": { "main": { "listen": format!("127.0.0.1:{port}"), "obfs": "none" } },
+            }))
+            .unwrap(),
+        );
+        let relay_fp = RelayIdentitySecret::from_seed("test out route")
+            .public()
+            .fingerprint();
+        let client = DaemonContext::new(serde_json::from_value(json!({})).unwrap());
+        let out_route = |fingerprint: RelayFingerprint| -> OutRouteConfig {
+            serde_json::from_value(json!({
+                "connect": format!("127.0.0.1:{port}"),
+                "fingerprint": fingerprint.to_string(),
+                "obfs": "none",
+            }))
+            .unwrap()
+        };
+
+        let _listener = smolscale::spawn(clone!([relay], async move {
+            let in_route = relay.init().in_routes["main"].clone();
+            listen_in_route(&relay, "main", &in_route).await
+        }));
+        let results = async {
+            // give the listener a moment to bind
+            smol::Timer::after(Duration::from_millis(100)).await;
+            let right = test_out_route(&client, &out_route(relay_fp)).await;
+            let wrong_fp = RelayIdentitySecret::generate().public().fingerprint();
+            let wrong = test_out_route(&client, &out_route(wrong_fp)).await;
+            (right, wrong, wrong_fp)
+        };
+        let (right, wrong, wrong_fp) = smol::future::block_on(results);
+
+        assert_eq!(right.outcome, RouteTestOutcome::Success);
+        assert_eq!(right.fingerprint, Some(relay_fp));
+        assert_eq!(right.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+        assert!(right.rtt_ms.is_some());
+        assert_eq!(
+            wrong.outcome,
+            RouteTestOutcome::FingerprintMismatch { expected: wrong_fp }
+        );
+        assert_eq!(wrong.fingerprint, Some(relay_fp));
+        // and neither test left the route running
+        assert!(network::all_relay_neighs(&client).is_empty());
+    }
+
+    #[test]
+    fn tofu_repins_only_within_a_capped_grace_period() {
+        use crate::{
+            daemon::identity_rotation::{IdentityRotation, MAX_GRACE_SECS},
+            db::{db_write, MiscKey},
+        };
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let dir = std::env::temp_dir().join(format!("earendil-repin-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let relay = DaemonContext::new(
+            serde_json::from_value(json!({
+                "identity_seed": "rotated relay",
+                "state_cache": dir.join("state.db"),
+                "in_routes": { "main": { "listen": format!("127.0.0.1:{port}"), "obfs": "none" } },
+            }))
+            .unwrap(),
+        );
+        let relay_id = RelayIdentitySecret::from_seed("rotated relay");
+        let client = DaemonContext::new(serde_json::from_value(json!({})).unwrap());
+        let cfg: OutRouteConfig = serde_json::from_value(json!({
+            "connect": format!("127.0.0.1:{port}"),
+            "tofu": true,
+            "obfs": "none",
+        }))
+        .unwrap();
+        // the relay went by this identity when the client pinned it
+        let old_id = RelayIdentitySecret::generate();
+        let old_fp = old_id.public().fingerprint();
+
+        async fn verify(client: &DaemonContext, cfg: &OutRouteConfig) -> anyhow::Result<()> {
+            let pipe = TcpDialer {
+                dest_addr: resolve_connect(&cfg.connect)?,
+            }
+            .dial()
+            .await?;
+            let (mux, _, their_relay_descr) = pipe_to_mux(client, pipe, &cfg.obfs.params()).await?;
+            let link = Link::new_dial(mux).await?;
+            verify_out_route(client, cfg, their_relay_descr.as_ref(), &link).await
+        }
+        let _listener = smolscale::spawn(clone!([relay], async move {
+            let in_route = relay.init().in_routes["main"].clone();
+            listen_in_route(&relay, "main", &in_route).await
+        }));
+        smol::future::block_on(async {
+            smol::Timer::after(Duration::from_millis(100)).await;
+            tofu::repin(&client, &cfg.connect, old_fp).await.unwrap();
+            let now = clock::unix_now(&relay);
+
+            // a rotation made long ago, claiming a grace period that never ends, is held to the cap, so it no longer vouches
+            let stale =
+                IdentityRotation::new(&old_id, &relay_id, now - 2 * MAX_GRACE_SECS, u64::MAX);
+            db_write(&relay, MiscKey::IdentityRotation, stale.stdcode())
+                .await
+                .unwrap();
+            assert!(verify(&client, &cfg).await.is_err());
+            assert_eq!(
+                tofu::pinned(&client, &cfg.connect).await.unwrap(),
+                Some(old_fp)
+            );
+
+            // a recent one does, and the pin follows it
+            let recent = IdentityRotation::new(&old_id, &relay_id, now, 3600);
+            db_write(&relay, MiscKey::IdentityRotation, recent.stdcode())
+                .await
+                .unwrap();
+            verify(&client, &cfg).await.unwrap();
+            assert_eq!(
+                tofu::pinned(&client, &cfg.connect).await.unwrap(),
+                Some(relay_id.public().fingerprint())
+            );
+        });
+        drop(relay);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn diverging_ledgers_are_noticed_on_both_sides() {
+        let node = |seed: &str| {
+            DaemonContext::new(
+                serde_json::from_value(json!({
+                    "identity_seed": seed,
+                    "debt_divergence_tolerance": 10,
+                }))
+                .unwrap(),
+            )
+        };
+        let (alice, bob) = (node("ledger alice"), node("ledger bob"));
+        let fp = |seed| RelayIdentitySecret::from_seed(seed).public().fingerprint();
+        let (alice_fp, bob_fp) = (fp("ledger alice"), fp("ledger bob"));
+        // what each side answers the other's balance hint with
+        let hint_server = |ctx: &DaemonContext, caller: RelayFingerprint| LinkProtocolImpl {
+            ctx: ctx.clone(),
+            remote_client_id: 0,
+            remote_relay_fp: Some(caller),
+            sent_packets: Default::default(),
+        };
+        let exchange = || {
+            smol::future::block_on(async {
+                let for_alice = hint_server(&bob, alice_fp).balance_hint().await;
+                let for_bob = hint_server(&alice, bob_fp).balance_hint().await;
+                compare_balance(
+                    &alice,
+                    Either::Right(bob_fp),
+                    &bob_fp.to_string(),
+                    for_alice,
+                );
+                compare_balance(
+                    &bob,
+                    Either::Right(alice_fp),
+                    &alice_fp.to_string(),
+                    for_bob,
+                );
+            })
+        };
+        let events = |ctx: &DaemonContext, after| {
+            smol::future::block_on(ctx.get(DEBTS).wait_events(after))
+                .into_iter()
+                .map(|(_, event)| event)
+                .collect::<Vec<_>>()
+        };
+
+        // alice counted 50 micromel of bob's traffic, which bob never counted
+        alice
+            .get(DEBTS)
+            .insert_relay_incoming_price(bob_fp, Micromel(10), Micromel(1000));
+        bob.get(DEBTS)
+            .insert_relay_outgoing_price(alice_fp, Micromel(10), Micromel(1000));
+        for _ in 0..5 {
+            alice.get(DEBTS).incr_relay_incoming(bob_fp);
+        }
+        exchange();
+        assert_eq!(
+            events(&alice, 0),
+            vec![DebtEvent::BalanceDiverged(bob_fp.to_string(), 50, 0)]
+        );
+        assert_eq!(
+            events(&bob, 0),
+            vec![DebtEvent::BalanceDiverged(alice_fp.to_string(), 0, -50)]
+        );
+        assert_eq!(
+            alice.get(DEBTS).remote_view(&bob_fp.to_string()),
+            Some(RemoteView {
+                net_debt: 0,
+                divergence: 50
+            })
+        );
+
+        // once bob catches up, both sides agree again
+        for _ in 0..5 {
+            bob.get(DEBTS).incr_relay_outgoing(alice_fp);
+        }
+        exchange();
+        assert_eq!(
+            events(&alice, 1),
+            vec![DebtEvent::BalanceAgreed(bob_fp.to_string())]
+        );
+        assert_eq!(
+            events(&bob, 1),
+            vec![DebtEvent::BalanceAgreed(alice_fp.to_string())]
+        );
+        assert_eq!(
+            bob.get(DEBTS).remote_view(&alice_fp.to_string()),
+            Some(RemoteView {
+                net_debt: -50,
+                divergence: 0
+            })
+        );
+    }
+
+    #[test]
+    fn strict_prepay_holds_per_link_and_only_for_charged_neighbors() {
+        use bytemuck::Zeroable;
+
+        let ctx = DaemonContext::new(
+            serde_json::from_value(json!({
+                "identity_seed": "prepay relay",
+                "strict_prepay": true,
+            }))
+            .unwrap(),
+        );
+        let charged = RelayIdentitySecret::generate().public().fingerprint();
+        let free = RelayIdentitySecret::generate().public().fingerprint();
+        ctx.get(DEBTS)
+            .insert_relay_incoming_price(charged, Micromel(10), Micromel(1000));
+        ctx.get(DEBTS).incr_relay_incoming(charged);
+        let pkt = RawPacket::zeroed();
+        let nacks = network::subscribe_nacks(&ctx, Either::Right(charged));
+        let admit = |neighbor: RelayFingerprint, policy: LinkDebtPolicy, tag| {
+            let origin = NackOrigin::Neighbor {
+                neighbor: Either::Right(neighbor),
+                tag,
+            };
+            admit_incoming(&ctx, Either::Right(neighbor), &policy, &pkt, Some(origin)).is_ok()
+        };
+        let (strict, lenient) = (
+            LinkDebtPolicy::default(),
+            LinkDebtPolicy {
+                strict_prepay: Some(false),
+                ..Default::default()
+            },
+        );
+
+        // a neighbor owing anything at all is refused, and told so
+        assert!(!admit(charged, strict, 1));
+        assert_eq!(nacks.try_recv().unwrap(), (1, NackReason::Policy));
+        // but not over a link whose route lets it run up debt, while its other link stays strict
+        assert!(admit(charged, lenient, 2));
+        assert!(!admit(charged, strict, 3));
+        assert_eq!(nacks.try_recv().unwrap(), (3, NackReason::Policy));
+        // neighbors we don't charge are never refused
+        assert!(admit(free, strict, 4));
+        // and settling brings the charged neighbor back
+        ctx.get(DEBTS)
+            .deduct_relay_settlement(charged, Micromel(10));
+        assert!(admit(charged, strict, 5));
+        assert!(nacks.try_recv().is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::AsyncWrite;
+
+use crate::config::ObfsParams;
+
+/// The writing half of a pipe, with writes capped at the `mtu` its route sets, if any. Writes bigger than that are cut short, so callers that write everything, as the mux does, hand the pipe MTU-sized pieces.
+///
+/// This sits above the route's transport, so it caps what sosistab3 or TCP is handed at once, not what goes on the wire: sosistab3 adds its own framing, and TCP may merge or split writes into segments of any size. Paths that need small segments still need the MTU set on the path itself.
+pub struct CappedWrite<W> {
+    inner: W,
+    mtu: Option<usize>,
+}
+
+impl<W> CappedWrite<W> {
+    pub fn new(inner: W, params: &ObfsParams) -> Self {
+        Self {
+            inner,
+            mtu: params.mtu,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CappedWrite<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let buf = match self.mtu {
+            Some(mtu) => &buf[..buf.len().min(mtu)],
+            None => buf,
+        };
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::AsyncWriteExt;
+
+    use crate::config::ObfsConfig;
+
+    use super::*;
+
+    /// Remembers how big each write was.
+    #[derive(Default)]
+    struct Recorder(Vec<usize>);
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.push(buf.len());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn route_mtu_caps_writes_to_pipe() {
+        let obfs: ObfsConfig =
+            serde_yaml::from_str("mode: sosistab3\ncookie: hello\nparams:\n  mtu: 100\n").unwrap();
+        let mut write = CappedWrite::new(Recorder::default(), &obfs.params());
+        smol::future::block_on(write.write_all(&[0; 1000])).unwrap();
+        assert_eq!(write.inner.0, vec![100; 10]);
+
+        // without an override, writes go through whole
+        let mut write = CappedWrite::new(
+            Recorder::default(),
+            &ObfsConfig::None { params: None }.params(),
+        );
+        smol::future::block_on(write.write_all(&[0; 1000])).unwrap();
+        assert_eq!(write.inner.0, vec![1000]);
+    }
+}
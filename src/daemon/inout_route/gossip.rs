@@ -1,45 +1,163 @@
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    collections::{BTreeMap, HashSet},
+    future::Future,
+    time::Duration,
+};
 
 use anyhow::Context;
 use bytes::Bytes;
+use dashmap::DashMap;
 use earendil_crypt::RelayFingerprint;
-use earendil_topology::{AdjacencyDescriptor, IdentityDescriptor};
+use earendil_topology::AdjacencyDescriptor;
 use itertools::Itertools;
-use moka::sync::{Cache, CacheBuilder};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use tap::TapOptional;
+use smol_timeout::TimeoutExt;
 
 use crate::{
+    clock,
     context::{CtxField, DaemonContext, MY_RELAY_IDENTITY, RELAY_GRAPH},
-    daemon::{inout_route::link_protocol::LinkClient, link::Link},
+    daemon::{inout_route::link_protocol::LinkClient, link::MAX_RPC_LINE},
+    network,
+    stats::STATS,
+    verified::{remember_verified, verified_lately, Verified},
 };
 
+pub const GOSSIP_ROUND_FAILED: &str = "gossip.round_failed";
+pub const GOSSIP_TIMED_OUT: &str = "gossip.timed_out";
+pub const GOSSIP_ADJACENCIES_TRUNCATED: &str = "gossip.adjacencies_truncated";
+
+/// The most adjacencies we process from any one reply to `adjacencies`. We only ever ask about a handful of relays at once, so an honest neighbor rarely sends anywhere near this many, and the rest of a reply stuffed with more is ignored.
+pub const MAX_ADJACENCIES_PER_REPLY: usize = 200;
+
+/// How long we wait between gossip rounds with a neighbor after a round that went well. Each failed round in a row doubles this, up to [MAX_GOSSIP_INTERVAL].
+const MIN_GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_GOSSIP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How much each round counts towards a neighbor's gossip health, against the rounds before it.
+const HEALTH_WEIGHT: f64 = 0.25;
+
+/// How gossip with each neighbor has been going, from 0 when the recent rounds all failed to 1 when they all went well.
+static GOSSIP_HEALTH: CtxField<DashMap<String, f64>> = |_| DashMap::new();
+
+/// Returns how gossip with each neighbor has been going, from 0 when the recent rounds all failed to 1 when they all went well.
+pub fn gossip_health(ctx: &DaemonContext) -> BTreeMap<String, f64> {
+    ctx.get(GOSSIP_HEALTH)
+        .iter()
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect()
+}
+
+/// Gossips with a neighbor for as long as the link is up. Rounds that fail, including ones where the neighbor is too slow to answer, back off and count against the neighbor's gossip health, but never stop the loop.
+pub async fn gossip_loop(
+    ctx: &DaemonContext,
+    client: &LinkClient,
+    remote_fp: Option<RelayFingerprint>,
+    neighbor: &str,
+) -> anyhow::Result<()> {
+    scopeguard::defer!({
+        ctx.get(GOSSIP_HEALTH).remove(neighbor);
+    });
+    let mut interval = MIN_GOSSIP_INTERVAL;
+    loop {
+        interval = if gossip_round(ctx, client, remote_fp, neighbor).await {
+            MIN_GOSSIP_INTERVAL
+        } else {
+            (interval * 2).min(MAX_GOSSIP_INTERVAL)
+        };
+        clock::clock(ctx).sleep(interval).await;
+    }
+}
+
+/// Runs one gossip round, recording how it went in the neighbor's gossip health. Returns whether it went well.
+async fn gossip_round(
+    ctx: &DaemonContext,
+    client: &LinkClient,
+    remote_fp: Option<RelayFingerprint>,
+    neighbor: &str,
+) -> bool {
+    let round_timeout = ctx.init().rpc_timeouts.gossip_round();
+    let result = match gossip_once(ctx, client, remote_fp)
+        .timeout(round_timeout)
+        .await
+    {
+        Some(result) => result,
+        None => {
+            ctx.get(STATS).incr(GOSSIP_TIMED_OUT);
+            Err(anyhow::anyhow!("round took longer than {round_timeout:?}"))
+        }
+    };
+    if let Err(err) = &result {
+        ctx.get(STATS).incr(GOSSIP_ROUND_FAILED);
+        tracing::debug!(
+            neighbor = display(neighbor),
+            err = debug(err),
+            "gossip round failed"
+        );
+    }
+    let went_well = if result.is_ok() { 1.0 } else { 0.0 };
+    ctx.get(GOSSIP_HEALTH)
+        .entry(neighbor.to_string())
+        .and_modify(|health| *health += (went_well - *health) * HEALTH_WEIGHT)
+        .or_insert(went_well);
+    result.is_ok()
+}
+
 #[tracing::instrument(skip_all)]
 pub async fn gossip_once(
     ctx: &DaemonContext,
-    link: &Link,
+    client: &LinkClient,
     remote_fp: Option<RelayFingerprint>,
 ) -> anyhow::Result<()> {
     if let Some(remote_fp) = remote_fp {
-        fetch_identity(ctx, link, remote_fp).await?;
-        sign_adjacency(ctx, link, remote_fp).await?;
+        fetch_identity(ctx, client, remote_fp).await?;
+        sign_adjacency(ctx, client, remote_fp).await?;
     }
-    gossip_graph(ctx, link).await?;
+    gossip_graph(ctx, client).await?;
 
     Ok(())
 }
 
+/// Waits for a call to the neighbor, giving up once `rpc_timeouts.gossip_ms` is up, so that a neighbor that never answers can't hold up gossip forever.
+async fn timed<T, E: Into<anyhow::Error>>(
+    ctx: &DaemonContext,
+    call: impl Future<Output = Result<T, E>>,
+) -> anyhow::Result<T> {
+    let timeout = ctx.init().rpc_timeouts.gossip();
+    match call.timeout(timeout).await {
+        Some(result) => result.map_err(Into::into),
+        None => {
+            ctx.get(STATS).incr(GOSSIP_TIMED_OUT);
+            anyhow::bail!("neighbor did not answer within {timeout:?}")
+        }
+    }
+}
+
+/// Keeps at most [MAX_ADJACENCIES_PER_REPLY] of the adjacencies a neighbor sent. The reply they came in was already no bigger than [MAX_RPC_LINE] when it was parsed, so this bounds the work we do for them, not the memory.
+fn bounded(
+    ctx: &DaemonContext,
+    mut adjacencies: Vec<AdjacencyDescriptor>,
+) -> Vec<AdjacencyDescriptor> {
+    if adjacencies.len() > MAX_ADJACENCIES_PER_REPLY {
+        ctx.get(STATS).incr(GOSSIP_ADJACENCIES_TRUNCATED);
+        tracing::debug!(
+            len = adjacencies.len(),
+            "neighbor sent too many adjacencies, ignoring the rest"
+        );
+        adjacencies.truncate(MAX_ADJACENCIES_PER_REPLY);
+    }
+    adjacencies
+}
+
 // Step 1: Fetch the identity of the neighbor.
 #[tracing::instrument(skip_all)]
 async fn fetch_identity(
     ctx: &DaemonContext,
-    link: &Link,
+    client: &LinkClient,
     remote_fp: RelayFingerprint,
 ) -> anyhow::Result<()> {
     tracing::trace!("fetching identity...");
-    let their_id = LinkClient(link.rpc_transport())
-        .identity(remote_fp)
+    let their_id = timed(ctx, client.identity(remote_fp))
         .await?
         .context("relay neighbors should give us their own id!!!")?;
     ctx.get(RELAY_GRAPH).write().insert_identity(their_id)?;
@@ -50,24 +168,28 @@ async fn fetch_identity(
 #[tracing::instrument(skip_all)]
 async fn sign_adjacency(
     ctx: &DaemonContext,
-    link: &Link,
+    client: &LinkClient,
     remote_fp: RelayFingerprint,
 ) -> anyhow::Result<()> {
     if let Some(my_sk) = ctx.get(MY_RELAY_IDENTITY).as_ref() {
         tracing::trace!("signing adjacency...");
         let my_fp = my_sk.public().fingerprint();
         if my_fp < remote_fp {
+            // right after a clock jump, our clock may not be corrected yet, and would timestamp the adjacency wrongly. If no neighbor confirms it for long enough, it's trusted anyway
+            if !clock::wall_clock_trusted(ctx) && !confirm_wall_clock(ctx, client).await? {
+                tracing::debug!("not signing adjacencies until a neighbor agrees with our clock");
+                return Ok(());
+            }
             tracing::trace!("signing adjacency with {remote_fp}");
             let mut left_incomplete = AdjacencyDescriptor {
                 left: my_fp,
                 right: remote_fp,
                 left_sig: Bytes::new(),
                 right_sig: Bytes::new(),
-                unix_timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                unix_timestamp: clock::unix_now(ctx),
             };
             left_incomplete.left_sig = my_sk.sign(left_incomplete.to_sign().as_bytes());
-            let complete = LinkClient(link.rpc_transport())
-                .sign_adjacency(left_incomplete)
+            let complete = timed(ctx, client.sign_adjacency(left_incomplete))
                 .await?
                 .context("remote refused to sign off")?;
             ctx.get(RELAY_GRAPH)
@@ -80,65 +202,264 @@ async fn sign_adjacency(
     Ok(())
 }
 
+/// Checks our wall clock against the neighbor's, returning whether they agree.
+async fn confirm_wall_clock(ctx: &DaemonContext, client: &LinkClient) -> anyhow::Result<bool> {
+    let info = timed(ctx, client.info()).await?;
+    Ok(info
+        .unix_time
+        .is_some_and(|theirs| clock::check_against_neighbor(ctx, theirs)))
+}
+
 // Step 3: Gossip the relay graph, by asking info about random nodes.
 #[tracing::instrument(skip_all)]
-async fn gossip_graph(ctx: &DaemonContext, link: &Link) -> anyhow::Result<()> {
+async fn gossip_graph(ctx: &DaemonContext, client: &LinkClient) -> anyhow::Result<()> {
     tracing::trace!("gossipping relay graph...");
     let all_known_nodes = ctx.get(RELAY_GRAPH).read().all_nodes().collect_vec();
     let random_sample = all_known_nodes
         .choose_multiple(&mut thread_rng(), 10.min(all_known_nodes.len()))
         .copied()
         .collect_vec();
-    let adjacencies = LinkClient(link.rpc_transport())
-        .adjacencies(random_sample)
-        .await?;
-    for adjacency in adjacencies {
-        let left_fp = adjacency.left;
-        let right_fp = adjacency.right;
-
-        static IDENTITY_CACHE: CtxField<Cache<RelayFingerprint, IdentityDescriptor>> = |_| {
-            CacheBuilder::default()
-                .time_to_live(Duration::from_secs(60))
-                .build()
-        };
-        let ourselves = ctx.get(MY_RELAY_IDENTITY);
-        let left_id = if ourselves.is_some() && ourselves.unwrap().public().fingerprint() == left_fp
-        {
-            None
-        } else if ctx.get(IDENTITY_CACHE).get(&left_fp).is_some() {
-            None
-        } else {
-            let val = LinkClient(link.rpc_transport())
-                .identity(left_fp)
-                .await?
-                .tap_some(|id| ctx.get(IDENTITY_CACHE).insert(left_fp, id.clone()));
-            val
-        };
+    let adjacencies = timed(ctx, client.adjacencies(random_sample)).await?;
+    for adjacency in bounded(ctx, adjacencies) {
+        learn_adjacency(ctx, client, adjacency).await?;
+    }
+    Ok(())
+}
 
-        let right_id =
-            if ourselves.is_some() && ourselves.unwrap().public().fingerprint() == right_fp {
-                None
-            } else if ctx.get(IDENTITY_CACHE).get(&right_fp).is_some() {
-                None
-            } else {
-                let val = LinkClient(link.rpc_transport())
-                    .identity(right_fp)
-                    .await?
-                    .tap_some(|id| ctx.get(IDENTITY_CACHE).insert(right_fp, id.clone()));
-                val
-            };
+/// Inserts an adjacency into the relay graph, fetching the identities on both sides of it if needed.
+async fn learn_adjacency(
+    ctx: &DaemonContext,
+    client: &LinkClient,
+    adjacency: AdjacencyDescriptor,
+) -> anyhow::Result<()> {
+    let left_fp = adjacency.left;
+    let right_fp = adjacency.right;
 
-        // fetch and insert the identities. we unconditionally do this since identity descriptors may change over time
-        if let Some(left_id) = left_id {
-            ctx.get(RELAY_GRAPH).write().insert_identity(left_id)?
+    let ourselves = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|identity| identity.public().fingerprint());
+    // identity descriptors may change over time, so we fetch them again once we forget verifying them
+    for fp in [left_fp, right_fp] {
+        if ourselves == Some(fp) || verified_lately(ctx, Verified::RelayIdentity(fp)) {
+            continue;
         }
+        if let Some(id) = timed(ctx, client.identity(fp)).await? {
+            ctx.get(RELAY_GRAPH).write().insert_identity(id)?;
+            remember_verified(ctx, Verified::RelayIdentity(fp));
+        }
+    }
 
-        if let Some(right_id) = right_id {
-            ctx.get(RELAY_GRAPH).write().insert_identity(right_id)?
+    // insert the adjacency
+    ctx.get(RELAY_GRAPH).write().insert_adjacency(adjacency)?;
+    Ok(())
+}
+
+/// How many hops out from the destination a route probe explores.
+const PROBE_MAX_DEPTH: usize = 3;
+/// How many relays a route probe asks about at each hop.
+const PROBE_MAX_WIDTH: usize = 10;
+
+/// Probes a neighbor for a route to `dest` after a routing miss, by walking outwards from `dest` through the adjacencies the neighbor knows about, until we can route to `dest` or the effort bound is hit.
+#[tracing::instrument(skip(ctx, client))]
+pub async fn probe_toward(
+    ctx: &DaemonContext,
+    client: &LinkClient,
+    dest: RelayFingerprint,
+) -> anyhow::Result<()> {
+    let mut seen = HashSet::from([dest]);
+    let mut frontier = vec![dest];
+    for _ in 0..PROBE_MAX_DEPTH {
+        if frontier.is_empty() {
+            break;
+        }
+        let adjacencies = timed(ctx, client.adjacencies(frontier)).await?;
+        frontier = vec![];
+        for adjacency in bounded(ctx, adjacencies) {
+            for fp in [adjacency.left, adjacency.right] {
+                if frontier.len() < PROBE_MAX_WIDTH && seen.insert(fp) {
+                    frontier.push(fp);
+                }
+            }
+            learn_adjacency(ctx, client, adjacency).await?;
+        }
+        if network::route_learned(ctx, dest) {
+            tracing::debug!("route probe succeeded");
+            return Ok(());
         }
+    }
+    anyhow::bail!("neighbor does not know a route to {dest}")
+}
 
-        // insert the adjacency
-        ctx.get(RELAY_GRAPH).write().insert_adjacency(adjacency)?
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU8, Ordering},
+            Arc,
+        },
+        time::Instant,
+    };
+
+    use async_trait::async_trait;
+    use futures::AsyncReadExt as _;
+    use nanorpc::{RpcService, ServerError};
+    use picomux::PicoMux;
+    use serde_json::{json, Value};
+    use smol::future::FutureExt as _;
+
+    use super::*;
+    use crate::{
+        daemon::{
+            inout_route::{link_protocol::LinkService, link_protocol_impl::LinkProtocolImpl},
+            link::{Link, LinkMessage},
+        },
+        network::NackReason,
+    };
+
+    const ANSWERING: u8 = 0;
+    const STUCK: u8 = 1;
+    const STUFFED: u8 = 2;
+
+    /// Answers the way a neighbor would, except for `adjacencies`, which it never answers while [STUCK], and answers with far too much while [STUFFED].
+    struct SlowNeighbor {
+        service: LinkService<LinkProtocolImpl>,
+        mode: Arc<AtomicU8>,
+    }
+
+    #[async_trait]
+    impl RpcService for SlowNeighbor {
+        async fn respond(
+            &self,
+            method: &str,
+            params: Vec<Value>,
+        ) -> Option<Result<Value, ServerError>> {
+            if method == "adjacencies" {
+                match self.mode.load(Ordering::SeqCst) {
+                    STUCK => smol::future::pending::<()>().await,
+                    STUFFED => return Some(Ok(Value::String("x".repeat(2 * MAX_RPC_LINE)))),
+                    _ => {}
+                }
+            }
+            self.service.respond(method, params).await
+        }
+    }
+
+    /// Both ends of a link over a real TCP connection.
+    async fn link_pair() -> (Link, Link) {
+        let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialed, accepted) =
+            futures::future::join(smol::net::TcpStream::connect(addr), listener.accept()).await;
+        let (read, write) = dialed.unwrap().split();
+        let near = PicoMux::new(read, write);
+        let (read, write) = accepted.unwrap().0.split();
+        let far = PicoMux::new(read, write);
+        let (near, far) = futures::future::join(Link::new_dial(near), Link::new_listen(far)).await;
+        (near.unwrap(), far.unwrap())
+    }
+
+    #[test]
+    fn stuck_neighbor_fails_the_round_without_blocking_the_link() {
+        let us = DaemonContext::new(
+            serde_json::from_value(json!({ "rpc_timeouts": { "gossip_ms": 200 } })).unwrap(),
+        );
+        let neighbor = DaemonContext::new(
+            serde_json::from_value(json!({ "identity_seed": "slow gossip" })).unwrap(),
+        );
+        let mode = Arc::new(AtomicU8::new(STUCK));
+        let service = SlowNeighbor {
+            service: LinkService(LinkProtocolImpl {
+                ctx: neighbor,
+                remote_client_id: 0,
+                remote_relay_fp: None,
+                sent_packets: Default::default(),
+            }),
+            mode: mode.clone(),
+        };
+
+        smolscale::block_on(async {
+            let (near, far) = link_pair().await;
+            let client = LinkClient(near.rpc_transport());
+            let neighbor_side = async {
+                far.rpc_serve(service).await.unwrap();
+                unreachable!()
+            };
+            let our_side = async {
+                // the round gives up on the stuck call, while other calls and messages on the same link still go through
+                let start = Instant::now();
+                let (went_well, info, sent) = futures::future::join3(
+                    gossip_round(&us, &client, None, "slow"),
+                    client.info(),
+                    near.send_msg(LinkMessage::Nack {
+                        tag: 1,
+                        reason: NackReason::Policy,
+                    }),
+                )
+                .await;
+                assert!(!went_well);
+                assert!(info.is_ok());
+                sent.unwrap();
+                assert!(matches!(
+                    far.recv_msg().await.unwrap(),
+                    LinkMessage::Nack { tag: 1, .. }
+                ));
+                assert!(start.elapsed() < Duration::from_secs(2));
+                assert_eq!(gossip_health(&us)["slow"], 0.0);
+                let stats = us.get(STATS).snapshot();
+                assert_eq!(stats.get(GOSSIP_TIMED_OUT), Some(&1));
+                assert_eq!(stats.get(GOSSIP_ROUND_FAILED), Some(&1));
+
+                // a reply stuffed past the size limit is refused before it's parsed, and fails the round too
+                mode.store(STUFFED, Ordering::SeqCst);
+                assert!(!gossip_round(&us, &client, None, "slow").await);
+                assert_eq!(us.get(STATS).snapshot().get(GOSSIP_ROUND_FAILED), Some(&2));
+
+                // once the neighbor answers again, gossip recovers
+                mode.store(ANSWERING, Ordering::SeqCst);
+                assert!(gossip_round(&us, &client, None, "slow").await);
+                let health = gossip_health(&us)["slow"];
+                assert!(health > 0.0 && health < 1.0);
+            };
+            neighbor_side.race(our_side).await
+        });
+    }
+
+    #[test]
+    fn rounds_have_an_overall_limit() {
+        let us = DaemonContext::new(
+            serde_json::from_value(
+                json!({ "rpc_timeouts": { "gossip_ms": 10_000, "gossip_round_ms": 200 } }),
+            )
+            .unwrap(),
+        );
+        let neighbor = DaemonContext::new(
+            serde_json::from_value(json!({ "identity_seed": "slow round" })).unwrap(),
+        );
+        let service = SlowNeighbor {
+            service: LinkService(LinkProtocolImpl {
+                ctx: neighbor,
+                remote_client_id: 0,
+                remote_relay_fp: None,
+                sent_packets: Default::default(),
+            }),
+            mode: Arc::new(AtomicU8::new(STUCK)),
+        };
+
+        smolscale::block_on(async {
+            let (near, far) = link_pair().await;
+            let client = LinkClient(near.rpc_transport());
+            let neighbor_side = async {
+                far.rpc_serve(service).await.unwrap();
+                unreachable!()
+            };
+            let our_side = async {
+                // no single call times out, but the round as a whole does
+                let start = Instant::now();
+                assert!(!gossip_round(&us, &client, None, "slow").await);
+                assert!(start.elapsed() < Duration::from_secs(2));
+                assert_eq!(us.get(STATS).snapshot().get(GOSSIP_TIMED_OUT), Some(&1));
+            };
+            neighbor_side.race(our_side).await
+        });
     }
-    Ok(())
 }
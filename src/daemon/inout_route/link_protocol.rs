@@ -7,7 +7,11 @@ use nanorpc::nanorpc_derive;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 
-use crate::settlement::{Seed, SettlementRequest, SettlementResponse};
+use crate::{
+    daemon::IdentityRotation,
+    network::DropReports,
+    settlement::{Seed, SettlementRequest, SettlementResponse},
+};
 
 #[nanorpc_derive]
 #[async_trait]
@@ -15,6 +19,9 @@ pub trait LinkProtocol {
     /// A method that returns some random info. Used for keepalive and statistics.
     async fn info(&self) -> InfoResponse;
 
+    /// Echoes the nonce back. Used to probe whether the other end is still alive.
+    async fn ping(&self, nonce: u64) -> u64;
+
     /// Asks the other end to complete an adjacency descriptor. Returns None to indicate refusal. This is called by the "left-hand" neighbor to ask the "right-hand" neighbor to sign.
     async fn sign_adjacency(
         &self,
@@ -30,11 +37,26 @@ pub trait LinkProtocol {
     /// Sends a settlement request and waits until a response is received or the call times out.
     async fn start_settlement(&self, req: SettlementRequest) -> Option<SettlementResponse>;
 
-    /// Send a chat message to the other end of the link.
+    /// Send a chat message to the other end of the link. Only for neighbors too old to have [LinkProtocol::push_chat_once], since a retry of a push whose response got lost shows up twice.
     async fn push_chat(&self, msg: String);
 
+    /// Send a chat message to the other end of the link. Retries of the same message must reuse its nonce, so that it only shows up once.
+    async fn push_chat_once(&self, nonce: u64, msg: String);
+
     /// Request a MelPoW seed (used to create an automatic payment proof).
     async fn request_seed(&self) -> Option<Seed>;
+
+    /// Asks a relay to report why it drops our packets, returning false if it won't.
+    async fn request_drop_reports(&self) -> bool;
+
+    /// Reports to a client that asked for it which of its packets we dropped, and why.
+    async fn drop_reports(&self, reports: DropReports);
+
+    /// How much the caller owes us by our ledger, or None if we have no balance with it. Only for noticing when the two ledgers disagree, never for accounting.
+    async fn balance_hint(&self) -> Option<i128>;
+
+    /// The rotation from the identity the caller may have pinned to the one we go by now, if we rotated recently enough.
+    async fn identity_rotation(&self) -> Option<IdentityRotation>;
 }
 
 /// Response to an authentication challenge.
@@ -51,4 +73,7 @@ pub struct AuthResponse {
 #[derive(Serialize, Deserialize)]
 pub struct InfoResponse {
     pub version: String,
+    /// The responder's wall-clock time, in seconds since the Unix epoch. Older neighbors don't say.
+    #[serde(default)]
+    pub unix_time: Option<u64>,
 }
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 
 use earendil_crypt::{ClientId, RelayFingerprint};
@@ -5,12 +7,19 @@ use earendil_crypt::{ClientId, RelayFingerprint};
 use earendil_topology::{AdjacencyDescriptor, IdentityDescriptor};
 
 use itertools::Itertools;
+use smol_timeout::TimeoutExt;
 
-use crate::daemon::chat::{ChatEntry, CHATS};
-use crate::settlement::{Seed, SettlementRequest, SettlementResponse};
+use crate::daemon::{
+    chat::{ChatEntry, CHATS},
+    identity_refresh, identity_rotation, IdentityRotation,
+};
+use crate::settlement::{
+    Seed, SettlementRequest, SettlementResponse, SETTLEMENTS, SETTLEMENT_WAIT,
+};
 use crate::{
-    context::{DaemonContext, MY_RELAY_IDENTITY, RELAY_GRAPH},
-    network::is_relay_neigh,
+    clock,
+    context::{DaemonContext, DEBTS, MY_RELAY_IDENTITY, RELAY_GRAPH},
+    network::{self, is_relay_neigh, DropReports, SentPackets},
 };
 
 use super::link_protocol::{InfoResponse, LinkProtocol};
@@ -22,6 +31,18 @@ pub struct LinkProtocolImpl {
 
     pub remote_client_id: ClientId,
     pub remote_relay_fp: Option<RelayFingerprint>,
+    /// What we sent over this link that drop reports may be about.
+    pub sent_packets: Arc<SentPackets>,
+}
+
+impl LinkProtocolImpl {
+    /// Who chats pushed over this link are from.
+    fn chat_neighbor(&self) -> either::Either<ClientId, RelayFingerprint> {
+        match self.remote_relay_fp {
+            Some(fingerprint) => either::Right(fingerprint),
+            None => either::Left(self.remote_client_id),
+        }
+    }
 }
 
 #[async_trait]
@@ -29,18 +50,24 @@ impl LinkProtocol for LinkProtocolImpl {
     async fn info(&self) -> InfoResponse {
         InfoResponse {
             version: env!("CARGO_PKG_VERSION").to_string(),
+            unix_time: Some(clock::unix_now(&self.ctx)),
         }
     }
 
+    async fn ping(&self, nonce: u64) -> u64 {
+        nonce
+    }
+
     #[tracing::instrument(skip(self))]
     async fn sign_adjacency(
         &self,
         mut left_incomplete: AdjacencyDescriptor,
     ) -> Option<AdjacencyDescriptor> {
-        let my_sk = self
-            .ctx
-            .get(MY_RELAY_IDENTITY)
-            .expect("only relays have global identities");
+        // neighbors may ask clients too, who have nothing to sign with
+        let Some(my_sk) = *self.ctx.get(MY_RELAY_IDENTITY) else {
+            tracing::debug!("refusing to sign an adjacency as a client");
+            return None;
+        };
         let my_fp = my_sk.public().fingerprint();
         // This must be a neighbor that is "left" of us
         let valid = left_incomplete.left < left_incomplete.right
@@ -67,7 +94,16 @@ impl LinkProtocol for LinkProtocolImpl {
     }
 
     async fn identity(&self, fp: RelayFingerprint) -> Option<IdentityDescriptor> {
-        self.ctx.get(RELAY_GRAPH).read().identity(&fp)
+        let descr = self.ctx.get(RELAY_GRAPH).read().identity(&fp)?;
+        // a relay neighbor that fetches our own descriptor passes it on to the rest of the network
+        let mine = self
+            .ctx
+            .get(MY_RELAY_IDENTITY)
+            .is_some_and(|id| id.public().fingerprint() == fp);
+        if mine && self.remote_relay_fp.is_some() {
+            identity_refresh::gossiped(&self.ctx, &descr);
+        }
+        Some(descr)
     }
 
     #[tracing::instrument(skip(self))]
@@ -80,50 +116,53 @@ impl LinkProtocol for LinkProtocolImpl {
     }
 
     #[tracing::instrument(skip(self))]
-    async fn start_settlement(&self, _req: SettlementRequest) -> Option<SettlementResponse> {
-        todo!()
-        // let settlements = self.ctx.get(SETTLEMENTS);
-
-        // match req.payment_proof {
-        //     SettlementProof::Automatic(_) => {
-        //         tracing::debug!("handling auto_settlement req: {:?}", req);
-        //         if let Ok(res) = settlements.verify_auto_settle(&self.ctx, req) {
-        //             res
-        //         } else {
-        //             None
-        //         }
-        //     }
-        //     SettlementProof::Manual => {
-        //         tracing::debug!("handling manual settlement req: {:?}", req);
-        //         let recv_res = settlements.insert_pending(req);
-
-        //         if let Ok(recv_res) = recv_res {
-        //             match recv_res.recv().timeout(Duration::from_secs(300)).await {
-        //                 Some(Ok(res)) => res,
-        //                 Some(Err(e)) => {
-        //                     log::warn!("settlement response receive error: {e}");
-        //                     None
-        //                 }
-        //                 None => None,
-        //             }
-        //         } else {
-        //             None
-        //         }
-        //     }
-        // }
+    async fn start_settlement(&self, req: SettlementRequest) -> Option<SettlementResponse> {
+        // only the relay at the other end of this link may settle its own debts over it
+        let neighbor = self.remote_relay_fp?;
+        if req.initiator() != neighbor {
+            tracing::warn!(
+                neighbor = display(neighbor),
+                "refusing a settlement for another relay"
+            );
+            return None;
+        }
+        let settlements = self.ctx.get(SETTLEMENTS);
+        let recv_res = match settlements.insert_pending(req) {
+            Ok(recv_res) => recv_res,
+            Err(err) => {
+                // automatic settlements aren't supported yet either
+                tracing::debug!(err = debug(err), "refusing a settlement request");
+                return None;
+            }
+        };
+        tracing::info!(
+            neighbor = display(neighbor),
+            "neighbor asks to settle, waiting for the operator to accept or reject it"
+        );
+        match recv_res.recv().timeout(SETTLEMENT_WAIT).await {
+            Some(Ok(res)) => res,
+            _ => {
+                settlements.forget_pending(&neighbor);
+                None
+            }
+        }
     }
 
     #[tracing::instrument(skip(self))]
     async fn push_chat(&self, msg: String) {
-        if let Some(fingerprint) = self.remote_relay_fp {
-            self.ctx
-                .get(CHATS)
-                .record(either::Right(fingerprint), ChatEntry::new_incoming(msg));
-        } else {
-            self.ctx.get(CHATS).record(
-                either::Left(self.remote_client_id),
-                ChatEntry::new_incoming(msg),
-            );
+        self.ctx
+            .get(CHATS)
+            .record(self.chat_neighbor(), ChatEntry::new_incoming(msg));
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn push_chat_once(&self, nonce: u64, msg: String) {
+        if !self
+            .ctx
+            .get(CHATS)
+            .record_incoming(self.chat_neighbor(), nonce, msg)
+        {
+            tracing::debug!(nonce, "dropping a chat we already have");
         }
     }
 
@@ -147,4 +186,40 @@ impl LinkProtocol for LinkProtocolImpl {
         //     }
         // }
     }
+
+    #[tracing::instrument(skip(self))]
+    async fn request_drop_reports(&self) -> bool {
+        // relays hand us mostly other people's packets, so only clients get reports
+        if self.remote_relay_fp.is_some() {
+            return false;
+        }
+        network::enable_drop_reports(&self.ctx, self.remote_client_id)
+    }
+
+    #[tracing::instrument(skip(self, reports))]
+    async fn drop_reports(&self, reports: DropReports) {
+        let Some(relay) = self.remote_relay_fp else {
+            tracing::debug!("ignoring drop reports from a client");
+            return;
+        };
+        network::incoming_drop_reports(&self.ctx, relay, reports, &self.sent_packets);
+    }
+
+    async fn balance_hint(&self) -> Option<i128> {
+        let debts = self.ctx.get(DEBTS);
+        match self.remote_relay_fp {
+            Some(fingerprint) => debts.relay_net_debt_est(&fingerprint),
+            None => debts.client_net_debt_est(&self.remote_client_id),
+        }
+    }
+
+    async fn identity_rotation(&self) -> Option<IdentityRotation> {
+        match identity_rotation::current_rotation(&self.ctx).await {
+            Ok(rotation) => rotation,
+            Err(err) => {
+                tracing::warn!(err = debug(err), "could not read our identity rotation");
+                None
+            }
+        }
+    }
 }
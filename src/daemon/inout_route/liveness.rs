@@ -0,0 +1,412 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use nanorpc::RpcTransport;
+use serde_json::Value;
+use smol_timeout::TimeoutExt;
+
+use super::{LINK_RTT, LINK_RTT_SECONDS};
+use crate::{
+    clock, config::LivenessConfig, context::DaemonContext, daemon::link::Link, stats::STATS,
+};
+
+pub const LINK_DECLARED_DEAD: &str = "link.declared_dead";
+
+/// How often a neighbor is probed while its silence is forgiven, so that we find out as soon as it's back.
+const GRACE_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Probes the neighbor at the other end of the link until it's declared dead, recording the link's round-trip time from every probe it echoes. Never returns unless `max_failed_probes` is set.
+pub async fn watch_neighbor(ctx: &DaemonContext, link: &Link, neighbor: &str) -> anyhow::Error {
+    scopeguard::defer!({
+        ctx.get(LINK_RTT).remove(neighbor);
+    });
+    probe_until_dead(
+        ctx.init().liveness,
+        || clock::in_jump_grace(ctx),
+        || async {
+            let start = Instant::now();
+            ping_neighbor(link).await?;
+            ctx.get(LINK_RTT)
+                .insert(neighbor.to_string(), start.elapsed());
+            ctx.get(STATS).observe(LINK_RTT_SECONDS, start.elapsed());
+            Ok(())
+        },
+    )
+    .await
+}
+
+/// Probes a neighbor every `probe_interval_ms` until `max_failed_probes` probes in a row go unanswered, then returns why it's considered dead. Any answer starts the count over, even an error: only silence says that the neighbor is gone.
+///
+/// Silence doesn't count while `in_grace` says so, such as right after the clock jumped, when the neighbor may only have been as unreachable as we were. The neighbor is probed more often instead.
+pub async fn probe_until_dead<F, Fut>(
+    cfg: LivenessConfig,
+    in_grace: impl Fn() -> bool,
+    mut probe: F,
+) -> anyhow::Error
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut failed = 0;
+    loop {
+        match probe().timeout(cfg.probe_timeout()).await {
+            Some(Ok(())) => failed = 0,
+            Some(Err(err)) => {
+                tracing::debug!(err = debug(err), "liveness probe got an unreadable answer");
+                failed = 0;
+            }
+            None if in_grace() => {
+                failed = 0;
+                smol::Timer::after(GRACE_PROBE_INTERVAL.min(cfg.probe_interval())).await;
+                continue;
+            }
+            None => failed += 1,
+        }
+        if cfg.max_failed_probes.is_some_and(|max| failed >= max) {
+            return anyhow::anyhow!("{failed} liveness probes in a row went unanswered");
+        }
+        smol::Timer::after(cfg.probe_interval()).await;
+    }
+}
+
+/// Pings the neighbor at the other end of the link, failing if the link broke or the neighbor echoed something other than the nonce.
+pub async fn ping_neighbor(link: &Link) -> anyhow::Result<()> {
+    let nonce: u64 = rand::random();
+    match link
+        .rpc_transport()
+        .call("ping", &[Value::from(nonce)])
+        .await?
+    {
+        Some(Ok(echo)) if echo.as_u64() == Some(nonce) => Ok(()),
+        Some(Ok(_)) => anyhow::bail!("neighbor echoed the wrong nonce"),
+        // neighbors that predate pings don't know the method, but still answered
+        None | Some(Err(_)) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
+        Arc,
+    };
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+    use nanorpc::{RpcService, ServerError};
+    use picomux::PicoMux;
+    use serde_json::json;
+    use smol::{
+        future::FutureExt as _,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::daemon::{
+        inout_route::{link_protocol::LinkService, link_protocol_impl::LinkProtocolImpl, link_rtt},
+        link::LinkMessage,
+    };
+
+    fn cfg() -> LivenessConfig {
+        LivenessConfig {
+            probe_interval_ms: 50,
+            probe_timeout_ms: 100,
+            max_failed_probes: Some(3),
+        }
+    }
+
+    #[test]
+    fn unanswered_probes_declare_death_within_the_window() {
+        let answering = Arc::new(AtomicBool::new(true));
+        let probes = Arc::new(AtomicU32::new(0));
+        let probe = {
+            let (answering, probes) = (answering.clone(), probes.clone());
+            move || {
+                let answering = answering.load(Ordering::SeqCst);
+                probes.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if !answering {
+                        smol::future::pending::<()>().await;
+                    }
+                    Ok(())
+                }
+            }
+        };
+        smol::future::block_on(async {
+            let dead = smol::spawn(probe_until_dead(cfg(), || false, probe));
+            smol::Timer::after(Duration::from_millis(300)).await;
+            assert!(probes.load(Ordering::SeqCst) >= 3);
+
+            answering.store(false, Ordering::SeqCst);
+            let stopped = Instant::now();
+            let err = dead
+                .timeout(cfg().detection_window().unwrap() + Duration::from_millis(200))
+                .await
+                .expect("the neighbor was never declared dead");
+            assert!(stopped.elapsed() >= cfg().probe_timeout() * 3);
+            assert!(err.to_string().contains("3 liveness probes"));
+        });
+    }
+
+    #[test]
+    fn without_a_limit_silence_is_never_death() {
+        let cfg = LivenessConfig {
+            max_failed_probes: None,
+            ..cfg()
+        };
+        let probe = || smol::future::pending::<anyhow::Result<()>>();
+        let dead = smol::future::block_on(
+            probe_until_dead(cfg, || false, probe).timeout(Duration::from_secs(2)),
+        );
+        assert!(dead.is_none());
+    }
+
+    #[test]
+    fn errors_are_answers() {
+        let probe = || async { anyhow::bail!("garbled") };
+        let dead = smol::future::block_on(
+            probe_until_dead(cfg(), || false, probe).timeout(Duration::from_secs(2)),
+        );
+        assert!(dead.is_none());
+    }
+
+    #[test]
+    fn occasional_failures_are_forgiven() {
+        let probes = AtomicU32::new(0);
+        let probe = || {
+            let n = probes.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if n % 3 != 2 {
+                    smol::future::pending::<()>().await;
+                }
+                Ok(())
+            }
+        };
+        let dead = smol::future::block_on(
+            probe_until_dead(cfg(), || false, probe).timeout(cfg().detection_window().unwrap() * 2),
+        );
+        assert!(dead.is_none());
+    }
+
+    #[test]
+    fn failures_right_after_a_clock_jump_are_forgiven() {
+        let cfg = LivenessConfig {
+            probe_interval_ms: 1500,
+            ..cfg()
+        };
+        let in_grace = Arc::new(AtomicBool::new(true));
+        let probes = Arc::new(AtomicU32::new(0));
+        let probe = {
+            let probes = probes.clone();
+            move || {
+                probes.fetch_add(1, Ordering::SeqCst);
+                smol::future::pending::<anyhow::Result<()>>()
+            }
+        };
+        smol::future::block_on(async {
+            let dead = smol::spawn(probe_until_dead(
+                cfg,
+                {
+                    let in_grace = in_grace.clone();
+                    move || in_grace.load(Ordering::SeqCst)
+                },
+                probe,
+            ));
+            // neither dead within the usual window, nor waiting out the usual interval to probe again
+            smol::Timer::after(cfg.detection_window().unwrap() + Duration::from_millis(500)).await;
+            assert!(!dead.is_finished());
+            assert!(probes.load(Ordering::SeqCst) > cfg.max_failed_probes.unwrap());
+
+            in_grace.store(false, Ordering::SeqCst);
+            dead.timeout(cfg.detection_window().unwrap() + GRACE_PROBE_INTERVAL * 2)
+                .await
+                .expect("the neighbor was never declared dead after the grace");
+        });
+    }
+
+    const ANSWERING: u8 = 0;
+    const GARBLED: u8 = 1;
+    const SILENT: u8 = 2;
+
+    /// Answers the way a neighbor would while [ANSWERING], answers pings with garbage while [GARBLED], and never answers them while [SILENT].
+    struct Neighbor {
+        service: LinkService<LinkProtocolImpl>,
+        mode: Arc<AtomicU8>,
+    }
+
+    #[async_trait]
+    impl RpcService for Neighbor {
+        async fn respond(
+            &self,
+            method: &str,
+            params: Vec<Value>,
+        ) -> Option<Result<Value, ServerError>> {
+            if method == "ping" {
+                match self.mode.load(Ordering::SeqCst) {
+                    GARBLED => return Some(Ok(json!("garbage"))),
+                    SILENT => smol::future::pending::<()>().await,
+                    _ => {}
+                }
+            }
+            self.service.respond(method, params).await
+        }
+    }
+
+    fn neighbor(mode: Arc<AtomicU8>) -> Neighbor {
+        Neighbor {
+            service: LinkService(LinkProtocolImpl {
+                ctx: DaemonContext::new(serde_json::from_value(json!({})).unwrap()),
+                remote_client_id: 0,
+                remote_relay_fp: None,
+                sent_packets: Default::default(),
+            }),
+            mode,
+        }
+    }
+
+    fn us() -> DaemonContext {
+        DaemonContext::new(
+            serde_json::from_value(json!({
+                "liveness": { "probe_interval_ms": 100, "probe_timeout_ms": 1000, "max_failed_probes": 3 },
+            }))
+            .unwrap(),
+        )
+    }
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialed, accepted) =
+            futures::future::join(TcpStream::connect(addr), listener.accept()).await;
+        (dialed.unwrap(), accepted.unwrap().0)
+    }
+
+    /// Passes along whatever it reads, each piece arriving `latency` after it was read, like a long physical link.
+    async fn delay_line(
+        mut from: impl AsyncRead + Unpin,
+        mut to: impl AsyncWrite + Unpin,
+        latency: Duration,
+    ) {
+        let (send, recv) = smol::channel::unbounded::<(Instant, Vec<u8>)>();
+        let read = async move {
+            let mut buf = vec![0u8; 65536];
+            while let Ok(n @ 1..) = from.read(&mut buf).await {
+                if send
+                    .send((Instant::now() + latency, buf[..n].to_vec()))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        };
+        let write = async move {
+            while let Ok((at, piece)) = recv.recv().await {
+                smol::Timer::at(at).await;
+                if to.write_all(&piece).await.is_err() {
+                    break;
+                }
+            }
+        };
+        futures::future::join(read, write).await;
+    }
+
+    /// Both ends of a link whose bytes take `latency` to get across either way, and the tasks that carry them.
+    async fn slow_link_pair(latency: Duration) -> (Link, Link, [smol::Task<()>; 2]) {
+        let (near_socket, near_line) = tcp_pair().await;
+        let (far_line, far_socket) = tcp_pair().await;
+        let lines = [
+            smol::spawn(delay_line(near_line.clone(), far_line.clone(), latency)),
+            smol::spawn(delay_line(far_line, near_line, latency)),
+        ];
+        let near = PicoMux::new(near_socket.clone(), near_socket);
+        let far = PicoMux::new(far_socket.clone(), far_socket);
+        let (near, far) = futures::future::join(Link::new_dial(near), Link::new_listen(far)).await;
+        (near.unwrap(), far.unwrap(), lines)
+    }
+
+    #[test]
+    fn busy_long_link_keeps_answering_probes() {
+        const LATENCY: Duration = Duration::from_millis(50);
+        const BULK_BYTES: usize = 4_000_000;
+
+        let us = us();
+        let mode = Arc::new(AtomicU8::new(ANSWERING));
+        smolscale::block_on(async {
+            let (near, far, _lines) = slow_link_pair(LATENCY).await;
+            let neighbor_side = async {
+                far.rpc_serve(neighbor(mode.clone())).await.unwrap();
+                unreachable!()
+            };
+            let probes = async {
+                let err = watch_neighbor(&us, &near, "far").await;
+                panic!("a busy neighbor was declared dead: {err}")
+            };
+            // as much as the link takes, while probes share it
+            let start = Instant::now();
+            let bulk = async {
+                let send = async {
+                    for rb_id in 0.. {
+                        near.send_msg(LinkMessage::ToClient {
+                            body: Bytes::from(vec![0u8; 1000]),
+                            rb_id,
+                        })
+                        .await
+                        .unwrap();
+                    }
+                };
+                let recv = async {
+                    let mut received = 0;
+                    while received < BULK_BYTES {
+                        if let LinkMessage::ToClient { body, .. } = far.recv_msg().await.unwrap() {
+                            received += body.len();
+                        }
+                    }
+                };
+                recv.race(send).await;
+                let elapsed = start.elapsed();
+                let throughput = BULK_BYTES as f64 / elapsed.as_secs_f64();
+                assert!(
+                    elapsed < Duration::from_secs(30),
+                    "only {throughput:.0} bytes/sec got across"
+                );
+                // the probes got answered all along, through the same queues as the bulk
+                let rtt = link_rtt(&us, "far").expect("no probe was answered");
+                assert!(rtt >= 2 * LATENCY, "rtt: {rtt:?}");
+            };
+            bulk.race(neighbor_side).race(probes).await;
+        });
+    }
+
+    #[test]
+    fn only_silence_drops_the_link() {
+        let us = us();
+        let mode = Arc::new(AtomicU8::new(GARBLED));
+        smolscale::block_on(async {
+            let (near, far, _lines) = slow_link_pair(Duration::ZERO).await;
+            let neighbor_side = async {
+                far.rpc_serve(neighbor(mode.clone())).await.unwrap();
+                unreachable!()
+            };
+            let ours = async {
+                let probes = watch_neighbor(&us, &near, "far");
+                // answers we can't read still show that the neighbor is there
+                let survived = async {
+                    smol::Timer::after(Duration::from_secs(2)).await;
+                    mode.store(SILENT, Ordering::SeqCst);
+                    smol::future::pending::<anyhow::Error>().await
+                };
+                let silenced = Instant::now() + Duration::from_secs(2);
+                let err = probes.race(survived).await;
+                assert!(err.to_string().contains("went unanswered"));
+                assert!(Instant::now() > silenced);
+                // three probes in a row, each waited on for a second
+                assert!(silenced.elapsed() < Duration::from_secs(4));
+            };
+            ours.race(neighbor_side).await;
+        });
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use dashmap::DashMap;
+use earendil_crypt::RelayIdentitySecret;
+use futures::{AsyncRead, AsyncWrite};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    Connection, Endpoint, EndpointConfig, Incoming, RecvStream, SendStream, SmolRuntime,
+    TransportConfig,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{
+        pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+    },
+    DigitallySignedStruct, SignatureScheme,
+};
+use sillad::Pipe;
+
+use crate::{
+    config::{QuicConnectConfig, QuicListenConfig},
+    context::{CtxField, DaemonContext},
+};
+
+/*
+A QUIC link is one bidirectional stream, carrying the mux just like a TCP connection does. We don't use datagrams: the mux expects a reliable, ordered byte stream, and n2r already tolerates loss above it.
+
+What QUIC buys us over TCP is at the connection level. A client whose address changes keeps its connection, and so its link, without authenticating again. Reconnecting resumes the TLS session, skipping the certificate exchange. We never send 0-RTT data, since the first thing on a link is its authentication, and 0-RTT data can be replayed.
+*/
+
+/// The name servers present and clients ask for. Certificates are pinned by fingerprint, so it's never checked against anything.
+const SERVER_NAME: &str = "earendil";
+
+const ALPN: &[u8] = b"earendil";
+
+/// How often an idle QUIC link sends something, so that NATs keep its mapping and a dead path is noticed quickly.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a QUIC link may go without hearing from the other side before it's dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The DER prefix of a PKCS#8 v1 Ed25519 private key, which the 32-byte seed follows.
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// The endpoint each QUIC out-route dials from, and the config it was made for, by route name. It's kept across reconnects, so that they resume the last TLS session.
+static OUT_ENDPOINTS: CtxField<DashMap<String, (QuicConnectConfig, Endpoint)>> = |_| DashMap::new();
+
+/// A QUIC connection's one stream, as a pipe.
+pub struct QuicPipe {
+    send: SendStream,
+    recv: RecvStream,
+    remote_addr: String,
+    // closing the connection would reset the streams
+    _conn: Connection,
+}
+
+impl QuicPipe {
+    fn new(conn: Connection, send: SendStream, recv: RecvStream) -> Self {
+        Self {
+            send,
+            recv,
+            remote_addr: conn.remote_address().to_string(),
+            _conn: conn,
+        }
+    }
+}
+
+impl AsyncRead for QuicPipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicPipe {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_close(cx)
+    }
+}
+
+impl Pipe for QuicPipe {
+    fn protocol(&self) -> &str {
+        "quic"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.remote_addr)
+    }
+}
+
+/// Listens for QUIC links on a UDP socket.
+pub struct QuicListener {
+    endpoint: Endpoint,
+    fingerprint: [u8; 32],
+}
+
+impl QuicListener {
+    pub fn bind(
+        identity: Option<&RelayIdentitySecret>,
+        listen: SocketAddr,
+        cfg: &QuicListenConfig,
+    ) -> anyhow::Result<Self> {
+        let (cert, key) = certificate(identity, cfg)?;
+        let fingerprint = fingerprint(&cert);
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut server_config =
+            quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+        server_config
+            .transport_config(transport_config()?)
+            .migration(true);
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(server_config),
+            UdpSocket::bind(listen)?,
+            Arc::new(SmolRuntime),
+        )?;
+        Ok(Self {
+            endpoint,
+            fingerprint,
+        })
+    }
+
+    /// The fingerprint of the certificate we present, which out-routes to us pin.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        self.fingerprint
+    }
+
+    /// Waits for a client to start connecting. Finish with [accept_pipe], which takes a round trip, so that slow clients don't hold up the next one.
+    pub async fn accept(&self) -> anyhow::Result<Incoming> {
+        self.endpoint
+            .accept()
+            .await
+            .context("QUIC endpoint was closed")
+    }
+}
+
+/// Finishes accepting a QUIC link, once the client opens its stream.
+pub async fn accept_pipe(incoming: Incoming) -> anyhow::Result<QuicPipe> {
+    let conn = incoming.await?;
+    let (send, recv) = conn.accept_bi().await?;
+    Ok(QuicPipe::new(conn, send, recv))
+}
+
+/// Dials a QUIC link from the endpoint kept for the named out-route, or from a throwaway one without a name.
+pub async fn dial(
+    ctx: &DaemonContext,
+    route_name: Option<&str>,
+    dest: SocketAddr,
+    cfg: &QuicConnectConfig,
+) -> anyhow::Result<QuicPipe> {
+    let endpoint = match route_name {
+        Some(name) => {
+            let endpoints = ctx.get(OUT_ENDPOINTS);
+            match endpoints.get(name).filter(|entry| &entry.0 == cfg) {
+                Some(entry) => entry.1.clone(),
+                None => {
+                    let endpoint = client_endpoint(dest, cfg)?;
+                    endpoints.insert(name.to_string(), (cfg.clone(), endpoint.clone()));
+                    endpoint
+                }
+            }
+        }
+        None => client_endpoint(dest, cfg)?,
+    };
+    let conn = endpoint.connect(dest, SERVER_NAME)?.await?;
+    let (send, recv) = conn.open_bi().await?;
+    Ok(QuicPipe::new(conn, send, recv))
+}
+
+/// Moves every QUIC out-route onto a fresh UDP socket. Their links carry on from the new address, as if our address had changed.
+pub fn rebind_out_routes(ctx: &DaemonContext) -> anyhow::Result<()> {
+    for entry in ctx.get(OUT_ENDPOINTS).iter() {
+        let endpoint = &entry.value().1;
+        let local = endpoint.local_addr()?;
+        endpoint.rebind(UdpSocket::bind(any_port(local))?)?;
+        tracing::debug!(
+            route = entry.key().as_str(),
+            old = display(local),
+            new = display(endpoint.local_addr()?),
+            "rebound QUIC out-route"
+        );
+    }
+    Ok(())
+}
+
+/// Any port on any address of the same family as `addr`.
+fn any_port(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    }
+}
+
+/// An endpoint to dial `dest` from. Its dials share one TLS client config, which keeps the tickets that let later ones resume earlier sessions.
+fn client_endpoint(dest: SocketAddr, cfg: &QuicConnectConfig) -> anyhow::Result<Endpoint> {
+    let mut endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        None,
+        UdpSocket::bind(any_port(dest))?,
+        Arc::new(SmolRuntime),
+    )?;
+    endpoint.set_default_client_config(client_config(cfg)?);
+    Ok(endpoint)
+}
+
+fn client_config(cfg: &QuicConnectConfig) -> anyhow::Result<quinn::ClientConfig> {
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCert {
+            fingerprint: cfg.server_fingerprint,
+            provider: provider(),
+        }))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let mut client_config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    client_config.transport_config(transport_config()?);
+    Ok(client_config)
+}
+
+fn transport_config() -> anyhow::Result<Arc<TransportConfig>> {
+    let mut transport = TransportConfig::default();
+    transport
+        .keep_alive_interval(Some(KEEPALIVE_INTERVAL))
+        .max_idle_timeout(Some(IDLE_TIMEOUT.try_into()?));
+    Ok(Arc::new(transport))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// The fingerprint out-routes pin a QUIC in-route's certificate by.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> [u8; 32] {
+    *blake3::hash(cert).as_bytes()
+}
+
+/// The fingerprint of the certificate a QUIC in-route with this config presents.
+pub fn listen_fingerprint(
+    identity: Option<&RelayIdentitySecret>,
+    cfg: &QuicListenConfig,
+) -> anyhow::Result<[u8; 32]> {
+    Ok(fingerprint(&certificate(identity, cfg)?.0))
+}
+
+/// The certificate a QUIC in-route presents, and its key: the configured one, or one derived from our relay identity.
+fn certificate(
+    identity: Option<&RelayIdentitySecret>,
+    cfg: &QuicListenConfig,
+) -> anyhow::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    match (&cfg.cert, &cfg.key) {
+        (Some(cert), Some(key)) => {
+            let cert = CertificateDer::pem_file_iter(cert)
+                .and_then(|mut certs| certs.next().transpose())
+                .with_context(|| format!("could not read QUIC certificate {cert:?}"))?
+                .with_context(|| format!("no certificate in {cert:?}"))?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .with_context(|| format!("could not read QUIC key {key:?}"))?;
+            Ok((cert, key))
+        }
+        _ => {
+            let identity = identity.context("only relays can derive a QUIC certificate")?;
+            self_signed(identity)
+        }
+    }
+}
+
+/// A self-signed certificate whose Ed25519 key is derived from the relay identity. Certificates get the same serial number and validity every time, and Ed25519 signatures are deterministic, so it comes out byte for byte the same, as does its fingerprint.
+fn self_signed(
+    identity: &RelayIdentitySecret,
+) -> anyhow::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let seed = blake3::keyed_hash(b"quic_certificate________________", identity.as_bytes());
+    let mut pkcs8 = ED25519_PKCS8_PREFIX.to_vec();
+    pkcs8.extend_from_slice(seed.as_bytes());
+    let key = PrivatePkcs8KeyDer::from(pkcs8);
+    let key_pair = rcgen::KeyPair::try_from(&key)?;
+    let cert =
+        rcgen::CertificateParams::new(vec![SERVER_NAME.to_string()])?.self_signed(&key_pair)?;
+    Ok((cert.der().clone(), key.into()))
+}
+
+/// Accepts only the certificate with the pinned fingerprint, whatever name or dates it carries.
+#[derive(Debug)]
+struct PinnedCert {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "QUIC certificate has fingerprint {}, but {} was configured",
+                hex::encode(fingerprint(end_entity)),
+                hex::encode(self.fingerprint)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,148 @@
+use dashmap::{mapref::entry::Entry, DashMap};
+use earendil_crypt::RelayFingerprint;
+use stdcode::StdcodeSerializeExt;
+use thiserror::Error;
+
+use crate::{
+    audit::{audit, AuditEvent},
+    context::{CtxField, DaemonContext},
+    db::{db_read, db_write, MiscKey},
+};
+
+/// Fingerprints pinned on first use, keyed by out-route address. Persisted to the state cache, if there is one.
+static TOFU_PINS: CtxField<DashMap<String, RelayFingerprint>> = |_| DashMap::new();
+
+#[derive(Error, Debug)]
+#[error("relay at {addr} presented fingerprint {seen}, but {pinned} was pinned on first use")]
+pub struct PinMismatch {
+    pub addr: String,
+    pub pinned: RelayFingerprint,
+    pub seen: RelayFingerprint,
+}
+
+/// Returns the fingerprint pinned for an out-route address, if any, without pinning anything.
+pub async fn pinned(ctx: &DaemonContext, addr: &str) -> anyhow::Result<Option<RelayFingerprint>> {
+    match ctx.get(TOFU_PINS).get(addr).map(|pin| *pin) {
+        Some(pinned) => Ok(Some(pinned)),
+        None => Ok(db_read(ctx, MiscKey::TofuPin(addr.to_string()))
+            .await?
+            .map(|bts| stdcode::deserialize::<RelayFingerprint>(&bts))
+            .transpose()?),
+    }
+}
+
+/// Checks the fingerprint seen at an out-route address against the one pinned for it, pinning it if this is the first time we connect. Of several first connections racing each other, only one gets to pin.
+pub async fn check_pin(
+    ctx: &DaemonContext,
+    addr: &str,
+    seen: RelayFingerprint,
+) -> anyhow::Result<()> {
+    let pinned = match pinned(ctx, addr).await? {
+        Some(pinned) => *ctx.get(TOFU_PINS).entry(addr.to_string()).or_insert(pinned),
+        None => {
+            let pinned = match ctx.get(TOFU_PINS).entry(addr.to_string()) {
+                Entry::Occupied(entry) => *entry.get(),
+                Entry::Vacant(entry) => *entry.insert(seen),
+            };
+            if pinned == seen {
+                tracing::info!(
+                    addr,
+                    fingerprint = display(seen),
+                    "pinning out-route fingerprint on first use"
+                );
+                db_write(ctx, MiscKey::TofuPin(addr.to_string()), seen.stdcode()).await?;
+            }
+            pinned
+        }
+    };
+    if pinned != seen {
+        tracing::error!(
+            addr,
+            pinned = display(pinned),
+            seen = display(seen),
+            "PINNED FINGERPRINT CHANGED! Someone may be impersonating this relay. Remove the pin from the state cache only if the change is expected"
+        );
+        audit(
+            ctx,
+            AuditEvent::FingerprintMismatch {
+                connect: addr.to_string(),
+                expected: pinned,
+                seen,
+            },
+        )
+        .await;
+        anyhow::bail!(PinMismatch {
+            addr: addr.to_string(),
+            pinned,
+            seen
+        })
+    }
+    Ok(())
+}
+
+/// Replaces the fingerprint pinned for an out-route address, when the relay there proved that it rotated its identity.
+pub async fn repin(
+    ctx: &DaemonContext,
+    addr: &str,
+    fingerprint: RelayFingerprint,
+) -> anyhow::Result<()> {
+    tracing::info!(
+        addr,
+        fingerprint = display(fingerprint),
+        "re-pinning out-route fingerprint after an identity rotation"
+    );
+    ctx.get(TOFU_PINS).insert(addr.to_string(), fingerprint);
+    db_write(
+        ctx,
+        MiscKey::TofuPin(addr.to_string()),
+        fingerprint.stdcode(),
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+
+    use super::*;
+
+    #[test]
+    fn tofu_pin_mismatch() {
+        let ctx = DaemonContext::new(serde_json::from_str("{}").unwrap());
+        let first = RelayIdentitySecret::generate().public().fingerprint();
+        let second = RelayIdentitySecret::generate().public().fingerprint();
+        smol::future::block_on(async {
+            check_pin(&ctx, "1.2.3.4:5678", first).await.unwrap();
+            check_pin(&ctx, "1.2.3.4:5678", first).await.unwrap();
+            let err = check_pin(&ctx, "1.2.3.4:5678", second).await.unwrap_err();
+            let mismatch = err.downcast_ref::<PinMismatch>().unwrap();
+            assert_eq!((mismatch.pinned, mismatch.seen), (first, second));
+            // pins are per address
+            check_pin(&ctx, "5.6.7.8:5678", second).await.unwrap();
+            // a rotation moves the pin
+            repin(&ctx, "1.2.3.4:5678", second).await.unwrap();
+            check_pin(&ctx, "1.2.3.4:5678", second).await.unwrap();
+            assert!(check_pin(&ctx, "1.2.3.4:5678", first).await.is_err());
+        });
+    }
+
+    #[test]
+    fn racing_first_connections_pin_once() {
+        let ctx = DaemonContext::new(serde_json::from_str("{}").unwrap());
+        let fingerprints: Vec<_> = (0..8)
+            .map(|_| RelayIdentitySecret::generate().public().fingerprint())
+            .collect();
+        let results = smol::future::block_on(futures_util::future::join_all(
+            fingerprints
+                .iter()
+                .map(|fp| check_pin(&ctx, "1.2.3.4:5678", *fp)),
+        ));
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        let winner = fingerprints[results.iter().position(|result| result.is_ok()).unwrap()];
+        assert_eq!(
+            smol::future::block_on(pinned(&ctx, "1.2.3.4:5678")).unwrap(),
+            Some(winner)
+        );
+    }
+}
@@ -1,11 +1,11 @@
-use std::{ops::DerefMut, sync::Arc};
+use std::{future::Future, ops::DerefMut, sync::Arc};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use earendil_crypt::RelayFingerprint;
 use futures::{
     io::{ReadHalf, WriteHalf},
-    AsyncBufReadExt,
+    AsyncBufRead, AsyncBufReadExt,
 };
 use futures_util::io::AsyncReadExt;
 use nanorpc::{DynRpcTransport, JrpcRequest, JrpcResponse, RpcService, RpcTransport};
@@ -15,10 +15,34 @@ use serde::{Deserialize, Serialize};
 use smol::io::{AsyncWriteExt, BufReader};
 use stdcode::StdcodeSerializeExt;
 
-use crate::pascal::{read_pascal, write_pascal};
+use crate::{
+    config::PacingConfig,
+    network::NackReason,
+    pascal::{read_pascal, write_pascal},
+};
+
+mod pacing;
+use pacing::Pacer;
+pub use pacing::PacingStats;
 
 const LABEL_RPC: &[u8] = b"!rpc";
 
+/// The longest RPC request or response either side of a link reads, in bytes. Anything longer is refused before it's parsed, so that a neighbor can't make us parse, or even buffer, arbitrarily big replies.
+pub const MAX_RPC_LINE: usize = 1024 * 1024;
+
+/// Reads one line of at most [MAX_RPC_LINE] bytes.
+async fn read_rpc_line(read: &mut (impl AsyncBufRead + Unpin)) -> anyhow::Result<String> {
+    let mut line = String::new();
+    read.take(MAX_RPC_LINE as u64 + 1)
+        .read_line(&mut line)
+        .await?;
+    anyhow::ensure!(
+        line.len() <= MAX_RPC_LINE,
+        "RPC message is over {MAX_RPC_LINE} bytes"
+    );
+    Ok(line)
+}
+
 /// Link represents a link to a neighbor, either client or relay.
 ///
 /// This presents a self-contained abstraction that does not depend on anything "global" in the context
@@ -26,6 +50,7 @@ pub struct Link {
     mux: Arc<PicoMux>,
     read: smol::lock::Mutex<ReadHalf<picomux::Stream>>,
     write: smol::lock::Mutex<WriteHalf<picomux::Stream>>,
+    pacer: Option<Pacer>,
 }
 
 impl Link {
@@ -37,6 +62,7 @@ impl Link {
             mux: mux.into(),
             read: read.into(),
             write: write.into(),
+            pacer: None,
         })
     }
 
@@ -48,15 +74,52 @@ impl Link {
             mux: mux.into(),
             read: read.into(),
             write: write.into(),
+            pacer: None,
         })
     }
 
+    /// Paces messages sent over this link at the rate the link can take, if given a pacing config.
+    pub fn with_pacing(mut self, config: Option<PacingConfig>) -> Self {
+        self.pacer = config.map(Pacer::new);
+        self
+    }
+
     pub async fn send_msg(&self, msg: LinkMessage) -> anyhow::Result<()> {
+        let msg = msg.stdcode();
         let mut write = self.write.lock().await;
-        write_pascal(&msg.stdcode(), write.deref_mut()).await?;
+        // we pace while holding the lock, since the link is what we are waiting for
+        if let Some(pacer) = self.pacer.as_ref() {
+            pacer.pace(msg.len()).await;
+        }
+        write_pascal(&msg, write.deref_mut()).await?;
+        if let Some(pacer) = self.pacer.as_ref() {
+            pacer.record_write(msg.len());
+        }
         Ok(())
     }
 
+    /// Awaits `ping`, a call the neighbor only answers once everything we sent before it arrived, and tells the pacer, if the link is paced. Paced links only send as fast as these get answered.
+    pub async fn probe_pacing<T, E>(
+        &self,
+        ping: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let Some(pacer) = self.pacer.as_ref() else {
+            return ping.await;
+        };
+        let probe = pacer.probe();
+        let answer = ping.await;
+        match answer {
+            Ok(_) => pacer.acked(probe),
+            Err(_) => pacer.lost(probe),
+        }
+        answer
+    }
+
+    /// How this link is currently being paced, if it is.
+    pub fn pacing_stats(&self) -> Option<PacingStats> {
+        self.pacer.as_ref().map(|pacer| pacer.stats())
+    }
+
     pub async fn recv_msg(&self) -> anyhow::Result<LinkMessage> {
         let mut read = self.read.lock().await;
         let bts = read_pascal(read.deref_mut()).await?;
@@ -78,8 +141,7 @@ impl Link {
                         let (read, mut write) = stream.split();
                         let mut read = BufReader::new(read);
                         for _ in 0..1000 {
-                            let mut line = String::new();
-                            read.read_line(&mut line).await?;
+                            let line = read_rpc_line(&mut read).await?;
                             let req: JrpcRequest = serde_json::from_str(&line)?;
                             let resp = service.respond_raw(req).await;
                             write
@@ -110,8 +172,7 @@ impl RpcTransport for MuxRpcTransport {
         conn.write_all(format!("{}\n", serde_json::to_string(&req)?).as_bytes())
             .await?;
         let mut conn = BufReader::new(conn);
-        let mut line = String::new();
-        conn.read_line(&mut line).await?;
+        let line = read_rpc_line(&mut conn).await?;
         let response: JrpcResponse = serde_json::from_str(&line)?;
         Ok(response)
     }
@@ -127,4 +188,15 @@ pub enum LinkMessage {
         body: Bytes,
         rb_id: u64,
     },
+    /// Like [LinkMessage::ToRelay], but NACK-eligible: if the packet gets dropped on its way, a [LinkMessage::Nack] with this tag comes back.
+    ToRelayNackable {
+        packet: Bytes,
+        next_peeler: RelayFingerprint,
+        nack_tag: u64,
+    },
+    /// Tells the neighbor that a NACK-eligible packet it sent under this tag was dropped.
+    Nack {
+        tag: u64,
+        reason: NackReason,
+    },
 }
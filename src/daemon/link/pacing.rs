@@ -0,0 +1,403 @@
+use std::time::{Duration, Instant};
+
+use async_event::Event;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::PacingConfig;
+
+/// How far each delivery rate sample below the current estimate moves it. Higher samples are taken as they are, since the link can't deliver faster than it does.
+const RATE_ALPHA: f64 = 0.125;
+
+/// How many times the data the link can hold we keep unacknowledged, so that acknowledgements arriving in clumps don't starve the link, and a link that got faster gets to show it.
+const WINDOW_GAIN: f64 = 2.0;
+
+/// Paces writes to a link at the link's estimated rate, so that data waits in our own queues, where it can be prioritized and shed, rather than in the OS socket buffer and the obfuscation layer, where it delays everything behind it.
+///
+/// We can't see how much of what we wrote has left the machine, so the neighbor tells us: whenever it answers a probe, everything we wrote before the probe has arrived. From those acknowledgements we learn how fast the link delivers and its round-trip time, and keep no more unacknowledged data than the link can hold plus a small queue. A paced link only sends as fast as probes get answered, so something must keep probing it with [Pacer::probe] and [Pacer::acked].
+pub struct Pacer {
+    config: PacingConfig,
+    state: Mutex<PacerState>,
+    acked: Event,
+}
+
+/// How a link is currently being paced.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct PacingStats {
+    /// The estimated rate of the link, in bytes per second, once a probe was answered.
+    pub rate_bytes_per_sec: Option<u64>,
+    /// Bytes written but not yet acknowledged by the neighbor.
+    pub queued_bytes: u64,
+    /// Writes that had to wait, so that the buffers below us stay small.
+    pub paced_writes: u64,
+    /// How long those writes waited, in total.
+    pub paced_delay_ms: u64,
+}
+
+/// Where the write position of a link stood when a probe went out.
+#[derive(Clone, Copy, Debug)]
+pub struct PacingProbe {
+    written: u64,
+    sent: Instant,
+}
+
+struct PacerState {
+    /// In bytes per second.
+    rate: Option<f64>,
+    min_rtt: Option<Duration>,
+    /// Total bytes written.
+    written: u64,
+    /// The last probe answered, and when.
+    acked: Option<(PacingProbe, Instant)>,
+    /// Whether a write waited for acknowledgements since the last one, so that the link, rather than us, set the pace.
+    window_limited: bool,
+    paced_writes: u64,
+    paced_delay: Duration,
+}
+
+impl Pacer {
+    pub fn new(config: PacingConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(PacerState::new()),
+            acked: Event::new(),
+        }
+    }
+
+    /// Waits until `len` more bytes can be written without keeping more unacknowledged data than the link can hold.
+    pub async fn pace(&self, len: usize) {
+        if self.state.lock().admits(&self.config, len) {
+            return;
+        }
+        let start = Instant::now();
+        self.acked
+            .wait_until(|| self.state.lock().admits(&self.config, len).then_some(()))
+            .await;
+        let mut state = self.state.lock();
+        state.paced_writes += 1;
+        state.paced_delay += start.elapsed();
+    }
+
+    /// Records that `len` bytes were written.
+    pub fn record_write(&self, len: usize) {
+        self.state.lock().written += len as u64;
+    }
+
+    /// Notes where the write position stands as a probe goes out. Probes must be answered in the order they're sent.
+    pub fn probe(&self) -> PacingProbe {
+        PacingProbe {
+            written: self.state.lock().written,
+            sent: Instant::now(),
+        }
+    }
+
+    /// Records that the neighbor answered a probe, so that everything written before it arrived.
+    pub fn acked(&self, probe: PacingProbe) {
+        self.state
+            .lock()
+            .on_ack(&self.config, probe, Instant::now());
+        self.acked.notify_all();
+    }
+
+    /// Records that a probe went unanswered. We take everything before it as delivered rather than stall the link, without learning anything from it.
+    pub fn lost(&self, probe: PacingProbe) {
+        let mut state = self.state.lock();
+        if state
+            .acked
+            .map_or(true, |(last, _)| probe.written >= last.written)
+        {
+            state.acked = Some((probe, Instant::now()));
+        }
+        drop(state);
+        self.acked.notify_all();
+    }
+
+    pub fn stats(&self) -> PacingStats {
+        let state = self.state.lock();
+        PacingStats {
+            rate_bytes_per_sec: state.rate.map(|rate| rate as u64),
+            queued_bytes: state.unacked(),
+            paced_writes: state.paced_writes,
+            paced_delay_ms: state.paced_delay.as_millis() as u64,
+        }
+    }
+}
+
+impl PacerState {
+    fn new() -> Self {
+        Self {
+            rate: None,
+            min_rtt: None,
+            written: 0,
+            acked: None,
+            window_limited: false,
+            paced_writes: 0,
+            paced_delay: Duration::ZERO,
+        }
+    }
+
+    fn unacked(&self) -> u64 {
+        self.written
+            - self
+                .acked
+                .map_or(0, |(probe, _)| probe.written.min(self.written))
+    }
+
+    /// How much unacknowledged data we keep: what the link holds over its round trip plus the target queue, with some headroom, but never less than the burst floor. Until a probe is answered, that's just the burst floor.
+    fn window(&self, config: &PacingConfig) -> f64 {
+        let floor = config.burst_floor_bytes as f64;
+        let (Some(rate), Some(min_rtt)) = (self.rate, self.min_rtt) else {
+            return floor;
+        };
+        let held = rate * (min_rtt.as_secs_f64() + config.target_queue_ms as f64 / 1000.0);
+        (WINDOW_GAIN * held).max(floor)
+    }
+
+    /// Whether `len` more bytes fit in the window. A write always fits once everything before it is acknowledged, however big it is.
+    fn admits(&mut self, config: &PacingConfig, len: usize) -> bool {
+        let unacked = self.unacked();
+        let fits = unacked == 0 || (unacked + len as u64) as f64 <= self.window(config);
+        if !fits {
+            self.window_limited = true;
+        }
+        fits
+    }
+
+    fn on_ack(&mut self, config: &PacingConfig, probe: PacingProbe, now: Instant) {
+        let rtt = now.saturating_duration_since(probe.sent);
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        if let Some((last, last_acked)) = self.acked {
+            if probe.written < last.written {
+                return;
+            }
+            // what arrived between the two acknowledgements, over however long it took to send or to acknowledge, whichever was longer, so that acknowledgements arriving together don't look like a fast link
+            let delivered = (probe.written - last.written) as f64;
+            let interval = probe
+                .sent
+                .saturating_duration_since(last.sent)
+                .max(now.saturating_duration_since(last_acked))
+                .as_secs_f64();
+            if delivered > 0.0 && interval > 0.0 {
+                let sample = delivered / interval;
+                let rate = match self.rate {
+                    None => Some(sample),
+                    Some(rate) if sample > rate => Some(sample),
+                    // when we didn't have enough to send to fill the window, the sample says how much we had, not how fast the link is
+                    Some(rate) if self.window_limited => Some(rate + RATE_ALPHA * (sample - rate)),
+                    Some(_) => None,
+                };
+                if let Some(rate) = rate {
+                    self.rate = Some(rate.max(config.min_rate_bytes_per_sec as f64));
+                }
+            }
+        }
+        self.acked = Some((probe, now));
+        self.window_limited = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        future::Future,
+        io,
+        pin::Pin,
+        task::{ready, Context, Poll},
+    };
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures::{AsyncRead, AsyncReadExt};
+    use nanorpc::{RpcService, RpcTransport, ServerError};
+    use picomux::PicoMux;
+    use serde_json::Value;
+    use smol::future::FutureExt;
+
+    use super::*;
+    use crate::daemon::link::{Link, LinkMessage};
+
+    fn config() -> PacingConfig {
+        serde_json::from_value(serde_json::json!({
+            "target_queue_ms": 50,
+            "burst_floor_bytes": 16384,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn learns_rate_from_acks_not_from_idle_periods() {
+        let config = config();
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut state = PacerState::new();
+        let ack = |state: &mut PacerState, written: u64, sent_ms: u64, acked_ms: u64| {
+            state.written = state.written.max(written);
+            state.on_ack(
+                &config,
+                PacingProbe {
+                    written,
+                    sent: at(sent_ms),
+                },
+                at(acked_ms),
+            );
+        };
+
+        // 100 KB delivered a second, with a 50 ms round trip
+        ack(&mut state, 0, 0, 50);
+        ack(&mut state, 100_000, 1000, 1050);
+        assert_eq!(state.rate, Some(100_000.0));
+        assert_eq!(state.min_rtt, Some(Duration::from_millis(50)));
+        // twice what the link holds over a round trip plus the target queue
+        assert_eq!(state.window(&config), 20_000.0);
+
+        // a quiet second says nothing about the link
+        ack(&mut state, 110_000, 2000, 2050);
+        assert_eq!(state.rate, Some(100_000.0));
+
+        // but a slower second while we had more to send does
+        state.written = 200_000;
+        assert!(!state.admits(&config, 1000));
+        ack(&mut state, 160_000, 3000, 3050);
+        assert_eq!(state.rate, Some(100_000.0 - RATE_ALPHA * 50_000.0));
+        assert_eq!(state.unacked(), 40_000);
+    }
+
+    /// Answers every call with its first argument, like the link protocol's `ping`.
+    struct Echo;
+
+    #[async_trait]
+    impl RpcService for Echo {
+        async fn respond(
+            &self,
+            _method: &str,
+            params: Vec<Value>,
+        ) -> Option<Result<Value, ServerError>> {
+            Some(Ok(params.into_iter().next().unwrap_or(Value::Null)))
+        }
+    }
+
+    /// Reads no faster than `rate` bytes per second, like the far end of a slow physical link. Whatever it hasn't read yet waits in the socket buffers, as it would in front of the slow link.
+    struct SlowRead<R> {
+        inner: R,
+        rate: f64,
+        start: Instant,
+        read: f64,
+        timer: Option<smol::Timer>,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for SlowRead<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = &mut *self;
+            if let Some(timer) = this.timer.as_mut() {
+                ready!(Pin::new(timer).poll(cx));
+                this.timer = None;
+            }
+            let max = buf.len().min(1000);
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..max]))?;
+            this.read += n as f64;
+            this.timer = Some(smol::Timer::at(
+                this.start + Duration::from_secs_f64(this.read / this.rate),
+            ));
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn paced_link_keeps_queueing_bounded() {
+        /// The far end takes this many bytes per second off the link.
+        const LINK_RATE: f64 = 200_000.0;
+
+        smolscale::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (dialed, accepted) =
+                futures::future::join(smol::net::TcpStream::connect(addr), listener.accept()).await;
+            let (read, write) = dialed.unwrap().split();
+            let near = PicoMux::new(read, write);
+            let (read, write) = accepted.unwrap().0.split();
+            let read = SlowRead {
+                inner: read,
+                rate: LINK_RATE,
+                start: Instant::now(),
+                read: 0.0,
+                timer: None,
+            };
+            let far = PicoMux::new(read, write);
+            let (sender, receiver) =
+                futures::future::join(Link::new_dial(near), Link::new_listen(far)).await;
+            let sender = sender.unwrap().with_pacing(Some(config()));
+            let receiver = receiver.unwrap();
+
+            let sent_at: Mutex<HashMap<u64, Instant>> = Default::default();
+            let start = Instant::now();
+            let mut queueing = vec![];
+
+            // the far end: answers pings, and notes how long everything else spent below the sender
+            let far_end = async {
+                let recv = async {
+                    while let Ok(LinkMessage::ToClient { rb_id, .. }) = receiver.recv_msg().await {
+                        if let Some(sent) = sent_at.lock().remove(&rb_id) {
+                            if start.elapsed() > Duration::from_secs(4) {
+                                queueing.push(sent.elapsed());
+                            }
+                        }
+                    }
+                };
+                let pings = async {
+                    receiver.rpc_serve(Echo).await.unwrap();
+                };
+                recv.race(pings).await
+            };
+            // pings, back to back, the way the daemon probes paced links
+            let prober = async {
+                let transport = sender.rpc_transport();
+                loop {
+                    sender
+                        .probe_pacing(transport.call("ping", &[Value::from(0)]))
+                        .await
+                        .unwrap();
+                }
+            };
+            // more traffic than the link can take, for eight seconds
+            let flood = async {
+                for seq in 0.. {
+                    if start.elapsed() > Duration::from_secs(8) {
+                        break;
+                    }
+                    sender
+                        .send_msg(LinkMessage::ToClient {
+                            body: Bytes::from(vec![0u8; 1000]),
+                            rb_id: seq,
+                        })
+                        .await
+                        .unwrap();
+                    // time spent in the buffers below us starts once the write returns
+                    sent_at.lock().insert(seq, Instant::now());
+                }
+            };
+            far_end.race(prober).race(flood).await;
+
+            let stats = sender.pacing_stats().unwrap();
+            let rate = stats.rate_bytes_per_sec.unwrap() as f64;
+            assert!(
+                rate > LINK_RATE / 2.0 && rate < LINK_RATE * 2.0,
+                "estimated {rate} bytes/sec"
+            );
+            assert!(stats.paced_writes > 0);
+            // the link stayed busy
+            assert!(
+                queueing.len() as f64 > 0.8 * 4.0 * LINK_RATE / 1000.0,
+                "only {} messages got through",
+                queueing.len()
+            );
+            // and nothing waited long below us
+            let worst = queueing.iter().max().unwrap();
+            assert!(*worst < Duration::from_millis(500), "worst: {worst:?}");
+        });
+    }
+}
@@ -0,0 +1,198 @@
+use std::{collections::HashSet, time::Duration};
+
+use earendil_crypt::RelayFingerprint;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::{
+    clock::{self, unix_now},
+    config::PartitionWatchConfig,
+    context::{self, CtxField, DaemonContext, MY_RELAY_IDENTITY, RELAY_GRAPH},
+    network,
+    stats::STATS,
+};
+
+pub const PARTITION_SHRUNK: &str = "partition.shrunk";
+pub const PARTITION_WATCHED_LOST: &str = "partition.watched_lost";
+
+/// The report of the last check that the watch loop ran, which the next one is compared against.
+static LAST_CHECK: CtxField<Mutex<Option<PartitionReport>>> = |_| Mutex::new(None);
+
+/// How the relay graph splits into connected components, and whether we can reach the relays we watch.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PartitionReport {
+    /// How many relays are in the component we're in, counting ourselves if we're a relay.
+    pub our_component: usize,
+    /// The sizes of the other components, largest first.
+    pub other_components: Vec<usize>,
+    /// Whether the check stopped at `max_nodes`, leaving some relays out.
+    pub truncated: bool,
+    pub watched: Vec<WatchedRelay>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WatchedRelay {
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub fingerprint: RelayFingerprint,
+    /// How many hops the shortest route to the relay takes, or None if there's no route.
+    pub hops: Option<usize>,
+}
+
+/// Checks how the relay graph splits, counting only adjacencies as fresh as `partition_watch` asks, or as its defaults if it's off. Where a client has no place in the graph, it counts from one of its relay neighbors.
+pub fn partition_check(ctx: &DaemonContext) -> PartitionReport {
+    let config = ctx.init().partition_watch.clone().unwrap_or_default();
+    check(ctx, &config, unix_now(ctx))
+}
+
+fn check(ctx: &DaemonContext, config: &PartitionWatchConfig, now_unix: u64) -> PartitionReport {
+    let start = match ctx.get(MY_RELAY_IDENTITY) {
+        Some(identity) => Some(identity.public().fingerprint()),
+        None => network::all_relay_neighs(ctx).into_iter().next(),
+    };
+    let partition = ctx.get(RELAY_GRAPH).read().partition(
+        start.as_ref(),
+        config.max_adjacency_age_secs,
+        now_unix,
+        config.max_nodes,
+    );
+    // a client is one hop further from everything than the neighbor we counted from
+    let client_hop = usize::from(context::is_client(ctx));
+    PartitionReport {
+        our_component: partition.hops.len(),
+        other_components: partition.other_components,
+        truncated: partition.truncated,
+        watched: config
+            .watch
+            .iter()
+            .map(|fp| WatchedRelay {
+                fingerprint: *fp,
+                hops: partition.hops.get(fp).map(|hops| hops + client_hop),
+            })
+            .collect(),
+    }
+}
+
+/// Checks for partitions every `interval_secs`, warning when our component shrinks by more than `shrink_ratio` since the last check, or when a watched relay becomes unreachable.
+pub async fn partition_watch_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let Some(config) = ctx.init().partition_watch.clone() else {
+        return Ok(());
+    };
+    loop {
+        let clock = clock::clock(&ctx);
+        check_and_alert(&ctx, &config, clock.unix_now());
+        clock.sleep(Duration::from_secs(config.interval_secs)).await;
+    }
+}
+
+fn check_and_alert(
+    ctx: &DaemonContext,
+    config: &PartitionWatchConfig,
+    now_unix: u64,
+) -> PartitionReport {
+    let report = check(ctx, config, now_unix);
+    let mut last = ctx.get(LAST_CHECK).lock();
+    if let Some(last) = last.as_ref() {
+        if (report.our_component as f64) < last.our_component as f64 * config.shrink_ratio {
+            ctx.get(STATS).incr(PARTITION_SHRUNK);
+            tracing::warn!(
+                was = last.our_component,
+                now = report.our_component,
+                other_components = debug(&report.other_components),
+                "NETWORK PARTITIONED: the part of the relay graph we can reach shrank sharply"
+            );
+        }
+        let reachable_before: HashSet<RelayFingerprint> = last
+            .watched
+            .iter()
+            .filter(|watched| watched.hops.is_some())
+            .map(|watched| watched.fingerprint)
+            .collect();
+        for watched in &report.watched {
+            let was_reachable = reachable_before.contains(&watched.fingerprint);
+            if watched.hops.is_none() && was_reachable {
+                ctx.get(STATS).incr(PARTITION_WATCHED_LOST);
+                tracing::warn!(
+                    relay = display(watched.fingerprint),
+                    "WATCHED RELAY UNREACHABLE: no route to it over fresh adjacencies"
+                );
+            } else if watched.hops.is_some() && !was_reachable {
+                tracing::info!(
+                    relay = display(watched.fingerprint),
+                    hops = watched.hops,
+                    "watched relay is reachable"
+                );
+            }
+        }
+    }
+    *last = Some(report.clone());
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::IdentityDescriptor;
+
+    use super::*;
+    use crate::test_util::{adjacency, known_relay};
+
+    #[test]
+    fn cutting_a_bridge_is_detected() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "partition watch",
+            }))
+            .unwrap(),
+        );
+        let me = RelayIdentitySecret::from_seed("partition watch");
+        // we're in a cluster of three, joined by a single bridge to another cluster of three
+        ctx.get(RELAY_GRAPH)
+            .write()
+            .insert_identity(IdentityDescriptor::new(&me, &DhSecret::generate()))
+            .unwrap();
+        let europe = [me, known_relay(&ctx), known_relay(&ctx)];
+        let america = [(); 3].map(|_| known_relay(&ctx));
+        let now = unix_now(&ctx);
+        let connect = |a: &RelayIdentitySecret, b: &RelayIdentitySecret, unix_timestamp: u64| {
+            ctx.get(RELAY_GRAPH)
+                .write()
+                .insert_adjacency(adjacency(a, b, unix_timestamp))
+                .unwrap();
+        };
+        for cluster in [&europe, &america] {
+            connect(&cluster[0], &cluster[1], now);
+            connect(&cluster[1], &cluster[2], now);
+        }
+        connect(&europe[2], &america[0], now);
+
+        let far_end = america[2].public().fingerprint();
+        let config = PartitionWatchConfig {
+            watch: vec![far_end],
+            max_adjacency_age_secs: 600,
+            ..Default::default()
+        };
+        let report = check_and_alert(&ctx, &config, now);
+        assert_eq!(report.our_component, 6);
+        assert!(report.other_components.is_empty());
+        assert_eq!(report.watched[0].hops, Some(5));
+
+        // the bridge stops being refreshed, and goes stale
+        connect(&europe[2], &america[0], now - 1200);
+        let report = check_and_alert(&ctx, &config, now);
+        assert_eq!(report.our_component, 3);
+        assert_eq!(report.other_components, vec![3]);
+        assert_eq!(report.watched[0].hops, None);
+        let stats = ctx.get(STATS).snapshot();
+        assert_eq!(stats.get(PARTITION_SHRUNK), Some(&1));
+        assert_eq!(stats.get(PARTITION_WATCHED_LOST), Some(&1));
+
+        // staying split doesn't warn again
+        check_and_alert(&ctx, &config, now);
+        let stats = ctx.get(STATS).snapshot();
+        assert_eq!(stats.get(PARTITION_SHRUNK), Some(&1));
+        assert_eq!(stats.get(PARTITION_WATCHED_LOST), Some(&1));
+    }
+}
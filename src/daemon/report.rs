@@ -0,0 +1,524 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use earendil_crypt::{RelayIdentityPublic, RelayIdentitySecret};
+use futures::TryStreamExt;
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+use smol::channel::{Receiver, Sender};
+use sqlx::Row;
+
+use crate::{
+    context::{CtxField, DaemonContext, DEBTS, MY_RELAY_IDENTITY},
+    control_protocol::{ReportArgs, ReportError, ReportFormat},
+    daemon::chat::CHATS,
+    db::DATABASE,
+    scope::{spawn_scoped, Stage},
+};
+
+/// How many bytes of a report are handed out at a time.
+const REPORT_CHUNK_BYTES: usize = 64 * 1024;
+
+/// How many chunks may be generated ahead of the caller reading them.
+const REPORT_BACKLOG: usize = 4;
+
+/// How long a report nobody reads from is kept around before it's abandoned.
+const REPORT_IDLE_TTL: Duration = Duration::from_secs(60);
+
+/// Reports being generated, by id, each one a queue of chunks or the error that stopped it.
+static REPORTS: CtxField<Cache<u64, Receiver<Result<String, String>>>> =
+    |_| Cache::builder().time_to_idle(REPORT_IDLE_TTL).build();
+
+static NEXT_REPORT: CtxField<AtomicU64> = |_| AtomicU64::new(1);
+
+/// One line of a report. Every report starts with a header, and ends with a signature over all the lines before it.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum ReportRecord {
+    Header {
+        relay: String,
+        period_start: u64,
+        period_end: u64,
+    },
+    /// Totals for one neighbor over the period.
+    Neighbor {
+        neighbor: String,
+        packets_in: u64,
+        packets_out: u64,
+        settled: u64,
+        /// The debt as of when the report was made, since debts are not tracked historically.
+        current_net_debt: Option<i128>,
+    },
+    Settlement {
+        unix_secs: u64,
+        neighbor: String,
+        amount: u64,
+        settlement: String,
+        /// Hex-encoded payment proofs.
+        proofs: Vec<String>,
+    },
+    Chat {
+        unix_secs: u64,
+        neighbor: String,
+        outgoing: bool,
+        text: String,
+    },
+    Signature {
+        /// Hex-encoded public key of the relay that signed the report.
+        signer: String,
+        signature: String,
+    },
+}
+
+const CSV_COLUMNS: &str =
+    "record,neighbor,unix_secs,packets_in,packets_out,amount,current_net_debt,detail";
+
+impl ReportRecord {
+    /// Encodes the record as one line in the given format, including the newline.
+    fn encode(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Json => {
+                serde_json::to_string(self).expect("report records always serialize") + "\n"
+            }
+            ReportFormat::Csv => {
+                let fields: [String; 8] = match self {
+                    ReportRecord::Header {
+                        relay,
+                        period_start,
+                        period_end,
+                    } => [
+                        "header".into(),
+                        relay.clone(),
+                        period_start.to_string(),
+                        "".into(),
+                        "".into(),
+                        "".into(),
+                        "".into(),
+                        period_end.to_string(),
+                    ],
+                    ReportRecord::Neighbor {
+                        neighbor,
+                        packets_in,
+                        packets_out,
+                        settled,
+                        current_net_debt,
+                    } => [
+                        "neighbor".into(),
+                        neighbor.clone(),
+                        "".into(),
+                        packets_in.to_string(),
+                        packets_out.to_string(),
+                        settled.to_string(),
+                        current_net_debt.map(|d| d.to_string()).unwrap_or_default(),
+                        "".into(),
+                    ],
+                    ReportRecord::Settlement {
+                        unix_secs,
+                        neighbor,
+                        amount,
+                        settlement,
+                        proofs,
+                    } => [
+                        "settlement".into(),
+                        neighbor.clone(),
+                        unix_secs.to_string(),
+                        "".into(),
+                        "".into(),
+                        amount.to_string(),
+                        "".into(),
+                        format!("{settlement}:{}", proofs.join(":")),
+                    ],
+                    ReportRecord::Chat {
+                        unix_secs,
+                        neighbor,
+                        outgoing,
+                        text,
+                    } => [
+                        if *outgoing { "chat_out" } else { "chat_in" }.into(),
+                        neighbor.clone(),
+                        unix_secs.to_string(),
+                        "".into(),
+                        "".into(),
+                        "".into(),
+                        "".into(),
+                        text.clone(),
+                    ],
+                    ReportRecord::Signature { signer, signature } => [
+                        "signature".into(),
+                        signer.clone(),
+                        "".into(),
+                        "".into(),
+                        "".into(),
+                        "".into(),
+                        "".into(),
+                        signature.clone(),
+                    ],
+                };
+                fields.map(|f| csv_escape(&f)).join(",") + "\n"
+            }
+        }
+    }
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn report_hasher() -> blake3::Hasher {
+    blake3::Hasher::new_keyed(b"earendil-report-----------------")
+}
+
+/// Starts generating a signed report of our dealings with our neighbors over the given period, returning the id to read it with through [report_chunk].
+pub fn start_report(ctx: &DaemonContext, args: ReportArgs) -> u64 {
+    let report_id = ctx.get(NEXT_REPORT).fetch_add(1, Ordering::Relaxed);
+    let (send_chunk, recv_chunk) = smol::channel::bounded(REPORT_BACKLOG);
+    ctx.get(REPORTS).insert(report_id, recv_chunk);
+    spawn_scoped(ctx, Stage::Inflight, "report", {
+        let ctx = ctx.clone();
+        async move {
+            if let Err(err) = generate_report(&ctx, &args, &send_chunk).await {
+                let _ = send_chunk.send(Err(format!("{err:?}"))).await;
+            }
+        }
+    });
+    report_id
+}
+
+/// Returns the next chunk of a report started through [start_report], or `None` once it's all been read.
+pub async fn report_chunk(
+    ctx: &DaemonContext,
+    report_id: u64,
+) -> Result<Option<String>, ReportError> {
+    let reports = ctx.get(REPORTS);
+    let chunks = reports
+        .get(&report_id)
+        .ok_or(ReportError::UnknownReport(report_id))?;
+    match chunks.recv().await {
+        Ok(chunk) => chunk.map(Some).map_err(ReportError::Generate),
+        Err(_) => {
+            reports.invalidate(&report_id);
+            Ok(None)
+        }
+    }
+}
+
+/// Encodes records into chunks of a report as they're generated, hashing them along the way, so that only a few chunks are ever held in memory.
+struct ReportWriter {
+    format: ReportFormat,
+    hasher: blake3::Hasher,
+    chunk: String,
+    out: Sender<Result<String, String>>,
+}
+
+impl ReportWriter {
+    async fn push_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.hasher.update(line.as_bytes());
+        self.chunk.push_str(line);
+        if self.chunk.len() >= REPORT_CHUNK_BYTES {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn emit(&mut self, record: ReportRecord) -> anyhow::Result<()> {
+        self.push_line(&record.encode(self.format)).await
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.out
+            .send(Ok(std::mem::take(&mut self.chunk)))
+            .await
+            .ok()
+            .context("the report was abandoned")
+    }
+
+    /// Signs everything written so far, and writes out the signature.
+    async fn finish(mut self, my_sk: &RelayIdentitySecret) -> anyhow::Result<()> {
+        let signature = ReportRecord::Signature {
+            signer: hex::encode(my_sk.public()),
+            signature: hex::encode(my_sk.sign(self.hasher.finalize().as_bytes())),
+        };
+        self.chunk.push_str(&signature.encode(self.format));
+        self.flush().await
+    }
+}
+
+/// Generates a signed report of our dealings with our neighbors over the given period, in chunks sent to `out`. Rows are streamed out of the state cache, so long periods don't need to fit in memory all at once.
+async fn generate_report(
+    ctx: &DaemonContext,
+    args: &ReportArgs,
+    out: &Sender<Result<String, String>>,
+) -> anyhow::Result<()> {
+    let my_sk = ctx
+        .get(MY_RELAY_IDENTITY)
+        .context("only relays can sign reports")?;
+    let pool = ctx
+        .get(DATABASE)
+        .as_ref()
+        .context("reports need a state cache")?;
+    let mut writer = ReportWriter {
+        format: args.format,
+        hasher: report_hasher(),
+        chunk: String::new(),
+        out: out.clone(),
+    };
+    if args.format == ReportFormat::Csv {
+        writer.push_line(&format!("{CSV_COLUMNS}\n")).await?;
+    }
+    writer
+        .emit(ReportRecord::Header {
+            relay: my_sk.public().fingerprint().to_string(),
+            period_start: args.period_start,
+            period_end: args.period_end,
+        })
+        .await?;
+
+    // per-neighbor totals. neighbors may have come and gone during the period, so we go by whoever shows up in the ledger, and then whoever else we have debts with
+    let mut debts: HashMap<String, i128> = ctx.get(DEBTS).net_debts().into_iter().collect();
+    let mut totals = sqlx::query(
+        "SELECT neighbor, SUM(packets_in) AS packets_in, SUM(packets_out) AS packets_out, SUM(settled) AS settled FROM (
+            SELECT neighbor, packets_in, packets_out, 0 AS settled FROM traffic WHERE bucket >= ?1 AND bucket < ?2
+            UNION ALL
+            SELECT neighbor, 0, 0, amount FROM settlements WHERE unix_secs >= ?1 AND unix_secs < ?2
+        ) GROUP BY neighbor ORDER BY neighbor",
+    )
+    .bind(args.period_start as i64)
+    .bind(args.period_end as i64)
+    .fetch(pool);
+    while let Some(row) = totals.try_next().await? {
+        let neighbor: String = row.get("neighbor");
+        writer
+            .emit(ReportRecord::Neighbor {
+                current_net_debt: debts.remove(&neighbor),
+                neighbor,
+                packets_in: row.get::<i64, _>("packets_in") as u64,
+                packets_out: row.get::<i64, _>("packets_out") as u64,
+                settled: row.get::<i64, _>("settled") as u64,
+            })
+            .await?;
+    }
+    drop(totals);
+    let mut idle: Vec<_> = debts.into_iter().collect();
+    idle.sort();
+    for (neighbor, net_debt) in idle {
+        writer
+            .emit(ReportRecord::Neighbor {
+                neighbor,
+                packets_in: 0,
+                packets_out: 0,
+                settled: 0,
+                current_net_debt: Some(net_debt),
+            })
+            .await?;
+    }
+
+    // settlement events, with their proofs
+    let mut settlements = sqlx::query(
+        "SELECT unix_secs, neighbor, amount, settlement FROM settlements WHERE unix_secs >= ? AND unix_secs < ? ORDER BY unix_secs",
+    )
+    .bind(args.period_start as i64)
+    .bind(args.period_end as i64)
+    .fetch(pool);
+    while let Some(row) = settlements.try_next().await? {
+        let settlement: String = row.get("settlement");
+        let proofs =
+            sqlx::query("SELECT proof FROM settlement_proofs WHERE settlement = ? ORDER BY idx")
+                .bind(&settlement)
+                .fetch(pool)
+                .map_ok(|row| hex::encode(row.get::<Vec<u8>, _>("proof")))
+                .try_collect()
+                .await?;
+        writer
+            .emit(ReportRecord::Settlement {
+                unix_secs: row.get::<i64, _>("unix_secs") as u64,
+                neighbor: row.get("neighbor"),
+                amount: row.get::<i64, _>("amount") as u64,
+                settlement,
+                proofs,
+            })
+            .await?;
+    }
+    drop(settlements);
+
+    // chat bodies are private to the people chatting, so they only go in when explicitly asked for
+    if args.include_chats {
+        let start = UNIX_EPOCH + Duration::from_secs(args.period_start);
+        let end = UNIX_EPOCH + Duration::from_secs(args.period_end);
+        for (neighbor, entry) in ctx.get(CHATS).entries_between(start, end) {
+            writer
+                .emit(ReportRecord::Chat {
+                    unix_secs: entry
+                        .time
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    neighbor,
+                    outgoing: entry.is_outgoing,
+                    text: entry.text,
+                })
+                .await?;
+        }
+    }
+
+    writer.finish(&my_sk).await
+}
+
+/// Checks the signature at the end of a report, returning who signed it. Recipients should check that the signer is the relay they expected.
+pub fn verify_report(report: &str) -> anyhow::Result<RelayIdentityPublic> {
+    let trimmed = report.strip_suffix('\n').context("report is truncated")?;
+    let split = trimmed.rfind('\n').context("report has no body")? + 1;
+    let (body, trailer) = trimmed.split_at(split);
+    let (signer, signature) = if let Ok(record) = serde_json::from_str(trailer) {
+        let ReportRecord::Signature { signer, signature } = record else {
+            anyhow::bail!("report does not end with a signature")
+        };
+        (signer, signature)
+    } else {
+        let fields: Vec<&str> = trailer.split(',').collect();
+        if fields.len() != 8 || fields[0] != "signature" {
+            anyhow::bail!("report does not end with a signature")
+        }
+        (fields[1].to_string(), fields[7].to_string())
+    };
+    let signer = RelayIdentityPublic::try_from(hex::decode(signer)?)
+        .map_err(|_| anyhow::anyhow!("report signer is not a public key"))?;
+    signer.verify(
+        report_hasher()
+            .update(body.as_bytes())
+            .finalize()
+            .as_bytes(),
+        &hex::decode(signature)?,
+    )?;
+    Ok(signer)
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+
+    use crate::{
+        ledger,
+        micromel::Micromel,
+        settlement::{SettlementProof, SettlementRequest, Settlements},
+    };
+
+    use super::*;
+
+    /// Reads a whole report the way the CLI does, returning it along with how many chunks it came in.
+    async fn read_report(ctx: &DaemonContext, args: ReportArgs) -> (String, usize) {
+        let report_id = start_report(ctx, args);
+        let mut report = String::new();
+        let mut chunks = 0;
+        while let Some(chunk) = report_chunk(ctx, report_id).await.unwrap() {
+            report.push_str(&chunk);
+            chunks += 1;
+        }
+        assert!(matches!(
+            report_chunk(ctx, report_id).await,
+            Err(ReportError::UnknownReport(_))
+        ));
+        (report, chunks)
+    }
+
+    #[test]
+    fn report_totals_and_signature() {
+        let state_cache =
+            std::env::temp_dir().join(format!("earendil-report-{}.db", rand::random::<u64>()));
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "report test",
+                "state_cache": state_cache,
+            }))
+            .unwrap(),
+        );
+        let payer = RelayIdentitySecret::generate();
+        let payer_name = payer.public().fingerprint().to_string();
+        let request = SettlementRequest::new(payer, Micromel(250), vec![SettlementProof::Manual]);
+
+        let report = smol::future::block_on(async {
+            for _ in 0..3 {
+                ledger::count_incoming(&ctx, &payer_name);
+            }
+            ledger::count_outgoing(&ctx, &payer_name);
+            ledger::count_incoming(&ctx, "12345");
+            ledger::flush_traffic(&ctx).await.unwrap();
+            ledger::count_incoming(&ctx, &payer_name);
+            ledger::flush_traffic(&ctx).await.unwrap();
+            Settlements::new(None)
+                .record_proofs(&ctx, &payer_name, &request)
+                .await
+                .unwrap();
+            ctx.get(CHATS).record(
+                either::Either::Left(12345),
+                crate::daemon::ChatEntry::new_incoming("secret".into()),
+            );
+            // enough chat to take several chunks
+            for _ in 0..100 {
+                ctx.get(CHATS).record(
+                    either::Either::Left(12345),
+                    crate::daemon::ChatEntry::new_incoming("x".repeat(2000)),
+                );
+            }
+
+            let now = ledger::unix_now();
+            let args = |format, include_chats| ReportArgs {
+                period_start: now - 2 * ledger::TRAFFIC_BUCKET_SECS,
+                period_end: now + ledger::TRAFFIC_BUCKET_SECS,
+                format,
+                include_chats,
+            };
+            let (csv, chunks) = read_report(&ctx, args(ReportFormat::Csv, false)).await;
+            assert_eq!(chunks, 1);
+            assert!(verify_report(&csv).is_ok());
+            assert!(!csv.contains("secret"));
+            let (with_chats, chunks) = read_report(&ctx, args(ReportFormat::Json, true)).await;
+            assert!(chunks > 1);
+            assert!(verify_report(&with_chats).is_ok());
+            assert!(with_chats.contains("secret"));
+            read_report(&ctx, args(ReportFormat::Json, false)).await.0
+        });
+
+        let signer = verify_report(&report).unwrap();
+        assert_eq!(
+            signer.fingerprint(),
+            RelayIdentitySecret::from_seed("report test")
+                .public()
+                .fingerprint()
+        );
+        let records: Vec<ReportRecord> = report
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(records.contains(&ReportRecord::Neighbor {
+            neighbor: payer_name.clone(),
+            packets_in: 4,
+            packets_out: 1,
+            settled: 250,
+            current_net_debt: None,
+        }));
+        assert!(records.contains(&ReportRecord::Neighbor {
+            neighbor: "12345".into(),
+            packets_in: 1,
+            packets_out: 0,
+            settled: 0,
+            current_net_debt: None,
+        }));
+        assert!(records.iter().any(|record| matches!(
+            record,
+            ReportRecord::Settlement { amount: 250, proofs, .. } if proofs.len() == 1
+        )));
+
+        // any tampering breaks the signature
+        let tampered = report.replace("\"packets_in\":4", "\"packets_in\":5");
+        assert!(verify_report(&tampered).is_err());
+        let _ = std::fs::remove_file(state_cache);
+    }
+}
@@ -1,22 +1,104 @@
 use crate::HavenHandler;
-use crate::{context::DaemonContext, HavenConfig, HavenListener, PooledListener};
+use crate::{
+    context::{CtxField, DaemonContext},
+    scope::{spawn_owned, Stage},
+    HavenConfig, HavenListener, PooledListener,
+};
 use anyhow::Context as _;
+use dashmap::{DashMap, DashSet};
+use earendil_crypt::{HavenFingerprint, RelayFingerprint};
 use futures::AsyncReadExt;
 use nursery_macro::nursery;
-use smol::future::FutureExt;
+use smol::{future::FutureExt, Task};
+
+/// Havens that are bound to their rendezvous and accepting connections.
+static SERVING_HAVENS: CtxField<DashSet<HavenFingerprint>> = |_| DashSet::new();
+
+/// Ephemeral havens bound through the control protocol. Removing one stops it, which tears it down.
+static EPHEMERAL_HAVENS: CtxField<DashMap<HavenFingerprint, EphemeralHaven>> = |_| DashMap::new();
+
+pub struct EphemeralHaven {
+    pub listen_port: u16,
+    pub handler: HavenHandler,
+    _task: Task<Option<anyhow::Result<()>>>,
+}
+
+pub fn is_serving(ctx: &DaemonContext, haven: &HavenFingerprint) -> bool {
+    ctx.get(SERVING_HAVENS).contains(haven)
+}
+
+/// The ephemeral havens currently up, by fingerprint, with their listen port and handler.
+pub fn ephemeral_havens(ctx: &DaemonContext) -> Vec<(HavenFingerprint, u16, HavenHandler)> {
+    ctx.get(EPHEMERAL_HAVENS)
+        .iter()
+        .map(|entry| {
+            (
+                *entry.key(),
+                entry.value().listen_port,
+                entry.value().handler.clone(),
+            )
+        })
+        .collect()
+}
 
 pub async fn serve_haven(ctx: &DaemonContext, cfg: &HavenConfig) -> anyhow::Result<()> {
     let identity = cfg.identity.actualize_haven()?;
-    let listener = PooledListener::new(
-        HavenListener::bind(ctx, identity, cfg.listen_port, cfg.rendezvous).await?,
+    let fingerprint = identity.public().fingerprint();
+    let rendezvous = std::iter::once(cfg.rendezvous)
+        .chain(cfg.backup_rendezvous.iter().copied())
+        .collect();
+    let listener = HavenListener::bind_multi(ctx, identity, cfg.listen_port, rendezvous).await?;
+    listener.set_rate_limit(cfg.rate_limit);
+    let listener = if cfg.throughput_sink {
+        PooledListener::with_throughput_sink(listener)
+    } else {
+        PooledListener::new(listener)
+    };
+    ctx.get(SERVING_HAVENS).insert(fingerprint);
+    scopeguard::defer!({
+        ctx.get(SERVING_HAVENS).remove(&fingerprint);
+    });
+    serve_listener(listener, &cfg.handler).await
+}
+
+/// Binds an ephemeral haven that serves connections with the given handler until [unbind_ephemeral_haven] is called, or the daemon stops.
+pub async fn bind_ephemeral_haven(
+    ctx: &DaemonContext,
+    listen_port: u16,
+    rendezvous: Vec<RelayFingerprint>,
+    handler: HavenHandler,
+) -> anyhow::Result<HavenFingerprint> {
+    let listener = HavenListener::bind_ephemeral(ctx, listen_port, rendezvous).await?;
+    let fingerprint = listener.fingerprint();
+    let listener = PooledListener::new(listener);
+    let task = spawn_owned(ctx, Stage::Intake, "ephemeral_haven", {
+        let handler = handler.clone();
+        async move { serve_listener(listener, &handler).await }
+    });
+    ctx.get(EPHEMERAL_HAVENS).insert(
+        fingerprint,
+        EphemeralHaven {
+            listen_port,
+            handler,
+            _task: task,
+        },
     );
+    tracing::debug!(haven = display(fingerprint), "bound an ephemeral haven");
+    Ok(fingerprint)
+}
+
+/// Stops an ephemeral haven, returning whether there was one with the given fingerprint. The haven tears itself down in the background.
+pub fn unbind_ephemeral_haven(ctx: &DaemonContext, fingerprint: &HavenFingerprint) -> bool {
+    ctx.get(EPHEMERAL_HAVENS).remove(fingerprint).is_some()
+}
+
+async fn serve_listener(listener: PooledListener, handler: &HavenHandler) -> anyhow::Result<()> {
     nursery!({
         loop {
             let client = listener
                 .accept()
                 .await
                 .context("could not accept another from PooledListener")?;
-            let handler = &cfg.handler;
             spawn!(async move {
                 match handler {
                     HavenHandler::TcpService { upstream } => {
@@ -0,0 +1,343 @@
+use std::time::Duration;
+
+use anyhow::Context as _;
+use futures::{AsyncReadExt, AsyncWriteExt};
+use futures_util::TryFutureExt;
+use nursery_macro::nursery;
+use serde::Serialize;
+use smol::net::{TcpListener, TcpStream};
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    context::{DaemonContext, RELAY_GRAPH},
+    control_protocol::{ControlProtocol, NodeStatus, StatusView},
+    stats::STATS,
+    StatusPageConfig,
+};
+
+use super::control_protocol_impl::ControlProtocolImpl;
+
+/// Requests whose head is bigger than this are refused, since no legitimate one comes close.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// How long a client gets to send its request before we hang up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting again after accepting failed, which it mostly does when we're out of file descriptors.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// What the status page shows, gathered from the same places as the control protocol's status.
+#[derive(Serialize)]
+struct StatusPage {
+    #[serde(flatten)]
+    status: NodeStatus,
+    neighbor_count: usize,
+    relay_count: usize,
+    /// Why the node isn't healthy, including the last error of every failed route.
+    problems: Vec<String>,
+}
+
+impl StatusPage {
+    /// Gathers the page. Which havens we host is only for those with the token, so without one they're left out.
+    async fn gather(ctx: &DaemonContext, authorized: bool) -> Self {
+        let mut status = ControlProtocolImpl::new(ctx.clone()).status().await;
+        if !authorized {
+            status.havens.clear();
+        }
+        Self {
+            neighbor_count: status.neighbors.len(),
+            relay_count: ctx.get(RELAY_GRAPH).read().all_nodes().count(),
+            problems: status.problems(),
+            status,
+        }
+    }
+
+    fn to_html(&self) -> String {
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>earendil status</title></head><body>\n",
+        );
+        html += &format!("<p>relays known: {}</p>\n", self.relay_count);
+        html += "<h2>problems</h2>\n<ul>\n";
+        if self.problems.is_empty() {
+            html += "<li>none</li>\n";
+        }
+        for problem in self.problems.iter() {
+            html += &format!("<li>{}</li>\n", escape_html(problem));
+        }
+        html += "</ul>\n<pre>\n";
+        html += &escape_html(&StatusView::new(&self.status, None).to_string());
+        html += "</pre>\n</body></html>\n";
+        html
+    }
+}
+
+/// Serves a read-only status page over plain HTTP: HTML at `/`, JSON at `/status.json`, and counters and histograms for Prometheus to scrape at `/metrics`. Without a token, the havens we host and `/metrics` are left out.
+pub async fn status_page_loop(ctx: &DaemonContext, cfg: StatusPageConfig) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(cfg.listen)
+        .await
+        .with_context(|| format!("could not bind the status page to {}", cfg.listen))?;
+    if cfg.token.is_none() {
+        tracing::info!(
+            listen = display(cfg.listen),
+            "status page has no token, so it leaves out havens and metrics"
+        );
+    }
+    serve(ctx, listener, cfg.token.as_deref()).await
+}
+
+async fn serve(
+    ctx: &DaemonContext,
+    listener: TcpListener,
+    token: Option<&str>,
+) -> anyhow::Result<()> {
+    nursery!(loop {
+        let client = match listener.accept().await {
+            Ok((client, _)) => client,
+            Err(err) => {
+                // one client hanging up before we got to it, or running out of descriptors for a moment, shouldn't take the page down
+                tracing::warn!(
+                    err = debug(err),
+                    "status page could not accept a connection"
+                );
+                smol::Timer::after(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        spawn!(serve_once(ctx, client, token)
+            .map_err(|e| tracing::debug!(err = debug(e), "status page request failed")))
+        .detach();
+    })
+}
+
+async fn serve_once(
+    ctx: &DaemonContext,
+    mut client: TcpStream,
+    token: Option<&str>,
+) -> anyhow::Result<()> {
+    let head = read_head(&mut client)
+        .timeout(REQUEST_TIMEOUT)
+        .await
+        .context("timed out reading the request")??;
+    let authorized = token.is_some();
+    let (status, content_type, body) = match route(&head, token) {
+        Ok(Page::Metrics) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            ctx.get(STATS).to_prometheus(),
+        ),
+        Ok(Page::Html) => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            StatusPage::gather(ctx, authorized).await.to_html(),
+        ),
+        Ok(Page::Json) => (
+            "200 OK",
+            "application/json",
+            serde_json::to_string_pretty(&StatusPage::gather(ctx, authorized).await)?,
+        ),
+        Err(status) => (status, "text/plain; charset=utf-8", format!("{status}\n")),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n",
+        body.len()
+    );
+    if status.starts_with("401") {
+        response += "WWW-Authenticate: Bearer\r\n";
+    }
+    response += "\r\n";
+    response += &body;
+    client.write_all(response.as_bytes()).await?;
+    client.flush().await?;
+    Ok(())
+}
+
+enum Page {
+    Html,
+    Json,
+    Metrics,
+}
+
+/// Decides which page a request is for, or which error status to answer it with. With a token, every page needs it, and without one, there are no metrics.
+fn route(head: &str, token: Option<&str>) -> Result<Page, &'static str> {
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, target) = (
+        request_line.next().unwrap_or_default(),
+        request_line.next().unwrap_or_default(),
+    );
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if method != "GET" {
+        return Err("405 Method Not Allowed");
+    }
+    if let Some(token) = token {
+        let bearer = lines
+            .filter_map(|line| line.split_once(':'))
+            .find_map(|(name, value)| {
+                name.trim()
+                    .eq_ignore_ascii_case("authorization")
+                    .then(|| value.trim().strip_prefix("Bearer ").map(str::trim))
+                    .flatten()
+            });
+        // browsers can't easily send headers, so the token may also come in the query string
+        let in_query = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("token="));
+        let matches = |given: Option<&str>| given.is_some_and(|given| same_token(given, token));
+        if !matches(bearer) && !matches(in_query) {
+            return Err("401 Unauthorized");
+        }
+    }
+    match path {
+        "/" | "/index.html" => Ok(Page::Html),
+        "/status.json" => Ok(Page::Json),
+        "/metrics" if token.is_some() => Ok(Page::Metrics),
+        _ => Err("404 Not Found"),
+    }
+}
+
+/// Compares tokens in constant time, by comparing their hashes, so that how long a refusal takes says nothing about how much of the token was right.
+fn same_token(given: &str, token: &str) -> bool {
+    blake3::hash(given.as_bytes()) == blake3::hash(token.as_bytes())
+}
+
+/// Reads the request line and headers. We never take a body, so whatever follows them is ignored.
+async fn read_head(client: &mut TcpStream) -> anyhow::Result<String> {
+    let mut head = vec![];
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            anyhow::bail!("request head too big");
+        }
+        let n = client.read(&mut buf).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before the request ended");
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use smol::future::FutureExt as _;
+
+    use super::*;
+
+    async fn fetch(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    /// Serves the status page on a port of its own while `client` runs. The port is bound before the client starts, so it never has to wait for it.
+    fn with_status_page<F: std::future::Future<Output = anyhow::Result<()>>>(
+        cfg: serde_json::Value,
+        client: impl FnOnce(DaemonContext, SocketAddr) -> F,
+    ) {
+        let ctx = DaemonContext::new(serde_json::from_value(cfg).unwrap());
+        let token = ctx
+            .init()
+            .status_page
+            .as_ref()
+            .and_then(|cfg| cfg.token.clone());
+        smol::future::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let listen = listener.local_addr().unwrap();
+            client(ctx.clone(), listen)
+                .race(serve(&ctx, listener, token.as_deref()))
+                .await
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn serves_the_neighbor_count() {
+        let cfg = serde_json::json!({
+            "status_page": { "listen": "127.0.0.1:0", "token": "letmein" },
+        });
+        with_status_page(cfg, |ctx, listen| async move {
+            let html = fetch(
+                listen,
+                "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer letmein\r\n\r\n",
+            )
+            .await;
+            assert!(html.starts_with("HTTP/1.1 200 OK"), "{html}");
+            assert!(html.contains("neighbors (0):"), "{html}");
+
+            let json = fetch(
+                listen,
+                "GET /status.json?token=letmein HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            )
+            .await;
+            let body: serde_json::Value =
+                serde_json::from_str(json.split_once("\r\n\r\n").unwrap().1).unwrap();
+            assert_eq!(body["neighbor_count"], 0);
+            assert_eq!(body["relay_count"], 0);
+
+            ctx.get(STATS)
+                .observe("link.rtt_seconds", Duration::from_millis(30));
+            let metrics = fetch(
+                listen,
+                "GET /metrics HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer letmein\r\n\r\n",
+            )
+            .await;
+            assert!(metrics.contains("version=0.0.4"), "{metrics}");
+            assert!(
+                metrics.contains("earendil_link_rtt_seconds_bucket{le=\"0.05\"} 1"),
+                "{metrics}"
+            );
+
+            // without the token, nothing about the node leaks
+            let refused = fetch(listen, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+            assert!(refused.starts_with("HTTP/1.1 401"), "{refused}");
+            assert!(!refused.contains("neighbors"));
+            let refused = fetch(
+                listen,
+                "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer letmeout\r\n\r\n",
+            )
+            .await;
+            assert!(refused.starts_with("HTTP/1.1 401"), "{refused}");
+
+            // and the page is read-only
+            let refused = fetch(
+                listen,
+                "POST / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer letmein\r\n\r\n",
+            )
+            .await;
+            assert!(refused.starts_with("HTTP/1.1 405"), "{refused}");
+            anyhow::Ok(())
+        });
+    }
+
+    #[test]
+    fn without_a_token_havens_and_metrics_stay_private() {
+        let cfg = serde_json::json!({
+            "status_page": { "listen": "127.0.0.1:0" },
+        });
+        with_status_page(cfg, |_, listen| async move {
+            let json = fetch(
+                listen,
+                "GET /status.json HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            )
+            .await;
+            let body: serde_json::Value =
+                serde_json::from_str(json.split_once("\r\n\r\n").unwrap().1).unwrap();
+            assert_eq!(body["havens"], serde_json::json!([]));
+
+            let metrics = fetch(listen, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+            assert!(metrics.starts_with("HTTP/1.1 404"), "{metrics}");
+            anyhow::Ok(())
+        });
+    }
+}
@@ -2,11 +2,46 @@ use sqlx::sqlite::SqliteConnectOptions;
 use sqlx::Pool;
 use sqlx::Row;
 use sqlx::SqlitePool;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Mutex;
 
+use crate::config::StateCompressionConfig;
 use crate::context::{CtxField, DaemonContext};
 
-static DATABASE: CtxField<Option<SqlitePool>> = |ctx| {
+mod graph;
+mod keys;
+
+pub use self::graph::{decode_graph, encode_graph, DecodedGraph, GRAPH_VERSION};
+pub use self::keys::MiscKey;
+
+/// Starts every compressed value in `misc`, followed by a zstd frame. Values without it were stored as they are, either by older daemons or with compression off.
+const COMPRESSED_MARKER: &[u8] = b"\xffearendil-zstd\x00";
+
+/// State caches in use by daemons in this process. Daemons sharing a state cache would clobber each other's state.
+static CLAIMED_STATE_CACHES: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Exclusive use of a state cache file by one daemon, released on drop.
+pub struct StateCacheClaim(PathBuf);
+
+impl StateCacheClaim {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let path = std::path::absolute(path)?;
+        if !CLAIMED_STATE_CACHES.lock().unwrap().insert(path.clone()) {
+            anyhow::bail!("state cache {:?} is already used by another daemon", path)
+        }
+        Ok(Self(path))
+    }
+}
+
+impl Drop for StateCacheClaim {
+    fn drop(&mut self) {
+        CLAIMED_STATE_CACHES.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub(crate) static DATABASE: CtxField<Option<SqlitePool>> = |ctx| {
     tracing::debug!("INITIALIZING DATABASE");
     if let Some(db_path) = &ctx.init().state_cache {
         let options = SqliteConnectOptions::from_str(db_path.to_str().unwrap())
@@ -24,6 +59,81 @@ static DATABASE: CtxField<Option<SqlitePool>> = |ctx| {
             .execute(&pool)
             .await
             .unwrap();
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS settlement_proofs (
+                settlement TEXT NOT NULL,
+                idx INTEGER NOT NULL,
+                proof BLOB NOT NULL,
+                PRIMARY KEY (settlement, idx)
+            );",
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS traffic (
+                bucket INTEGER NOT NULL,
+                neighbor TEXT NOT NULL,
+                packets_in INTEGER NOT NULL,
+                packets_out INTEGER NOT NULL,
+                PRIMARY KEY (bucket, neighbor)
+            );",
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS settlements (
+                unix_secs INTEGER NOT NULL,
+                neighbor TEXT NOT NULL,
+                amount INTEGER NOT NULL,
+                settlement TEXT NOT NULL
+            );",
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS hourly_stats (
+                hour INTEGER PRIMARY KEY,
+                transit_packets INTEGER NOT NULL,
+                transit_bytes INTEGER NOT NULL,
+                terminated_packets INTEGER NOT NULL,
+                terminated_bytes INTEGER NOT NULL,
+                relay_peers INTEGER NOT NULL DEFAULT 0,
+                client_peers INTEGER NOT NULL DEFAULT 0,
+                settlements INTEGER NOT NULL DEFAULT 0,
+                settled INTEGER NOT NULL DEFAULT 0
+            );",
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS hourly_peers (
+                hour INTEGER NOT NULL,
+                neighbor TEXT NOT NULL,
+                relay INTEGER NOT NULL,
+                PRIMARY KEY (hour, neighbor)
+            );",
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS audit_log (
+                seq INTEGER PRIMARY KEY,
+                unix_secs INTEGER NOT NULL,
+                event TEXT NOT NULL,
+                hash BLOB NOT NULL
+            );",
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+            if let Err(e) = consolidate_keys(&pool).await {
+                tracing::warn!(err = debug(e), "could not consolidate state cache keys");
+            }
 
             Some(pool)
         })
@@ -32,26 +142,221 @@ static DATABASE: CtxField<Option<SqlitePool>> = |ctx| {
     }
 };
 
-pub async fn db_write(ctx: &DaemonContext, key: &str, value: Vec<u8>) -> Result<(), sqlx::Error> {
+/// Moves values stored under legacy spellings of a key to the key itself, unless the key already has a newer value, and deletes keys that nothing reads.
+async fn consolidate_keys(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut txn = pool.begin().await?;
+    for key in MiscKey::FIXED {
+        for &legacy in key.legacy_names() {
+            let moved = sqlx::query("UPDATE OR IGNORE misc SET key = ? WHERE key = ?")
+                .bind(key.name())
+                .bind(legacy)
+                .execute(&mut *txn)
+                .await?
+                .rows_affected();
+            if moved > 0 {
+                tracing::info!(from = legacy, to = %key.name(), "moved state cache value to its canonical key");
+            }
+            sqlx::query("DELETE FROM misc WHERE key = ?")
+                .bind(legacy)
+                .execute(&mut *txn)
+                .await?;
+        }
+    }
+    for &dead in keys::DEAD_KEYS {
+        sqlx::query("DELETE FROM misc WHERE key = ?")
+            .bind(dead)
+            .execute(&mut *txn)
+            .await?;
+    }
+    txn.commit().await
+}
+
+pub async fn db_write(
+    ctx: &DaemonContext,
+    key: MiscKey,
+    value: Vec<u8>,
+) -> Result<(), sqlx::Error> {
     if let Some(pool) = ctx.get(DATABASE) {
         sqlx::query("INSERT INTO misc (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value")
-        .bind(key)
-        .bind(value)
+        .bind(key.name())
+        .bind(compress_value(ctx.init().state_compression.as_ref(), value))
         .execute(pool)
         .await?;
     }
     Ok(())
 }
 
-pub async fn db_read(ctx: &DaemonContext, key: &str) -> Result<Option<Vec<u8>>, sqlx::Error> {
+pub async fn db_read(ctx: &DaemonContext, key: MiscKey) -> Result<Option<Vec<u8>>, sqlx::Error> {
     if let Some(pool) = ctx.get(DATABASE) {
         let result = sqlx::query("SELECT value FROM misc WHERE key = ?")
-            .bind(key)
+            .bind(key.name())
             .fetch_optional(pool)
             .await?
-            .map(|row| row.get("value"));
+            .map(|row| decompress_value(row.get("value")))
+            .transpose()
+            .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
         Ok(result)
     } else {
         Ok(None)
     }
 }
+
+/// Compresses a value on its way into `misc`, if compression is on and the value is big enough to be worth it.
+pub(crate) fn compress_value(config: Option<&StateCompressionConfig>, value: Vec<u8>) -> Vec<u8> {
+    let Some(config) = config else {
+        return value;
+    };
+    if value.len() < config.min_bytes {
+        return value;
+    }
+    let mut compressed = COMPRESSED_MARKER.to_vec();
+    match zstd::stream::copy_encode(value.as_slice(), &mut compressed, config.level) {
+        Ok(()) if compressed.len() < value.len() => compressed,
+        Ok(()) => value,
+        Err(e) => {
+            tracing::warn!(err = debug(e), "could not compress a state cache value");
+            value
+        }
+    }
+}
+
+/// Undoes [compress_value], passing through values that were stored uncompressed.
+pub(crate) fn decompress_value(value: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    match value.strip_prefix(COMPRESSED_MARKER) {
+        Some(compressed) => zstd::stream::decode_all(compressed),
+        None => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_topology::{
+        test_util::{adjacency, known_relay},
+        RelayGraph,
+    };
+    use stdcode::StdcodeSerializeExt;
+
+    use crate::ledger::unix_now;
+
+    use super::*;
+
+    fn big_graph() -> RelayGraph {
+        let mut graph = RelayGraph::new();
+        let relays: Vec<_> = (0..200).map(|_| known_relay(&mut graph)).collect();
+        for pair in relays.windows(2) {
+            graph
+                .insert_adjacency(adjacency(&pair[0], &pair[1], unix_now()))
+                .unwrap();
+        }
+        graph
+    }
+
+    #[test]
+    fn compressed_relay_graph_round_trips() {
+        let state_cache =
+            std::env::temp_dir().join(format!("earendil-compress-{}.db", rand::random::<u64>()));
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "state_cache": state_cache,
+                "state_compression": {},
+            }))
+            .unwrap(),
+        );
+        let graph = big_graph();
+        let raw = graph.stdcode();
+        smol::future::block_on(async {
+            db_write(&ctx, MiscKey::RelayGraph, raw.clone())
+                .await
+                .unwrap();
+            db_write(&ctx, MiscKey::Chats, b"hello".to_vec())
+                .await
+                .unwrap();
+
+            let stored: Vec<u8> = sqlx::query("SELECT value FROM misc WHERE key = 'relay_graph'")
+                .fetch_one(ctx.get(DATABASE).as_ref().unwrap())
+                .await
+                .unwrap()
+                .get("value");
+            assert!(stored.starts_with(COMPRESSED_MARKER));
+            assert!(
+                stored.len() < raw.len() * 3 / 4,
+                "{} compressed bytes out of {}",
+                stored.len(),
+                raw.len()
+            );
+
+            let read = db_read(&ctx, MiscKey::RelayGraph).await.unwrap().unwrap();
+            assert_eq!(read, raw);
+            let decoded: RelayGraph = stdcode::deserialize(&read).unwrap();
+            assert_eq!(decoded.all_nodes().count(), graph.all_nodes().count());
+            // too small to bother compressing
+            assert_eq!(
+                db_read(&ctx, MiscKey::Chats).await.unwrap().unwrap(),
+                b"hello".to_vec()
+            );
+        });
+    }
+
+    #[test]
+    fn legacy_keys_are_consolidated() {
+        let state_cache =
+            std::env::temp_dir().join(format!("earendil-keys-{}.db", rand::random::<u64>()));
+        let config = serde_json::json!({ "state_cache": state_cache });
+        let ctx = DaemonContext::new(serde_json::from_value(config.clone()).unwrap());
+        smol::future::block_on(async {
+            let pool = ctx.get(DATABASE).as_ref().unwrap();
+            for (key, value) in [
+                ("relay-graph", b"old graph".to_vec()),
+                ("my-client-id", b"old id".to_vec()),
+                ("client_id", b"new id".to_vec()),
+                ("global_identity", b"secret".to_vec()),
+            ] {
+                sqlx::query("INSERT INTO misc (key, value) VALUES (?, ?)")
+                    .bind(key)
+                    .bind(value)
+                    .execute(pool)
+                    .await
+                    .unwrap();
+            }
+        });
+        drop(ctx);
+
+        let ctx = DaemonContext::new(serde_json::from_value(config).unwrap());
+        smol::future::block_on(async {
+            assert_eq!(
+                db_read(&ctx, MiscKey::RelayGraph).await.unwrap().unwrap(),
+                b"old graph".to_vec()
+            );
+            // a value under the canonical key wins over a legacy one
+            assert_eq!(
+                db_read(&ctx, MiscKey::ClientId).await.unwrap().unwrap(),
+                b"new id".to_vec()
+            );
+            let keys: Vec<String> = sqlx::query("SELECT key FROM misc ORDER BY key")
+                .fetch_all(ctx.get(DATABASE).as_ref().unwrap())
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|row| row.get("key"))
+                .collect();
+            assert_eq!(keys, vec!["client_id", "relay_graph"]);
+        });
+        drop(ctx);
+        let _ = std::fs::remove_file(state_cache);
+    }
+
+    #[test]
+    fn uncompressed_values_still_read() {
+        let raw = vec![7u8; 10000];
+        assert_eq!(decompress_value(raw.clone()).unwrap(), raw);
+        let compressed = compress_value(
+            Some(&StateCompressionConfig {
+                level: 3,
+                min_bytes: 0,
+            }),
+            raw.clone(),
+        );
+        assert!(compressed.len() < 100);
+        assert_eq!(decompress_value(compressed).unwrap(), raw);
+    }
+}
@@ -0,0 +1,272 @@
+use bytes::Bytes;
+use earendil_topology::{
+    legacy::{RelayGraphV0, RelayGraphV1},
+    RelayGraph,
+};
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+use thiserror::Error;
+
+use crate::config::GraphFormat;
+
+/// Starts every persisted relay graph, followed by its envelope.
+const GRAPH_MARKER: &[u8] = b"\xffearendil-graph\x00";
+
+/// The version of the graph layout we persist.
+pub const GRAPH_VERSION: u32 = 2;
+
+/// The version whose descriptors had the overload hint as a field of their own.
+const OVERLOAD_FIELD_GRAPH: u32 = 1;
+
+/// The version bare stdcode graphs from before envelopes count as.
+pub const UNVERSIONED_GRAPH: u32 = 0;
+
+/// What says how to read a persisted graph, always in stdcode.
+#[derive(Serialize, Deserialize)]
+struct GraphEnvelope {
+    format: String,
+    version: u32,
+    payload: Bytes,
+}
+
+/// Why a persisted graph was rejected.
+#[derive(Error, Debug)]
+pub enum GraphDecodeError {
+    #[error("it has layout version {0}, newer than the {GRAPH_VERSION} we know, so a newer daemon must have written it")]
+    TooNew(u32),
+    #[error("it is in the unknown format {0:?}")]
+    UnknownFormat(String),
+    #[error("it is corrupt: {0}")]
+    Corrupt(String),
+}
+
+/// A persisted graph that was read back.
+pub struct DecodedGraph {
+    pub graph: RelayGraph,
+    /// The version the graph was migrated from, if it was persisted in an older one.
+    pub migrated_from: Option<u32>,
+}
+
+impl GraphFormat {
+    fn tag(self) -> &'static str {
+        match self {
+            GraphFormat::Stdcode => "stdcode",
+            GraphFormat::Cbor => "cbor",
+        }
+    }
+
+    fn from_tag(tag: &str) -> Option<Self> {
+        [GraphFormat::Stdcode, GraphFormat::Cbor]
+            .into_iter()
+            .find(|format| format.tag() == tag)
+    }
+}
+
+/// Encodes the graph in `format`, wrapped in an envelope saying so, and which version of the layout it has.
+pub fn encode_graph(graph: &RelayGraph, format: GraphFormat) -> anyhow::Result<Vec<u8>> {
+    let payload = match format {
+        GraphFormat::Stdcode => graph.stdcode(),
+        GraphFormat::Cbor => serde_cbor::to_vec(graph)?,
+    };
+    let envelope = GraphEnvelope {
+        format: format.tag().into(),
+        version: GRAPH_VERSION,
+        payload: payload.into(),
+    };
+    Ok([GRAPH_MARKER, &envelope.stdcode()].concat())
+}
+
+/// Reads back a graph persisted by [encode_graph], migrating older versions.
+pub fn decode_graph(bytes: &[u8]) -> Result<DecodedGraph, GraphDecodeError> {
+    let Some(enveloped) = bytes.strip_prefix(GRAPH_MARKER) else {
+        // bare graphs were persisted both before and after descriptors had the overload hint
+        let graph = stdcode::deserialize::<RelayGraphV0>(bytes)
+            .map(RelayGraph::from)
+            .or_else(|_| stdcode::deserialize::<RelayGraphV1>(bytes).map(RelayGraph::from))
+            .map_err(|e| GraphDecodeError::Corrupt(format!("not a bare stdcode graph: {e}")))?;
+        return Ok(DecodedGraph {
+            graph,
+            migrated_from: Some(UNVERSIONED_GRAPH),
+        });
+    };
+    let envelope: GraphEnvelope = stdcode::deserialize(enveloped)
+        .map_err(|e| GraphDecodeError::Corrupt(format!("bad envelope: {e}")))?;
+    if envelope.version > GRAPH_VERSION {
+        return Err(GraphDecodeError::TooNew(envelope.version));
+    }
+    let format = GraphFormat::from_tag(&envelope.format)
+        .ok_or(GraphDecodeError::UnknownFormat(envelope.format))?;
+    if envelope.version == OVERLOAD_FIELD_GRAPH {
+        let graph: RelayGraphV1 = decode_payload(format, &envelope.payload)?;
+        return Ok(DecodedGraph {
+            graph: graph.into(),
+            migrated_from: Some(OVERLOAD_FIELD_GRAPH),
+        });
+    }
+    Ok(DecodedGraph {
+        graph: decode_payload(format, &envelope.payload)?,
+        migrated_from: None,
+    })
+}
+
+fn decode_payload<T: serde::de::DeserializeOwned>(
+    format: GraphFormat,
+    payload: &[u8],
+) -> Result<T, GraphDecodeError> {
+    match format {
+        GraphFormat::Stdcode => stdcode::deserialize(payload).map_err(|e| e.to_string()),
+        GraphFormat::Cbor => serde_cbor::from_slice(payload).map_err(|e| e.to_string()),
+    }
+    .map_err(GraphDecodeError::Corrupt)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::{AdjacencyDescriptor, IdentityDescriptor};
+    use indexmap::IndexMap;
+
+    use super::*;
+
+    fn graph() -> RelayGraph {
+        let mut graph = RelayGraph::new();
+        for _ in 0..5 {
+            let relay = RelayIdentitySecret::generate();
+            graph
+                .insert_identity(IdentityDescriptor::new(&relay, &DhSecret::generate()))
+                .unwrap();
+        }
+        graph
+    }
+
+    /// Two adjacent relays, laid out as an older version did.
+    fn legacy_graph<D: Serialize>(layout: impl Fn(IdentityDescriptor) -> D) -> Vec<u8> {
+        let relays: Vec<_> = (0..2)
+            .map(|_| {
+                let relay = RelayIdentitySecret::generate();
+                (
+                    relay.public().fingerprint(),
+                    IdentityDescriptor::new(&relay, &DhSecret::generate()),
+                )
+            })
+            .collect();
+        let adjacency = AdjacencyDescriptor {
+            left: relays[0].0.min(relays[1].0),
+            right: relays[0].0.max(relays[1].0),
+            left_sig: Bytes::new(),
+            right_sig: Bytes::new(),
+            unix_timestamp: 0,
+        };
+        let ids = |id: u64| (relays[id as usize].0, id);
+        (
+            2u64,
+            HashMap::from([ids(0), ids(1)]),
+            HashMap::from([(0u64, relays[0].0), (1u64, relays[1].0)]),
+            relays
+                .iter()
+                .enumerate()
+                .map(|(id, (_, descr))| (id as u64, layout(descr.clone())))
+                .collect::<HashMap<_, _>>(),
+            HashMap::from([(0u64, HashSet::from([1u64])), (1u64, HashSet::from([0u64]))]),
+            IndexMap::from([((0u64, 1u64), adjacency)]),
+        )
+            .stdcode()
+    }
+
+    #[test]
+    fn graphs_round_trip_in_every_format() {
+        let graph = graph();
+        for format in [GraphFormat::Stdcode, GraphFormat::Cbor] {
+            let decoded = decode_graph(&encode_graph(&graph, format).unwrap()).unwrap();
+            assert_eq!(decoded.migrated_from, None);
+            assert_eq!(decoded.graph.all_nodes().count(), 5);
+        }
+    }
+
+    #[test]
+    fn bare_graphs_are_migrated() {
+        let decoded = decode_graph(&legacy_graph(|descr| {
+            (
+                descr.identity_pk,
+                descr.onion_pk,
+                descr.sig,
+                descr.unix_timestamp,
+            )
+        }))
+        .unwrap();
+        assert_eq!(decoded.migrated_from, Some(UNVERSIONED_GRAPH));
+        assert_eq!(decoded.graph.all_nodes().count(), 2);
+        assert_eq!(decoded.graph.all_adjacencies().count(), 1);
+        for fingerprint in decoded.graph.all_nodes().collect::<Vec<_>>() {
+            decoded
+                .graph
+                .identity(&fingerprint)
+                .unwrap()
+                .verify()
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn graphs_with_the_overload_field_keep_their_adjacencies() {
+        let payload = legacy_graph(|descr| {
+            (
+                descr.identity_pk,
+                descr.onion_pk,
+                descr.sig,
+                descr.unix_timestamp,
+                true,
+            )
+        });
+        let envelope = GraphEnvelope {
+            format: "stdcode".into(),
+            version: OVERLOAD_FIELD_GRAPH,
+            payload: payload.into(),
+        };
+        let decoded = decode_graph(&[GRAPH_MARKER, &envelope.stdcode()].concat()).unwrap();
+        assert_eq!(decoded.migrated_from, Some(OVERLOAD_FIELD_GRAPH));
+        assert_eq!(decoded.graph.all_adjacencies().count(), 1);
+        assert!(decoded
+            .graph
+            .all_nodes()
+            .all(|fingerprint| decoded.graph.identity(&fingerprint).is_none()));
+    }
+
+    #[test]
+    fn incompatible_graphs_are_rejected() {
+        let enveloped = |format: &str, version, payload: Vec<u8>| {
+            let envelope = GraphEnvelope {
+                format: format.into(),
+                version,
+                payload: payload.into(),
+            };
+            [GRAPH_MARKER, &envelope.stdcode()].concat()
+        };
+        let payload = graph().stdcode();
+
+        assert!(matches!(
+            decode_graph(&enveloped("stdcode", GRAPH_VERSION + 1, payload.clone())),
+            Err(GraphDecodeError::TooNew(version)) if version == GRAPH_VERSION + 1
+        ));
+        assert!(matches!(
+            decode_graph(&enveloped("protobuf", GRAPH_VERSION, payload.clone())),
+            Err(GraphDecodeError::UnknownFormat(format)) if format == "protobuf"
+        ));
+        // a payload in another layout than its version says
+        assert!(matches!(
+            decode_graph(&enveloped(
+                "stdcode",
+                GRAPH_VERSION,
+                b"\x07not a graph".to_vec()
+            )),
+            Err(GraphDecodeError::Corrupt(_))
+        ));
+        assert!(matches!(
+            decode_graph(b"\x07not a graph"),
+            Err(GraphDecodeError::Corrupt(_))
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+use std::borrow::Cow;
+
+/// A key of the `misc` table in the state cache. Every read and write of `misc` goes through one of these, so that what is saved under a key is always loaded from the same key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MiscKey {
+    RelayGraph,
+    Chats,
+    /// The nonces of the latest chats each neighbor pushed us.
+    ChatNonces,
+    RouteMemory,
+    EntryGuards,
+    Debts,
+    ClientId,
+    IdentityRotation,
+    /// The fingerprint pinned on first use for an out-route address.
+    TofuPin(String),
+    /// The state cache migration that last ran.
+    MigrationVersion,
+    /// Where the old daemon kept its relay graph, in a format of its own.
+    LegacyGraph,
+    /// The last audit log entry pruned for being too old, signed like [MiscKey::AuditLogHead].
+    AuditLogPruned,
+    /// The last entry of the audit log, signed by our relay identity, so that removing entries from the end shows.
+    AuditLogHead,
+    /// The SURB bundles we issued and haven't seen expire, with the degarblers of their unused reply blocks.
+    IssuedSurbBundles,
+    /// The SURB bundles issued to us, with their unused reply blocks.
+    ImportedSurbBundles,
+}
+
+impl MiscKey {
+    /// The keys that are the same for every daemon, as opposed to ones like [MiscKey::TofuPin] that are made per address.
+    pub const FIXED: [MiscKey; 14] = [
+        MiscKey::RelayGraph,
+        MiscKey::Chats,
+        MiscKey::ChatNonces,
+        MiscKey::RouteMemory,
+        MiscKey::EntryGuards,
+        MiscKey::Debts,
+        MiscKey::ClientId,
+        MiscKey::IdentityRotation,
+        MiscKey::MigrationVersion,
+        MiscKey::LegacyGraph,
+        MiscKey::AuditLogPruned,
+        MiscKey::AuditLogHead,
+        MiscKey::IssuedSurbBundles,
+        MiscKey::ImportedSurbBundles,
+    ];
+
+    /// What the key is stored as.
+    pub fn name(&self) -> Cow<'static, str> {
+        Cow::Borrowed(match self {
+            MiscKey::RelayGraph => "relay_graph",
+            MiscKey::Chats => "chats",
+            MiscKey::ChatNonces => "chat_nonces",
+            MiscKey::RouteMemory => "route_memory",
+            MiscKey::EntryGuards => "entry_guards",
+            MiscKey::Debts => "debts",
+            MiscKey::ClientId => "client_id",
+            MiscKey::IdentityRotation => "identity_rotation",
+            MiscKey::TofuPin(addr) => return Cow::Owned(format!("tofu_pin:{addr}")),
+            MiscKey::MigrationVersion => "migration_version",
+            MiscKey::LegacyGraph => "graph",
+            MiscKey::AuditLogPruned => "audit_log_pruned",
+            MiscKey::AuditLogHead => "audit_log_head",
+            MiscKey::IssuedSurbBundles => "issued_surb_bundles",
+            MiscKey::ImportedSurbBundles => "imported_surb_bundles",
+        })
+    }
+
+    /// Other spellings of the key that state caches may hold values under, which are moved to [MiscKey::name] when a state cache is opened.
+    pub fn legacy_names(&self) -> &'static [&'static str] {
+        match self {
+            MiscKey::RelayGraph => &["relay-graph"],
+            MiscKey::ClientId => &["my-client-id"],
+            _ => &[],
+        }
+    }
+}
+
+/// Keys that daemons used to write, but that nothing reads. They're deleted when a state cache is opened.
+pub const DEAD_KEYS: &[&str] = &[
+    // held our relay identity secret, even when it was meant to stay off disk
+    "global_identity",
+];
@@ -1,9 +1,19 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
 
-use dashmap::DashMap;
+use async_event::Event;
+use dashmap::{DashMap, DashSet};
 use earendil_crypt::{ClientId, RelayFingerprint};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 
+use crate::micromel::Micromel;
+
+/// How many debt events we keep around for subscribers that haven't caught up yet.
+const MAX_DEBT_EVENTS: usize = 1000;
+
 pub struct Debts {
     client_incoming_prices: DashMap<ClientId, PriceInfo>,
     client_outgoing_prices: DashMap<ClientId, PriceInfo>,
@@ -11,20 +21,78 @@ pub struct Debts {
     relay_outgoing_prices: DashMap<RelayFingerprint, PriceInfo>,
     client_balances: DashMap<ClientId, Balances>,
     relay_balances: DashMap<RelayFingerprint, Balances>,
+    warning_threshold: Option<Micromel>,
+    /// Whether neighbors must pay before we forward for them, rather than running up debt to their debt limit.
+    strict_prepay: bool,
+    /// The fraction of a neighbor's debt limit past which we shed a rising share of its packets, if admission is graduated.
+    graduated_admission: Option<f64>,
+    /// If set, graduated admission sheds by holding packets for up to this long, rather than by dropping them.
+    graduated_delay: Option<Duration>,
+    /// Neighbors whose debt is currently above the warning threshold.
+    warned: DashSet<String>,
+    /// How far a neighbor's view of our balance may be from ours before we warn.
+    divergence_tolerance: Option<Micromel>,
+    /// The last view of our balance each neighbor hinted at, by the neighbor's name.
+    remote_views: DashMap<String, RemoteView>,
+    /// Neighbors whose view of our balance is currently too far from ours.
+    diverged: DashSet<String>,
+    /// Recent debt events, numbered in the order they happened.
+    events: Mutex<VecDeque<(u64, DebtEvent)>>,
+    new_event: Event,
+}
+
+/// How the route a link came up on overrides the daemon-wide debt settings, for what the neighbor sends over that link only. A neighbor linked to us over two routes may be held to different terms on each.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkDebtPolicy {
+    /// Overrides `strict_prepay`.
+    pub strict_prepay: Option<bool>,
+    /// Overrides `graduated_admission`.
+    pub graduated_admission: Option<f64>,
+}
+
+/// What to do with one packet a neighbor sent, given its debt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Admission {
+    Admit,
+    /// Forward it, but only after holding it for this long.
+    Delay(Duration),
+    Refuse,
+}
+
+/// Fired when a neighbor's net debt to us crosses the warning threshold, in either direction.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DebtEvent {
+    /// The neighbor now owes us more than the warning threshold.
+    DebtWarning(String, i128),
+    /// The neighbor's debt dropped back to or below the warning threshold.
+    DebtRecovered(String, i128),
+    /// The neighbor's ledger disagrees with ours by more than the divergence tolerance. Gives how much the neighbor owes us by our ledger, then by theirs.
+    BalanceDiverged(String, i128, i128),
+    /// The neighbor's ledger is back within the divergence tolerance of ours.
+    BalanceAgreed(String),
+}
+
+/// What a neighbor's ledger says about our balance with it, as of its last balance hint.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RemoteView {
+    /// How much the neighbor owes us, by its ledger.
+    pub net_debt: i128,
+    /// How much more the neighbor owes us by our ledger than by its own.
+    pub divergence: i128,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PriceInfo {
-    pub price: u64,
-    pub debt_limit: u64,
+    pub price: Micromel,
+    pub debt_limit: Micromel,
 }
 
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct Balances {
-    client_incoming_balance: u64,
-    client_outgoing_balance: u64,
-    relay_incoming_balance: u64,
-    relay_outgoing_balance: u64,
+    client_incoming_balance: Micromel,
+    client_outgoing_balance: Micromel,
+    relay_incoming_balance: Micromel,
+    relay_outgoing_balance: Micromel,
 }
 
 impl Debts {
@@ -36,10 +104,76 @@ impl Debts {
             relay_outgoing_prices: DashMap::new(),
             client_balances: DashMap::new(),
             relay_balances: DashMap::new(),
+            warning_threshold: None,
+            strict_prepay: false,
+            graduated_admission: None,
+            graduated_delay: None,
+            warned: DashSet::new(),
+            divergence_tolerance: None,
+            remote_views: DashMap::new(),
+            diverged: DashSet::new(),
+            events: Mutex::new(VecDeque::new()),
+            new_event: Event::new(),
+        }
+    }
+
+    /// Sets the net debt above which a neighbor's debt fires a [DebtEvent::DebtWarning].
+    pub fn with_warning_threshold(mut self, warning_threshold: Option<Micromel>) -> Self {
+        self.warning_threshold = warning_threshold;
+        self
+    }
+
+    /// Sets how far a neighbor's view of our balance may be from ours before it fires a [DebtEvent::BalanceDiverged]. Without one, balance hints are only recorded.
+    pub fn with_divergence_tolerance(mut self, divergence_tolerance: Option<Micromel>) -> Self {
+        self.divergence_tolerance = divergence_tolerance;
+        self
+    }
+
+    /// Sets whether neighbors must have no outstanding debt at all before we forward for them.
+    pub fn with_strict_prepay(mut self, strict_prepay: bool) -> Self {
+        self.strict_prepay = strict_prepay;
+        self
+    }
+
+    fn is_strict_prepay(&self, link: &LinkDebtPolicy) -> bool {
+        link.strict_prepay.unwrap_or(self.strict_prepay)
+    }
+
+    /// Sets the fraction of the debt limit past which we start dropping a rising share of a neighbor's packets, reaching all of them at the limit. Without one, neighbors are admitted in full until they're over the limit.
+    pub fn with_graduated_admission(mut self, graduated_admission: Option<f64>) -> Self {
+        self.graduated_admission = graduated_admission;
+        self
+    }
+
+    /// Makes graduated admission hold a neighbor's packets instead of dropping them, for longer the closer it is to its debt limit, up to `max_delay` just below the limit. Packets over the limit are still refused.
+    pub fn with_graduated_delay(mut self, max_delay: Option<Duration>) -> Self {
+        self.graduated_delay = max_delay;
+        self
+    }
+
+    fn graduated_admission(&self, link: &LinkDebtPolicy) -> Option<f64> {
+        link.graduated_admission.or(self.graduated_admission)
+    }
+
+    /// Turns the chance that we'd forward a packet into what to do with it, either shedding it at random or delaying it.
+    fn admission(&self, probability: f64) -> Admission {
+        if probability <= 0.0 {
+            return Admission::Refuse;
+        }
+        match self.graduated_delay {
+            _ if probability >= 1.0 => Admission::Admit,
+            Some(max_delay) => Admission::Delay(max_delay.mul_f64(1.0 - probability)),
+            None if rand::random::<f64>() < probability => Admission::Admit,
+            None => Admission::Refuse,
         }
     }
 
-    pub fn insert_client_incoming_price(&self, neigh: ClientId, price: u64, debt_limit: u64) {
+    pub fn insert_client_incoming_price(
+        &self,
+        neigh: ClientId,
+        price: Micromel,
+        debt_limit: Micromel,
+    ) {
         let _ = self
             .client_incoming_prices
             .insert(neigh, PriceInfo { price, debt_limit });
@@ -48,15 +182,20 @@ impl Debts {
     pub fn insert_relay_incoming_price(
         &self,
         neigh: RelayFingerprint,
-        price: u64,
-        debt_limit: u64,
+        price: Micromel,
+        debt_limit: Micromel,
     ) {
         let _ = self
             .relay_incoming_prices
             .insert(neigh, PriceInfo { price, debt_limit });
     }
 
-    pub fn insert_client_outgoing_price(&self, neigh: ClientId, price: u64, debt_limit: u64) {
+    pub fn insert_client_outgoing_price(
+        &self,
+        neigh: ClientId,
+        price: Micromel,
+        debt_limit: Micromel,
+    ) {
         let _ = self
             .client_outgoing_prices
             .insert(neigh, PriceInfo { price, debt_limit });
@@ -65,8 +204,8 @@ impl Debts {
     pub fn insert_relay_outgoing_price(
         &self,
         neigh: RelayFingerprint,
-        price: u64,
-        debt_limit: u64,
+        price: Micromel,
+        debt_limit: Micromel,
     ) {
         let _ = self
             .relay_outgoing_prices
@@ -76,79 +215,259 @@ impl Debts {
     pub fn incr_relay_outgoing(&self, neigh: RelayFingerprint) {
         if let Some(price_info) = self.relay_outgoing_prices.get(&neigh) {
             let to_add = price_info.price;
-            self.relay_balances
-                .entry(neigh)
-                .or_default()
-                .relay_outgoing_balance += to_add;
+            add_to_balance(
+                &mut self
+                    .relay_balances
+                    .entry(neigh)
+                    .or_default()
+                    .relay_outgoing_balance,
+                to_add,
+            );
+            self.check_warning(neigh.to_string(), self.relay_net_debt_est(&neigh));
         }
     }
 
     pub fn incr_client_incoming(&self, neigh: ClientId) {
         if let Some(price_info) = self.client_incoming_prices.get(&neigh) {
             let to_add = price_info.price;
-            self.client_balances
-                .entry(neigh)
-                .or_default()
-                .client_incoming_balance += to_add;
+            add_to_balance(
+                &mut self
+                    .client_balances
+                    .entry(neigh)
+                    .or_default()
+                    .client_incoming_balance,
+                to_add,
+            );
+            self.check_warning(neigh.to_string(), self.client_net_debt_est(&neigh));
         }
     }
 
     pub fn incr_relay_incoming(&self, neigh: RelayFingerprint) {
         if let Some(price_info) = self.relay_incoming_prices.get(&neigh) {
             let to_add = price_info.price;
-            self.relay_balances
-                .entry(neigh)
-                .or_default()
-                .relay_incoming_balance += to_add;
+            add_to_balance(
+                &mut self
+                    .relay_balances
+                    .entry(neigh)
+                    .or_default()
+                    .relay_incoming_balance,
+                to_add,
+            );
+            self.check_warning(neigh.to_string(), self.relay_net_debt_est(&neigh));
         }
     }
 
-    fn insert_client_incoming(&self, neigh: ClientId, new_debt: u64) {
+    fn insert_client_incoming(&self, neigh: ClientId, new_debt: Micromel) {
         self.client_balances
             .entry(neigh)
             .or_default()
             .client_incoming_balance = new_debt;
+        self.check_warning(neigh.to_string(), self.client_net_debt_est(&neigh));
     }
 
-    fn insert_relay_incoming(&self, neigh: RelayFingerprint, new_debt: u64) {
+    fn insert_relay_incoming(&self, neigh: RelayFingerprint, new_debt: Micromel) {
         self.relay_balances
             .entry(neigh)
             .or_default()
             .relay_incoming_balance = new_debt;
+        self.check_warning(neigh.to_string(), self.relay_net_debt_est(&neigh));
+    }
+
+    /// Fires a debt event if the neighbor's new balance is on the other side of the warning threshold from before.
+    fn check_warning(&self, neighbor: String, balance: Option<i128>) {
+        let (Some(threshold), Some(balance)) = (self.warning_threshold, balance) else {
+            return;
+        };
+        let event = if balance > i128::from(threshold) {
+            if !self.warned.insert(neighbor.clone()) {
+                return;
+            }
+            tracing::warn!(neighbor = %neighbor, balance = %balance, "neighbor's debt crossed the warning threshold");
+            DebtEvent::DebtWarning(neighbor, balance)
+        } else {
+            if self.warned.remove(&neighbor).is_none() {
+                return;
+            }
+            tracing::info!(neighbor = %neighbor, balance = %balance, "neighbor's debt is back under the warning threshold");
+            DebtEvent::DebtRecovered(neighbor, balance)
+        };
+        self.push_event(event);
+    }
+
+    fn push_event(&self, event: DebtEvent) {
+        let mut events = self.events.lock();
+        let seq = events.back().map(|(seq, _)| seq + 1).unwrap_or(1);
+        events.push_back((seq, event));
+        if events.len() > MAX_DEBT_EVENTS {
+            events.pop_front();
+        }
+        drop(events);
+        self.new_event.notify_all();
+    }
+
+    /// Compares our view of the balance with a neighbor to the one it hinted at, which is how much we owe it by its ledger. Fires a debt event when the two drift further apart than the divergence tolerance, or come back within it. The hint is never used for accounting, since the neighbor could say anything.
+    pub fn compare_remote_view(&self, neighbor: String, ours: Option<i128>, theirs: Option<i128>) {
+        if ours.is_none() && theirs.is_none() {
+            return;
+        }
+        let ours = ours.unwrap_or(0);
+        let net_debt = theirs.unwrap_or(0).saturating_neg();
+        let remote = RemoteView {
+            net_debt,
+            divergence: ours.saturating_sub(net_debt),
+        };
+        self.remote_views.insert(neighbor.clone(), remote);
+        let Some(tolerance) = self.divergence_tolerance else {
+            return;
+        };
+        if remote.divergence.unsigned_abs() > u128::from(tolerance.0) {
+            if !self.diverged.insert(neighbor.clone()) {
+                return;
+            }
+            tracing::warn!(
+                neighbor = %neighbor,
+                ours = %ours,
+                theirs = %remote.net_debt,
+                "DEBT VIEWS DIVERGED: our ledger says {neighbor} owes us {ours} micromel, but theirs says {}, so they may start dropping our packets",
+                remote.net_debt
+            );
+            self.push_event(DebtEvent::BalanceDiverged(neighbor, ours, remote.net_debt));
+        } else if self.diverged.remove(&neighbor).is_some() {
+            tracing::info!(neighbor = %neighbor, "neighbor's ledger agrees with ours again");
+            self.push_event(DebtEvent::BalanceAgreed(neighbor));
+        }
+    }
+
+    /// The last view of our balance the neighbor hinted at.
+    pub fn remote_view(&self, neighbor: &str) -> Option<RemoteView> {
+        self.remote_views.get(neighbor).map(|view| *view)
+    }
+
+    /// Waits until there are debt events numbered after `after`, then returns them in order.
+    pub async fn wait_events(&self, after: u64) -> Vec<(u64, DebtEvent)> {
+        self.new_event
+            .wait_until(|| {
+                let events: Vec<_> = self
+                    .events
+                    .lock()
+                    .iter()
+                    .filter(|(seq, _)| *seq > after)
+                    .cloned()
+                    .collect();
+                if events.is_empty() {
+                    None
+                } else {
+                    Some(events)
+                }
+            })
+            .await
     }
 
     pub fn client_net_debt_est(&self, neigh: &ClientId) -> Option<i128> {
-        self.client_balances
-            .get(neigh)
-            .map(|b| b.client_incoming_balance as i128 - b.client_outgoing_balance as i128)
+        self.client_balances.get(neigh).map(|b| {
+            b.client_incoming_balance
+                .signed_sub(b.client_outgoing_balance)
+        })
     }
 
     pub fn relay_net_debt_est(&self, neigh: &RelayFingerprint) -> Option<i128> {
-        self.relay_balances
-            .get(neigh)
-            .map(|b| b.relay_incoming_balance as i128 - b.relay_outgoing_balance as i128)
+        self.relay_balances.get(neigh).map(|b| {
+            b.relay_incoming_balance
+                .signed_sub(b.relay_outgoing_balance)
+        })
     }
 
-    pub fn client_is_within_debt_limit(&self, neigh: &ClientId) -> bool {
-        if let Some(price_info) = self.client_incoming_prices.get(neigh) {
-            if let Some(net) = self.client_net_debt_est(neigh) {
-                if net > price_info.debt_limit as i128 {
-                    return false;
-                }
+    /// Whether a client is within its debt limit, or owes nothing at all under strict prepay. Clients we don't charge always are.
+    pub fn client_is_within_debt_limit(&self, neigh: &ClientId, link: &LinkDebtPolicy) -> bool {
+        let Some(price_info) = self.client_incoming_prices.get(neigh) else {
+            return true;
+        };
+        let net = self.client_net_debt_est(neigh).unwrap_or(0);
+        if self.is_strict_prepay(link) {
+            return net <= 0;
+        }
+        net <= i128::from(price_info.debt_limit)
+    }
+
+    /// Whether a relay is within its debt limit, or owes nothing at all under strict prepay. Relays we don't charge always are.
+    pub fn relay_is_within_debt_limit(
+        &self,
+        neigh: &RelayFingerprint,
+        link: &LinkDebtPolicy,
+    ) -> bool {
+        let Some(price_info) = self.relay_incoming_prices.get(neigh) else {
+            return true;
+        };
+        let net = self.relay_net_debt_est(neigh).unwrap_or(0);
+        if self.is_strict_prepay(link) {
+            return net <= 0;
+        }
+        net <= i128::from(price_info.debt_limit)
+    }
+
+    /// The chance that we forward a packet for this client, given how close it is to its debt limit.
+    pub fn client_admission_probability(&self, neigh: &ClientId, link: &LinkDebtPolicy) -> f64 {
+        if !self.client_is_within_debt_limit(neigh, link) {
+            return 0.0;
+        }
+        match (
+            self.client_incoming_prices.get(neigh),
+            self.client_net_debt_est(neigh),
+        ) {
+            (Some(price_info), Some(net)) => {
+                admission_probability(net, price_info.debt_limit, self.graduated_admission(link))
             }
+            _ => 1.0,
         }
-        true
     }
 
-    pub fn relay_is_within_debt_limit(&self, neigh: &RelayFingerprint) -> bool {
-        if let Some(price_info) = self.relay_incoming_prices.get(neigh) {
-            if let Some(net) = self.relay_net_debt_est(neigh) {
-                if net > price_info.debt_limit as i128 {
-                    return false;
-                }
+    /// The chance that we forward a packet for this relay, given how close it is to its debt limit.
+    pub fn relay_admission_probability(
+        &self,
+        neigh: &RelayFingerprint,
+        link: &LinkDebtPolicy,
+    ) -> f64 {
+        if !self.relay_is_within_debt_limit(neigh, link) {
+            return 0.0;
+        }
+        match (
+            self.relay_incoming_prices.get(neigh),
+            self.relay_net_debt_est(neigh),
+        ) {
+            (Some(price_info), Some(net)) => {
+                admission_probability(net, price_info.debt_limit, self.graduated_admission(link))
             }
+            _ => 1.0,
         }
-        true
+    }
+
+    /// Decides what to do with one packet this client sent over a link with the given policy.
+    pub fn client_admission(&self, neigh: &ClientId, link: &LinkDebtPolicy) -> Admission {
+        self.admission(self.client_admission_probability(neigh, link))
+    }
+
+    /// Decides what to do with one packet this relay sent over a link with the given policy.
+    pub fn relay_admission(&self, neigh: &RelayFingerprint, link: &LinkDebtPolicy) -> Admission {
+        self.admission(self.relay_admission_probability(neigh, link))
+    }
+
+    /// Returns the estimated net debt of every neighbor we have a balance with, keyed by the neighbor's name.
+    pub fn net_debts(&self) -> Vec<(String, i128)> {
+        let client_debts = self.client_balances.iter().map(|entry| {
+            let b = entry.value();
+            let debt = b
+                .client_incoming_balance
+                .signed_sub(b.client_outgoing_balance);
+            (entry.key().to_string(), debt)
+        });
+        let relay_debts = self.relay_balances.iter().map(|entry| {
+            let b = entry.value();
+            let debt = b
+                .relay_incoming_balance
+                .signed_sub(b.relay_outgoing_balance);
+            (entry.key().to_string(), debt)
+        });
+        client_debts.chain(relay_debts).collect()
     }
 
     pub fn list(&self) -> Vec<String> {
@@ -175,22 +494,31 @@ impl Debts {
             .collect::<Vec<String>>()
     }
 
-    pub fn deduct_client_settlement(&self, neigh: ClientId, amount: u64) {
+    pub fn deduct_client_settlement(&self, neigh: ClientId, amount: Micromel) {
         if let Some(current_debt) = self.client_net_debt_est(&neigh) {
-            let debt = current_debt - amount as i128;
-            let settled_debt = if debt > 0 { debt as u64 } else { 0 };
+            let settled_debt = Micromel::clamp_from(current_debt - i128::from(amount));
             self.insert_client_incoming(neigh, settled_debt);
         }
     }
 
-    pub fn deduct_relay_settlement(&self, neigh: RelayFingerprint, amount: u64) {
+    pub fn deduct_relay_settlement(&self, neigh: RelayFingerprint, amount: Micromel) {
         if let Some(current_debt) = self.relay_net_debt_est(&neigh) {
-            let debt = current_debt - amount as i128;
-            let settled_debt = if debt > 0 { debt as u64 } else { 0 };
+            let settled_debt = Micromel::clamp_from(current_debt - i128::from(amount));
             self.insert_relay_incoming(neigh, settled_debt);
         }
     }
 
+    /// Takes a settlement a relay neighbor accepted from us off what we owe them.
+    pub fn deduct_relay_outgoing_settlement(&self, neigh: RelayFingerprint, amount: Micromel) {
+        let owed = self.relay_balances.get_mut(&neigh).map(|mut balances| {
+            balances.relay_outgoing_balance =
+                balances.relay_outgoing_balance.saturating_sub(amount);
+        });
+        if owed.is_some() {
+            self.check_warning(neigh.to_string(), self.relay_net_debt_est(&neigh));
+        }
+    }
+
     pub fn as_bytes(&self) -> anyhow::Result<Vec<u8>> {
         let client_incoming_prices: HashMap<ClientId, PriceInfo> = self
             .client_incoming_prices
@@ -269,6 +597,166 @@ impl Debts {
             relay_outgoing_prices,
             client_balances,
             relay_balances,
+            ..Debts::new()
         })
     }
 }
+
+/// Falls linearly from 1, at `start` of the way to the debt limit, to 0 at the limit.
+fn admission_probability(net_debt: i128, debt_limit: Micromel, start: Option<f64>) -> f64 {
+    let Some(start) = start else {
+        return 1.0;
+    };
+    let limit = i128::from(debt_limit) as f64;
+    let ramp_start = limit * start;
+    let net_debt = net_debt as f64;
+    if net_debt <= ramp_start {
+        1.0
+    } else if net_debt >= limit {
+        0.0
+    } else {
+        (limit - net_debt) / (limit - ramp_start)
+    }
+}
+
+/// Adds to a balance, leaving it alone if that would overflow.
+fn add_to_balance(balance: &mut Micromel, amount: Micromel) {
+    match balance.checked_add(amount) {
+        Some(sum) => *balance = sum,
+        None => tracing::warn!(balance = %balance, amount = %amount, "balance would overflow"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+
+    use super::*;
+
+    #[test]
+    fn debt_warning_fires_once() {
+        let debts = Debts::new().with_warning_threshold(Some(Micromel(100)));
+        let neigh = RelayIdentitySecret::generate().public().fingerprint();
+        debts.insert_relay_incoming_price(neigh, Micromel(30), Micromel(1000));
+        for _ in 0..10 {
+            debts.incr_relay_incoming(neigh);
+        }
+        let events = smol::future::block_on(debts.wait_events(0));
+        assert_eq!(
+            events,
+            vec![(1, DebtEvent::DebtWarning(neigh.to_string(), 120))]
+        );
+
+        debts.deduct_relay_settlement(neigh, Micromel(250));
+        let events = smol::future::block_on(debts.wait_events(1));
+        assert_eq!(
+            events,
+            vec![(2, DebtEvent::DebtRecovered(neigh.to_string(), 50))]
+        );
+    }
+
+    #[test]
+    fn strict_prepay_refuses_any_debt() {
+        let neigh = RelayIdentitySecret::generate().public().fingerprint();
+        let lenient = Debts::new();
+        let strict = Debts::new().with_strict_prepay(true);
+        for debts in [&lenient, &strict] {
+            debts.insert_relay_incoming_price(neigh, Micromel(30), Micromel(1000));
+            debts.incr_relay_incoming(neigh);
+        }
+
+        let default = LinkDebtPolicy::default();
+        // owing 30 is well within the debt limit, but not paid up front
+        assert!(lenient.relay_is_within_debt_limit(&neigh, &default));
+        assert!(!strict.relay_is_within_debt_limit(&neigh, &default));
+
+        // settling brings the neighbor back to forwarding
+        strict.deduct_relay_settlement(neigh, Micromel(30));
+        assert!(strict.relay_is_within_debt_limit(&neigh, &default));
+
+        // and a link's override takes precedence over the default
+        strict.incr_relay_incoming(neigh);
+        let (strict_link, lenient_link) = (
+            LinkDebtPolicy {
+                strict_prepay: Some(true),
+                ..Default::default()
+            },
+            LinkDebtPolicy {
+                strict_prepay: Some(false),
+                ..Default::default()
+            },
+        );
+        assert!(strict.relay_is_within_debt_limit(&neigh, &lenient_link));
+        assert!(!lenient.relay_is_within_debt_limit(&neigh, &strict_link));
+
+        // neighbors we don't charge owe us nothing, however strict we are
+        let unpriced = RelayIdentitySecret::generate().public().fingerprint();
+        strict.incr_relay_incoming(unpriced);
+        assert!(strict.relay_is_within_debt_limit(&unpriced, &strict_link));
+    }
+
+    #[test]
+    fn graduated_admission_sheds_more_near_the_limit() {
+        let neigh = RelayIdentitySecret::generate().public().fingerprint();
+        let debts = Debts::new().with_graduated_admission(Some(0.5));
+        debts.insert_relay_incoming_price(neigh, Micromel(10), Micromel(1000));
+
+        let mut drop_probabilities = vec![];
+        for _ in 0..110 {
+            drop_probabilities
+                .push(1.0 - debts.relay_admission_probability(&neigh, &LinkDebtPolicy::default()));
+            debts.incr_relay_incoming(neigh);
+        }
+        // nothing is dropped until half the limit, then more and more until everything is at the limit
+        assert!(drop_probabilities.windows(2).all(|w| w[0] <= w[1]));
+        assert!(drop_probabilities[..=50].iter().all(|&p| p == 0.0));
+        assert!(drop_probabilities[50..=100].windows(2).all(|w| w[0] < w[1]));
+        assert!(drop_probabilities[100..].iter().all(|&p| p == 1.0));
+        assert!((drop_probabilities[75] - 0.5).abs() < 1e-9);
+
+        // the shedding shows up in which packets get admitted
+        let admitted = |debts: &Debts, link: &LinkDebtPolicy| {
+            (0..1000)
+                .filter(|_| debts.relay_admission(&neigh, link) == Admission::Admit)
+                .count()
+        };
+        debts.deduct_relay_settlement(neigh, Micromel(350));
+        assert!((400..600).contains(&admitted(&debts, &LinkDebtPolicy::default())));
+
+        // and a neighbor whose link is set to 1 is admitted in full right up to the limit, while its other links keep shedding
+        let full = LinkDebtPolicy {
+            graduated_admission: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(admitted(&debts, &full), 1000);
+        assert!(admitted(&debts, &LinkDebtPolicy::default()) < 1000);
+    }
+
+    #[test]
+    fn graduated_delay_holds_longer_near_the_limit() {
+        let neigh = RelayIdentitySecret::generate().public().fingerprint();
+        let debts = Debts::new()
+            .with_graduated_admission(Some(0.5))
+            .with_graduated_delay(Some(Duration::from_millis(1000)));
+        debts.insert_relay_incoming_price(neigh, Micromel(10), Micromel(1000));
+        let link = LinkDebtPolicy::default();
+
+        let mut delays = vec![];
+        for _ in 0..=100 {
+            delays.push(debts.relay_admission(&neigh, &link));
+            debts.incr_relay_incoming(neigh);
+        }
+        // nothing is held until half the limit, then for longer and longer, and nothing is forwarded at the limit
+        assert!(delays[..=50].iter().all(|a| *a == Admission::Admit));
+        let held: Vec<Duration> = delays[51..100]
+            .iter()
+            .map(|a| match a {
+                Admission::Delay(delay) => *delay,
+                other => panic!("expected a delay, got {other:?}"),
+            })
+            .collect();
+        assert!(held.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(held[24], Duration::from_millis(500));
+        assert_eq!(delays[100], Admission::Refuse);
+    }
+}
@@ -1,44 +1,55 @@
-use std::time::Duration;
+use std::{collections::BTreeMap, future::Future, time::Duration};
 
 use anyhow::Context;
 use earendil_crypt::{HavenFingerprint, RelayFingerprint};
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use moka::sync::{Cache, CacheBuilder};
+use serde::{Deserialize, Serialize};
+use smol_timeout::TimeoutExt;
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
+    clock,
     control_protocol::DhtError,
-    global_rpc::{transport::GlobalRpcTransport, GlobalRpcClient},
+    global_rpc::{
+        transport::{GlobalRpcTransport, TransportRole},
+        GlobalRpcClient,
+    },
     haven::HavenLocator,
-    n2r_socket::N2rClientSocket,
+    verified::{check_verified, Verified},
 };
 
 use crate::context::{CtxField, DaemonContext, RELAY_GRAPH};
 
 const DHT_REDUNDANCY: usize = 3;
 
-static DHT_CACHE: CtxField<Cache<HavenFingerprint, HavenLocator>> = |_| {
-    CacheBuilder::default()
-        .time_to_live(Duration::from_secs(60))
-        .build()
-};
+/// How long locators we looked up stay cached.
+pub const DHT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+static DHT_CACHE: CtxField<Cache<HavenFingerprint, HavenLocator>> =
+    |_| CacheBuilder::default().time_to_live(DHT_CACHE_TTL).build();
 
-/// Insert a locator into the DHT.
-pub async fn dht_insert(ctx: &DaemonContext, locator: HavenLocator, n2r_skt: &N2rClientSocket) {
+/// Insert a locator into the DHT, giving each replica `rpc_timeouts.dht_insert_ms` to take it.
+pub async fn dht_insert(ctx: &DaemonContext, locator: HavenLocator) {
     let key = locator.identity_pk.fingerprint();
-    let replicas = dht_key_to_fps(ctx, &key.to_string());
+    let timeout = ctx.init().rpc_timeouts.dht_insert();
     let mut gatherer = FuturesUnordered::new();
 
-    for replica in replicas.into_iter().take(DHT_REDUNDANCY) {
-        let n2r_skt = n2r_skt.clone();
+    for replica in dht_replicas(ctx, key) {
         let locator = locator.clone();
         gatherer.push(async move {
             tracing::trace!("key {key} inserting into remote replica {replica}");
-            let gclient = GlobalRpcClient(GlobalRpcTransport::new(ctx.clone(), replica, n2r_skt));
+            let gclient = GlobalRpcClient(GlobalRpcTransport::cached(
+                ctx,
+                replica,
+                TransportRole::Hosting,
+            )?);
             anyhow::Ok(
                 gclient
                     .dht_insert(locator.clone(), false)
+                    .timeout(timeout)
                     .await
+                    .context("timed out")?
                     .context("DHT insert failed")??,
             )
         })
@@ -51,40 +62,78 @@ pub async fn dht_insert(ctx: &DaemonContext, locator: HavenLocator, n2r_skt: &N2
     }
 }
 
-/// Obtain a locator from the DHT.
+/// Obtain a locator from the DHT, giving each replica `rpc_timeouts.dht_get_ms` to answer.
 pub async fn dht_get(
     ctx: &DaemonContext,
     fingerprint: HavenFingerprint,
-    n2r_skt: &N2rClientSocket,
 ) -> Result<Option<HavenLocator>, DhtError> {
     if let Some(locator) = ctx.get(DHT_CACHE).get(&fingerprint) {
         return Ok(Some(locator));
     }
-    let replicas = dht_key_to_fps(ctx, &fingerprint.to_string());
-
-    let mut gatherer = FuturesUnordered::new();
-    for replica in replicas.into_iter().take(DHT_REDUNDANCY) {
-        let n2r_skt = n2r_skt.clone();
-        gatherer.push(async move {
-            let gclient = GlobalRpcClient(GlobalRpcTransport::new(ctx.clone(), replica, n2r_skt));
+    let replicas = dht_replicas(ctx, fingerprint);
+    let timeout = ctx.init().rpc_timeouts.dht_get();
+    let now_unix = clock::unix_now(ctx);
+    let locator = query_replicas(
+        ctx,
+        fingerprint,
+        replicas,
+        timeout,
+        now_unix,
+        |replica| async move {
+            let gclient = GlobalRpcClient(GlobalRpcTransport::cached(
+                ctx,
+                replica,
+                TransportRole::Visiting,
+            )?);
             anyhow::Ok(gclient.dht_get(fingerprint, false).await?)
-        })
+        },
+    )
+    .await?;
+    // ephemeral havens may go away at any moment, so their locators are looked up afresh every time
+    if let Some(locator) = &locator {
+        if locator.expires_at.is_none() {
+            ctx.get(DHT_CACHE).insert(fingerprint, locator.clone());
+        }
     }
+    Ok(locator)
+}
+
+/// Asks the replicas for a haven's locator all at once, returning the first valid one any of them has.
+async fn query_replicas<F, Fut>(
+    ctx: &DaemonContext,
+    fingerprint: HavenFingerprint,
+    replicas: Vec<RelayFingerprint>,
+    timeout: Duration,
+    now_unix: u64,
+    fetch: F,
+) -> Result<Option<HavenLocator>, DhtError>
+where
+    F: Fn(RelayFingerprint) -> Fut,
+    Fut: Future<Output = anyhow::Result<Result<Option<HavenLocator>, DhtError>>>,
+{
+    let mut gatherer: FuturesUnordered<_> = replicas
+        .into_iter()
+        .map(|replica| fetch(replica).timeout(timeout))
+        .collect();
     let mut retval = Ok(None);
     while let Some(result) = gatherer.next().await {
         match result {
-            Err(err) => retval = Err(DhtError::NetworkFailure(err.to_string())),
-            Ok(Err(err)) => retval = Err(err),
-            Ok(Ok(None)) => continue,
-            Ok(Ok(Some(locator))) => {
+            None => {
+                retval = Err(DhtError::NetworkFailure(format!(
+                    "replica timed out after {timeout:?}"
+                )))
+            }
+            Some(Err(err)) => retval = Err(DhtError::NetworkFailure(err.to_string())),
+            Some(Ok(Err(err))) => retval = Err(err),
+            Some(Ok(Ok(None))) => continue,
+            Some(Ok(Ok(Some(locator)))) => {
                 tracing::debug!("got locator");
-                let id_pk = locator.identity_pk;
-                let payload = locator.to_sign();
-                if id_pk.fingerprint() == fingerprint {
-                    id_pk
-                        .verify(&payload, &locator.signature)
-                        .map_err(|_| DhtError::VerifyFailed)?;
-                    ctx.get(DHT_CACHE).insert(fingerprint, locator.clone());
+                if locator.identity_pk.fingerprint() == fingerprint {
+                    verify_locator(ctx, fingerprint, &locator)?;
+                    if locator.is_expired(now_unix) {
+                        // a tombstone, or a stale replica of an ephemeral haven's locator
+                        continue;
+                    }
                     return Ok(Some(locator));
                 } else {
                     retval = Err(DhtError::VerifyFailed);
@@ -95,8 +144,403 @@ pub async fn dht_get(
     retval
 }
 
+/// How many locators we have cached from lookups.
+pub fn dht_cache_size(ctx: &DaemonContext) -> u64 {
+    let cache = ctx.get(DHT_CACHE);
+    cache.run_pending_tasks();
+    cache.entry_count()
+}
+
+/// The locators we have cached from lookups.
+pub fn dht_cache_entries(ctx: &DaemonContext) -> Vec<(HavenFingerprint, HavenLocator)> {
+    ctx.get(DHT_CACHE)
+        .iter()
+        .map(|(fingerprint, locator)| (*fingerprint, locator))
+        .collect()
+}
+
+/// Caches locators as if we had just looked them up.
+pub fn restore_dht_cache(ctx: &DaemonContext, entries: Vec<(HavenFingerprint, HavenLocator)>) {
+    for (fingerprint, locator) in entries {
+        ctx.get(DHT_CACHE).insert(fingerprint, locator);
+    }
+}
+
+/// Checks that a locator is signed by the haven with the given fingerprint, skipping the signature check if we checked the very same locator lately.
+pub(crate) fn verify_locator(
+    ctx: &DaemonContext,
+    fingerprint: HavenFingerprint,
+    locator: &HavenLocator,
+) -> Result<(), DhtError> {
+    let id_pk = locator.identity_pk;
+    if id_pk.fingerprint() != fingerprint {
+        return Err(DhtError::VerifyFailed);
+    }
+    check_verified(
+        ctx,
+        Verified::HavenLocator(blake3::hash(&locator.stdcode())),
+        || {
+            id_pk
+                .verify(&locator.to_sign(), &locator.signature)
+                .map_err(|_| DhtError::VerifyFailed)
+        },
+    )
+}
+
+/// How many replicas must hold the current locator for a haven to stay findable.
+pub const DHT_QUORUM: usize = DHT_REDUNDANCY / 2 + 1;
+
+/// What one replica answered when asked for a haven's locator.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaState {
+    /// The replica holds the current locator.
+    Current,
+    /// The replica holds a locator that expired, or that differs from the one most replicas hold.
+    Stale,
+    /// The replica has no locator for the haven.
+    Missing,
+    /// The replica returned a locator that isn't signed by the haven.
+    Invalid,
+    /// The replica couldn't be asked, or answered with an error.
+    Failed(String),
+    TimedOut,
+}
+
+/// How well a haven's locator is replicated across the replicas responsible for it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplicationReport {
+    pub haven: HavenFingerprint,
+    /// Every replica responsible for the haven, in the order lookups try them.
+    pub replicas: Vec<(RelayFingerprint, ReplicaState)>,
+    pub quorum: usize,
+    pub meets_quorum: bool,
+}
+
+/// Asks every replica responsible for a haven whether it holds the haven's locator, right now.
+pub async fn check_dht_replication(
+    ctx: &DaemonContext,
+    haven: HavenFingerprint,
+) -> ReplicationReport {
+    let replicas = dht_replicas(ctx, haven);
+    let timeout = ctx.init().rpc_timeouts.replica_check();
+    let now_unix = clock::unix_now(ctx);
+    check_replicas(
+        ctx,
+        haven,
+        replicas,
+        timeout,
+        now_unix,
+        |replica| async move {
+            let gclient = GlobalRpcClient(GlobalRpcTransport::cached(
+                ctx,
+                replica,
+                TransportRole::Visiting,
+            )?);
+            anyhow::Ok(gclient.dht_get(haven, false).await??)
+        },
+    )
+    .await
+}
+
+async fn check_replicas<F, Fut>(
+    ctx: &DaemonContext,
+    haven: HavenFingerprint,
+    replicas: Vec<RelayFingerprint>,
+    timeout: Duration,
+    now_unix: u64,
+    fetch: F,
+) -> ReplicationReport
+where
+    F: Fn(RelayFingerprint) -> Fut,
+    Fut: Future<Output = anyhow::Result<Option<HavenLocator>>>,
+{
+    let answers = futures_util::future::join_all(
+        replicas
+            .iter()
+            .map(|replica| fetch(*replica).timeout(timeout)),
+    )
+    .await;
+    // the locator most replicas hold is the current one, and the others are left over from before it
+    let mut counts: BTreeMap<[u8; 32], usize> = BTreeMap::new();
+    for locator in answers.iter().filter_map(|answer| match answer {
+        Some(Ok(Some(locator)))
+            if verify_locator(ctx, haven, locator).is_ok() && !locator.is_expired(now_unix) =>
+        {
+            Some(locator)
+        }
+        _ => None,
+    }) {
+        *counts.entry(locator.to_sign()).or_default() += 1;
+    }
+    let current = counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(hash, _)| hash);
+
+    let replicas: Vec<(RelayFingerprint, ReplicaState)> = replicas
+        .into_iter()
+        .zip(answers)
+        .map(|(replica, answer)| {
+            let state = match answer {
+                None => ReplicaState::TimedOut,
+                Some(Err(err)) => ReplicaState::Failed(err.to_string()),
+                Some(Ok(None)) => ReplicaState::Missing,
+                Some(Ok(Some(locator))) if verify_locator(ctx, haven, &locator).is_err() => {
+                    ReplicaState::Invalid
+                }
+                Some(Ok(Some(locator)))
+                    if locator.is_expired(now_unix) || Some(locator.to_sign()) != current =>
+                {
+                    ReplicaState::Stale
+                }
+                Some(Ok(Some(_))) => ReplicaState::Current,
+            };
+            (replica, state)
+        })
+        .collect();
+    let current_count = replicas
+        .iter()
+        .filter(|(_, state)| *state == ReplicaState::Current)
+        .count();
+    ReplicationReport {
+        haven,
+        replicas,
+        quorum: DHT_QUORUM,
+        meets_quorum: current_count >= DHT_QUORUM,
+    }
+}
+
+/// The relays that hold a haven's locator under the current relay graph, in the order lookups try them. Inserts, lookups, and replication checks all go to exactly these.
+pub fn dht_replicas(ctx: &DaemonContext, haven: HavenFingerprint) -> Vec<RelayFingerprint> {
+    dht_key_to_fps(ctx, &haven.to_string())
+        .into_iter()
+        .take(DHT_REDUNDANCY)
+        .collect()
+}
+
 fn dht_key_to_fps(ctx: &DaemonContext, key: &str) -> Vec<RelayFingerprint> {
     let mut all_nodes: Vec<RelayFingerprint> = ctx.get(RELAY_GRAPH).read().all_nodes().collect();
     all_nodes.sort_unstable_by_key(|fp| *blake3::hash(&(key, fp).stdcode()).as_bytes());
     all_nodes
 }
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::{HavenIdentitySecret, RelayIdentitySecret};
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::IdentityDescriptor;
+
+    use crate::clock::Clock;
+
+    use super::*;
+
+    fn relay() -> RelayFingerprint {
+        RelayIdentitySecret::generate().public().fingerprint()
+    }
+
+    #[test]
+    fn replica_sets_are_deterministic() {
+        let identities: Vec<RelayIdentitySecret> =
+            (0..10).map(|_| RelayIdentitySecret::generate()).collect();
+        let with_graph = |identities: &mut dyn Iterator<Item = &RelayIdentitySecret>| {
+            let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+            for identity in identities {
+                ctx.get(RELAY_GRAPH)
+                    .write()
+                    .insert_identity(IdentityDescriptor::new(identity, &DhSecret::generate()))
+                    .unwrap();
+            }
+            ctx
+        };
+        let ctx = with_graph(&mut identities.iter());
+        let haven = HavenIdentitySecret::generate().public().fingerprint();
+        let replicas = dht_replicas(&ctx, haven);
+        assert_eq!(replicas.len(), DHT_REDUNDANCY);
+        assert_eq!(dht_replicas(&ctx, haven), replicas);
+        // however the graph was learned
+        let reversed = with_graph(&mut identities.iter().rev());
+        assert_eq!(dht_replicas(&reversed, haven), replicas);
+
+        // and they're the relays closest to the key, which inserts go to as well
+        let mut closest: Vec<RelayFingerprint> = identities
+            .iter()
+            .map(|identity| identity.public().fingerprint())
+            .collect();
+        closest.sort_by_key(|fp| *blake3::hash(&(haven.to_string(), fp).stdcode()).as_bytes());
+        assert_eq!(replicas, closest[..DHT_REDUNDANCY]);
+    }
+
+    #[test]
+    fn replicas_in_mixed_states() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let haven = HavenIdentitySecret::generate();
+        let fingerprint = haven.public().fingerprint();
+        let current = HavenLocator::new(haven, DhSecret::generate().public(), relay(), 1);
+        let outdated = HavenLocator::new(haven, DhSecret::generate().public(), relay(), 1);
+        let tombstone = current.clone().expiring(haven, 1);
+        let forged = HavenLocator::new(
+            HavenIdentitySecret::generate(),
+            DhSecret::generate().public(),
+            relay(),
+            1,
+        );
+
+        let replicas: Vec<RelayFingerprint> = (0..8).map(|_| relay()).collect();
+        let answer = |replica: RelayFingerprint| {
+            let i = replicas.iter().position(|r| *r == replica).unwrap();
+            let (current, outdated, tombstone, forged) = (
+                current.clone(),
+                outdated.clone(),
+                tombstone.clone(),
+                forged.clone(),
+            );
+            async move {
+                match i {
+                    0 | 1 => Ok(Some(current)),
+                    2 => Ok(Some(outdated)),
+                    3 => Ok(Some(tombstone)),
+                    4 => Ok(None),
+                    5 => Ok(Some(forged)),
+                    6 => anyhow::bail!("connection refused"),
+                    _ => smol::future::pending().await,
+                }
+            }
+        };
+        let report = smol::future::block_on(check_replicas(
+            &ctx,
+            fingerprint,
+            replicas.clone(),
+            Duration::from_millis(100),
+            Clock::Real.unix_now(),
+            answer,
+        ));
+        let states: Vec<ReplicaState> = report.replicas.iter().map(|(_, s)| s.clone()).collect();
+        assert_eq!(
+            states,
+            vec![
+                ReplicaState::Current,
+                ReplicaState::Current,
+                ReplicaState::Stale,
+                ReplicaState::Stale,
+                ReplicaState::Missing,
+                ReplicaState::Invalid,
+                ReplicaState::Failed("connection refused".into()),
+                ReplicaState::TimedOut,
+            ]
+        );
+        assert_eq!(
+            report.replicas.iter().map(|(r, _)| *r).collect::<Vec<_>>(),
+            replicas
+        );
+        assert!(report.meets_quorum);
+
+        // one current replica isn't enough
+        let report = smol::future::block_on(check_replicas(
+            &ctx,
+            fingerprint,
+            replicas[1..5].to_vec(),
+            Duration::from_millis(100),
+            Clock::Real.unix_now(),
+            answer,
+        ));
+        assert!(!report.meets_quorum);
+    }
+
+    #[test]
+    fn lookups_give_up_on_slow_replicas_at_the_configured_timeout() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "rpc_timeouts": { "dht_get_ms": 300 }
+            }))
+            .unwrap(),
+        );
+        let timeout = ctx.init().rpc_timeouts.dht_get();
+        assert_eq!(timeout, Duration::from_millis(300));
+
+        let haven = HavenIdentitySecret::generate();
+        let fingerprint = haven.public().fingerprint();
+        let locator = HavenLocator::new(haven, DhSecret::generate().public(), relay(), 1);
+        let (slow, empty) = (relay(), relay());
+        let answer = |delay: Duration| {
+            let locator = locator.clone();
+            move |replica: RelayFingerprint| {
+                let locator = locator.clone();
+                async move {
+                    if replica == slow {
+                        smol::Timer::after(delay).await;
+                        Ok(Ok(Some(locator)))
+                    } else {
+                        Ok(Ok(None))
+                    }
+                }
+            }
+        };
+
+        let start = std::time::Instant::now();
+        let result = smol::future::block_on(query_replicas(
+            &ctx,
+            fingerprint,
+            vec![slow, empty],
+            timeout,
+            Clock::Real.unix_now(),
+            answer(Duration::from_secs(5)),
+        ));
+        let elapsed = start.elapsed();
+        assert!(
+            matches!(&result, Err(DhtError::NetworkFailure(err)) if err.contains("timed out")),
+            "{result:?}"
+        );
+        assert!(
+            elapsed >= timeout && elapsed < Duration::from_secs(2),
+            "{elapsed:?}"
+        );
+
+        // a replica that's slow, but not that slow, still gets its answer through
+        let result = smol::future::block_on(query_replicas(
+            &ctx,
+            fingerprint,
+            vec![slow, empty],
+            timeout,
+            Clock::Real.unix_now(),
+            answer(Duration::from_millis(50)),
+        ));
+        assert!(matches!(result, Ok(Some(_))));
+    }
+
+    #[test]
+    fn expiry_is_judged_by_our_clock() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let haven = HavenIdentitySecret::generate();
+        let fingerprint = haven.public().fingerprint();
+        let now = Clock::Real.unix_now();
+        let live = HavenLocator::new(haven, DhSecret::generate().public(), relay(), 1)
+            .expiring(haven, now + 30);
+        let tombstone = live.clone().expiring(haven, 0);
+        let (withdrawn, current) = (relay(), relay());
+        // replicas hand out whatever they hold, however their own clocks read it
+        let answer = |replica: RelayFingerprint| {
+            let locator = if replica == withdrawn {
+                tombstone.clone()
+            } else {
+                live.clone()
+            };
+            async move { Ok(Ok(Some(locator))) }
+        };
+        let query = |now_unix| {
+            smol::future::block_on(query_replicas(
+                &ctx,
+                fingerprint,
+                vec![withdrawn, current],
+                Duration::from_secs(1),
+                now_unix,
+                answer,
+            ))
+        };
+        let found = |now_unix| query(now_unix).unwrap().map(|locator| locator.to_sign());
+        assert_eq!(found(now), Some(live.to_sign()));
+        // the tombstone reads as expired even to a clock far behind
+        assert_eq!(found(0), Some(live.to_sign()));
+        assert!(found(now + 30).is_none());
+    }
+}
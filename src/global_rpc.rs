@@ -1,4 +1,5 @@
 mod bicache;
+pub mod pending;
 pub mod server;
 pub mod transport;
 
@@ -8,15 +9,19 @@ use earendil_crypt::HavenFingerprint;
 use earendil_crypt::VerifyError;
 use earendil_packet::Dock;
 
-use nanorpc::nanorpc_derive;
+use nanorpc::{nanorpc_derive, JrpcId, JrpcRequest};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     control_protocol::DhtError,
-    haven::{HavenLocator, RegisterHavenReq},
+    haven::{DeregisterHavenReq, HavenLocator, RegisterHavenError, RegisterHavenReq},
 };
 
 pub const GLOBAL_RPC_DOCK: Dock = 100001;
 
+/// Dock on which relays serve GlobalRpc requests sealed end-to-end to their onion keys.
+pub const GLOBAL_RPC_SEALED_DOCK: Dock = 100003;
+
 #[nanorpc_derive]
 #[async_trait]
 pub trait GlobalRpcProtocol {
@@ -30,5 +35,40 @@ pub trait GlobalRpcProtocol {
         recurse: bool,
     ) -> Result<Option<HavenLocator>, DhtError>;
 
-    async fn alloc_forward(&self, forward_req: RegisterHavenReq) -> Result<(), VerifyError>;
+    /// Has us forward a haven's traffic, unless we don't offer rendezvous or already forward for as many havens as we take.
+    async fn alloc_forward(&self, forward_req: RegisterHavenReq) -> Result<(), RegisterHavenError>;
+
+    /// Stops forwarding to a haven registered through [GlobalRpcProtocol::alloc_forward].
+    async fn dealloc_forward(&self, dealloc_req: DeregisterHavenReq) -> Result<(), VerifyError>;
+
+    /// Reports progress `steps` times, `interval_ms` apart, then returns `steps`. For checking that progress from long-running calls makes it back to the caller. Only relays built with the `progress-probe` feature do this; others return 0 right away.
+    async fn progress_probe(&self, steps: u32, interval_ms: u64) -> u32;
+}
+
+/// A GlobalRpc request, and whether the caller wants to hear about its progress while it runs. Older relays ignore the flag, and never send progress.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GlobalRpcRequest {
+    #[serde(flatten)]
+    pub req: JrpcRequest,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub want_progress: bool,
+}
+
+/// An interim update on a GlobalRpc call that is still running. Updates are advisory: they may be lost, or arrive out of order, which `seq` tells apart. Only the response ends a call.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GlobalRpcProgress {
+    /// Counts up from 1 over the updates of each call.
+    pub seq: u64,
+    #[serde(default)]
+    pub percent: Option<f32>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// A progress update on the wire, tagged with the call it's about. It has none of the fields of a response, so the two can't be mistaken for each other.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProgressMsg {
+    pub progress_for: JrpcId,
+    #[serde(flatten)]
+    pub progress: GlobalRpcProgress,
 }
@@ -34,4 +34,19 @@ impl<
     pub fn get_by_value(&self, v: &V) -> Option<K> {
         self.v_to_k.get(v)
     }
+
+    /// How many distinct values are cached. Keys that were replaced by another key for the same value may linger until they expire, so this is what to count rather than keys.
+    pub fn value_count(&self) -> u64 {
+        self.v_to_k.run_pending_tasks();
+        self.v_to_k.entry_count()
+    }
+
+    pub fn remove_by_key(&self, k: &K) {
+        if let Some(v) = self.k_to_v.remove(k) {
+            // the value may have been registered under another key since
+            if self.v_to_k.get(&v).as_ref() == Some(k) {
+                self.v_to_k.invalidate(&v);
+            }
+        }
+    }
 }
@@ -0,0 +1,378 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use earendil_crypt::RelayFingerprint;
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol::channel::{Receiver, Sender};
+use thiserror::Error;
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    stats::STATS,
+};
+
+pub const GLOBAL_RPC_CANCELLED: &str = "global_rpc.cancelled";
+
+/// How many finished sends we remember the outcome of, so that cancelling one that just finished reports how it went.
+const FINISHED_OUTCOMES: usize = 1024;
+
+/// Where a send is at.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendState {
+    /// Queued behind earlier calls on the same socket.
+    WaitingTurn,
+    /// Being handed to the socket.
+    Sending,
+    /// Sent, and waiting for the response until the next retry.
+    AwaitingReply,
+}
+
+/// A GlobalRPC call that hasn't finished, as [pending_sends] reports it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingSend {
+    /// What [cancel_send] knows the send by.
+    pub id: u64,
+    /// The socket it's sent from, by the id `skt-info` prints.
+    pub socket_id: u64,
+    pub destination: RelayFingerprint,
+    pub method: String,
+    /// How long ago the send started, in milliseconds.
+    pub age_ms: u64,
+    pub state: SendState,
+    /// How many times the request was sent again after getting no response in time.
+    pub retries: u32,
+}
+
+/// How a send ended.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendOutcome {
+    Cancelled,
+    Succeeded,
+    Failed,
+}
+
+/// What a cancelled send returns to whoever is waiting on it.
+#[derive(Error, Debug, Clone, Copy)]
+#[error("the send was cancelled")]
+pub struct SendCancelled;
+
+struct Tracked {
+    socket_id: u64,
+    destination: RelayFingerprint,
+    method: String,
+    started: Instant,
+    state: SendState,
+    retries: u32,
+    /// Dropped to cancel the send.
+    _cancel: Sender<()>,
+}
+
+struct Registry {
+    active: HashMap<u64, Tracked>,
+    finished: LruCache<u64, SendOutcome>,
+}
+
+/// Sends that haven't finished, and how the latest finished ones ended. One lock over both, so that a send is always in exactly one of them.
+static PENDING: CtxField<Mutex<Registry>> = |_| {
+    Mutex::new(Registry {
+        active: HashMap::new(),
+        finished: LruCache::new(
+            NonZeroUsize::new(FINISHED_OUTCOMES).expect("must remember at least one outcome"),
+        ),
+    })
+};
+
+static NEXT_SEND_ID: CtxField<AtomicU64> = |_| AtomicU64::new(1);
+
+/// A send listed by [pending_sends] for as long as it lives.
+pub struct TrackedSend {
+    ctx: DaemonContext,
+    id: u64,
+    cancelled: Receiver<()>,
+}
+
+/// Starts tracking a call of `method` from the socket with the given id to `destination`, waiting for its turn.
+pub fn track(
+    ctx: &DaemonContext,
+    socket_id: u64,
+    destination: RelayFingerprint,
+    method: &str,
+) -> TrackedSend {
+    let id = ctx.get(NEXT_SEND_ID).fetch_add(1, Ordering::Relaxed);
+    let (send_cancel, recv_cancel) = smol::channel::bounded(1);
+    ctx.get(PENDING).lock().active.insert(
+        id,
+        Tracked {
+            socket_id,
+            destination,
+            method: method.to_string(),
+            started: Instant::now(),
+            state: SendState::WaitingTurn,
+            retries: 0,
+            _cancel: send_cancel,
+        },
+    );
+    TrackedSend {
+        ctx: ctx.clone(),
+        id,
+        cancelled: recv_cancel,
+    }
+}
+
+impl TrackedSend {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_state(&self, state: SendState, retries: u32) {
+        if let Some(tracked) = self.ctx.get(PENDING).lock().active.get_mut(&self.id) {
+            tracked.state = state;
+            tracked.retries = retries;
+        }
+    }
+
+    /// Drives `send` until it finishes, or until the send is cancelled, which drops it and fails with [SendCancelled].
+    pub async fn run<T>(&self, send: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+        let res = smol::future::or(send, async {
+            let _ = self.cancelled.recv().await;
+            Err(SendCancelled.into())
+        })
+        .await;
+        self.finish(res)
+    }
+
+    /// Records how the send ended. If it was cancelled in the meantime, that's how it ended, whatever `res` says.
+    fn finish<T>(&self, res: anyhow::Result<T>) -> anyhow::Result<T> {
+        let mut registry = self.ctx.get(PENDING).lock();
+        if registry.active.remove(&self.id).is_none() {
+            return Err(SendCancelled.into());
+        }
+        let outcome = if res.is_ok() {
+            SendOutcome::Succeeded
+        } else {
+            SendOutcome::Failed
+        };
+        registry.finished.put(self.id, outcome);
+        res
+    }
+}
+
+impl Drop for TrackedSend {
+    fn drop(&mut self) {
+        // whoever waited on the send gave up on it
+        self.ctx.get(PENDING).lock().active.remove(&self.id);
+    }
+}
+
+/// The sends that haven't finished, oldest first, optionally only those from the socket with the given id.
+pub fn pending_sends(ctx: &DaemonContext, socket_id: Option<u64>) -> Vec<PendingSend> {
+    let now = Instant::now();
+    let mut sends: Vec<PendingSend> = ctx
+        .get(PENDING)
+        .lock()
+        .active
+        .iter()
+        .filter(|(_, tracked)| socket_id.map_or(true, |id| tracked.socket_id == id))
+        .map(|(id, tracked)| PendingSend {
+            id: *id,
+            socket_id: tracked.socket_id,
+            destination: tracked.destination,
+            method: tracked.method.clone(),
+            age_ms: now.saturating_duration_since(tracked.started).as_millis() as u64,
+            state: tracked.state,
+            retries: tracked.retries,
+        })
+        .collect();
+    sends.sort_unstable_by_key(|send| (std::cmp::Reverse(send.age_ms), send.id));
+    sends
+}
+
+/// Cancels the send with the given id, stopping its retries and failing it with [SendCancelled]. Returns how the send ended, which is something else if it finished first, or `None` if there never was such a send or it finished too long ago.
+pub fn cancel_send(ctx: &DaemonContext, id: u64) -> Option<SendOutcome> {
+    let mut registry = ctx.get(PENDING).lock();
+    match registry.active.remove(&id) {
+        Some(_) => {
+            registry.finished.put(id, SendOutcome::Cancelled);
+            drop(registry);
+            ctx.get(STATS).incr(GLOBAL_RPC_CANCELLED);
+            Some(SendOutcome::Cancelled)
+        }
+        None => registry.finished.get(&id).copied(),
+    }
+}
+
+/// Cancels every send from the socket with the given id, or only those of them to `destination`, returning the ids of the sends cancelled.
+pub fn cancel_all(
+    ctx: &DaemonContext,
+    socket_id: u64,
+    destination: Option<RelayFingerprint>,
+) -> Vec<u64> {
+    let mut registry = ctx.get(PENDING).lock();
+    let mut cancelled: Vec<u64> = registry
+        .active
+        .iter()
+        .filter(|(_, tracked)| {
+            tracked.socket_id == socket_id
+                && destination.map_or(true, |dest| tracked.destination == dest)
+        })
+        .map(|(id, _)| *id)
+        .collect();
+    cancelled.sort_unstable();
+    for id in cancelled.iter() {
+        registry.active.remove(id);
+        registry.finished.put(*id, SendOutcome::Cancelled);
+    }
+    drop(registry);
+    if !cancelled.is_empty() {
+        ctx.get(STATS)
+            .add(GLOBAL_RPC_CANCELLED, cancelled.len() as u64);
+    }
+    cancelled
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use earendil_crypt::{AnonEndpoint, RelayIdentitySecret};
+    use smol::channel::Receiver;
+
+    use super::*;
+    use crate::{
+        context::RELAY_GRAPH,
+        global_rpc::{transport::GlobalRpcTransport, GlobalRpcClient},
+        ledger::unix_now,
+        n2r::forget_reply_blocks,
+        n2r_socket::N2rClientSocket,
+        network::{subscribe_outgoing_relay, RelayLinkMsg},
+        test_util::{adjacency, known_relay},
+    };
+
+    fn ctx() -> DaemonContext {
+        DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap())
+    }
+
+    /// A destination the relay graph routes to through a neighbor of its own, whose link nobody reads, so that calls to the destination are sent fine but never answered.
+    fn unreachable_destination(ctx: &DaemonContext) -> (RelayFingerprint, Receiver<RelayLinkMsg>) {
+        let [neigh, dest] = [(); 2].map(|_| known_relay(ctx));
+        ctx.get(RELAY_GRAPH)
+            .write()
+            .insert_adjacency(adjacency(&neigh, &dest, unix_now()))
+            .unwrap();
+        let link = subscribe_outgoing_relay(ctx, neigh.public().fingerprint());
+        (dest.public().fingerprint(), link)
+    }
+
+    /// Calls `ping` on `dest` through a transport of its own, returning the socket it's sent from and the call.
+    fn call(
+        ctx: &DaemonContext,
+        dest: RelayFingerprint,
+        nacks: bool,
+    ) -> (N2rClientSocket, smol::Task<anyhow::Result<u64>>) {
+        let socket = N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random()).unwrap();
+        let transport = GlobalRpcTransport::new(ctx.clone(), dest, socket.clone());
+        let transport = if nacks {
+            transport.with_nacks()
+        } else {
+            transport
+        };
+        let task = smol::spawn(async move { GlobalRpcClient(transport).ping(7).await });
+        (socket, task)
+    }
+
+    async fn wait_for_sends(ctx: &DaemonContext, count: usize) -> Vec<PendingSend> {
+        loop {
+            let sends = pending_sends(ctx, None);
+            if sends.len() == count
+                && sends
+                    .iter()
+                    .all(|send| send.state == SendState::AwaitingReply)
+            {
+                return sends;
+            }
+            smol::Timer::after(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn stuck_calls_are_listed_and_cancelled() {
+        let ctx = ctx();
+        let (unreachable, _link) = unreachable_destination(&ctx);
+        let (other, _other_link) = unreachable_destination(&ctx);
+        smol::future::block_on(async {
+            // calls waiting on a response, and calls that would also fail as soon as a relay NACKed them, both only end when cancelled
+            let (first_skt, first) = call(&ctx, unreachable, false);
+            let (nackable_skt, nackable) = call(&ctx, unreachable, true);
+            let (_, elsewhere) = call(&ctx, other, false);
+            let listed = wait_for_sends(&ctx, 3).await;
+            assert!(listed.iter().all(|send| send.method == "ping"));
+            let first_id = pending_sends(&ctx, Some(first_skt.socket_id()))[0].id;
+            assert_eq!(
+                pending_sends(&ctx, Some(nackable_skt.socket_id()))[0].destination,
+                unreachable
+            );
+
+            assert_eq!(cancel_send(&ctx, first_id), Some(SendOutcome::Cancelled));
+            assert!(first.await.unwrap_err().is::<SendCancelled>());
+            assert_eq!(
+                cancel_all(&ctx, nackable_skt.socket_id(), Some(unreachable)).len(),
+                1
+            );
+            assert!(nackable.await.unwrap_err().is::<SendCancelled>());
+
+            // the cancelled calls took their tracker entries and the reply blocks they left at the destination with them
+            let listed = pending_sends(&ctx, None);
+            assert_eq!(listed.len(), 1);
+            assert_eq!(listed[0].destination, other);
+            for socket in [&first_skt, &nackable_skt] {
+                assert_eq!(
+                    forget_reply_blocks(&ctx, socket.local_endpoint(), unreachable),
+                    0
+                );
+            }
+
+            // a call whose caller gives up stops being listed too
+            elsewhere.cancel().await;
+            assert!(pending_sends(&ctx, None).is_empty());
+            assert_eq!(ctx.get(STATS).snapshot()[GLOBAL_RPC_CANCELLED], 2);
+        });
+    }
+
+    #[test]
+    fn cancelling_a_finished_call_reports_how_it_ended() {
+        let ctx = ctx();
+        // nothing routes to a destination the relay graph doesn't know, so calls to it fail right away
+        let nowhere = RelayIdentitySecret::generate().public().fingerprint();
+        smol::future::block_on(async {
+            let (socket, failed) = call(&ctx, nowhere, false);
+            assert!(failed.await.is_err());
+            let id = ctx.get(NEXT_SEND_ID).load(Ordering::Relaxed) - 1;
+            assert_eq!(cancel_send(&ctx, id), Some(SendOutcome::Failed));
+            assert!(pending_sends(&ctx, Some(socket.socket_id())).is_empty());
+
+            // no relay answers calls in here, so a call that succeeds is run by hand
+            let tracked = track(&ctx, 1, nowhere, "ping");
+            tracked.run(async { anyhow::Ok(()) }).await.unwrap();
+            assert_eq!(
+                cancel_send(&ctx, tracked.id()),
+                Some(SendOutcome::Succeeded)
+            );
+            assert_eq!(cancel_send(&ctx, u64::MAX), None);
+        });
+        assert_eq!(
+            ctx.get(STATS)
+                .snapshot()
+                .get(GLOBAL_RPC_CANCELLED)
+                .copied()
+                .unwrap_or(0),
+            0
+        );
+    }
+}
@@ -1,28 +1,111 @@
-use std::time::Duration;
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use moka::sync::Cache;
+use nanorpc::RpcService;
+use smol::{channel::Sender, future::FutureExt as _};
 
 use crate::{
     context::{CtxField, DaemonContext},
     control_protocol::DhtError,
-    dht::{dht_get, dht_insert},
-    haven::{HavenLocator, RegisterHavenReq},
-    n2r_socket::N2rClientSocket,
+    dht::{dht_get, dht_insert, verify_locator},
+    haven::{
+        admit_registration, DeregisterHavenReq, HavenLocator, RegisterHavenError, RegisterHavenReq,
+    },
 };
 use earendil_crypt::{AnonEndpoint, HavenFingerprint, VerifyError};
 
-use super::{bicache::Bicache, GlobalRpcProtocol};
+use super::{
+    bicache::Bicache, GlobalRpcProgress, GlobalRpcProtocol, GlobalRpcRequest, GlobalRpcService,
+    ProgressMsg,
+};
+
+/// How many progress updates may wait to be sent before newer ones are dropped.
+const PROGRESS_BACKLOG: usize = 16;
+
+/// The most steps [GlobalRpcProtocol::progress_probe] takes, and the longest it waits between them, whatever the caller asks for.
+#[cfg(feature = "progress-probe")]
+const MAX_PROBE_STEPS: u32 = 20;
+#[cfg(feature = "progress-probe")]
+const MAX_PROBE_INTERVAL_MS: u64 = 5_000;
 
 pub struct GlobalRpcImpl {
     ctx: DaemonContext,
-    n2r_skt: N2rClientSocket,
+    progress: Option<Sender<GlobalRpcProgress>>,
+    progress_seq: AtomicU64,
 }
 
 impl GlobalRpcImpl {
-    pub fn new(ctx: DaemonContext, n2r_skt: N2rClientSocket) -> GlobalRpcImpl {
-        GlobalRpcImpl { ctx, n2r_skt }
+    pub fn new(ctx: DaemonContext) -> GlobalRpcImpl {
+        GlobalRpcImpl {
+            ctx,
+            progress: None,
+            progress_seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Tells the caller how the call is going, if it asked to know. Updates that can't be sent right away are dropped, since they're only advisory.
+    // only the probe reports progress so far
+    #[cfg_attr(not(feature = "progress-probe"), allow(dead_code))]
+    fn report_progress(&self, percent: Option<f32>, message: Option<String>) {
+        if let Some(progress) = &self.progress {
+            let _ = progress.try_send(GlobalRpcProgress {
+                seq: self.progress_seq.fetch_add(1, Ordering::Relaxed) + 1,
+                percent,
+                message,
+            });
+        }
+    }
+}
+
+/// Answers one GlobalRpc request, handing each response or progress update to `send` already encoded. Progress is only sent if the caller asked for it, and never after the response.
+pub async fn respond_with_progress<F, Fut>(
+    ctx: &DaemonContext,
+    req: GlobalRpcRequest,
+    send: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(Vec<u8>) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let (send_progress, recv_progress) = smol::channel::bounded(PROGRESS_BACKLOG);
+    let service = GlobalRpcService(GlobalRpcImpl {
+        progress: req.want_progress.then_some(send_progress),
+        ..GlobalRpcImpl::new(ctx.clone())
+    });
+    let id = req.req.id.clone();
+    let send = &send;
+    let send_progress = |progress| {
+        let msg = ProgressMsg {
+            progress_for: id.clone(),
+            progress,
+        };
+        async move {
+            // progress is advisory, so failing to send it doesn't fail the call
+            if let Err(err) = send(serde_json::to_vec(&msg)?).await {
+                tracing::debug!(err = debug(err), "could not send GlobalRpc progress");
+            }
+            anyhow::Ok(())
+        }
+    };
+    let resp = service
+        .respond_raw(req.req)
+        .race(async {
+            while let Ok(progress) = recv_progress.recv().await {
+                let _ = send_progress(progress).await;
+            }
+            futures_util::future::pending().await
+        })
+        .await;
+    // updates reported right before the method returned still go out ahead of the response
+    while let Ok(progress) = recv_progress.try_recv() {
+        send_progress(progress).await?;
     }
+    send(serde_json::to_vec(&resp)?).await
 }
 
 static LOCAL_DHT_SHARD: CtxField<Cache<HavenFingerprint, HavenLocator>> = |_| {
@@ -31,6 +114,13 @@ static LOCAL_DHT_SHARD: CtxField<Cache<HavenFingerprint, HavenLocator>> = |_| {
         .build()
 };
 
+/// How many locators we hold as a DHT replica.
+pub fn local_dht_shard_size(ctx: &DaemonContext) -> u64 {
+    let shard = ctx.get(LOCAL_DHT_SHARD);
+    shard.run_pending_tasks();
+    shard.entry_count()
+}
+
 pub static REGISTERED_HAVENS: CtxField<Bicache<AnonEndpoint, HavenFingerprint>> =
     |_| Bicache::new(3600);
 
@@ -44,12 +134,10 @@ impl GlobalRpcProtocol for GlobalRpcImpl {
         let key = locator.identity_pk.fingerprint();
 
         if recurse {
-            dht_insert(&self.ctx, locator, &self.n2r_skt).await
+            dht_insert(&self.ctx, locator).await
         } else {
-            locator
-                .identity_pk
-                .verify(&locator.to_sign(), &locator.signature)
-                .map_err(|_| DhtError::VerifyFailed)?;
+            verify_locator(&self.ctx, key, &locator)?;
+            // tombstones replace the locators they withdraw, and then read as nothing
             self.ctx.get(LOCAL_DHT_SHARD).insert(key, locator.clone());
         }
         Ok(())
@@ -61,21 +149,55 @@ impl GlobalRpcProtocol for GlobalRpcImpl {
         recurse: bool,
     ) -> Result<Option<HavenLocator>, DhtError> {
         if let Some(val) = self.ctx.get(LOCAL_DHT_SHARD).get(&key) {
+            // expired locators are handed out too, since whether they expired is for the asker's clock to say, and tombstones must reach visitors to tell them the haven is gone
             return Ok(Some(val));
         } else if recurse {
             tracing::debug!("searching DHT for {key}");
-            return dht_get(&self.ctx, key, &self.n2r_skt).await;
+            return dht_get(&self.ctx, key).await;
         }
         Ok(None)
     }
 
-    async fn alloc_forward(&self, registration: RegisterHavenReq) -> Result<(), VerifyError> {
+    async fn alloc_forward(
+        &self,
+        registration: RegisterHavenReq,
+    ) -> Result<(), RegisterHavenError> {
         registration
             .identity_pk
             .verify(registration.to_sign().as_bytes(), &registration.sig)?;
-        self.ctx
-            .get(REGISTERED_HAVENS)
-            .insert(registration.anon_id, registration.identity_pk.fingerprint());
+        admit_registration(&self.ctx, &registration)
+    }
+
+    async fn dealloc_forward(&self, dealloc_req: DeregisterHavenReq) -> Result<(), VerifyError> {
+        dealloc_req
+            .identity_pk
+            .verify(dealloc_req.to_sign().as_bytes(), &dealloc_req.sig)?;
+        let registered = self.ctx.get(REGISTERED_HAVENS);
+        // only the haven registered at the endpoint can deregister it
+        if registered.get_by_key(&dealloc_req.anon_id)
+            == Some(dealloc_req.identity_pk.fingerprint())
+        {
+            registered.remove_by_key(&dealloc_req.anon_id);
+        }
         Ok(())
     }
+
+    #[cfg(not(feature = "progress-probe"))]
+    async fn progress_probe(&self, _steps: u32, _interval_ms: u64) -> u32 {
+        0
+    }
+
+    #[cfg(feature = "progress-probe")]
+    async fn progress_probe(&self, steps: u32, interval_ms: u64) -> u32 {
+        let steps = steps.min(MAX_PROBE_STEPS);
+        let interval = Duration::from_millis(interval_ms.min(MAX_PROBE_INTERVAL_MS));
+        for step in 1..=steps {
+            smol::Timer::after(interval).await;
+            self.report_progress(
+                Some(step as f32 * 100.0 / steps as f32),
+                Some(format!("step {step} of {steps}")),
+            );
+        }
+        steps
+    }
 }
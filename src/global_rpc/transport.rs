@@ -1,23 +1,91 @@
-use std::time::{Duration, Instant};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use async_trait::async_trait;
-use earendil_crypt::RelayFingerprint;
-use futures_util::{future, FutureExt};
-use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
-use smol::Timer;
+use earendil_crypt::{AnonEndpoint, RelayFingerprint};
+use futures_util::future;
+use moka::sync::Cache;
+use nanorpc::{JrpcId, JrpcRequest, JrpcResponse, RpcTransport};
+use smol::{channel::Sender, Timer};
 
 use crate::{
-    context::DaemonContext,
-    n2r_socket::{N2rClientSocket, RelayEndpoint},
+    context::{CtxField, DaemonContext, RELAY_GRAPH},
+    n2r::{self, MessageClass},
+    n2r_socket::{N2rClientSocket, RelayEndpoint, SealedSender},
+    network::NackReason,
+    stats::STATS,
+};
+
+use super::{
+    pending::{self, SendCancelled, SendState, TrackedSend},
+    GlobalRpcProgress, GlobalRpcRequest, ProgressMsg, GLOBAL_RPC_DOCK, GLOBAL_RPC_SEALED_DOCK,
 };
 
-use super::GLOBAL_RPC_DOCK;
+/// Histogram of how long GlobalRPC calls take from first sending the request to getting the response, across retries. N2R messages carry no timestamps, so this round trip is the closest we get to end-to-end delivery time.
+pub const GLOBAL_RPC_ROUND_TRIP_SECONDS: &str = "global_rpc.round_trip_seconds";
+
+/// How long a cached transport may go unused before it's torn down, so that calls far apart in time can't be linked by their anonymous endpoint.
+const CACHED_TRANSPORT_IDLE_TTL: Duration = Duration::from_secs(120);
+
+/// How long a cached transport lives however busy it is. Havens republish their locators every few seconds, which would otherwise keep their transports, and so their endpoints, alive forever.
+const CACHED_TRANSPORT_MAX_LIFETIME: Duration = Duration::from_secs(600);
+
+/// How many times a call through a cached transport may time out before we assume the destination is unreachable, and stop caching the transport.
+const CACHED_TRANSPORT_MAX_RETRIES: u32 = 2;
+
+/// What a cached transport is for. Hosting and visiting havens never share a transport, so that a DHT replica can't link the havens we host to the ones we look up by the endpoint asking.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportRole {
+    /// Publishing the locators of havens we host.
+    Hosting,
+    /// Looking up the locators of havens we visit.
+    Visiting,
+}
+
+/// Warm transports to the destinations we talk to repeatedly, such as DHT replicas, for hosting havens.
+static HOSTING_TRANSPORTS: CtxField<Cache<RelayFingerprint, GlobalRpcTransport>> =
+    |_| new_transport_cache();
+
+/// Like [HOSTING_TRANSPORTS], but for visiting havens.
+static VISITING_TRANSPORTS: CtxField<Cache<RelayFingerprint, GlobalRpcTransport>> =
+    |_| new_transport_cache();
+
+fn new_transport_cache() -> Cache<RelayFingerprint, GlobalRpcTransport> {
+    Cache::builder()
+        .time_to_idle(CACHED_TRANSPORT_IDLE_TTL)
+        .time_to_live(CACHED_TRANSPORT_MAX_LIFETIME)
+        .build()
+}
+
+fn cached_transports(
+    ctx: &DaemonContext,
+    role: TransportRole,
+) -> &Cache<RelayFingerprint, GlobalRpcTransport> {
+    match role {
+        TransportRole::Hosting => ctx.get(HOSTING_TRANSPORTS),
+        TransportRole::Visiting => ctx.get(VISITING_TRANSPORTS),
+    }
+}
 
+#[derive(Clone)]
 pub struct GlobalRpcTransport {
     ctx: DaemonContext,
     dest_fp: RelayFingerprint,
     n2r_client_skt: N2rClientSocket,
+    sealed: bool,
+    /// Which cache the transport is kept in, if any.
+    cached: Option<TransportRole>,
+    /// Whether requests ask relays to NACK them if dropped, so that calls fail fast rather than time out.
+    nacks: bool,
+    /// Calls share one socket, so they take turns, or else they could receive each other's responses.
+    call_lock: Arc<smol::lock::Mutex<()>>,
+    /// Where progress updates on calls go, if we want any.
+    progress: Option<Sender<GlobalRpcProgress>>,
 }
 
 impl GlobalRpcTransport {
@@ -30,6 +98,73 @@ impl GlobalRpcTransport {
             ctx,
             dest_fp,
             n2r_client_skt,
+            sealed: false,
+            cached: None,
+            nacks: false,
+            call_lock: Default::default(),
+            progress: None,
+        }
+    }
+
+    /// Asks the destination to report progress on calls while they run, passing each update to `progress` as it arrives. Updates that don't fit are dropped. A call that makes progress is waited on for as long as it keeps doing so, rather than retried.
+    pub fn with_progress(mut self, progress: Sender<GlobalRpcProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Makes requests NACK-eligible, so that a call fails as soon as a relay reports dropping its request, rather than after waiting out its timeouts.
+    pub fn with_nacks(mut self) -> Self {
+        self.nacks = true;
+        self
+    }
+
+    /// Returns a transport to the given destination that is kept warm across calls in the given role: its anonymous endpoint, circuit, and stock of reply blocks at the destination are reused, until it goes unused for a while, gets too old, or the destination stops answering.
+    pub fn cached(
+        ctx: &DaemonContext,
+        dest_fp: RelayFingerprint,
+        role: TransportRole,
+    ) -> anyhow::Result<GlobalRpcTransport> {
+        cached_transports(ctx, role)
+            .try_get_with(dest_fp, || {
+                tracing::debug!(dest_fp = display(dest_fp), "building a cached transport");
+                // cached transports carry DHT maintenance, which nobody waits on
+                let n2r_client_skt = N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())?
+                    .with_class(MessageClass::Background)
+                    .without_fallback();
+                anyhow::Ok(GlobalRpcTransport {
+                    cached: Some(role),
+                    ..GlobalRpcTransport::new(ctx.clone(), dest_fp, n2r_client_skt)
+                })
+            })
+            .map_err(|e| anyhow::anyhow!("{e}"))
+    }
+
+    /// Tears down the cached transport to our destination, so that the next call builds a fresh one.
+    fn discard_cached(&self) {
+        if let Some(role) = self.cached {
+            tracing::debug!(
+                dest_fp = display(self.dest_fp),
+                "discarding cached transport"
+            );
+            cached_transports(&self.ctx, role).invalidate(&self.dest_fp);
+        }
+    }
+
+    /// Like [GlobalRpcTransport::new], but seals requests and responses end-to-end to the destination's onion key, so that nothing but the destination can read them.
+    pub fn new_sealed(
+        ctx: DaemonContext,
+        dest_fp: RelayFingerprint,
+        n2r_client_skt: N2rClientSocket,
+    ) -> GlobalRpcTransport {
+        GlobalRpcTransport {
+            ctx,
+            dest_fp,
+            n2r_client_skt,
+            sealed: true,
+            cached: None,
+            nacks: false,
+            call_lock: Default::default(),
+            progress: None,
         }
     }
 }
@@ -39,45 +174,224 @@ impl RpcTransport for GlobalRpcTransport {
     type Error = anyhow::Error;
 
     async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
-        let endpoint = RelayEndpoint::new(self.dest_fp, GLOBAL_RPC_DOCK);
+        let tracked = pending::track(
+            &self.ctx,
+            self.n2r_client_skt.socket_id(),
+            self.dest_fp,
+            &req.method,
+        );
+        let res = tracked.run(self.send_call(req, &tracked)).await;
+        if res.as_ref().is_err_and(|err| err.is::<SendCancelled>()) {
+            // whoever cancelled gave up on the destination, so the reply blocks we left there would only go to waste, and so would our transport
+            self.discard_cached();
+            n2r::forget_reply_blocks(
+                &self.ctx,
+                self.n2r_client_skt.local_endpoint(),
+                self.dest_fp,
+            );
+        }
+        res
+    }
+}
+
+impl GlobalRpcTransport {
+    /// Sends the request until a response comes back, keeping `tracked` up to date on how it's going.
+    async fn send_call(
+        &self,
+        req: JrpcRequest,
+        tracked: &TrackedSend,
+    ) -> anyhow::Result<JrpcResponse> {
+        let plain_req = serde_json::to_vec(&GlobalRpcRequest {
+            req: req.clone(),
+            want_progress: self.progress.is_some(),
+        })?;
+        let (endpoint, body, reply_key) = if self.sealed {
+            let service_pk = self
+                .ctx
+                .get(RELAY_GRAPH)
+                .read()
+                .identity(&self.dest_fp)
+                .context("no identity descriptor known for sealed GlobalRpc destination")?
+                .sealed_service_pk()
+                .context("sealed GlobalRpc destination doesn't publish a service key")?;
+            let (envelope, reply_key) = SealedSender::seal(&service_pk, &plain_req);
+            (
+                RelayEndpoint::new(self.dest_fp, GLOBAL_RPC_SEALED_DOCK),
+                envelope,
+                Some(reply_key),
+            )
+        } else {
+            (
+                RelayEndpoint::new(self.dest_fp, GLOBAL_RPC_DOCK),
+                plain_req.into(),
+                None,
+            )
+        };
         let mut retries = 0;
         let mut timeout: Duration;
 
+        let _call_guard = self.call_lock.lock().await;
+        let started = Instant::now();
         let socket = self.n2r_client_skt.clone();
         loop {
-            socket
-                .send_to(serde_json::to_string(&req)?.into(), endpoint)
-                .await
-                .context("socket send_to failed")?;
+            tracked.set_state(SendState::Sending, retries);
+            let sent = if self.nacks {
+                socket.send_to_nackable(body.clone(), endpoint).await
+            } else {
+                socket.send_to(body.clone(), endpoint).await.map(|_| None)
+            };
+            let nacks = match sent {
+                Ok(nacks) => nacks,
+                Err(err) => {
+                    self.discard_cached();
+                    return Err(err.context("socket send_to failed"));
+                }
+            };
             tracing::debug!(
                 "=====> x{retries} {}/{} ({:?})",
                 self.dest_fp,
                 req.method,
                 req.id
             );
+            tracked.set_state(SendState::AwaitingReply, retries);
 
             timeout = Duration::from_secs(2u64.pow(retries + 1));
             let when = Instant::now() + timeout;
-            let timer = Timer::at(when);
-            let recv_future = Box::pin(socket.recv_from());
-
-            match future::select(recv_future, timer.fuse()).await {
-                future::Either::Left((res, _)) => match res {
-                    Ok((res, _endpoint)) => {
-                        let jrpc_res: JrpcResponse =
-                            serde_json::from_str(&String::from_utf8(res.to_vec())?)?;
-                        tracing::debug!("<===== {}/{} ({:?})", self.dest_fp, req.method, req.id);
-                        return Ok(jrpc_res);
+            // we stop waiting at the timeout, or as soon as a relay NACKs the request, since then no response is coming
+            let mut deadline: Pin<Box<dyn Future<Output = Option<NackReason>> + Send + '_>> =
+                Box::pin(smol::future::or(
+                    async {
+                        Timer::at(when).await;
+                        None
+                    },
+                    async {
+                        if let Some(nacks) = nacks {
+                            if let Ok(reason) = nacks.recv().await {
+                                return Some(reason);
+                            }
+                        }
+                        future::pending().await
+                    },
+                ));
+
+            let mut progressed = false;
+            // a response to an earlier call that gave up waiting can still trickle in on a reused socket, so we wait until the one to this call
+            loop {
+                let recv_future = Box::pin(socket.recv_from());
+                match future::select(recv_future, &mut deadline).await {
+                    future::Either::Left((res, _)) => match res {
+                        Ok((res, _endpoint)) => {
+                            let res = match &reply_key {
+                                Some(reply_key) => match reply_key.open(&res) {
+                                    Ok(res) => res,
+                                    Err(err) => {
+                                        tracing::debug!(
+                                            err = debug(err),
+                                            "dropping sealed GlobalRpc response that failed to open"
+                                        );
+                                        continue;
+                                    }
+                                },
+                                None => res,
+                            };
+                            if let Ok(msg) = serde_json::from_slice::<ProgressMsg>(&res) {
+                                if same_id(&msg.progress_for, &req.id) {
+                                    progressed = true;
+                                    if let Some(progress) = &self.progress {
+                                        let _ = progress.try_send(msg.progress);
+                                    }
+                                }
+                                continue;
+                            }
+                            let jrpc_res: JrpcResponse =
+                                serde_json::from_str(&String::from_utf8(res.to_vec())?)?;
+                            if !same_id(&jrpc_res.id, &req.id) {
+                                tracing::debug!("dropping stale response {:?}", jrpc_res.id);
+                                continue;
+                            }
+                            tracing::debug!(
+                                "<===== {}/{} ({:?})",
+                                self.dest_fp,
+                                req.method,
+                                req.id
+                            );
+                            self.ctx
+                                .get(STATS)
+                                .observe(GLOBAL_RPC_ROUND_TRIP_SECONDS, started.elapsed());
+                            return Ok(jrpc_res);
+                        }
+                        Err(_) => {
+                            self.discard_cached();
+                            return Err(anyhow::anyhow!("error receiving GlobalRPC response"));
+                        }
+                    },
+                    // the request got there and is still running, so resending it would only start it over
+                    future::Either::Right((None, _)) if progressed => {
+                        progressed = false;
+                        deadline = Box::pin(async move {
+                            Timer::after(timeout).await;
+                            None
+                        });
                     }
-                    Err(_) => {
-                        return Err(anyhow::anyhow!("error receiving GlobalRPC response"));
+                    future::Either::Right((None, _)) => break,
+                    future::Either::Right((Some(reason), _)) => {
+                        self.discard_cached();
+                        anyhow::bail!("a relay dropped the GlobalRPC request: {reason:?}");
                     }
-                },
-                future::Either::Right((_, _)) => {
-                    retries += 1;
-                    continue;
                 }
             }
+            retries += 1;
+            if retries == CACHED_TRANSPORT_MAX_RETRIES {
+                self.discard_cached();
+            }
         }
     }
 }
+
+fn same_id(a: &JrpcId, b: &JrpcId) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+
+    use super::*;
+
+    #[test]
+    fn cached_transports_reuse_their_endpoint() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let [a, b] = [(); 2].map(|_| RelayIdentitySecret::generate().public().fingerprint());
+        let endpoint = |dest| {
+            GlobalRpcTransport::cached(&ctx, dest, TransportRole::Visiting)
+                .unwrap()
+                .n2r_client_skt
+                .local_endpoint()
+        };
+
+        let first = endpoint(a);
+        assert_eq!(endpoint(a), first);
+        assert_ne!(endpoint(b), first);
+
+        // once the destination stops answering, the next call starts afresh
+        GlobalRpcTransport::cached(&ctx, a, TransportRole::Visiting)
+            .unwrap()
+            .discard_cached();
+        assert_ne!(endpoint(a), first);
+        // but uncached transports never touch the cache
+        let uncached = GlobalRpcTransport::new(
+            ctx.clone(),
+            b,
+            N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random()).unwrap(),
+        );
+        let b_endpoint = endpoint(b);
+        uncached.discard_cached();
+        assert_eq!(endpoint(b), b_endpoint);
+
+        // hosting a haven never goes through the endpoint that visits them
+        let hosting = GlobalRpcTransport::cached(&ctx, b, TransportRole::Hosting).unwrap();
+        assert_ne!(hosting.n2r_client_skt.local_endpoint(), b_endpoint);
+        hosting.discard_cached();
+        assert_eq!(endpoint(b), b_endpoint);
+    }
+}
@@ -1,21 +1,36 @@
+mod beacon;
+mod capacity;
+mod forward;
 mod listen;
+mod rendezvous;
+mod session;
 mod visitor;
 mod vrh;
 
 use std::{
     fmt::{self, Display, Formatter},
     str::FromStr,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{context::DaemonContext, dht::dht_get};
-use crate::{global_rpc::server::REGISTERED_HAVENS, n2r_socket::N2rClientSocket};
-use crate::{haven::vrh::H2rMessage, n2r_socket::RelayEndpoint};
-use crate::{haven::vrh::R2hMessage, n2r_socket::N2rRelaySocket};
+use crate::limits::MAX_PIPELINED_PAYLOAD;
+use crate::n2r::MessageClass;
+use crate::n2r_socket::{shaper::Shaper, N2rClientSocket, RelayEndpoint};
+use crate::{
+    config::SendRateLimit,
+    context::DaemonContext,
+    dht::{dht_get, dht_insert},
+    global_rpc::{transport::GlobalRpcTransport, GlobalRpcClient},
+    scope::{spawn_owned, spawn_scoped, Stage},
+    stats::STATS,
+};
 use anyhow::Context as _;
 use bytes::Bytes;
-use earendil_crypt::{AnonEndpoint, HavenFingerprint, HavenIdentityPublic};
+use earendil_crypt::{AnonEndpoint, HavenFingerprint, HavenIdentityPublic, VerifyError};
 use earendil_crypt::{HavenIdentitySecret, RelayFingerprint};
 use earendil_packet::crypt::DhSecret;
 use earendil_packet::crypt::{AeadKey, DhPublic};
@@ -28,48 +43,133 @@ use smol::{
 };
 use smol_timeout::TimeoutExt;
 use stdcode::StdcodeSerializeExt;
-use tap::Tap;
-use tracing::instrument;
+use thiserror::Error;
 
+pub use self::beacon::{haven_beacons, is_degraded, BeaconStatus};
+pub use self::capacity::{admit_registration, rendezvous_occupancy, RendezvousOccupancy};
+pub use self::forward::{rendezvous_forward_loop, rendezvous_tap, RendezvousTap};
+pub use self::session::{REKEY_AFTER, REKEY_INTERVAL};
 use self::{
     listen::listen_loop,
+    session::{directional_keys, Opener, Sealer},
     visitor::visitor_loop,
     vrh::{HavenMsg, V2rMessage, VisitorHandshake},
 };
 
-#[derive(Copy, Clone, Deserialize, Serialize, Hash, Debug, PartialEq, PartialOrd, Ord, Eq)]
+/// The address of a port on a haven. Unlike [RelayEndpoint], this can only ever point at a haven.
+///
+/// Its string form is `haven:<fingerprint>:<port>`. The `haven:` prefix is optional when parsing, for compatibility with older configs.
+#[derive(Copy, Clone, Serialize, Hash, Debug, PartialEq, PartialOrd, Ord, Eq)]
 pub struct HavenEndpoint {
     pub fingerprint: HavenFingerprint,
     pub port: u16,
 }
 
 impl HavenEndpoint {
+    /// Creates an endpoint from a haven fingerprint. Relay fingerprints are a different type, so they can't be mixed up:
+    ///
+    /// ```compile_fail
+    /// use earendil::HavenEndpoint;
+    ///
+    /// let relay = earendil_crypt::RelayIdentitySecret::generate().public().fingerprint();
+    /// let _ = HavenEndpoint::new(relay, 80);
+    /// ```
     pub fn new(fingerprint: HavenFingerprint, port: u16) -> Self {
         Self { fingerprint, port }
     }
+
+    /// Creates an endpoint for a port on the haven with the given identity.
+    pub fn from_identity(identity: &HavenIdentityPublic, port: u16) -> Self {
+        Self::new(identity.fingerprint(), port)
+    }
+}
+
+impl From<(HavenFingerprint, u16)> for HavenEndpoint {
+    fn from((fingerprint, port): (HavenFingerprint, u16)) -> Self {
+        Self::new(fingerprint, port)
+    }
 }
 
 impl Display for HavenEndpoint {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}", self.fingerprint, self.port)
+        write!(f, "haven:{}:{}", self.fingerprint, self.port)
     }
 }
 
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HavenEndpointParseError {
+    #[error("{0:?} is a relay endpoint, not a haven endpoint")]
+    RelayEndpoint(String),
+    #[error("{0:?} has no port; haven endpoints look like haven:<fingerprint>:<port>")]
+    MissingPort(String),
+    #[error("{0:?} has too many parts; haven endpoints look like haven:<fingerprint>:<port>")]
+    TooManyParts(String),
+    #[error("{0:?} is not a haven fingerprint: {1}")]
+    BadFingerprint(String, String),
+    #[error("{0:?} is not a port number between 0 and 65535")]
+    BadPort(String),
+}
+
 impl FromStr for HavenEndpoint {
-    type Err = anyhow::Error;
+    type Err = HavenEndpointParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.split(':').collect();
-        if parts.len() != 2 {
-            return Err(anyhow::anyhow!("invalid haven endpoint format"));
-        }
-        let fingerprint = HavenFingerprint::from_str(parts[0])?;
-        let port = u16::from_str(parts[1])?;
+        let unprefixed = s.strip_prefix("haven:").unwrap_or(s);
+        let parts: Vec<&str> = unprefixed.split(':').collect();
+        let (fingerprint, port) = match parts.as_slice() {
+            [_] => return Err(HavenEndpointParseError::MissingPort(s.into())),
+            [fingerprint, port] => (*fingerprint, *port),
+            _ => return Err(HavenEndpointParseError::TooManyParts(s.into())),
+        };
+        let fingerprint = HavenFingerprint::from_str(fingerprint).map_err(|e| {
+            // relay fingerprints are hex, which can't be valid crockford base32 of the right length
+            if RelayFingerprint::from_str(fingerprint).is_ok() {
+                HavenEndpointParseError::RelayEndpoint(s.into())
+            } else {
+                HavenEndpointParseError::BadFingerprint(fingerprint.into(), e.to_string())
+            }
+        })?;
+        let port =
+            u16::from_str(port).map_err(|_| HavenEndpointParseError::BadPort(port.into()))?;
         Ok(HavenEndpoint::new(fingerprint, port))
     }
 }
 
-const HAVEN_FORWARD_DOCK: u32 = 100002;
+impl<'de> Deserialize<'de> for HavenEndpoint {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Fields {
+            fingerprint: HavenFingerprint,
+            port: u16,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum HumanReadable {
+            String(String),
+            Fields(Fields),
+        }
+
+        // human-readable formats also accept the string form, which is how older clients send endpoints
+        let fields = if deserializer.is_human_readable() {
+            match HumanReadable::deserialize(deserializer)? {
+                HumanReadable::String(s) => return s.parse().map_err(serde::de::Error::custom),
+                HumanReadable::Fields(fields) => fields,
+            }
+        } else {
+            Fields::deserialize(deserializer)?
+        };
+        Ok(Self::new(fields.fingerprint, fields.port))
+    }
+}
+
+pub(crate) const HAVEN_FORWARD_DOCK: u32 = 100002;
+
+/// Handshake version advertised by havens that accept a visitor's first packet bundled with its handshake.
+pub const HANDSHAKE_PIPELINED: u8 = 1;
+
+/// Handshake version advertised by havens whose connections pad every packet to the same size, reject replays, and ratchet their keys. Visitors refuse to connect to havens that don't advertise at least this, since their packets would be told apart by size.
+pub const HANDSHAKE_SEALED: u8 = 2;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct HavenLocator {
@@ -77,6 +177,12 @@ pub struct HavenLocator {
     pub onion_pk: DhPublic,
     pub rendezvous_point: RelayFingerprint,
     pub signature: Bytes,
+    /// The newest connection handshake the haven understands. Older havens don't advertise one, and only understand the sequential handshake.
+    #[serde(default)]
+    pub handshake_version: u8,
+    /// When the locator stops being valid, in seconds since the Unix epoch. Only ephemeral havens' locators expire, and a locator that already expired is a tombstone, telling visitors that the haven is gone. Visitors judge this by their own clocks, and havens date their tombstones to the epoch so that no clock reads them as current.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
 }
 
 impl HavenLocator {
@@ -84,6 +190,7 @@ impl HavenLocator {
         identity_sk: HavenIdentitySecret,
         onion_pk: DhPublic,
         rendezvous_fingerprint: RelayFingerprint,
+        handshake_version: u8,
     ) -> HavenLocator {
         let identity_pk = identity_sk.public();
         let locator = HavenLocator {
@@ -91,25 +198,65 @@ impl HavenLocator {
             onion_pk,
             rendezvous_point: rendezvous_fingerprint,
             signature: Bytes::new(),
+            handshake_version,
+            expires_at: None,
         };
         let signature = identity_sk.sign(&locator.to_sign());
 
         HavenLocator {
-            identity_pk,
-            onion_pk,
-            rendezvous_point: rendezvous_fingerprint,
             signature,
+            ..locator
+        }
+    }
+
+    /// Re-signs the locator so that it expires at the given time, in seconds since the Unix epoch.
+    pub fn expiring(self, identity_sk: HavenIdentitySecret, expires_at: u64) -> HavenLocator {
+        let locator = HavenLocator {
+            signature: Bytes::new(),
+            expires_at: Some(expires_at),
+            ..self
+        };
+        let signature = identity_sk.sign(&locator.to_sign());
+        HavenLocator {
+            signature,
+            ..locator
         }
     }
 
+    /// Whether the locator has expired by `now_unix`, which is always the case for tombstones.
+    pub fn is_expired(&self, now_unix: u64) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= now_unix)
+    }
+
     pub fn to_sign(&self) -> [u8; 32] {
         let locator = HavenLocator {
-            identity_pk: self.identity_pk,
-            onion_pk: self.onion_pk,
-            rendezvous_point: self.rendezvous_point,
             signature: Bytes::new(),
+            ..self.clone()
         };
-        let hash = blake3::keyed_hash(b"haven_locator___________________", &locator.stdcode());
+        let to_hash = if locator.expires_at.is_some() {
+            locator.stdcode()
+        } else if locator.handshake_version == 0 {
+            // unversioned locators are signed exactly as they were before versioning existed
+            (
+                locator.identity_pk,
+                locator.onion_pk,
+                locator.rendezvous_point,
+                locator.signature,
+            )
+                .stdcode()
+        } else {
+            // and locators that don't expire exactly as they were before expiry existed
+            (
+                locator.identity_pk,
+                locator.onion_pk,
+                locator.rendezvous_point,
+                locator.signature,
+                locator.handshake_version,
+            )
+                .stdcode()
+        };
+        let hash = blake3::keyed_hash(b"haven_locator___________________", &to_hash);
 
         *hash.as_bytes()
     }
@@ -131,10 +278,7 @@ impl RegisterHavenReq {
             identity_pk: identity_sk.public(),
             port,
             sig: Bytes::new(),
-            unix_timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            unix_timestamp: unix_now(),
         };
         reg.sig = identity_sk.sign(reg.to_sign().as_bytes());
         reg
@@ -147,13 +291,74 @@ impl RegisterHavenReq {
     }
 }
 
-const HAVEN_UP: &[u8] = b"haven-up";
-const HAVEN_DN: &[u8] = b"haven-dn";
+/// Why a rendezvous turned down a haven's registration.
+#[derive(Error, Serialize, Deserialize, Debug)]
+pub enum RegisterHavenError {
+    #[error("the registration has a bad signature: {0}")]
+    BadSignature(#[from] VerifyError),
+    #[error("this relay doesn't offer rendezvous")]
+    NotOffered,
+    #[error("this relay already forwards for as many havens as it takes ({0})")]
+    Full(usize),
+}
+
+/// Asks a rendezvous to stop forwarding to a haven. Signed by the haven, so that nobody else can take it offline.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeregisterHavenReq {
+    pub anon_id: AnonEndpoint,
+    pub identity_pk: HavenIdentityPublic,
+    pub sig: Bytes,
+    pub unix_timestamp: u64,
+}
+
+impl DeregisterHavenReq {
+    pub fn new(my_anon_id: AnonEndpoint, identity_sk: HavenIdentitySecret) -> Self {
+        let mut dereg = Self {
+            anon_id: my_anon_id,
+            identity_pk: identity_sk.public(),
+            sig: Bytes::new(),
+            unix_timestamp: unix_now(),
+        };
+        dereg.sig = identity_sk.sign(dereg.to_sign().as_bytes());
+        dereg
+    }
+
+    pub fn to_sign(&self) -> blake3::Hash {
+        let mut this = self.clone();
+        this.sig = Bytes::new();
+        blake3::keyed_hash(b"haven_deregistration____________", &this.stdcode())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+const HAVEN_EARLY: &[u8] = b"haven-early";
+
+/// The key that seals a pipelined first packet, derived from the visitor's ephemeral key and the onion key in the haven's locator.
+fn early_key(shared_sec: &[u8; 32]) -> AeadKey {
+    AeadKey::from_bytes(
+        blake3::keyed_hash(blake3::hash(HAVEN_EARLY).as_bytes(), shared_sec).as_bytes(),
+    )
+}
+
+/// How long an ephemeral haven's locator stays valid without being refreshed. This bounds how long visitors can still find an ephemeral haven whose daemon died without cleaning up.
+pub const EPHEMERAL_LOCATOR_TTL: Duration = Duration::from_secs(30);
 
 /// Represents a running haven, able to accept incoming [HavenPacketConn]s.
 pub struct HavenListener {
-    _listen_task: Task<anyhow::Result<()>>,
+    listen_task: Option<Task<Option<anyhow::Result<()>>>>,
     recv_accepted: Receiver<HavenPacketConn>,
+    identity: HavenIdentitySecret,
+    onion_pk: DhPublic,
+    /// Shared by every connection the listener accepts, to limit how fast the haven sends altogether.
+    shaper: Arc<Shaper>,
+    /// Set for ephemeral havens, which take themselves down when unbound or dropped.
+    teardown: Option<Teardown>,
 }
 
 impl HavenListener {
@@ -164,29 +369,214 @@ impl HavenListener {
         port: u16,
         rendezvous: RelayFingerprint,
     ) -> anyhow::Result<Self> {
+        Self::bind_multi(ctx, identity, port, vec![rendezvous]).await
+    }
+
+    /// Binds a new haven that registers with several rendezvous points. Visitors are pointed at whichever is currently the most responsive, preferring earlier ones when they are equally so.
+    pub async fn bind_multi(
+        ctx: &DaemonContext,
+        identity: HavenIdentitySecret,
+        port: u16,
+        rendezvous: Vec<RelayFingerprint>,
+    ) -> anyhow::Result<Self> {
+        Self::bind_inner(ctx, identity, DhSecret::generate(), port, rendezvous, false)
+    }
+
+    /// Like [HavenListener::bind_multi], but advertises the given onion key rather than one generated for this haven alone. Havens sharing an onion key can open each other's pipelined first packets, so only share one between havens that trust each other.
+    pub async fn bind_with_onion_key(
+        ctx: &DaemonContext,
+        identity: HavenIdentitySecret,
+        onion_sk: DhSecret,
+        port: u16,
+        rendezvous: Vec<RelayFingerprint>,
+    ) -> anyhow::Result<Self> {
+        Self::bind_inner(ctx, identity, onion_sk, port, rendezvous, false)
+    }
+
+    /// Binds a haven under a freshly generated identity, which only exists as long as the listener does. Its locator expires soon unless refreshed, and once the listener is unbound or dropped, the haven deregisters from its rendezvous points and replaces its locator with a tombstone, so that visitors fail fast rather than time out. Use [HavenListener::fingerprint] to tell visitors where to find it.
+    pub async fn bind_ephemeral(
+        ctx: &DaemonContext,
+        port: u16,
+        rendezvous: Vec<RelayFingerprint>,
+    ) -> anyhow::Result<Self> {
+        Self::bind_inner(
+            ctx,
+            HavenIdentitySecret::generate(),
+            DhSecret::generate(),
+            port,
+            rendezvous,
+            true,
+        )
+    }
+
+    fn bind_inner(
+        ctx: &DaemonContext,
+        identity: HavenIdentitySecret,
+        onion_sk: DhSecret,
+        port: u16,
+        rendezvous: Vec<RelayFingerprint>,
+        ephemeral: bool,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(!rendezvous.is_empty(), "a haven needs a rendezvous");
         let (send_accepted, recv_accepted) = smol::channel::bounded(100);
-        let _listen_task = smolscale::spawn(
-            listen_loop(ctx.clone(), identity, port, rendezvous, send_accepted)
-                .inspect_err(|e| tracing::warn!(err = debug(e), "haven listener loop died")),
+        let anon_ep = AnonEndpoint::random();
+        let onion_pk = onion_sk.public();
+        let listen_task = spawn_owned(
+            ctx,
+            Stage::Intake,
+            "haven_listen",
+            listen_loop(
+                ctx.clone(),
+                identity,
+                onion_sk,
+                port,
+                rendezvous.clone(),
+                anon_ep,
+                ephemeral.then_some(EPHEMERAL_LOCATOR_TTL),
+                send_accepted,
+            )
+            .inspect_err(|e| tracing::warn!(err = debug(e), "haven listener loop died")),
         );
         Ok(Self {
-            _listen_task,
+            listen_task: Some(listen_task),
             recv_accepted,
+            identity,
+            onion_pk,
+            shaper: Shaper::register(ctx, format!("haven:{}", identity.public().fingerprint())),
+            teardown: ephemeral.then(|| Teardown {
+                ctx: ctx.clone(),
+                anon_ep,
+                rendezvous,
+            }),
         })
     }
 
+    /// The fingerprint of the haven, which is how visitors find it.
+    pub fn fingerprint(&self) -> HavenFingerprint {
+        self.identity.public().fingerprint()
+    }
+
+    /// The onion key the haven's locator advertises. Unless bound with [HavenListener::bind_with_onion_key], every haven has its own, which lives as long as the listener and is never the node's.
+    pub fn onion_public(&self) -> DhPublic {
+        self.onion_pk
+    }
+
+    /// Whether the haven only exists as long as this listener does.
+    pub fn is_ephemeral(&self) -> bool {
+        self.teardown.is_some()
+    }
+
     /// Accepts a new unreliable connection. Wrap in a [Stream] or similar if reliability is required.
     pub async fn accept(&self) -> anyhow::Result<HavenPacketConn> {
-        Ok(self.recv_accepted.recv().await?)
+        let mut conn = self.recv_accepted.recv().await?;
+        conn.haven_shaper = Some(self.shaper.clone());
+        Ok(conn)
+    }
+
+    /// Caps how fast the haven sends to all of its visitors together, or lifts the cap given `None`. Connections can be limited further with [HavenPacketConn::set_rate_limit], and whichever limit is stricter wins.
+    pub fn set_rate_limit(&self, limit: Option<SendRateLimit>) {
+        self.shaper.set_limit(limit);
+    }
+
+    /// What the control protocol knows the haven by, to adjust its rate limit.
+    pub fn socket_id(&self) -> u64 {
+        self.shaper.id()
+    }
+
+    /// Stops the haven. Ephemeral havens also wait until they are deregistered and their tombstone is published, which dropping the listener does in the background instead.
+    pub async fn unbind(mut self) {
+        drop(self.listen_task.take());
+        if let Some(teardown) = self.teardown.take() {
+            teardown.run(self.identity).await;
+        }
     }
 }
 
+impl Drop for HavenListener {
+    fn drop(&mut self) {
+        // the locator must stop being refreshed before the tombstone replaces it
+        drop(self.listen_task.take());
+        if let Some(teardown) = self.teardown.take() {
+            let ctx = teardown.ctx.clone();
+            spawn_scoped(
+                &ctx,
+                Stage::Upkeep,
+                "haven_teardown",
+                teardown.run(self.identity),
+            );
+        }
+    }
+}
+
+/// What an ephemeral haven needs to take itself down.
+struct Teardown {
+    ctx: DaemonContext,
+    anon_ep: AnonEndpoint,
+    rendezvous: Vec<RelayFingerprint>,
+}
+
+impl Teardown {
+    async fn run(self, identity: HavenIdentitySecret) {
+        let fingerprint = identity.public().fingerprint();
+        let dereg = DeregisterHavenReq::new(self.anon_ep, identity);
+        let deregister_all = futures::future::join_all(self.rendezvous.iter().map(|rendezvous| {
+            let dereg = dereg.clone();
+            async move {
+                let result = async {
+                    let gclient = GlobalRpcClient(GlobalRpcTransport::new(
+                        self.ctx.clone(),
+                        *rendezvous,
+                        N2rClientSocket::bind(self.ctx.clone(), AnonEndpoint::random())?
+                            .with_class(MessageClass::Background)
+                            .without_fallback(),
+                    ));
+                    gclient
+                        .dealloc_forward(dereg)
+                        .timeout(self.ctx.init().rpc_timeouts.forward())
+                        .await
+                        .context("timed out")???;
+                    anyhow::Ok(())
+                }
+                .await;
+                if let Err(err) = result {
+                    tracing::debug!(
+                        haven = display(fingerprint),
+                        rendezvous = display(rendezvous),
+                        "could not deregister ephemeral haven: {err:?}"
+                    );
+                }
+            }
+        }));
+        // visitors that find the tombstone fail right away, rather than waiting on a haven that is gone. it expired at the epoch, so that it reads as expired whatever the visitor's clock says
+        let tombstone = HavenLocator::new(
+            identity,
+            DhSecret::generate().public(),
+            self.rendezvous[0],
+            0,
+        )
+        .expiring(identity, 0);
+        futures::future::join(deregister_all, dht_insert(&self.ctx, tombstone)).await;
+        tracing::debug!(haven = display(fingerprint), "ephemeral haven torn down");
+    }
+}
+
+/// The haven no longer knows a connection, typically because it restarted with fresh keys since the connection was made.
+#[derive(Error, Debug, Clone, Copy)]
+#[error("the haven reset the connection, probably because its keys changed; connect again")]
+pub struct HavenConnReset;
+
 /// A low-level, best-effort visitor-haven connection.
+///
+/// Packets are end-to-end encrypted between the visitor and the haven, so the rendezvous in between only sees how many there are: every packet is padded to [crate::limits::HAVEN_PACKET_SIZE] before it's sealed. Packets that were tampered with or replayed are dropped, and both sides ratchet to fresh keys every [REKEY_INTERVAL] packets or [REKEY_AFTER], whichever comes first.
+/// Handshakes visitors sent to havens, counting retries, so that each one is a round trip.
+pub const HAVEN_HANDSHAKES_SENT: &str = "haven.handshakes_sent";
+/// First packets that went to a haven together with the handshake, saving a round trip.
+pub const HAVEN_FIRST_PKT_PIPELINED: &str = "haven.first_pkt_pipelined";
+
 pub struct HavenPacketConn {
     // encryption state for this connection
-    enc_key: AeadKey,
-    enc_nonce: AtomicU64,
-    dec_key: AeadKey,
+    sealer: Sealer,
+    opener: Opener,
 
     // some way of sending packets to the other side (e.g. the sending end of a channel, or a boxed closure)
     // some way of receiving packets from the other side (e.g. the receiving end of a channel, or a boxed closure)
@@ -196,34 +586,94 @@ pub struct HavenPacketConn {
     send_upstream: Sender<Bytes>,
     recv_downstream: Receiver<Bytes>,
 
-    _task: Task<anyhow::Result<()>>,
+    shaper: Arc<Shaper>,
+    /// The shaper of the listener that accepted the connection, on the haven side.
+    haven_shaper: Option<Arc<Shaper>>,
+    /// Set on the visitor side once the haven said it doesn't know the connection.
+    reset_by_haven: Arc<AtomicBool>,
+
+    _task: Task<Option<anyhow::Result<()>>>,
 }
 
 impl HavenPacketConn {
     /// Establish a connection to the given haven endpoint.
     pub async fn connect(ctx: &DaemonContext, dest_haven: HavenEndpoint) -> anyhow::Result<Self> {
-        let rpc_n2r_skt = N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())?;
-        let n2r_skt = N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())?;
+        Self::connect_inner(ctx, dest_haven, None, DhSecret::generate()).await
+    }
+
+    /// Establish a connection to the given haven endpoint, sending `first_pkt` as the first packet on it.
+    ///
+    /// If the haven supports it, the first packet travels together with the handshake, and the haven's first packet comes back together with its handshake, saving a round trip. Otherwise, or if `first_pkt` is larger than [MAX_PIPELINED_PAYLOAD], this falls back to connecting and then sending.
+    ///
+    /// **Idempotency**: if a pipelined handshake is lost or retried, the haven may receive `first_pkt` more than once. Only pipeline packets that are safe to redeliver, like idempotent requests.
+    pub async fn connect_pipelined(
+        ctx: &DaemonContext,
+        dest_haven: HavenEndpoint,
+        first_pkt: &[u8],
+    ) -> anyhow::Result<Self> {
+        Self::connect_inner(ctx, dest_haven, Some(first_pkt), DhSecret::generate()).await
+    }
+
+    /// Connects with the given ephemeral key, which havens can recognize their own beacons by.
+    async fn connect_inner(
+        ctx: &DaemonContext,
+        dest_haven: HavenEndpoint,
+        first_pkt: Option<&[u8]>,
+        my_esk: DhSecret,
+    ) -> anyhow::Result<Self> {
+        let n2r_skt =
+            N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())?.without_fallback();
 
         // lookup the haven info using the dht
-        let locator = dht_get(ctx, dest_haven.fingerprint, &rpc_n2r_skt)
+        let locator = dht_get(ctx, dest_haven.fingerprint)
             .await
             .context("dht_get failed")?
             .context("haven not found in DHT")?;
+        anyhow::ensure!(
+            locator.handshake_version >= HANDSHAKE_SEALED,
+            "haven {} only understands handshake version {}, which doesn't pad or rekey its connections, so we won't connect to it",
+            dest_haven.fingerprint,
+            locator.handshake_version
+        );
 
         let rendezvous_ep = RelayEndpoint::new(locator.rendezvous_point, HAVEN_FORWARD_DOCK);
         tracing::debug!("got n2r_skt: {}", n2r_skt.local_endpoint());
         // do the handshake to the other side over N2R
-        let my_esk = DhSecret::generate();
-        let my_hs = V2rMessage {
+        let plain_hs = V2rMessage {
             dest_haven,
             payload: HavenMsg::VisitorHs(VisitorHandshake(my_esk.public())),
         };
+        // the first packet rides along with the first handshake attempt only if the haven understands that
+        let pipelined_hs = first_pkt
+            .filter(|pkt| {
+                locator.handshake_version >= HANDSHAKE_PIPELINED
+                    && pkt.len() <= MAX_PIPELINED_PAYLOAD
+            })
+            .map(|pkt| {
+                let early_key = early_key(&my_esk.shared_secret(&locator.onion_pk));
+                // padded like any other packet, only to a smaller size
+                let padded = session::pad(pkt, MAX_PIPELINED_PAYLOAD).expect("checked above");
+                V2rMessage {
+                    dest_haven,
+                    payload: HavenMsg::PipelinedVisitorHs(
+                        VisitorHandshake(my_esk.public()),
+                        early_key.seal(&[0; 12], &padded).into(),
+                    ),
+                }
+            });
         let mut shared_sec: Option<[u8; 32]> = None;
+        let mut first_from_haven: Option<Bytes> = None;
+        let mut first_pkt_delivered = false;
         for i in 0.. {
+            let my_hs = if i == 0 {
+                pipelined_hs.as_ref().unwrap_or(&plain_hs)
+            } else {
+                &plain_hs
+            };
             n2r_skt
                 .send_to(my_hs.stdcode().into(), rendezvous_ep)
                 .await?;
+            ctx.get(STATS).incr(HAVEN_HANDSHAKES_SENT);
             tracing::debug!("sent handshake! i = {i}");
             // they sign their ephemeral public key
             if let Some(Ok((from_haven, addr))) =
@@ -237,129 +687,352 @@ impl HavenPacketConn {
                 );
                 let haven_msg: HavenMsg = stdcode::deserialize(&from_haven)
                     .context("deserialization of haven handshake failed")?;
-                match haven_msg {
-                    HavenMsg::HavenHs(server_hs) => {
+                let server_hs = match haven_msg {
+                    HavenMsg::HavenHs(server_hs) => server_hs,
+                    HavenMsg::PipelinedHavenHs(server_hs, first) => {
+                        first_from_haven = Some(first);
                         server_hs
-                            .id_pk
-                            .verify(server_hs.eph_pk.as_bytes(), &server_hs.sig)?;
-                        if server_hs.id_pk.fingerprint() != dest_haven.fingerprint {
-                            anyhow::bail!("haven public key verification failed")
-                        }
-                        shared_sec = Some(my_esk.shared_secret(&server_hs.eph_pk));
-                        break;
                     }
-                    x => tracing::debug!(
-                        "haven sent us something other than a haven handshake: {:?}",
-                        x
-                    ),
+                    x => {
+                        tracing::debug!(
+                            "haven sent us something other than a haven handshake: {:?}",
+                            x
+                        );
+                        smol::Timer::after(Duration::from_secs(2u64.pow(i))).await;
+                        continue;
+                    }
                 };
+                server_hs
+                    .id_pk
+                    .verify(server_hs.eph_pk.as_bytes(), &server_hs.sig)?;
+                if server_hs.id_pk.fingerprint() != dest_haven.fingerprint {
+                    anyhow::bail!("haven public key verification failed")
+                }
+                shared_sec = Some(my_esk.shared_secret(&server_hs.eph_pk));
+                // havens only answer a pipelined handshake after accepting the packet in it
+                first_pkt_delivered = i == 0 && pipelined_hs.is_some();
+                break;
             }
             smol::Timer::after(Duration::from_secs(2u64.pow(i))).await;
         }
 
         let shared_sec = shared_sec.context("impossible")?;
-        let up_key = AeadKey::from_bytes(
-            blake3::keyed_hash(blake3::hash(HAVEN_UP).as_bytes(), &shared_sec).as_bytes(),
-        );
-        let down_key = AeadKey::from_bytes(
-            blake3::keyed_hash(blake3::hash(HAVEN_DN).as_bytes(), &shared_sec).as_bytes(),
-        );
+        let (up_key, down_key) = directional_keys(&shared_sec);
 
         let (send_upstream, recv_upstream) = smol::channel::bounded(1);
         let (send_downstream, recv_downstream) = smol::channel::bounded(1);
+        if let Some(first) = first_from_haven {
+            send_downstream.try_send(first)?;
+        }
 
         // construct the connection
-        Ok(HavenPacketConn {
-            enc_key: up_key,
-            enc_nonce: AtomicU64::new(0),
-            dec_key: down_key,
+        let reset_by_haven = Arc::new(AtomicBool::new(false));
+        let conn = HavenPacketConn {
+            // a pipelined first packet takes sequence number 0, so we never reuse it
+            sealer: Sealer::new(up_key, if pipelined_hs.is_some() { 1 } else { 0 }),
+            opener: Opener::new(down_key),
 
             send_upstream,
             recv_downstream,
 
-            _task: smolscale::spawn(visitor_loop(
-                send_downstream,
-                recv_upstream,
-                locator.rendezvous_point,
-                dest_haven,
-                n2r_skt,
-            )),
-        })
+            shaper: Shaper::register(ctx, format!("visitor:{dest_haven}")),
+            haven_shaper: None,
+            reset_by_haven: reset_by_haven.clone(),
+
+            _task: spawn_owned(
+                ctx,
+                Stage::Inflight,
+                "haven_visitor",
+                visitor_loop(
+                    send_downstream,
+                    recv_upstream,
+                    locator.rendezvous_point,
+                    locator.identity_pk,
+                    dest_haven,
+                    n2r_skt,
+                    reset_by_haven,
+                ),
+            ),
+        };
+        if let Some(first_pkt) = first_pkt {
+            if first_pkt_delivered {
+                ctx.get(STATS).incr(HAVEN_FIRST_PKT_PIPELINED);
+            } else {
+                conn.send_pkt(first_pkt).await?;
+            }
+        }
+        Ok(conn)
     }
 
-    /// Sends a packet to the other side. It may or may not get there, since the connection is best-effort.
+    /// Caps how fast this connection sends, or lifts the cap given `None`. Sends over the cap wait their turn, and fail if that would take too long.
+    pub fn set_rate_limit(&self, limit: Option<SendRateLimit>) {
+        self.shaper.set_limit(limit);
+    }
+
+    pub fn rate_limit(&self) -> Option<SendRateLimit> {
+        self.shaper.limit()
+    }
+
+    /// What the control protocol knows this connection by, to adjust its rate limit.
+    pub fn socket_id(&self) -> u64 {
+        self.shaper.id()
+    }
+
+    /// Sends a packet to the other side. It may or may not get there, since the connection is best-effort. Packets bigger than [crate::limits::HAVEN_PACKET_SIZE] are refused.
     pub async fn send_pkt(&self, bts: &[u8]) -> anyhow::Result<()> {
-        let nonce = self.enc_nonce.fetch_add(1, Ordering::SeqCst);
-        let nonce_bts = [0; 12].tap_mut(|b| b[..8].copy_from_slice(&nonce.to_le_bytes()));
-        let ctext = self.enc_key.seal(&nonce_bts, bts);
-        self.send_upstream
-            .send((nonce, ctext).stdcode().into())
-            .await?;
+        self.shaper.wait(bts.len()).await?;
+        if let Some(haven_shaper) = &self.haven_shaper {
+            haven_shaper.wait(bts.len()).await?;
+        }
+        let sealed = self.sealer.seal(bts)?;
+        self.send_upstream.send(sealed).await?;
         Ok(())
     }
 
-    /// Receives a packet from the other side. We may not receive all the packets sent, since the connection is best-effort.
+    /// Receives a packet from the other side. We may not receive all the packets sent, since the connection is best-effort, and packets that were tampered with or replayed are dropped.
+    ///
+    /// Fails once the connection is gone. If the haven no longer knows the connection, say because it restarted with fresh keys, this fails with [HavenConnReset], and the visitor should connect anew.
     pub async fn recv_pkt(&self) -> anyhow::Result<Bytes> {
-        let ctext = self.recv_downstream.recv().await?;
-        let (nonce, ctext): (u64, Vec<u8>) = stdcode::deserialize(&ctext)?;
-        // TODO TODO replay protection by preventing the nonce from repeating
-        let nonce_bts = [0; 12].tap_mut(|b| b[..8].copy_from_slice(&nonce.to_le_bytes()));
-        let ptext = self.dec_key.open(&nonce_bts, &ctext)?;
-        Ok(ptext.into())
+        loop {
+            let Ok(sealed) = self.recv_downstream.recv().await else {
+                if self.reset_by_haven.load(Ordering::SeqCst) {
+                    return Err(HavenConnReset.into());
+                }
+                anyhow::bail!("haven connection closed");
+            };
+            match self.opener.open(&sealed) {
+                Ok(pkt) => return Ok(pkt),
+                Err(err) => tracing::debug!(err = debug(err), "dropping a haven packet"),
+            }
+        }
     }
 }
 
-#[instrument(skip(ctx))]
-/// Loop that listens to and handles incoming haven forwarding requests
-pub async fn rendezvous_forward_loop(ctx: DaemonContext) -> anyhow::Result<()> {
-    let socket = N2rRelaySocket::bind(ctx.clone(), Some(HAVEN_FORWARD_DOCK))?;
-
-    loop {
-        if let Ok((msg, src_ep)) = socket.recv_from().await {
-            let ctx = ctx.clone();
-            let src_is_visitor = ctx.get(REGISTERED_HAVENS).get_by_key(&src_ep).is_none();
-            if src_is_visitor {
-                let inner: V2rMessage = stdcode::deserialize(&msg)?;
-
-                if let Some(haven_anon_ep) = ctx
-                    .get(REGISTERED_HAVENS)
-                    .get_by_value(&inner.dest_haven.fingerprint)
-                {
-                    tracing::debug!(
-                        src_ep = debug(src_ep),
-                        haven_anon_ep = debug(haven_anon_ep),
-                        "received V2R msg"
-                    );
+#[cfg(test)]
+impl HavenPacketConn {
+    /// A visitor's connection and the haven's end of it, wired straight to each other as if through a rendezvous that forwards everything faithfully.
+    pub(crate) fn pair(ctx: &DaemonContext) -> (Self, Self) {
+        let (visitor_sk, haven_sk) = (DhSecret::generate(), DhSecret::generate());
+        let (up_key, down_key) = directional_keys(&visitor_sk.shared_secret(&haven_sk.public()));
+        let (send_up, recv_up) = smol::channel::bounded(100);
+        let (send_down, recv_down) = smol::channel::bounded(100);
+        let conn = |sealer, opener, send_upstream, recv_downstream| HavenPacketConn {
+            sealer,
+            opener,
+            send_upstream,
+            recv_downstream,
+            shaper: Shaper::register(ctx, "pair".into()),
+            haven_shaper: None,
+            reset_by_haven: Default::default(),
+            _task: spawn_owned(ctx, Stage::Inflight, "haven_pair", smol::future::pending()),
+        };
+        (
+            conn(
+                Sealer::new(up_key, 0),
+                Opener::new(down_key),
+                send_up,
+                recv_down,
+            ),
+            conn(
+                Sealer::new(down_key, 0),
+                Opener::new(up_key),
+                send_down,
+                recv_up,
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+
+    use super::*;
+    use crate::haven::vrh::HavenReset;
+
+    #[test]
+    fn haven_endpoint_round_trips() {
+        let endpoint = HavenEndpoint::from_identity(&HavenIdentitySecret::generate().public(), 80);
+        let displayed = endpoint.to_string();
+        assert!(displayed.starts_with("haven:"));
+        assert_eq!(displayed.parse::<HavenEndpoint>().unwrap(), endpoint);
+        // the legacy unprefixed form still parses
+        let legacy = format!("{}:{}", endpoint.fingerprint, endpoint.port);
+        assert_eq!(legacy.parse::<HavenEndpoint>().unwrap(), endpoint);
+        // as do both serde forms, and the binary form
+        let json = serde_json::to_value(endpoint).unwrap();
+        assert_eq!(
+            serde_json::from_value::<HavenEndpoint>(json).unwrap(),
+            endpoint
+        );
+        let json = serde_json::Value::String(displayed);
+        assert_eq!(
+            serde_json::from_value::<HavenEndpoint>(json).unwrap(),
+            endpoint
+        );
+        let binary = endpoint.stdcode();
+        assert_eq!(
+            stdcode::deserialize::<HavenEndpoint>(&binary).unwrap(),
+            endpoint
+        );
+    }
 
-                    let body: Bytes = R2hMessage {
-                        src_visitor: src_ep,
+    #[test]
+    fn expiring_locators() {
+        let identity = HavenIdentitySecret::generate();
+        let rendezvous = RelayIdentitySecret::generate().public().fingerprint();
+        let locator = HavenLocator::new(identity, DhSecret::generate().public(), rendezvous, 1);
+        let now = unix_now();
+        let expiring = locator.clone().expiring(identity, now + 30);
+        assert!(!expiring.is_expired(now));
+        assert!(expiring.is_expired(now + 30));
+        assert!(identity
+            .public()
+            .verify(&expiring.to_sign(), &expiring.signature)
+            .is_ok());
+        // the expiry is covered by the signature, so it can't be pushed back
+        let extended = HavenLocator {
+            expires_at: Some(now + 3600),
+            ..expiring.clone()
+        };
+        assert!(identity
+            .public()
+            .verify(&extended.to_sign(), &extended.signature)
+            .is_err());
+        // locators that don't expire are still signed as before expiry existed
+        assert_ne!(locator.to_sign(), expiring.to_sign());
+        assert!(!locator.is_expired(u64::MAX));
+
+        let tombstone = locator.expiring(identity, 0);
+        assert!(tombstone.is_expired(0));
+    }
+
+    #[test]
+    fn haven_endpoint_rejects_relays() {
+        let relay = RelayEndpoint::new(RelayIdentitySecret::generate().public().fingerprint(), 80);
+        assert_eq!(
+            relay.to_string().parse::<HavenEndpoint>(),
+            Err(HavenEndpointParseError::RelayEndpoint(relay.to_string()))
+        );
+        let haven = HavenEndpoint::from_identity(&HavenIdentitySecret::generate().public(), 80);
+        assert!(haven.to_string().parse::<RelayEndpoint>().is_err());
+        assert!(matches!(
+            "haven:abc".parse::<HavenEndpoint>(),
+            Err(HavenEndpointParseError::MissingPort(_))
+        ));
+        assert!(matches!(
+            format!("{}:99999", haven.fingerprint).parse::<HavenEndpoint>(),
+            Err(HavenEndpointParseError::BadPort(_))
+        ));
+    }
 
-                        payload: inner.payload,
+    /// What the rendezvous between a visitor and a haven gets to see and do.
+    struct Rendezvous {
+        forwarded: Arc<parking_lot::Mutex<Vec<Bytes>>>,
+        tamper: Arc<AtomicBool>,
+        to_haven: Sender<Bytes>,
+    }
+
+    /// A visitor and a haven, connected through a rendezvous that records everything the visitor sends, and flips a bit in it while told to tamper.
+    fn through_rendezvous(ctx: &DaemonContext) -> (HavenPacketConn, HavenPacketConn, Rendezvous) {
+        let (visitor_sk, haven_sk) = (DhSecret::generate(), DhSecret::generate());
+        let (up_key, down_key) = directional_keys(&visitor_sk.shared_secret(&haven_sk.public()));
+        let (visitor_up, rendezvous_in) = smol::channel::bounded::<Bytes>(100);
+        let (to_haven, haven_dn) = smol::channel::bounded(100);
+        let (haven_up, visitor_dn) = smol::channel::bounded(100);
+        let rendezvous = Rendezvous {
+            forwarded: Default::default(),
+            tamper: Default::default(),
+            to_haven: to_haven.clone(),
+        };
+        let forward = {
+            let (forwarded, tamper) = (rendezvous.forwarded.clone(), rendezvous.tamper.clone());
+            async move {
+                while let Ok(msg) = rendezvous_in.recv().await {
+                    forwarded.lock().push(msg.clone());
+                    let mut msg = msg.to_vec();
+                    if tamper.load(Ordering::SeqCst) {
+                        *msg.last_mut().unwrap() ^= 1;
                     }
-                    .stdcode()
-                    .into();
-
-                    tracing::debug!(haven_anon_ep = debug(haven_anon_ep), "sending R2H");
-                    socket.send_to(body, haven_anon_ep).await?;
-                } else {
-                    tracing::warn!(
-                        "haven {} is not registered with me!",
-                        inner.dest_haven.fingerprint
-                    );
+                    to_haven.send(msg.into()).await?;
                 }
-            } else {
-                // src is haven
-                let inner: H2rMessage = stdcode::deserialize(&msg)?;
-                tracing::debug!(
-                    src_ep = debug(src_ep),
-                    dest_visitor = debug(inner.dest_visitor),
-                    len = msg.len(),
-                    "received H2R msg",
-                );
-                let body: Bytes = inner.payload.stdcode().into();
-                tracing::debug!(dest_visitor = debug(inner.dest_visitor), "sending bare");
-                socket.send_to(body, inner.dest_visitor).await?;
+                anyhow::Ok(())
             }
         };
+        let conn = |sealer, opener, send_upstream, recv_downstream, task| HavenPacketConn {
+            sealer,
+            opener,
+            send_upstream,
+            recv_downstream,
+            shaper: Shaper::register(ctx, "test".into()),
+            haven_shaper: None,
+            reset_by_haven: Default::default(),
+            _task: task,
+        };
+        let visitor = conn(
+            Sealer::new(up_key, 0),
+            Opener::new(down_key),
+            visitor_up,
+            visitor_dn,
+            spawn_owned(ctx, Stage::Inflight, "rendezvous_forward", forward),
+        );
+        let haven = conn(
+            Sealer::new(down_key, 0),
+            Opener::new(up_key),
+            haven_up,
+            haven_dn,
+            spawn_owned(
+                ctx,
+                Stage::Inflight,
+                "haven_pending",
+                smol::future::pending::<anyhow::Result<()>>(),
+            ),
+        );
+        (visitor, haven, rendezvous)
+    }
+
+    #[test]
+    fn rendezvous_only_sees_ciphertext() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let (visitor, haven, rendezvous) = through_rendezvous(&ctx);
+        smol::future::block_on(async {
+            let secret = b"attack at dawn";
+            visitor.send_pkt(secret).await.unwrap();
+            visitor.send_pkt(&[b'x'; 5000]).await.unwrap();
+            assert_eq!(&haven.recv_pkt().await.unwrap()[..], secret);
+            assert_eq!(&haven.recv_pkt().await.unwrap()[..], &[b'x'; 5000]);
+            haven.send_pkt(b"at dawn it is").await.unwrap();
+            assert_eq!(&visitor.recv_pkt().await.unwrap()[..], b"at dawn it is");
+
+            let forwarded = rendezvous.forwarded.lock().clone();
+            assert_eq!(forwarded.len(), 2);
+            assert_eq!(forwarded[0].len(), forwarded[1].len());
+            assert!(!forwarded[0].windows(secret.len()).any(|w| w == secret));
+            assert!(!forwarded[1].windows(64).any(|w| w == [b'x'; 64]));
+
+            // the haven drops tampered and replayed packets, and carries on with the rest
+            rendezvous.tamper.store(true, Ordering::SeqCst);
+            visitor.send_pkt(b"tampered").await.unwrap();
+            rendezvous.tamper.store(false, Ordering::SeqCst);
+            rendezvous
+                .to_haven
+                .send(forwarded[0].clone())
+                .await
+                .unwrap();
+            visitor.send_pkt(b"intact").await.unwrap();
+            assert_eq!(&haven.recv_pkt().await.unwrap()[..], b"intact");
+        });
+    }
+
+    #[test]
+    fn only_the_haven_can_reset() {
+        let identity = HavenIdentitySecret::generate();
+        let visitor = AnonEndpoint::random();
+        let reset = HavenReset::new(identity, visitor);
+        assert!(reset.verify(&identity.public(), visitor));
+        assert!(!reset.verify(&identity.public(), AnonEndpoint::random()));
+        assert!(!reset.verify(&HavenIdentitySecret::generate().public(), visitor));
+        let forged = HavenReset::new(HavenIdentitySecret::generate(), visitor);
+        assert!(!forged.verify(&identity.public(), visitor));
     }
 }
@@ -0,0 +1,297 @@
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use async_event::Event;
+use dashmap::DashMap;
+use earendil_crypt::HavenFingerprint;
+use earendil_packet::crypt::{DhPublic, DhSecret};
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    stats::STATS,
+};
+
+use super::{unix_now, HavenEndpoint, HavenPacketConn};
+
+pub const BEACON_SUCCEEDED: &str = "haven.beacon.succeeded";
+pub const BEACON_FAILED: &str = "haven.beacon.failed";
+pub const BEACON_REREGISTERED: &str = "haven.beacon.reregistered";
+
+/// How long a haven keeps echoing on a beacon's connection. Long enough for the beacon's retries to get there, if its first handshake was lost.
+const BEACON_ECHO_LINGER: Duration = Duration::from_secs(30);
+
+/// How the beacons of every haven we serve went, for havens with beacons on.
+static BEACONS: CtxField<DashMap<HavenFingerprint, BeaconStatus>> = |_| DashMap::new();
+
+/// How a haven's beacons, which dial the haven the way any visitor would, have been going.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BeaconStatus {
+    pub haven: HavenFingerprint,
+    /// When the last beacon came back or failed, in seconds since the Unix epoch. None until the first one did.
+    pub last_at: Option<u64>,
+    /// The round-trip time of the last beacon that came back, in milliseconds.
+    pub last_rtt_ms: Option<u64>,
+    /// Why the last beacon failed, unless it came back.
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    pub successes: u64,
+    pub failures: u64,
+    /// How many times failing beacons made the haven re-register and republish its locator.
+    pub reregistrations: u64,
+    /// Whether enough beacons failed in a row that visitors likely can't reach the haven.
+    pub degraded: bool,
+}
+
+impl BeaconStatus {
+    fn new(haven: HavenFingerprint) -> Self {
+        Self {
+            haven,
+            last_at: None,
+            last_rtt_ms: None,
+            last_error: None,
+            consecutive_failures: 0,
+            successes: 0,
+            failures: 0,
+            reregistrations: 0,
+            degraded: false,
+        }
+    }
+
+    /// Records how a beacon went, returning whether the haven should re-register now.
+    fn record(
+        &mut self,
+        outcome: Result<Duration, String>,
+        failures_before_reregister: u32,
+    ) -> bool {
+        self.last_at = Some(unix_now());
+        match outcome {
+            Ok(rtt) => {
+                self.last_rtt_ms = Some(rtt.as_millis() as u64);
+                self.last_error = None;
+                self.consecutive_failures = 0;
+                self.successes += 1;
+                self.degraded = false;
+                false
+            }
+            Err(err) => {
+                self.last_error = Some(err);
+                self.consecutive_failures += 1;
+                self.failures += 1;
+                self.degraded = self.consecutive_failures >= failures_before_reregister;
+                // and again every so many failures, for as long as they last
+                let reregister = self.consecutive_failures % failures_before_reregister == 0;
+                if reregister {
+                    self.reregistrations += 1;
+                }
+                reregister
+            }
+        }
+    }
+}
+
+/// The beacon statuses of every haven we serve that has beacons on.
+pub fn haven_beacons(ctx: &DaemonContext) -> Vec<BeaconStatus> {
+    let mut beacons: Vec<BeaconStatus> = ctx
+        .get(BEACONS)
+        .iter()
+        .map(|entry| entry.value().clone())
+        .collect();
+    beacons.sort_by_key(|status| status.haven.to_string());
+    beacons
+}
+
+/// Whether the haven's beacons say that visitors likely can't reach it.
+pub fn is_degraded(ctx: &DaemonContext, haven: &HavenFingerprint) -> bool {
+    ctx.get(BEACONS)
+        .get(haven)
+        .map_or(false, |status| status.degraded)
+}
+
+/// What a haven's beacon shares with the rest of its listen loop.
+pub struct Beacon {
+    ctx: DaemonContext,
+    haven: HavenFingerprint,
+    /// Ephemeral keys of the beacons in flight. The haven echoes their connections rather than accepting them.
+    probes: Mutex<HashSet<DhPublic>>,
+    /// Bumped whenever the haven should re-register and republish its locator.
+    generation: AtomicU64,
+    changed: Event,
+}
+
+impl Beacon {
+    pub fn new(ctx: &DaemonContext, haven: HavenFingerprint) -> Self {
+        if ctx.init().haven_beacon.interval_secs > 0 {
+            ctx.get(BEACONS).insert(haven, BeaconStatus::new(haven));
+        }
+        Self {
+            ctx: ctx.clone(),
+            haven,
+            probes: Mutex::new(HashSet::new()),
+            generation: AtomicU64::new(0),
+            changed: Event::new(),
+        }
+    }
+
+    /// Whether a visitor's handshake comes from one of our own beacons.
+    pub fn is_probe(&self, visitor: &DhPublic) -> bool {
+        self.probes.lock().contains(visitor)
+    }
+
+    /// Counts how many times the beacon asked for the haven to re-register.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the beacon asks for the haven to re-register, if it didn't already since `generation`.
+    pub async fn wait_reregister(&self, generation: u64) {
+        self.changed
+            .wait_until(|| (self.generation() != generation).then_some(()))
+            .await
+    }
+
+    /// Has the haven re-register and republish its locator right away.
+    pub(super) fn reregister(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.changed.notify_all();
+    }
+}
+
+impl Drop for Beacon {
+    fn drop(&mut self) {
+        self.ctx.get(BEACONS).remove(&self.haven);
+    }
+}
+
+/// Dials the haven every so often through the DHT and its rendezvous, the way any visitor would, and makes it re-register and republish its locator when that keeps failing.
+pub async fn beacon_loop(
+    ctx: &DaemonContext,
+    endpoint: HavenEndpoint,
+    beacon: &Beacon,
+) -> anyhow::Result<()> {
+    let config = ctx.init().haven_beacon;
+    if config.interval_secs == 0 {
+        return smol::future::pending().await;
+    }
+    loop {
+        // randomized, so that beacons don't stand out by how regular they are
+        let wait = config.interval_secs as f64 * rand::thread_rng().gen_range(0.5..1.5);
+        smol::Timer::after(Duration::from_secs_f64(wait)).await;
+
+        let outcome = probe(ctx, endpoint, beacon)
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .await
+            .unwrap_or_else(|| Err(anyhow::anyhow!("timed out")))
+            .map_err(|err| format!("{err:#}"));
+        let (reregister, recovered, status) = {
+            let mut status = ctx
+                .get(BEACONS)
+                .entry(endpoint.fingerprint)
+                .or_insert_with(|| BeaconStatus::new(endpoint.fingerprint));
+            let was_degraded = status.degraded;
+            let reregister = status.record(outcome, config.failures_before_reregister);
+            (reregister, was_degraded && !status.degraded, status.clone())
+        };
+        if status.consecutive_failures == 0 {
+            ctx.get(STATS).incr(BEACON_SUCCEEDED);
+            tracing::debug!(
+                haven = display(endpoint.fingerprint),
+                rtt_ms = status.last_rtt_ms,
+                "haven beacon came back"
+            );
+        } else {
+            ctx.get(STATS).incr(BEACON_FAILED);
+            tracing::debug!(
+                haven = display(endpoint.fingerprint),
+                err = status.last_error,
+                "haven beacon failed"
+            );
+        }
+        if recovered {
+            tracing::info!(
+                haven = display(endpoint.fingerprint),
+                "haven is reachable through its rendezvous again"
+            );
+        }
+        if reregister {
+            ctx.get(STATS).incr(BEACON_REREGISTERED);
+            tracing::warn!(
+                haven = display(endpoint.fingerprint),
+                failures = status.consecutive_failures,
+                err = status.last_error,
+                "HAVEN UNREACHABLE: beacons keep failing, so visitors likely can't reach it. re-registering and republishing its locator"
+            );
+            beacon.reregister();
+        }
+    }
+}
+
+/// Dials the haven once, returning how long it took to echo a random packet back.
+async fn probe(
+    ctx: &DaemonContext,
+    endpoint: HavenEndpoint,
+    beacon: &Beacon,
+) -> anyhow::Result<Duration> {
+    let esk = DhSecret::generate();
+    let visitor = esk.public();
+    beacon.probes.lock().insert(visitor);
+    scopeguard::defer!({
+        beacon.probes.lock().remove(&visitor);
+    });
+    let nonce: [u8; 32] = rand::random();
+    let start = Instant::now();
+    // beacons go as normal messages, since they measure what visitors get
+    let conn = HavenPacketConn::connect_inner(ctx, endpoint, Some(&nonce), esk).await?;
+    loop {
+        if conn.recv_pkt().await?.as_ref() == nonce {
+            return Ok(start.elapsed());
+        }
+    }
+}
+
+/// Answers a beacon's connection on the haven side, by echoing whatever comes in on it for a while.
+pub async fn echo(conn: HavenPacketConn) {
+    let echo_all = async {
+        loop {
+            let pkt = conn.recv_pkt().await?;
+            conn.send_pkt(&pkt).await?;
+        }
+    };
+    let _: Option<anyhow::Result<()>> = echo_all.timeout(BEACON_ECHO_LINGER).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::HavenIdentitySecret;
+
+    use super::*;
+
+    #[test]
+    fn failures_in_a_row_degrade_and_reregister() {
+        let haven = HavenIdentitySecret::generate().public().fingerprint();
+        let mut status = BeaconStatus::new(haven);
+        assert!(!status.record(Ok(Duration::from_millis(300)), 2));
+        assert_eq!(status.last_rtt_ms, Some(300));
+
+        assert!(!status.record(Err("timed out".into()), 2));
+        assert!(!status.degraded);
+        assert!(status.record(Err("timed out".into()), 2));
+        assert!(status.degraded);
+        // staying down keeps re-registering, but not on every beacon
+        assert!(!status.record(Err("timed out".into()), 2));
+        assert!(status.record(Err("timed out".into()), 2));
+        assert_eq!(status.reregistrations, 2);
+        assert_eq!(status.last_error.as_deref(), Some("timed out"));
+
+        assert!(!status.record(Ok(Duration::from_millis(500)), 2));
+        assert!(!status.degraded);
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!((status.successes, status.failures), (2, 4));
+    }
+}
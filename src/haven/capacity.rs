@@ -0,0 +1,345 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use earendil_crypt::HavenFingerprint;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    global_rpc::server::REGISTERED_HAVENS,
+    stats::STATS,
+};
+
+use super::{RegisterHavenError, RegisterHavenReq};
+
+pub const REGISTER_NOT_OFFERED: &str = "rendezvous.register.not_offered";
+pub const REGISTER_FULL: &str = "rendezvous.register.full";
+pub const FORWARD_SHED_BYTES: &str = "rendezvous.forward.shed_bytes";
+
+/// How often the forwarding rates are measured, and which havens to shed decided anew.
+const BUDGET_WINDOW: Duration = Duration::from_secs(1);
+
+/// How fast we forwarded haven traffic over the last few windows, in bytes per second.
+static FORWARD_RATE: CtxField<AtomicU64> = |_| AtomicU64::new(0);
+
+/// Held from counting the registered havens until a new one is in, so that registrations racing each other can't all fit under the cap.
+static ADMITTING: CtxField<Mutex<()>> = |_| Mutex::new(());
+
+/// How much of its rendezvous capacity a relay is using, against the caps it's configured with.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RendezvousOccupancy {
+    pub enabled: bool,
+    pub registered_havens: u64,
+    pub max_registered_havens: usize,
+    /// How fast we forwarded haven traffic lately, in bytes per second.
+    pub forward_bytes_per_sec: u64,
+    pub max_forward_bandwidth: Option<u64>,
+}
+
+pub fn rendezvous_occupancy(ctx: &DaemonContext) -> RendezvousOccupancy {
+    let cfg = &ctx.init().rendezvous;
+    RendezvousOccupancy {
+        enabled: cfg.enabled,
+        registered_havens: ctx.get(REGISTERED_HAVENS).value_count(),
+        max_registered_havens: cfg.max_registered_havens,
+        forward_bytes_per_sec: ctx.get(FORWARD_RATE).load(Ordering::Relaxed),
+        max_forward_bandwidth: cfg.max_forward_bandwidth,
+    }
+}
+
+/// Takes a haven's registration, which must already be verified, if there's room for it. Havens already registered, at this endpoint or another one, can always renew, however many havens there are.
+pub fn admit_registration(
+    ctx: &DaemonContext,
+    registration: &RegisterHavenReq,
+) -> Result<(), RegisterHavenError> {
+    let cfg = &ctx.init().rendezvous;
+    if !cfg.enabled {
+        ctx.get(STATS).incr(REGISTER_NOT_OFFERED);
+        return Err(RegisterHavenError::NotOffered);
+    }
+    let _admitting = ctx.get(ADMITTING).lock().unwrap();
+    let registered = ctx.get(REGISTERED_HAVENS);
+    let renewing = registered.get_by_key(&registration.anon_id).is_some()
+        || registered
+            .get_by_value(&registration.identity_pk.fingerprint())
+            .is_some();
+    if !renewing && registered.value_count() >= cfg.max_registered_havens as u64 {
+        ctx.get(STATS).incr(REGISTER_FULL);
+        return Err(RegisterHavenError::Full(cfg.max_registered_havens));
+    }
+    registered.insert(registration.anon_id, registration.identity_pk.fingerprint());
+    Ok(())
+}
+
+/// Keeps the haven traffic we forward under the configured bandwidth cap, by shedding the traffic of the heaviest havens, and of the newest among equally heavy ones. Havens are ranked by how much they'd have us forward, shed traffic included, so that a haven over its share stays shed for as long as it keeps asking for that much. Only what a haven sends counts towards its rank: visitors are limited on their own, and could otherwise get any haven shed just by flooding it.
+pub(super) struct ForwardBudget {
+    cap: Option<u64>,
+    havens: HashMap<HavenFingerprint, HavenUsage>,
+    shed: HashSet<HavenFingerprint>,
+    window_start: Instant,
+    forwarded: u64,
+    rate: f64,
+}
+
+struct HavenUsage {
+    first_seen: Instant,
+    /// Bytes asked to be forwarded in the current window.
+    window_bytes: u64,
+    /// Bytes per second asked to be forwarded, averaged over the last few windows.
+    demand: f64,
+}
+
+impl ForwardBudget {
+    pub fn new(cap: Option<u64>, now: Instant) -> Self {
+        Self {
+            cap,
+            havens: HashMap::new(),
+            shed: HashSet::new(),
+            window_start: now,
+            forwarded: 0,
+            rate: 0.0,
+        }
+    }
+
+    /// Accounts for a message of `len` bytes from `haven`, returning whether to forward it.
+    pub fn allow(&mut self, haven: HavenFingerprint, len: usize, now: Instant) -> bool {
+        self.tick(now);
+        self.havens
+            .entry(haven)
+            .or_insert(HavenUsage {
+                first_seen: now,
+                window_bytes: 0,
+                demand: 0.0,
+            })
+            .window_bytes += len as u64;
+        if self.shed.contains(&haven) {
+            return false;
+        }
+        self.forwarded += len as u64;
+        true
+    }
+
+    /// Accounts for a message of `len` bytes from a visitor to `haven`, returning whether to forward it. It isn't charged to the haven, but a shed haven gets none, since its answers would be shed anyway.
+    pub fn allow_visitor(&mut self, haven: HavenFingerprint, len: usize, now: Instant) -> bool {
+        self.tick(now);
+        if self.shed.contains(&haven) {
+            return false;
+        }
+        self.forwarded += len as u64;
+        true
+    }
+
+    /// How fast we forwarded lately, in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.rate as u64
+    }
+
+    fn tick(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= BUDGET_WINDOW {
+            self.roll(elapsed);
+            self.window_start = now;
+        }
+    }
+
+    fn roll(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        self.rate = (self.rate + self.forwarded as f64 / secs) / 2.0;
+        self.forwarded = 0;
+        // havens that went quiet are forgotten, and count as new if they come back
+        self.havens.retain(|_, usage| {
+            usage.demand = (usage.demand + usage.window_bytes as f64 / secs) / 2.0;
+            usage.window_bytes = 0;
+            usage.demand >= 1.0
+        });
+        self.shed.clear();
+        let Some(cap) = self.cap else {
+            return;
+        };
+        let mut ranked: Vec<(&HavenFingerprint, &HavenUsage)> = self.havens.iter().collect();
+        ranked.sort_by(|(_, a), (_, b)| {
+            a.demand
+                .total_cmp(&b.demand)
+                .then(a.first_seen.cmp(&b.first_seen))
+        });
+        // the lightest and oldest havens fit in the cap first
+        let mut kept = 0.0;
+        for (haven, usage) in ranked {
+            kept += usage.demand;
+            if kept > cap as f64 {
+                self.shed.insert(*haven);
+            }
+        }
+        if !self.shed.is_empty() {
+            tracing::debug!(
+                shed = self.shed.len(),
+                havens = self.havens.len(),
+                "over the rendezvous bandwidth cap"
+            );
+        }
+    }
+}
+
+/// Publishes how fast the forward loop forwarded lately, for [rendezvous_occupancy].
+pub(super) fn publish_forward_rate(ctx: &DaemonContext, budget: &ForwardBudget) {
+    ctx.get(FORWARD_RATE)
+        .store(budget.rate(), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::{AnonEndpoint, HavenIdentitySecret};
+    use serde_json::json;
+
+    use super::*;
+    use crate::global_rpc::{server::GlobalRpcImpl, GlobalRpcProtocol};
+
+    fn ctx(rendezvous: serde_json::Value) -> DaemonContext {
+        DaemonContext::new(serde_json::from_value(json!({ "rendezvous": rendezvous })).unwrap())
+    }
+
+    fn register(
+        ctx: &DaemonContext,
+        anon_id: AnonEndpoint,
+        haven: HavenIdentitySecret,
+    ) -> Result<(), RegisterHavenError> {
+        smol::future::block_on(
+            GlobalRpcImpl::new(ctx.clone())
+                .alloc_forward(RegisterHavenReq::new(anon_id, haven, 1234)),
+        )
+    }
+
+    #[test]
+    fn disabled_rendezvous_turns_havens_away() {
+        let ctx = ctx(json!({ "enabled": false }));
+        assert!(matches!(
+            register(
+                &ctx,
+                AnonEndpoint::random(),
+                HavenIdentitySecret::generate()
+            ),
+            Err(RegisterHavenError::NotOffered)
+        ));
+        assert_eq!(ctx.get(REGISTERED_HAVENS).value_count(), 0);
+        assert_eq!(ctx.get(STATS).snapshot()[REGISTER_NOT_OFFERED], 1);
+        assert!(!rendezvous_occupancy(&ctx).enabled);
+    }
+
+    #[test]
+    fn registrations_over_the_cap_are_rejected() {
+        let ctx = ctx(json!({ "max_registered_havens": 2 }));
+        let havens: Vec<(AnonEndpoint, HavenIdentitySecret)> = (0..3)
+            .map(|_| (AnonEndpoint::random(), HavenIdentitySecret::generate()))
+            .collect();
+        register(&ctx, havens[0].0, havens[0].1).unwrap();
+        register(&ctx, havens[1].0, havens[1].1).unwrap();
+        assert!(matches!(
+            register(&ctx, havens[2].0, havens[2].1),
+            Err(RegisterHavenError::Full(2))
+        ));
+        assert_eq!(ctx.get(STATS).snapshot()[REGISTER_FULL], 1);
+
+        // registered havens still renew, even from a fresh endpoint
+        register(&ctx, havens[0].0, havens[0].1).unwrap();
+        register(&ctx, AnonEndpoint::random(), havens[1].1).unwrap();
+        let occupancy = rendezvous_occupancy(&ctx);
+        assert_eq!(occupancy.registered_havens, 2);
+        assert_eq!(occupancy.max_registered_havens, 2);
+
+        // and bad signatures are still told apart
+        let mut forged = RegisterHavenReq::new(havens[2].0, havens[2].1, 1234);
+        forged.port = 4321;
+        assert!(matches!(
+            smol::future::block_on(GlobalRpcImpl::new(ctx.clone()).alloc_forward(forged)),
+            Err(RegisterHavenError::BadSignature(_))
+        ));
+    }
+
+    #[test]
+    fn heaviest_and_newest_havens_are_shed_first() {
+        let start = Instant::now();
+        let mut budget = ForwardBudget::new(Some(10_000), start);
+        let [old, new, heavy] =
+            [(); 3].map(|_| HavenIdentitySecret::generate().public().fingerprint());
+        let run = |budget: &mut ForwardBudget, second: u64| {
+            let now = start + Duration::from_secs(second);
+            [(old, 4_000), (new, 4_000), (heavy, 20_000)].map(|(haven, bytes)| {
+                (0..bytes / 1000)
+                    .filter(|_| budget.allow(haven, 1000, now))
+                    .count()
+                    * 1000
+            })
+        };
+        // nothing is shed before there's a measurement to go on
+        assert_eq!(run(&mut budget, 0), [4_000, 4_000, 20_000]);
+        // the heavy haven alone is over the cap
+        assert_eq!(run(&mut budget, 1), [4_000, 4_000, 0]);
+        assert_eq!(run(&mut budget, 2), [4_000, 4_000, 0]);
+        assert!(budget.rate() > 0);
+
+        // with the cap only fitting one of two equally heavy havens, the one seen first keeps going
+        let mut budget = ForwardBudget::new(Some(3_000), start);
+        assert!(budget.allow(old, 4_000, start));
+        assert!(budget.allow(new, 4_000, start + Duration::from_millis(500)));
+        let later = start + Duration::from_secs(1);
+        assert!(budget.allow(old, 1_000, later));
+        assert!(!budget.allow(new, 1_000, later));
+    }
+
+    #[test]
+    fn visitors_cannot_get_a_haven_shed() {
+        let start = Instant::now();
+        let mut budget = ForwardBudget::new(Some(10_000), start);
+        let [quiet, heavy] =
+            [(); 2].map(|_| HavenIdentitySecret::generate().public().fingerprint());
+        for second in 0..3 {
+            let now = start + Duration::from_secs(second);
+            // visitors flood the quiet haven with far more than the cap
+            assert_eq!(
+                (0..50)
+                    .filter(|_| budget.allow_visitor(quiet, 1000, now))
+                    .count(),
+                50
+            );
+            assert!(budget.allow(quiet, 1000, now));
+            if second > 0 {
+                assert!(!budget.allow(heavy, 20_000, now));
+            } else {
+                assert!(budget.allow(heavy, 20_000, now));
+            }
+        }
+        // a haven shed for what it sends hears from no visitors either
+        assert!(!budget.allow_visitor(heavy, 1000, start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn racing_registrations_stay_under_the_cap() {
+        let ctx = ctx(json!({ "max_registered_havens": 4 }));
+        let ctx = &ctx;
+        let admitted = std::thread::scope(|s| {
+            let racers: Vec<_> = (0..32)
+                .map(|_| {
+                    let registration = RegisterHavenReq::new(
+                        AnonEndpoint::random(),
+                        HavenIdentitySecret::generate(),
+                        1234,
+                    );
+                    s.spawn(move || admit_registration(ctx, &registration).is_ok())
+                })
+                .collect();
+            racers
+                .into_iter()
+                .filter(|racer| racer.join().unwrap())
+                .count()
+        });
+        assert_eq!(admitted, 4);
+        assert_eq!(ctx.get(REGISTERED_HAVENS).value_count(), 4);
+        assert_eq!(ctx.get(STATS).snapshot()[REGISTER_FULL], 28);
+    }
+}
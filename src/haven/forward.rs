@@ -0,0 +1,263 @@
+use std::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use earendil_crypt::{AnonEndpoint, HavenFingerprint};
+use lru::LruCache;
+use parking_lot::Mutex;
+use stdcode::StdcodeSerializeExt;
+use tracing::instrument;
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    global_rpc::server::REGISTERED_HAVENS,
+    limits,
+    n2r_socket::N2rRelaySocket,
+    stats::STATS,
+};
+
+use super::{
+    capacity::{publish_forward_rate, ForwardBudget, FORWARD_SHED_BYTES},
+    vrh::{H2rMessage, HavenMsg, R2hMessage, V2rMessage},
+    HAVEN_FORWARD_DOCK,
+};
+
+/// How many messages per second each visitor may have us forward, at most. A connection rarely needs a fraction of this.
+const VISITOR_MSGS_PER_SEC: f64 = 500.0;
+const VISITOR_MSG_BURST: f64 = 1000.0;
+
+/// How many visitors we keep rate limits for. Visitors that fall out start over with a full burst, which is no worse than a visitor using a fresh endpoint.
+const TRACKED_VISITORS: usize = 10_000;
+
+pub const FORWARD_MALFORMED: &str = "rendezvous.forward.malformed";
+pub const FORWARD_UNKNOWN_DESTINATION: &str = "rendezvous.forward.unknown_destination";
+pub const FORWARD_OVER_SIZE: &str = "rendezvous.forward.over_size";
+pub const FORWARD_RATE_LIMITED: &str = "rendezvous.forward.rate_limited";
+pub const FORWARD_SEND_FAILED: &str = "rendezvous.forward.send_failed";
+pub const FORWARD_SHED: &str = "rendezvous.forward.shed";
+
+/// The sealed packets a rendezvous forwarded between visitors and havens, and whether to tamper with the next ones, for checking that it can neither read nor forge them. Only relays built with the `rendezvous-tap` feature fill it in or tamper; others leave it empty.
+#[derive(Default)]
+pub struct RendezvousTap {
+    /// Every regular haven message forwarded so far, either way, as it arrived.
+    pub forwarded: Mutex<Vec<Bytes>>,
+    /// While set, a bit is flipped in every regular haven message before it's forwarded.
+    pub tamper: AtomicBool,
+}
+
+static RENDEZVOUS_TAP: CtxField<RendezvousTap> = |_| RendezvousTap::default();
+
+/// What this daemon forwarded as a rendezvous. See [RendezvousTap].
+pub fn rendezvous_tap(ctx: &DaemonContext) -> &RendezvousTap {
+    ctx.get(RENDEZVOUS_TAP)
+}
+
+/// Records a haven message we're about to forward, and tampers with it while told to, if built to.
+fn tap(ctx: &DaemonContext, payload: &mut HavenMsg) {
+    if !cfg!(feature = "rendezvous-tap") {
+        return;
+    }
+    let HavenMsg::Regular(sealed) = payload else {
+        return;
+    };
+    let tap = rendezvous_tap(ctx);
+    tap.forwarded.lock().push(sealed.clone());
+    if tap.tamper.load(Ordering::SeqCst) {
+        let mut tampered = sealed.to_vec();
+        if let Some(last) = tampered.last_mut() {
+            *last ^= 1;
+        }
+        *sealed = tampered.into();
+    }
+}
+
+/// Why a rendezvous dropped a message rather than forwarding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dropped {
+    Malformed,
+    UnknownDestination,
+    OverSize,
+    RateLimited,
+    SendFailed,
+    Shed,
+}
+
+impl Dropped {
+    fn stat(self) -> &'static str {
+        match self {
+            Dropped::Malformed => FORWARD_MALFORMED,
+            Dropped::UnknownDestination => FORWARD_UNKNOWN_DESTINATION,
+            Dropped::OverSize => FORWARD_OVER_SIZE,
+            Dropped::RateLimited => FORWARD_RATE_LIMITED,
+            Dropped::SendFailed => FORWARD_SEND_FAILED,
+            Dropped::Shed => FORWARD_SHED,
+        }
+    }
+}
+
+#[instrument(skip(ctx))]
+/// Loop that listens to and handles incoming haven forwarding requests. Anyone can send to the forward dock, so nothing a single message does may take the loop down.
+pub async fn rendezvous_forward_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let socket = N2rRelaySocket::bind(ctx.clone(), Some(HAVEN_FORWARD_DOCK))?;
+    let mut visitors = VisitorLimits::new();
+    let mut budget =
+        ForwardBudget::new(ctx.init().rendezvous.max_forward_bandwidth, Instant::now());
+
+    loop {
+        let (msg, src_ep) = socket.recv_from().await?;
+        let len = msg.len();
+        let forwarded = forward_once(&ctx, &socket, &mut visitors, &mut budget, msg, src_ep).await;
+        publish_forward_rate(&ctx, &budget);
+        if let Err(dropped) = forwarded {
+            if dropped == Dropped::Shed {
+                ctx.get(STATS).add(FORWARD_SHED_BYTES, len as u64);
+            }
+            ctx.get(STATS).incr(dropped.stat());
+            tracing::debug!(
+                src_ep = debug(src_ep),
+                reason = debug(dropped),
+                "dropped a message to forward"
+            );
+        }
+    }
+}
+
+async fn forward_once(
+    ctx: &DaemonContext,
+    socket: &N2rRelaySocket,
+    visitors: &mut VisitorLimits,
+    budget: &mut ForwardBudget,
+    msg: Bytes,
+    src_ep: AnonEndpoint,
+) -> Result<(), Dropped> {
+    // nothing that fits in one N2R message is bigger, so this only matters if something upstream stops enforcing that
+    if msg.len() > limits::max_single_message(HAVEN_FORWARD_DOCK) {
+        return Err(Dropped::OverSize);
+    }
+    let src_haven = ctx.get(REGISTERED_HAVENS).get_by_key(&src_ep);
+    if let Some(src_haven) = src_haven {
+        // src is haven
+        let mut inner: H2rMessage = stdcode::deserialize(&msg).map_err(|_| Dropped::Malformed)?;
+        tracing::debug!(
+            src_ep = debug(src_ep),
+            dest_visitor = debug(inner.dest_visitor),
+            len = msg.len(),
+            "received H2R msg",
+        );
+        if !budget.allow(src_haven, msg.len(), Instant::now()) {
+            return Err(Dropped::Shed);
+        }
+        tap(ctx, &mut inner.payload);
+        let body: Bytes = inner.payload.stdcode().into();
+        tracing::debug!(dest_visitor = debug(inner.dest_visitor), "sending bare");
+        send(socket, body, inner.dest_visitor).await
+    } else {
+        // havens answer every visitor through us, so only visitors are limited
+        if !visitors.allow(src_ep, Instant::now()) {
+            return Err(Dropped::RateLimited);
+        }
+        let mut inner: V2rMessage = stdcode::deserialize(&msg).map_err(|_| Dropped::Malformed)?;
+        if !well_formed(&inner.dest_haven.fingerprint) {
+            return Err(Dropped::Malformed);
+        }
+        let haven_anon_ep = ctx
+            .get(REGISTERED_HAVENS)
+            .get_by_value(&inner.dest_haven.fingerprint)
+            .ok_or(Dropped::UnknownDestination)?;
+        if !budget.allow_visitor(inner.dest_haven.fingerprint, msg.len(), Instant::now()) {
+            return Err(Dropped::Shed);
+        }
+        tracing::debug!(
+            src_ep = debug(src_ep),
+            haven_anon_ep = debug(haven_anon_ep),
+            "received V2R msg"
+        );
+        tap(ctx, &mut inner.payload);
+
+        let body: Bytes = R2hMessage {
+            src_visitor: src_ep,
+
+            payload: inner.payload,
+        }
+        .stdcode()
+        .into();
+
+        tracing::debug!(haven_anon_ep = debug(haven_anon_ep), "sending R2H");
+        send(socket, body, haven_anon_ep).await
+    }
+}
+
+async fn send(socket: &N2rRelaySocket, body: Bytes, dest: AnonEndpoint) -> Result<(), Dropped> {
+    socket.send_to(body, dest).await.map_err(|err| {
+        tracing::debug!(err = debug(err), dest = debug(dest), "could not forward");
+        Dropped::SendFailed
+    })
+}
+
+/// Whether a fingerprint could belong to a haven at all. Fingerprints are hashes, so an all-zero one only turns up in garbage.
+fn well_formed(fingerprint: &HavenFingerprint) -> bool {
+    bytemuck::bytes_of(fingerprint).iter().any(|b| *b != 0)
+}
+
+/// A token bucket for each recently seen visitor.
+struct VisitorLimits {
+    buckets: LruCache<AnonEndpoint, (f64, Instant)>,
+}
+
+impl VisitorLimits {
+    fn new() -> Self {
+        Self {
+            buckets: LruCache::new(
+                NonZeroUsize::new(TRACKED_VISITORS).expect("must track at least one visitor"),
+            ),
+        }
+    }
+
+    /// Takes a token from the visitor's bucket, returning whether there was one.
+    fn allow(&mut self, visitor: AnonEndpoint, now: Instant) -> bool {
+        let (tokens, updated) = self
+            .buckets
+            .get_or_insert_mut(visitor, || (VISITOR_MSG_BURST, now));
+        let elapsed = now.saturating_duration_since(*updated).as_secs_f64();
+        *tokens = (*tokens + elapsed * VISITOR_MSGS_PER_SEC).min(VISITOR_MSG_BURST);
+        *updated = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn visitors_are_limited_separately() {
+        let mut visitors = VisitorLimits::new();
+        let (flooder, other) = (AnonEndpoint::random(), AnonEndpoint::random());
+        let start = Instant::now();
+        let allowed = (0..2000).filter(|_| visitors.allow(flooder, start)).count();
+        assert_eq!(allowed, VISITOR_MSG_BURST as usize);
+        assert!(visitors.allow(other, start));
+        // the bucket refills over time
+        assert!(visitors.allow(flooder, start + Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn garbage_fingerprints_are_malformed() {
+        let zeroed: HavenFingerprint = bytemuck::Zeroable::zeroed();
+        assert!(!well_formed(&zeroed));
+        let real = earendil_crypt::HavenIdentitySecret::generate()
+            .public()
+            .fingerprint();
+        assert!(well_formed(&real));
+    }
+}
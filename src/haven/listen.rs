@@ -1,55 +1,114 @@
 use bytes::Bytes;
 
 use earendil_crypt::{AnonEndpoint, HavenIdentitySecret, RelayFingerprint};
-use earendil_packet::crypt::{AeadKey, DhSecret};
+use earendil_packet::crypt::{DhPublic, DhSecret};
+use lru::LruCache;
 use smol::{
     channel::{Receiver, Sender},
     future::FutureExt as _,
     Timer,
 };
 use smol_timeout::TimeoutExt;
-use std::{collections::HashMap, sync::atomic::AtomicU64, time::Duration};
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    context::DaemonContext,
+    clock,
+    context::{DaemonContext, RELAY_GRAPH},
     dht::dht_insert,
     global_rpc::{transport::GlobalRpcTransport, GlobalRpcClient},
-    haven::vrh::HavenHandshake,
-    n2r_socket::{N2rClientSocket, RelayEndpoint},
+    haven::vrh::{HavenHandshake, HavenReset, VisitorHandshake},
+    limits::MAX_PIPELINED_PAYLOAD,
+    n2r::{self, MessageClass},
+    n2r_socket::{shaper::Shaper, N2rClientSocket, RelayEndpoint},
+    scope::{spawn_owned, spawn_scoped, Stage},
 };
 
 use super::{
+    beacon::{self, beacon_loop, Beacon},
+    early_key,
+    rendezvous::RendezvousHealth,
+    session::{self, directional_keys, Opener, Sealer},
     vrh::{H2rMessage, HavenMsg, R2hMessage},
-    HavenLocator, HavenPacketConn, RegisterHavenReq, HAVEN_DN, HAVEN_FORWARD_DOCK, HAVEN_UP,
+    HavenEndpoint, HavenPacketConn, RegisterHavenReq, HAVEN_FORWARD_DOCK,
 };
 
+/// How many visitors we remember resetting, so that a visitor that keeps sending on a connection we don't know gets one reset rather than one per packet.
+const RESET_VISITORS: usize = 1000;
+
+/// How long a haven waits for its first packet on a pipelined connection, so that it can go back together with the handshake.
+const PIPELINED_REPLY_WAIT: Duration = Duration::from_secs(1);
+
+/// How long a haven waits before asking a rendezvous that turned it away again.
+const REFUSED_RETRY: Duration = Duration::from_secs(60);
+
+/// Keeps a haven registered and its locator published, handing off accepted connections. The locator advertises `onion_sk`, which visitors seal pipelined first packets to. Locators published with a `locator_ttl` expire unless refreshed within it. A beacon checks that visitors can actually reach the haven, and has it re-register when they can't.
 pub async fn listen_loop(
     ctx: DaemonContext,
     identity: HavenIdentitySecret,
+    onion_sk: DhSecret,
     port: u16,
-    rendezvous: RelayFingerprint,
+    rendezvous: Vec<RelayFingerprint>,
+    anon_ep: AnonEndpoint,
+    locator_ttl: Option<Duration>,
     send_accepted: Sender<HavenPacketConn>,
 ) -> anyhow::Result<()> {
-    let anon_ep = AnonEndpoint::random();
-    let n2r_socket = N2rClientSocket::bind(ctx.clone(), anon_ep)?;
+    let n2r_socket = N2rClientSocket::bind(ctx.clone(), anon_ep)?.without_fallback();
+    let health = Arc::new(RendezvousHealth::new(rendezvous));
+    let beacon = Arc::new(Beacon::new(&ctx, identity.public().fingerprint()));
     loop {
-        // register ourselves with rendezvous & upload info to DHT in a loop
-        let register_loop = register_haven(
+        // register ourselves with every rendezvous in a loop
+        let register_loops =
+            futures::future::try_join_all(health.all().into_iter().map(|rendezvous| {
+                register_haven(
+                    &ctx,
+                    identity,
+                    port,
+                    rendezvous,
+                    n2r_socket.local_endpoint(),
+                    &health,
+                    &beacon,
+                )
+            }));
+        // upload a locator pointing at the healthiest rendezvous to the DHT in a loop
+        let publish_loop = publish_locator(
             &ctx,
             identity,
-            port,
-            rendezvous,
-            n2r_socket.local_endpoint(),
+            onion_sk.public(),
+            &health,
+            &beacon,
+            locator_ttl,
+        );
+        // check that visitors can reach us, the same way they would
+        let beacon_checks = beacon_loop(
+            &ctx,
+            HavenEndpoint::from_identity(&identity.public(), port),
+            &beacon,
         );
         // start loop that demultiplexes incoming messages
         let demultiplex_loop = haven_demultiplex(
+            &ctx,
             identity,
+            onion_sk.clone(),
             n2r_socket.clone(),
-            rendezvous,
+            health.clone(),
+            beacon.clone(),
             send_accepted.clone(),
         );
-        if let Err(err) = register_loop.race(demultiplex_loop).await {
+        // the rendezvous may only hold reply blocks anchored at a relay we roamed away from
+        let roaming_watch = reregister_on_roam(&ctx, n2r_socket.local_endpoint(), &beacon);
+        if let Err(err) = async { register_loops.await.map(|_| ()) }
+            .race(publish_loop)
+            .race(demultiplex_loop)
+            .race(beacon_checks)
+            .race(roaming_watch)
+            .await
+        {
             tracing::warn!(err = debug(err), "restarting listen");
             smol::Timer::after(Duration::from_secs(1)).await;
         }
@@ -62,20 +121,22 @@ async fn register_haven(
     port: u16,
     rendezvous: RelayFingerprint,
     anon_endpoint: AnonEndpoint,
+    health: &RendezvousHealth,
+    beacon: &Beacon,
 ) -> anyhow::Result<()> {
-    let esk = DhSecret::generate();
-    let epk = esk.public();
     let forward_req = RegisterHavenReq::new(anon_endpoint, identity, port);
     let gclient = GlobalRpcClient(GlobalRpcTransport::new(
         ctx.clone(),
         rendezvous,
-        N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())?,
+        N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())?
+            .with_class(MessageClass::Background)
+            .without_fallback(),
     ));
     loop {
-        let dht_socket = N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())?;
+        let generation = beacon.generation();
         match gclient
             .alloc_forward(forward_req.clone())
-            .timeout(Duration::from_secs(10))
+            .timeout(ctx.init().rpc_timeouts.forward())
             .await
         {
             Some(Err(e)) => {
@@ -84,46 +145,137 @@ async fn register_haven(
                     rendezvous,
                     e
                 );
+                health.record(rendezvous, false);
                 Timer::after(Duration::from_secs(3)).await;
-                continue;
             }
             None => {
-                tracing::debug!("registering haven rendezvous relay timed out");
+                tracing::debug!("registering haven rendezvous relay {rendezvous} timed out");
+                health.record(rendezvous, false);
                 Timer::after(Duration::from_secs(3)).await;
             }
-            _ => {
-                tracing::debug!("registering haven {}", identity.public().fingerprint());
-                dht_insert(
-                    ctx,
-                    HavenLocator::new(identity, epk, rendezvous),
-                    &dht_socket,
-                )
-                .timeout(Duration::from_secs(30))
+            Some(Ok(Err(err))) => {
+                tracing::warn!("rendezvous {rendezvous} refused to register our haven: {err}");
+                health.record_refused(rendezvous);
+                // it won't change its mind soon, so we lean on our other rendezvous meanwhile
+                Timer::after(REFUSED_RETRY).await;
+            }
+            Some(Ok(Ok(()))) => {
+                tracing::debug!(
+                    "registered haven {} with rendezvous {rendezvous}",
+                    identity.public().fingerprint()
+                );
+                // we must be able to keep reaching our rendezvous, however full the relay graph gets
+                ctx.get(RELAY_GRAPH).read().mark_used([rendezvous]);
+                health.record_registered(rendezvous);
+                // or right away, if the beacon says visitors can't reach us
+                async {
+                    Timer::after(Duration::from_secs(5)).await;
+                }
+                .race(beacon.wait_reregister(generation))
                 .await;
-                Timer::after(Duration::from_secs(5)).await;
             }
         }
     }
 }
 
+/// Has the haven re-register whenever we roam away from a relay that reply blocks for its endpoint were anchored at, so that the rendezvous gets fresh ones and visitors reach us again without waiting for the beacon to notice.
+async fn reregister_on_roam(
+    ctx: &DaemonContext,
+    anon_endpoint: AnonEndpoint,
+    beacon: &Beacon,
+) -> anyhow::Result<()> {
+    let mut after = n2r::last_roaming_event(ctx);
+    loop {
+        for (seq, event) in n2r::roaming_events(ctx, after).await {
+            after = seq;
+            if event.endpoints.contains(&anon_endpoint) {
+                tracing::debug!(
+                    from = display(event.from),
+                    "re-registering haven after roaming"
+                );
+                beacon.reregister();
+            }
+        }
+    }
+}
+
+/// Keeps a locator pointing at our healthiest rendezvous in the DHT, republishing right away whenever the healthiest one changes, or the beacon asks for it.
+async fn publish_locator(
+    ctx: &DaemonContext,
+    identity: HavenIdentitySecret,
+    epk: DhPublic,
+    health: &RendezvousHealth,
+    beacon: &Beacon,
+    ttl: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mut published: Option<(RelayFingerprint, std::time::Instant)> = None;
+    let mut generation = beacon.generation();
+    loop {
+        if let Some(locator) = health.locator(identity, epk) {
+            let rendezvous = locator.rendezvous_point;
+            let due = match published {
+                _ if beacon.generation() != generation => true,
+                Some((prev, _)) if prev != rendezvous => {
+                    tracing::info!(
+                        prev = display(prev),
+                        next = display(rendezvous),
+                        "haven rendezvous failing over"
+                    );
+                    true
+                }
+                Some((_, at)) => at.elapsed() >= Duration::from_secs(5),
+                None => true,
+            };
+            if due {
+                let locator = match ttl {
+                    Some(ttl) => {
+                        let expires_at = clock::unix_now(ctx) + ttl.as_secs();
+                        locator.expiring(identity, expires_at)
+                    }
+                    None => locator,
+                };
+                dht_insert(ctx, locator).await;
+                published = Some((rendezvous, std::time::Instant::now()));
+                generation = beacon.generation();
+            }
+        }
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
 #[tracing::instrument(skip_all, fields(identity=display(identity.public().fingerprint())))]
 async fn haven_demultiplex(
+    ctx: &DaemonContext,
     identity: HavenIdentitySecret,
+    onion_sk: DhSecret,
     n2r_socket: N2rClientSocket,
-    rendezvous: RelayFingerprint,
+    health: Arc<RendezvousHealth>,
+    beacon: Arc<Beacon>,
     send_accepted: Sender<HavenPacketConn>,
 ) -> anyhow::Result<()> {
     let resupply_loop = async {
         loop {
             smol::Timer::after(Duration::from_secs(10)).await;
-            tracing::trace!("resupplying reply blocks for the rendezvous ");
-            n2r_socket.supply_reply_blocks(rendezvous).await?;
+            for rendezvous in health.all() {
+                tracing::trace!("resupplying reply blocks for rendezvous {rendezvous}");
+                // one unreachable rendezvous is no reason to stop serving visitors through the others
+                if let Err(err) = n2r_socket.supply_reply_blocks(rendezvous).await {
+                    tracing::debug!(
+                        rendezvous = display(rendezvous),
+                        err = debug(err),
+                        "could not resupply reply blocks"
+                    );
+                    health.record(rendezvous, false);
+                }
+            }
         }
     };
 
     resupply_loop
         .race(async {
             let mut conn_queues: HashMap<AnonEndpoint, (Sender<Bytes>, DhSecret)> = HashMap::new();
+            let mut reset_visitors: LruCache<AnonEndpoint, ()> =
+                LruCache::new(NonZeroUsize::new(RESET_VISITORS).unwrap());
             loop {
                 // *occasionally* cleanup the conn_queue. the probability given here makes this asymptotically constant time.
                 if rand::random::<f64>() < 1.0 / (conn_queues.len() as f64) {
@@ -131,9 +283,15 @@ async fn haven_demultiplex(
                     conn_queues.retain(|_, q| q.0.receiver_count() > 0)
                 }
 
-                let (msg, _) = n2r_socket.recv_from().await?;
+                let (msg, source) = n2r_socket.recv_from().await?;
+                // visitors may come in through any of our rendezvous, and we answer through the same one
+                let rendezvous = source.fingerprint;
                 let msg_len = msg.len();
                 let msg: Result<R2hMessage, _> = stdcode::deserialize(&msg);
+                // only messages the rendezvous forwarded intact say it's healthy
+                if msg.is_ok() {
+                    health.record(rendezvous, true);
+                }
                 match msg {
                     Ok(R2hMessage {
                         src_visitor,
@@ -142,11 +300,25 @@ async fn haven_demultiplex(
                         let queue = conn_queues.get(&src_visitor);
                         if let Some((queue, _)) = queue {
                             let _ = queue.try_send(normal);
-                        } else {
-                            tracing::warn!(
+                        } else if reset_visitors.put(src_visitor, ()).is_none() {
+                            // we restarted since the visitor connected, losing the keys of its connection, or closed the connection since
+                            tracing::debug!(
                                 src_visitor = debug(src_visitor),
-                                "r2h to unknown connection"
-                            )
+                                "resetting unknown connection"
+                            );
+                            let response = H2rMessage {
+                                dest_visitor: src_visitor,
+                                payload: HavenMsg::Reset(HavenReset::new(identity, src_visitor)),
+                            };
+                            if let Err(err) = n2r_socket
+                                .send_to(
+                                    response.stdcode().into(),
+                                    RelayEndpoint::new(rendezvous, HAVEN_FORWARD_DOCK),
+                                )
+                                .await
+                            {
+                                tracing::debug!(err = debug(err), "could not send reset");
+                            }
                         }
                     }
                     Ok(R2hMessage {
@@ -157,44 +329,24 @@ async fn haven_demultiplex(
                             tracing::debug!("RECEIVED DUPLICATE HavenMsg::VisitorHs");
                             eph_sk.clone()
                         } else {
-                            let eph_sk = DhSecret::generate();
-                            let shared_sec = eph_sk.shared_secret(&handshake.0);
-                            let up_key = AeadKey::from_bytes(
-                                blake3::keyed_hash(blake3::hash(HAVEN_UP).as_bytes(), &shared_sec)
-                                    .as_bytes(),
-                            );
-                            let down_key = AeadKey::from_bytes(
-                                blake3::keyed_hash(blake3::hash(HAVEN_DN).as_bytes(), &shared_sec)
-                                    .as_bytes(),
+                            let is_probe = beacon.is_probe(&handshake.0);
+                            let (conn, eph_sk) = accept_conn(
+                                ctx,
+                                &mut conn_queues,
+                                handshake,
+                                src_visitor,
+                                None,
+                                &n2r_socket,
+                                rendezvous,
+                                health.clone(),
                             );
-                            let (send_upstream, recv_upstream) = smol::channel::bounded(1000);
-                            let (send_downstream, recv_downstream) = smol::channel::bounded(1000);
-                            let conn = HavenPacketConn {
-                                enc_key: down_key,
-                                enc_nonce: AtomicU64::new(0),
-                                dec_key: up_key,
-
-                                send_upstream,
-                                recv_downstream,
-                                _task: smolscale::spawn(per_conn_loop(
-                                    recv_upstream,
-                                    src_visitor,
-                                    n2r_socket.clone(),
-                                    rendezvous,
-                                )),
-                            };
-                            conn_queues.insert(src_visitor, (send_downstream, eph_sk.clone()));
-                            send_accepted.send(conn).await?;
+                            hand_off(ctx, conn, is_probe, &send_accepted).await?;
                             eph_sk
                         };
                         // Finish the handshake
                         let response = H2rMessage {
                             dest_visitor: src_visitor,
-                            payload: HavenMsg::HavenHs(HavenHandshake {
-                                id_pk: identity.public(),
-                                eph_pk: eph_sk.public(),
-                                sig: identity.sign(eph_sk.public().as_bytes()),
-                            }),
+                            payload: HavenMsg::HavenHs(haven_handshake(identity, &eph_sk)),
                         };
                         n2r_socket
                             .send_to(
@@ -206,11 +358,70 @@ async fn haven_demultiplex(
                     }
                     Ok(R2hMessage {
                         src_visitor,
-                        payload: HavenMsg::HavenHs(_),
+                        payload: HavenMsg::PipelinedVisitorHs(handshake, sealed),
+                    }) => {
+                        // a first packet we can't open means the visitor has a stale locator, so we reject the whole handshake. the visitor falls back to a sequential one.
+                        let first_pkt = match early_key(&onion_sk.shared_secret(&handshake.0))
+                            .open(&[0; 12], &sealed)
+                            .map_err(anyhow::Error::from)
+                            .and_then(|padded| Ok(session::unpad(&padded)?))
+                            .and_then(|first_pkt| {
+                                anyhow::ensure!(
+                                    first_pkt.len() <= MAX_PIPELINED_PAYLOAD,
+                                    "pipelined first packet too big"
+                                );
+                                Ok(first_pkt)
+                            })
+                        {
+                            Ok(first_pkt) => first_pkt,
+                            Err(err) => {
+                                tracing::debug!(
+                                    src_visitor = debug(src_visitor),
+                                    err = debug(err),
+                                    "rejecting pipelined handshake"
+                                );
+                                continue;
+                            }
+                        };
+                        if let Some((queue, eph_sk)) = conn_queues.get(&src_visitor) {
+                            // a retried handshake needs our reply again, since the earlier one may have been lost. the first packet it redelivers is dropped as a replay if it already got through
+                            tracing::debug!("RECEIVED DUPLICATE HavenMsg::PipelinedVisitorHs");
+                            let _ =
+                                queue.try_send(reseal_first_pkt(eph_sk, &handshake, &first_pkt));
+                            let response = H2rMessage {
+                                dest_visitor: src_visitor,
+                                payload: HavenMsg::HavenHs(haven_handshake(identity, eph_sk)),
+                            };
+                            n2r_socket
+                                .send_to(
+                                    response.stdcode().into(),
+                                    RelayEndpoint::new(rendezvous, HAVEN_FORWARD_DOCK),
+                                )
+                                .await?;
+                        } else {
+                            // the connection's own loop finishes the handshake, ideally together with its first packet
+                            let is_probe = beacon.is_probe(&handshake.0);
+                            let (conn, _) = accept_conn(
+                                ctx,
+                                &mut conn_queues,
+                                handshake,
+                                src_visitor,
+                                Some((identity, first_pkt)),
+                                &n2r_socket,
+                                rendezvous,
+                                health.clone(),
+                            );
+                            hand_off(ctx, conn, is_probe, &send_accepted).await?;
+                        }
+                    }
+                    Ok(R2hMessage {
+                        src_visitor,
+                        payload:
+                            HavenMsg::HavenHs(_) | HavenMsg::PipelinedHavenHs(..) | HavenMsg::Reset(_),
                     }) => {
                         tracing::warn!(
                             src_visitor = debug(src_visitor),
-                            "invalid HavenHs at haven side"
+                            "haven-to-visitor message at haven side"
                         )
                     }
                     Err(err) => {
@@ -222,24 +433,139 @@ async fn haven_demultiplex(
         .await
 }
 
+/// Hands an accepted connection off to whoever listens on the haven, unless it's one of our own beacons, which we answer ourselves.
+async fn hand_off(
+    ctx: &DaemonContext,
+    conn: HavenPacketConn,
+    is_probe: bool,
+    send_accepted: &Sender<HavenPacketConn>,
+) -> anyhow::Result<()> {
+    if is_probe {
+        spawn_scoped(ctx, Stage::Inflight, "beacon_echo", beacon::echo(conn));
+    } else {
+        send_accepted.send(conn).await?;
+    }
+    Ok(())
+}
+
+/// Sets up the haven side of a new connection, returning it together with the ephemeral key it was set up with.
+///
+/// For pipelined handshakes, `pipelined` contains our identity and the visitor's first packet, and the handshake is finished by the connection's own loop.
+#[allow(clippy::too_many_arguments)]
+fn accept_conn(
+    ctx: &DaemonContext,
+    conn_queues: &mut HashMap<AnonEndpoint, (Sender<Bytes>, DhSecret)>,
+    handshake: VisitorHandshake,
+    src_visitor: AnonEndpoint,
+    pipelined: Option<(HavenIdentitySecret, Bytes)>,
+    n2r_socket: &N2rClientSocket,
+    rendezvous: RelayFingerprint,
+    health: Arc<RendezvousHealth>,
+) -> (HavenPacketConn, DhSecret) {
+    let eph_sk = DhSecret::generate();
+    let (up_key, down_key) = directional_keys(&eph_sk.shared_secret(&handshake.0));
+    let (send_upstream, recv_upstream) = smol::channel::bounded(1000);
+    let (send_downstream, recv_downstream) = smol::channel::bounded(1000);
+    let pending_hs = pipelined.map(|(identity, first_pkt)| {
+        let _ = send_downstream.try_send(reseal_first_pkt(&eph_sk, &handshake, &first_pkt));
+        haven_handshake(identity, &eph_sk)
+    });
+    let conn = HavenPacketConn {
+        sealer: Sealer::new(down_key, 0),
+        opener: Opener::new(up_key),
+
+        send_upstream,
+        recv_downstream,
+        shaper: Shaper::register(ctx, format!("haven_conn:{src_visitor}")),
+        haven_shaper: None,
+        reset_by_haven: Arc::new(AtomicBool::new(false)),
+        _task: spawn_owned(
+            ctx,
+            Stage::Inflight,
+            "haven_conn",
+            per_conn_loop(
+                recv_upstream,
+                src_visitor,
+                n2r_socket.clone(),
+                rendezvous,
+                health,
+                pending_hs,
+            ),
+        ),
+    };
+    conn_queues.insert(src_visitor, (send_downstream, eph_sk.clone()));
+    (conn, eph_sk)
+}
+
+fn haven_handshake(identity: HavenIdentitySecret, eph_sk: &DhSecret) -> HavenHandshake {
+    HavenHandshake {
+        id_pk: identity.public(),
+        eph_pk: eph_sk.public(),
+        sig: identity.sign(eph_sk.public().as_bytes()),
+    }
+}
+
+/// Re-encrypts a pipelined first packet as if it were the visitor's packet with sequence number 0, which visitors never use after pipelining, so that it can be received like any other.
+fn reseal_first_pkt(eph_sk: &DhSecret, handshake: &VisitorHandshake, first_pkt: &[u8]) -> Bytes {
+    let (up_key, _) = directional_keys(&eph_sk.shared_secret(&handshake.0));
+    session::seal_first(&up_key, first_pkt).expect("pipelined packets are smaller than any other")
+}
+
 async fn per_conn_loop(
     recv_upstream: Receiver<Bytes>,
     dest_visitor: AnonEndpoint,
     n2r_socket: N2rClientSocket,
     rendezvous: RelayFingerprint,
+    health: Arc<RendezvousHealth>,
+    pending_hs: Option<HavenHandshake>,
 ) -> anyhow::Result<()> {
+    if let Some(hs) = pending_hs {
+        let payload = match recv_upstream.recv().timeout(PIPELINED_REPLY_WAIT).await {
+            Some(first) => HavenMsg::PipelinedHavenHs(hs, first?),
+            None => HavenMsg::HavenHs(hs),
+        };
+        send_h2r(
+            &n2r_socket,
+            &health,
+            rendezvous,
+            H2rMessage {
+                dest_visitor,
+                payload,
+            },
+        )
+        .await?;
+        tracing::debug!("returned pipelined HavenHandshake to {dest_visitor}");
+    }
     loop {
         let to_send = recv_upstream.recv().await?;
-        n2r_socket
-            .send_to(
-                H2rMessage {
-                    dest_visitor,
-                    payload: HavenMsg::Regular(to_send),
-                }
-                .stdcode()
-                .into(),
-                RelayEndpoint::new(rendezvous, HAVEN_FORWARD_DOCK),
-            )
-            .await?;
+        send_h2r(
+            &n2r_socket,
+            &health,
+            rendezvous,
+            H2rMessage {
+                dest_visitor,
+                payload: HavenMsg::Regular(to_send),
+            },
+        )
+        .await?;
+    }
+}
+
+/// Sends a message to a visitor through the given rendezvous. Failing to send counts against the rendezvous's health.
+async fn send_h2r(
+    n2r_socket: &N2rClientSocket,
+    health: &RendezvousHealth,
+    rendezvous: RelayFingerprint,
+    msg: H2rMessage,
+) -> anyhow::Result<()> {
+    let res = n2r_socket
+        .send_to(
+            msg.stdcode().into(),
+            RelayEndpoint::new(rendezvous, HAVEN_FORWARD_DOCK),
+        )
+        .await;
+    if res.is_err() {
+        health.record(rendezvous, false);
     }
+    res
 }
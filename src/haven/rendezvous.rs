@@ -0,0 +1,204 @@
+use earendil_crypt::{HavenIdentitySecret, RelayFingerprint};
+use earendil_packet::crypt::DhPublic;
+use parking_lot::Mutex;
+
+use super::{HavenLocator, HANDSHAKE_SEALED};
+
+/// How far each new outcome moves a rendezvous's health score towards 0 or 1.
+const HEALTH_ALPHA: f64 = 0.3;
+
+/// How much healthier another rendezvous must be than the one we publish before we fail over to it. A single failure moves a score by less, so one lost message doesn't make visitors chase a new locator.
+const FAILOVER_MARGIN: f64 = 0.4;
+
+/// Tracks how responsive each of a haven's rendezvous points currently is, so that the locator we publish points visitors at a healthy one.
+pub struct RendezvousHealth {
+    /// In order of configured preference.
+    points: Mutex<Vec<RendezvousPoint>>,
+    /// The rendezvous we last preferred, which we stick with until another is clearly healthier.
+    current: Mutex<Option<RelayFingerprint>>,
+}
+
+struct RendezvousPoint {
+    fingerprint: RelayFingerprint,
+    /// Exponentially weighted success rate of registering with and forwarding through the rendezvous, from 0 to 1.
+    score: f64,
+    /// Whether the rendezvous ever accepted our registration. Visitors can only be pointed at ones that did.
+    registered: bool,
+}
+
+impl RendezvousHealth {
+    pub fn new(rendezvous: impl IntoIterator<Item = RelayFingerprint>) -> Self {
+        let mut points: Vec<RendezvousPoint> = vec![];
+        for fingerprint in rendezvous {
+            if points.iter().all(|point| point.fingerprint != fingerprint) {
+                points.push(RendezvousPoint {
+                    fingerprint,
+                    score: 1.0,
+                    registered: false,
+                });
+            }
+        }
+        Self {
+            points: Mutex::new(points),
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Every rendezvous point, in order of configured preference.
+    pub fn all(&self) -> Vec<RelayFingerprint> {
+        self.points
+            .lock()
+            .iter()
+            .map(|point| point.fingerprint)
+            .collect()
+    }
+
+    /// Records whether an interaction with a rendezvous succeeded. Rendezvous points that aren't ours are ignored.
+    pub fn record(&self, rendezvous: RelayFingerprint, success: bool) {
+        if let Some(point) = self
+            .points
+            .lock()
+            .iter_mut()
+            .find(|point| point.fingerprint == rendezvous)
+        {
+            let outcome = if success { 1.0 } else { 0.0 };
+            point.score += HEALTH_ALPHA * (outcome - point.score);
+        }
+    }
+
+    /// Records that a rendezvous accepted our registration, which also counts as a success.
+    pub fn record_registered(&self, rendezvous: RelayFingerprint) {
+        if let Some(point) = self
+            .points
+            .lock()
+            .iter_mut()
+            .find(|point| point.fingerprint == rendezvous)
+        {
+            point.registered = true;
+        }
+        self.record(rendezvous, true);
+    }
+
+    /// Records that a rendezvous turned our registration away, because it doesn't serve havens or is full, so that we publish another one until it takes us.
+    pub fn record_refused(&self, rendezvous: RelayFingerprint) {
+        if let Some(point) = self
+            .points
+            .lock()
+            .iter_mut()
+            .find(|point| point.fingerprint == rendezvous)
+        {
+            point.registered = false;
+            point.score = 0.0;
+        }
+    }
+
+    /// The healthiest rendezvous that accepted our registration, sticking with the one preferred so far unless another is healthier by [FAILOVER_MARGIN] or it stopped taking us. Ties go to the one configured first.
+    pub fn preferred(&self) -> Option<RelayFingerprint> {
+        let points = self.points.lock();
+        let mut current = self.current.lock();
+        let mut best: Option<&RendezvousPoint> = None;
+        for point in points.iter().filter(|point| point.registered) {
+            if best.map_or(true, |best| point.score > best.score) {
+                best = Some(point);
+            }
+        }
+        let best = best?;
+        let kept = current.and_then(|current| {
+            points
+                .iter()
+                .find(|point| point.fingerprint == current && point.registered)
+        });
+        let preferred = match kept {
+            Some(kept) if best.score - kept.score < FAILOVER_MARGIN => kept.fingerprint,
+            _ => best.fingerprint,
+        };
+        *current = Some(preferred);
+        Some(preferred)
+    }
+
+    /// The locator to publish right now, pointing at the preferred rendezvous, if any accepted our registration yet.
+    pub fn locator(
+        &self,
+        identity: HavenIdentitySecret,
+        onion_pk: DhPublic,
+    ) -> Option<HavenLocator> {
+        let rendezvous = self.preferred()?;
+        Some(HavenLocator::new(
+            identity,
+            onion_pk,
+            rendezvous,
+            HANDSHAKE_SEALED,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+
+    use super::*;
+
+    #[test]
+    fn locator_fails_over_to_healthy_rendezvous() {
+        let [a, b] = [(); 2].map(|_| RelayIdentitySecret::generate().public().fingerprint());
+        let identity = HavenIdentitySecret::generate();
+        let onion_pk = DhSecret::generate().public();
+        let health = RendezvousHealth::new([a, b]);
+        assert!(health.locator(identity, onion_pk).is_none());
+
+        health.record_registered(a);
+        health.record_registered(b);
+        assert_eq!(
+            health.locator(identity, onion_pk).unwrap().rendezvous_point,
+            a
+        );
+
+        // a starts failing, while b keeps working
+        for _ in 0..3 {
+            health.record(a, false);
+            health.record(b, true);
+        }
+        let locator = health.locator(identity, onion_pk).unwrap();
+        assert_eq!(locator.rendezvous_point, b);
+        locator
+            .identity_pk
+            .verify(&locator.to_sign(), &locator.signature)
+            .unwrap();
+
+        // and once a recovers and b starts failing, we go back
+        for _ in 0..5 {
+            health.record(a, true);
+            health.record(b, false);
+        }
+        assert_eq!(health.preferred(), Some(a));
+    }
+
+    #[test]
+    fn one_lost_message_does_not_fail_over() {
+        let [a, b] = [(); 2].map(|_| RelayIdentitySecret::generate().public().fingerprint());
+        let health = RendezvousHealth::new([a, b]);
+        health.record_registered(a);
+        health.record_registered(b);
+        assert_eq!(health.preferred(), Some(a));
+
+        // failures scattered among successes never move us
+        for _ in 0..20 {
+            health.record(a, false);
+            assert_eq!(health.preferred(), Some(a));
+            for _ in 0..5 {
+                health.record(a, true);
+            }
+            assert_eq!(health.preferred(), Some(a));
+        }
+        // but a rendezvous that keeps failing is left
+        health.record(a, false);
+        health.record(a, false);
+        assert_eq!(health.preferred(), Some(b));
+        // and once we left it, it has to be clearly healthier to win us back
+        health.record(a, true);
+        health.record(a, true);
+        health.record(b, false);
+        assert_eq!(health.preferred(), Some(b));
+    }
+}
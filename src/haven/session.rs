@@ -0,0 +1,334 @@
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use earendil_packet::crypt::AeadKey;
+use parking_lot::Mutex;
+use stdcode::StdcodeSerializeExt;
+use tap::Tap;
+use thiserror::Error;
+
+use crate::limits::HAVEN_PACKET_SIZE;
+
+const HAVEN_UP: &[u8] = b"haven-up";
+const HAVEN_DN: &[u8] = b"haven-dn";
+const HAVEN_RATCHET: &[u8] = b"haven-ratchet";
+
+/// How many packets each side seals with one key before ratcheting to the next. The sequence number says which key a packet is sealed with, so both sides ratchet in step without telling each other.
+pub const REKEY_INTERVAL: u64 = 1 << 16;
+
+/// How long a key is used for at most, however few packets it sealed. Quiet sessions ratchet by skipping ahead to the next key's sequence numbers.
+pub const REKEY_AFTER: Duration = Duration::from_secs(600);
+
+/// How many packets behind the newest one a packet may arrive, and still be accepted once.
+const REPLAY_WINDOW: u64 = 128;
+
+/// How many keys ahead of ours a packet may be sealed with. Getting further ahead takes losing more packets in a row than any live connection does, so a packet claiming to be that far ahead is forged.
+const MAX_RATCHET_SKIP: u64 = 4;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError {
+    #[error("haven packets carry at most {HAVEN_PACKET_SIZE} bytes, not {0}")]
+    TooBig(usize),
+    #[error("malformed haven packet")]
+    Malformed,
+    #[error("haven packet failed to decrypt, so it was tampered with or sealed with another key")]
+    Tampered,
+    #[error("haven packet was replayed, or arrived too late")]
+    Replayed,
+}
+
+/// The keys a visitor seals with and a haven seals with, in that order, derived from the shared secret of their handshake.
+pub fn directional_keys(shared_sec: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let derive = |direction: &[u8]| {
+        *blake3::keyed_hash(blake3::hash(direction).as_bytes(), shared_sec).as_bytes()
+    };
+    (derive(HAVEN_UP), derive(HAVEN_DN))
+}
+
+/// Seals packets on one direction of a haven connection.
+pub struct Sealer {
+    state: Mutex<SealState>,
+}
+
+struct SealState {
+    next_seq: u64,
+    key: [u8; 32],
+    rekey_at: Instant,
+}
+
+impl Sealer {
+    /// Starts sealing with `key`, at sequence number `first_seq`. Sequence numbers below it must have been used by [seal_first].
+    pub fn new(key: [u8; 32], first_seq: u64) -> Self {
+        Self {
+            state: Mutex::new(SealState {
+                next_seq: first_seq,
+                key,
+                rekey_at: Instant::now() + REKEY_AFTER,
+            }),
+        }
+    }
+
+    /// Pads and seals a packet, under the next sequence number.
+    pub fn seal(&self, pkt: &[u8]) -> Result<Bytes, SessionError> {
+        let padded = pad(pkt, HAVEN_PACKET_SIZE)?;
+        let mut state = self.state.lock();
+        let mut seq = state.next_seq;
+        if seq % REKEY_INTERVAL != 0 && Instant::now() >= state.rekey_at {
+            seq = (seq / REKEY_INTERVAL + 1) * REKEY_INTERVAL;
+        }
+        if seq > 0 && seq % REKEY_INTERVAL == 0 {
+            state.key = ratchet(&state.key);
+            state.rekey_at = Instant::now() + REKEY_AFTER;
+        }
+        state.next_seq = seq + 1;
+        Ok(seal_with(&state.key, seq, &padded))
+    }
+}
+
+/// Seals the packet with sequence number 0 under `key`, which is the first key a [Sealer] starting at 1 would have used.
+pub fn seal_first(key: &[u8; 32], pkt: &[u8]) -> Result<Bytes, SessionError> {
+    Ok(seal_with(key, 0, &pad(pkt, HAVEN_PACKET_SIZE)?))
+}
+
+/// Opens packets on one direction of a haven connection, rejecting ones that were tampered with or seen before.
+pub struct Opener {
+    state: Mutex<OpenState>,
+}
+
+struct OpenState {
+    epoch: u64,
+    key: [u8; 32],
+    /// The key of the epoch before, for packets reordered across a ratchet. Any older one is forgotten, so that stealing our keys later can't open old packets.
+    prev_key: Option<[u8; 32]>,
+    window: ReplayWindow,
+}
+
+impl Opener {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            state: Mutex::new(OpenState {
+                epoch: 0,
+                key,
+                prev_key: None,
+                window: ReplayWindow::default(),
+            }),
+        }
+    }
+
+    /// Opens and unpads a packet sealed by the other side's [Sealer].
+    pub fn open(&self, sealed: &[u8]) -> Result<Bytes, SessionError> {
+        let (seq, ctext): (u64, Vec<u8>) =
+            stdcode::deserialize(sealed).map_err(|_| SessionError::Malformed)?;
+        let mut state = self.state.lock();
+        if !state.window.is_fresh(seq) {
+            return Err(SessionError::Replayed);
+        }
+        let epoch = seq / REKEY_INTERVAL;
+        // the key is only committed to once the packet proves it was sealed with it
+        let (key, ratcheted) = if epoch == state.epoch {
+            (state.key, None)
+        } else if epoch + 1 == state.epoch {
+            (state.prev_key.ok_or(SessionError::Replayed)?, None)
+        } else if epoch > state.epoch && epoch - state.epoch <= MAX_RATCHET_SKIP {
+            let mut prev = state.key;
+            let mut key = ratchet(&prev);
+            for _ in state.epoch + 1..epoch {
+                prev = key;
+                key = ratchet(&key);
+            }
+            (key, Some(prev))
+        } else {
+            return Err(SessionError::Replayed);
+        };
+        let padded = AeadKey::from_bytes(&key)
+            .open(&nonce(seq), &ctext)
+            .map_err(|_| SessionError::Tampered)?;
+        if let Some(prev) = ratcheted {
+            state.epoch = epoch;
+            state.key = key;
+            state.prev_key = Some(prev);
+        }
+        state.window.mark(seq);
+        unpad(&padded)
+    }
+}
+
+/// Which of the latest sequence numbers were seen already.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `i` is set if `highest - i` was seen.
+    seen: u128,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, seq: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if seq > highest => true,
+            Some(highest) if highest - seq >= REPLAY_WINDOW => false,
+            Some(highest) => self.seen & (1 << (highest - seq)) == 0,
+        }
+    }
+
+    fn mark(&mut self, seq: u64) {
+        match self.highest {
+            Some(highest) if seq <= highest => self.seen |= 1 << (highest - seq),
+            highest => {
+                let shift = highest.map_or(REPLAY_WINDOW, |highest| seq - highest);
+                self.seen = if shift >= REPLAY_WINDOW {
+                    0
+                } else {
+                    self.seen << shift
+                } | 1;
+                self.highest = Some(seq);
+            }
+        }
+    }
+}
+
+fn ratchet(key: &[u8; 32]) -> [u8; 32] {
+    *blake3::keyed_hash(key, HAVEN_RATCHET).as_bytes()
+}
+
+fn nonce(seq: u64) -> [u8; 12] {
+    [0; 12].tap_mut(|b| b[..8].copy_from_slice(&seq.to_le_bytes()))
+}
+
+fn seal_with(key: &[u8; 32], seq: u64, padded: &[u8]) -> Bytes {
+    let ctext = AeadKey::from_bytes(key).seal(&nonce(seq), padded);
+    (seq, ctext).stdcode().into()
+}
+
+/// Prefixes the packet with its length and pads it with zeros to `size` bytes plus the prefix, so that every packet padded to the same size looks alike once sealed.
+pub fn pad(pkt: &[u8], size: usize) -> Result<Vec<u8>, SessionError> {
+    if pkt.len() > size {
+        return Err(SessionError::TooBig(pkt.len()));
+    }
+    let mut padded = Vec::with_capacity(4 + size);
+    padded.extend_from_slice(&(pkt.len() as u32).to_le_bytes());
+    padded.extend_from_slice(pkt);
+    padded.resize(4 + size, 0);
+    Ok(padded)
+}
+
+/// Undoes [pad].
+pub fn unpad(padded: &[u8]) -> Result<Bytes, SessionError> {
+    if padded.len() < 4 {
+        return Err(SessionError::Malformed);
+    }
+    let len = u32::from_le_bytes(padded[..4].try_into().unwrap()) as usize;
+    padded[4..]
+        .get(..len)
+        .map(Bytes::copy_from_slice)
+        .ok_or(SessionError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::{AnonEndpoint, HavenIdentitySecret};
+
+    use super::*;
+    use crate::{
+        haven::{
+            vrh::{HavenMsg, R2hMessage, V2rMessage},
+            HavenEndpoint, HAVEN_FORWARD_DOCK,
+        },
+        limits::max_single_message,
+    };
+
+    fn pair() -> (Sealer, Opener) {
+        let key = rand::random();
+        (Sealer::new(key, 0), Opener::new(key))
+    }
+
+    #[test]
+    fn sealed_packets_look_alike() {
+        let (sealer, opener) = pair();
+        let short = sealer.seal(b"hi").unwrap();
+        let long = sealer.seal(&[7; HAVEN_PACKET_SIZE]).unwrap();
+        assert_eq!(short.len(), long.len());
+        assert_eq!(&opener.open(&short).unwrap()[..], b"hi");
+        assert_eq!(opener.open(&long).unwrap().len(), HAVEN_PACKET_SIZE);
+        assert_eq!(
+            sealer.seal(&[0; HAVEN_PACKET_SIZE + 1]).unwrap_err(),
+            SessionError::TooBig(HAVEN_PACKET_SIZE + 1)
+        );
+    }
+
+    #[test]
+    fn tampered_and_replayed_packets_are_rejected() {
+        let (sealer, opener) = pair();
+        let sealed = sealer.seal(b"attack at dawn").unwrap();
+        let mut tampered = sealed.to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(opener.open(&tampered).unwrap_err(), SessionError::Tampered);
+        assert_eq!(
+            Opener::new(rand::random()).open(&sealed).unwrap_err(),
+            SessionError::Tampered
+        );
+
+        assert_eq!(&opener.open(&sealed).unwrap()[..], b"attack at dawn");
+        assert_eq!(opener.open(&sealed).unwrap_err(), SessionError::Replayed);
+
+        // reordering within the window is fine, but only once per packet
+        let late = sealer.seal(b"late").unwrap();
+        for _ in 0..10 {
+            opener.open(&sealer.seal(b"on time").unwrap()).unwrap();
+        }
+        assert_eq!(&opener.open(&late).unwrap()[..], b"late");
+        assert_eq!(opener.open(&late).unwrap_err(), SessionError::Replayed);
+        let too_late = sealer.seal(b"too late").unwrap();
+        for _ in 0..REPLAY_WINDOW {
+            opener.open(&sealer.seal(b"on time").unwrap()).unwrap();
+        }
+        assert_eq!(opener.open(&too_late).unwrap_err(), SessionError::Replayed);
+    }
+
+    #[test]
+    fn keys_ratchet_in_step() {
+        let key = rand::random();
+        let sealer = Sealer::new(key, REKEY_INTERVAL - 2);
+        let opener = Opener::new(key);
+        let before = sealer.seal(b"before").unwrap();
+        let last = sealer.seal(b"last").unwrap();
+        let after = sealer.seal(b"after").unwrap();
+        assert_ne!(sealer.state.lock().key, key);
+
+        // a packet of the next epoch ratchets the opener, which still opens the previous epoch's stragglers
+        assert_eq!(&opener.open(&after).unwrap()[..], b"after");
+        assert_eq!(&opener.open(&before).unwrap()[..], b"before");
+        assert_eq!(&opener.open(&last).unwrap()[..], b"last");
+
+        // quiet sessions skip ahead to the next key once the current one gets old
+        sealer.state.lock().rekey_at = Instant::now();
+        let skipped = sealer.seal(b"skipped").unwrap();
+        let (seq, _): (u64, Vec<u8>) = stdcode::deserialize(&skipped).unwrap();
+        assert_eq!(seq, 2 * REKEY_INTERVAL);
+        assert_eq!(&opener.open(&skipped).unwrap()[..], b"skipped");
+
+        // but nobody gets to make the opener ratchet without the key
+        let forged = seal_with(&rand::random(), 3 * REKEY_INTERVAL, &pad(b"", 0).unwrap());
+        assert_eq!(opener.open(&forged).unwrap_err(), SessionError::Tampered);
+        assert_eq!(opener.state.lock().epoch, 2);
+    }
+
+    #[test]
+    fn full_packets_fit_in_one_message() {
+        let sealed = Sealer::new(rand::random(), 0)
+            .seal(&[0; HAVEN_PACKET_SIZE])
+            .unwrap();
+        let to_rendezvous = V2rMessage {
+            dest_haven: HavenEndpoint::from_identity(&HavenIdentitySecret::generate().public(), 80),
+            payload: HavenMsg::Regular(sealed.clone()),
+        };
+        let to_haven = R2hMessage {
+            src_visitor: AnonEndpoint::random(),
+            payload: HavenMsg::Regular(sealed),
+        };
+        let max = max_single_message(HAVEN_FORWARD_DOCK);
+        assert!(to_rendezvous.stdcode().len() <= max);
+        assert!(to_haven.stdcode().len() <= max);
+    }
+}
@@ -1,5 +1,10 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
 use bytes::Bytes;
-use earendil_crypt::RelayFingerprint;
+use earendil_crypt::{HavenIdentityPublic, RelayFingerprint};
 use smol::{
     channel::{Receiver, Sender},
     future::FutureExt as _,
@@ -10,15 +15,18 @@ use crate::n2r_socket::{N2rClientSocket, RelayEndpoint};
 
 use super::{
     vrh::{HavenMsg, V2rMessage},
-    HavenEndpoint, HAVEN_FORWARD_DOCK,
+    HavenConnReset, HavenEndpoint, HAVEN_FORWARD_DOCK,
 };
 
+/// Shuffles a visitor's packets to and from the haven with `haven_pk`. Ends, setting `reset_by_haven`, once the haven says it doesn't know the connection.
 pub async fn visitor_loop(
     send_downstream: Sender<Bytes>,
     recv_upstream: Receiver<Bytes>,
     rendezvous: RelayFingerprint,
+    haven_pk: HavenIdentityPublic,
     haven: HavenEndpoint,
     n2r_socket: N2rClientSocket,
+    reset_by_haven: Arc<AtomicBool>,
 ) -> anyhow::Result<()> {
     let rendezvous = RelayEndpoint::new(rendezvous, HAVEN_FORWARD_DOCK);
     // upstream messages are wrapped in V2rMessage
@@ -45,6 +53,11 @@ pub async fn visitor_loop(
             let msg: HavenMsg = stdcode::deserialize(&msg)?;
             match msg {
                 HavenMsg::Regular(payload) => send_downstream.send(payload).await?,
+                // only the haven can sign a reset, so the rendezvous can't cut us off this way
+                HavenMsg::Reset(reset) if reset.verify(&haven_pk, n2r_socket.local_endpoint()) => {
+                    reset_by_haven.store(true, Ordering::SeqCst);
+                    return Err(HavenConnReset.into());
+                }
                 _ => tracing::debug!("haven sent a non-regular message"),
             }
         }
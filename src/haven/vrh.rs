@@ -1,7 +1,8 @@
 use bytes::Bytes;
-use earendil_crypt::{AnonEndpoint, HavenIdentityPublic};
+use earendil_crypt::{AnonEndpoint, HavenIdentityPublic, HavenIdentitySecret};
 use earendil_packet::crypt::DhPublic;
 use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
 
 use super::HavenEndpoint;
 
@@ -28,6 +29,12 @@ pub enum HavenMsg {
     VisitorHs(VisitorHandshake),
     HavenHs(HavenHandshake),
     Regular(Bytes),
+    /// A visitor handshake that carries the visitor's first packet, sealed to the onion key in the haven's locator. Only sent to havens whose locator advertises [super::HANDSHAKE_PIPELINED].
+    PipelinedVisitorHs(VisitorHandshake, Bytes),
+    /// The haven's handshake, sent together with the haven's first packet on the connection.
+    PipelinedHavenHs(HavenHandshake, Bytes),
+    /// Sent by a haven in answer to a packet on a connection it doesn't know, typically because it restarted with fresh keys since. Visitors fail the connection rather than wait on it.
+    Reset(HavenReset),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -39,3 +46,32 @@ pub struct HavenHandshake {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct VisitorHandshake(pub DhPublic);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HavenReset {
+    pub id_pk: HavenIdentityPublic,
+    pub sig: Bytes,
+}
+
+impl HavenReset {
+    /// Tells the visitor at `visitor` that we don't know its connection, signed so that the rendezvous can't forge it.
+    pub fn new(identity: HavenIdentitySecret, visitor: AnonEndpoint) -> Self {
+        Self {
+            id_pk: identity.public(),
+            sig: identity.sign(Self::to_sign(visitor).as_bytes()),
+        }
+    }
+
+    /// Whether the reset really came from the haven with `id_pk`, about the connection at `visitor`.
+    pub fn verify(&self, id_pk: &HavenIdentityPublic, visitor: AnonEndpoint) -> bool {
+        &self.id_pk == id_pk
+            && self
+                .id_pk
+                .verify(Self::to_sign(visitor).as_bytes(), &self.sig)
+                .is_ok()
+    }
+
+    fn to_sign(visitor: AnonEndpoint) -> blake3::Hash {
+        blake3::keyed_hash(b"haven_reset_____________________", &visitor.stdcode())
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt};
+use nursery_macro::nursery;
+use smol::lock::Semaphore;
+use smol_timeout::TimeoutExt;
+use thiserror::Error;
+
+use crate::{context::DaemonContext, HavenEndpoint, HavenListener, PooledListener, PooledVisitor};
+
+/// The biggest request or response body either side accepts.
+pub const MAX_BODY: usize = 16 * 1024 * 1024;
+
+/// How much room a body gets up front, before any of it arrived.
+const BODY_CHUNK: usize = 16 * 1024;
+
+const STATUS_OK: u8 = 0;
+const STATUS_NO_SUCH_VERB: u8 = 1;
+const STATUS_FAILED: u8 = 2;
+
+type Handler =
+    Arc<dyn Fn(Bytes, ReplySink) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+/// Where a [HavenServer] handler sends its reply. A handler that fails, or returns without replying, makes the request fail instead.
+pub struct ReplySink {
+    send: smol::channel::Sender<Bytes>,
+}
+
+impl ReplySink {
+    pub fn reply(self, body: impl Into<Bytes>) {
+        let _ = self.send.try_send(body.into());
+    }
+}
+
+/// A request-response service on a haven, for when a raw stream or packet connection is more than an application wants to deal with.
+///
+/// Each request names a verb, such as `"echo"` or `"users/get"`, and carries a body. The server passes the body to the handler registered for the verb, and sends back whatever the handler replies. Requests arrive over [PooledListener], so each one gets its own cheap picomux stream, and many can be in flight at once from the same visitor. [HavenClient] is the other side.
+///
+/// ```no_run
+/// # async fn serve(listener: earendil::HavenListener) -> anyhow::Result<()> {
+/// use earendil::HavenServer;
+///
+/// HavenServer::new()
+///     .route("echo", |body, reply| async move {
+///         reply.reply(body);
+///         Ok(())
+///     })
+///     .serve(listener)
+///     .await
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct HavenServer {
+    handlers: HashMap<String, Handler>,
+    max_concurrency: usize,
+    idle_timeout: Duration,
+    handler_timeout: Duration,
+}
+
+impl Default for HavenServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HavenServer {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+            max_concurrency: 64,
+            idle_timeout: Duration::from_secs(30),
+            handler_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// Handles requests for `verb` with the given handler, replacing any earlier one for the same verb.
+    pub fn route<F, Fut>(mut self, verb: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(Bytes, ReplySink) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.handlers.insert(
+            verb.into(),
+            Arc::new(move |body, reply| handler(body, reply).boxed()),
+        );
+        self
+    }
+
+    /// Caps how many requests are handled at once. Further requests wait for their turn. Defaults to 64.
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self
+    }
+
+    /// How long a visitor may take to send its whole request. Defaults to 30 seconds.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// How long a handler may take to reply. Defaults to 60 seconds.
+    pub fn handler_timeout(mut self, handler_timeout: Duration) -> Self {
+        self.handler_timeout = handler_timeout;
+        self
+    }
+
+    /// Serves requests coming in on the listener, until the listener fails. Requests still in flight are dropped together with the returned future.
+    pub async fn serve(self, listener: HavenListener) -> anyhow::Result<()> {
+        let this = Arc::new(self);
+        let permits = Arc::new(Semaphore::new(this.max_concurrency));
+        let listener = PooledListener::new(listener);
+        nursery!(loop {
+            let stream = listener.accept().await?;
+            let permit = permits.acquire_arc().await;
+            let this = this.clone();
+            spawn!(async move {
+                let _permit = permit;
+                if let Err(err) = this.handle(stream).await {
+                    tracing::debug!(err = debug(err), "haven server request failed");
+                }
+            })
+            .detach();
+        })
+    }
+
+    async fn handle(&self, mut stream: picomux::Stream) -> anyhow::Result<()> {
+        let (verb, body) = read_request(&mut stream)
+            .timeout(self.idle_timeout)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("timed out reading a request"))??;
+        let Some(handler) = self.handlers.get(&verb) else {
+            return write_response(&mut stream, STATUS_NO_SUCH_VERB, verb.as_bytes()).await;
+        };
+        let (send, recv) = smol::channel::bounded(1);
+        let outcome = handler(body, ReplySink { send })
+            .timeout(self.handler_timeout)
+            .await
+            .unwrap_or_else(|| Err(anyhow::anyhow!("handler timed out")));
+        match (outcome, recv.try_recv()) {
+            (Ok(()), Ok(reply)) => write_response(&mut stream, STATUS_OK, &reply).await,
+            (Ok(()), Err(_)) => {
+                write_response(&mut stream, STATUS_FAILED, b"handler did not reply").await
+            }
+            (Err(err), _) => {
+                write_response(&mut stream, STATUS_FAILED, format!("{err:#}").as_bytes()).await
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HavenRequestError {
+    #[error("haven has no handler for {0:?}")]
+    NoSuchVerb(String),
+    #[error("haven failed to handle the request: {0}")]
+    Failed(String),
+    #[error("could not reach the haven: {0:#}")]
+    Transport(anyhow::Error),
+}
+
+/// Sends requests to [HavenServer]s, dialing havens as needed and reusing connections to them, so that concurrent requests to the same haven ride on one connection.
+pub struct HavenClient {
+    pool: PooledVisitor,
+    retries: usize,
+    timeout: Duration,
+    idempotent: HashSet<String>,
+}
+
+impl HavenClient {
+    pub fn new(ctx: DaemonContext) -> Self {
+        Self {
+            pool: PooledVisitor::new(ctx),
+            retries: 2,
+            timeout: Duration::from_secs(90),
+            idempotent: HashSet::new(),
+        }
+    }
+
+    /// How many more times to try a request that couldn't reach the haven. Requests the haven answered, even with an error, are never retried, and neither are requests that failed after being sent, unless their verb is [idempotent](Self::idempotent). Defaults to 2.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// How long each try waits for a response. Defaults to 90 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Marks requests for `verb` as safe to send more than once, so that they're retried even if they failed after being sent, when the haven may already have handled them.
+    pub fn idempotent(mut self, verb: &str) -> Self {
+        self.idempotent.insert(verb.to_string());
+        self
+    }
+
+    /// Sends a request to the haven's handler for `verb`, returning its reply.
+    pub async fn request(
+        &self,
+        haven: HavenEndpoint,
+        verb: &str,
+        body: &[u8],
+    ) -> Result<Bytes, HavenRequestError> {
+        let mut tries = 0;
+        loop {
+            let mut sent = false;
+            let result = self
+                .request_once(haven, verb, body, &mut sent)
+                .timeout(self.timeout)
+                .await
+                .unwrap_or_else(|| Err(anyhow::anyhow!("timed out")));
+            match result {
+                Ok((STATUS_OK, reply)) => return Ok(reply),
+                Ok((STATUS_NO_SUCH_VERB, _)) => {
+                    return Err(HavenRequestError::NoSuchVerb(verb.to_string()))
+                }
+                Ok((_, reply)) => {
+                    return Err(HavenRequestError::Failed(
+                        String::from_utf8_lossy(&reply).into_owned(),
+                    ))
+                }
+                Err(err) if tries >= self.retries => return Err(HavenRequestError::Transport(err)),
+                Err(err) if sent && !self.idempotent.contains(verb) => {
+                    return Err(HavenRequestError::Transport(err))
+                }
+                Err(err) => {
+                    tries += 1;
+                    tracing::debug!(
+                        haven = display(haven),
+                        verb,
+                        tries,
+                        err = debug(err),
+                        "retrying haven request"
+                    );
+                    smol::Timer::after(Duration::from_millis(500 << tries.min(5))).await;
+                }
+            }
+        }
+    }
+
+    async fn request_once(
+        &self,
+        haven: HavenEndpoint,
+        verb: &str,
+        body: &[u8],
+        sent: &mut bool,
+    ) -> anyhow::Result<(u8, Bytes)> {
+        let mut stream = self.pool.connect(haven, b"").await?;
+        // from here on, the haven may get the request even if we fail
+        *sent = true;
+        write_request(&mut stream, verb, body).await?;
+        read_response(&mut stream).await
+    }
+}
+
+async fn write_request(
+    mut w: impl AsyncWrite + Unpin,
+    verb: &str,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let verb_len: u16 = verb.len().try_into()?;
+    anyhow::ensure!(body.len() <= MAX_BODY, "request body too big");
+    w.write_all(&verb_len.to_be_bytes()).await?;
+    w.write_all(verb.as_bytes()).await?;
+    w.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    w.write_all(body).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+async fn read_request(mut r: impl AsyncRead + Unpin) -> anyhow::Result<(String, Bytes)> {
+    let mut verb_len = [0u8; 2];
+    r.read_exact(&mut verb_len).await?;
+    let mut verb = vec![0u8; u16::from_be_bytes(verb_len) as usize];
+    r.read_exact(&mut verb).await?;
+    let body = read_body(&mut r).await?;
+    Ok((String::from_utf8(verb)?, body))
+}
+
+async fn write_response(
+    mut w: impl AsyncWrite + Unpin,
+    status: u8,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    anyhow::ensure!(body.len() <= MAX_BODY, "response body too big");
+    w.write_all(&[status]).await?;
+    w.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    w.write_all(body).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+async fn read_response(mut r: impl AsyncRead + Unpin) -> anyhow::Result<(u8, Bytes)> {
+    let mut status = [0u8; 1];
+    r.read_exact(&mut status).await?;
+    Ok((status[0], read_body(&mut r).await?))
+}
+
+async fn read_body(mut r: impl AsyncRead + Unpin) -> anyhow::Result<Bytes> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    anyhow::ensure!(len <= MAX_BODY, "body of {len} bytes is too big");
+    // grown as the body actually arrives, so that a peer can't make us allocate by merely promising a big body
+    let mut body = Vec::with_capacity(len.min(BODY_CHUNK));
+    r.take(len as u64).read_to_end(&mut body).await?;
+    anyhow::ensure!(
+        body.len() == len,
+        "body ended after {} of {len} bytes",
+        body.len()
+    );
+    Ok(body.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn envelopes_round_trip() {
+        smol::future::block_on(async {
+            let mut wire = vec![];
+            write_request(&mut wire, "users/get", b"alice")
+                .await
+                .unwrap();
+            write_response(&mut wire, STATUS_NO_SUCH_VERB, b"")
+                .await
+                .unwrap();
+
+            let mut wire = Cursor::new(wire);
+            let (verb, body) = read_request(&mut wire).await.unwrap();
+            assert_eq!(verb, "users/get");
+            assert_eq!(&body[..], b"alice");
+            let (status, body) = read_response(&mut wire).await.unwrap();
+            assert_eq!(status, STATUS_NO_SUCH_VERB);
+            assert!(body.is_empty());
+
+            // a length prefix promising too much is refused before anything gets allocated
+            let mut huge = vec![STATUS_OK];
+            huge.extend_from_slice(&u32::MAX.to_be_bytes());
+            assert!(read_response(Cursor::new(huge)).await.is_err());
+
+            // and so is a body shorter than its length prefix
+            let mut short = vec![STATUS_OK];
+            short.extend_from_slice(&1000u32.to_be_bytes());
+            short.extend_from_slice(&[0u8; 10]);
+            assert!(read_response(Cursor::new(short)).await.is_err());
+        });
+    }
+}
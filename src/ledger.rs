@@ -0,0 +1,116 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    db::DATABASE,
+    micromel::Micromel,
+};
+
+/// Traffic is accounted in buckets of this many seconds, so that the ledger doesn't get a row per packet.
+pub const TRAFFIC_BUCKET_SECS: u64 = 3600;
+
+/// Packets exchanged with each neighbor since the last flush, as (incoming, outgoing).
+static UNFLUSHED_TRAFFIC: CtxField<DashMap<String, (u64, u64)>> = |_| DashMap::new();
+
+/// Packets exchanged with each neighbor since the daemon started, as (incoming, outgoing). Never flushed, so that rates can be computed from it.
+static TRAFFIC_TOTALS: CtxField<DashMap<String, (u64, u64)>> = |_| DashMap::new();
+
+/// Counts one packet received from the given neighbor.
+pub fn count_incoming(ctx: &DaemonContext, neighbor: &str) {
+    ctx.get(UNFLUSHED_TRAFFIC)
+        .entry(neighbor.to_string())
+        .or_default()
+        .0 += 1;
+    ctx.get(TRAFFIC_TOTALS)
+        .entry(neighbor.to_string())
+        .or_default()
+        .0 += 1;
+}
+
+/// Counts one packet sent to the given neighbor.
+pub fn count_outgoing(ctx: &DaemonContext, neighbor: &str) {
+    ctx.get(UNFLUSHED_TRAFFIC)
+        .entry(neighbor.to_string())
+        .or_default()
+        .1 += 1;
+    ctx.get(TRAFFIC_TOTALS)
+        .entry(neighbor.to_string())
+        .or_default()
+        .1 += 1;
+}
+
+/// Packets received from and sent to the given neighbor since the daemon started.
+pub fn traffic_totals(ctx: &DaemonContext, neighbor: &str) -> (u64, u64) {
+    ctx.get(TRAFFIC_TOTALS)
+        .get(neighbor)
+        .map(|totals| *totals)
+        .unwrap_or_default()
+}
+
+/// Packets received from and sent to all neighbors together since the daemon started.
+pub fn all_traffic_totals(ctx: &DaemonContext) -> (u64, u64) {
+    ctx.get(TRAFFIC_TOTALS)
+        .iter()
+        .fold((0, 0), |(total_in, total_out), entry| {
+            (total_in + entry.0, total_out + entry.1)
+        })
+}
+
+/// Adds the traffic counted since the last flush to the current bucket in the state cache.
+pub async fn flush_traffic(ctx: &DaemonContext) -> Result<(), sqlx::Error> {
+    let Some(pool) = ctx.get(DATABASE) else {
+        return Ok(());
+    };
+    let counts: Vec<(String, (u64, u64))> = {
+        let traffic = ctx.get(UNFLUSHED_TRAFFIC);
+        let neighbors: Vec<String> = traffic.iter().map(|entry| entry.key().clone()).collect();
+        neighbors
+            .into_iter()
+            .filter_map(|neighbor| traffic.remove(&neighbor))
+            .collect()
+    };
+    let bucket = unix_now() / TRAFFIC_BUCKET_SECS * TRAFFIC_BUCKET_SECS;
+    let mut txn = pool.begin().await?;
+    for (neighbor, (incoming, outgoing)) in counts {
+        sqlx::query(
+            "INSERT INTO traffic (bucket, neighbor, packets_in, packets_out) VALUES (?, ?, ?, ?) ON CONFLICT(bucket, neighbor) DO UPDATE SET packets_in = packets_in + excluded.packets_in, packets_out = packets_out + excluded.packets_out",
+        )
+        .bind(bucket as i64)
+        .bind(neighbor)
+        .bind(incoming as i64)
+        .bind(outgoing as i64)
+        .execute(&mut *txn)
+        .await?;
+    }
+    txn.commit().await
+}
+
+/// Records a settlement with a neighbor, referring to its payment proofs by the hash of the settlement request.
+pub async fn record_settlement(
+    ctx: &DaemonContext,
+    neighbor: &str,
+    amount: Micromel,
+    settlement: blake3::Hash,
+) -> Result<(), sqlx::Error> {
+    if let Some(pool) = ctx.get(DATABASE) {
+        sqlx::query(
+            "INSERT INTO settlements (unix_secs, neighbor, amount, settlement) VALUES (?, ?, ?, ?)",
+        )
+        .bind(unix_now() as i64)
+        .bind(neighbor)
+        .bind(i64::try_from(amount.0).unwrap_or(i64::MAX))
+        .bind(settlement.to_hex().to_string())
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -1,3 +1,5 @@
+mod audit;
+mod clock;
 mod commands;
 pub mod config;
 mod context;
@@ -8,30 +10,55 @@ mod debts;
 mod dht;
 mod global_rpc;
 mod haven;
+mod haven_server;
+mod ledger;
+pub mod limits;
+mod micromel;
+mod migrate;
 mod n2r;
 mod n2r_socket;
 mod network;
+mod scope;
 mod settlement;
+mod snapshot;
+mod stats;
+mod throughput;
+mod usage;
+mod verified;
 
 mod pascal;
 mod pooled;
 mod stream;
+#[cfg(test)]
+mod test_util;
 
 // Create the public API here.
 
-pub use commands::ControlCommand;
+pub use audit::{AuditEntry, AuditEvent, AuditVerification};
+pub use commands::{
+    cli, write_completion, Cli, CliCommand, ControlCommand, COMPLETE_VAR, DEFAULT_CONTROL_ADDR,
+};
 pub use config::*;
-pub use control_protocol::main_control;
+pub use control_protocol::{check_config, main_control};
 pub use daemon::Daemon;
-pub use haven::{HavenEndpoint, HavenListener, HavenPacketConn};
+pub use haven::{
+    rendezvous_tap, BeaconStatus, HavenConnReset, HavenEndpoint, HavenListener, HavenPacketConn,
+    RendezvousTap, REKEY_AFTER, REKEY_INTERVAL,
+};
+pub use haven_server::{HavenClient, HavenRequestError, HavenServer, ReplySink};
+pub use micromel::{Micromel, MICROMEL_PER_MEL};
+pub use migrate::Migration;
+pub use n2r::{
+    CircuitToken, MessageClass, RoamingEvent, SurbBundle, SurbBundleError, SurbBundleStock,
+    SurbBundles,
+};
 pub use n2r_socket::*;
+pub use network::{DropReason, ObservedDrop};
+pub use scope::TaskHealth;
+pub use snapshot::{
+    seal_received_snapshot, SnapshotError, SNAPSHOT_PASSPHRASE_VAR, SNAPSHOT_VERSION,
+};
 
 pub use pooled::*;
 pub use stream::HavenStream;
-
-fn log_error<E>(label: &str) -> impl FnOnce(E) + '_
-where
-    E: std::fmt::Debug,
-{
-    move |s| tracing::warn!("{label} restart, error: {:?}", s)
-}
+pub use throughput::{measure_throughput, ThroughputReport};
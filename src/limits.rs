@@ -0,0 +1,120 @@
+//! How big the things applications send may be. The send paths check against these same numbers, so what we report can't drift from what actually fits.
+
+use earendil_packet::{Dock, Message};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::DaemonContext,
+    n2r::{self, CircuitToken, MessageClass},
+    n2r_socket::RelayEndpoint,
+};
+
+/// Routes must pass through fewer relays than this, counting the destination.
+pub const MAX_ROUTE_HOPS: usize = earendil_packet::MAX_HOPS;
+
+/// The largest first packet that can be bundled with a haven handshake. Larger first packets are sent after the handshake completes.
+pub const MAX_PIPELINED_PAYLOAD: usize = 8192;
+
+/// The most a haven stream puts in one packet. Writes bigger than this are split across packets, so there's no point in writing in bigger chunks.
+pub const STREAM_CHUNK: usize = virta::stream_state::MSS;
+
+/// The biggest packet a haven connection carries, which leaves room for a full stream chunk and its framing. Every haven packet is padded to this size before it's sealed, so the rendezvous can't tell packets apart by their size.
+pub const HAVEN_PACKET_SIZE: usize = STREAM_CHUNK + 256;
+
+/// The biggest payload a single N2R message to or from `dock` can carry. N2R messages are never fragmented, so bigger payloads are refused outright.
+pub fn max_single_message(dock: Dock) -> usize {
+    Message::max_body_len(dock)
+}
+
+/// The biggest payload a single N2R message can carry whatever its dock, since bigger docks take more bytes to encode.
+pub fn max_single_message_any_dock() -> usize {
+    max_single_message(Dock::MAX)
+}
+
+/// How big the messages to a destination may be, right now.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TransportLimits {
+    /// The biggest payload a single N2R message to the destination can carry.
+    pub max_single_message: usize,
+    /// How many relays, counting the destination, the route to the destination passes through: the one the circuit would send on, or without a circuit, the most a fresh one would. `None` if we can't route to it yet. Onion headers are fixed-size, so longer routes don't shrink `max_single_message`.
+    pub route_hops: Option<usize>,
+    pub max_route_hops: usize,
+    /// The largest first packet that can be bundled with a haven handshake.
+    pub max_pipelined_payload: usize,
+    /// The most a haven stream puts in one packet.
+    pub stream_chunk: usize,
+}
+
+/// The limits on messages to `destination`, with the route length on the given circuit and class, if any.
+pub fn transport_limits(
+    ctx: &DaemonContext,
+    destination: RelayEndpoint,
+    circuit: Option<(CircuitToken, MessageClass)>,
+) -> TransportLimits {
+    let route_hops = match circuit {
+        Some((circuit, class)) => {
+            n2r::forward_route_len(ctx, destination.fingerprint, circuit, class)
+        }
+        None => n2r::fresh_route_max_len(ctx, destination.fingerprint),
+    };
+    TransportLimits {
+        max_single_message: max_single_message(destination.dock),
+        route_hops,
+        max_route_hops: MAX_ROUTE_HOPS,
+        max_pipelined_payload: MAX_PIPELINED_PAYLOAD,
+        stream_chunk: STREAM_CHUNK,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use earendil_crypt::{AnonEndpoint, RelayIdentitySecret, RemoteId};
+    use earendil_packet::{
+        crypt::DhSecret, ForwardInstruction, InnerPacket, PacketConstructError, RawPacket,
+    };
+
+    use super::*;
+
+    #[test]
+    fn limits_hold_for_every_route_length() {
+        let dest = DhSecret::generate();
+        for hops in 0..MAX_ROUTE_HOPS {
+            let route: Vec<ForwardInstruction> = (0..hops)
+                .map(|_| ForwardInstruction {
+                    this_pubkey: DhSecret::generate().public(),
+                    next_hop: RelayIdentitySecret::generate().public().fingerprint(),
+                })
+                .collect();
+            for dock in [7, 100001, Dock::MAX] {
+                let packet = |len: usize| {
+                    RawPacket::new_normal(
+                        &route,
+                        &dest.public(),
+                        InnerPacket::Message(Message::new(dock, Bytes::from(vec![0u8; len]))),
+                        RemoteId::Anon(AnonEndpoint::random()),
+                    )
+                };
+                let max = max_single_message(dock);
+                assert!(max >= max_single_message_any_dock());
+                assert!(packet(max).is_ok(), "{hops} hops, dock {dock}");
+                assert!(matches!(
+                    packet(max + 1),
+                    Err(PacketConstructError::MessageTooBig)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn streams_fit_in_one_message() {
+        // stream chunks travel inside haven packets, which have overhead of their own
+        assert!(STREAM_CHUNK + 512 < max_single_message_any_dock());
+        assert!(MAX_PIPELINED_PAYLOAD < STREAM_CHUNK);
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let unknown = RelayIdentitySecret::generate().public().fingerprint();
+        let limits = transport_limits(&ctx, RelayEndpoint::new(unknown, 7));
+        assert_eq!(limits.route_hops, None);
+        assert_eq!(limits.max_single_message, max_single_message(7));
+    }
+}
@@ -1,44 +1,32 @@
 use anyhow::Context;
 use bip39::Mnemonic;
-use clap::{Parser, Subcommand};
+use clap::Parser;
+use clap_complete::CompleteEnv;
+use earendil::check_config;
+use earendil::cli;
+use earendil::gen_identity_file;
 use earendil::main_control;
+use earendil::write_completion;
+use earendil::Cli;
+use earendil::CliCommand;
 use earendil::ConfigFile;
-use earendil::ControlCommand;
 use earendil::Daemon;
-use std::{net::SocketAddr, path::PathBuf};
+use earendil::Migration;
+use earendil::COMPLETE_VAR;
+use earendil::SNAPSHOT_PASSPHRASE_VAR;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
-/// Official implementation of an Earendil node
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    #[command(subcommand)]
-    command: Commands,
-}
-
-#[derive(Subcommand)]
-enum Commands {
-    /// Runs an Earendil daemon.
-    Daemon {
-        #[arg(short, long)]
-        config: PathBuf,
-    },
-
-    /// Runs a control-protocol verb.
-    Control {
-        #[arg(short, long, default_value = "127.0.0.1:18964")]
-        connect: SocketAddr,
-        #[command(subcommand)]
-        control_command: ControlCommand,
-    },
-
-    GenerateSeed,
-}
-
 #[tracing::instrument]
 fn main() -> anyhow::Result<()> {
+    // answers the shell when a dynamic completion script calls back into us
+    CompleteEnv::with_factory(cli).var(COMPLETE_VAR).complete();
+
     // initialize tracing subscriber that displays to output
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer().compact())
@@ -49,33 +37,102 @@ fn main() -> anyhow::Result<()> {
         )
         .init();
 
-    match Args::parse().command {
-        Commands::Daemon { config } => {
-            let json: serde_json::Value =
-                serde_yaml::from_slice(&std::fs::read(config).context("cannot read config file")?)
-                    .context("syntax error in config file")?;
-            let config_parsed: ConfigFile = serde_json::from_value(json)?;
+    match Cli::parse().command {
+        CliCommand::Daemon { config, restore } => {
+            if let Some(path) = config.as_ref().filter(|path| path.as_os_str() != "-") {
+                smolscale::block_on(check_migrated(path))?;
+            }
+            let config_parsed = ConfigFile::from_yaml(&read_config(config)?)?;
             tracing::debug!(
                 "parsed config file: {}",
                 serde_json::to_string_pretty(&config_parsed)?
             );
             tracing::info!("about to init daemon!");
-            let daemon = Daemon::init(config_parsed)?;
-            match smol::future::block_on(daemon.wait_until_dead()) {
-                Ok(_) => anyhow::bail!("daemon is dead, with no error msg"),
-                Err(err) => anyhow::bail!(err),
+            match restore {
+                Some(path) => {
+                    let snapshot = std::fs::read(&path)
+                        .with_context(|| format!("cannot read snapshot {}", path.display()))?;
+                    let passphrase = std::env::var(SNAPSHOT_PASSPHRASE_VAR).with_context(|| {
+                        format!("{SNAPSHOT_PASSPHRASE_VAR} must hold the snapshot's passphrase")
+                    })?;
+                    Daemon::restore_state(config_parsed, &snapshot, &passphrase)?.join()
+                }
+                None => Daemon::start(config_parsed)?.join(),
             }
         }
-        Commands::Control {
+        CliCommand::Control {
             control_command,
             connect,
         } => smolscale::block_on(main_control(control_command, connect)),
-        Commands::GenerateSeed => {
+        CliCommand::GenerateSeed => {
             let seed_phrase = gen_seed()?;
             println!("{}", seed_phrase);
             Ok(())
         }
+        CliCommand::CheckConfig {
+            config,
+            diff,
+            connect,
+        } => {
+            let yaml = read_config(Some(config))?;
+            smolscale::block_on(check_config(yaml, diff.then_some(connect)))
+        }
+        CliCommand::Migrate { config, dry_run } => smolscale::block_on(async {
+            let migration = Migration::plan(&config).await?;
+            print!("{migration}");
+            if !dry_run && !migration.is_empty() {
+                migration.apply().await?;
+                println!("migrated");
+            }
+            Ok(())
+        }),
+        CliCommand::GenIdentity { path } => {
+            let identity = gen_identity_file(&path)?;
+            println!("{}", identity.public().fingerprint());
+            Ok(())
+        }
+        CliCommand::Completion { shell, dynamic } => {
+            let bin = std::env::current_exe().context("cannot find our own binary")?;
+            write_completion(
+                shell,
+                dynamic,
+                &bin.to_string_lossy(),
+                &mut std::io::stdout(),
+            )?;
+            Ok(())
+        }
+    }
+}
+
+/// Reads a YAML config from a file, from stdin if the path is `-`, or from the EARENDIL_CONFIG environment variable if there is no path, so that containers can run the daemon without a config file on disk.
+fn read_config(path: Option<PathBuf>) -> anyhow::Result<Vec<u8>> {
+    match path {
+        Some(path) if path.as_os_str() == "-" => {
+            let mut yaml = vec![];
+            std::io::stdin()
+                .read_to_end(&mut yaml)
+                .context("cannot read config from stdin")?;
+            Ok(yaml)
+        }
+        Some(path) => std::fs::read(path).context("cannot read config file"),
+        None => Ok(std::env::var(CONFIG_ENV)
+            .with_context(|| format!("no config file given, and {CONFIG_ENV} not set"))?
+            .into_bytes()),
+    }
+}
+
+const CONFIG_ENV: &str = "EARENDIL_CONFIG";
+
+/// Refuses to start on whatever an older version left behind, rather than migrating it behind the operator's back.
+async fn check_migrated(config: &Path) -> anyhow::Result<()> {
+    let migration = Migration::plan(config).await?;
+    if !migration.is_empty() {
+        anyhow::bail!(
+            "this node was set up by an older version, and needs migrating first:\n{migration}run `earendil migrate {}` to migrate it",
+            config.display()
+        );
     }
+    Ok(())
 }
 
 fn gen_seed() -> anyhow::Result<String> {
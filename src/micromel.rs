@@ -0,0 +1,129 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// How many micromel make up one mel.
+pub const MICROMEL_PER_MEL: u64 = 1_000_000;
+
+/// An amount of mel, counted in micromel, the millionths of a mel that prices, debts, and settlements are denominated in.
+///
+/// Serializes as a plain integer, so that it can replace a `u64` of micromel without changing any stored or wire format. Arithmetic is checked, so amounts never silently wrap around.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+pub struct Micromel(pub u64);
+
+impl Micromel {
+    pub const ZERO: Self = Self(0);
+    pub const MAX: Self = Self(u64::MAX);
+
+    /// Converts a whole number of mel, or returns `None` if it doesn't fit.
+    pub fn from_mel(mel: u64) -> Option<Self> {
+        mel.checked_mul(MICROMEL_PER_MEL).map(Self)
+    }
+
+    /// Converts a fractional number of mel, rounding to the nearest micromel. Returns `None` for negative, non-finite, or too large amounts.
+    pub fn from_mel_f64(mel: f64) -> Option<Self> {
+        let micromel = (mel * MICROMEL_PER_MEL as f64).round();
+        if micromel.is_finite() && micromel >= 0.0 && micromel < u64::MAX as f64 {
+            Some(Self(micromel as u64))
+        } else {
+            None
+        }
+    }
+
+    /// Converts to mel, for display only, since an `f64` can't represent every amount exactly.
+    pub fn to_mel_f64(self) -> f64 {
+        self.0 as f64 / MICROMEL_PER_MEL as f64
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    pub fn checked_mul(self, factor: u64) -> Option<Self> {
+        self.0.checked_mul(factor).map(Self)
+    }
+
+    pub fn saturating_add(self, other: Self) -> Self {
+        Self(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Self) -> Self {
+        Self(self.0.saturating_sub(other.0))
+    }
+
+    pub fn saturating_mul(self, factor: u64) -> Self {
+        Self(self.0.saturating_mul(factor))
+    }
+
+    /// `self - other`, in micromel, which may be negative. Never overflows.
+    pub fn signed_sub(self, other: Self) -> i128 {
+        i128::from(self) - i128::from(other)
+    }
+
+    /// Clamps a signed amount of micromel, such as a net debt, into the range of amounts.
+    pub fn clamp_from(micromel: i128) -> Self {
+        Self(micromel.clamp(0, u64::MAX as i128) as u64)
+    }
+}
+
+impl From<Micromel> for i128 {
+    fn from(amount: Micromel) -> Self {
+        amount.0 as i128
+    }
+}
+
+/// Displays the amount in mel, exactly, with all six decimals.
+impl Display for Micromel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{:06} MEL",
+            self.0 / MICROMEL_PER_MEL,
+            self.0 % MICROMEL_PER_MEL
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_between_units() {
+        assert_eq!(Micromel::from_mel(3), Some(Micromel(3_000_000)));
+        assert_eq!(Micromel::from_mel(u64::MAX), None);
+        assert_eq!(Micromel::from_mel_f64(0.1), Some(Micromel(100_000)));
+        assert_eq!(Micromel::from_mel_f64(1.0000004), Some(Micromel(1_000_000)));
+        assert_eq!(Micromel::from_mel_f64(-1.0), None);
+        assert_eq!(Micromel::from_mel_f64(f64::NAN), None);
+        assert_eq!(Micromel::from_mel_f64(1e300), None);
+        assert_eq!(Micromel(2_500_000).to_mel_f64(), 2.5);
+        assert_eq!(Micromel(1_000_001).to_string(), "1.000001 MEL");
+        // stored and sent as a plain integer, like the u64 it replaces
+        assert_eq!(
+            stdcode::serialize(&Micromel(1234)).unwrap(),
+            stdcode::serialize(&1234u64).unwrap()
+        );
+    }
+
+    #[test]
+    fn arithmetic_is_checked() {
+        let (small, big) = (Micromel(5), Micromel::MAX);
+        assert_eq!(small.checked_add(small), Some(Micromel(10)));
+        assert_eq!(big.checked_add(small), None);
+        assert_eq!(small.checked_sub(big), None);
+        assert_eq!(big.checked_mul(2), None);
+        assert_eq!(big.saturating_add(small), Micromel::MAX);
+        assert_eq!(small.saturating_sub(big), Micromel::ZERO);
+        assert_eq!(small.signed_sub(big), 5 - u64::MAX as i128);
+        assert_eq!(Micromel::clamp_from(-3), Micromel::ZERO);
+        assert_eq!(Micromel::clamp_from(i128::MAX), Micromel::MAX);
+    }
+}
@@ -0,0 +1,643 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use earendil_crypt::{HavenIdentitySecret, RelayFingerprint, RelayIdentitySecret};
+use earendil_topology::{legacy::IdentityDescriptorV0, AdjacencyDescriptor, RelayGraph};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, Connection, Row, SqliteConnection};
+use stdcode::StdcodeSerializeExt;
+
+use crate::{
+    config::{write_secret_file, ConfigFile, GraphFormat},
+    db::{decode_graph, decompress_value, encode_graph, MiscKey},
+};
+
+/// Bumped whenever a new migration is added, and recorded in the state cache once it ran.
+pub const MIGRATION_VERSION: u64 = 1;
+
+/// Appended to the path of everything we migrate, to keep the original around.
+const BACKUP_SUFFIX: &str = ".pre-migration";
+
+/// Appended to the path of migrated files while they're being written, before they're renamed over the originals.
+const STAGING_SUFFIX: &str = ".migrating";
+
+/// How the old daemon persisted the relay graph: every descriptor it knew, as signed by the relays themselves.
+#[derive(Serialize, Deserialize)]
+struct LegacyGraph {
+    identities: Vec<IdentityDescriptorV0>,
+    adjacencies: Vec<AdjacencyDescriptor>,
+}
+
+/// Everything that needs to change for a node set up by the old daemon to run on this one. Planning a migration touches nothing, so that it can be shown to the user first, and the daemon never applies one by itself.
+///
+/// Each part of a migration is only planned if it's still needed, so a migration that was interrupted can be planned and applied again to finish it.
+pub struct Migration {
+    config_path: PathBuf,
+    /// The migrated config as YAML, if the config needs migrating.
+    new_config: Option<String>,
+    /// Identity files in the old hex format, with the secrets they hold.
+    identities: Vec<(PathBuf, [u8; 32])>,
+    /// The state cache, if it still needs migrating.
+    state_cache: Option<PathBuf>,
+    /// The old relay graph, if the state cache has one that can be decoded.
+    graph: Option<LegacyGraph>,
+    changes: Vec<String>,
+    /// What can't be migrated automatically, and what to do about it.
+    errors: Vec<String>,
+}
+
+impl Migration {
+    /// Works out what it takes to migrate the node with the given config file, without changing anything.
+    pub async fn plan(config_path: &Path) -> anyhow::Result<Self> {
+        let yaml = std::fs::read(config_path)
+            .with_context(|| format!("cannot read config file {:?}", config_path))?;
+        let mut config: Value =
+            serde_yaml::from_slice(&yaml).context("syntax error in config file")?;
+        let mut migration = Migration {
+            config_path: config_path.to_owned(),
+            new_config: None,
+            identities: vec![],
+            state_cache: None,
+            graph: None,
+            changes: vec![],
+            errors: vec![],
+        };
+
+        migration.migrate_routes(&mut config);
+        if !migration.changes.is_empty() && migration.errors.is_empty() {
+            match serde_json::from_value::<ConfigFile>(config.clone()) {
+                Ok(_) => migration.new_config = Some(serde_yaml::to_string(&config)?),
+                Err(err) => migration
+                    .errors
+                    .push(format!("the migrated config is still invalid: {err}")),
+            }
+        }
+
+        let relay_identity = config["identity_file"]
+            .as_str()
+            .map(|path| (PathBuf::from(path), true));
+        let haven_identities = config["havens"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|haven| haven["identity_file"].as_str())
+            .map(|path| (PathBuf::from(path), false));
+        for (path, is_relay) in relay_identity.into_iter().chain(haven_identities) {
+            let Ok(contents) = std::fs::read(&path) else {
+                continue;
+            };
+            if let Some(secret) = legacy_secret(&contents) {
+                let identity = if is_relay {
+                    format!(
+                        "relay fingerprint {}",
+                        RelayIdentitySecret::from_bytes(&secret)
+                            .public()
+                            .fingerprint()
+                    )
+                } else {
+                    format!(
+                        "haven fingerprint {}",
+                        HavenIdentitySecret::from_bytes(&secret)
+                            .public()
+                            .fingerprint()
+                    )
+                };
+                migration.changes.push(format!(
+                    "identity file {:?}: re-encode from hex to raw bytes ({identity})",
+                    path
+                ));
+                migration.identities.push((path, secret));
+            }
+        }
+
+        if let Some(state_cache) = config["state_cache"].as_str().map(PathBuf::from) {
+            if state_cache.exists() {
+                migration.plan_state_cache(&state_cache).await?;
+            }
+        }
+        Ok(migration)
+    }
+
+    /// Whether there is nothing to migrate.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.errors.is_empty()
+    }
+
+    /// Carries out the migration, backing up everything it changes. Fails without changing anything if some of it can't be migrated automatically.
+    ///
+    /// Everything is written out to staging files and a state cache transaction first, and only once all of it is ready does the transaction commit and the staging files get renamed over the originals. Failing before then leaves everything as it was.
+    pub async fn apply(self) -> anyhow::Result<()> {
+        if !self.errors.is_empty() {
+            anyhow::bail!("cannot migrate:\n{}", self.errors.join("\n"))
+        }
+        let mut staged = Staged::default();
+        if let Err(err) = self.stage_and_commit(&mut staged).await {
+            staged.discard();
+            return Err(err);
+        }
+        for (staging, path) in staged.renames {
+            std::fs::rename(&staging, &path).with_context(|| {
+                format!("cannot move {:?} into place. Migrate again to finish", path)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Writes out everything the migration changes, and commits the state cache part of it.
+    async fn stage_and_commit(&self, staged: &mut Staged) -> anyhow::Result<()> {
+        if let Some(new_config) = &self.new_config {
+            staged.back_up(&self.config_path)?;
+            let staging = staged.stage(&self.config_path)?;
+            std::fs::write(&staging, new_config)
+                .with_context(|| format!("cannot write config file {:?}", staging))?;
+        }
+        for (path, secret) in &self.identities {
+            staged.back_up(path)?;
+            let staging = staged.stage(path)?;
+            write_secret_file(&staging, secret)
+                .with_context(|| format!("cannot write identity file {:?}", staging))?;
+        }
+        let Some(state_cache) = &self.state_cache else {
+            return Ok(());
+        };
+        staged.back_up(state_cache)?;
+        let mut conn = connect(state_cache, false).await?;
+        let mut tx = conn.begin().await?;
+        if let Some(legacy) = &self.graph {
+            let mut graph = match read_misc(&mut tx, MiscKey::RelayGraph).await? {
+                // merging into a graph we can't read would throw away what it holds, so we stop instead
+                Some(graph) => {
+                    decode_graph(&graph)
+                        .context("cannot read the relay graph already in the state cache")?
+                        .graph
+                }
+                None => RelayGraph::new(),
+            };
+            import_graph(&mut graph, legacy);
+            write_misc(
+                &mut tx,
+                MiscKey::RelayGraph,
+                encode_graph(&graph, GraphFormat::default())?,
+            )
+            .await?;
+        }
+        sqlx::query("DELETE FROM misc WHERE key = ?")
+            .bind(MiscKey::LegacyGraph.name())
+            .execute(&mut *tx)
+            .await?;
+        write_misc(
+            &mut tx,
+            MiscKey::MigrationVersion,
+            MIGRATION_VERSION.stdcode(),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Maps the old route settings onto their current equivalents, or explains why they can't be.
+    fn migrate_routes(&mut self, config: &mut Value) {
+        for (kind, key) in [("in_route", "in_routes"), ("out_route", "out_routes")] {
+            let Some(routes) = config.get_mut(key).and_then(Value::as_object_mut) else {
+                continue;
+            };
+            for (name, route) in routes.iter_mut() {
+                let Some(route) = route.as_object_mut() else {
+                    continue;
+                };
+                self.migrate_route(kind, name, route);
+            }
+        }
+    }
+
+    fn migrate_route(&mut self, kind: &str, name: &str, route: &mut Map<String, Value>) {
+        match route.remove("protocol") {
+            None => {}
+            Some(Value::String(protocol)) if protocol == "tcp" => {
+                self.changes.push(format!(
+                    "{kind} {name}: drop `protocol: tcp`, since TCP is the only transport now"
+                ));
+            }
+            Some(Value::String(protocol)) if protocol == "obfsudp" => {
+                let key = if kind == "in_route" {
+                    "secret"
+                } else {
+                    "cookie"
+                };
+                self.errors.push(format!(
+                    "{kind} {name}: obfsudp is no longer supported. Replace `protocol: obfsudp` and `{key}` with `obfs: {{sosistab3: <shared secret>}}`, and configure the relays on the other end with the same secret"
+                ));
+                return;
+            }
+            Some(other) => {
+                self.errors
+                    .push(format!("{kind} {name}: unknown protocol {other}"));
+                return;
+            }
+        }
+        if !route.contains_key("obfs") {
+            route.insert("obfs".into(), json!("none"));
+            self.changes.push(format!(
+                "{kind} {name}: set `obfs: none`, which is what routes without obfuscation settings used to mean"
+            ));
+        }
+        if let Some(fingerprint) = route.get("fingerprint").and_then(Value::as_str) {
+            // trusting whichever relay answers first instead would quietly undo the pin
+            if RelayFingerprint::from_str(fingerprint).is_err()
+                && is_legacy_fingerprint(fingerprint)
+            {
+                self.errors.push(format!(
+                    "{kind} {name}: the old-style fingerprint {fingerprint} can't be converted. Ask the relay's operator for its current fingerprint and put that in `fingerprint`"
+                ));
+            }
+        }
+    }
+
+    async fn plan_state_cache(&mut self, path: &Path) -> anyhow::Result<()> {
+        let mut conn = connect(path, true).await?;
+        let has_misc =
+            sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'misc'")
+                .fetch_optional(&mut conn)
+                .await?
+                .is_some();
+        if !has_misc {
+            return Ok(());
+        }
+        let version: u64 = read_misc(&mut conn, MiscKey::MigrationVersion)
+            .await?
+            .and_then(|version| stdcode::deserialize(&version).ok())
+            .unwrap_or(0);
+        if version >= MIGRATION_VERSION {
+            return Ok(());
+        }
+        let Some(legacy) = read_misc(&mut conn, MiscKey::LegacyGraph).await? else {
+            return Ok(());
+        };
+        match stdcode::deserialize::<LegacyGraph>(&legacy) {
+            Ok(graph) => {
+                self.changes.push(format!(
+                    "state cache {:?}: import {} relays and {} adjacencies from the old relay graph",
+                    path,
+                    graph.identities.len(),
+                    graph.adjacencies.len()
+                ));
+                self.graph = Some(graph);
+            }
+            Err(_) => self.changes.push(format!(
+                "state cache {:?}: discard the old relay graph, which can't be decoded. It will be learned again from neighbors",
+                path
+            )),
+        }
+        self.state_cache = Some(path.to_owned());
+        Ok(())
+    }
+}
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "nothing to migrate");
+        }
+        for change in self.changes.iter() {
+            writeln!(f, "~ {change}")?;
+        }
+        for error in self.errors.iter() {
+            writeln!(f, "! {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Imports the old graph through the same validating path as gossip, so that nothing unsigned or expired gets in.
+fn import_graph(graph: &mut RelayGraph, legacy: &LegacyGraph) {
+    let (mut imported, mut rejected) = (0, 0);
+    for identity in legacy.identities.iter().cloned() {
+        match graph.insert_identity(identity.into()) {
+            Ok(()) => imported += 1,
+            Err(_) => rejected += 1,
+        }
+    }
+    for adjacency in legacy.adjacencies.iter().cloned() {
+        match graph.insert_adjacency(adjacency) {
+            Ok(()) => imported += 1,
+            Err(_) => rejected += 1,
+        }
+    }
+    tracing::info!(imported, rejected, "imported the old relay graph");
+}
+
+/// The secret in an identity file, if the file has it in the old hex format rather than as raw bytes.
+fn legacy_secret(contents: &[u8]) -> Option<[u8; 32]> {
+    if contents.len() == 32 {
+        return None;
+    }
+    let hex_secret = std::str::from_utf8(contents).ok()?.trim();
+    hex::decode(hex_secret).ok()?.try_into().ok()
+}
+
+/// Whether a fingerprint looks like the old 20-byte base32 ones.
+fn is_legacy_fingerprint(fingerprint: &str) -> bool {
+    fingerprint.len() == 32 && fingerprint.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// The files a migration wrote before committing, and the renames that commit them.
+#[derive(Default)]
+struct Staged {
+    /// Backups and staging files we created, which go away again if the migration fails before committing.
+    created: Vec<PathBuf>,
+    renames: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Staged {
+    /// Copies a file to its backup path. An earlier backup is only kept if it's the same as the file, as when an interrupted migration is resumed, and refused otherwise.
+    fn back_up(&mut self, path: &Path) -> anyhow::Result<()> {
+        let backup = with_suffix(path, BACKUP_SUFFIX);
+        if backup.exists() {
+            let current = std::fs::read(path).with_context(|| format!("cannot read {:?}", path))?;
+            if std::fs::read(&backup).ok().as_deref() == Some(current.as_slice()) {
+                return Ok(());
+            }
+            anyhow::bail!(
+                "backup {:?} already exists. Move it out of the way to migrate again",
+                backup
+            )
+        }
+        std::fs::copy(path, &backup)
+            .with_context(|| format!("cannot back up {:?} to {:?}", path, backup))?;
+        self.created.push(backup);
+        Ok(())
+    }
+
+    /// Returns where to write the migrated version of `path`, to be renamed over it once the migration commits.
+    fn stage(&mut self, path: &Path) -> anyhow::Result<PathBuf> {
+        let staging = with_suffix(path, STAGING_SUFFIX);
+        // left over from a migration that failed
+        let _ = std::fs::remove_file(&staging);
+        self.created.push(staging.clone());
+        self.renames.push((staging.clone(), path.to_owned()));
+        Ok(staging)
+    }
+
+    /// Removes everything we created, for a migration that failed.
+    fn discard(self) {
+        for path in self.created {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.to_owned().into_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+async fn connect(path: &Path, read_only: bool) -> anyhow::Result<SqliteConnection> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(read_only);
+    options
+        .connect()
+        .await
+        .with_context(|| format!("cannot open state cache {:?}", path))
+}
+
+async fn read_misc(conn: &mut SqliteConnection, key: MiscKey) -> anyhow::Result<Option<Vec<u8>>> {
+    Ok(sqlx::query("SELECT value FROM misc WHERE key = ?")
+        .bind(key.name())
+        .fetch_optional(conn)
+        .await?
+        .map(|row| decompress_value(row.get("value")))
+        .transpose()?)
+}
+
+async fn write_misc(
+    conn: &mut SqliteConnection,
+    key: MiscKey,
+    value: Vec<u8>,
+) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO misc (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value")
+        .bind(key.name())
+        .bind(value)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::IdentityDescriptor;
+
+    use crate::{
+        context::{DaemonContext, MY_RELAY_IDENTITY, RELAY_GRAPH},
+        ledger::unix_now,
+        test_util::adjacency,
+    };
+
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("earendil-migrate-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A graph of a few relays in a line, in the old format.
+    fn legacy_graph(relays: &[RelayIdentitySecret]) -> LegacyGraph {
+        let now = unix_now();
+        let identities = relays
+            .iter()
+            .map(|relay| IdentityDescriptor::new(relay, &DhSecret::generate()).into())
+            .collect();
+        let adjacencies = relays
+            .windows(2)
+            .map(|pair| adjacency(&pair[0], &pair[1], now))
+            .collect();
+        LegacyGraph {
+            identities,
+            adjacencies,
+        }
+    }
+
+    async fn write_legacy_state_cache(path: &Path, graph: &LegacyGraph) {
+        let mut conn = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .connect()
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE misc (key TEXT PRIMARY KEY, value BLOB NOT NULL);")
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        write_misc(&mut conn, MiscKey::LegacyGraph, graph.stdcode())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn migrates_a_legacy_node() {
+        smol::future::block_on(async {
+            let dir = temp_dir();
+            let identity = RelayIdentitySecret::generate();
+            let identity_path = dir.join("identity");
+            std::fs::write(
+                &identity_path,
+                format!("{}\n", hex::encode(identity.as_bytes())),
+            )
+            .unwrap();
+            let state_cache = dir.join("state.db");
+            let relays: Vec<RelayIdentitySecret> =
+                (0..3).map(|_| RelayIdentitySecret::generate()).collect();
+            write_legacy_state_cache(&state_cache, &legacy_graph(&relays)).await;
+            let config_path = dir.join("config.yaml");
+            let peer = RelayIdentitySecret::generate().public().fingerprint();
+            let legacy_config = format!(
+                "identity_file: {}\nstate_cache: {}\nin_routes:\n  main:\n    listen: 0.0.0.0:19999\n    protocol: tcp\nout_routes:\n  peer:\n    connect: 1.2.3.4:19999\n    fingerprint: {peer}\n",
+                identity_path.display(),
+                state_cache.display()
+            );
+            std::fs::write(&config_path, &legacy_config).unwrap();
+
+            // planning is a dry run, which touches nothing
+            let migration = Migration::plan(&config_path).await.unwrap();
+            assert!(migration.errors.is_empty(), "{migration}");
+            assert_eq!(migration.changes.len(), 5, "{migration}");
+            assert_eq!(
+                std::fs::read_to_string(&config_path).unwrap(),
+                legacy_config
+            );
+            assert_eq!(std::fs::read(&identity_path).unwrap().len(), 65);
+            assert!(!dir.join("config.yaml.pre-migration").exists());
+
+            migration.apply().await.unwrap();
+            for backup in ["config.yaml", "identity", "state.db"] {
+                assert!(dir.join(format!("{backup}{BACKUP_SUFFIX}")).exists());
+            }
+            let config = ConfigFile::from_yaml(&std::fs::read(&config_path).unwrap()).unwrap();
+            // the pin stays as it was
+            assert_eq!(config.out_routes["peer"].fingerprint, Some(peer));
+            assert!(!config.out_routes["peer"].tofu);
+            assert!(!dir.join(format!("config.yaml{STAGING_SUFFIX}")).exists());
+
+            // the node comes up as the same relay, and still knows the graph
+            let ctx = DaemonContext::new(config);
+            assert_eq!(
+                ctx.get(MY_RELAY_IDENTITY).unwrap().public().fingerprint(),
+                identity.public().fingerprint()
+            );
+            let graph = ctx.get(RELAY_GRAPH).read();
+            for relay in relays.iter() {
+                assert!(graph.identity(&relay.public().fingerprint()).is_some());
+            }
+            assert_eq!(graph.all_adjacencies().count(), 2);
+            drop(graph);
+
+            // and the migration only ever runs once
+            assert!(Migration::plan(&config_path).await.unwrap().is_empty());
+
+            // but one that was cut short before the config got moved into place can be finished
+            std::fs::copy(
+                dir.join(format!("config.yaml{BACKUP_SUFFIX}")),
+                &config_path,
+            )
+            .unwrap();
+            let migration = Migration::plan(&config_path).await.unwrap();
+            assert_eq!(migration.changes.len(), 3, "{migration}");
+            migration.apply().await.unwrap();
+            assert!(Migration::plan(&config_path).await.unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    fn pinned_fingerprints_are_never_dropped() {
+        smol::future::block_on(async {
+            let dir = temp_dir();
+            let config_path = dir.join("config.yaml");
+            let legacy_config = "out_routes:\n  peer:\n    connect: 1.2.3.4:19999\n    fingerprint: 8nvvqmx2m16haw897y1c2d5f26gdpb92\n";
+            std::fs::write(&config_path, legacy_config).unwrap();
+
+            let migration = Migration::plan(&config_path).await.unwrap();
+            assert!(migration
+                .to_string()
+                .contains("! out_route peer: the old-style fingerprint"));
+            assert!(migration.apply().await.is_err());
+            assert_eq!(
+                std::fs::read_to_string(&config_path).unwrap(),
+                legacy_config
+            );
+        })
+    }
+
+    #[test]
+    fn unreadable_graphs_are_not_merged_over() {
+        smol::future::block_on(async {
+            let dir = temp_dir();
+            let state_cache = dir.join("state.db");
+            let relays: Vec<RelayIdentitySecret> =
+                (0..2).map(|_| RelayIdentitySecret::generate()).collect();
+            write_legacy_state_cache(&state_cache, &legacy_graph(&relays)).await;
+            let mut conn = connect(&state_cache, false).await.unwrap();
+            write_misc(&mut conn, MiscKey::RelayGraph, b"\x07not a graph".to_vec())
+                .await
+                .unwrap();
+            drop(conn);
+            let identity_path = dir.join("identity");
+            let hex_identity = hex::encode(RelayIdentitySecret::generate().as_bytes());
+            std::fs::write(&identity_path, &hex_identity).unwrap();
+            let config_path = dir.join("config.yaml");
+            std::fs::write(
+                &config_path,
+                format!(
+                    "identity_file: {}\nstate_cache: {}\n",
+                    identity_path.display(),
+                    state_cache.display()
+                ),
+            )
+            .unwrap();
+
+            let migration = Migration::plan(&config_path).await.unwrap();
+            assert!(!migration.is_empty(), "{migration}");
+            assert!(migration.apply().await.is_err());
+            // the identity was ready to go by then, but stays as it was, with nothing left behind
+            assert_eq!(
+                std::fs::read_to_string(&identity_path).unwrap(),
+                hex_identity
+            );
+            let mut left: Vec<String> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            left.sort();
+            assert_eq!(left, ["config.yaml", "identity", "state.db"]);
+        })
+    }
+
+    #[test]
+    fn obfsudp_routes_need_a_hand() {
+        smol::future::block_on(async {
+            let dir = temp_dir();
+            let config_path = dir.join("config.yaml");
+            let legacy_config = "identity_seed: gorilla\nin_routes:\n  main_udp:\n    listen: 0.0.0.0:12024\n    protocol: obfsudp\n    secret: snake\nout_routes:\n  cow:\n    connect: 200.64.1.20:12038\n    cookie: f3a8797c\n    fingerprint: 3850wrrv3a36qermpeh4r39fz3hhpvp1\n    protocol: obfsudp\n";
+            std::fs::write(&config_path, legacy_config).unwrap();
+
+            let migration = Migration::plan(&config_path).await.unwrap();
+            let report = migration.to_string();
+            assert!(report.contains("! in_route main_udp: obfsudp is no longer supported"));
+            assert!(report.contains("! out_route cow: obfsudp is no longer supported"));
+            assert!(report.contains("`cookie`"));
+            assert!(migration.apply().await.is_err());
+            assert_eq!(
+                std::fs::read_to_string(&config_path).unwrap(),
+                legacy_config
+            );
+            assert!(!dir.join("config.yaml.pre-migration").exists());
+        })
+    }
+}
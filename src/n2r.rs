@@ -1,10 +1,29 @@
 mod anon_dest;
-mod delay_queue;
+mod circuit;
+mod class;
+mod guards;
 mod remote_rb;
+mod roaming;
+mod route_memory;
+mod surb_bundle;
+mod surb_routes;
 
-pub use remote_rb::replenish_remote_rb;
+pub use circuit::CircuitToken;
+pub use class::{MessageClass, MAX_ROUTE_HOPS, MIN_ROUTE_HOPS};
+pub(crate) use guards::current_guards;
+pub use guards::{entry_guards, EntryGuard, ENTRY_GUARDS};
+pub use remote_rb::{forget_reply_blocks, replenish_remote_rb};
+pub use roaming::{last_roaming_event, link_down, roaming_events, RoamingEvent, ROAMED};
+pub use route_memory::{LearnedRoute, ROUTE_MEMORY};
+pub use surb_bundle::{
+    import_surb_bundle, issue_surb_bundle, persisted_surb_bundles, surb_bundles, SurbBundle,
+    SurbBundleError, SurbBundleStock, SurbBundles, MAX_BUNDLES, MAX_BUNDLE_SURBS, MAX_HELD_SURBS,
+};
 
-use std::time::Instant;
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Instant,
+};
 
 use anyhow::Context;
 use bytes::Bytes;
@@ -13,13 +32,17 @@ use earendil_crypt::{AnonEndpoint, RelayFingerprint, RemoteId};
 use earendil_packet::{
     Dock, ForwardInstruction, InnerPacket, Message, RawBody, RawPacket, ReplyDegarbler,
 };
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use smol::channel::{Receiver, Sender};
+use thiserror::Error;
 
 use crate::{
-    context::{CtxField, DaemonContext, MY_RELAY_IDENTITY, RELAY_GRAPH},
+    context::{require_relay, CtxField, DaemonContext, RELAY_GRAPH},
+    ledger, limits,
     n2r::anon_dest::ANON_DESTS,
     n2r_socket::RelayEndpoint,
-    network::send_raw,
+    network::{check_reachable, check_reachable_cached, send_raw, send_raw_nackable, NackOrigin},
+    stats::STATS,
 };
 
 static DEGARBLERS: CtxField<DashMap<u64, ReplyDegarbler>> = |_| Default::default();
@@ -76,37 +99,75 @@ pub async fn read_forward(ctx: &DaemonContext) -> anyhow::Result<(Bytes, AnonEnd
     }
 }
 
+pub const DEGARBLE_SUCCESS: &str = "degarble.success";
+pub const DEGARBLE_NO_DEGARBLER: &str = "degarble.no_degarbler";
+pub const DEGARBLE_CRYPTO_FAILURE: &str = "degarble.crypto_failure";
+
+pub const FORWARD_REROUTED: &str = "n2r.forward_rerouted";
+
 // called by a loop in `daemon.rs` to send data to the right sockets
 pub async fn read_backward(
     ctx: &DaemonContext,
 ) -> anyhow::Result<(Bytes, RelayEndpoint, AnonEndpoint)> {
-    let (mut reply, degarbler_id) = ctx.get(INCOMING_BACKWARDS).1.recv().await?;
-    let degarbler = ctx
-        .get(DEGARBLERS)
-        .remove(&degarbler_id)
-        .context("no degarbler for incoming reply")?
-        .1;
-    let (inner_pkt, relay_fp) = degarbler.degarble(&mut reply)?;
+    let (reply, degarbler_id) = ctx.get(INCOMING_BACKWARDS).1.recv().await?;
+    let (inner_pkt, relay_fp, anon_endpoint, from_bundle) =
+        degarble_backward(ctx, reply, degarbler_id)?;
     match inner_pkt {
         InnerPacket::Message(msg) => {
-            let anon_endpoint = degarbler.my_anon_id();
             let relay_endpoint = RelayEndpoint::new(relay_fp, msg.relay_dock);
-            // consume a reply block
-            remote_rb::consume_remote_rb(ctx, anon_endpoint, relay_endpoint.fingerprint).await;
+            // replies using bundled reply blocks don't answer anything we sent, and the bundle is never replenished
+            if !from_bundle {
+                route_memory::reply_received(ctx, anon_endpoint, relay_fp);
+                // consume a reply block
+                remote_rb::consume_remote_rb(ctx, anon_endpoint, relay_endpoint.fingerprint).await;
+            }
             Ok((msg.body, relay_endpoint, anon_endpoint))
         }
         InnerPacket::ReplyBlocks(_) => anyhow::bail!("we shouldn't be getting reply blocks here"),
     }
 }
 
-/// Sends a raw N2R message with the given parameters.
-#[tracing::instrument(skip(ctx, content))]
+/// Degarbles an incoming reply with its matching degarbler, recording the outcome in the stats. Also returns whether the reply used a reply block from a [SurbBundle].
+fn degarble_backward(
+    ctx: &DaemonContext,
+    mut reply: RawBody,
+    degarbler_id: u64,
+) -> anyhow::Result<(InnerPacket, RelayFingerprint, AnonEndpoint, bool)> {
+    let (degarbler, from_bundle) = match ctx.get(DEGARBLERS).remove(&degarbler_id) {
+        Some((_, degarbler)) => (degarbler, false),
+        None => match surb_bundle::take_degarbler(ctx, degarbler_id) {
+            Some(degarbler) => (degarbler, true),
+            None => {
+                ctx.get(STATS).incr(DEGARBLE_NO_DEGARBLER);
+                anyhow::bail!("no degarbler for incoming reply")
+            }
+        },
+    };
+    match degarbler.degarble(&mut reply) {
+        Ok((inner_pkt, relay_fp)) => {
+            ctx.get(STATS).incr(DEGARBLE_SUCCESS);
+            Ok((inner_pkt, relay_fp, degarbler.my_anon_id(), from_bundle))
+        }
+        Err(err) => {
+            ctx.get(STATS).incr(DEGARBLE_CRYPTO_FAILURE);
+            tracing::warn!(err = debug(&err), "failed to degarble incoming reply");
+            Err(err)
+        }
+    }
+}
+
+/// Sends a raw N2R message with the given parameters, along a route belonging to the given circuit. The route's length, and how long relays hold the message, follow from its class. If `nack` is given, the message is NACK-eligible. Fails with [NoRoute] right away if the relay graph has no route to the destination.
+#[tracing::instrument(skip(ctx, content, nack))]
+#[allow(clippy::too_many_arguments)]
 pub async fn send_forward(
     ctx: &DaemonContext,
     src: AnonEndpoint,
     dst_fp: RelayFingerprint,
     dst_dock: Dock,
     content: Bytes,
+    circuit: CircuitToken,
+    class: MessageClass,
+    nack: Option<NackOrigin>,
 ) -> anyhow::Result<()> {
     tracing::trace!("calling send_n2r here");
     let now = Instant::now();
@@ -115,13 +176,52 @@ pub async fn send_forward(
         tracing::trace!("send message took {:?}", send_msg_time);
     });
 
-    let route = forward_route_to(ctx, dst_fp).context("failed to create forward route")?;
+    check_message_size(dst_dock, &content)?;
+    // however the route starts out, it has to end up at the destination
+    if check_reachable_cached(ctx, dst_fp).await.is_err() {
+        return Err(NoRoute(dst_fp).into());
+    }
+    let mut rng = StdRng::from_rng(rand::thread_rng()).context("cannot seed a route rng")?;
+    let route = usable_forward_route(ctx, dst_fp, circuit, class, &mut rng)
+        .await
+        .context("failed to create forward route")?;
     tracing::trace!("RRRRRRRRRRRRRRRRRRRRRR route: {:?}", route);
     let first_peeler = *route
         .first()
         .context("empty route, cannot obtain first peeler")?;
 
-    let instructs = route_to_instructs(ctx, &route).context("route_to_instructs failed")?;
+    let wrapped_onion = forward_packet(
+        ctx,
+        &route,
+        InnerPacket::Message(Message::new(dst_dock, content.clone())),
+        src,
+        class,
+    )?;
+
+    replenish_remote_rb(ctx, src, dst_fp, circuit)
+        .await
+        .context("failed to replenish remote reply blocks")?;
+
+    send_raw_nackable(ctx, wrapped_onion, first_peeler, nack)
+        .await
+        .context("send_raw failed")?;
+    route_memory::sent_along(ctx, src, dst_fp, circuit, &route);
+
+    Ok(())
+}
+
+/// Onion-wraps `payload` from `src` along `route`, whose last relay is the destination, to be held along the way as long as its class says.
+fn forward_packet(
+    ctx: &DaemonContext,
+    route: &[RelayFingerprint],
+    payload: InnerPacket,
+    src: AnonEndpoint,
+    class: MessageClass,
+) -> anyhow::Result<RawPacket> {
+    let dst_fp = *route
+        .last()
+        .context("empty route, cannot obtain destination")?;
+    let instructs = route_to_instructs(ctx, route).context("route_to_instructs failed")?;
     tracing::trace!(
         "*************************** translated this route to instructions: {:?} => {:?}",
         route,
@@ -135,22 +235,13 @@ pub async fn send_forward(
             "couldn't get the identity of the destination fp {dst_fp}"
         ))?
         .onion_pk;
-    let wrapped_onion = RawPacket::new_normal(
+    Ok(RawPacket::new_normal_with_delay(
         &instructs,
         &dest_opk,
-        InnerPacket::Message(Message::new(dst_dock, content.clone())),
+        payload,
         RemoteId::Anon(src),
-    )?;
-
-    replenish_remote_rb(ctx, src, dst_fp)
-        .await
-        .context("failed to replenish remote reply blocks")?;
-
-    send_raw(ctx, wrapped_onion, first_peeler)
-        .await
-        .context("send_raw failed")?;
-
-    Ok(())
+        class::class_routes(ctx, class).mean_delay_ms,
+    )?)
 }
 
 pub async fn send_backward(
@@ -159,10 +250,16 @@ pub async fn send_backward(
     dst: AnonEndpoint,
     content: Bytes,
 ) -> anyhow::Result<()> {
+    // checked before taking a reply block, so that clients and oversized messages don't use one up
+    let my_fp = require_relay(ctx, "sending backwards")?
+        .public()
+        .fingerprint();
+    check_message_size(src_dock, &content)?;
     let reply_block = ctx
         .get(ANON_DESTS)
         .lock()
         .pop(&dst)
+        .or_else(|| surb_bundle::pop_imported(ctx, &dst))
         .context(format!("no reply block for destination: {dst}"))?;
     let message = Message {
         relay_dock: src_dock,
@@ -172,32 +269,200 @@ pub async fn send_backward(
     let packet = RawPacket::new_reply(
         &reply_block,
         InnerPacket::Message(message.clone()),
-        &RemoteId::Relay(
-            ctx.get(MY_RELAY_IDENTITY)
-                .expect("only relays have global identities")
-                .public()
-                .fingerprint(),
-        ),
+        &RemoteId::Relay(my_fp),
     )?;
 
     send_raw(ctx, packet, reply_block.first_peeler).await?;
     Ok(())
 }
 
+/// Refuses messages too big for a single packet, with an error that says how big they may be.
+fn check_message_size(dock: Dock, content: &[u8]) -> anyhow::Result<()> {
+    let max = limits::max_single_message(dock);
+    if content.len() > max {
+        anyhow::bail!(
+            "message of {} bytes does not fit in one packet, which carries at most {max} bytes",
+            content.len()
+        );
+    }
+    Ok(())
+}
+
+/// Like [forward_route_to], but if `forward_route_retries` is on, checks the route before it's used. A route with a hop we have no fresh onion key for, or whose first hop we can't reach, is swapped for one that avoids the failed hops, up to `forward_route_retries` times.
+async fn usable_forward_route(
+    ctx: &DaemonContext,
+    dest_fp: RelayFingerprint,
+    circuit: CircuitToken,
+    class: MessageClass,
+    rng: &mut (impl Rng + Send),
+) -> anyhow::Result<Vec<RelayFingerprint>> {
+    let mut route = forward_route_to(ctx, dest_fp, circuit, class, rng)?;
+    let retries = ctx.init().forward_route_retries;
+    if retries == 0 {
+        return Ok(route);
+    }
+    let mut avoid = HashSet::new();
+    for retry in 0..=retries {
+        let failed: Vec<RelayFingerprint> = match validate_route(ctx, &route) {
+            Err(invalid) => invalid.problems.into_iter().map(|(hop, _)| hop).collect(),
+            Ok(()) => match check_reachable(ctx, route[0]).await {
+                Ok(()) => return Ok(route),
+                Err(err) => {
+                    tracing::debug!(
+                        first_hop = display(route[0]),
+                        err = debug(err),
+                        "first hop of forward route unreachable"
+                    );
+                    vec![route[0]]
+                }
+            },
+        };
+        if retry == retries {
+            break;
+        }
+        // there's no going around the destination itself
+        avoid.extend(failed.into_iter().filter(|hop| *hop != dest_fp));
+        let Some(mut hops) = circuit::reroute_forward(ctx, circuit, class, &avoid) else {
+            break;
+        };
+        hops.push(dest_fp);
+        ctx.get(STATS).incr(FORWARD_REROUTED);
+        tracing::debug!(
+            retry = retry + 1,
+            route = debug(&hops),
+            "retrying along another forward route"
+        );
+        route = hops;
+    }
+    anyhow::bail!("no usable forward route to {dest_fp}, even avoiding {avoid:?}")
+}
+
+/// Forms a forward route to `dest_fp` on the circuit, preferring one the circuit learned works unless `rng` decides to explore.
 fn forward_route_to(
     ctx: &DaemonContext,
     dest_fp: RelayFingerprint,
+    circuit: CircuitToken,
+    class: MessageClass,
+    rng: &mut impl Rng,
 ) -> anyhow::Result<Vec<RelayFingerprint>> {
-    let mut route = ctx.get(RELAY_GRAPH).read().rand_relays(2);
+    let explore = route_memory::explores(ctx, rng);
+    Ok(circuit_route(ctx, dest_fp, circuit, class, explore))
+}
+
+/// Forms the forward route to `dest_fp` the circuit uses for the class: one it learned works, unless `explore`, or else its own hops.
+fn circuit_route(
+    ctx: &DaemonContext,
+    dest_fp: RelayFingerprint,
+    circuit: CircuitToken,
+    class: MessageClass,
+    explore: bool,
+) -> Vec<RelayFingerprint> {
+    // learned routes are only used if they don't break circuit isolation, enter anywhere but our guards, or are the wrong length for the class
+    let guards = guards::current_guards(ctx);
+    let routes = class::class_routes(ctx, class);
+    let learned = if explore {
+        None
+    } else {
+        route_memory::learned_route(ctx, circuit, dest_fp, |route| {
+            (routes.min_hops..=routes.max_hops).contains(&route.len().saturating_sub(1))
+                && route.first().is_some_and(|first| {
+                    circuit::first_hop_free(ctx, circuit, *first)
+                        && circuit::may_enter_at(guards.as_deref(), *first)
+                })
+        })
+    };
+    if let Some(route) = learned {
+        tracing::trace!("using learned forward route: {:?}", route);
+        return route;
+    }
+    let mut route = circuit::forward_hops(ctx, circuit, class);
     route.push(dest_fp);
     tracing::trace!("forward route formed: {:?}", route);
-    Ok(route)
+    route
+}
+
+/// How many relays, counting the destination, the next send to `dest_fp` on the circuit passes through, unless it happens to explore a fresh route. Forms the route the same way sending does, so learned routes and guards count. Returns `None` if we can't route to the destination at all.
+pub fn forward_route_len(
+    ctx: &DaemonContext,
+    dest_fp: RelayFingerprint,
+    circuit: CircuitToken,
+    class: MessageClass,
+) -> Option<usize> {
+    ctx.get(RELAY_GRAPH).read().identity(&dest_fp)?;
+    Some(circuit_route(ctx, dest_fp, circuit, class, false).len())
+}
+
+/// How many relays, counting the destination, a forward route to `dest_fp` for a normal message on a circuit that never sent anything passes through at most. Such circuits learned no routes yet, and how many hops they pick is random within the class's range, so this is as close as we can get without a circuit. Returns `None` if we can't route to the destination at all.
+pub fn fresh_route_max_len(ctx: &DaemonContext, dest_fp: RelayFingerprint) -> Option<usize> {
+    let graph = ctx.get(RELAY_GRAPH).read();
+    graph.identity(&dest_fp)?;
+    let usable = graph
+        .all_nodes()
+        .filter(|fp| graph.identity(fp).is_some())
+        .count();
+    let max_hops = class::class_routes(ctx, MessageClass::Normal).max_hops;
+    Some(usable.min(max_hops) + 1)
+}
+
+/// Returns the routes we learned to each destination, with their current scores.
+pub fn learned_routes(ctx: &DaemonContext) -> BTreeMap<String, Vec<LearnedRoute>> {
+    let Some(config) = ctx.init().route_learning else {
+        return BTreeMap::new();
+    };
+    ctx.get(ROUTE_MEMORY)
+        .lock()
+        .dump(ledger::unix_now(), config.score_half_life_secs)
+        .into_iter()
+        .map(|((circuit, dest), routes)| (format!("{dest} on circuit {circuit}"), routes))
+        .collect()
+}
+
+/// The relay graph has no route from here to the destination, such as when it's in a part of the graph we aren't connected to.
+#[derive(Error, Debug, Clone, Copy)]
+#[error("no route to {0}")]
+pub struct NoRoute(pub RelayFingerprint);
+
+/// Why a hop in a route cannot be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HopProblem {
+    /// We don't have the hop's identity descriptor, and so don't know its onion key.
+    MissingOnionKey,
+    /// The hop's identity descriptor is stale, so its onion key may be out of date.
+    StaleDescriptor,
+}
+
+#[derive(Error, Debug)]
+#[error("route has unusable hops: {problems:?}")]
+pub struct InvalidRoute {
+    /// Every unusable hop, in route order.
+    pub problems: Vec<(RelayFingerprint, HopProblem)>,
+}
+
+/// Checks that we have a fresh onion key for every hop in the route, reporting the hops we don't.
+pub fn validate_route(ctx: &DaemonContext, route: &[RelayFingerprint]) -> Result<(), InvalidRoute> {
+    let graph = ctx.get(RELAY_GRAPH).read();
+    let problems: Vec<_> = route
+        .iter()
+        .filter_map(|hop| match graph.identity(hop) {
+            None => Some((*hop, HopProblem::MissingOnionKey)),
+            Some(descr) if descr.is_stale() => Some((*hop, HopProblem::StaleDescriptor)),
+            Some(_) => None,
+        })
+        .collect();
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(InvalidRoute { problems })
+    }
 }
 
 fn route_to_instructs(
     ctx: &DaemonContext,
     route: &[RelayFingerprint],
 ) -> anyhow::Result<Vec<ForwardInstruction>> {
+    validate_route(ctx, route)?;
+    // keep the relays we are routing through from being evicted
+    ctx.get(RELAY_GRAPH).read().mark_used(route.iter().copied());
     route
         .windows(2)
         .map(|wind| {
@@ -217,3 +482,287 @@ fn route_to_instructs(
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::{crypt::DhSecret, PeeledPacket, RAW_BODY_SIZE};
+    use earendil_topology::{IdentityDescriptor, DESCRIPTOR_STALE_AFTER};
+
+    use super::*;
+    use crate::test_util::{adjacency, known_relay};
+
+    #[test]
+    fn unreachable_first_hop_is_routed_around() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "route_learning": { "exploration_ratio": 0.0 },
+                "forward_route_retries": 2,
+            }))
+            .unwrap(),
+        );
+        // our only neighbor is `a`, and nothing connects to `c`
+        let [a, b, c, dest] = [(); 4].map(|_| known_relay(&ctx));
+        let [a_fp, b_fp, c_fp, dest_fp] = [a, b, c, dest].map(|id| id.public().fingerprint());
+        {
+            let mut graph = ctx.get(RELAY_GRAPH).write();
+            for (x, y) in [(a, b), (a, dest), (b, dest)] {
+                graph
+                    .insert_adjacency(adjacency(&x, &y, ledger::unix_now()))
+                    .unwrap();
+            }
+        }
+        let _link = crate::network::subscribe_outgoing_relay(&ctx, a_fp);
+        // a route through `c` once worked, so it's the one we'd pick first
+        let circuit = CircuitToken::new();
+        ctx.get(ROUTE_MEMORY).lock().record(
+            circuit,
+            dest_fp,
+            &[c_fp, b_fp, dest_fp],
+            true,
+            ledger::unix_now(),
+            3600,
+        );
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            forward_route_to(&ctx, dest_fp, circuit, MessageClass::Normal, &mut rng).unwrap(),
+            vec![c_fp, b_fp, dest_fp]
+        );
+
+        smol::future::block_on(async {
+            assert!(check_reachable(&ctx, c_fp).await.is_err());
+            let route =
+                usable_forward_route(&ctx, dest_fp, circuit, MessageClass::Normal, &mut rng)
+                    .await
+                    .unwrap();
+            assert!(!route.contains(&c_fp), "{route:?}");
+            assert_eq!(route.last(), Some(&dest_fp));
+            assert!(check_reachable(&ctx, route[0]).await.is_ok());
+        });
+        assert_eq!(ctx.get(STATS).snapshot().get(FORWARD_REROUTED), Some(&1));
+    }
+
+    #[test]
+    fn route_len_is_that_of_the_route_we_send_on() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "route_learning": { "exploration_ratio": 0.0 },
+                "privacy": { "message_classes": {
+                    "normal": { "min_hops": 1, "max_hops": 2 },
+                } },
+            }))
+            .unwrap(),
+        );
+        let [a_fp, b_fp, _, dest_fp] = [(); 4].map(|_| known_relay(&ctx).public().fingerprint());
+        let mut rng = StdRng::seed_from_u64(1962);
+        let mut sent_on = |circuit| {
+            forward_route_to(&ctx, dest_fp, circuit, MessageClass::Normal, &mut rng)
+                .unwrap()
+                .len()
+        };
+
+        // a learned route is what gets used, however many hops a fresh one would have
+        let learned = CircuitToken::new();
+        ctx.get(ROUTE_MEMORY).lock().record(
+            learned,
+            dest_fp,
+            &[a_fp, b_fp, dest_fp],
+            true,
+            ledger::unix_now(),
+            3600,
+        );
+        let len = forward_route_len(&ctx, dest_fp, learned, MessageClass::Normal);
+        assert_eq!(len, Some(3));
+        assert_eq!(len, Some(sent_on(learned)));
+
+        // and otherwise, the circuit's own hops
+        let fresh = CircuitToken::new();
+        let len = forward_route_len(&ctx, dest_fp, fresh, MessageClass::Normal);
+        assert_eq!(len, Some(sent_on(fresh)));
+
+        let unknown = RelayIdentitySecret::generate().public().fingerprint();
+        assert_eq!(
+            forward_route_len(&ctx, unknown, fresh, MessageClass::Normal),
+            None
+        );
+    }
+
+    #[test]
+    fn learned_routes_win_over_fresh_ones_that_fail() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "route_learning": { "exploration_ratio": 0.1 },
+                "privacy": { "message_classes": {
+                    "normal": { "min_hops": 1, "max_hops": 1 },
+                } },
+            }))
+            .unwrap(),
+        );
+        let [a_fp, b_fp, dest_fp] = [(); 3].map(|_| known_relay(&ctx).public().fingerprint());
+        let circuit = CircuitToken::new();
+        let mut rng = StdRng::seed_from_u64(1949);
+        let mut route = || {
+            smol::future::block_on(usable_forward_route(
+                &ctx,
+                dest_fp,
+                circuit,
+                MessageClass::Normal,
+                &mut rng,
+            ))
+            .unwrap()
+        };
+
+        // the circuit's fresh route drops everything, while another one was seen to work once
+        let dropping = route();
+        let working = vec![if dropping[0] == a_fp { b_fp } else { a_fp }, dest_fp];
+        let record = |route: &[RelayFingerprint], success| {
+            ctx.get(ROUTE_MEMORY).lock().record(
+                circuit,
+                dest_fp,
+                route,
+                success,
+                ledger::unix_now(),
+                3600,
+            )
+        };
+        record(&dropping, false);
+        record(&working, true);
+
+        let mut picked_working = 0;
+        for _ in 0..200 {
+            let route = route();
+            let success = route == working;
+            assert!(success || route == dropping, "{route:?}");
+            record(&route, success);
+            if success {
+                picked_working += 1;
+            }
+        }
+        // everything but the 10% spent exploring
+        assert!(
+            picked_working > 170,
+            "picked the working path {picked_working}/200 times"
+        );
+        // other circuits learned nothing from it
+        assert!(ctx
+            .get(ROUTE_MEMORY)
+            .lock()
+            .best(
+                CircuitToken::new(),
+                dest_fp,
+                ledger::unix_now(),
+                3600,
+                |_| true
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn relays_hold_messages_as_long_as_their_class_says() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "privacy": { "message_classes": {
+                    "interactive": { "mean_delay_ms": 0 },
+                    "background": { "mean_delay_ms": 5000 },
+                } },
+            }))
+            .unwrap(),
+        );
+        let (hop, hop_osk) = (RelayIdentitySecret::generate(), DhSecret::generate());
+        ctx.get(RELAY_GRAPH)
+            .write()
+            .insert_identity(IdentityDescriptor::new(&hop, &hop_osk))
+            .unwrap();
+        let dest = known_relay(&ctx);
+        let route = [hop.public().fingerprint(), dest.public().fingerprint()];
+        let mean_delay = |class| {
+            let total: u64 = (0..50)
+                .map(|_| {
+                    let packet = forward_packet(
+                        &ctx,
+                        &route,
+                        InnerPacket::Message(Message::new(0, Bytes::new())),
+                        AnonEndpoint::random(),
+                        class,
+                    )
+                    .unwrap();
+                    match packet.peel(&hop_osk).unwrap() {
+                        PeeledPacket::Relay { delay_ms, .. } => delay_ms as u64,
+                        other => panic!("expected a packet to relay, got {other:?}"),
+                    }
+                })
+                .sum();
+            total / 50
+        };
+
+        assert_eq!(mean_delay(MessageClass::Interactive), 0);
+        let normal = mean_delay(MessageClass::Normal);
+        assert!(normal < 100, "{normal}");
+        let background = mean_delay(MessageClass::Background);
+        assert!(background > 1000, "{background}");
+    }
+
+    #[test]
+    fn validate_route_pinpoints_missing_onion_key() {
+        let ctx = DaemonContext::new(serde_json::from_str("{}").unwrap());
+        let known: Vec<RelayFingerprint> = (0..3)
+            .map(|_| known_relay(&ctx).public().fingerprint())
+            .collect();
+        let unknown = RelayIdentitySecret::generate().public().fingerprint();
+
+        assert!(validate_route(&ctx, &known).is_ok());
+        let route = [known[0], unknown, known[1], known[2]];
+        let err = validate_route(&ctx, &route).unwrap_err();
+        assert_eq!(err.problems, vec![(unknown, HopProblem::MissingOnionKey)]);
+    }
+
+    #[test]
+    fn validate_route_pinpoints_stale_descriptor() {
+        let ctx = DaemonContext::new(serde_json::from_str("{}").unwrap());
+        let fresh = known_relay(&ctx).public().fingerprint();
+        // old enough to be stale, but not so old that the graph drops it
+        let identity = RelayIdentitySecret::generate();
+        let mut descr = IdentityDescriptor::new(&identity, &DhSecret::generate());
+        descr.unix_timestamp -= DESCRIPTOR_STALE_AFTER + 60;
+        descr.sig = identity.sign(descr.to_sign().as_bytes());
+        ctx.get(RELAY_GRAPH).write().insert_identity(descr).unwrap();
+        let stale = identity.public().fingerprint();
+        assert!(ctx.get(RELAY_GRAPH).read().identity(&stale).is_some());
+
+        let err = validate_route(&ctx, &[fresh, stale]).unwrap_err();
+        assert_eq!(err.problems, vec![(stale, HopProblem::StaleDescriptor)]);
+    }
+
+    #[test]
+    fn missing_degarbler_is_counted() {
+        let ctx = DaemonContext::new(serde_json::from_str("{}").unwrap());
+        let reply: RawBody = [0; RAW_BODY_SIZE];
+        assert!(degarble_backward(&ctx, reply, rand::random()).is_err());
+        let stats = ctx.get(STATS).snapshot();
+        assert_eq!(stats.get(DEGARBLE_NO_DEGARBLER), Some(&1));
+        assert_eq!(stats.get(DEGARBLE_SUCCESS), None);
+    }
+
+    #[test]
+    fn only_relays_send_backwards() {
+        let client = DaemonContext::new(serde_json::from_str("{}").unwrap());
+        let relay = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({"identity_seed": "backwards"})).unwrap(),
+        );
+        assert!(crate::context::is_client(&client));
+        assert!(crate::context::is_relay(&relay));
+
+        smol::future::block_on(async {
+            let dst = AnonEndpoint::random();
+            let err = send_backward(&client, 1, dst, Bytes::from_static(b"hi"))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("only works on relays"), "{err}");
+            // relays get past the check, and only fail for lack of a reply block
+            let err = send_backward(&relay, 1, dst, Bytes::from_static(b"hi"))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("no reply block"), "{err}");
+        });
+    }
+}
@@ -0,0 +1,400 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    time::Duration,
+};
+
+use earendil_crypt::{AnonEndpoint, RelayFingerprint};
+use moka::sync::Cache;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::context::{CtxField, DaemonContext, RELAY_GRAPH};
+
+use super::{
+    class::{class_routes, MessageClass},
+    guards,
+};
+
+/// How many relays come before the SURB anchor in every reply route. Forward routes are as long as their [MessageClass] says.
+pub(super) const CIRCUIT_HOPS: usize = 2;
+
+/// How long a circuit's routes are reused, after which it picks fresh ones.
+pub(super) const CIRCUIT_LIFETIME: Duration = Duration::from_secs(600);
+
+/// How many circuits we remember routes for. Every app flow may bring its own token, and picking hops for a new circuit looks at all the others, so this must be bounded. The least recently used circuits pick fresh routes once they're back.
+pub(super) const MAX_CIRCUITS: u64 = 1024;
+
+/// Controls which sends may share a path through the network.
+///
+/// Sends with the same token may reuse the same cached routes, while sends with different tokens always use independently selected routes, whose first hops differ whenever the relay graph is big enough. Reply blocks are pooled per [AnonEndpoint], so using a different token per endpoint also gives independent reply-block pools.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct CircuitToken(u64);
+
+impl Display for CircuitToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl CircuitToken {
+    /// Creates a token that shares no routes with any other.
+    pub fn new() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl Default for CircuitToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The relays before the destination, for forward routes of each class and for reply routes.
+#[derive(Clone)]
+struct CircuitHops {
+    /// Forward hops, picked for each class of messages once the circuit sends one, and kept until the circuit is gone, so that switching between classes doesn't pick fresh hops every time.
+    forward: HashMap<MessageClass, Vec<RelayFingerprint>>,
+    reply: Vec<RelayFingerprint>,
+    /// The class of messages the circuit last picked forward hops for.
+    last_class: MessageClass,
+}
+
+impl CircuitHops {
+    fn first_hops(&self) -> impl Iterator<Item = RelayFingerprint> + '_ {
+        self.forward
+            .values()
+            .chain(std::iter::once(&self.reply))
+            .filter_map(|hops| hops.first().copied())
+    }
+}
+
+static CIRCUITS: CtxField<Cache<CircuitToken, CircuitHops>> = |_| {
+    Cache::builder()
+        .max_capacity(MAX_CIRCUITS)
+        .time_to_live(CIRCUIT_LIFETIME)
+        .build()
+};
+
+/// The circuit each anonymous endpoint last sent on, so that reply blocks replenished upon receiving a reply use the same circuit as the send that caused it.
+static ENDPOINT_CIRCUITS: CtxField<Cache<AnonEndpoint, CircuitToken>> = |_| {
+    Cache::builder()
+        .max_capacity(MAX_CIRCUITS)
+        .time_to_live(CIRCUIT_LIFETIME)
+        .build()
+};
+
+/// Remembers that `endpoint` is using the given circuit.
+pub(super) fn remember_circuit(ctx: &DaemonContext, endpoint: AnonEndpoint, circuit: CircuitToken) {
+    ctx.get(ENDPOINT_CIRCUITS).insert(endpoint, circuit);
+}
+
+/// Returns the circuit `endpoint` last used, or a fresh one if it never sent anything.
+pub(super) fn circuit_of(ctx: &DaemonContext, endpoint: AnonEndpoint) -> CircuitToken {
+    ctx.get(ENDPOINT_CIRCUITS)
+        .get_with(endpoint, CircuitToken::new)
+}
+
+/// Returns the relays that forward routes for messages of the given class on this circuit pass through before the destination.
+pub(super) fn forward_hops(
+    ctx: &DaemonContext,
+    circuit: CircuitToken,
+    class: MessageClass,
+) -> Vec<RelayFingerprint> {
+    circuit_hops(ctx, circuit, Some(class)).forward[&class].clone()
+}
+
+/// Returns the class of messages this circuit last picked forward hops for.
+pub(super) fn circuit_class(ctx: &DaemonContext, circuit: CircuitToken) -> MessageClass {
+    circuit_hops(ctx, circuit, None).last_class
+}
+
+/// Returns the relays that reply routes on this circuit pass through before the SURB anchor.
+pub(super) fn reply_hops(ctx: &DaemonContext, circuit: CircuitToken) -> Vec<RelayFingerprint> {
+    circuit_hops(ctx, circuit, None).reply
+}
+
+/// Returns whether `hop` isn't the first hop of any other circuit, and so can be the first hop of this one.
+pub(super) fn first_hop_free(
+    ctx: &DaemonContext,
+    circuit: CircuitToken,
+    hop: RelayFingerprint,
+) -> bool {
+    !ctx.get(CIRCUITS)
+        .iter()
+        .filter(|(token, _)| **token != circuit)
+        .any(|(_, hops)| hops.first_hops().any(|first| first == hop))
+}
+
+/// Returns whether a forward route may start at `hop`, which is any relay unless entry guards are on.
+pub(super) fn may_enter_at(guards: Option<&[RelayFingerprint]>, hop: RelayFingerprint) -> bool {
+    guards.map_or(true, |guards| guards.contains(&hop))
+}
+
+/// Returns the circuit's hops, picking new ones if they're gone or stale. If `class` is given, the forward hops for it are picked too, unless the circuit already has some.
+fn circuit_hops(
+    ctx: &DaemonContext,
+    circuit: CircuitToken,
+    class: Option<MessageClass>,
+) -> CircuitHops {
+    let circuits = ctx.get(CIRCUITS);
+    let guards = guards::current_guards(ctx);
+    let cached = circuits.get(&circuit).filter(|hops| {
+        // relays may have left the graph since we picked them, and guards may have rotated
+        let graph = ctx.get(RELAY_GRAPH).read();
+        hops.forward
+            .values()
+            .flatten()
+            .chain(hops.reply.iter())
+            .all(|hop| graph.identity(hop).is_some())
+            && hops
+                .forward
+                .values()
+                .filter_map(|forward| forward.first())
+                .all(|first| may_enter_at(guards.as_deref(), *first))
+    });
+    let (mut hops, mut changed) = match cached {
+        Some(hops) => (hops, false),
+        None => {
+            let taken = taken_first_hops(ctx, circuit);
+            let hops = CircuitHops {
+                forward: HashMap::new(),
+                reply: pick_hops(ctx, &taken, None, &HashSet::new(), CIRCUIT_HOPS),
+                last_class: MessageClass::default(),
+            };
+            (hops, true)
+        }
+    };
+    if let Some(class) = class {
+        if !hops.forward.contains_key(&class) {
+            let taken = taken_first_hops(ctx, circuit);
+            let forward = pick_forward_hops(ctx, &taken, guards.as_deref(), class, &HashSet::new());
+            hops.forward.insert(class, forward);
+            changed = true;
+        }
+        changed |= hops.last_class != class;
+        hops.last_class = class;
+    }
+    if changed {
+        circuits.insert(circuit, hops.clone());
+    }
+    hops
+}
+
+/// Picks new forward hops for the circuit that avoid the given relays, for when its route turned out to be unusable. Later sends on the circuit use them too. Returns None if there's no way into the network that avoids them.
+pub(super) fn reroute_forward(
+    ctx: &DaemonContext,
+    circuit: CircuitToken,
+    class: MessageClass,
+    avoid: &HashSet<RelayFingerprint>,
+) -> Option<Vec<RelayFingerprint>> {
+    let guards = guards::current_guards(ctx);
+    let taken = taken_first_hops(ctx, circuit);
+    let forward = pick_forward_hops(ctx, &taken, guards.as_deref(), class, avoid);
+    let first = *forward.first()?;
+    if !may_enter_at(guards.as_deref(), first) {
+        return None;
+    }
+    let circuits = ctx.get(CIRCUITS);
+    let mut hops = circuits.get(&circuit).unwrap_or_else(|| CircuitHops {
+        forward: HashMap::new(),
+        reply: pick_hops(ctx, &taken, None, &HashSet::new(), CIRCUIT_HOPS),
+        last_class: class,
+    });
+    hops.forward.insert(class, forward.clone());
+    hops.last_class = class;
+    circuits.insert(circuit, hops);
+    Some(forward)
+}
+
+/// The first hops of every other circuit. They see all of their circuit's traffic, so we avoid them where possible.
+fn taken_first_hops(ctx: &DaemonContext, circuit: CircuitToken) -> HashSet<RelayFingerprint> {
+    ctx.get(CIRCUITS)
+        .iter()
+        .filter(|(token, _)| **token != circuit)
+        .flat_map(|(_, hops)| hops.first_hops().collect::<Vec<_>>())
+        .collect()
+}
+
+/// Like [pick_hops], but as many as the class's routes are configured to pass through.
+fn pick_forward_hops(
+    ctx: &DaemonContext,
+    taken: &HashSet<RelayFingerprint>,
+    guards: Option<&[RelayFingerprint]>,
+    class: MessageClass,
+    avoid: &HashSet<RelayFingerprint>,
+) -> Vec<RelayFingerprint> {
+    let routes = class_routes(ctx, class);
+    let count = rand::thread_rng().gen_range(routes.min_hops..=routes.max_hops);
+    pick_hops(ctx, taken, guards, avoid, count)
+}
+
+/// Picks `count` random relays for a route, none of them in `avoid`, with a first hop outside of `taken` unless every known relay is taken. If `guards` is given, the first hop is always one of them.
+fn pick_hops(
+    ctx: &DaemonContext,
+    taken: &HashSet<RelayFingerprint>,
+    guards: Option<&[RelayFingerprint]>,
+    avoid: &HashSet<RelayFingerprint>,
+    count: usize,
+) -> Vec<RelayFingerprint> {
+    let graph = ctx.get(RELAY_GRAPH).read();
+    let mut relays: Vec<RelayFingerprint> = graph
+        .all_nodes()
+        .filter(|fp| graph.identity(fp).is_some() && !avoid.contains(fp))
+        .collect();
+    relays.shuffle(&mut rand::thread_rng());
+    // the sort is stable, so untaken relays come first but stay shuffled. overloaded relays are avoided, but only if there are others
+    relays.sort_by_key(|fp| {
+        (
+            taken.contains(fp),
+            graph.identity(fp).is_some_and(|id| id.overloaded()),
+        )
+    });
+    // guards come first even when taken, since entering anywhere else is what they're there to prevent
+    if let Some(entry) = relays.iter().position(|fp| may_enter_at(guards, *fp)) {
+        let entry = relays.remove(entry);
+        relays.insert(0, entry);
+    }
+    relays.truncate(count);
+    relays
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::IdentityDescriptor;
+
+    use super::*;
+
+    #[test]
+    fn different_tokens_use_disjoint_first_hops() {
+        let ctx = DaemonContext::new(serde_json::from_str("{}").unwrap());
+        for _ in 0..6 {
+            ctx.get(RELAY_GRAPH)
+                .write()
+                .insert_identity(IdentityDescriptor::new(
+                    &RelayIdentitySecret::generate(),
+                    &DhSecret::generate(),
+                ))
+                .unwrap();
+        }
+
+        let (a, b) = (CircuitToken::new(), CircuitToken::new());
+        let a_hops = forward_hops(&ctx, a, MessageClass::Normal);
+        let b_hops = forward_hops(&ctx, b, MessageClass::Normal);
+        assert_eq!(a_hops.len(), CIRCUIT_HOPS);
+        assert_ne!(a_hops[0], b_hops[0]);
+        assert_ne!(a_hops[0], reply_hops(&ctx, b)[0]);
+        assert_ne!(reply_hops(&ctx, a)[0], b_hops[0]);
+
+        // the same token reuses its route
+        assert_eq!(forward_hops(&ctx, a, MessageClass::Normal), a_hops);
+        assert_eq!(forward_hops(&ctx, b, MessageClass::Normal), b_hops);
+    }
+
+    #[test]
+    fn forward_routes_are_as_long_as_their_class_says() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "privacy": { "message_classes": {
+                    "interactive": { "min_hops": 3, "max_hops": 3 },
+                    "background": { "min_hops": 4, "max_hops": 5 },
+                } },
+            }))
+            .unwrap(),
+        );
+        for _ in 0..12 {
+            ctx.get(RELAY_GRAPH)
+                .write()
+                .insert_identity(IdentityDescriptor::new(
+                    &RelayIdentitySecret::generate(),
+                    &DhSecret::generate(),
+                ))
+                .unwrap();
+        }
+
+        let circuit = CircuitToken::new();
+        let reply = reply_hops(&ctx, circuit);
+        let interactive = forward_hops(&ctx, circuit, MessageClass::Interactive);
+        assert_eq!(interactive.len(), 3);
+        assert_eq!(
+            forward_hops(&ctx, circuit, MessageClass::Normal).len(),
+            CIRCUIT_HOPS
+        );
+        let background = forward_hops(&ctx, circuit, MessageClass::Background);
+        assert!((4..=5).contains(&background.len()), "{background:?}");
+        assert_eq!(circuit_class(&ctx, circuit), MessageClass::Background);
+        // the same class keeps its hops, and reply routes don't depend on the class at all
+        assert_eq!(
+            forward_hops(&ctx, circuit, MessageClass::Background),
+            background
+        );
+        assert_eq!(reply_hops(&ctx, circuit), reply);
+        assert_eq!(reply.len(), CIRCUIT_HOPS);
+        // and going back to an earlier class goes back to its hops, rather than picking fresh ones
+        assert_eq!(
+            forward_hops(&ctx, circuit, MessageClass::Interactive),
+            interactive
+        );
+        assert_eq!(circuit_class(&ctx, circuit), MessageClass::Interactive);
+    }
+
+    #[test]
+    fn circuits_are_bounded() {
+        let ctx = DaemonContext::new(serde_json::from_str("{}").unwrap());
+        for _ in 0..6 {
+            ctx.get(RELAY_GRAPH)
+                .write()
+                .insert_identity(IdentityDescriptor::new(
+                    &RelayIdentitySecret::generate(),
+                    &DhSecret::generate(),
+                ))
+                .unwrap();
+        }
+
+        for _ in 0..MAX_CIRCUITS * 2 {
+            let circuit = CircuitToken::new();
+            forward_hops(&ctx, circuit, MessageClass::Normal);
+            remember_circuit(&ctx, AnonEndpoint::random(), circuit);
+        }
+        let circuits = ctx.get(CIRCUITS);
+        circuits.run_pending_tasks();
+        assert!(circuits.entry_count() <= MAX_CIRCUITS);
+        let endpoints = ctx.get(ENDPOINT_CIRCUITS);
+        endpoints.run_pending_tasks();
+        assert!(endpoints.entry_count() <= MAX_CIRCUITS);
+    }
+
+    #[test]
+    fn first_hops_are_entry_guards() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "privacy": { "entry_guards": { "count": 2 } }
+            }))
+            .unwrap(),
+        );
+        for _ in 0..10 {
+            ctx.get(RELAY_GRAPH)
+                .write()
+                .insert_identity(IdentityDescriptor::new(
+                    &RelayIdentitySecret::generate(),
+                    &DhSecret::generate(),
+                ))
+                .unwrap();
+        }
+
+        let mut first_hops = HashSet::new();
+        for _ in 0..100 {
+            let hops = forward_hops(&ctx, CircuitToken::new(), MessageClass::Normal);
+            assert_eq!(hops.len(), CIRCUIT_HOPS);
+            first_hops.insert(hops[0]);
+        }
+        let guards: HashSet<RelayFingerprint> = guards::entry_guards(&ctx)
+            .into_iter()
+            .map(|guard| guard.relay)
+            .collect();
+        assert_eq!(guards.len(), 2);
+        assert!(first_hops.is_subset(&guards));
+    }
+}
@@ -0,0 +1,48 @@
+use std::fmt::{self, Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ClassRouteConfig, context::DaemonContext};
+
+/// The fewest relays any forward route passes through before the destination, whatever its class is configured to. With a single relay, that relay would see both who sent a message and where it goes.
+pub const MIN_ROUTE_HOPS: usize = 2;
+
+/// The most relays a forward route can pass through before the destination, since packets have room for only so many layers.
+pub const MAX_ROUTE_HOPS: usize = earendil_packet::MAX_HOPS - 2;
+
+/// How much latency a message can tolerate, which decides how long its route is and how long relays hold it. Latency-tolerant traffic can afford longer, slower routes, which are harder to trace.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageClass {
+    /// Someone is waiting on it, such as a chat or a haven connection.
+    Interactive,
+    #[default]
+    Normal,
+    /// Maintenance nobody is waiting on, such as DHT replication and haven registration.
+    Background,
+}
+
+impl MessageClass {
+    pub const ALL: [MessageClass; 3] = [
+        MessageClass::Interactive,
+        MessageClass::Normal,
+        MessageClass::Background,
+    ];
+}
+
+impl Display for MessageClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MessageClass::Interactive => "interactive",
+            MessageClass::Normal => "normal",
+            MessageClass::Background => "background",
+        })
+    }
+}
+
+/// How forward routes for messages of the given class are built.
+pub(super) fn class_routes(ctx: &DaemonContext, class: MessageClass) -> ClassRouteConfig {
+    ctx.init().privacy.message_classes.get(class)
+}
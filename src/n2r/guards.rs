@@ -0,0 +1,152 @@
+use earendil_crypt::RelayFingerprint;
+use earendil_topology::RelayGraph;
+use parking_lot::Mutex;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::EntryGuardConfig,
+    context::{CtxField, DaemonContext, RELAY_GRAPH},
+    db::{db_read, MiscKey},
+    ledger::unix_now,
+    snapshot::take_restored,
+};
+
+/// Our entry guards, persisted in the state cache across restarts.
+pub static ENTRY_GUARDS: CtxField<Mutex<EntryGuards>> = |ctx| {
+    smol::future::block_on(async move {
+        let stored = match take_restored(ctx, |snapshot| snapshot.entry_guards.take()) {
+            Some(bytes) => Ok(Some(bytes)),
+            None => db_read(ctx, MiscKey::EntryGuards).await,
+        };
+        let guards = match stored {
+            Ok(Some(bytes)) => stdcode::deserialize(&bytes).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                tracing::warn!("error retrieving entry guards: {e}");
+                None
+            }
+        };
+        Mutex::new(guards.unwrap_or_default())
+    })
+};
+
+/// The small set of relays that every forward route starts at, so that a hostile first hop gets to see our traffic only if it happens to be one of them, rather than eventually.
+#[derive(Serialize, Deserialize, Default)]
+pub struct EntryGuards {
+    guards: Vec<EntryGuard>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryGuard {
+    pub relay: RelayFingerprint,
+    /// When we picked the guard, in seconds since the Unix epoch.
+    pub chosen_unix: u64,
+    /// When we stop using the guard and pick another.
+    pub expires_unix: u64,
+}
+
+impl EntryGuards {
+    /// Drops the guards that expired or left the relay graph, and picks new ones until there are `config.count` of them.
+    fn refresh(&mut self, graph: &RelayGraph, config: EntryGuardConfig, now_unix: u64) {
+        let before = self.guards.len();
+        self.guards.retain(|guard| {
+            guard.expires_unix > now_unix && graph.identity(&guard.relay).is_some()
+        });
+        if self.guards.len() < before {
+            tracing::debug!(
+                dropped = before - self.guards.len(),
+                "entry guards expired or left the relay graph"
+            );
+        }
+        if self.guards.len() >= config.count {
+            return;
+        }
+        let mut candidates: Vec<RelayFingerprint> = graph
+            .all_nodes()
+            .filter(|fp| {
+                graph.identity(fp).is_some() && !self.guards.iter().any(|g| g.relay == *fp)
+            })
+            .collect();
+        candidates.shuffle(&mut rand::thread_rng());
+        // the sort is stable, so overloaded relays only become guards if there are no others
+        candidates.sort_by_key(|fp| graph.identity(fp).is_some_and(|id| id.overloaded()));
+        for relay in candidates
+            .into_iter()
+            .take(config.count - self.guards.len())
+        {
+            // randomized, so that guards picked together don't all rotate together
+            let lifetime = config.lifetime_secs as f64 * rand::thread_rng().gen_range(1.0..1.5);
+            tracing::debug!(relay = display(relay), "picked a new entry guard");
+            self.guards.push(EntryGuard {
+                relay,
+                chosen_unix: now_unix,
+                expires_unix: now_unix + lifetime as u64,
+            });
+        }
+    }
+
+    fn relays(&self) -> Vec<RelayFingerprint> {
+        self.guards.iter().map(|guard| guard.relay).collect()
+    }
+}
+
+/// The relays that forward routes may start at, or None if entry guards are off. Picks new guards as old ones expire.
+pub(crate) fn current_guards(ctx: &DaemonContext) -> Option<Vec<RelayFingerprint>> {
+    let config = ctx.init().privacy.entry_guards?;
+    let graph = ctx.get(RELAY_GRAPH).read();
+    let mut guards = ctx.get(ENTRY_GUARDS).lock();
+    guards.refresh(&graph, config, unix_now());
+    Some(guards.relays())
+}
+
+/// Our entry guards, which are none unless entry guards are on.
+pub fn entry_guards(ctx: &DaemonContext) -> Vec<EntryGuard> {
+    if ctx.init().privacy.entry_guards.is_none() {
+        return vec![];
+    }
+    ctx.get(ENTRY_GUARDS).lock().guards.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::IdentityDescriptor;
+
+    use super::*;
+
+    #[test]
+    fn guards_rotate_slowly() {
+        let mut graph = RelayGraph::new();
+        for _ in 0..10 {
+            graph
+                .insert_identity(IdentityDescriptor::new(
+                    &RelayIdentitySecret::generate(),
+                    &DhSecret::generate(),
+                ))
+                .unwrap();
+        }
+        let config = EntryGuardConfig {
+            count: 3,
+            lifetime_secs: 1000,
+        };
+        let now = unix_now();
+        let mut guards = EntryGuards::default();
+        guards.refresh(&graph, config, now);
+        let first = guards.relays();
+        assert_eq!(first.len(), 3);
+
+        // nothing changes before the guards expire
+        guards.refresh(&graph, config, now + 999);
+        assert_eq!(guards.relays(), first);
+
+        // and once they have, they're all picked again
+        guards.refresh(&graph, config, now + 1500);
+        assert_eq!(guards.guards.len(), 3);
+        assert!(guards
+            .guards
+            .iter()
+            .all(|guard| guard.chosen_unix == now + 1500 && guard.expires_unix >= now + 2500));
+    }
+}
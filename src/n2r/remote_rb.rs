@@ -1,26 +1,33 @@
 use anyhow::Context;
-use earendil_crypt::{AnonEndpoint, RelayFingerprint, RemoteId};
-use earendil_packet::{InnerPacket, RawPacket, ReplyBlock};
+use earendil_crypt::{AnonEndpoint, RelayFingerprint};
+use earendil_packet::{InnerPacket, ReplyBlock, ReplyDegarbler};
 use moka::sync::Cache;
 use parking_lot::Mutex;
 use rand::prelude::*;
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
+use thiserror::Error;
 
 use crate::{
+    config::SurbAnchor,
     context::{CtxField, DaemonContext, MY_CLIENT_ID, MY_RELAY_IDENTITY, RELAY_GRAPH},
-    n2r::{forward_route_to, route_to_instructs, DEGARBLERS},
+    n2r::{
+        circuit::{self, CircuitToken},
+        forward_packet, forward_route_to, route_to_instructs, surb_routes, DEGARBLERS,
+    },
     network::{all_relay_neighs, send_raw},
 };
 
 static LAWK: Mutex<()> = Mutex::new(());
 
-/// Call to replenish remote reply blocks as needed.
+/// Call to replenish remote reply blocks as needed. Reply blocks are sent, and later travel back, along routes belonging to the given circuit.
 pub async fn replenish_remote_rb(
     ctx: &DaemonContext,
     my_anon_id: AnonEndpoint,
     dst_fp: RelayFingerprint,
+    circuit: CircuitToken,
 ) -> anyhow::Result<()> {
     const BATCH_SIZE: usize = 5;
+    circuit::remember_circuit(ctx, my_anon_id, circuit);
     let mut count = 0;
     {
         let _guard = LAWK.lock();
@@ -34,7 +41,7 @@ pub async fn replenish_remote_rb(
         }
     }
     for _ in 0..count {
-        send_reply_blocks(ctx, BATCH_SIZE, my_anon_id, dst_fp).await?;
+        send_reply_blocks(ctx, BATCH_SIZE, my_anon_id, dst_fp, circuit).await?;
     }
     Ok(())
 }
@@ -48,7 +55,8 @@ pub async fn consume_remote_rb(
     let new_balance = rb_balance(ctx, my_anon_id, reply_source);
     ctx.get(BALANCE_TABLE)
         .insert((my_anon_id, reply_source), new_balance - 1.0);
-    let _ = replenish_remote_rb(ctx, my_anon_id, reply_source).await;
+    let circuit = circuit::circuit_of(ctx, my_anon_id);
+    let _ = replenish_remote_rb(ctx, my_anon_id, reply_source, circuit).await;
 }
 
 fn rb_balance(
@@ -66,6 +74,77 @@ static BALANCE_TABLE: CtxField<Cache<(AnonEndpoint, RelayFingerprint), f64>> = |
         .build()
 };
 
+/// The anchor of each reply block we sent out and still hold the degarbler of, along with the conversation it was sent for. Reply blocks older than this keeps track of are unlikely to still be unused.
+static ANCHORED_AT: CtxField<Cache<u64, (RelayFingerprint, AnonEndpoint, RelayFingerprint)>> =
+    |_| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(3600))
+            .build()
+    };
+
+/// Remembers where the reply block with the given degarbler id is anchored, so that it can be invalidated if we lose the anchor.
+pub(super) fn remember_anchor(
+    ctx: &DaemonContext,
+    id: u64,
+    anchor: RelayFingerprint,
+    my_anon_id: AnonEndpoint,
+    dst_fp: RelayFingerprint,
+) {
+    ctx.get(ANCHORED_AT)
+        .insert(id, (anchor, my_anon_id, dst_fp));
+}
+
+/// Forgets the reply blocks we sent out that are anchored at `anchor`, since replies using them can't reach us anymore. Returns how many were forgotten, and the conversations that lost some while still active, whose balances now count only the reply blocks they have left.
+pub(super) fn invalidate_anchor(
+    ctx: &DaemonContext,
+    anchor: RelayFingerprint,
+) -> (usize, Vec<(AnonEndpoint, RelayFingerprint)>) {
+    let _guard = LAWK.lock();
+    let mut lost: HashMap<(AnonEndpoint, RelayFingerprint), usize> = HashMap::new();
+    for (id, (rb_anchor, my_anon_id, dst_fp)) in ctx.get(ANCHORED_AT).iter() {
+        if rb_anchor != anchor {
+            continue;
+        }
+        ctx.get(ANCHORED_AT).invalidate(&*id);
+        if ctx.get(DEGARBLERS).remove(&*id).is_some() {
+            *lost.entry((my_anon_id, dst_fp)).or_default() += 1;
+        }
+    }
+    let invalidated = lost.values().sum();
+    let active = lost
+        .into_iter()
+        .filter_map(|(conversation, count)| {
+            let balance = ctx.get(BALANCE_TABLE).get(&conversation)?;
+            // like when replenishing, we assume half of them got there
+            ctx.get(BALANCE_TABLE)
+                .insert(conversation, (balance - count as f64 / 2.0).max(0.0));
+            Some(conversation)
+        })
+        .collect();
+    (invalidated, active)
+}
+
+/// Forgets the reply blocks we sent `dst_fp` for `my_anon_id`, for when we gave up on the conversation and no replies using them are wanted anymore. The balance goes with them, so talking again starts with a fresh batch. Returns how many were forgotten.
+pub fn forget_reply_blocks(
+    ctx: &DaemonContext,
+    my_anon_id: AnonEndpoint,
+    dst_fp: RelayFingerprint,
+) -> usize {
+    let _guard = LAWK.lock();
+    let mut forgotten = 0;
+    for (id, (_, rb_anon_id, rb_dst_fp)) in ctx.get(ANCHORED_AT).iter() {
+        if (rb_anon_id, rb_dst_fp) != (my_anon_id, dst_fp) {
+            continue;
+        }
+        ctx.get(ANCHORED_AT).invalidate(&*id);
+        if ctx.get(DEGARBLERS).remove(&*id).is_some() {
+            forgotten += 1;
+        }
+    }
+    ctx.get(BALANCE_TABLE).invalidate(&(my_anon_id, dst_fp));
+    forgotten
+}
+
 #[tracing::instrument(skip(ctx))]
 /// Send a batch of reply blocks to the given N2R destination.
 async fn send_reply_blocks(
@@ -73,50 +152,34 @@ async fn send_reply_blocks(
     count: usize,
     my_anon_id: AnonEndpoint,
     dst_fp: RelayFingerprint,
+    circuit: CircuitToken,
 ) -> anyhow::Result<()> {
     tracing::trace!("sending a batch of {count} reply blocks for {my_anon_id} to {dst_fp}");
 
-    let route = forward_route_to(ctx, dst_fp).context("failed to form forward route")?;
+    // reply blocks go out like the messages they're replenished for
+    let class = circuit::circuit_class(ctx, circuit);
+    let route = forward_route_to(ctx, dst_fp, circuit, class, &mut rand::thread_rng())
+        .context("failed to form forward route")?;
     let first_peeler = route[0];
 
-    let dest_opk = ctx
-        .get(RELAY_GRAPH)
-        .read()
-        .identity(&dst_fp)
-        .context("failed to lookup destination identity")?
-        .onion_pk;
-
-    let instructs = route_to_instructs(ctx, &route).context("failed to translate forward route")?;
-    // currently the path for every one of them is the same; will want to change this in the future
-    let reverse_route = reply_route(ctx).context("failed to form reply route")?;
-    let rb_dest_opk = ctx
-        .get(RELAY_GRAPH)
-        .read()
-        .identity(reverse_route.last().context("reverse route no last")?)
-        .context("cannot lookup identity of neighbor")?
-        .onion_pk;
-
-    let reverse_instructs =
-        route_to_instructs(ctx, &reverse_route).context("failed to translate reply route")?;
-
     let mut rbs: Vec<ReplyBlock> = vec![];
     for _ in 0..count {
-        let (rb, (id, degarbler)) = ReplyBlock::new(
-            &reverse_instructs,
-            reverse_route[0],
-            &rb_dest_opk,
-            *ctx.get(MY_CLIENT_ID),
-            my_anon_id,
-        )
-        .context("cannot build reply block")?;
+        // every reply block gets its own route, so that the replies don't all share one path back
+        let reverse_route =
+            reply_route(ctx, dst_fp, circuit).context("failed to form reply route")?;
+        let (rb, (id, degarbler)) = new_reply_block(ctx, &reverse_route, my_anon_id)?;
         rbs.push(rb);
         ctx.get(DEGARBLERS).insert(id, degarbler);
+        if let Some(anchor) = reverse_route.last() {
+            remember_anchor(ctx, id, *anchor, my_anon_id, dst_fp);
+        }
     }
-    let wrapped_rb_onion = RawPacket::new_normal(
-        &instructs,
-        &dest_opk,
+    let wrapped_rb_onion = forward_packet(
+        ctx,
+        &route,
         InnerPacket::ReplyBlocks(rbs),
-        RemoteId::Anon(my_anon_id),
+        my_anon_id,
+        class,
     )?;
 
     send_raw(ctx, wrapped_rb_onion, first_peeler)
@@ -128,19 +191,179 @@ async fn send_reply_blocks(
     Ok(())
 }
 
-fn reply_route(ctx: &DaemonContext) -> anyhow::Result<Vec<RelayFingerprint>> {
-    let mut route = ctx.get(RELAY_GRAPH).read().rand_relays(2);
-    let mut my_neighs: Vec<RelayFingerprint> = all_relay_neighs(ctx);
-    if let Some(myself) = ctx.get(MY_RELAY_IDENTITY) {
-        my_neighs.push(myself.public().fingerprint());
-    }
-    let rand_neigh = my_neighs.choose(&mut rand::thread_rng()).copied();
+/// Builds a reply block that travels back to `my_anon_id` along `reverse_route`, which ends at the SURB anchor. The caller must keep the degarbler, or replies using the block can't be read.
+pub(super) fn new_reply_block(
+    ctx: &DaemonContext,
+    reverse_route: &[RelayFingerprint],
+    my_anon_id: AnonEndpoint,
+) -> anyhow::Result<(ReplyBlock, (u64, ReplyDegarbler))> {
+    let rb_dest_opk = ctx
+        .get(RELAY_GRAPH)
+        .read()
+        .identity(reverse_route.last().context("reverse route no last")?)
+        .context("cannot lookup identity of neighbor")?
+        .onion_pk;
+    let reverse_instructs =
+        route_to_instructs(ctx, reverse_route).context("failed to translate reply route")?;
+    ReplyBlock::new(
+        &reverse_instructs,
+        reverse_route[0],
+        &rb_dest_opk,
+        *ctx.get(MY_CLIENT_ID),
+        my_anon_id,
+    )
+    .context("cannot build reply block")
+}
 
-    match rand_neigh {
-        Some(neigh) => route.push(neigh),
-        None => anyhow::bail!("we don't have any neighbors, so we cannot plot a reply route"),
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SurbAnchorError {
+    #[error("SURBs can only be anchored at ourselves if we are a relay")]
+    NotARelay,
+    #[error("we don't have any relay neighbors to anchor SURBs at")]
+    NoNeighbors,
+    #[error("SURB anchor {0} is not a relay neighbor we know the identity of")]
+    Unreachable(RelayFingerprint),
+}
+
+/// Picks the relay at the end of our reply routes, following the configured [SurbAnchor] policy.
+pub fn surb_anchor(ctx: &DaemonContext) -> Result<RelayFingerprint, SurbAnchorError> {
+    let myself = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|id| id.public().fingerprint());
+    let policy = ctx
+        .init()
+        .privacy
+        .surb_anchor
+        .unwrap_or(if myself.is_some() {
+            SurbAnchor::Myself
+        } else {
+            SurbAnchor::RandomNeighbor
+        });
+    let reachable = |fp: &RelayFingerprint| {
+        all_relay_neighs(ctx).contains(fp) && ctx.get(RELAY_GRAPH).read().identity(fp).is_some()
+    };
+    match policy {
+        SurbAnchor::Myself => myself.ok_or(SurbAnchorError::NotARelay),
+        SurbAnchor::RandomNeighbor => all_relay_neighs(ctx)
+            .into_iter()
+            .filter(reachable)
+            .choose(&mut rand::thread_rng())
+            .ok_or(SurbAnchorError::NoNeighbors),
+        SurbAnchor::Fixed(fp) if Some(fp) == myself || reachable(&fp) => Ok(fp),
+        SurbAnchor::Fixed(fp) => Err(SurbAnchorError::Unreachable(fp)),
     }
+}
+
+/// Forms a route for a reply block that `replier` will send back to us. The middle hops are sampled from the graph paths between the replier and the anchor, falling back to the circuit's reply hops if the graph doesn't connect them.
+fn reply_route(
+    ctx: &DaemonContext,
+    replier: RelayFingerprint,
+    circuit: CircuitToken,
+) -> anyhow::Result<Vec<RelayFingerprint>> {
+    let anchor = surb_anchor(ctx)?;
+    Ok(reply_route_via(ctx, replier, anchor, circuit))
+}
+
+/// Like [reply_route], but ending at the given anchor.
+pub(super) fn reply_route_via(
+    ctx: &DaemonContext,
+    replier: RelayFingerprint,
+    anchor: RelayFingerprint,
+    circuit: CircuitToken,
+) -> Vec<RelayFingerprint> {
+    let mut route = surb_routes::sample_middle_hops(ctx, replier, anchor, circuit)
+        .unwrap_or_else(|| circuit::reply_hops(ctx, circuit));
+    route.push(anchor);
 
     tracing::trace!("reply route formed: {:?}", route);
-    Ok(route)
+    route
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::IdentityDescriptor;
+
+    use crate::{network::subscribe_outgoing_relay, test_util::known_relay};
+
+    use super::*;
+
+    fn ctx(cfg: serde_json::Value) -> DaemonContext {
+        DaemonContext::new(serde_json::from_value(cfg).unwrap())
+    }
+
+    #[test]
+    fn anchor_defaults() {
+        let relay = ctx(serde_json::json!({ "identity_seed": "anchor_defaults" }));
+        let myself = relay.get(MY_RELAY_IDENTITY).unwrap().public().fingerprint();
+        assert_eq!(surb_anchor(&relay), Ok(myself));
+
+        let client = ctx(serde_json::json!({}));
+        assert_eq!(surb_anchor(&client), Err(SurbAnchorError::NoNeighbors));
+        let neigh = known_relay(&client).public().fingerprint();
+        let _link = subscribe_outgoing_relay(&client, neigh);
+        assert_eq!(surb_anchor(&client), Ok(neigh));
+    }
+
+    #[test]
+    fn anchor_self_needs_relay() {
+        let client = ctx(serde_json::json!({ "privacy": { "surb_anchor": "self" } }));
+        assert_eq!(surb_anchor(&client), Err(SurbAnchorError::NotARelay));
+    }
+
+    #[test]
+    fn anchor_random_neighbor() {
+        let relay = ctx(serde_json::json!({
+            "identity_seed": "anchor_random_neighbor",
+            "privacy": { "surb_anchor": "random_neighbor" },
+        }));
+        assert_eq!(surb_anchor(&relay), Err(SurbAnchorError::NoNeighbors));
+
+        // neighbors we don't know the identity of can't be anchors
+        let unknown = RelayIdentitySecret::generate().public().fingerprint();
+        let _unknown_link = subscribe_outgoing_relay(&relay, unknown);
+        assert_eq!(surb_anchor(&relay), Err(SurbAnchorError::NoNeighbors));
+
+        let neighs = [(); 2].map(|_| known_relay(&relay).public().fingerprint());
+        let _links = neighs.map(|neigh| subscribe_outgoing_relay(&relay, neigh));
+        for _ in 0..10 {
+            assert!(neighs.contains(&surb_anchor(&relay).unwrap()));
+        }
+    }
+
+    #[test]
+    fn anchor_fixed() {
+        let neigh_id = RelayIdentitySecret::generate();
+        let neigh = neigh_id.public().fingerprint();
+        let client = ctx(serde_json::json!({
+            "privacy": { "surb_anchor": { "fixed": neigh.to_string() } },
+        }));
+        assert_eq!(
+            surb_anchor(&client),
+            Err(SurbAnchorError::Unreachable(neigh))
+        );
+        // knowing its identity is not enough: we must also be connected to it
+        client
+            .get(RELAY_GRAPH)
+            .write()
+            .insert_identity(IdentityDescriptor::new(&neigh_id, &DhSecret::generate()))
+            .unwrap();
+        assert_eq!(
+            surb_anchor(&client),
+            Err(SurbAnchorError::Unreachable(neigh))
+        );
+        let _link = subscribe_outgoing_relay(&client, neigh);
+        assert_eq!(surb_anchor(&client), Ok(neigh));
+
+        // relays can fix the anchor at themselves
+        let myself = RelayIdentitySecret::from_seed("anchor_fixed")
+            .public()
+            .fingerprint();
+        let relay = ctx(serde_json::json!({
+            "identity_seed": "anchor_fixed",
+            "privacy": { "surb_anchor": { "fixed": myself.to_string() } },
+        }));
+        assert_eq!(surb_anchor(&relay), Ok(myself));
+    }
 }
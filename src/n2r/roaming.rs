@@ -0,0 +1,202 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use async_event::Event;
+use dashmap::DashMap;
+use earendil_crypt::{AnonEndpoint, RelayFingerprint};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    n2r::{circuit, remote_rb},
+    network::is_relay_neigh,
+    scope::{spawn_scoped, Stage},
+    stats::STATS,
+};
+
+pub const ROAMED: &str = "n2r.roamed";
+
+const MAX_ROAMING_EVENTS: usize = 1000;
+
+/// A relay we anchored reply blocks at went away, and the conversations that relied on them were handed fresh reply blocks anchored at relays we're still connected to.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RoamingEvent {
+    /// The relay we roamed away from.
+    pub from: RelayFingerprint,
+    /// How many of our reply blocks were anchored at it.
+    pub invalidated: usize,
+    /// Our endpoints whose active conversations were moved to another anchor.
+    pub endpoints: Vec<AnonEndpoint>,
+}
+
+struct Roaming {
+    /// Bumped whenever a relay neighbor's link goes down, so that only the settling after the last one counts.
+    downs: DashMap<RelayFingerprint, u64>,
+    next_down: AtomicU64,
+    events: Mutex<VecDeque<(u64, RoamingEvent)>>,
+    new_event: Event,
+}
+
+static ROAMING: CtxField<Roaming> = |_| Roaming {
+    downs: DashMap::new(),
+    next_down: AtomicU64::new(0),
+    events: Mutex::new(VecDeque::new()),
+    new_event: Event::new(),
+};
+
+/// Called when a link to a relay neighbor goes down. If we have no link to the relay once `roaming_settle_ms` passes, we roam away from it.
+pub fn link_down(ctx: &DaemonContext, relay: RelayFingerprint) {
+    let roaming = ctx.get(ROAMING);
+    let down = roaming.next_down.fetch_add(1, Ordering::SeqCst);
+    roaming.downs.insert(relay, down);
+    let settle = Duration::from_millis(ctx.init().roaming_settle_ms);
+    spawn_scoped(ctx, Stage::Upkeep, "roaming_settle", {
+        let ctx = ctx.clone();
+        async move {
+            smol::Timer::after(settle).await;
+            let latest = ctx
+                .get(ROAMING)
+                .downs
+                .remove_if(&relay, |_, latest| *latest == down)
+                .is_some();
+            // the link came back, or went down again and is settling anew
+            if !latest || is_relay_neigh(&ctx, relay) {
+                return;
+            }
+            roam(&ctx, relay).await;
+        }
+    });
+}
+
+/// Invalidates the reply blocks anchored at a relay we lost, and replenishes the active conversations that relied on them, which anchors the new reply blocks at relays we're still connected to.
+async fn roam(ctx: &DaemonContext, from: RelayFingerprint) {
+    let (invalidated, conversations) = remote_rb::invalidate_anchor(ctx, from);
+    if invalidated == 0 {
+        return;
+    }
+    tracing::info!(
+        from = display(from),
+        invalidated,
+        conversations = conversations.len(),
+        "roaming away from a lost SURB anchor"
+    );
+    let mut endpoints = vec![];
+    for (my_anon_id, dst_fp) in conversations {
+        let circuit = circuit::circuit_of(ctx, my_anon_id);
+        if let Err(err) = remote_rb::replenish_remote_rb(ctx, my_anon_id, dst_fp, circuit).await {
+            tracing::debug!(
+                err = debug(err),
+                dst_fp = display(dst_fp),
+                "could not replenish reply blocks after roaming"
+            );
+        }
+        if !endpoints.contains(&my_anon_id) {
+            endpoints.push(my_anon_id);
+        }
+    }
+    ctx.get(STATS).incr(ROAMED);
+    push_event(
+        ctx,
+        RoamingEvent {
+            from,
+            invalidated,
+            endpoints,
+        },
+    );
+}
+
+fn push_event(ctx: &DaemonContext, event: RoamingEvent) {
+    let roaming = ctx.get(ROAMING);
+    let mut events = roaming.events.lock();
+    let seq = events.back().map(|(seq, _)| seq + 1).unwrap_or(1);
+    events.push_back((seq, event));
+    if events.len() > MAX_ROAMING_EVENTS {
+        events.pop_front();
+    }
+    drop(events);
+    roaming.new_event.notify_all();
+}
+
+/// The number of the last roaming event, or zero if there weren't any.
+pub fn last_roaming_event(ctx: &DaemonContext) -> u64 {
+    ctx.get(ROAMING)
+        .events
+        .lock()
+        .back()
+        .map(|(seq, _)| *seq)
+        .unwrap_or(0)
+}
+
+/// Waits until there are roaming events numbered after `after`, then returns them in order.
+pub async fn roaming_events(ctx: &DaemonContext, after: u64) -> Vec<(u64, RoamingEvent)> {
+    let roaming = ctx.get(ROAMING);
+    roaming
+        .new_event
+        .wait_until(|| {
+            let events: Vec<_> = roaming
+                .events
+                .lock()
+                .iter()
+                .filter(|(seq, _)| *seq > after)
+                .cloned()
+                .collect();
+            if events.is_empty() {
+                None
+            } else {
+                Some(events)
+            }
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use smol_timeout::TimeoutExt;
+
+    use super::*;
+    use crate::{
+        n2r::{remote_rb::new_reply_block, DEGARBLERS},
+        network::subscribe_outgoing_relay,
+        test_util::known_relay,
+    };
+
+    #[test]
+    fn flaps_settle_without_roaming() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "roaming_settle_ms": 200 })).unwrap(),
+        );
+        let anchor = known_relay(&ctx).public().fingerprint();
+        let replier = known_relay(&ctx).public().fingerprint();
+        let me = AnonEndpoint::random();
+        let link = subscribe_outgoing_relay(&ctx, anchor);
+        let (_, (id, degarbler)) = new_reply_block(&ctx, &[anchor], me).unwrap();
+        ctx.get(DEGARBLERS).insert(id, degarbler);
+        remote_rb::remember_anchor(&ctx, id, anchor, me, replier);
+
+        smol::future::block_on(async {
+            // the link comes back before it settles
+            drop(link);
+            link_down(&ctx, anchor);
+            let link = subscribe_outgoing_relay(&ctx, anchor);
+            smol::Timer::after(Duration::from_millis(400)).await;
+            assert!(ctx.get(DEGARBLERS).contains_key(&id));
+            assert_eq!(last_roaming_event(&ctx), 0);
+
+            // now it stays down
+            drop(link);
+            link_down(&ctx, anchor);
+            let events = roaming_events(&ctx, 0)
+                .timeout(Duration::from_secs(5))
+                .await
+                .expect("never roamed away from the dead anchor");
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].1.from, anchor);
+            assert_eq!(events[0].1.invalidated, 1);
+            assert!(!ctx.get(DEGARBLERS).contains_key(&id));
+        });
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use earendil_crypt::{AnonEndpoint, RelayFingerprint};
+use moka::sync::Cache;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::RouteLearningConfig,
+    context::{CtxField, DaemonContext, RELAY_GRAPH},
+    db::{db_read, MiscKey},
+    ledger::unix_now,
+};
+
+use super::CircuitToken;
+
+/// How many routes we remember per circuit and destination. The lowest-scoring ones are forgotten first.
+const MAX_ROUTES_PER_DEST: usize = 8;
+
+/// How many circuit and destination pairs we remember routes for. The ones that went unused the longest are forgotten first.
+const MAX_LEARNED_FLOWS: usize = 1024;
+
+/// How many sends from one endpoint to one destination may wait for replies at once. Older ones are forgotten without counting either way.
+const MAX_UNCONFIRMED_PER_FLOW: usize = 64;
+
+/// How long a send waits for a reply before its route counts as having failed.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Learned routes, persisted in the state cache across restarts.
+pub static ROUTE_MEMORY: CtxField<Mutex<RouteMemory>> = |ctx| {
+    smol::future::block_on(async move {
+        let memory = match db_read(ctx, MiscKey::RouteMemory).await {
+            Ok(Some(bytes)) => stdcode::deserialize(&bytes).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                tracing::warn!("error retrieving route memory: {e}");
+                None
+            }
+        };
+        Mutex::new(memory.unwrap_or_default())
+    })
+};
+
+/// A send still waiting for a reply to confirm its route.
+struct Unconfirmed {
+    circuit: CircuitToken,
+    route: Vec<RelayFingerprint>,
+    sent: Instant,
+}
+
+/// The unconfirmed sends from each of our anonymous endpoints to each destination, oldest first.
+static UNCONFIRMED: CtxField<
+    Cache<(AnonEndpoint, RelayFingerprint), Arc<Mutex<VecDeque<Unconfirmed>>>>,
+> = |_| {
+    Cache::builder()
+        .time_to_idle(Duration::from_secs(600))
+        .build()
+};
+
+/// Remembers, per circuit and destination, which routes got replies and which didn't. Keeping circuits apart means what one learned never leads another down the same path.
+#[derive(Serialize, Deserialize, Default)]
+pub struct RouteMemory {
+    routes: BTreeMap<(CircuitToken, RelayFingerprint), Vec<LearnedRoute>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LearnedRoute {
+    pub hops: Vec<RelayFingerprint>,
+    /// Positive for routes that mostly worked, negative for ones that mostly failed. Decays towards zero over time.
+    pub score: f64,
+    pub updated_unix: u64,
+}
+
+impl LearnedRoute {
+    fn decayed_score(&self, now_unix: u64, half_life_secs: u64) -> f64 {
+        let age = now_unix.saturating_sub(self.updated_unix) as f64;
+        self.score * 0.5f64.powf(age / half_life_secs.max(1) as f64)
+    }
+}
+
+impl RouteMemory {
+    /// Records whether sending along `hops` to `dest` on the circuit got a reply.
+    pub fn record(
+        &mut self,
+        circuit: CircuitToken,
+        dest: RelayFingerprint,
+        hops: &[RelayFingerprint],
+        success: bool,
+        now_unix: u64,
+        half_life_secs: u64,
+    ) {
+        if !self.routes.contains_key(&(circuit, dest)) && self.routes.len() >= MAX_LEARNED_FLOWS {
+            let stalest = self
+                .routes
+                .iter()
+                .min_by_key(|(_, routes)| routes.iter().map(|route| route.updated_unix).max())
+                .map(|(flow, _)| *flow);
+            if let Some(stalest) = stalest {
+                self.routes.remove(&stalest);
+            }
+        }
+        let routes = self.routes.entry((circuit, dest)).or_default();
+        let delta = if success { 1.0 } else { -1.0 };
+        if let Some(route) = routes.iter_mut().find(|route| route.hops == hops) {
+            route.score = route.decayed_score(now_unix, half_life_secs) + delta;
+            route.updated_unix = now_unix;
+        } else {
+            routes.push(LearnedRoute {
+                hops: hops.to_vec(),
+                score: delta,
+                updated_unix: now_unix,
+            });
+        }
+        if routes.len() > MAX_ROUTES_PER_DEST {
+            routes.sort_by(|a, b| {
+                b.decayed_score(now_unix, half_life_secs)
+                    .total_cmp(&a.decayed_score(now_unix, half_life_secs))
+            });
+            routes.truncate(MAX_ROUTES_PER_DEST);
+        }
+    }
+
+    /// Returns the best-scoring route to `dest` the circuit learned that is still usable, if any has a positive score.
+    pub fn best(
+        &self,
+        circuit: CircuitToken,
+        dest: RelayFingerprint,
+        now_unix: u64,
+        half_life_secs: u64,
+        usable: impl Fn(&[RelayFingerprint]) -> bool,
+    ) -> Option<Vec<RelayFingerprint>> {
+        self.routes
+            .get(&(circuit, dest))?
+            .iter()
+            .map(|route| (route.decayed_score(now_unix, half_life_secs), route))
+            .filter(|(score, route)| *score > 0.0 && usable(&route.hops))
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, route)| route.hops.clone())
+    }
+
+    /// Returns every learned route, by circuit and destination, with its current score.
+    pub fn dump(
+        &self,
+        now_unix: u64,
+        half_life_secs: u64,
+    ) -> BTreeMap<(CircuitToken, RelayFingerprint), Vec<LearnedRoute>> {
+        self.routes
+            .iter()
+            .map(|(flow, routes)| {
+                let routes = routes
+                    .iter()
+                    .map(|route| LearnedRoute {
+                        score: route.decayed_score(now_unix, half_life_secs),
+                        ..route.clone()
+                    })
+                    .collect();
+                (*flow, routes)
+            })
+            .collect()
+    }
+}
+
+/// Decides whether a send explores a fresh route rather than using a learned one.
+pub(super) fn explores(ctx: &DaemonContext, rng: &mut impl Rng) -> bool {
+    ctx.init()
+        .route_learning
+        .is_some_and(|config| rng.gen_bool(config.exploration_ratio.clamp(0.0, 1.0)))
+}
+
+/// Returns a route to `dest` the circuit learned, to use instead of a fresh one, unless route learning is off.
+pub(super) fn learned_route(
+    ctx: &DaemonContext,
+    circuit: CircuitToken,
+    dest: RelayFingerprint,
+    usable: impl Fn(&[RelayFingerprint]) -> bool,
+) -> Option<Vec<RelayFingerprint>> {
+    let config = ctx.init().route_learning?;
+    let graph = ctx.get(RELAY_GRAPH).read();
+    let in_graph = |hops: &[RelayFingerprint]| hops.iter().all(|hop| graph.identity(hop).is_some());
+    ctx.get(ROUTE_MEMORY).lock().best(
+        circuit,
+        dest,
+        unix_now(),
+        config.score_half_life_secs,
+        |hops| in_graph(hops) && usable(hops),
+    )
+}
+
+/// Notes that we sent from `src` to `dest` along `route` on the circuit, to be confirmed by a reply. Earlier sends between them that went unconfirmed for too long count as failed.
+pub(super) fn sent_along(
+    ctx: &DaemonContext,
+    src: AnonEndpoint,
+    dest: RelayFingerprint,
+    circuit: CircuitToken,
+    route: &[RelayFingerprint],
+) {
+    let Some(config) = ctx.init().route_learning else {
+        return;
+    };
+    let unconfirmed = ctx.get(UNCONFIRMED).get_with((src, dest), Default::default);
+    let mut unconfirmed = unconfirmed.lock();
+    expire_unconfirmed(ctx, config, dest, &mut unconfirmed);
+    if unconfirmed.len() >= MAX_UNCONFIRMED_PER_FLOW {
+        unconfirmed.pop_front();
+    }
+    unconfirmed.push_back(Unconfirmed {
+        circuit,
+        route: route.to_vec(),
+        sent: Instant::now(),
+    });
+}
+
+/// Notes that `dest` replied to `src`, confirming the route of the oldest send between them still waiting for one.
+pub(super) fn reply_received(ctx: &DaemonContext, src: AnonEndpoint, dest: RelayFingerprint) {
+    let Some(config) = ctx.init().route_learning else {
+        return;
+    };
+    let Some(unconfirmed) = ctx.get(UNCONFIRMED).get(&(src, dest)) else {
+        return;
+    };
+    let mut unconfirmed = unconfirmed.lock();
+    expire_unconfirmed(ctx, config, dest, &mut unconfirmed);
+    if let Some(confirmed) = unconfirmed.pop_front() {
+        record(ctx, config, confirmed.circuit, dest, &confirmed.route, true);
+    }
+}
+
+/// Counts the sends that waited too long for a reply as failed.
+fn expire_unconfirmed(
+    ctx: &DaemonContext,
+    config: RouteLearningConfig,
+    dest: RelayFingerprint,
+    unconfirmed: &mut VecDeque<Unconfirmed>,
+) {
+    while let Some(oldest) = unconfirmed.front() {
+        if oldest.sent.elapsed() <= CONFIRM_TIMEOUT {
+            break;
+        }
+        let oldest = unconfirmed.pop_front().expect("just looked at it");
+        record(ctx, config, oldest.circuit, dest, &oldest.route, false);
+    }
+}
+
+fn record(
+    ctx: &DaemonContext,
+    config: RouteLearningConfig,
+    circuit: CircuitToken,
+    dest: RelayFingerprint,
+    hops: &[RelayFingerprint],
+    success: bool,
+) {
+    ctx.get(ROUTE_MEMORY).lock().record(
+        circuit,
+        dest,
+        hops,
+        success,
+        unix_now(),
+        config.score_half_life_secs,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+
+    use super::*;
+
+    #[test]
+    fn every_send_waits_for_its_own_reply() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "route_learning": {} })).unwrap(),
+        );
+        let [dest, a, b, c] =
+            [(); 4].map(|_| RelayIdentitySecret::generate().public().fingerprint());
+        let (src, circuit) = (AnonEndpoint::random(), CircuitToken::new());
+        // three sends in a row, before any reply comes back
+        for hop in [a, b, c] {
+            sent_along(&ctx, src, dest, circuit, &[hop, dest]);
+        }
+        for _ in 0..3 {
+            reply_received(&ctx, src, dest);
+        }
+        let learned = ctx.get(ROUTE_MEMORY).lock().dump(unix_now(), 3600);
+        let routes = &learned[&(circuit, dest)];
+        assert_eq!(routes.len(), 3);
+        assert!(routes.iter().all(|route| route.score > 0.0));
+        // a reply with nothing left to confirm changes nothing
+        reply_received(&ctx, src, dest);
+        assert_eq!(
+            ctx.get(ROUTE_MEMORY).lock().dump(unix_now(), 3600)[&(circuit, dest)].len(),
+            3
+        );
+    }
+
+    #[test]
+    fn circuits_learn_apart() {
+        let [dest, a] = [(); 2].map(|_| RelayIdentitySecret::generate().public().fingerprint());
+        let (mine, theirs) = (CircuitToken::new(), CircuitToken::new());
+        let mut memory = RouteMemory::default();
+        memory.record(mine, dest, &[a, dest], true, 0, 100);
+        assert!(memory.best(mine, dest, 0, 100, |_| true).is_some());
+        assert!(memory.best(theirs, dest, 0, 100, |_| true).is_none());
+    }
+
+    #[test]
+    fn learned_flows_are_bounded() {
+        let [dest, a] = [(); 2].map(|_| RelayIdentitySecret::generate().public().fingerprint());
+        let mut memory = RouteMemory::default();
+        let first = CircuitToken::new();
+        memory.record(first, dest, &[a, dest], true, 0, 100);
+        for now in 1..=MAX_LEARNED_FLOWS as u64 {
+            memory.record(CircuitToken::new(), dest, &[a, dest], true, now, 100);
+        }
+        assert_eq!(memory.routes.len(), MAX_LEARNED_FLOWS);
+        // the one that went unused the longest is forgotten
+        assert!(!memory.routes.contains_key(&(first, dest)));
+    }
+
+    #[test]
+    fn scores_decay() {
+        let [dest, a] = [(); 2].map(|_| RelayIdentitySecret::generate().public().fingerprint());
+        let circuit = CircuitToken::new();
+        let mut memory = RouteMemory::default();
+        memory.record(circuit, dest, &[a, dest], true, 0, 100);
+        assert!(memory.best(circuit, dest, 0, 100, |_| true).is_some());
+        let score = memory.dump(1000, 100)[&(circuit, dest)][0].score;
+        assert!(score < 0.001);
+    }
+}
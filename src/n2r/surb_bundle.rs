@@ -0,0 +1,574 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use earendil_crypt::{AnonEndpoint, RelayFingerprint};
+use earendil_packet::{ReplyBlock, ReplyDegarbler};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+use thiserror::Error;
+
+use crate::{
+    clock,
+    context::{CtxField, DaemonContext},
+    db::{db_read, MiscKey},
+    stats::STATS,
+};
+
+use super::{
+    circuit::CircuitToken,
+    remote_rb::{new_reply_block, reply_route_via, surb_anchor},
+};
+
+/// The most reply blocks one bundle may carry. Each is about a kilobyte, so this keeps bundles to about ten megabytes.
+pub const MAX_BUNDLE_SURBS: usize = 10_000;
+
+/// The most bundles we keep track of on each side, issued or imported, until they expire.
+pub const MAX_BUNDLES: usize = 256;
+
+/// The most reply blocks the bundles on each side may carry between them. Reply blocks on the sending side are about a kilobyte each, so this keeps them to about a hundred megabytes.
+pub const MAX_HELD_SURBS: usize = 100_000;
+
+/// We warn once a bundle is down to this fraction of the reply blocks it was issued with.
+const LOW_STOCK_DIVISOR: usize = 10;
+
+pub const SURB_BUNDLE_RECEIVED: &str = "surb_bundle.received";
+pub const SURB_BUNDLE_REPLAYED: &str = "surb_bundle.replayed";
+pub const SURB_BUNDLE_EXPIRED: &str = "surb_bundle.expired";
+pub const SURB_BUNDLE_LOW: &str = "surb_bundle.low";
+
+/// A batch of reply blocks issued ahead of time, so that a relay can send to an anonymous endpoint that never sends anything itself. All of them end at the same SURB anchor, so the endpoint only needs a link to that one relay to receive.
+///
+/// Bundles are delivered out of band, as [SurbBundle::to_bytes]. Every reply block can be used once, and only until the bundle expires.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SurbBundle {
+    pub id: u64,
+    pub anon_dest: AnonEndpoint,
+    pub anchor: RelayFingerprint,
+    /// When the bundle stops being usable, in seconds since the Unix epoch.
+    pub expires_at: u64,
+    pub reply_blocks: Vec<ReplyBlock>,
+}
+
+impl SurbBundle {
+    /// Encodes the bundle, for handing to the sending party.
+    pub fn to_bytes(&self) -> Bytes {
+        self.stdcode().into()
+    }
+
+    pub fn from_bytes(bts: &[u8]) -> anyhow::Result<Self> {
+        Ok(stdcode::deserialize(bts)?)
+    }
+}
+
+#[derive(Error, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum SurbBundleError {
+    #[error("a bundle must carry between 1 and {MAX_BUNDLE_SURBS} reply blocks")]
+    BadSize,
+    #[error("not a SURB bundle")]
+    Malformed,
+    #[error("already holding {MAX_BUNDLES} bundles or {MAX_HELD_SURBS} reply blocks, so wait for some to run out or expire")]
+    TooMany,
+    #[error("bundle {0} has expired")]
+    Expired(u64),
+    #[error("bundle {0} was already imported, and its reply blocks may have been used")]
+    AlreadyImported(u64),
+}
+
+/// How many reply blocks of a bundle are left.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SurbBundleStock {
+    pub id: u64,
+    pub anon_dest: AnonEndpoint,
+    pub anchor: RelayFingerprint,
+    pub expires_at: u64,
+    pub total: usize,
+    pub remaining: usize,
+}
+
+/// The bundles we issued, on the receiving side, and the bundles we imported, on the sending side.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SurbBundles {
+    pub issued: Vec<SurbBundleStock>,
+    pub imported: Vec<SurbBundleStock>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IssuedBundle {
+    anon_dest: AnonEndpoint,
+    anchor: RelayFingerprint,
+    expires_at: u64,
+    total: usize,
+    degarblers: HashMap<u64, ReplyDegarbler>,
+    warned_low: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Issued {
+    bundles: HashMap<u64, IssuedBundle>,
+    /// The bundle of every reply block we issued, including used ones, so that replays can be told apart from stray replies.
+    owners: HashMap<u64, u64>,
+}
+
+/// The bundles we issued, persisted in the state cache so that they keep working across restarts.
+static ISSUED: CtxField<Mutex<Issued>> = |ctx| Mutex::new(load(ctx, MiscKey::IssuedSurbBundles));
+
+#[derive(Serialize, Deserialize)]
+struct ImportedBundle {
+    id: u64,
+    anchor: RelayFingerprint,
+    expires_at: u64,
+    total: usize,
+    reply_blocks: Vec<ReplyBlock>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Imported {
+    by_dest: HashMap<AnonEndpoint, Vec<ImportedBundle>>,
+    /// Every bundle imported and not yet expired, even ones used up, with their expiry.
+    seen: HashMap<u64, u64>,
+}
+
+/// The bundles issued to us, persisted in the state cache like [ISSUED].
+static IMPORTED: CtxField<Mutex<Imported>> =
+    |ctx| Mutex::new(load(ctx, MiscKey::ImportedSurbBundles));
+
+fn load<T: serde::de::DeserializeOwned + Default>(ctx: &DaemonContext, key: MiscKey) -> T {
+    smol::future::block_on(async {
+        match db_read(ctx, key.clone()).await {
+            Ok(Some(bytes)) => stdcode::deserialize(&bytes).unwrap_or_else(|e| {
+                tracing::warn!(key = debug(key), "discarding unreadable SURB bundles: {e}");
+                T::default()
+            }),
+            Ok(None) => T::default(),
+            Err(e) => {
+                tracing::warn!(key = debug(key), "error retrieving SURB bundles: {e}");
+                T::default()
+            }
+        }
+    })
+}
+
+/// Encodes the bundles we issued and imported that haven't expired, to be saved under [MiscKey::IssuedSurbBundles] and [MiscKey::ImportedSurbBundles] respectively. Reply blocks used since the last save come back unused after a crash, so saving often keeps those few.
+pub fn persisted_surb_bundles(ctx: &DaemonContext) -> (Vec<u8>, Vec<u8>) {
+    let issued = {
+        let mut issued = ctx.get(ISSUED).lock();
+        purge_issued(ctx, &mut issued);
+        issued.stdcode()
+    };
+    let imported = {
+        let mut imported = ctx.get(IMPORTED).lock();
+        purge_imported(ctx, &mut imported);
+        imported.stdcode()
+    };
+    (issued, imported)
+}
+
+/// Issues a bundle of `count` reply blocks to `anon_dest`, for `replier` to send with. Replies come back through our SURB anchor, which must be reachable now, but the bundle stays usable for `ttl` whether or not we stay connected to anything else.
+pub fn issue_surb_bundle(
+    ctx: &DaemonContext,
+    anon_dest: AnonEndpoint,
+    replier: RelayFingerprint,
+    count: usize,
+    ttl: Duration,
+) -> anyhow::Result<SurbBundle> {
+    if count == 0 || count > MAX_BUNDLE_SURBS {
+        return Err(SurbBundleError::BadSize.into());
+    }
+    let anchor = surb_anchor(ctx)?;
+    let circuit = CircuitToken::new();
+    let mut reply_blocks = Vec::with_capacity(count);
+    let mut degarblers = HashMap::with_capacity(count);
+    for _ in 0..count {
+        let reverse_route = reply_route_via(ctx, replier, anchor, circuit);
+        let (rb, (rb_id, degarbler)) = new_reply_block(ctx, &reverse_route, anon_dest)?;
+        reply_blocks.push(rb);
+        degarblers.insert(rb_id, degarbler);
+    }
+    let bundle = SurbBundle {
+        id: rand::random(),
+        anon_dest,
+        anchor,
+        expires_at: clock::unix_now(ctx) + ttl.as_secs(),
+        reply_blocks,
+    };
+
+    let mut issued = ctx.get(ISSUED).lock();
+    purge_issued(ctx, &mut issued);
+    let held: usize = issued.bundles.values().map(|bundle| bundle.total).sum();
+    if issued.bundles.len() >= MAX_BUNDLES || held + count > MAX_HELD_SURBS {
+        return Err(SurbBundleError::TooMany.into());
+    }
+    for rb_id in degarblers.keys() {
+        issued.owners.insert(*rb_id, bundle.id);
+    }
+    issued.bundles.insert(
+        bundle.id,
+        IssuedBundle {
+            anon_dest,
+            anchor,
+            expires_at: bundle.expires_at,
+            total: count,
+            degarblers,
+            warned_low: false,
+        },
+    );
+    Ok(bundle)
+}
+
+/// Takes the degarbler of a reply block from a bundle we issued, if the reply block is from one, hasn't been used yet, and its bundle hasn't expired.
+pub(super) fn take_degarbler(ctx: &DaemonContext, rb_id: u64) -> Option<ReplyDegarbler> {
+    let mut issued = ctx.get(ISSUED).lock();
+    purge_issued(ctx, &mut issued);
+    let bundle_id = *issued.owners.get(&rb_id)?;
+    let bundle = issued.bundles.get_mut(&bundle_id)?;
+    let Some(degarbler) = bundle.degarblers.remove(&rb_id) else {
+        ctx.get(STATS).incr(SURB_BUNDLE_REPLAYED);
+        tracing::debug!(bundle_id, "reply block from a bundle used twice");
+        return None;
+    };
+    ctx.get(STATS).incr(SURB_BUNDLE_RECEIVED);
+    let remaining = bundle.degarblers.len();
+    if !bundle.warned_low && remaining * LOW_STOCK_DIVISOR <= bundle.total {
+        bundle.warned_low = true;
+        ctx.get(STATS).incr(SURB_BUNDLE_LOW);
+        tracing::warn!(
+            bundle_id,
+            remaining,
+            total = bundle.total,
+            "SURB bundle running low, issue a new one soon"
+        );
+    }
+    Some(degarbler)
+}
+
+fn purge_issued(ctx: &DaemonContext, issued: &mut Issued) {
+    let now = clock::unix_now(ctx);
+    let expired: HashSet<u64> = issued
+        .bundles
+        .iter()
+        .filter(|(_, bundle)| bundle.expires_at <= now)
+        .map(|(id, _)| *id)
+        .collect();
+    if expired.is_empty() {
+        return;
+    }
+    for bundle_id in expired.iter() {
+        ctx.get(STATS).incr(SURB_BUNDLE_EXPIRED);
+        tracing::debug!(bundle_id, "SURB bundle expired");
+        issued.bundles.remove(bundle_id);
+    }
+    issued
+        .owners
+        .retain(|_, bundle_id| !expired.contains(bundle_id));
+}
+
+/// Imports a bundle issued to us, so that relay sockets can send to its anonymous endpoint. Each bundle can only be imported once, since its reply blocks can only be used once.
+pub fn import_surb_bundle(ctx: &DaemonContext, bundle: SurbBundle) -> Result<(), SurbBundleError> {
+    if bundle.reply_blocks.is_empty() || bundle.reply_blocks.len() > MAX_BUNDLE_SURBS {
+        return Err(SurbBundleError::BadSize);
+    }
+    if bundle.expires_at <= clock::unix_now(ctx) {
+        return Err(SurbBundleError::Expired(bundle.id));
+    }
+    let mut imported = ctx.get(IMPORTED).lock();
+    purge_imported(ctx, &mut imported);
+    if imported.seen.contains_key(&bundle.id) {
+        return Err(SurbBundleError::AlreadyImported(bundle.id));
+    }
+    // used-up bundles count too, since we have to remember them to refuse them
+    let held: usize = imported
+        .by_dest
+        .values()
+        .flatten()
+        .map(|bundle| bundle.reply_blocks.len())
+        .sum();
+    if imported.seen.len() >= MAX_BUNDLES || held + bundle.reply_blocks.len() > MAX_HELD_SURBS {
+        return Err(SurbBundleError::TooMany);
+    }
+    imported.seen.insert(bundle.id, bundle.expires_at);
+    let bundles = imported.by_dest.entry(bundle.anon_dest).or_default();
+    bundles.push(ImportedBundle {
+        id: bundle.id,
+        anchor: bundle.anchor,
+        expires_at: bundle.expires_at,
+        total: bundle.reply_blocks.len(),
+        reply_blocks: bundle.reply_blocks,
+    });
+    // the bundles expiring soonest get used first
+    bundles.sort_unstable_by_key(|bundle| bundle.expires_at);
+    Ok(())
+}
+
+/// Takes a reply block to `anon_dest` from the bundles we imported, if any are left.
+pub(super) fn pop_imported(ctx: &DaemonContext, anon_dest: &AnonEndpoint) -> Option<ReplyBlock> {
+    let mut imported = ctx.get(IMPORTED).lock();
+    purge_imported(ctx, &mut imported);
+    let bundles = imported.by_dest.get_mut(anon_dest)?;
+    let rb = bundles
+        .iter_mut()
+        .find_map(|bundle| bundle.reply_blocks.pop());
+    bundles.retain(|bundle| !bundle.reply_blocks.is_empty());
+    if bundles.is_empty() {
+        imported.by_dest.remove(anon_dest);
+    }
+    rb
+}
+
+fn purge_imported(ctx: &DaemonContext, imported: &mut Imported) {
+    let now = clock::unix_now(ctx);
+    imported.seen.retain(|_, expires_at| *expires_at > now);
+    imported.by_dest.retain(|_, bundles| {
+        bundles.retain(|bundle| bundle.expires_at > now);
+        !bundles.is_empty()
+    });
+}
+
+/// How many reply blocks are left in every bundle we issued or imported.
+pub fn surb_bundles(ctx: &DaemonContext) -> SurbBundles {
+    let mut issued = ctx.get(ISSUED).lock();
+    purge_issued(ctx, &mut issued);
+    let mut imported = ctx.get(IMPORTED).lock();
+    purge_imported(ctx, &mut imported);
+    SurbBundles {
+        issued: issued
+            .bundles
+            .iter()
+            .map(|(id, bundle)| SurbBundleStock {
+                id: *id,
+                anon_dest: bundle.anon_dest,
+                anchor: bundle.anchor,
+                expires_at: bundle.expires_at,
+                total: bundle.total,
+                remaining: bundle.degarblers.len(),
+            })
+            .collect(),
+        imported: imported
+            .by_dest
+            .iter()
+            .flat_map(|(anon_dest, bundles)| {
+                bundles.iter().map(|bundle| SurbBundleStock {
+                    id: bundle.id,
+                    anon_dest: *anon_dest,
+                    anchor: bundle.anchor,
+                    expires_at: bundle.expires_at,
+                    total: bundle.total,
+                    remaining: bundle.reply_blocks.len(),
+                })
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::IdentityDescriptor;
+
+    use crate::{
+        clock::{Clock, FakeClock},
+        context::RELAY_GRAPH,
+        db::db_write,
+    };
+
+    use super::*;
+
+    /// A relay whose SURBs are anchored at itself, and which knows of one other relay to be the replier.
+    fn relay() -> (DaemonContext, RelayFingerprint) {
+        relay_with(serde_json::json!({ "identity_seed": "surb_bundle" }))
+    }
+
+    fn relay_with(config: serde_json::Value) -> (DaemonContext, RelayFingerprint) {
+        let ctx = DaemonContext::new(serde_json::from_value(config).unwrap());
+        let replier = RelayIdentitySecret::generate();
+        for identity in [replier, RelayIdentitySecret::from_seed("surb_bundle")] {
+            ctx.get(RELAY_GRAPH)
+                .write()
+                .insert_identity(IdentityDescriptor::new(&identity, &DhSecret::generate()))
+                .unwrap();
+        }
+        (ctx, replier.public().fingerprint())
+    }
+
+    fn rb_ids(ctx: &DaemonContext, bundle_id: u64) -> Vec<u64> {
+        ctx.get(ISSUED).lock().bundles[&bundle_id]
+            .degarblers
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn issued_bundles_run_out() {
+        let (ctx, replier) = relay();
+        let anon_dest = AnonEndpoint::random();
+        let bundle =
+            issue_surb_bundle(&ctx, anon_dest, replier, 20, Duration::from_secs(60)).unwrap();
+        assert_eq!(bundle.reply_blocks.len(), 20);
+        let ids = rb_ids(&ctx, bundle.id);
+        for (used, rb_id) in ids.iter().enumerate() {
+            let degarbler = take_degarbler(&ctx, *rb_id).unwrap();
+            assert_eq!(degarbler.my_anon_id(), anon_dest);
+            let stock = &surb_bundles(&ctx).issued[0];
+            assert_eq!(stock.remaining, 20 - used - 1);
+        }
+        // every reply block works once
+        assert!(take_degarbler(&ctx, ids[0]).is_none());
+        let stats = ctx.get(STATS).snapshot();
+        assert_eq!(stats.get(SURB_BUNDLE_RECEIVED), Some(&20));
+        assert_eq!(stats.get(SURB_BUNDLE_REPLAYED), Some(&1));
+        assert_eq!(stats.get(SURB_BUNDLE_LOW), Some(&1));
+        // stray replies aren't replays
+        assert!(take_degarbler(&ctx, rand::random()).is_none());
+        assert_eq!(
+            ctx.get(STATS).snapshot().get(SURB_BUNDLE_REPLAYED),
+            Some(&1)
+        );
+    }
+
+    #[test]
+    fn expired_bundles_are_forgotten() {
+        let (ctx, replier) = relay();
+        let bundle =
+            issue_surb_bundle(&ctx, AnonEndpoint::random(), replier, 3, Duration::ZERO).unwrap();
+        let ids = rb_ids(&ctx, bundle.id);
+        assert!(take_degarbler(&ctx, ids[0]).is_none());
+        assert!(surb_bundles(&ctx).issued.is_empty());
+        assert_eq!(ctx.get(STATS).snapshot().get(SURB_BUNDLE_EXPIRED), Some(&1));
+        assert_eq!(
+            import_surb_bundle(&ctx, bundle.clone()),
+            Err(SurbBundleError::Expired(bundle.id))
+        );
+    }
+
+    #[test]
+    fn bundles_expire_when_the_clock_passes_their_ttl() {
+        let fake = FakeClock::new();
+        let (issuer, replier) = relay();
+        clock::set_clock(&issuer, Clock::Fake(fake.clone()));
+        let (sender, _) = relay();
+        clock::set_clock(&sender, Clock::Fake(fake.clone()));
+        let anon_dest = AnonEndpoint::random();
+        let bundle =
+            issue_surb_bundle(&issuer, anon_dest, replier, 2, Duration::from_secs(600)).unwrap();
+        import_surb_bundle(&sender, bundle.clone()).unwrap();
+        let ids = rb_ids(&issuer, bundle.id);
+
+        fake.advance(Duration::from_secs(599));
+        assert!(take_degarbler(&issuer, ids[0]).is_some());
+        assert!(pop_imported(&sender, &anon_dest).is_some());
+
+        fake.advance(Duration::from_secs(1));
+        assert!(take_degarbler(&issuer, ids[1]).is_none());
+        assert_eq!(
+            issuer.get(STATS).snapshot().get(SURB_BUNDLE_EXPIRED),
+            Some(&1)
+        );
+        assert!(pop_imported(&sender, &anon_dest).is_none());
+        assert!(surb_bundles(&sender).imported.is_empty());
+    }
+
+    #[test]
+    fn imported_bundles_run_out() {
+        let (issuer, replier) = relay();
+        let anon_dest = AnonEndpoint::random();
+        let bundle =
+            issue_surb_bundle(&issuer, anon_dest, replier, 5, Duration::from_secs(60)).unwrap();
+        let bundle = SurbBundle::from_bytes(&bundle.to_bytes()).unwrap();
+
+        let (sender, _) = relay();
+        import_surb_bundle(&sender, bundle.clone()).unwrap();
+        // importing the same bundle again would hand out reply blocks that may already be used
+        assert_eq!(
+            import_surb_bundle(&sender, bundle.clone()),
+            Err(SurbBundleError::AlreadyImported(bundle.id))
+        );
+        assert_eq!(surb_bundles(&sender).imported[0].remaining, 5);
+        for _ in 0..5 {
+            assert!(pop_imported(&sender, &anon_dest).is_some());
+        }
+        assert!(pop_imported(&sender, &anon_dest).is_none());
+        assert!(surb_bundles(&sender).imported.is_empty());
+        // even once used up
+        assert_eq!(
+            import_surb_bundle(&sender, bundle.clone()),
+            Err(SurbBundleError::AlreadyImported(bundle.id))
+        );
+    }
+
+    #[test]
+    fn bundles_survive_restart() {
+        let state_cache =
+            std::env::temp_dir().join(format!("earendil-surbs-{}.db", rand::random::<u64>()));
+        let config =
+            serde_json::json!({ "identity_seed": "surb_bundle", "state_cache": state_cache });
+        let anon_dest = AnonEndpoint::random();
+        let (ctx, replier) = relay_with(config.clone());
+        let bundle =
+            issue_surb_bundle(&ctx, anon_dest, replier, 3, Duration::from_secs(60)).unwrap();
+        import_surb_bundle(&ctx, bundle.clone()).unwrap();
+        let ids = rb_ids(&ctx, bundle.id);
+        assert!(take_degarbler(&ctx, ids[0]).is_some());
+        assert!(pop_imported(&ctx, &anon_dest).is_some());
+        let (issued, imported) = persisted_surb_bundles(&ctx);
+        smol::future::block_on(async {
+            db_write(&ctx, MiscKey::IssuedSurbBundles, issued).await?;
+            db_write(&ctx, MiscKey::ImportedSurbBundles, imported).await
+        })
+        .unwrap();
+        drop(ctx);
+
+        let (ctx, _) = relay_with(config);
+        // what was used before the restart stays used
+        assert!(take_degarbler(&ctx, ids[0]).is_none());
+        assert_eq!(
+            ctx.get(STATS).snapshot().get(SURB_BUNDLE_REPLAYED),
+            Some(&1)
+        );
+        assert!(take_degarbler(&ctx, ids[1]).is_some());
+        let bundles = surb_bundles(&ctx);
+        assert_eq!(bundles.issued[0].remaining, 1);
+        assert_eq!(bundles.imported[0].remaining, 2);
+        assert_eq!(
+            import_surb_bundle(&ctx, bundle.clone()),
+            Err(SurbBundleError::AlreadyImported(bundle.id))
+        );
+        drop(ctx);
+        let _ = std::fs::remove_file(state_cache);
+    }
+
+    #[test]
+    fn bundles_are_capped() {
+        let (issuer, replier) = relay();
+        let anon_dest = AnonEndpoint::random();
+        let ttl = Duration::from_secs(60);
+        for _ in 0..MAX_BUNDLES {
+            issue_surb_bundle(&issuer, anon_dest, replier, 1, ttl).unwrap();
+        }
+        let err = issue_surb_bundle(&issuer, anon_dest, replier, 1, ttl).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SurbBundleError>(),
+            Some(&SurbBundleError::TooMany)
+        );
+
+        // bundles we used up still count, since they're remembered to refuse them
+        let (sender, sender_replier) = relay();
+        let bundle = issue_surb_bundle(&sender, anon_dest, sender_replier, 1, ttl).unwrap();
+        for _ in 0..MAX_BUNDLES {
+            let copy = SurbBundle {
+                id: rand::random(),
+                ..bundle.clone()
+            };
+            import_surb_bundle(&sender, copy).unwrap();
+            assert!(pop_imported(&sender, &anon_dest).is_some());
+        }
+        assert_eq!(
+            import_surb_bundle(&sender, bundle),
+            Err(SurbBundleError::TooMany)
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use std::{collections::HashMap, ops::RangeInclusive, sync::Arc};
+
+use earendil_crypt::RelayFingerprint;
+use earendil_topology::RelayGraph;
+use moka::sync::Cache;
+use parking_lot::Mutex;
+use rand::seq::SliceRandom;
+
+use crate::{
+    context::{CtxField, DaemonContext, MY_RELAY_IDENTITY, RELAY_GRAPH},
+    stats::STATS,
+};
+
+use super::circuit::{CircuitToken, CIRCUIT_HOPS, CIRCUIT_LIFETIME, MAX_CIRCUITS};
+
+/// How many reply routes we keep to choose from, per replier and anchor.
+const MAX_CANDIDATES: usize = 32;
+
+/// How many path extensions one search may try, so that dense graphs don't make SURB creation slow.
+const MAX_SEARCH_STEPS: usize = 10_000;
+
+/// How much likelier the shortest candidates are to be picked than the ones a hop longer.
+const SHORTER_WEIGHT: u32 = 2;
+
+pub const SURB_ROUTES_NONE: &str = "surb_routes.candidates.none";
+pub const SURB_ROUTES_ONE: &str = "surb_routes.candidates.one";
+pub const SURB_ROUTES_FEW: &str = "surb_routes.candidates.few";
+pub const SURB_ROUTES_MANY: &str = "surb_routes.candidates.many";
+
+/// Candidate reply routes for each (replier, anchor) pair, computed for one graph generation and reused until the graph changes.
+static SURB_ROUTES: CtxField<Mutex<HashMap<(RelayFingerprint, RelayFingerprint), CandidateSet>>> =
+    |_| Mutex::new(HashMap::new());
+
+/// The middle hops each circuit picked last for each (replier, anchor) pair. A circuit's next pick avoids its own last one, and the first hops other circuits picked, so that circuits stay as isolated on the way back as they are on the way out.
+static LAST_PICKS: CtxField<
+    Cache<(CircuitToken, RelayFingerprint, RelayFingerprint), Vec<RelayFingerprint>>,
+> = |_| {
+    Cache::builder()
+        .max_capacity(MAX_CIRCUITS)
+        .time_to_live(CIRCUIT_LIFETIME)
+        .build()
+};
+
+struct CandidateSet {
+    generation: u64,
+    /// Each candidate is the relays strictly between the replier and the anchor.
+    routes: Arc<Vec<Vec<RelayFingerprint>>>,
+}
+
+/// Picks the middle hops of the given circuit's reply route from `replier` to `anchor`, at random among the graph paths within a hop of the shortest, so that reply routes don't single out whoever keeps issuing them. Returns `None` if the graph has no path between the two.
+pub(super) fn sample_middle_hops(
+    ctx: &DaemonContext,
+    replier: RelayFingerprint,
+    anchor: RelayFingerprint,
+    circuit: CircuitToken,
+) -> Option<Vec<RelayFingerprint>> {
+    if replier == anchor {
+        return None;
+    }
+    let routes = candidates(ctx, replier, anchor);
+    let last_picks = ctx.get(LAST_PICKS);
+    let last = last_picks.get(&(circuit, replier, anchor));
+    let taken: Vec<RelayFingerprint> = last_picks
+        .iter()
+        .filter(|(key, _)| key.0 != circuit && key.1 == replier && key.2 == anchor)
+        .filter_map(|(_, picked)| picked.first().copied())
+        .collect();
+    let picked = pick(&routes, last.as_deref(), &taken)?;
+    last_picks.insert((circuit, replier, anchor), picked.clone());
+    Some(picked)
+}
+
+/// Returns the candidate routes from `replier` to `anchor` for the current graph, searching for them only if the graph changed since we last did. The search runs without holding the lock, so that one slow search doesn't hold up every other SURB.
+fn candidates(
+    ctx: &DaemonContext,
+    replier: RelayFingerprint,
+    anchor: RelayFingerprint,
+) -> Arc<Vec<Vec<RelayFingerprint>>> {
+    let generation = ctx.get(RELAY_GRAPH).read().generation();
+    if let Some(set) = ctx.get(SURB_ROUTES).lock().get(&(replier, anchor)) {
+        if set.generation == generation {
+            return set.routes.clone();
+        }
+    }
+
+    let myself = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|identity| identity.public().fingerprint());
+    let (generation, routes) = {
+        let graph = ctx.get(RELAY_GRAPH).read();
+        (
+            graph.generation(),
+            Arc::new(find_candidates(&graph, replier, anchor, myself)),
+        )
+    };
+    ctx.get(STATS).incr(match routes.len() {
+        0 => SURB_ROUTES_NONE,
+        1 => SURB_ROUTES_ONE,
+        2..=7 => SURB_ROUTES_FEW,
+        _ => SURB_ROUTES_MANY,
+    });
+
+    let mut sets = ctx.get(SURB_ROUTES).lock();
+    // sets for older generations will never be used again
+    sets.retain(|_, set| set.generation >= generation);
+    let set = sets
+        .entry((replier, anchor))
+        .or_insert_with(|| CandidateSet {
+            generation,
+            routes: routes.clone(),
+        });
+    // a concurrent search may have gotten there first, possibly on a newer graph
+    if set.generation < generation {
+        *set = CandidateSet { generation, routes };
+    }
+    set.routes.clone()
+}
+
+/// Picks one of the candidates, weighted towards the shortest ones, and avoiding `last` and routes starting at a `taken` relay, unless that leaves nothing to pick.
+fn pick(
+    routes: &[Vec<RelayFingerprint>],
+    last: Option<&[RelayFingerprint]>,
+    taken: &[RelayFingerprint],
+) -> Option<Vec<RelayFingerprint>> {
+    let shortest = routes.iter().map(|route| route.len()).min()?;
+    let not_last = |route: &&Vec<RelayFingerprint>| Some(route.as_slice()) != last;
+    let untaken =
+        |route: &&Vec<RelayFingerprint>| route.first().map_or(true, |hop| !taken.contains(hop));
+    let mut choices: Vec<&Vec<RelayFingerprint>> =
+        routes.iter().filter(not_last).filter(untaken).collect();
+    if choices.is_empty() {
+        choices = routes.iter().filter(not_last).collect();
+    }
+    if choices.is_empty() {
+        choices = routes.iter().collect();
+    }
+    choices
+        .choose_weighted(&mut rand::thread_rng(), |route| {
+            if route.len() == shortest {
+                SHORTER_WEIGHT
+            } else {
+                1
+            }
+        })
+        .ok()
+        .map(|route| (*route).clone())
+}
+
+/// Finds up to [MAX_CANDIDATES] paths through the graph from `replier` to `anchor`, with at least [CIRCUIT_HOPS] relays in between and at most one more than the shortest such path. On graphs too small for that, falls back to the single shortest path.
+fn find_candidates(
+    graph: &RelayGraph,
+    replier: RelayFingerprint,
+    anchor: RelayFingerprint,
+    myself: Option<RelayFingerprint>,
+) -> Vec<Vec<RelayFingerprint>> {
+    let Some(shortest) = graph.find_shortest_path(&replier, &anchor) else {
+        return vec![];
+    };
+    let shortest_middle = &shortest[1..shortest.len() - 1];
+    let min_middle = shortest_middle.len().max(CIRCUIT_HOPS);
+    let mut search = Search {
+        graph,
+        anchor,
+        myself,
+        lengths: min_middle..=min_middle + 1,
+        found: vec![],
+        steps: 0,
+    };
+    search.extend(&mut vec![replier]);
+    if search.found.is_empty() {
+        return vec![shortest_middle.to_vec()];
+    }
+    search.found
+}
+
+/// A depth-first search for candidate paths, in random order, so that capping the search still leaves a random sample.
+struct Search<'a> {
+    graph: &'a RelayGraph,
+    anchor: RelayFingerprint,
+    myself: Option<RelayFingerprint>,
+    /// How many relays may be between the replier and the anchor.
+    lengths: RangeInclusive<usize>,
+    found: Vec<Vec<RelayFingerprint>>,
+    steps: usize,
+}
+
+impl Search<'_> {
+    fn extend(&mut self, path: &mut Vec<RelayFingerprint>) {
+        if self.found.len() >= MAX_CANDIDATES || self.steps >= MAX_SEARCH_STEPS {
+            return;
+        }
+        self.steps += 1;
+        let middle = path.len() - 1;
+        let Some(neighbors) = self.graph.neighbors(path.last().unwrap()) else {
+            return;
+        };
+        let mut neighbors: Vec<RelayFingerprint> = neighbors.collect();
+        neighbors.shuffle(&mut rand::thread_rng());
+        for next in neighbors {
+            if next == self.anchor {
+                if self.lengths.contains(&middle) && self.found.len() < MAX_CANDIDATES {
+                    self.found.push(path[1..].to_vec());
+                }
+            } else if middle < *self.lengths.end()
+                && !path.contains(&next)
+                && Some(next) != self.myself
+                // we need its onion key to route through it
+                && self.graph.identity(&next).is_some()
+            {
+                path.push(next);
+                self.extend(path);
+                path.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use earendil_crypt::RelayIdentitySecret;
+
+    use super::*;
+    use crate::{
+        ledger::unix_now,
+        test_util::{adjacency, known_relay},
+    };
+
+    fn connect(graph: &mut RelayGraph, a: &RelayIdentitySecret, b: &RelayIdentitySecret) {
+        graph.insert_adjacency(adjacency(a, b, unix_now())).unwrap();
+    }
+
+    fn new_relays(ctx: &DaemonContext, count: usize) -> Vec<RelayIdentitySecret> {
+        (0..count).map(|_| known_relay(ctx)).collect()
+    }
+
+    /// A replier and an anchor with two layers of three relays between them, fully connected layer to layer, so there are nine equally short paths.
+    fn layered_graph(ctx: &DaemonContext) -> (RelayFingerprint, RelayFingerprint) {
+        let relays = new_relays(ctx, 8);
+        let (replier, first, second, anchor) =
+            (&relays[0], &relays[1..4], &relays[4..7], &relays[7]);
+        let mut graph = ctx.get(RELAY_GRAPH).write();
+        for hop in first {
+            connect(&mut graph, replier, hop);
+            for next in second {
+                connect(&mut graph, hop, next);
+            }
+        }
+        for hop in second {
+            connect(&mut graph, hop, anchor);
+        }
+        (
+            replier.public().fingerprint(),
+            anchor.public().fingerprint(),
+        )
+    }
+
+    #[test]
+    fn middle_hops_are_spread_out() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let (replier, anchor) = layered_graph(&ctx);
+        let circuit = CircuitToken::new();
+
+        let picks: Vec<Vec<RelayFingerprint>> = (0..200)
+            .map(|_| sample_middle_hops(&ctx, replier, anchor, circuit).unwrap())
+            .collect();
+        assert!(picks.iter().all(|pick| pick.len() == 2));
+        let distinct: HashSet<&Vec<RelayFingerprint>> = picks.iter().collect();
+        assert_eq!(distinct.len(), 9);
+        for route in distinct {
+            let count = picks.iter().filter(|pick| *pick == route).count();
+            assert!(count < 60, "{count} of 200 picks were the same route");
+        }
+        assert!(picks.windows(2).all(|pair| pair[0] != pair[1]));
+        // and the candidates were only computed once
+        assert_eq!(ctx.get(STATS).snapshot()[SURB_ROUTES_MANY], 1);
+    }
+
+    #[test]
+    fn circuits_pick_apart() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let (replier, anchor) = layered_graph(&ctx);
+
+        // three circuits fit on the three first hops, and keep to their own while they take turns
+        let circuits = [(); 3].map(|_| CircuitToken::new());
+        for _ in 0..20 {
+            let first_hops: HashSet<RelayFingerprint> = circuits
+                .iter()
+                .map(|circuit| sample_middle_hops(&ctx, replier, anchor, *circuit).unwrap()[0])
+                .collect();
+            assert_eq!(first_hops.len(), 3);
+        }
+    }
+
+    #[test]
+    fn tiny_graphs_use_the_shortest_path() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let relays = new_relays(&ctx, 3);
+        {
+            let mut graph = ctx.get(RELAY_GRAPH).write();
+            connect(&mut graph, &relays[0], &relays[1]);
+            connect(&mut graph, &relays[1], &relays[2]);
+        }
+        let [replier, middle, anchor] = [0, 1, 2].map(|i| relays[i].public().fingerprint());
+        for _ in 0..5 {
+            assert_eq!(
+                sample_middle_hops(&ctx, replier, anchor, CircuitToken::new()),
+                Some(vec![middle])
+            );
+        }
+        assert_eq!(ctx.get(STATS).snapshot()[SURB_ROUTES_ONE], 1);
+
+        // with no path at all, there is nothing to sample
+        let stranger = new_relays(&ctx, 1)[0].public().fingerprint();
+        assert_eq!(
+            sample_middle_hops(&ctx, replier, stranger, CircuitToken::new()),
+            None
+        );
+    }
+}
@@ -2,22 +2,35 @@ use std::{
     fmt::{self, Display, Formatter},
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
+mod fallback;
 mod queues;
+mod sealed;
+pub mod shaper;
 use anyhow::Context;
 use bytes::Bytes;
 
 use earendil_crypt::{AnonEndpoint, RelayFingerprint};
 use earendil_packet::Dock;
 use serde::{Deserialize, Serialize};
-use smol::future::FutureExt as _;
+use smol::{channel::Receiver, future::FutureExt as _};
+use smol_timeout::TimeoutExt;
 
 use crate::{
-    context::{DaemonContext, MY_RELAY_IDENTITY},
-    n2r,
+    config::SendRateLimit,
+    context::{require_relay, DaemonContext, MY_RELAY_IDENTITY},
+    limits,
+    n2r::{self, CircuitToken, MessageClass, NoRoute, SurbBundle, SurbBundleError},
+    network::{self, NackReason},
 };
 
-use self::queues::{new_client_queue, new_relay_queue, QueueReceiver};
+pub(crate) use self::fallback::{fallback_haven_loop, fallback_identity};
+pub use self::sealed::*;
+use self::{
+    queues::{new_client_queue, new_relay_queue, QueueReceiver},
+    shaper::Shaper,
+};
 
 #[derive(Copy, Clone, Deserialize, Serialize, Hash, Debug, PartialEq, PartialOrd, Ord, Eq)]
 pub struct RelayEndpoint {
@@ -41,6 +54,11 @@ impl FromStr for RelayEndpoint {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("haven:") {
+            return Err(anyhow::anyhow!(
+                "{s:?} is a haven endpoint, not a relay endpoint"
+            ));
+        }
         let parts: Vec<&str> = s.split(':').collect();
         if parts.len() != 2 {
             return Err(anyhow::anyhow!("invalid relay endpoint format"));
@@ -60,9 +78,7 @@ pub struct N2rRelaySocket {
 
 impl N2rRelaySocket {
     pub fn bind(ctx: DaemonContext, dock: Option<Dock>) -> anyhow::Result<Self> {
-        if ctx.init().is_client() {
-            anyhow::bail!("cannot bind a relay socket on a client")
-        }
+        require_relay(&ctx, "binding a relay socket")?;
 
         let (dock, recv_incoming) = if let Some(dock) = dock {
             (dock, new_relay_queue(&ctx, dock)?)
@@ -93,6 +109,16 @@ impl N2rRelaySocket {
         Ok((message, source))
     }
 
+    /// Imports a [SurbBundle] issued to this relay, so that [N2rRelaySocket::send_to] can reach its endpoint even though the endpoint never sends anything. Any relay socket on this daemon can then send to it, until the bundle runs out or expires.
+    pub fn import_surb_bundle(&self, bundle: SurbBundle) -> Result<(), SurbBundleError> {
+        n2r::import_surb_bundle(&self.ctx, bundle)
+    }
+
+    /// The biggest payload [N2rRelaySocket::send_to] can send in one message. Bigger ones are refused, since N2R messages are never fragmented.
+    pub fn max_single_message(&self) -> usize {
+        limits::max_single_message(self.dock)
+    }
+
     pub fn local_endpoint(&self) -> RelayEndpoint {
         RelayEndpoint::new(
             self.ctx
@@ -109,47 +135,193 @@ impl N2rRelaySocket {
 pub struct N2rClientSocket {
     ctx: DaemonContext,
     endpoint: AnonEndpoint,
+    circuit: CircuitToken,
+    class: MessageClass,
+    /// Whether messages the relay graph has no route for may go through the destination's fallback haven.
+    fallback: bool,
     recv_incoming: Arc<QueueReceiver<(Bytes, RelayEndpoint)>>, // relays can only ever receive communication from clients
+    shaper: Arc<Shaper>,
 }
 
 impl N2rClientSocket {
+    /// Binds a socket on its own circuit, so that it shares no routes with any other socket.
     pub fn bind(ctx: DaemonContext, my_anon_id: AnonEndpoint) -> anyhow::Result<Self> {
+        Self::bind_with_circuit(ctx, my_anon_id, CircuitToken::new())
+    }
+
+    /// Binds a socket on the given circuit. Sockets on the same circuit may share routes, and so may be linkable by relays along them.
+    pub fn bind_with_circuit(
+        ctx: DaemonContext,
+        my_anon_id: AnonEndpoint,
+        circuit: CircuitToken,
+    ) -> anyhow::Result<Self> {
         let recv_incoming = new_client_queue(&ctx, my_anon_id)?;
+        let shaper = Shaper::register(&ctx, format!("n2r:{my_anon_id}"));
 
         Ok(N2rClientSocket {
             ctx,
             endpoint: my_anon_id,
+            circuit,
+            class: MessageClass::default(),
+            fallback: true,
             recv_incoming: Arc::new(recv_incoming),
+            shaper,
         })
     }
 
+    /// Sends this socket's messages as the given class, rather than as normal messages.
+    pub fn with_class(mut self, class: MessageClass) -> Self {
+        self.class = class;
+        self
+    }
+
+    /// Never sends this socket's messages through fallback havens, for the daemon's own sockets, which connecting to a fallback haven uses.
+    pub(crate) fn without_fallback(mut self) -> Self {
+        self.fallback = false;
+        self
+    }
+
+    /// The class this socket's messages are sent as.
+    pub fn class(&self) -> MessageClass {
+        self.class
+    }
+
+    /// Caps how fast this socket and its clones send, or lifts the cap given `None`. Sends over the cap wait their turn, and fail if that would take more than [shaper::MAX_SHAPING_WAIT]. Link pacing still applies underneath, so whichever is slower wins.
+    pub fn set_rate_limit(&self, limit: Option<SendRateLimit>) {
+        self.shaper.set_limit(limit);
+    }
+
+    pub fn rate_limit(&self) -> Option<SendRateLimit> {
+        self.shaper.limit()
+    }
+
+    /// What the control protocol knows this socket by, to adjust its rate limit.
+    pub fn socket_id(&self) -> u64 {
+        self.shaper.id()
+    }
+
+    /// Sends a message to `endpoint`. If the relay graph has no route there, but the destination serves a fallback haven, the message goes through the haven's rendezvous instead. The daemon's own messages never do.
     pub async fn send_to(&self, body: Bytes, endpoint: RelayEndpoint) -> anyhow::Result<()> {
+        self.shaper.wait(body.len()).await?;
+        let sent = n2r::send_forward(
+            &self.ctx,
+            self.endpoint,
+            endpoint.fingerprint,
+            endpoint.dock,
+            body.clone(),
+            self.circuit,
+            self.class,
+            None,
+        )
+        .await;
+        match sent {
+            Err(err)
+                if err.is::<NoRoute>()
+                    && self.fallback
+                    && fallback::may_fall_back(endpoint.dock) =>
+            {
+                tracing::debug!(
+                    endpoint = display(endpoint),
+                    "no route, falling back to the destination's rendezvous"
+                );
+                fallback::send_via_rendezvous(&self.ctx, self.endpoint, endpoint, body)
+                    .await
+                    .context(err)
+            }
+            sent => sent.context("n2r send_forward failed"),
+        }
+    }
+
+    /// Like [N2rClientSocket::send_to], but asks relays along the way to NACK the message if they drop it, returning where the NACK arrives. Returns no receiver if we asked for too many NACKs lately, in which case the message is sent without asking.
+    pub async fn send_to_nackable(
+        &self,
+        body: Bytes,
+        endpoint: RelayEndpoint,
+    ) -> anyhow::Result<Option<Receiver<NackReason>>> {
+        self.shaper.wait(body.len()).await?;
+        let (origin, nacks) = network::mark_local(&self.ctx).unzip();
         n2r::send_forward(
             &self.ctx,
             self.endpoint,
             endpoint.fingerprint,
             endpoint.dock,
             body,
+            self.circuit,
+            self.class,
+            origin,
         )
         .await
         .context("n2r send_forward failed")?;
-        Ok(())
+        Ok(nacks)
+    }
+
+    /// Sends a request to `dest` from a fresh anonymous endpoint, and returns the first reply `dest` sends back to it, failing if none comes within `timeout`. The endpoint acts as the request's nonce: every call has one of its own, which only its own replies are addressed to, so concurrent calls never get each other's replies. It's unbound once the call returns.
+    pub async fn send_and_recv(
+        ctx: &DaemonContext,
+        dest: RelayEndpoint,
+        content: Bytes,
+        timeout: Duration,
+    ) -> anyhow::Result<Bytes> {
+        let socket = Self::bind(ctx.clone(), AnonEndpoint::random())?;
+        // sending replenishes the reply blocks that the reply comes back on
+        socket.send_to(content, dest).await?;
+        async {
+            loop {
+                let (reply, source) = socket.recv_from().await?;
+                if source == dest {
+                    return anyhow::Ok(reply);
+                }
+                tracing::debug!(
+                    dest = display(dest),
+                    source = display(source),
+                    "ignoring a reply from somewhere other than where the request went"
+                );
+            }
+        }
+        .timeout(timeout)
+        .await
+        .with_context(|| format!("no reply from {dest} within {timeout:?}"))?
     }
 
     pub async fn supply_reply_blocks(&self, fingerprint: RelayFingerprint) -> anyhow::Result<()> {
-        n2r::replenish_remote_rb(&self.ctx, self.endpoint, fingerprint).await?;
+        n2r::replenish_remote_rb(&self.ctx, self.endpoint, fingerprint, self.circuit).await?;
         Ok(())
     }
 
+    /// Issues a bundle of `count` reply blocks to this socket, for the relay `replier` to send with after importing it. This lets the socket receive without ever sending, for as long as `ttl` and we keep a link to the SURB anchor, which is the only relay the replies pass through that we must be connected to.
+    pub fn issue_surb_bundle(
+        &self,
+        replier: RelayFingerprint,
+        count: usize,
+        ttl: Duration,
+    ) -> anyhow::Result<SurbBundle> {
+        n2r::issue_surb_bundle(&self.ctx, self.endpoint, replier, count, ttl)
+    }
+
     pub async fn recv_from(&self) -> anyhow::Result<(Bytes, RelayEndpoint)> {
         let (message, source) = self.recv_incoming.recv().await?;
 
         Ok((message, source))
     }
 
+    /// The biggest payload [N2rClientSocket::send_to] can send to `endpoint` in one message. Bigger ones are refused, since N2R messages are never fragmented.
+    pub fn max_single_message(&self, endpoint: RelayEndpoint) -> usize {
+        limits::max_single_message(endpoint.dock)
+    }
+
+    /// How big messages to `endpoint` may be, and how long the route this socket would send them along is.
+    pub fn transport_limits(&self, endpoint: RelayEndpoint) -> limits::TransportLimits {
+        limits::transport_limits(&self.ctx, endpoint, Some((self.circuit, self.class)))
+    }
+
     pub fn local_endpoint(&self) -> AnonEndpoint {
         self.endpoint
     }
+
+    /// The circuit this socket sends on.
+    pub fn circuit(&self) -> CircuitToken {
+        self.circuit
+    }
 }
 
 #[tracing::instrument(skip(ctx))]
@@ -162,7 +334,7 @@ pub async fn n2r_socket_shuttle(ctx: DaemonContext) -> anyhow::Result<()> {
                 dst_anon_ep = debug(dst_anon_ep),
                 "shuttling a backward msg"
             );
-            queues::fwd_to_client_queue(&ctx, msg_body, src_relay_ep, dst_anon_ep)?;
+            queues::fwd_to_client_queue(&ctx, msg_body, src_relay_ep, dst_anon_ep);
         }
     }
     .race(async {
@@ -173,7 +345,7 @@ pub async fn n2r_socket_shuttle(ctx: DaemonContext) -> anyhow::Result<()> {
                 dst_dock = debug(dst_dock),
                 "shuttling a forward msg"
             );
-            queues::fwd_to_relay_queue(&ctx, msg_body, src_anon_ep, dst_dock)?;
+            queues::fwd_to_relay_queue(&ctx, msg_body, src_anon_ep, dst_dock);
         }
     })
     .await
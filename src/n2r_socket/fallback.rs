@@ -0,0 +1,178 @@
+use std::{collections::HashSet, num::NonZeroUsize, sync::Arc};
+
+use anyhow::Context;
+use bytes::Bytes;
+use earendil_crypt::{AnonEndpoint, HavenIdentitySecret, RelayFingerprint, RelayIdentitySecret};
+use earendil_packet::Dock;
+use lru::LruCache;
+use nursery_macro::nursery;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+
+use crate::{
+    context::{require_relay, CtxField, DaemonContext, RELAY_GRAPH},
+    global_rpc::{GLOBAL_RPC_DOCK, GLOBAL_RPC_SEALED_DOCK},
+    haven::{HavenEndpoint, HavenListener, HavenPacketConn, HAVEN_FORWARD_DOCK},
+    stats::STATS,
+};
+
+use super::{queues::fwd_to_relay_queue, RelayEndpoint};
+
+/// The port relays serve their fallback haven on.
+const FALLBACK_PORT: u16 = 0;
+
+/// How many connections to fallback havens we keep open, one for each socket and destination that needed one lately.
+const FALLBACK_CONNS: usize = 64;
+
+pub const FALLBACK_SENT: &str = "n2r.fallback.sent";
+pub const FALLBACK_DELIVERED: &str = "n2r.fallback.delivered";
+pub const FALLBACK_MALFORMED: &str = "n2r.fallback.malformed";
+
+/// A message to a relay socket, carried over a connection to the relay's fallback haven rather than along an onion route.
+#[derive(Serialize, Deserialize)]
+struct FallbackMsg {
+    src: AnonEndpoint,
+    dock: Dock,
+    body: Bytes,
+}
+
+/// Open connections to fallback havens, by the socket that sends on each and its destination. Sockets never share one, so that the destination can't link them.
+static CONNS: CtxField<Mutex<LruCache<(AnonEndpoint, RelayFingerprint), Arc<HavenPacketConn>>>> =
+    |_| {
+        Mutex::new(LruCache::new(
+            NonZeroUsize::new(FALLBACK_CONNS).expect("must keep at least one connection"),
+        ))
+    };
+
+/// Connections to fallback havens being made, by the same keys as [CONNS]. A send that needs one already being made fails rather than making another, so nothing the connecting does can set off more of it.
+static CONNECTING: CtxField<Mutex<HashSet<(AnonEndpoint, RelayFingerprint)>>> =
+    |_| Mutex::new(HashSet::new());
+
+/// The haven identity a relay registers at its fallback rendezvous. It's derived from the relay identity rather than being it, so that nothing the haven signs could pass for something the relay signed; the relay's descriptor names the haven instead.
+pub(crate) fn fallback_identity(relay: &RelayIdentitySecret) -> HavenIdentitySecret {
+    HavenIdentitySecret::from_bytes(
+        blake3::keyed_hash(b"fallback_haven__________________", relay.as_bytes()).as_bytes(),
+    )
+}
+
+/// The docks of the daemon's own traffic, which never falls back: it's what connecting to a fallback haven is made of, so falling back on it could only go around in circles.
+const INTERNAL_DOCKS: [Dock; 3] = [GLOBAL_RPC_DOCK, GLOBAL_RPC_SEALED_DOCK, HAVEN_FORWARD_DOCK];
+
+/// Whether messages to `dock` may go through a fallback haven.
+pub fn may_fall_back(dock: Dock) -> bool {
+    !INTERNAL_DOCKS.contains(&dock)
+}
+
+/// Sends a message from the socket at `src` to `dest` through the rendezvous of the destination's fallback haven, for when the relay graph has no route to it. Fails if the destination's descriptor names no fallback haven, or if the same socket is already connecting to it.
+///
+/// Only the message gets there: replies still need a route back.
+pub async fn send_via_rendezvous(
+    ctx: &DaemonContext,
+    src: AnonEndpoint,
+    dest: RelayEndpoint,
+    body: Bytes,
+) -> anyhow::Result<()> {
+    let msg = FallbackMsg {
+        src,
+        dock: dest.dock,
+        body,
+    }
+    .stdcode();
+    let key = (src, dest.fingerprint);
+    let cached = ctx.get(CONNS).lock().get(&key).cloned();
+    let conn = match cached {
+        Some(conn) => conn,
+        None => {
+            let fingerprint = ctx
+                .get(RELAY_GRAPH)
+                .read()
+                .identity(&dest.fingerprint)
+                .and_then(|descr| descr.fallback_haven())
+                .with_context(|| format!("{} serves no fallback haven", dest.fingerprint))?;
+            if !ctx.get(CONNECTING).lock().insert(key) {
+                anyhow::bail!(
+                    "already connecting to the fallback haven of {}",
+                    dest.fingerprint
+                );
+            }
+            let _connecting = scopeguard::guard((), |_| {
+                ctx.get(CONNECTING).lock().remove(&key);
+            });
+            let haven = HavenEndpoint::new(fingerprint, FALLBACK_PORT);
+            let conn = HavenPacketConn::connect(ctx, haven)
+                .await
+                .with_context(|| format!("{} has no reachable fallback haven", dest.fingerprint))?;
+            tracing::debug!(
+                dest = display(dest.fingerprint),
+                "connected to a fallback haven"
+            );
+            let conn = Arc::new(conn);
+            ctx.get(CONNS).lock().put(key, conn.clone());
+            conn
+        }
+    };
+    if let Err(err) = conn.send_pkt(&msg).await {
+        // the connection is no good anymore, so the next message connects afresh
+        ctx.get(CONNS).lock().pop(&key);
+        return Err(err);
+    }
+    ctx.get(STATS).incr(FALLBACK_SENT);
+    Ok(())
+}
+
+/// Registers our fallback haven at the configured rendezvous relays, and delivers the messages that come in through it to our relay sockets.
+pub async fn fallback_haven_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let identity = fallback_identity(&require_relay(&ctx, "serving a fallback haven")?);
+    let listener = HavenListener::bind_multi(
+        &ctx,
+        identity,
+        FALLBACK_PORT,
+        ctx.init().fallback_rendezvous.clone(),
+    )
+    .await?;
+    nursery!({
+        loop {
+            let conn = listener.accept().await?;
+            spawn!(deliver_fallback(&ctx, conn)).detach();
+        }
+    })
+}
+
+/// Delivers the messages arriving on a connection to our fallback haven, until it closes.
+async fn deliver_fallback(ctx: &DaemonContext, conn: HavenPacketConn) -> anyhow::Result<()> {
+    loop {
+        let pkt = conn.recv_pkt().await?;
+        let Ok(msg) = stdcode::deserialize::<FallbackMsg>(&pkt) else {
+            ctx.get(STATS).incr(FALLBACK_MALFORMED);
+            continue;
+        };
+        ctx.get(STATS).incr(FALLBACK_DELIVERED);
+        fwd_to_relay_queue(ctx, msg.body, msg.src, msg.dock);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallback_havens_have_keys_of_their_own() {
+        let relay = RelayIdentitySecret::generate();
+        let haven = fallback_identity(&relay);
+        assert_ne!(haven.as_bytes(), relay.as_bytes());
+        // the same relay always serves the same haven, so its descriptor keeps naming the right one
+        assert_eq!(
+            fallback_identity(&relay).public().fingerprint(),
+            haven.public().fingerprint()
+        );
+    }
+
+    #[test]
+    fn internal_traffic_never_falls_back() {
+        for dock in [GLOBAL_RPC_DOCK, GLOBAL_RPC_SEALED_DOCK, HAVEN_FORWARD_DOCK] {
+            assert!(!may_fall_back(dock));
+        }
+        assert!(may_fall_back(42));
+    }
+}
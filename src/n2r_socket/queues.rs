@@ -1,13 +1,20 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    time::{Duration, Instant},
+};
 
-use anyhow::Context;
 use bytes::Bytes;
 
 use earendil_packet::Dock;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use smol::channel::{Receiver, Sender};
 
-use crate::context::{CtxField, DaemonContext};
+use crate::{
+    config::UnhandledPolicy,
+    context::{CtxField, DaemonContext},
+    stats::STATS,
+};
 
 use super::{AnonEndpoint, RelayEndpoint};
 
@@ -28,6 +35,18 @@ impl<T> Drop for QueueReceiver<T> {
     }
 }
 
+pub const UNHANDLED_DROPPED: &str = "n2r_socket.unhandled.dropped";
+pub const UNHANDLED_BUFFERED: &str = "n2r_socket.unhandled.buffered";
+pub const UNHANDLED_DELIVERED: &str = "n2r_socket.unhandled.delivered";
+
+/// Messages that arrived before any socket was bound to where they were going, oldest first. Only used under [UnhandledPolicy::Buffer].
+type Unhandled<K, T> = Mutex<VecDeque<(Instant, K, T)>>;
+
+static UNHANDLED_RELAY: CtxField<Unhandled<Dock, (Bytes, AnonEndpoint)>> = |_| Default::default();
+
+static UNHANDLED_CLIENT: CtxField<Unhandled<AnonEndpoint, (Bytes, RelayEndpoint)>> =
+    |_| Default::default();
+
 static RELAY_SOCKET_RECV_QUEUES: CtxField<RwLock<HashMap<Dock, Sender<(Bytes, AnonEndpoint)>>>> =
     |_| Default::default();
 
@@ -40,6 +59,10 @@ pub fn new_relay_queue(
     if queues.contains_key(&bind_to) {
         anyhow::bail!("dock {bind_to} is occupied")
     }
+    // still holding the lock, so that buffered messages come out before any new ones
+    for msg in take_unhandled(ctx, ctx.get(UNHANDLED_RELAY), &bind_to) {
+        let _ = send.try_send(msg);
+    }
     queues.insert(bind_to, send);
     let ctx = ctx.clone();
     Ok(QueueReceiver {
@@ -63,6 +86,9 @@ pub fn new_client_queue(
     if queues.contains_key(&bind_to) {
         anyhow::bail!("endpoint {bind_to} is occupied")
     }
+    for msg in take_unhandled(ctx, ctx.get(UNHANDLED_CLIENT), &bind_to) {
+        let _ = send.try_send(msg);
+    }
     queues.insert(bind_to, send);
     let ctx = ctx.clone();
     Ok(QueueReceiver {
@@ -73,31 +99,142 @@ pub fn new_client_queue(
     })
 }
 
-pub fn fwd_to_client_queue(
-    ctx: &DaemonContext,
-    msg: Bytes,
-    from: RelayEndpoint,
-    to: AnonEndpoint,
-) -> anyhow::Result<()> {
+/// Hands a message to the socket bound to `to`, or if there is none, deals with it as the configured [UnhandledPolicy] says.
+pub fn fwd_to_client_queue(ctx: &DaemonContext, msg: Bytes, from: RelayEndpoint, to: AnonEndpoint) {
     let queues = ctx.get(CLIENT_SOCKET_RECV_QUEUES).read();
-    let send_to = queues.get(&to).context(format!(
-        "cannot find socket bound to {to} among {:?}",
-        queues.keys().collect::<Vec<_>>()
-    ))?;
-    let _ = send_to.try_send((msg, from));
-    Ok(())
+    match queues.get(&to) {
+        Some(send_to) => {
+            let _ = send_to.try_send((msg, from));
+        }
+        None => unhandled(ctx, ctx.get(UNHANDLED_CLIENT), to, (msg, from)),
+    }
 }
 
-pub fn fwd_to_relay_queue(
-    ctx: &DaemonContext,
-    msg: Bytes,
-    from: AnonEndpoint,
-    to: Dock,
-) -> anyhow::Result<()> {
+/// Hands a message to the socket bound to the dock, or if there is none, deals with it as the configured [UnhandledPolicy] says.
+pub fn fwd_to_relay_queue(ctx: &DaemonContext, msg: Bytes, from: AnonEndpoint, to: Dock) {
     let queues = ctx.get(RELAY_SOCKET_RECV_QUEUES).read();
-    let send_to = queues
-        .get(&to)
-        .context(format!("cannot find socket bound to {to}"))?;
-    let _ = send_to.try_send((msg, from));
-    Ok(())
+    match queues.get(&to) {
+        Some(send_to) => {
+            let _ = send_to.try_send((msg, from));
+        }
+        None => unhandled(ctx, ctx.get(UNHANDLED_RELAY), to, (msg, from)),
+    }
+}
+
+fn unhandled<K: Display, T>(ctx: &DaemonContext, buffer: &Unhandled<K, T>, to: K, msg: T) {
+    match ctx.init().unhandled_messages {
+        UnhandledPolicy::Drop => ctx.get(STATS).incr(UNHANDLED_DROPPED),
+        UnhandledPolicy::Log => {
+            tracing::info!(
+                to = display(&to),
+                "dropping a message for an unbound socket"
+            );
+            ctx.get(STATS).incr(UNHANDLED_DROPPED)
+        }
+        UnhandledPolicy::Buffer {
+            max_messages,
+            ttl_secs,
+        } => {
+            let mut buffer = buffer.lock();
+            expire(ctx, &mut buffer, Duration::from_secs(ttl_secs));
+            if max_messages == 0 {
+                ctx.get(STATS).incr(UNHANDLED_DROPPED);
+                return;
+            }
+            while buffer.len() >= max_messages {
+                buffer.pop_front();
+                ctx.get(STATS).incr(UNHANDLED_DROPPED);
+            }
+            buffer.push_back((Instant::now(), to, msg));
+            ctx.get(STATS).incr(UNHANDLED_BUFFERED);
+        }
+    }
+}
+
+/// Takes the buffered messages for a socket that just bound, oldest first.
+fn take_unhandled<K: PartialEq, T>(
+    ctx: &DaemonContext,
+    buffer: &Unhandled<K, T>,
+    to: &K,
+) -> Vec<T> {
+    let mut buffer = buffer.lock();
+    match ctx.init().unhandled_messages {
+        UnhandledPolicy::Buffer { ttl_secs, .. } => {
+            expire(ctx, &mut buffer, Duration::from_secs(ttl_secs))
+        }
+        // the policy changed since the messages were buffered
+        _ => expire(ctx, &mut buffer, Duration::ZERO),
+    }
+    let (taken, kept): (VecDeque<_>, VecDeque<_>) =
+        buffer.drain(..).partition(|(_, key, _)| key == to);
+    *buffer = kept;
+    taken
+        .into_iter()
+        .map(|(_, _, msg)| {
+            ctx.get(STATS).incr(UNHANDLED_DELIVERED);
+            msg
+        })
+        .collect()
+}
+
+fn expire<K, T>(ctx: &DaemonContext, buffer: &mut VecDeque<(Instant, K, T)>, ttl: Duration) {
+    while buffer
+        .front()
+        .is_some_and(|(arrived, _, _)| arrived.elapsed() >= ttl)
+    {
+        buffer.pop_front();
+        ctx.get(STATS).incr(UNHANDLED_DROPPED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+
+    use super::*;
+
+    fn with_policy(policy: serde_json::Value) -> DaemonContext {
+        DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "unhandled_messages": policy })).unwrap(),
+        )
+    }
+
+    /// Sends `count` messages to an unbound endpoint, then binds it, returning what the new socket got.
+    fn send_then_bind(ctx: &DaemonContext, count: usize) -> Vec<Bytes> {
+        let to = AnonEndpoint::random();
+        let from = RelayEndpoint::new(RelayIdentitySecret::generate().public().fingerprint(), 1);
+        for i in 0..count {
+            fwd_to_client_queue(ctx, Bytes::from(i.to_string()), from, to);
+        }
+        let queue = new_client_queue(ctx, to).unwrap();
+        std::iter::from_fn(|| queue.inner.try_recv().ok())
+            .map(|(msg, _)| msg)
+            .collect()
+    }
+
+    #[test]
+    fn unhandled_messages_follow_the_policy() {
+        for policy in [serde_json::json!("drop"), serde_json::json!("log")] {
+            let ctx = with_policy(policy);
+            assert!(send_then_bind(&ctx, 5).is_empty());
+            assert_eq!(ctx.get(STATS).snapshot()[UNHANDLED_DROPPED], 5);
+            assert!(ctx.get(UNHANDLED_CLIENT).lock().is_empty());
+        }
+
+        // buffered messages reach a socket that binds late, but only the newest ones fit
+        let ctx =
+            with_policy(serde_json::json!({ "buffer": { "max_messages": 3, "ttl_secs": 60 } }));
+        assert_eq!(send_then_bind(&ctx, 1000), vec!["997", "998", "999"]);
+        let stats = ctx.get(STATS).snapshot();
+        assert_eq!(stats[UNHANDLED_DROPPED], 997);
+        assert_eq!(stats[UNHANDLED_DELIVERED], 3);
+        assert!(ctx.get(UNHANDLED_CLIENT).lock().is_empty());
+
+        // and only while they are fresh
+        let ctx =
+            with_policy(serde_json::json!({ "buffer": { "max_messages": 3, "ttl_secs": 0 } }));
+        assert!(send_then_bind(&ctx, 5).is_empty());
+        assert_eq!(ctx.get(STATS).snapshot()[UNHANDLED_DROPPED], 5);
+        assert!(ctx.get(UNHANDLED_CLIENT).lock().is_empty());
+    }
 }
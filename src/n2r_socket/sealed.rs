@@ -0,0 +1,195 @@
+use bytes::Bytes;
+use earendil_crypt::AnonEndpoint;
+use earendil_packet::crypt::{AeadError, AeadKey, DhPublic, DhSecret};
+
+use super::N2rRelaySocket;
+
+const SEALED_UP: &[u8] = b"sealed-up";
+const SEALED_DN: &[u8] = b"sealed-dn";
+
+/// Number of bytes a sealed envelope adds on top of the payload: an ephemeral public key, a nonce, and an AEAD tag.
+///
+/// N2R messages are not fragmented, so applications must keep their payloads this much smaller than they otherwise would.
+pub const SEALED_OVERHEAD: usize = 32 + 12 + 16;
+
+/// Seals messages end-to-end to a service's long-term X25519 public key, on top of the onion encryption that only protects them in transit.
+///
+/// The envelope identifies nothing about the sender: every message uses a fresh ephemeral keypair, so the anonymity of the underlying N2R socket is preserved.
+pub struct SealedSender;
+
+impl SealedSender {
+    /// Seals a payload for the owner of `service_pk`. Returns the envelope, and a key that opens the service's replies to this particular envelope.
+    pub fn seal(service_pk: &DhPublic, payload: &[u8]) -> (Bytes, SealedReplyKey) {
+        let my_esk = DhSecret::generate();
+        let (up_key, down_key) = derive_keys(&my_esk.shared_secret(service_pk));
+        let mut envelope = my_esk.public().as_bytes().to_vec();
+        envelope.extend_from_slice(&seal_with_random_nonce(&up_key, payload));
+        (
+            envelope.into(),
+            SealedReplyKey {
+                send_key: up_key,
+                recv_key: down_key,
+            },
+        )
+    }
+}
+
+/// The service side of [SealedSender], holding the service's long-term secret key.
+#[derive(Clone)]
+pub struct SealedReceiver {
+    service_sk: DhSecret,
+}
+
+impl SealedReceiver {
+    pub fn new(service_sk: DhSecret) -> Self {
+        Self { service_sk }
+    }
+
+    /// The public key that senders should seal their messages to.
+    pub fn public(&self) -> DhPublic {
+        self.service_sk.public()
+    }
+
+    /// Opens an envelope produced by [SealedSender::seal], failing if it was tampered with or sealed to a different key. Returns the payload, and a key for replying to the sender.
+    pub fn open(&self, envelope: &[u8]) -> Result<(Bytes, SealedReplyKey), AeadError> {
+        if envelope.len() < SEALED_OVERHEAD {
+            return Err(AeadError::DecryptionFailed);
+        }
+        let their_epk = DhPublic::from_bytes(envelope[..32].try_into().unwrap());
+        let (up_key, down_key) = derive_keys(&self.service_sk.shared_secret(&their_epk));
+        let payload = open_with_nonce(&up_key, &envelope[32..])?;
+        Ok((
+            payload,
+            SealedReplyKey {
+                send_key: down_key,
+                recv_key: up_key,
+            },
+        ))
+    }
+}
+
+/// Keys for the replies to one sealed envelope, in whichever direction the holder is talking.
+#[derive(Clone)]
+pub struct SealedReplyKey {
+    send_key: AeadKey,
+    recv_key: AeadKey,
+}
+
+impl SealedReplyKey {
+    /// Seals a message to the other side.
+    pub fn seal(&self, payload: &[u8]) -> Bytes {
+        seal_with_random_nonce(&self.send_key, payload).into()
+    }
+
+    /// Opens a message from the other side.
+    pub fn open(&self, ctext: &[u8]) -> Result<Bytes, AeadError> {
+        open_with_nonce(&self.recv_key, ctext)
+    }
+}
+
+/// A relay socket whose dock only accepts sealed messages, handing out already-decrypted payloads.
+pub struct SealedRelaySocket {
+    inner: N2rRelaySocket,
+    receiver: SealedReceiver,
+}
+
+impl SealedRelaySocket {
+    pub fn new(inner: N2rRelaySocket, receiver: SealedReceiver) -> Self {
+        Self { inner, receiver }
+    }
+
+    /// Receives the next message that opens correctly, silently dropping anything that doesn't.
+    pub async fn recv_from(&self) -> anyhow::Result<(Bytes, AnonEndpoint, SealedReplyKey)> {
+        loop {
+            let (envelope, endpoint) = self.inner.recv_from().await?;
+            match self.receiver.open(&envelope) {
+                Ok((payload, reply_key)) => return Ok((payload, endpoint, reply_key)),
+                Err(err) => {
+                    tracing::debug!(
+                        endpoint = debug(endpoint),
+                        err = debug(err),
+                        "dropping sealed message that fails to open"
+                    )
+                }
+            }
+        }
+    }
+
+    /// Sends a sealed reply to the sender of a previously received message.
+    pub async fn send_to(
+        &self,
+        body: &[u8],
+        endpoint: AnonEndpoint,
+        reply_key: &SealedReplyKey,
+    ) -> anyhow::Result<()> {
+        self.inner.send_to(reply_key.seal(body), endpoint).await
+    }
+}
+
+fn derive_keys(shared_sec: &[u8; 32]) -> (AeadKey, AeadKey) {
+    let up_key = AeadKey::from_bytes(
+        blake3::keyed_hash(blake3::hash(SEALED_UP).as_bytes(), shared_sec).as_bytes(),
+    );
+    let down_key = AeadKey::from_bytes(
+        blake3::keyed_hash(blake3::hash(SEALED_DN).as_bytes(), shared_sec).as_bytes(),
+    );
+    (up_key, down_key)
+}
+
+// keys may be used for more than one message (e.g. retransmitted requests, multiple replies), so nonces are random rather than fixed
+fn seal_with_random_nonce(key: &AeadKey, payload: &[u8]) -> Vec<u8> {
+    let nonce: [u8; 12] = rand::random();
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&key.seal(&nonce, payload));
+    out
+}
+
+fn open_with_nonce(key: &AeadKey, ctext: &[u8]) -> Result<Bytes, AeadError> {
+    if ctext.len() < 12 {
+        return Err(AeadError::DecryptionFailed);
+    }
+    Ok(key
+        .open(ctext[..12].try_into().unwrap(), &ctext[12..])?
+        .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_round_trip() {
+        let receiver = SealedReceiver::new(DhSecret::generate());
+        let (envelope, client_key) = SealedSender::seal(&receiver.public(), b"hello service");
+        assert_eq!(envelope.len(), b"hello service".len() + SEALED_OVERHEAD);
+
+        let (payload, service_key) = receiver.open(&envelope).unwrap();
+        assert_eq!(&payload[..], b"hello service");
+
+        let reply = service_key.seal(b"hello client");
+        assert_eq!(&client_key.open(&reply).unwrap()[..], b"hello client");
+    }
+
+    #[test]
+    fn sealed_wrong_key() {
+        let receiver = SealedReceiver::new(DhSecret::generate());
+        let other = SealedReceiver::new(DhSecret::generate());
+        let (envelope, _) = SealedSender::seal(&receiver.public(), b"for someone else");
+        assert!(other.open(&envelope).is_err());
+    }
+
+    #[test]
+    fn sealed_tampered() {
+        let receiver = SealedReceiver::new(DhSecret::generate());
+        let (envelope, client_key) = SealedSender::seal(&receiver.public(), b"integrity");
+        let mut tampered = envelope.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(receiver.open(&tampered).is_err());
+        assert!(receiver.open(&envelope[..20]).is_err());
+
+        let (_, service_key) = receiver.open(&envelope).unwrap();
+        let mut reply = service_key.seal(b"reply").to_vec();
+        reply[15] ^= 1;
+        assert!(client_key.open(&reply).is_err());
+    }
+}
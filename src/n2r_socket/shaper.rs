@@ -0,0 +1,241 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::SendRateLimit,
+    context::{CtxField, DaemonContext},
+};
+
+/// The longest a send waits for its socket's rate limit before failing. A sender that would wait longer is sending faster than its limit for good, so it's told so rather than having its messages pile up.
+pub const MAX_SHAPING_WAIT: Duration = Duration::from_secs(2);
+
+/// About how long the observed send rate is averaged over.
+const OBSERVED_WINDOW: Duration = Duration::from_secs(2);
+
+static SHAPERS: CtxField<DashMap<u64, Weak<Shaper>>> = |_| DashMap::new();
+
+static NEXT_SHAPER_ID: CtxField<AtomicU64> = |_| AtomicU64::new(1);
+
+/// A socket whose sending can be rate limited, as [socket_info] reports it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SocketInfo {
+    /// What [set_socket_rate_limit] knows the socket by.
+    pub id: u64,
+    /// What the socket is, like `n2r:<endpoint>` or `haven:<fingerprint>`.
+    pub name: String,
+    pub rate_limit: Option<SendRateLimit>,
+    /// About how many bytes per second the socket sent lately.
+    pub observed_bytes_per_sec: u64,
+}
+
+/// Limits how fast a socket sends with a token bucket, and keeps track of how fast it actually sends. Clones of a socket share one.
+pub struct Shaper {
+    ctx: DaemonContext,
+    id: u64,
+    name: String,
+    state: Mutex<ShaperState>,
+}
+
+struct ShaperState {
+    limit: Option<SendRateLimit>,
+    /// Bytes that may be sent right now. Negative when sends are waiting for their turn.
+    tokens: f64,
+    refilled: Instant,
+    /// Bytes sent, decaying over [OBSERVED_WINDOW].
+    observed: f64,
+    observed_at: Instant,
+}
+
+impl ShaperState {
+    fn refill(&mut self, limit: &SendRateLimit, now: Instant) {
+        let refilled =
+            now.saturating_duration_since(self.refilled).as_secs_f64() * limit.bytes_per_sec as f64;
+        self.tokens = (self.tokens + refilled).min(limit.burst_bytes as f64);
+        self.refilled = now;
+    }
+
+    fn decay(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.observed_at);
+        self.observed *= (-elapsed.as_secs_f64() / OBSERVED_WINDOW.as_secs_f64()).exp();
+        self.observed_at = now;
+    }
+}
+
+impl Shaper {
+    /// Creates a shaper without a limit, which the control protocol can find by its id for as long as it lives.
+    pub fn register(ctx: &DaemonContext, name: String) -> Arc<Self> {
+        let now = Instant::now();
+        let shaper = Arc::new(Self {
+            ctx: ctx.clone(),
+            id: ctx.get(NEXT_SHAPER_ID).fetch_add(1, Ordering::Relaxed),
+            name,
+            state: Mutex::new(ShaperState {
+                limit: None,
+                tokens: 0.0,
+                refilled: now,
+                observed: 0.0,
+                observed_at: now,
+            }),
+        });
+        ctx.get(SHAPERS).insert(shaper.id, Arc::downgrade(&shaper));
+        shaper
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Replaces the limit, or lifts it given `None`. A new limit starts out with a full burst.
+    pub fn set_limit(&self, limit: Option<SendRateLimit>) {
+        let mut state = self.state.lock();
+        state.limit = limit;
+        state.tokens = limit.map_or(0.0, |limit| limit.burst_bytes as f64);
+        state.refilled = Instant::now();
+    }
+
+    pub fn limit(&self) -> Option<SendRateLimit> {
+        self.state.lock().limit
+    }
+
+    /// About how many bytes per second went through [Shaper::wait] lately.
+    pub fn observed_bytes_per_sec(&self) -> u64 {
+        let mut state = self.state.lock();
+        state.decay(Instant::now());
+        (state.observed / OBSERVED_WINDOW.as_secs_f64()) as u64
+    }
+
+    /// Waits until `len` more bytes may be sent under the limit, failing instead if that would take longer than [MAX_SHAPING_WAIT]. Concurrent senders take turns in the order they called this.
+    pub async fn wait(&self, len: usize) -> anyhow::Result<()> {
+        let wait = {
+            let mut state = self.state.lock();
+            let now = Instant::now();
+            state.decay(now);
+            let wait = if let Some(limit) = state.limit {
+                state.refill(&limit, now);
+                let deficit = (len as f64 - state.tokens).max(0.0);
+                let wait = Duration::try_from_secs_f64(deficit / limit.bytes_per_sec as f64)
+                    .unwrap_or(Duration::MAX);
+                if wait > MAX_SHAPING_WAIT {
+                    anyhow::bail!(
+                        "{} is sending faster than its limit of {} bytes/sec",
+                        self.name,
+                        limit.bytes_per_sec
+                    );
+                }
+                // taking the tokens before waiting for them puts later sends behind this one
+                state.tokens -= len as f64;
+                wait
+            } else {
+                Duration::ZERO
+            };
+            state.observed += len as f64;
+            wait
+        };
+        if !wait.is_zero() {
+            smol::Timer::after(wait).await;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Shaper {
+    fn drop(&mut self) {
+        self.ctx.get(SHAPERS).remove(&self.id);
+    }
+}
+
+/// Every socket that can be rate limited, with its limit and how fast it sent lately, by id.
+pub fn socket_info(ctx: &DaemonContext) -> Vec<SocketInfo> {
+    // upgrading holds the shapers past the iteration, since dropping the last one removes it from the map
+    let shapers: Vec<Arc<Shaper>> = ctx
+        .get(SHAPERS)
+        .iter()
+        .filter_map(|entry| entry.value().upgrade())
+        .collect();
+    let mut info: Vec<SocketInfo> = shapers
+        .iter()
+        .map(|shaper| SocketInfo {
+            id: shaper.id,
+            name: shaper.name.clone(),
+            rate_limit: shaper.limit(),
+            observed_bytes_per_sec: shaper.observed_bytes_per_sec(),
+        })
+        .collect();
+    info.sort_unstable_by_key(|info| info.id);
+    info
+}
+
+/// Replaces the rate limit of the socket with the given id, returning whether there is one.
+pub fn set_socket_rate_limit(ctx: &DaemonContext, id: u64, limit: Option<SendRateLimit>) -> bool {
+    let shaper = ctx
+        .get(SHAPERS)
+        .get(&id)
+        .and_then(|entry| entry.value().upgrade());
+    match shaper {
+        Some(shaper) => {
+            shaper.set_limit(limit);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limited_sockets_send_at_their_limit() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let limited = Shaper::register(&ctx, "limited".into());
+        let unlimited = Shaper::register(&ctx, "unlimited".into());
+        let limit = SendRateLimit {
+            bytes_per_sec: 20_000,
+            burst_bytes: 2_000,
+        };
+        assert!(set_socket_rate_limit(&ctx, limited.id(), Some(limit)));
+
+        smol::future::block_on(async {
+            let start = Instant::now();
+            let mut sent = 0;
+            while start.elapsed() < Duration::from_secs(3) {
+                limited.wait(1_000).await.unwrap();
+                sent += 1_000;
+            }
+            let rate = sent as f64 / start.elapsed().as_secs_f64();
+            assert!(
+                (rate - 20_000.0).abs() < 20_000.0 * 0.15,
+                "sent at {rate} bytes/sec"
+            );
+
+            // the other socket on the same node doesn't wait at all
+            let start = Instant::now();
+            for _ in 0..1_000 {
+                unlimited.wait(1_000).await.unwrap();
+            }
+            assert!(start.elapsed() < Duration::from_millis(100));
+        });
+
+        let info = socket_info(&ctx);
+        assert_eq!(info.len(), 2);
+        assert_eq!(info[0].rate_limit, Some(limit));
+        assert!(info[0].observed_bytes_per_sec > 0);
+        assert_eq!(info[1].rate_limit, None);
+
+        // a send that would wait too long is refused rather than queued
+        assert!(smol::future::block_on(limited.wait(100_000)).is_err());
+
+        drop(limited);
+        assert_eq!(socket_info(&ctx).len(), 1);
+        assert!(!set_socket_rate_limit(&ctx, 1, None));
+    }
+}
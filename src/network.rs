@@ -1,20 +1,63 @@
+mod client_limit;
+mod delay_queue;
+mod drop_report;
+mod latency;
+mod nack;
+mod ordered;
+mod overload;
+mod probe;
+mod send_limit;
 mod spider;
 
-use std::time::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use async_recursion::async_recursion;
 use dashmap::DashSet;
 use earendil_crypt::{ClientId, RelayFingerprint};
 use earendil_packet::{PeeledPacket, RawBody, RawPacket};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use smol::channel::Receiver;
 
 use crate::{
-    context::{CtxField, DaemonContext, MY_RELAY_IDENTITY, MY_RELAY_ONION_SK, RELAY_GRAPH},
+    clock,
+    context::{
+        is_client, require_relay, CtxField, DaemonContext, MY_RELAY_IDENTITY, MY_RELAY_ONION_SK,
+        RELAY_GRAPH,
+    },
     n2r,
+    scope::{Stage, TaskKind},
+    stats::STATS,
+    usage,
+};
+
+pub use self::client_limit::{forget_client, within_client_rate_limit};
+pub use self::delay_queue::delay_queue_stats;
+pub use self::drop_report::{
+    disable_drop_reports, enable_drop_reports, incoming_drop_reports, observed_drops, report_drop,
+    subscribe_drop_reports, DropReason, DropReport, DropReportStream, DropReports, ObservedDrop,
+    SentPackets,
+};
+pub use self::latency::{forwarding_latency, mark_ingress, ClassLatency};
+pub use self::nack::{
+    dropped, incoming_nack, mark_local, subscribe_nacks, NackOrigin, NackReason, NeighborId,
+};
+pub use self::overload::{is_overloaded, load_state, LoadLevel, LoadState};
+pub use self::probe::{route_learned, wanted_probes};
+pub use self::send_limit::{send_concurrency, tracked_destinations, SendConcurrency};
+use self::{
+    delay_queue::{Delayed, DELAY_QUEUE},
+    latency::{record_egress, take_ingress, TrafficClass},
+    send_limit::SendPermit,
+    spider::Spider,
 };
 
-use self::spider::Spider;
+/// Packets we dropped because we already saw the very same packet, such as the second copy under redundant forwarding.
+pub const DUPLICATES_DROPPED: &str = "forwarding.duplicates_dropped";
 
 /// Dumps a raw packet onto the network with its next peeler, trying our best to have it go in the right direction.
 pub async fn send_raw(
@@ -22,59 +65,161 @@ pub async fn send_raw(
     packet: RawPacket,
     next_peeler: RelayFingerprint,
 ) -> anyhow::Result<()> {
-    if ctx.init().is_client() {
-        let next_hop = one_hop_closer(ctx, next_peeler).context("failed to get next hop")?;
-        ctx.get(RELAY_SPIDER)
-            .send(&next_hop, (packet, next_peeler))
+    send_raw_nackable(ctx, packet, next_peeler, None).await
+}
+
+/// Like [send_raw], but if `origin` is given, the packet is NACK-eligible: relays that drop it on its way send a NACK back to the origin.
+pub async fn send_raw_nackable(
+    ctx: &DaemonContext,
+    packet: RawPacket,
+    next_peeler: RelayFingerprint,
+    origin: Option<NackOrigin>,
+) -> anyhow::Result<()> {
+    if is_client(ctx) {
+        let next_hop = match next_hop_toward(ctx, next_peeler)
+            .await
+            .context("failed to get next hop")
+        {
+            Ok(next_hop) => next_hop,
+            Err(err) => {
+                nack::dropped(ctx, origin, NackReason::Unroutable);
+                return Err(err);
+            }
+        };
+        forward_to_neigh(ctx, next_hop, packet, next_peeler, None, origin)
             .context(format!("failed to send packet to next hop {next_hop}"))?;
     } else {
-        let my_fp = ctx
-            .get(MY_RELAY_IDENTITY)
-            .expect("only relays have global identities")
+        let my_fp = require_relay(ctx, "peeling packets")?
             .public()
             .fingerprint();
 
         if next_peeler == my_fp {
             // todo: don't allow ourselves to be the first hop when choosing forward routes
-            if let Err(e) = incoming_raw(ctx, next_peeler, packet).await {
+            if let Err(e) = incoming_raw(ctx, next_peeler, packet, None, origin).await {
                 anyhow::bail!("incoming_raw failed with: {e}")
             }
         } else {
-            let next_hop = one_hop_closer(ctx, next_peeler)?;
-            match ctx.get(RELAY_SPIDER).send(&next_hop, (packet, next_peeler)) {
-                Ok(_) => (),
-                Err(e) => {
-                    let relays = ctx.get(RELAY_SPIDER).keys();
-                    println!("network.rs 48: RELAY_SPIDER: {:?}", relays);
-                    anyhow::bail!(e)
+            let next_hop = match next_hop_toward(ctx, next_peeler).await {
+                Ok(next_hop) => next_hop,
+                Err(err) => {
+                    nack::dropped(ctx, origin, NackReason::Unroutable);
+                    return Err(err);
                 }
+            };
+            if let Err(e) = forward_to_neigh(ctx, next_hop, packet, next_peeler, None, origin) {
+                let relays = ctx.get(RELAY_SPIDER).keys();
+                println!("network.rs 48: RELAY_SPIDER: {:?}", relays);
+                anyhow::bail!(e)
             }
         }
     }
     Ok(())
 }
 
-#[tracing::instrument(skip(ctx, pkt), fields(packet_hash=debug(blake3::hash(bytemuck::bytes_of(&pkt)))))]
+/// Moves delayed packets to and from the spill file, if there is one.
+pub async fn delay_spill_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    ctx.get(DELAY_QUEUE)
+        .spill_loop(ctx.get(STATS), &clock::clock(&ctx))
+        .await;
+    Ok(())
+}
+
+/// Sends peeled packets on towards their next peelers as their mix delays run out.
+pub async fn delay_queue_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let sends = TaskKind::new(&ctx, Stage::Inflight, "delayed_send");
+    loop {
+        let delayed = ctx
+            .get(DELAY_QUEUE)
+            .pop(ctx.get(STATS), &clock::clock(&ctx), || {
+                clock::notice_jumps(&ctx)
+            })
+            .await;
+        if ctx.init().ordered_forwarding {
+            ordered::enqueue(&ctx, delayed);
+        } else {
+            // waiting here rather than in the task keeps a stalled destination from piling up tasks
+            let permit = send_limit::acquire_send(&ctx, delayed.next_peeler).await;
+            sends
+                .spawn(&ctx, {
+                    let ctx = ctx.clone();
+                    async move { send_delayed(&ctx, delayed, permit).await }
+                })
+                .detach();
+        }
+    }
+}
+
+/// Sends a packet whose mix delay ran out on towards its next peeler, under a permit for sending to it.
+async fn send_delayed(
+    ctx: &DaemonContext,
+    delayed: Delayed,
+    _permit: SendPermit,
+) -> anyhow::Result<()> {
+    let delay = delayed.queued.elapsed();
+    let next_peeler = delayed.next_peeler;
+    if let Err(e) = send_raw_nackable(ctx, delayed.pkt, next_peeler, delayed.origin).await {
+        anyhow::bail!("failed to forward a delayed packet to next_peeler = {next_peeler}: {e}");
+    }
+    if let Some(ingress) = delayed.ingress {
+        record_egress(ctx, TrafficClass::Peeled, ingress, delay);
+    }
+    Ok(())
+}
+
+/// Hands a packet to the link to a neighboring relay, under a fresh NACK tag if it is NACK-eligible. If there is no such link, the packet is dropped, and NACKed. `prev_hop` is the relay the very same packet came from, if any.
+fn forward_to_neigh(
+    ctx: &DaemonContext,
+    next_hop: RelayFingerprint,
+    packet: RawPacket,
+    next_peeler: RelayFingerprint,
+    prev_hop: Option<RelayFingerprint>,
+    origin: Option<NackOrigin>,
+) -> anyhow::Result<()> {
+    let tag = origin.map(|origin| nack::forwarded(ctx, next_hop, origin));
+    if let Err(err) = ctx
+        .get(RELAY_SPIDER)
+        .send(&next_hop, (packet, next_peeler, tag))
+    {
+        if let Some(tag) = tag {
+            nack::unforward(ctx, tag, NackReason::Unroutable);
+        }
+        return Err(err);
+    }
+    send_redundant(ctx, packet, next_peeler, next_hop, prev_hop);
+    Ok(())
+}
+
+/// Processes a packet that arrived at this relay. `prev_hop` is the relay it came from, if it came from one rather than from our own direct clients or from us; packets we would merely forward may only be shed under overload if it did. If the packet is NACK-eligible, `origin` says where to send a NACK if we drop it for a routing or admission reason.
+#[tracing::instrument(skip(ctx, pkt, origin), fields(packet_hash=debug(blake3::hash(bytemuck::bytes_of(&pkt)))))]
 #[async_recursion]
 pub async fn incoming_raw(
     ctx: &DaemonContext,
     next_peeler: RelayFingerprint,
     pkt: RawPacket,
+    prev_hop: Option<RelayFingerprint>,
+    origin: Option<NackOrigin>,
 ) -> anyhow::Result<()> {
     tracing::trace!("incoming raw packet!");
+    let sheddable = prev_hop.is_some();
+    let _backlogged = overload::enter_backlog(ctx);
     static PKTS_SEEN: CtxField<DashSet<blake3::Hash>> = |_| DashSet::new();
 
-    let my_fp = ctx
-        .get(MY_RELAY_IDENTITY)
-        .expect("only relays have global identities")
+    let my_fp = require_relay(ctx, "peeling packets")?
         .public()
         .fingerprint();
 
     let pkts_seen = ctx.get(PKTS_SEEN);
     let packet_hash = blake3::hash(bytemuck::bytes_of(&pkt));
     if !pkts_seen.insert(packet_hash) {
-        anyhow::bail!("received replayed pkt {packet_hash}");
+        // with redundant forwarding, a second copy of every packet is expected, and goes no further
+        ctx.get(STATS).incr(DUPLICATES_DROPPED);
+        tracing::trace!(
+            packet_hash = display(packet_hash),
+            "dropping a replayed packet"
+        );
+        return Ok(());
     }
+    let ingress = take_ingress(ctx, packet_hash);
 
     tracing::trace!(my_fp = my_fp.to_string(), "on raw packet");
 
@@ -94,18 +239,24 @@ pub async fn incoming_raw(
                 pkt,
                 delay_ms,
             } => {
-                let emit_time = Instant::now() + Duration::from_millis(delay_ms as u64);
-                // TODO delay queue here rather than this inefficient approach
-                let ctx = ctx.clone();
-                smolscale::spawn(async move {
-                    smol::Timer::at(emit_time).await;
-                    if let Err(e) = send_raw(&ctx, pkt, next_peeler).await {
-                        println!("network.rs line 102 failed with next_peeler = {next_peeler}, err = {e}");
-                        anyhow::bail!(e)
-                    }
-                    anyhow::Ok(())
-                })
-                .detach();
+                if sheddable && overload::shed_transit(ctx) {
+                    tracing::trace!("shedding a peeled packet under overload");
+                    nack::dropped(ctx, origin, NackReason::Overloaded);
+                    return Ok(());
+                }
+                let clock = clock::clock(ctx);
+                let emit_time = clock.now() + Duration::from_millis(delay_ms as u64);
+                ctx.get(DELAY_QUEUE).insert(
+                    Delayed {
+                        pkt,
+                        next_peeler,
+                        origin,
+                        ingress,
+                        queued: Instant::now(),
+                    },
+                    emit_time,
+                );
+                usage::count_transit(ctx);
             }
             PeeledPacket::Received { from, pkt } => {
                 if let Err(e) = n2r::incoming_forward(ctx, pkt, from).await {
@@ -113,6 +264,7 @@ pub async fn incoming_raw(
                         "PeelPacket::Received called n2r::incoming_forward failed with: {e}"
                     )
                 }
+                usage::count_terminated(ctx);
             }
             PeeledPacket::GarbledReply {
                 rb_id,
@@ -125,46 +277,186 @@ pub async fn incoming_raw(
                     "got a GARBLED REPLY to FORWARD to the CLIENT!!!"
                 );
                 if let Err(e) = ctx.get(CLIENT_SPIDER).send(&client_id, (pkt, rb_id)) {
+                    nack::dropped(ctx, origin, NackReason::Unroutable);
                     let clients = ctx.get(CLIENT_SPIDER).keys();
                     anyhow::bail!(
                         "PeeledPacket::GarbledReply CLIENT_SPIDER.send() failed with: {e}. CLIENT_SPIDER: {:?}", clients
                     )
                 }
+                usage::count_transit(ctx);
+                if let Some(ingress) = ingress {
+                    record_egress(ctx, TrafficClass::Reply, ingress, Duration::ZERO);
+                }
             }
         }
     } else {
         tracing::trace!("we are not the peeler");
+        if sheddable && overload::shed_transit(ctx) {
+            tracing::trace!("shedding a transit packet under overload");
+            nack::dropped(ctx, origin, NackReason::Overloaded);
+            return Ok(());
+        }
         // we are not peeler, forward the packet a step closer to peeler
-        let next_hop = one_hop_closer(ctx, next_peeler)?;
+        let next_hop = match one_hop_closer(ctx, next_peeler) {
+            Ok(next_hop) => next_hop,
+            Err(err) => {
+                nack::dropped(ctx, origin, NackReason::Unroutable);
+                return Err(err);
+            }
+        };
         tracing::trace!(
             next_hop = debug(next_hop),
             "forwarding the packet one hop closer"
         );
-        ctx.get(RELAY_SPIDER)
-            .send(&next_hop, (pkt, next_peeler))
+        forward_to_neigh(ctx, next_hop, pkt, next_peeler, prev_hop, origin)
             .context(format!("could not find this next hop {next_hop}"))?;
+        usage::count_transit(ctx);
+        if let Some(ingress) = ingress {
+            record_egress(ctx, TrafficClass::Transit, ingress, Duration::ZERO);
+        }
     }
     Ok(())
 }
 
-fn one_hop_closer(ctx: &DaemonContext, dest: RelayFingerprint) -> anyhow::Result<RelayFingerprint> {
-    let my_neighs: Vec<RelayFingerprint> = ctx.get(RELAY_SPIDER).keys();
+/// Like [one_hop_closer], but if the relay graph has no route and probing is enabled, first tries to learn one from our neighbors.
+async fn next_hop_toward(
+    ctx: &DaemonContext,
+    dest: RelayFingerprint,
+) -> anyhow::Result<RelayFingerprint> {
+    match one_hop_closer(ctx, dest) {
+        Err(err) if ctx.init().probe_route_misses && !ctx.get(RELAY_SPIDER).keys().is_empty() => {
+            probe::probe_for_route(ctx, dest).await.context(err)
+        }
+        res => res,
+    }
+}
 
-    if my_neighs.is_empty() {
-        anyhow::bail!("cannot route one hop closer since we don't have ANY neighbors!")
+/// Checks that a packet for `next_peeler` would get out of here, without sending anything, and so without NACKing anyone if it wouldn't.
+pub async fn check_reachable(
+    ctx: &DaemonContext,
+    next_peeler: RelayFingerprint,
+) -> anyhow::Result<()> {
+    if ctx
+        .get(MY_RELAY_IDENTITY)
+        .is_some_and(|id| id.public().fingerprint() == next_peeler)
+    {
+        return Ok(());
+    }
+    next_hop_toward(ctx, next_peeler).await.map(|_| ())
+}
+
+/// How long a cached answer of [check_reachable_cached] holds even while the relay graph and our neighbors stay the same, since adjacencies also age out of routing.
+const REACHABILITY_TTL: Duration = Duration::from_secs(10);
+
+/// Whether a destination was reachable, as of a graph generation and a set of neighbors.
+struct Reachability {
+    generation: u64,
+    neighbors: Vec<RelayFingerprint>,
+    checked: Instant,
+    reachable: bool,
+}
+
+static REACHABILITY: CtxField<Mutex<HashMap<RelayFingerprint, Reachability>>> =
+    |_| Default::default();
+
+/// Like [check_reachable], but reuses the last answer for `dest` while the relay graph and our neighbors stay the same, so that a stream of messages to one destination doesn't search the graph for each of them.
+pub async fn check_reachable_cached(
+    ctx: &DaemonContext,
+    dest: RelayFingerprint,
+) -> anyhow::Result<()> {
+    let generation = ctx.get(RELAY_GRAPH).read().generation();
+    let mut neighbors = ctx.get(RELAY_SPIDER).keys();
+    neighbors.sort_unstable();
+    let cached = ctx.get(REACHABILITY).lock().get(&dest).and_then(|known| {
+        (known.generation == generation
+            && known.neighbors == neighbors
+            && known.checked.elapsed() < REACHABILITY_TTL)
+            .then_some(known.reachable)
+    });
+    match cached {
+        Some(true) => return Ok(()),
+        Some(false) => anyhow::bail!("no route to {dest}, as of the last check"),
+        None => {}
     }
+    let res = check_reachable(ctx, dest).await;
+    let mut known = ctx.get(REACHABILITY).lock();
+    // answers for older generations will never be used again
+    known.retain(|_, reach| {
+        reach.generation >= generation && reach.checked.elapsed() < REACHABILITY_TTL
+    });
+    known.insert(
+        dest,
+        Reachability {
+            generation,
+            neighbors,
+            checked: Instant::now(),
+            reachable: res.is_ok(),
+        },
+    );
+    res
+}
 
+/// If redundant forwarding is on, also sends the packet through the closest neighbor other than `next_hop`, if there is one. The backup never leads back to us or to `prev_hop`, the relay the packet came from, which would only drop it as a replay.
+fn send_redundant(
+    ctx: &DaemonContext,
+    packet: RawPacket,
+    next_peeler: RelayFingerprint,
+    next_hop: RelayFingerprint,
+    prev_hop: Option<RelayFingerprint>,
+) {
+    if !ctx.init().redundant_forwarding {
+        return;
+    }
+    let my_fp = ctx
+        .get(MY_RELAY_IDENTITY)
+        .as_ref()
+        .map(|id| id.public().fingerprint());
+    let backup = closest_neigh(ctx, next_peeler, |neigh, route| {
+        *neigh != next_hop
+            && !route
+                .iter()
+                .any(|hop| Some(*hop) == prev_hop || Some(*hop) == my_fp)
+    });
+    if let Some(backup) = backup {
+        tracing::trace!(
+            backup = debug(backup),
+            "also forwarding through a backup hop"
+        );
+        // backup copies are never NACK-eligible, or a NACK for one would make the sender give up on the other
+        let _ = ctx
+            .get(RELAY_SPIDER)
+            .send(&backup, (packet, next_peeler, None));
+    }
+}
+
+/// Returns the neighbor with the shortest route to `dest`, out of those the filter allows along their route, which starts at the neighbor.
+fn closest_neigh(
+    ctx: &DaemonContext,
+    dest: RelayFingerprint,
+    filter: impl Fn(&RelayFingerprint, &[RelayFingerprint]) -> bool,
+) -> Option<RelayFingerprint> {
     let mut shortest_route_len = usize::MAX;
     let mut next_hop = None;
 
-    for neigh in my_neighs.iter() {
+    for neigh in ctx.get(RELAY_SPIDER).keys().iter() {
         if let Some(route) = ctx.get(RELAY_GRAPH).read().find_shortest_path(neigh, &dest) {
-            if route.len() < shortest_route_len {
+            if route.len() < shortest_route_len && filter(neigh, &route) {
                 shortest_route_len = route.len();
                 next_hop = Some(*neigh);
             }
         }
     }
+    next_hop
+}
+
+fn one_hop_closer(ctx: &DaemonContext, dest: RelayFingerprint) -> anyhow::Result<RelayFingerprint> {
+    let my_neighs: Vec<RelayFingerprint> = ctx.get(RELAY_SPIDER).keys();
+
+    if my_neighs.is_empty() {
+        anyhow::bail!("cannot route one hop closer since we don't have ANY neighbors!")
+    }
+
+    let next_hop = closest_neigh(ctx, dest, |_, _| true);
 
     next_hop
         .context(format!("cannot route one hop closer to {:?} since none of our neighbors ({:?}) could find a route there", dest, my_neighs))
@@ -186,7 +478,67 @@ pub fn all_client_neighs(ctx: &DaemonContext) -> Vec<ClientId> {
     ctx.get(CLIENT_SPIDER).keys()
 }
 
-pub type RelayLinkMsg = (RawPacket, RelayFingerprint);
+/// How far along the node is in joining the network. Every phase implies the ones before it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum BootstrapPhase {
+    /// No neighbor has connected yet.
+    Starting,
+    NeighborConnected,
+    /// Gossip taught us at least one adjacency.
+    GraphLearned,
+    /// We can compute a route through a neighbor to some relay other than ourselves.
+    FirstRouteComputed,
+}
+
+/// How far along we are in joining the network.
+pub fn bootstrap_phase(ctx: &DaemonContext) -> BootstrapPhase {
+    let relay_neighs = all_relay_neighs(ctx);
+    if relay_neighs.is_empty() && all_client_neighs(ctx).is_empty() {
+        return BootstrapPhase::Starting;
+    }
+    let me = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|identity| identity.public().fingerprint());
+    let graph = ctx.get(RELAY_GRAPH).read();
+    if graph.all_adjacencies().next().is_none() {
+        return BootstrapPhase::NeighborConnected;
+    }
+    let routable = graph
+        .all_nodes()
+        .filter(|node| Some(*node) != me)
+        .any(|node| {
+            relay_neighs
+                .iter()
+                .any(|neigh| graph.find_shortest_path(neigh, &node).is_some())
+        });
+    if routable {
+        BootstrapPhase::FirstRouteComputed
+    } else {
+        BootstrapPhase::GraphLearned
+    }
+}
+
+/// How many packets are waiting to go out to each neighbor, for the neighbors that have any waiting.
+pub fn queue_depths(ctx: &DaemonContext) -> Vec<(String, usize)> {
+    let relay_queues = ctx
+        .get(RELAY_SPIDER)
+        .depths()
+        .into_iter()
+        .map(|(neigh, depth)| (format!("relay:{neigh}"), depth));
+    let client_queues = ctx
+        .get(CLIENT_SPIDER)
+        .depths()
+        .into_iter()
+        .map(|(neigh, depth)| (format!("client:{neigh}"), depth));
+    relay_queues
+        .chain(client_queues)
+        .filter(|(_, depth)| *depth > 0)
+        .collect()
+}
+
+/// A packet, its next peeler, and the tag a NACK for it should carry, if it is NACK-eligible.
+pub type RelayLinkMsg = (RawPacket, RelayFingerprint, Option<u64>);
 static RELAY_SPIDER: CtxField<Spider<RelayFingerprint, RelayLinkMsg>> = |_| Spider::new();
 
 /// Subscribe to all outgoing messages that should be routed to the given neighboring relay.
@@ -204,3 +556,278 @@ static CLIENT_SPIDER: CtxField<Spider<ClientId, ClientLinkMsg>> = |_| Spider::ne
 pub fn subscribe_outgoing_client(ctx: &DaemonContext, neigh: ClientId) -> Receiver<ClientLinkMsg> {
     ctx.get(CLIENT_SPIDER).subscribe(neigh)
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use earendil_crypt::{AnonEndpoint, RelayIdentitySecret, RemoteId};
+    use earendil_packet::{crypt::DhSecret, InnerPacket, Message};
+    use earendil_topology::IdentityDescriptor;
+    use smol::future::FutureExt as _;
+    use smol_timeout::TimeoutExt;
+
+    use super::*;
+    use crate::{
+        ledger::unix_now,
+        test_util::{adjacency, known_relay},
+    };
+
+    #[test]
+    fn redundant_forwarding_delivers_once() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "redundant sender",
+                "redundant_forwarding": true,
+            }))
+            .unwrap(),
+        );
+        let peeler_ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "identity_seed": "redundant peeler" }))
+                .unwrap(),
+        );
+        let peeler_id = RelayIdentitySecret::from_seed("redundant peeler");
+        let peeler = peeler_id.public().fingerprint();
+        let [neigh_a, neigh_b] = [(); 2].map(|_| known_relay(&ctx));
+        {
+            let mut graph = ctx.get(RELAY_GRAPH).write();
+            graph
+                .insert_identity(IdentityDescriptor::new(
+                    &peeler_id,
+                    peeler_ctx.get(MY_RELAY_ONION_SK),
+                ))
+                .unwrap();
+            for neigh in [neigh_a, neigh_b] {
+                graph
+                    .insert_adjacency(adjacency(&neigh, &peeler_id, unix_now()))
+                    .unwrap();
+            }
+        }
+        let links = [neigh_a, neigh_b]
+            .map(|neigh| subscribe_outgoing_relay(&ctx, neigh.public().fingerprint()));
+
+        let pkt = RawPacket::new_normal(
+            &[],
+            &peeler_ctx.get(MY_RELAY_ONION_SK).public(),
+            InnerPacket::Message(Message::new(1, Bytes::from_static(b"hello"))),
+            RemoteId::Anon(AnonEndpoint::random()),
+        )
+        .unwrap();
+        smol::future::block_on(async {
+            send_raw(&ctx, pkt, peeler).await.unwrap();
+            // both neighbors got a copy, and pass it on to the peeler
+            for link in links {
+                let (copy, next_peeler, _) = link.try_recv().unwrap();
+                assert_eq!(next_peeler, peeler);
+                let _ = incoming_raw(&peeler_ctx, peeler, copy, None, None).await;
+            }
+            let (body, _, _) = n2r::read_forward(&peeler_ctx).await.unwrap();
+            assert_eq!(body, Bytes::from_static(b"hello"));
+            assert!(n2r::read_forward(&peeler_ctx)
+                .timeout(Duration::from_millis(100))
+                .await
+                .is_none());
+        });
+        assert_eq!(
+            peeler_ctx.get(STATS).snapshot().get(DUPLICATES_DROPPED),
+            Some(&1)
+        );
+    }
+
+    #[test]
+    fn backup_copies_never_go_back() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "redundant transit",
+                "redundant_forwarding": true,
+            }))
+            .unwrap(),
+        );
+        let me = RelayIdentitySecret::from_seed("redundant transit");
+        // the packet came from `prev`, which also leads to the peeler, only the long way round
+        let [prev, next, far, peeler] = [(); 4].map(|_| known_relay(&ctx));
+        {
+            let mut graph = ctx.get(RELAY_GRAPH).write();
+            graph
+                .insert_identity(IdentityDescriptor::new(&me, &DhSecret::generate()))
+                .unwrap();
+            for (x, y) in [
+                (me, prev),
+                (me, next),
+                (next, peeler),
+                (prev, far),
+                (far, peeler),
+            ] {
+                graph
+                    .insert_adjacency(adjacency(&x, &y, unix_now()))
+                    .unwrap();
+            }
+        }
+        let [prev_fp, next_fp, peeler_fp] =
+            [prev, next, peeler].map(|id| id.public().fingerprint());
+        let prev_link = subscribe_outgoing_relay(&ctx, prev_fp);
+        let next_link = subscribe_outgoing_relay(&ctx, next_fp);
+
+        let pkt: RawPacket = bytemuck::Zeroable::zeroed();
+        smol::future::block_on(incoming_raw(&ctx, peeler_fp, pkt, Some(prev_fp), None)).unwrap();
+        assert!(next_link.try_recv().is_ok());
+        assert!(prev_link.try_recv().is_err());
+
+        // the same packet again goes nowhere
+        smol::future::block_on(incoming_raw(&ctx, peeler_fp, pkt, Some(next_fp), None)).unwrap();
+        assert!(next_link.try_recv().is_err());
+        assert!(prev_link.try_recv().is_err());
+        assert_eq!(ctx.get(STATS).snapshot().get(DUPLICATES_DROPPED), Some(&1));
+    }
+
+    #[test]
+    fn nack_beats_the_timeout() {
+        let sender = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let relay_id = RelayIdentitySecret::from_seed("nacking relay");
+        let relay = relay_id.public().fingerprint();
+        let relay_ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "identity_seed": "nacking relay" }))
+                .unwrap(),
+        );
+        let peeler_id = RelayIdentitySecret::generate();
+        let peeler_sk = DhSecret::generate();
+        {
+            // the sender thinks the relay leads to the peeler, but the relay lost its link there
+            let mut graph = sender.get(RELAY_GRAPH).write();
+            graph
+                .insert_identity(IdentityDescriptor::new(&peeler_id, &peeler_sk))
+                .unwrap();
+            graph
+                .insert_identity(IdentityDescriptor::new(&relay_id, &DhSecret::generate()))
+                .unwrap();
+            graph
+                .insert_adjacency(adjacency(&relay_id, &peeler_id, unix_now()))
+                .unwrap();
+        }
+        let sender_link = subscribe_outgoing_relay(&sender, relay);
+        let sender_id: NeighborId = either::Either::Left(*sender.get(crate::context::MY_CLIENT_ID));
+        let relay_nacks = subscribe_nacks(&relay_ctx, sender_id);
+
+        let pkt = RawPacket::new_normal(
+            &[],
+            &peeler_sk.public(),
+            InnerPacket::Message(Message::new(1, Bytes::from_static(b"hello"))),
+            RemoteId::Anon(AnonEndpoint::random()),
+        )
+        .unwrap();
+        let (origin, nacks) = mark_local(&sender).unwrap();
+        let start = Instant::now();
+        smol::future::block_on(async {
+            send_raw_nackable(&sender, pkt, peeler_id.public().fingerprint(), Some(origin))
+                .await
+                .unwrap();
+            // the relay gets the packet under some tag, and has nowhere to send it
+            let (pkt, next_peeler, tag) = sender_link.try_recv().unwrap();
+            let tag = tag.unwrap();
+            let origin = NackOrigin::Neighbor {
+                neighbor: sender_id,
+                tag,
+            };
+            assert!(
+                incoming_raw(&relay_ctx, next_peeler, pkt, None, Some(origin))
+                    .await
+                    .is_err()
+            );
+            let (nack_tag, reason) = relay_nacks.try_recv().unwrap();
+            assert_eq!((nack_tag, reason), (tag, NackReason::Unroutable));
+
+            // NACKs only count from the neighbor the packet went to
+            let stranger = either::Either::Right(peeler_id.public().fingerprint());
+            incoming_nack(&sender, stranger, tag, NackReason::Policy);
+            assert!(nacks.try_recv().is_err());
+            incoming_nack(&sender, either::Either::Right(relay), tag, reason);
+            let reason = nacks
+                .recv()
+                .timeout(Duration::from_millis(100))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(reason, NackReason::Unroutable);
+        });
+        // well before even the first GlobalRpc retry
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn ordered_forwarding_keeps_enqueue_order() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "ordered forwarder",
+                "ordered_forwarding": true,
+            }))
+            .unwrap(),
+        );
+        let neigh = known_relay(&ctx).public().fingerprint();
+        let link = subscribe_outgoing_relay(&ctx, neigh);
+
+        // all due at once, so that only the order they were queued in tells them apart
+        let clock = clock::clock(&ctx);
+        let due = clock.now();
+        for marker in 0u64..200 {
+            let mut pkt = <RawPacket as bytemuck::Zeroable>::zeroed();
+            pkt.onion_body[..8].copy_from_slice(&marker.to_le_bytes());
+            ctx.get(DELAY_QUEUE).insert(
+                Delayed {
+                    pkt,
+                    next_peeler: neigh,
+                    origin: None,
+                    ingress: None,
+                    queued: due,
+                },
+                due,
+            );
+        }
+        let arrived = smol::future::block_on(
+            async {
+                let mut arrived = vec![];
+                for _ in 0..200 {
+                    let (pkt, next_peeler, _) = link.recv().await.unwrap();
+                    assert_eq!(next_peeler, neigh);
+                    arrived.push(u64::from_le_bytes(pkt.onion_body[..8].try_into().unwrap()));
+                }
+                arrived
+            }
+            .or(async {
+                delay_queue_loop(ctx.clone()).await.unwrap();
+                unreachable!()
+            }),
+        );
+        assert_eq!(arrived, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reachability_is_searched_again_only_when_the_graph_or_neighbors_change() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let [neigh, middle, dest] = [(); 3].map(|_| known_relay(&ctx));
+        let dest_fp = dest.public().fingerprint();
+        ctx.get(RELAY_GRAPH)
+            .write()
+            .insert_adjacency(adjacency(&neigh, &middle, unix_now()))
+            .unwrap();
+        let link = subscribe_outgoing_relay(&ctx, neigh.public().fingerprint());
+
+        smol::future::block_on(async {
+            assert!(check_reachable_cached(&ctx, dest_fp).await.is_err());
+            // a new adjacency makes a new graph generation, so the answer changes with it
+            ctx.get(RELAY_GRAPH)
+                .write()
+                .insert_adjacency(adjacency(&middle, &dest, unix_now() - 600))
+                .unwrap();
+            assert!(check_reachable_cached(&ctx, dest_fp).await.is_ok());
+
+            // the last answer stands while the graph and our neighbors stay the same
+            ctx.get(RELAY_GRAPH).write().set_max_routing_age(Some(60));
+            assert!(check_reachable(&ctx, dest_fp).await.is_err());
+            assert!(check_reachable_cached(&ctx, dest_fp).await.is_ok());
+
+            // losing the neighbor is a change too
+            drop(link);
+            ctx.get(RELAY_GRAPH).write().set_max_routing_age(None);
+            assert!(check_reachable_cached(&ctx, dest_fp).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,39 @@
+use std::{collections::HashMap, time::Instant};
+
+use earendil_crypt::ClientId;
+use parking_lot::Mutex;
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    stats::STATS,
+};
+
+use super::nack::Budget;
+
+pub const CLIENT_RATE_LIMITED: &str = "client_rate_limit.dropped";
+
+/// How many more packets each direct client may send us right now.
+static CLIENT_BUDGETS: CtxField<Mutex<HashMap<ClientId, Budget>>> = |_| Mutex::new(HashMap::new());
+
+/// Counts one more packet from a direct client, returning whether it is within the limit.
+pub fn within_client_rate_limit(ctx: &DaemonContext, client: ClientId) -> bool {
+    let Some(limit) = ctx.init().client_rate_limit else {
+        return true;
+    };
+    let now = Instant::now();
+    let allowed = ctx
+        .get(CLIENT_BUDGETS)
+        .lock()
+        .entry(client)
+        .or_insert_with(|| Budget::full(limit.burst as f64, now))
+        .take(limit.per_sec, limit.burst as f64, now);
+    if !allowed {
+        ctx.get(STATS).incr(CLIENT_RATE_LIMITED);
+    }
+    allowed
+}
+
+/// Forgets how much a client sent us, once its link is gone.
+pub fn forget_client(ctx: &DaemonContext, client: ClientId) {
+    ctx.get(CLIENT_BUDGETS).lock().remove(&client);
+}
@@ -0,0 +1,666 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_event::Event;
+use earendil_crypt::RelayFingerprint;
+use earendil_packet::{crypt::AeadKey, RawPacket};
+use parking_lot::Mutex;
+use smol::future::FutureExt;
+
+use crate::{
+    clock::Clock,
+    context::{CtxField, DaemonContext},
+    stats::Stats,
+};
+
+use super::nack::NackOrigin;
+
+/// How long before their emit time spilled packets are read back.
+const RELOAD_AHEAD: Duration = Duration::from_millis(200);
+
+const PACKET_BYTES: usize = std::mem::size_of::<RawPacket>();
+
+/// How much room a spilled packet and its Poly1305 tag take in the spill file.
+const SLOT_BYTES: u64 = PACKET_BYTES as u64 + 16;
+
+/// How many packets one trip to the disk spills or reads back at most.
+const SPILL_BATCH: usize = 64;
+
+/// How long to wait before trying the spill file again after it failed.
+const SPILL_RETRY: Duration = Duration::from_secs(1);
+
+/// How long the packets that a clock jump made overdue are spread over.
+const OVERDUE_SPREAD: Duration = Duration::from_secs(2);
+
+pub const DELAY_SPILLED: &str = "delay_queue.spilled";
+pub const DELAY_RELOADED: &str = "delay_queue.reloaded";
+pub const DELAY_SPILL_LOST: &str = "delay_queue.spill_lost";
+pub const DELAY_RESPREAD: &str = "delay_queue.respread";
+
+pub(super) static DELAY_QUEUE: CtxField<DelayQueue> = |ctx| {
+    let spill = ctx.init().delay_spill.as_ref().and_then(|cfg| {
+        match Spill::create(&cfg.path, cfg.memory_limit_bytes) {
+            Ok(spill) => Some(spill),
+            Err(err) => {
+                tracing::warn!(
+                    path = debug(&cfg.path),
+                    "cannot create delay queue spill file, keeping all delayed packets in memory: {err:?}"
+                );
+                None
+            }
+        }
+    });
+    DelayQueue::new(spill)
+};
+
+/// A peeled packet waiting out its mix delay.
+pub(super) struct Delayed {
+    pub pkt: RawPacket,
+    pub next_peeler: RelayFingerprint,
+    pub origin: Option<NackOrigin>,
+    /// When the packet arrived on a link, for latency accounting.
+    pub ingress: Option<Instant>,
+    pub queued: Instant,
+}
+
+/// A delayed packet whose body is on disk.
+struct Spilled {
+    offset: u64,
+    next_peeler: RelayFingerprint,
+    origin: Option<NackOrigin>,
+    ingress: Option<Instant>,
+    queued: Instant,
+}
+
+/// How much the delay queue holds, in memory and on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DelayQueueStats {
+    pub memory_packets: usize,
+    pub memory_bytes: usize,
+    pub spilled_packets: usize,
+    pub spill_bytes: u64,
+}
+
+/// Emit time, then insertion order among packets due at the same time.
+type Key = (Instant, u64);
+
+/// Peeled packets waiting out their mix delays, spilling to disk past a memory limit if configured.
+pub(super) struct DelayQueue {
+    state: Mutex<State>,
+    spill: Option<Arc<Mutex<Spill>>>,
+    /// How many bytes of packets to hold in memory before spilling, if spilling at all.
+    memory_limit: Option<usize>,
+    event: Event,
+}
+
+struct State {
+    memory: BTreeMap<Key, Delayed>,
+    /// Only ever changed by the spill loop.
+    spilled: BTreeMap<Key, Spilled>,
+    next_seq: u64,
+    /// Bumped whenever packets are inserted or read back.
+    changes: u64,
+    /// The number of the last clock jump whose overdue packets were spread out.
+    spread_for_jump: u64,
+}
+
+/// One trip to the disk.
+enum SpillWork {
+    Spill(Vec<(Key, RawPacket)>),
+    Reload(Vec<(Key, u64)>),
+}
+
+impl DelayQueue {
+    fn new(spill: Option<Spill>) -> Self {
+        Self {
+            state: Mutex::new(State {
+                memory: BTreeMap::new(),
+                spilled: BTreeMap::new(),
+                next_seq: 0,
+                changes: 0,
+                spread_for_jump: 0,
+            }),
+            memory_limit: spill.as_ref().map(|spill| spill.memory_limit),
+            spill: spill.map(|spill| Arc::new(Mutex::new(spill))),
+            event: Event::new(),
+        }
+    }
+
+    /// Inserts a packet to be emitted at `emit_time`. Spilling it, if need be, is left to the spill loop.
+    pub fn insert(&self, delayed: Delayed, emit_time: Instant) {
+        {
+            let mut state = self.state.lock();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.changes += 1;
+            state.memory.insert((emit_time, seq), delayed);
+        }
+        self.event.notify_all();
+    }
+
+    /// *Blocks* until the packet with the earliest emit time is due, then returns it.
+    ///
+    /// `last_jump` returns the number of the last clock jump, after checking for a new one.
+    pub async fn pop(&self, stats: &Stats, clock: &Clock, last_jump: impl Fn() -> u64) -> Delayed {
+        loop {
+            let (wake, changes) = {
+                let jump = last_jump();
+                let mut state = self.state.lock();
+                let now = clock.now();
+                if jump != state.spread_for_jump {
+                    state.spread_for_jump = jump;
+                    state.spread_overdue(stats, now);
+                }
+                let first_spilled = state.spilled.keys().next().copied();
+                let wake = match state.memory.first_entry() {
+                    // a spilled packet due before it has to be read back first
+                    Some(entry) if first_spilled.is_some_and(|spilled| spilled < *entry.key()) => {
+                        None
+                    }
+                    Some(entry) if entry.key().0 <= now => return entry.remove(),
+                    Some(entry) => Some(entry.key().0),
+                    None => None,
+                };
+                (wake, state.changes)
+            };
+            // a newly inserted or reloaded packet may be due sooner than whatever we're waiting for
+            self.wait(clock, wake, changes).await;
+        }
+    }
+
+    /// Waits until `wake`, or until the queue changes.
+    async fn wait(&self, clock: &Clock, wake: Option<Instant>, changes: u64) {
+        let timer = async {
+            match wake {
+                Some(wake) => clock.sleep_until(wake).await,
+                None => {
+                    smol::Timer::never().await;
+                }
+            }
+        };
+        timer
+            .race(
+                self.event
+                    .wait_until(|| (self.state.lock().changes != changes).then_some(())),
+            )
+            .await;
+    }
+
+    /// Spills packets past the memory limit to disk, and reads them back as they come due.
+    pub async fn spill_loop(&self, stats: &Stats, clock: &Clock) {
+        if self.spill.is_none() {
+            return smol::future::pending().await;
+        }
+        loop {
+            match self.spill_step(stats, clock).await {
+                Ok(None) => {}
+                Ok(Some((wake, changes))) => self.wait(clock, wake, changes).await,
+                Err(err) => {
+                    tracing::warn!("cannot use the delay queue spill file: {err:?}");
+                    smol::Timer::after(SPILL_RETRY).await;
+                }
+            }
+        }
+    }
+
+    /// Makes one trip to the disk, or returns when to look again if there's nothing to do.
+    async fn spill_step(
+        &self,
+        stats: &Stats,
+        clock: &Clock,
+    ) -> anyhow::Result<Option<(Option<Instant>, u64)>> {
+        let (Some(spill), Some(memory_limit)) = (self.spill.clone(), self.memory_limit) else {
+            return Ok(Some((None, self.state.lock().changes)));
+        };
+        let now = clock.now();
+        let work = {
+            let state = self.state.lock();
+            match state.next_spill_work(memory_limit, now) {
+                Some(work) => work,
+                None => {
+                    let reload_at = state
+                        .spilled
+                        .keys()
+                        .next()
+                        .map(|(emit, _)| emit.checked_sub(RELOAD_AHEAD).unwrap_or(*emit));
+                    return Ok(Some((reload_at, state.changes)));
+                }
+            }
+        };
+        match work {
+            SpillWork::Spill(batch) => {
+                let (written, failed) = smol::unblock(move || {
+                    let mut spill = spill.lock();
+                    let mut written = vec![];
+                    for (key, pkt) in batch {
+                        match spill.write(key.1, &pkt) {
+                            Ok(offset) => written.push((key, offset)),
+                            Err(err) => return (written, Some(err)),
+                        }
+                    }
+                    (written, None)
+                })
+                .await;
+                let mut orphaned = vec![];
+                {
+                    let mut state = self.state.lock();
+                    for (key, offset) in written {
+                        // the packet may have been respread or sent on while it was being written
+                        match state.memory.remove(&key) {
+                            Some(delayed) => {
+                                stats.incr(DELAY_SPILLED);
+                                state.spilled.insert(
+                                    key,
+                                    Spilled {
+                                        offset,
+                                        next_peeler: delayed.next_peeler,
+                                        origin: delayed.origin,
+                                        ingress: delayed.ingress,
+                                        queued: delayed.queued,
+                                    },
+                                );
+                            }
+                            None => orphaned.push(offset),
+                        }
+                    }
+                }
+                if !orphaned.is_empty() {
+                    let spill = self.spill.clone().unwrap();
+                    smol::unblock(move || {
+                        let mut spill = spill.lock();
+                        orphaned
+                            .into_iter()
+                            .try_for_each(|offset| spill.release(offset))
+                    })
+                    .await?;
+                }
+                if let Some(err) = failed {
+                    return Err(err);
+                }
+            }
+            SpillWork::Reload(batch) => {
+                let read = smol::unblock(move || {
+                    let mut spill = spill.lock();
+                    batch
+                        .into_iter()
+                        .map(|(key, offset)| (key, spill.read(key.1, offset)))
+                        .collect::<Vec<_>>()
+                })
+                .await;
+                {
+                    let mut state = self.state.lock();
+                    for (key, pkt) in read {
+                        let spilled = state
+                            .spilled
+                            .remove(&key)
+                            .context("spilled packet went missing while being read back")?;
+                        match pkt {
+                            Ok(pkt) => {
+                                stats.incr(DELAY_RELOADED);
+                                state.memory.insert(
+                                    key,
+                                    Delayed {
+                                        pkt,
+                                        next_peeler: spilled.next_peeler,
+                                        origin: spilled.origin,
+                                        ingress: spilled.ingress,
+                                        queued: spilled.queued,
+                                    },
+                                );
+                            }
+                            Err(err) => {
+                                stats.incr(DELAY_SPILL_LOST);
+                                tracing::warn!("lost a spilled packet: {err:?}");
+                            }
+                        }
+                    }
+                    state.changes += 1;
+                }
+                self.event.notify_all();
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn stats(&self) -> DelayQueueStats {
+        let state = self.state.lock();
+        DelayQueueStats {
+            memory_packets: state.memory.len(),
+            memory_bytes: state.memory.len() * PACKET_BYTES,
+            spilled_packets: state.spilled.len(),
+            spill_bytes: state.spilled.len() as u64 * SLOT_BYTES,
+        }
+    }
+}
+
+impl State {
+    /// Spreads the packets that are already due over the next [OVERDUE_SPREAD], keeping their order.
+    fn spread_overdue(&mut self, stats: &Stats, now: Instant) {
+        let due = self
+            .memory
+            .keys()
+            .take_while(|(emit, _)| *emit <= now)
+            .count();
+        if due == 0 {
+            return;
+        }
+        let overdue: Vec<_> = (0..due).filter_map(|_| self.memory.pop_first()).collect();
+        tracing::debug!(
+            count = overdue.len(),
+            "spreading out packets that are far overdue"
+        );
+        stats.add(DELAY_RESPREAD, overdue.len() as u64);
+        for (i, ((_, seq), delayed)) in overdue.into_iter().enumerate() {
+            let emit = now + OVERDUE_SPREAD.mul_f64(i as f64 / due as f64);
+            self.memory.insert((emit, seq), delayed);
+        }
+    }
+
+    /// Picks what to do next with the disk, reading back due packets before spilling more.
+    fn next_spill_work(&self, memory_limit: usize, now: Instant) -> Option<SpillWork> {
+        let reload: Vec<(Key, u64)> = self
+            .spilled
+            .iter()
+            .take_while(|((emit, _), _)| *emit <= now + RELOAD_AHEAD)
+            .take(SPILL_BATCH)
+            .map(|(key, spilled)| (*key, spilled.offset))
+            .collect();
+        if !reload.is_empty() {
+            return Some(SpillWork::Reload(reload));
+        }
+        let excess = (self.memory.len() * PACKET_BYTES).saturating_sub(memory_limit);
+        let spill: Vec<(Key, RawPacket)> = self
+            .memory
+            .iter()
+            .rev()
+            .take(excess.div_ceil(PACKET_BYTES).min(SPILL_BATCH))
+            // packets due soon would only be read right back
+            .take_while(|((emit, _), _)| *emit > now + RELOAD_AHEAD)
+            .map(|(key, delayed)| (*key, delayed.pkt))
+            .collect();
+        (!spill.is_empty()).then_some(SpillWork::Spill(spill))
+    }
+}
+
+/// A file of spilled packets in fixed-size slots, encrypted under a key that only lives as long as the daemon.
+struct Spill {
+    file: File,
+    path: PathBuf,
+    key: AeadKey,
+    memory_limit: usize,
+    /// Where the file ends.
+    end: u64,
+    /// The slots before `end` that hold nothing we need.
+    free: BTreeSet<u64>,
+}
+
+impl Spill {
+    /// Creates the spill file, discarding whatever an earlier run left there.
+    fn create(path: &Path, memory_limit: usize) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+            key: AeadKey::from_bytes(&rand::random()),
+            memory_limit,
+            end: 0,
+            free: BTreeSet::new(),
+        })
+    }
+
+    /// Writes a packet into a free slot, returning where.
+    fn write(&mut self, seq: u64, pkt: &RawPacket) -> anyhow::Result<u64> {
+        let sealed = self.key.seal(&nonce(seq), bytemuck::bytes_of(pkt));
+        debug_assert_eq!(sealed.len() as u64, SLOT_BYTES);
+        let offset = self.free.pop_first().unwrap_or(self.end);
+        let written = self
+            .file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.write_all(&sealed));
+        if let Err(err) = written {
+            if offset < self.end {
+                self.free.insert(offset);
+            }
+            return Err(err.into());
+        }
+        self.end = self.end.max(offset + SLOT_BYTES);
+        Ok(offset)
+    }
+
+    /// Reads back the packet at `offset`, freeing its slot whether or not that works.
+    fn read(&mut self, seq: u64, offset: u64) -> anyhow::Result<RawPacket> {
+        let mut sealed = vec![0u8; SLOT_BYTES as usize];
+        let read = self
+            .file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.read_exact(&mut sealed));
+        self.release(offset)?;
+        read?;
+        let plain = self.key.open(&nonce(seq), &sealed)?;
+        if plain.len() != PACKET_BYTES {
+            anyhow::bail!("spilled packet has the wrong length {}", plain.len());
+        }
+        Ok(bytemuck::pod_read_unaligned(&plain))
+    }
+
+    /// Frees the slot at `offset`, and cuts off however many free slots the file ends with.
+    fn release(&mut self, offset: u64) -> anyhow::Result<()> {
+        self.free.insert(offset);
+        let end = self.end;
+        while self.end > 0 && self.free.remove(&(self.end - SLOT_BYTES)) {
+            self.end -= SLOT_BYTES;
+        }
+        if self.end != end {
+            self.file.set_len(self.end)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Every spilled packet has a different sequence number, so nonces never repeat.
+fn nonce(seq: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&seq.to_le_bytes());
+    nonce
+}
+
+/// How much the delay queue holds right now.
+pub fn delay_queue_stats(ctx: &DaemonContext) -> DelayQueueStats {
+    ctx.get(DELAY_QUEUE).stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use bytemuck::Zeroable;
+    use earendil_crypt::RelayIdentitySecret;
+    use rand::seq::SliceRandom;
+    use smol_timeout::TimeoutExt;
+
+    use super::*;
+    use crate::clock::FakeClock;
+
+    fn spill_path() -> PathBuf {
+        std::env::temp_dir().join(format!("earendil-spill-{}", rand::random::<u64>()))
+    }
+
+    fn delayed(marker: u64) -> Delayed {
+        let mut pkt = RawPacket::zeroed();
+        pkt.onion_body[..8].copy_from_slice(&marker.to_le_bytes());
+        Delayed {
+            pkt,
+            next_peeler: RelayIdentitySecret::generate().public().fingerprint(),
+            origin: None,
+            ingress: None,
+            queued: Instant::now(),
+        }
+    }
+
+    fn marker(delayed: &Delayed) -> u64 {
+        u64::from_le_bytes(delayed.pkt.onion_body[..8].try_into().unwrap())
+    }
+
+    /// Spills and reloads until there is nothing left to do right now.
+    fn settle(queue: &DelayQueue, stats: &Stats, clock: &Clock) {
+        while smol::future::block_on(queue.spill_step(stats, clock))
+            .unwrap()
+            .is_none()
+        {}
+    }
+
+    /// Pops a packet while the spill loop runs, as it does in the daemon.
+    fn pop_spilling(queue: &DelayQueue, stats: &Stats, clock: &Clock) -> Delayed {
+        let spilling = async {
+            queue.spill_loop(stats, clock).await;
+            smol::future::pending::<Delayed>().await
+        };
+        smol::future::block_on(queue.pop(stats, clock, || 0).race(spilling))
+    }
+
+    #[test]
+    fn spills_past_the_memory_limit_and_still_emits_in_order() {
+        let path = spill_path();
+        let stats = Stats::default();
+        let queue = DelayQueue::new(Some(Spill::create(&path, 4 * PACKET_BYTES).unwrap()));
+        let start = Instant::now() + RELOAD_AHEAD * 2;
+        let mut markers: Vec<u64> = (0..20).collect();
+        markers.shuffle(&mut rand::thread_rng());
+        for marker in markers {
+            queue.insert(delayed(marker), start + Duration::from_millis(10 * marker));
+        }
+        // nothing touches the disk until the spill loop gets to it
+        assert_eq!(queue.stats().spilled_packets, 0);
+        settle(&queue, &stats, &Clock::Real);
+
+        let held = queue.stats();
+        assert_eq!(held.memory_packets, 4);
+        assert_eq!(held.spilled_packets, 16);
+        assert!(held.spill_bytes >= 16 * PACKET_BYTES as u64);
+        assert!(std::fs::metadata(&path).unwrap().len() >= held.spill_bytes);
+        // the packets are almost all zeros, which would show on disk if they weren't encrypted
+        let on_disk = std::fs::read(&path).unwrap();
+        assert!(on_disk
+            .windows(64)
+            .all(|window| window.iter().any(|b| *b != 0)));
+
+        let emitted: Vec<u64> = (0..20)
+            .map(|_| marker(&pop_spilling(&queue, &stats, &Clock::Real)))
+            .collect();
+        assert_eq!(emitted, (0..20).collect::<Vec<_>>());
+        let counters = stats.snapshot();
+        assert_eq!(counters[DELAY_SPILLED], 16);
+        assert_eq!(counters[DELAY_RELOADED], 16);
+        assert_eq!(queue.stats(), DelayQueueStats::default());
+        // once everything is read back, the file starts over
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn spill_file_stays_small_under_steady_load() {
+        let path = spill_path();
+        let fake = FakeClock::new();
+        let clock = Clock::Fake(fake.clone());
+        let stats = Stats::default();
+        let queue = DelayQueue::new(Some(Spill::create(&path, 0).unwrap()));
+
+        // a packet a second, each delayed for two, so that the file is never empty
+        for i in 0..50 {
+            queue.insert(delayed(i), clock.now() + Duration::from_secs(2));
+            settle(&queue, &stats, &clock);
+            assert!(queue.stats().spilled_packets >= 1);
+            assert!(std::fs::metadata(&path).unwrap().len() <= 3 * SLOT_BYTES);
+            if i >= 2 {
+                let popped = smol::future::block_on(
+                    queue
+                        .pop(&stats, &clock, || 0)
+                        .timeout(Duration::from_secs(1)),
+                );
+                assert_eq!(popped.map(|d| marker(&d)), Some(i - 2));
+            }
+            fake.advance(Duration::from_secs(1));
+        }
+        assert_eq!(stats.snapshot()[DELAY_SPILLED], 50);
+    }
+
+    #[test]
+    fn spill_file_does_not_outlive_the_run() {
+        let path = spill_path();
+        std::fs::write(&path, b"packets from a previous run").unwrap();
+        let queue = DelayQueue::new(Some(Spill::create(&path, 0).unwrap()));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        queue.insert(delayed(0), Instant::now() + Duration::from_secs(60));
+        settle(&queue, &Stats::default(), &Clock::Real);
+        assert_eq!(queue.stats().spilled_packets, 1);
+        drop(queue);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn packets_overdue_after_a_clock_jump_are_spread_out() {
+        let fake = FakeClock::new();
+        let clock = Clock::Fake(fake.clone());
+        let stats = Stats::default();
+        let queue = DelayQueue::new(None);
+        let last_jump = AtomicU64::new(0);
+        let pop = |wait| {
+            smol::future::block_on(
+                queue
+                    .pop(&stats, &clock, || last_jump.load(Ordering::SeqCst))
+                    .timeout(wait),
+            )
+            .map(|d| marker(&d))
+        };
+
+        // falling far behind without the clock jumping sends everything right away
+        for marker in 0..3 {
+            queue.insert(delayed(marker), clock.now());
+        }
+        fake.advance(Duration::from_secs(60));
+        let behind: Vec<u64> = (0..3)
+            .filter_map(|_| pop(Duration::from_millis(50)))
+            .collect();
+        assert_eq!(behind, vec![0, 1, 2]);
+        assert_eq!(
+            stats.snapshot().get(DELAY_RESPREAD).copied().unwrap_or(0),
+            0
+        );
+
+        for marker in 0..10 {
+            let emit = clock.now() + Duration::from_millis(10 * (marker + 1));
+            queue.insert(delayed(marker), emit);
+        }
+        // the clock jumps far past all of them, but only the first goes out right away
+        fake.advance(Duration::from_secs(60));
+        last_jump.store(1, Ordering::SeqCst);
+        assert_eq!(pop(Duration::from_secs(1)), Some(0));
+        assert_eq!(pop(Duration::from_millis(50)), None);
+        assert_eq!(stats.snapshot()[DELAY_RESPREAD], 10);
+
+        fake.advance(OVERDUE_SPREAD);
+        let rest: Vec<u64> = (1..10)
+            .filter_map(|_| pop(Duration::from_secs(1)))
+            .collect();
+        assert_eq!(rest, (1..10).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashSet;
+use earendil_crypt::{ClientId, RelayFingerprint};
+use earendil_packet::RawPacket;
+use either::Either;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol::channel::Receiver;
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    context::{is_relay, CtxField, DaemonContext},
+    ledger::unix_now,
+    stats::STATS,
+};
+
+use super::{
+    nack::{self, Budget, NackReason, NeighborId},
+    spider::Spider,
+};
+
+pub const DROP_REPORT_SENT: &str = "drop_report.sent";
+pub const DROP_REPORT_SUPPRESSED: &str = "drop_report.suppressed";
+
+/// How long a relay waits for more drops to report along with the first one.
+const BATCH_WINDOW: Duration = Duration::from_millis(200);
+const MAX_BATCH: usize = 64;
+
+/// How many drop reports a client keeps around for looking at.
+const OBSERVED_KEPT: usize = 256;
+
+/// How many NACK-eligible packets per link a client remembers having sent.
+const SENT_KEPT: usize = 1024;
+
+/// Why a first-hop relay dropped a packet its client sent it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    /// The client owes the relay more than its debt limit.
+    DebtLimit,
+    /// The client sent faster than the relay's `client_rate_limit`.
+    RateLimited,
+    /// The relay couldn't peel the packet, or had no way to pass it on.
+    Undeliverable,
+}
+
+impl DropReason {
+    /// The stat that counts the drop reports a client got for this reason.
+    pub fn stat(&self) -> &'static str {
+        match self {
+            DropReason::DebtLimit => "drop_report.debt_limit",
+            DropReason::RateLimited => "drop_report.rate_limited",
+            DropReason::Undeliverable => "drop_report.undeliverable",
+        }
+    }
+}
+
+impl From<DropReason> for NackReason {
+    fn from(reason: DropReason) -> Self {
+        match reason {
+            DropReason::DebtLimit | DropReason::RateLimited => NackReason::Policy,
+            DropReason::Undeliverable => NackReason::Unroutable,
+        }
+    }
+}
+
+/// One packet a relay dropped.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DropReport {
+    pub reason: DropReason,
+    /// The first 8 bytes of the packet's hash.
+    pub packet_hash: u64,
+}
+
+/// The drop reports a relay sends a client at once.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DropReports {
+    pub drops: Vec<DropReport>,
+    /// How many more packets were dropped, but went unreported.
+    pub suppressed: u64,
+}
+
+/// A drop report, as the client that got it keeps it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ObservedDrop {
+    /// The relay that dropped the packet.
+    pub relay: RelayFingerprint,
+    pub reason: DropReason,
+    pub packet_hash: u64,
+    /// When the report arrived, in seconds since the Unix epoch.
+    pub at: u64,
+}
+
+/// Truncates a packet's hash for drop reports.
+pub fn packet_hash(pkt: &RawPacket) -> u64 {
+    let hash = blake3::hash(bytemuck::bytes_of(pkt));
+    u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+}
+
+/// The direct clients that asked us for drop reports.
+static REPORTED_CLIENTS: CtxField<DashSet<ClientId>> = |_| DashSet::new();
+
+static REPORT_SPIDER: CtxField<Spider<ClientId, DropReport>> = |_| Spider::new();
+
+static OBSERVED_DROPS: CtxField<Mutex<VecDeque<ObservedDrop>>> = |_| Mutex::new(VecDeque::new());
+
+/// Starts giving drop reports to a direct client, returning false if our policy is not to.
+pub fn enable_drop_reports(ctx: &DaemonContext, client: ClientId) -> bool {
+    if !is_relay(ctx) || !ctx.init().drop_reports.serve {
+        return false;
+    }
+    ctx.get(REPORTED_CLIENTS).insert(client);
+    true
+}
+
+/// Stops giving drop reports to a client, such as when its link goes away.
+pub fn disable_drop_reports(ctx: &DaemonContext, client: ClientId) {
+    ctx.get(REPORTED_CLIENTS).remove(&client);
+}
+
+/// Subscribe to the drop reports that should go to the given direct client.
+pub fn subscribe_drop_reports(ctx: &DaemonContext, client: ClientId) -> DropReportStream {
+    DropReportStream {
+        recv: ctx.get(REPORT_SPIDER).subscribe(client),
+        budget: Budget::full(ctx.init().drop_reports.burst as f64, Instant::now()),
+    }
+}
+
+/// Reports a dropped packet to the client that handed it to us, if it asked for reports.
+pub fn report_drop(ctx: &DaemonContext, from: NeighborId, pkt: &RawPacket, reason: DropReason) {
+    let Either::Left(client) = from else {
+        return;
+    };
+    if !ctx.get(REPORTED_CLIENTS).contains(&client) {
+        return;
+    }
+    let report = DropReport {
+        reason,
+        packet_hash: packet_hash(pkt),
+    };
+    let _ = ctx.get(REPORT_SPIDER).send(&client, report);
+}
+
+/// The drop reports for one direct client, batched and kept within `drop_reports.per_sec`.
+pub struct DropReportStream {
+    recv: Receiver<DropReport>,
+    budget: Budget,
+}
+
+impl DropReportStream {
+    /// Waits for a drop to report, and batches it with the ones that follow shortly after.
+    pub async fn next_batch(&mut self, ctx: &DaemonContext) -> anyhow::Result<DropReports> {
+        let config = ctx.init().drop_reports;
+        let mut batch = DropReports::default();
+        let mut report = self.recv.recv().await?;
+        let deadline = Instant::now() + BATCH_WINDOW;
+        loop {
+            if self
+                .budget
+                .take(config.per_sec, config.burst as f64, Instant::now())
+            {
+                batch.drops.push(report);
+            } else {
+                batch.suppressed += 1;
+            }
+            if batch.drops.len() >= MAX_BATCH {
+                break;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.recv.recv().timeout(left).await {
+                Some(next) => report = next?,
+                None => break,
+            }
+        }
+        let stats = ctx.get(STATS);
+        stats.add(DROP_REPORT_SENT, batch.drops.len() as u64);
+        stats.add(DROP_REPORT_SUPPRESSED, batch.suppressed);
+        Ok(batch)
+    }
+}
+
+/// The NACK-eligible packets a client recently sent one of its relays, by truncated hash.
+#[derive(Default)]
+pub struct SentPackets {
+    inner: Mutex<(HashMap<u64, u64>, VecDeque<u64>)>,
+}
+
+impl SentPackets {
+    /// Remembers that a packet was sent under the given NACK tag.
+    pub fn record(&self, pkt: &RawPacket, tag: u64) {
+        let hash = packet_hash(pkt);
+        let mut inner = self.inner.lock();
+        let (tags, order) = &mut *inner;
+        tags.insert(hash, tag);
+        order.push_back(hash);
+        while order.len() > SENT_KEPT {
+            if let Some(oldest) = order.pop_front() {
+                tags.remove(&oldest);
+            }
+        }
+    }
+
+    fn take(&self, hash: u64) -> Option<u64> {
+        self.inner.lock().0.remove(&hash)
+    }
+}
+
+/// Processes drop reports from one of our relays, about packets we sent it ourselves.
+pub fn incoming_drop_reports(
+    ctx: &DaemonContext,
+    relay: RelayFingerprint,
+    reports: DropReports,
+    sent: &SentPackets,
+) {
+    let stats = ctx.get(STATS);
+    stats.add(DROP_REPORT_SUPPRESSED, reports.suppressed);
+    for drop in reports.drops {
+        stats.incr(drop.reason.stat());
+        tracing::debug!(
+            relay = display(relay),
+            reason = debug(drop.reason),
+            packet_hash = drop.packet_hash,
+            "our relay dropped one of our packets"
+        );
+        {
+            let mut observed = ctx.get(OBSERVED_DROPS).lock();
+            observed.push_back(ObservedDrop {
+                relay,
+                reason: drop.reason,
+                packet_hash: drop.packet_hash,
+                at: unix_now(),
+            });
+            if observed.len() > OBSERVED_KEPT {
+                observed.pop_front();
+            }
+        }
+        // whoever sent a NACK-eligible packet is waiting to hear about it, and the relay's own NACK may have been rate limited
+        if let Some(tag) = sent.take(drop.packet_hash) {
+            nack::incoming_nack(ctx, Either::Right(relay), tag, drop.reason.into());
+        }
+    }
+}
+
+/// The drop reports we got from our relays lately, oldest first.
+pub fn observed_drops(ctx: &DaemonContext) -> Vec<ObservedDrop> {
+    ctx.get(OBSERVED_DROPS).lock().iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use super::*;
+
+    #[test]
+    fn only_clients_that_asked_get_reports() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "drop report test",
+                "drop_reports": { "per_sec": 0.0, "burst": 3 }
+            }))
+            .unwrap(),
+        );
+        let pkt = RawPacket::zeroed();
+        let (asked, quiet) = (1, 2);
+        let (mut asked_reports, quiet_reports) = (
+            subscribe_drop_reports(&ctx, asked),
+            subscribe_drop_reports(&ctx, quiet),
+        );
+        assert!(enable_drop_reports(&ctx, asked));
+        for _ in 0..5 {
+            report_drop(&ctx, Either::Left(asked), &pkt, DropReason::RateLimited);
+            report_drop(&ctx, Either::Left(quiet), &pkt, DropReason::RateLimited);
+        }
+        assert!(quiet_reports.recv.is_empty());
+
+        let batch = smol::future::block_on(asked_reports.next_batch(&ctx)).unwrap();
+        // a burst's worth gets reported, and the rest is only counted
+        assert_eq!(batch.drops.len(), 3);
+        assert_eq!(batch.suppressed, 2);
+        assert_eq!(
+            batch.drops[0],
+            DropReport {
+                reason: DropReason::RateLimited,
+                packet_hash: packet_hash(&pkt),
+            }
+        );
+    }
+
+    #[test]
+    fn relays_refuse_by_policy() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "drop report test",
+                "drop_reports": { "serve": false }
+            }))
+            .unwrap(),
+        );
+        assert!(!enable_drop_reports(&ctx, 1));
+        // and clients have nothing to serve them with
+        let client = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        assert!(!enable_drop_reports(&client, 1));
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use earendil_packet::RawPacket;
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    stats::{PercentileEstimator, Percentiles, STATS},
+};
+
+/// Histogram of how long packets we peel take to process, excluding their mix delay.
+pub const PEEL_LATENCY_SECONDS: &str = "forwarding.peel_latency_seconds";
+
+/// How many packets we remember ingress times for. Packets that are dropped never have their ingress time taken back out, so this must be bounded.
+const MAX_TRACKED_PACKETS: usize = 10_000;
+
+/// We only take the ingress time of one packet in this many, which is plenty for percentiles and keeps hashing off most packets.
+const SAMPLE_EVERY: u64 = 16;
+
+/// The ingress times are split over this many maps by packet hash, so that links don't all wait on one lock.
+const INGRESS_SHARDS: usize = 16;
+
+/// The kinds of traffic whose forwarding latency we track separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// Packets we peeled and then forwarded to the next peeler, after their mix delay.
+    Peeled,
+    /// Packets we are not the peeler of, which we pass one hop closer to their peeler.
+    Transit,
+    /// Garbled replies we deliver to one of our client neighbors.
+    Reply,
+}
+
+impl TrafficClass {
+    const ALL: [Self; 3] = [Self::Peeled, Self::Transit, Self::Reply];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Peeled => "peeled",
+            Self::Transit => "transit",
+            Self::Reply => "reply",
+        }
+    }
+}
+
+/// Forwarding latency percentiles of one [TrafficClass].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClassLatency {
+    /// Time between a packet arriving on a link and the corresponding packet being handed to the outgoing link, excluding intentional delay.
+    pub processing: Option<Percentiles>,
+    /// Time packets spent waiting out their intentional mix delay.
+    pub delay_queue: Option<Percentiles>,
+}
+
+struct ForwardingLatency {
+    arrivals: AtomicU64,
+    ingress: [Mutex<LruCache<blake3::Hash, Instant>>; INGRESS_SHARDS],
+    processing: [PercentileEstimator; 3],
+    delay_queue: [PercentileEstimator; 3],
+}
+
+static FORWARDING_LATENCY: CtxField<ForwardingLatency> = |_| ForwardingLatency {
+    arrivals: AtomicU64::new(0),
+    ingress: std::array::from_fn(|_| {
+        Mutex::new(LruCache::new(
+            NonZeroUsize::new(MAX_TRACKED_PACKETS / INGRESS_SHARDS)
+                .expect("must track at least one packet"),
+        ))
+    }),
+    processing: Default::default(),
+    delay_queue: Default::default(),
+};
+
+impl ForwardingLatency {
+    fn shard(&self, packet_hash: &blake3::Hash) -> &Mutex<LruCache<blake3::Hash, Instant>> {
+        &self.ingress[packet_hash.as_bytes()[0] as usize % INGRESS_SHARDS]
+    }
+}
+
+/// Notes that `pkt` just arrived on a link, if it's one of the packets we sample. This is kept on the side, keyed by the packet hash, rather than on the packet itself.
+pub fn mark_ingress(ctx: &DaemonContext, pkt: &RawPacket) {
+    let latency = ctx.get(FORWARDING_LATENCY);
+    if latency.arrivals.fetch_add(1, Ordering::Relaxed) % SAMPLE_EVERY != 0 {
+        return;
+    }
+    let packet_hash = blake3::hash(bytemuck::bytes_of(pkt));
+    latency
+        .shard(&packet_hash)
+        .lock()
+        .put(packet_hash, Instant::now());
+}
+
+/// Takes back out the time the packet with the given hash arrived on a link, if it did and was sampled.
+pub(super) fn take_ingress(ctx: &DaemonContext, packet_hash: blake3::Hash) -> Option<Instant> {
+    ctx.get(FORWARDING_LATENCY)
+        .shard(&packet_hash)
+        .lock()
+        .pop(&packet_hash)
+}
+
+/// Records that a packet of the given class that arrived at `ingress` was just forwarded, after spending `delay` in the delay queue.
+pub(super) fn record_egress(
+    ctx: &DaemonContext,
+    class: TrafficClass,
+    ingress: Instant,
+    delay: Duration,
+) {
+    let latency = ctx.get(FORWARDING_LATENCY);
+    let processing = ingress.elapsed().saturating_sub(delay);
+    latency.processing[class as usize].record(processing);
+    if class == TrafficClass::Peeled {
+        latency.delay_queue[class as usize].record(delay);
+        ctx.get(STATS).observe(PEEL_LATENCY_SECONDS, processing);
+    }
+}
+
+/// Returns the current forwarding latency percentiles, by traffic class.
+pub fn forwarding_latency(ctx: &DaemonContext) -> BTreeMap<String, ClassLatency> {
+    let latency = ctx.get(FORWARDING_LATENCY);
+    TrafficClass::ALL
+        .into_iter()
+        .map(|class| {
+            (
+                class.name().to_string(),
+                ClassLatency {
+                    processing: latency.processing[class as usize].percentiles(),
+                    delay_queue: latency.delay_queue[class as usize].percentiles(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Returns the highest p90 processing latency across traffic classes, if anything was forwarded yet.
+pub(super) fn processing_p90(ctx: &DaemonContext) -> Option<Duration> {
+    ctx.get(FORWARDING_LATENCY)
+        .processing
+        .iter()
+        .filter_map(|estimator| estimator.percentiles())
+        .map(|percentiles| Duration::from_micros(percentiles.p90_us))
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::IdentityDescriptor;
+
+    use crate::{
+        context::RELAY_GRAPH,
+        network::{incoming_raw, subscribe_outgoing_relay},
+    };
+
+    use super::*;
+
+    #[test]
+    fn processing_delay_shows_up_in_percentiles() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "identity_seed": "latency test" })).unwrap(),
+        );
+        let neigh_id = RelayIdentitySecret::generate();
+        let neigh = neigh_id.public().fingerprint();
+        ctx.get(RELAY_GRAPH)
+            .write()
+            .insert_identity(IdentityDescriptor::new(&neigh_id, &DhSecret::generate()))
+            .unwrap();
+        let link = subscribe_outgoing_relay(&ctx, neigh);
+
+        let pkt: RawPacket = bytemuck::Zeroable::zeroed();
+        mark_ingress(&ctx, &pkt);
+        // a synthetic processing delay between ingress and forwarding
+        std::thread::sleep(Duration::from_millis(50));
+        smol::future::block_on(incoming_raw(&ctx, neigh, pkt, Some(neigh), None)).unwrap();
+        assert!(link.try_recv().is_ok());
+
+        let report = forwarding_latency(&ctx);
+        let transit = report["transit"].processing.unwrap();
+        assert_eq!(transit.samples, 1);
+        assert!(transit.p50_us >= 50_000);
+        assert!(transit.p99_us >= transit.p50_us);
+        assert!(report["transit"].delay_queue.is_none());
+        assert!(report["peeled"].processing.is_none());
+    }
+
+    #[test]
+    fn only_some_packets_are_sampled() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "identity_seed": "latency sampling" }))
+                .unwrap(),
+        );
+        let pkts: Vec<RawPacket> = (0..SAMPLE_EVERY as u8 * 4)
+            .map(|i| {
+                let mut pkt: RawPacket = bytemuck::Zeroable::zeroed();
+                bytemuck::bytes_of_mut(&mut pkt)[0] = i;
+                pkt
+            })
+            .collect();
+        for pkt in &pkts {
+            mark_ingress(&ctx, pkt);
+        }
+        let sampled = pkts
+            .iter()
+            .filter(|pkt| take_ingress(&ctx, blake3::hash(bytemuck::bytes_of(*pkt))).is_some())
+            .count();
+        assert_eq!(sampled, 4);
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use earendil_crypt::{ClientId, RelayFingerprint};
+use either::Either;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol::channel::{Receiver, Sender};
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    stats::STATS,
+};
+
+use super::spider::Spider;
+
+/// How long after passing on a NACK-eligible packet we still pass back a NACK for it. Past this, whoever sent it has long given up waiting.
+const FORWARDED_TTL: Duration = Duration::from_secs(30);
+
+/// How many NACK-eligible packets we remember passing on, at most. Past this, the oldest are forgotten early, and NACKs for them go nowhere.
+const MAX_FORWARDED: usize = 65536;
+
+/// How many NACKs per second each neighbor may be sent, at most.
+const NACKS_PER_SEC: f64 = 10.0;
+const NACK_BURST: f64 = 20.0;
+
+/// How many of our own packets per second we mark NACK-eligible, at most. Marked packets stand out to every relay along their path, so we spend the marking sparingly.
+const MARKED_PER_SEC: f64 = 2.0;
+const MARKED_BURST: f64 = 10.0;
+
+pub const NACK_SENT: &str = "nack.sent";
+pub const NACK_RATE_LIMITED: &str = "nack.rate_limited";
+pub const NACK_RECEIVED: &str = "nack.received";
+pub const NACK_FORGOTTEN: &str = "nack.forgotten";
+
+/// A neighbor, which may be a client or a relay.
+pub type NeighborId = Either<ClientId, RelayFingerprint>;
+
+/// Why a relay dropped a NACK-eligible packet. Deliberately coarse, so that provoking NACKs teaches little about the relay.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NackReason {
+    /// There is no route to the next peeler, or the link to the next hop just went away.
+    Unroutable,
+    /// The relay is shedding load.
+    Overloaded,
+    /// The relay refused to forward the packet.
+    Policy,
+}
+
+/// Where a NACK-eligible packet came from, so that a NACK for it can retrace its steps.
+pub enum NackOrigin {
+    /// We sent the packet ourselves, and the sender waits on the other end of this channel.
+    Local(Sender<NackReason>),
+    /// A neighbor sent the packet to us under this tag.
+    Neighbor { neighbor: NeighborId, tag: u64 },
+}
+
+static NACKS: CtxField<Mutex<NackState>> = |_| Mutex::new(NackState::new(Instant::now()));
+
+static NACK_SPIDER: CtxField<Spider<NeighborId, (u64, NackReason)>> = |_| Spider::new();
+
+struct NackState {
+    /// NACK-eligible packets we passed on, by the tag we passed them on under.
+    forwarded: HashMap<u64, Forwarded>,
+    /// The tags in `forwarded`, oldest first. Tags taken back or NACKed stay here until they expire, so this bounds `forwarded` too.
+    expiry: VecDeque<(Instant, u64)>,
+    /// How many NACKs each neighbor may still be sent right now.
+    budgets: HashMap<NeighborId, Budget>,
+    /// How many of our own packets may still be marked right now.
+    marking: Budget,
+}
+
+struct Forwarded {
+    next_hop: RelayFingerprint,
+    origin: NackOrigin,
+}
+
+/// A token bucket.
+pub(super) struct Budget {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Budget {
+    pub(super) fn full(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    pub(super) fn take(&mut self, per_sec: f64, burst: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl NackState {
+    fn new(now: Instant) -> Self {
+        Self {
+            forwarded: HashMap::new(),
+            expiry: VecDeque::new(),
+            budgets: HashMap::new(),
+            marking: Budget::full(MARKED_BURST, now),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(sent, tag)) = self.expiry.front() {
+            if now.saturating_duration_since(sent) < FORWARDED_TTL {
+                break;
+            }
+            self.expiry.pop_front();
+            self.forwarded.remove(&tag);
+        }
+    }
+
+    /// Remembers a packet passed on under `tag`, forgetting the oldest ones to make room if need be. Returns how many still-pending packets were forgotten.
+    fn remember(&mut self, tag: u64, forwarded: Forwarded, now: Instant) -> usize {
+        self.expire(now);
+        let mut forgotten = 0;
+        while self.expiry.len() >= MAX_FORWARDED {
+            let Some((_, oldest)) = self.expiry.pop_front() else {
+                break;
+            };
+            if self.forwarded.remove(&oldest).is_some() {
+                forgotten += 1;
+            }
+        }
+        self.forwarded.insert(tag, forwarded);
+        self.expiry.push_back((now, tag));
+        forgotten
+    }
+}
+
+/// Subscribe to the NACKs that should go back to the given neighbor.
+pub fn subscribe_nacks(ctx: &DaemonContext, neigh: NeighborId) -> Receiver<(u64, NackReason)> {
+    ctx.get(NACK_SPIDER).subscribe(neigh)
+}
+
+/// Marks a packet we are about to send as NACK-eligible, returning the origin to send it with, and where a NACK for it arrives if a relay drops it. Returns `None` if we marked too many packets lately, in which case the packet should go out unmarked.
+pub fn mark_local(ctx: &DaemonContext) -> Option<(NackOrigin, Receiver<NackReason>)> {
+    if !ctx
+        .get(NACKS)
+        .lock()
+        .marking
+        .take(MARKED_PER_SEC, MARKED_BURST, Instant::now())
+    {
+        return None;
+    }
+    let (send, recv) = smol::channel::bounded(1);
+    Some((NackOrigin::Local(send), recv))
+}
+
+/// Remembers that a NACK-eligible packet is being passed on to `next_hop`, returning the tag to pass it on under. Every hop gets a fresh tag, so that relays along the path can't tell that they saw the same packet by its tag.
+pub(super) fn forwarded(
+    ctx: &DaemonContext,
+    next_hop: RelayFingerprint,
+    origin: NackOrigin,
+) -> u64 {
+    let tag = rand::random();
+    let forgotten =
+        ctx.get(NACKS)
+            .lock()
+            .remember(tag, Forwarded { next_hop, origin }, Instant::now());
+    if forgotten > 0 {
+        ctx.get(STATS).add(NACK_FORGOTTEN, forgotten as u64);
+    }
+    tag
+}
+
+/// Takes back a packet that was tagged by [forwarded], but then couldn't be passed on after all, NACKing it instead.
+pub(super) fn unforward(ctx: &DaemonContext, tag: u64, reason: NackReason) {
+    let forwarded = ctx.get(NACKS).lock().forwarded.remove(&tag);
+    if let Some(forwarded) = forwarded {
+        dropped(ctx, Some(forwarded.origin), reason);
+    }
+}
+
+/// Sends a NACK back towards the sender of a packet we dropped, if the packet was NACK-eligible.
+pub fn dropped(ctx: &DaemonContext, origin: Option<NackOrigin>, reason: NackReason) {
+    match origin {
+        None => {}
+        Some(NackOrigin::Local(send)) => {
+            let _ = send.try_send(reason);
+        }
+        Some(NackOrigin::Neighbor { neighbor, tag }) => {
+            let allowed = ctx
+                .get(NACKS)
+                .lock()
+                .budgets
+                .entry(neighbor)
+                .or_insert_with(|| Budget::full(NACK_BURST, Instant::now()))
+                .take(NACKS_PER_SEC, NACK_BURST, Instant::now());
+            if !allowed {
+                ctx.get(STATS).incr(NACK_RATE_LIMITED);
+                return;
+            }
+            tracing::trace!(
+                neighbor = display(neighbor),
+                reason = debug(reason),
+                "sending a NACK"
+            );
+            if ctx.get(NACK_SPIDER).send(&neighbor, (tag, reason)).is_ok() {
+                ctx.get(STATS).incr(NACK_SENT);
+            }
+        }
+    }
+}
+
+/// Processes a NACK from a neighbor, passing it back the way the dropped packet came.
+pub fn incoming_nack(ctx: &DaemonContext, from: NeighborId, tag: u64, reason: NackReason) {
+    let forwarded = {
+        let mut state = ctx.get(NACKS).lock();
+        state.expire(Instant::now());
+        match state.forwarded.get(&tag) {
+            // only the neighbor we passed the packet on to can NACK it
+            Some(forwarded) if Either::Right(forwarded.next_hop) == from => {
+                state.forwarded.remove(&tag)
+            }
+            _ => None,
+        }
+    };
+    let Some(forwarded) = forwarded else {
+        tracing::trace!(from = display(from), "dropping a NACK for an unknown tag");
+        return;
+    };
+    ctx.get(STATS).incr(NACK_RECEIVED);
+    dropped(ctx, Some(forwarded.origin), reason);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nacks_are_rate_limited_per_neighbor() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        let [noisy, quiet]: [NeighborId; 2] = [Either::Left(1), Either::Left(2)];
+        let (noisy_nacks, quiet_nacks) =
+            (subscribe_nacks(&ctx, noisy), subscribe_nacks(&ctx, quiet));
+        for tag in 0..100 {
+            let origin = NackOrigin::Neighbor {
+                neighbor: noisy,
+                tag,
+            };
+            dropped(&ctx, Some(origin), NackReason::Unroutable);
+        }
+        let origin = NackOrigin::Neighbor {
+            neighbor: quiet,
+            tag: 0,
+        };
+        dropped(&ctx, Some(origin), NackReason::Overloaded);
+
+        // a burst's worth gets through, and the rest is dropped
+        let sent: Vec<u64> = std::iter::from_fn(|| noisy_nacks.try_recv().ok())
+            .map(|(tag, _)| tag)
+            .collect();
+        assert_eq!(sent, (0..NACK_BURST as u64).collect::<Vec<_>>());
+        // without eating into anyone else's budget
+        assert_eq!(quiet_nacks.try_recv().unwrap(), (0, NackReason::Overloaded));
+    }
+
+    #[test]
+    fn forwarded_packets_are_bounded() {
+        let now = Instant::now();
+        let mut state = NackState::new(now);
+        let next_hop = earendil_crypt::RelayIdentitySecret::generate()
+            .public()
+            .fingerprint();
+        let origin = |tag| NackOrigin::Neighbor {
+            neighbor: Either::Left(1),
+            tag,
+        };
+        let mut forgotten = 0;
+        for tag in 0..MAX_FORWARDED as u64 + 100 {
+            let forwarded = Forwarded {
+                next_hop,
+                origin: origin(tag),
+            };
+            forgotten += state.remember(tag, forwarded, now);
+            // packets that got NACKed still take up room until they expire, but aren't counted as forgotten
+            if tag < 10 {
+                state.forwarded.remove(&tag);
+            }
+        }
+        assert_eq!(state.forwarded.len(), MAX_FORWARDED);
+        assert_eq!(state.expiry.len(), MAX_FORWARDED);
+        assert_eq!(forgotten, 90);
+        // the oldest went first
+        assert!(!state.forwarded.contains_key(&99));
+        assert!(state.forwarded.contains_key(&100));
+    }
+}
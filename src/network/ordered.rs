@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use dashmap::DashMap;
+use earendil_crypt::RelayFingerprint;
+use smol::channel::{Receiver, Sender, TrySendError};
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    scope::{spawn_scoped, Stage},
+    stats::STATS,
+};
+
+use super::{
+    delay_queue::Delayed,
+    nack::{self, NackReason},
+    send_delayed, send_limit,
+};
+
+/// Packets dropped, and NACKed, because the lane to their next peeler was full.
+pub const LANE_FULL: &str = "ordered_forwarding.lane_full";
+
+/// How long a lane waits for another packet before it goes away.
+const LANE_IDLE: Duration = Duration::from_secs(10);
+
+/// How many packets may wait in one lane. A lane fills up when its next peeler takes packets slower than their mix delays run out, and the packets past this are better dropped than queued without end.
+const LANE_CAPACITY: usize = 1000;
+
+/// One lane per next peeler with packets on their way to it, each sending its packets one after another. A peeled packet's next peeler is the neighbor it goes to, unless that neighbor went away and it has to be routed further, so this keeps the packets to each next hop in order.
+static LANES: CtxField<DashMap<RelayFingerprint, Sender<Delayed>>> = |_| DashMap::new();
+
+/// Sends a packet whose mix delay ran out after every packet to the same next peeler that ran out before it. If too many are already waiting, it's dropped and NACKed instead.
+pub(super) fn enqueue(ctx: &DaemonContext, delayed: Delayed) {
+    let next_peeler = delayed.next_peeler;
+    // the lane is only ever sent to, and removed, with its entry locked, so a packet can't land in a lane that's going away
+    let lane = ctx.get(LANES).entry(next_peeler).or_insert_with(|| {
+        let (send, recv) = smol::channel::bounded(LANE_CAPACITY);
+        spawn_scoped(
+            ctx,
+            Stage::Inflight,
+            "ordered_send",
+            lane_loop(ctx.clone(), next_peeler, recv),
+        );
+        send
+    });
+    match lane.try_send(delayed) {
+        Ok(()) => {}
+        Err(TrySendError::Full(delayed)) => {
+            tracing::trace!(
+                next_peeler = display(next_peeler),
+                "dropping a delayed packet, since its lane is full"
+            );
+            ctx.get(STATS).incr(LANE_FULL);
+            nack::dropped(ctx, delayed.origin, NackReason::Overloaded);
+        }
+        Err(TrySendError::Closed(_)) => {
+            tracing::debug!(
+                next_peeler = display(next_peeler),
+                "dropping a delayed packet, since the daemon is stopping"
+            );
+        }
+    }
+}
+
+async fn lane_loop(ctx: DaemonContext, next_peeler: RelayFingerprint, recv: Receiver<Delayed>) {
+    loop {
+        match recv.recv().timeout(LANE_IDLE).await {
+            Some(Ok(delayed)) => {
+                let permit = send_limit::acquire_send(&ctx, next_peeler).await;
+                if let Err(err) = send_delayed(&ctx, delayed, permit).await {
+                    tracing::debug!(
+                        next_peeler = display(next_peeler),
+                        "ordered send failed: {err:?}"
+                    );
+                }
+            }
+            Some(Err(_)) => return,
+            None => {
+                if ctx
+                    .get(LANES)
+                    .remove_if(&next_peeler, |_, lane| lane.is_empty())
+                    .is_some()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+
+    use crate::network::nack::NackOrigin;
+
+    use super::*;
+
+    #[test]
+    fn full_lanes_drop_and_nack() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "ordered_forwarding": true,
+                "send_concurrency": { "per_destination": 1 },
+            }))
+            .unwrap(),
+        );
+        let next_peeler = RelayIdentitySecret::generate().public().fingerprint();
+        let (send_nack, recv_nack) = smol::channel::unbounded();
+        let delayed = || Delayed {
+            pkt: bytemuck::Zeroable::zeroed(),
+            next_peeler,
+            origin: Some(NackOrigin::Local(send_nack.clone())),
+            ingress: None,
+            queued: std::time::Instant::now(),
+        };
+        smol::block_on(async {
+            // holding the only permit stalls the lane at its first packet
+            let _permit = send_limit::acquire_send(&ctx, next_peeler).await;
+            enqueue(&ctx, delayed());
+            smol::Timer::after(Duration::from_millis(100)).await;
+            for _ in 0..LANE_CAPACITY + 5 {
+                enqueue(&ctx, delayed());
+            }
+            assert_eq!(ctx.get(STATS).snapshot()[LANE_FULL], 5);
+            for _ in 0..5 {
+                assert_eq!(recv_nack.try_recv().unwrap(), NackReason::Overloaded);
+            }
+            assert!(recv_nack.try_recv().is_err());
+        });
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::OverloadConfig,
+    context::{CtxField, DaemonContext},
+    stats::STATS,
+};
+
+use super::latency::processing_p90;
+
+/// How often the load level is re-evaluated. Evaluating it sorts the latency window, so it isn't done for every packet.
+const EVAL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How loaded a relay is, from fine to "route around me".
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LoadLevel {
+    /// Keeping up; nothing is shed.
+    #[default]
+    Green,
+    /// Falling behind; a growing fraction of transit packets is shed.
+    Yellow,
+    /// Overloaded; transit packets are shed at the maximum ratio, and our descriptor asks others to route around us.
+    Red,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LoadState {
+    pub level: LoadLevel,
+    /// Packets being processed at once when the level was last evaluated.
+    pub backlog: usize,
+    /// The highest p90 processing latency across traffic classes when the level was last evaluated.
+    pub processing_p90_us: u64,
+    /// Fraction of transit packets currently being shed.
+    pub shed_ratio: f64,
+}
+
+struct Overload {
+    backlog: AtomicUsize,
+    /// The latest load state, and when it was evaluated.
+    state: Mutex<(LoadState, Option<Instant>)>,
+}
+
+static OVERLOAD: CtxField<Overload> = |_| Overload {
+    backlog: AtomicUsize::new(0),
+    state: Mutex::new((LoadState::default(), None)),
+};
+
+/// A packet being processed, counted in the backlog until dropped.
+pub(super) struct Backlogged<'a>(&'a AtomicUsize);
+
+impl Drop for Backlogged<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a packet in the backlog for as long as the returned guard lives.
+pub(super) fn enter_backlog(ctx: &DaemonContext) -> Backlogged<'_> {
+    let backlog = &ctx.get(OVERLOAD).backlog;
+    backlog.fetch_add(1, Ordering::Relaxed);
+    Backlogged(backlog)
+}
+
+/// Returns the current load state, re-evaluating it if it's been a while.
+pub fn load_state(ctx: &DaemonContext) -> LoadState {
+    let overload = ctx.get(OVERLOAD);
+    let Some(config) = ctx.init().overload_shedding else {
+        return LoadState {
+            backlog: overload.backlog.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+    };
+    let mut state = overload.state.lock();
+    if state.1.map_or(true, |at| at.elapsed() >= EVAL_INTERVAL) {
+        let prev = state.0.level;
+        let next = evaluate(
+            &config,
+            prev,
+            overload.backlog.load(Ordering::Relaxed),
+            processing_p90(ctx).unwrap_or_default(),
+        );
+        if next.level != prev {
+            tracing::warn!(
+                prev = debug(prev),
+                next = debug(next.level),
+                backlog = next.backlog,
+                processing_p90_us = next.processing_p90_us,
+                "load level changed"
+            );
+            ctx.get(STATS).incr("overload.level_changes");
+        }
+        *state = (next, Some(Instant::now()));
+    }
+    state.0.clone()
+}
+
+/// Whether we are overloaded enough to ask others to route around us.
+pub fn is_overloaded(ctx: &DaemonContext) -> bool {
+    load_state(ctx).level == LoadLevel::Red
+}
+
+/// Decides whether to drop a transit packet to shed load, counting the drop if so.
+pub(super) fn shed_transit(ctx: &DaemonContext) -> bool {
+    let shed_ratio = load_state(ctx).shed_ratio;
+    if shed_ratio > 0.0 && rand::thread_rng().gen_bool(shed_ratio.clamp(0.0, 1.0)) {
+        ctx.get(STATS).incr("overload.shed_transit");
+        true
+    } else {
+        false
+    }
+}
+
+fn evaluate(
+    config: &OverloadConfig,
+    prev: LoadLevel,
+    backlog: usize,
+    processing_p90: Duration,
+) -> LoadState {
+    let backlog_f = backlog as f64;
+    let latency_ms = processing_p90.as_secs_f64() * 1000.0;
+    let above = |backlog_threshold: usize, latency_threshold_ms: u64, scale: f64| {
+        backlog_f >= backlog_threshold as f64 * scale
+            || latency_ms >= latency_threshold_ms as f64 * scale
+    };
+    let above_red = |scale| above(config.red_backlog, config.red_latency_ms, scale);
+    let above_yellow = |scale| above(config.yellow_backlog, config.yellow_latency_ms, scale);
+
+    let mut level = if above_red(1.0) {
+        LoadLevel::Red
+    } else if above_yellow(1.0) {
+        LoadLevel::Yellow
+    } else {
+        LoadLevel::Green
+    };
+    // a level is only left once load drops well below its thresholds
+    if prev == LoadLevel::Red && above_red(config.hysteresis) {
+        level = LoadLevel::Red;
+    } else if prev >= LoadLevel::Yellow && above_yellow(config.hysteresis) {
+        level = level.max(LoadLevel::Yellow);
+    }
+
+    let shed_ratio = match level {
+        LoadLevel::Green => 0.0,
+        LoadLevel::Yellow => {
+            // how far we are from the yellow thresholds towards the red ones, by whichever signal is further along
+            let progress = |value: f64, yellow: f64, red: f64| {
+                ((value - yellow) / (red - yellow).max(f64::EPSILON)).clamp(0.0, 1.0)
+            };
+            let progress = progress(
+                backlog_f,
+                config.yellow_backlog as f64,
+                config.red_backlog as f64,
+            )
+            .max(progress(
+                latency_ms,
+                config.yellow_latency_ms as f64,
+                config.red_latency_ms as f64,
+            ));
+            config.min_shed_ratio + (config.max_shed_ratio - config.min_shed_ratio) * progress
+        }
+        LoadLevel::Red => config.max_shed_ratio,
+    };
+
+    LoadState {
+        level,
+        backlog,
+        processing_p90_us: processing_p90.as_micros() as u64,
+        shed_ratio,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::{crypt::DhSecret, RawPacket};
+    use earendil_topology::IdentityDescriptor;
+
+    use crate::{
+        context::RELAY_GRAPH,
+        network::{incoming_raw, subscribe_outgoing_relay},
+    };
+
+    use super::*;
+
+    fn config() -> OverloadConfig {
+        serde_json::from_value(serde_json::json!({
+            "yellow_backlog": 100,
+            "red_backlog": 200,
+            "yellow_latency_ms": 1_000_000,
+            "red_latency_ms": 2_000_000,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn levels_have_hysteresis() {
+        let config = config();
+        let level = |prev, backlog| evaluate(&config, prev, backlog, Duration::ZERO).level;
+        assert_eq!(level(LoadLevel::Green, 90), LoadLevel::Green);
+        assert_eq!(level(LoadLevel::Green, 100), LoadLevel::Yellow);
+        assert_eq!(level(LoadLevel::Yellow, 85), LoadLevel::Yellow);
+        assert_eq!(level(LoadLevel::Yellow, 79), LoadLevel::Green);
+        assert_eq!(level(LoadLevel::Yellow, 200), LoadLevel::Red);
+        assert_eq!(level(LoadLevel::Red, 170), LoadLevel::Red);
+        assert_eq!(level(LoadLevel::Red, 150), LoadLevel::Yellow);
+        assert_eq!(level(LoadLevel::Red, 10), LoadLevel::Green);
+
+        let shed =
+            |backlog| evaluate(&config, LoadLevel::Green, backlog, Duration::ZERO).shed_ratio;
+        assert!(shed(100) < shed(150));
+        assert!(shed(150) < shed(200));
+        assert_eq!(shed(50), 0.0);
+    }
+
+    #[test]
+    fn sheds_transit_but_not_client_traffic() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "overload test",
+                "overload_shedding": {
+                    "yellow_backlog": 2,
+                    "red_backlog": 4,
+                    "yellow_latency_ms": 1_000_000,
+                    "red_latency_ms": 2_000_000,
+                },
+            }))
+            .unwrap(),
+        );
+        let neigh_id = RelayIdentitySecret::generate();
+        let neigh = neigh_id.public().fingerprint();
+        ctx.get(RELAY_GRAPH)
+            .write()
+            .insert_identity(IdentityDescriptor::new(&neigh_id, &DhSecret::generate()))
+            .unwrap();
+        let link = subscribe_outgoing_relay(&ctx, neigh);
+        let send = |i: u64, sheddable: bool| {
+            let mut pkt: RawPacket = bytemuck::Zeroable::zeroed();
+            bytemuck::bytes_of_mut(&mut pkt)[..8].copy_from_slice(&i.to_le_bytes());
+            smol::future::block_on(incoming_raw(
+                &ctx,
+                neigh,
+                pkt,
+                sheddable.then_some(neigh),
+                None,
+            ))
+            .unwrap();
+        };
+
+        // drive the relay past its capacity
+        let stuck: Vec<_> = (0..10).map(|_| enter_backlog(&ctx)).collect();
+        assert!(is_overloaded(&ctx));
+        for i in 0..200 {
+            send(i, true);
+        }
+        let transit = std::iter::from_fn(|| link.try_recv().ok()).count();
+        assert!(transit < 100, "forwarded {transit}/200 transit packets");
+        assert_eq!(
+            ctx.get(STATS).snapshot()["overload.shed_transit"],
+            200 - transit as u64
+        );
+        // direct clients are still fully served
+        for i in 200..250 {
+            send(i, false);
+        }
+        assert_eq!(std::iter::from_fn(|| link.try_recv().ok()).count(), 50);
+
+        // and once the backlog clears, so is everyone else
+        drop(stuck);
+        std::thread::sleep(EVAL_INTERVAL);
+        assert_eq!(load_state(&ctx).level, LoadLevel::Green);
+        for i in 250..300 {
+            send(i, true);
+        }
+        assert_eq!(std::iter::from_fn(|| link.try_recv().ok()).count(), 50);
+    }
+}
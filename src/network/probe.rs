@@ -0,0 +1,136 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_event::Event;
+use earendil_crypt::RelayFingerprint;
+use parking_lot::Mutex;
+use smol_timeout::TimeoutExt;
+
+use crate::context::{CtxField, DaemonContext};
+
+use super::one_hop_closer;
+
+/// How long a routing miss waits for a probe to find a route, before giving up.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Destinations we failed to route to, which links probe their neighbors about.
+struct RouteProbes {
+    /// Every wanted destination, with the generation it was last requested in and when.
+    wanted: Mutex<HashMap<RelayFingerprint, (u64, Instant)>>,
+    generation: AtomicU64,
+    requested: Event,
+    learned: Event,
+}
+
+static ROUTE_PROBES: CtxField<RouteProbes> = |_| RouteProbes {
+    wanted: Default::default(),
+    generation: Default::default(),
+    requested: Event::new(),
+    learned: Event::new(),
+};
+
+/// Asks our links to probe for a route to `dest`, and waits until one of them learns enough of the graph to route one hop closer to it.
+pub async fn probe_for_route(
+    ctx: &DaemonContext,
+    dest: RelayFingerprint,
+) -> anyhow::Result<RelayFingerprint> {
+    let probes = ctx.get(ROUTE_PROBES);
+    let generation = probes.generation.fetch_add(1, Ordering::SeqCst) + 1;
+    probes
+        .wanted
+        .lock()
+        .insert(dest, (generation, Instant::now()));
+    tracing::debug!(
+        dest = display(dest),
+        "probing for a route after a routing miss"
+    );
+    probes.requested.notify_all();
+    probes
+        .learned
+        .wait_until(|| one_hop_closer(ctx, dest).ok())
+        .timeout(PROBE_TIMEOUT)
+        .await
+        .context(format!("probing for a route to {dest} timed out"))
+}
+
+/// Waits for destinations requested after generation `seen`, returning them along with the newest generation.
+pub async fn wanted_probes(ctx: &DaemonContext, seen: u64) -> (u64, Vec<RelayFingerprint>) {
+    let probes = ctx.get(ROUTE_PROBES);
+    probes
+        .requested
+        .wait_until(|| {
+            let mut wanted = probes.wanted.lock();
+            // nobody is waiting for stale probes anymore
+            wanted.retain(|_, (_, requested)| requested.elapsed() < PROBE_TIMEOUT);
+            let fresh: Vec<_> = wanted
+                .iter()
+                .filter(|(_, (generation, _))| *generation > seen)
+                .collect();
+            let newest = fresh.iter().map(|(_, (generation, _))| *generation).max()?;
+            Some((newest, fresh.into_iter().map(|(dest, _)| *dest).collect()))
+        })
+        .await
+}
+
+/// Tells everybody waiting in [probe_for_route] that the relay graph grew. Returns whether we can now route towards `dest`.
+pub fn route_learned(ctx: &DaemonContext, dest: RelayFingerprint) -> bool {
+    ctx.get(ROUTE_PROBES).learned.notify_all();
+    let found = one_hop_closer(ctx, dest).is_ok();
+    if found {
+        ctx.get(ROUTE_PROBES).wanted.lock().remove(&dest);
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::{crypt::DhSecret, RawPacket};
+    use earendil_topology::IdentityDescriptor;
+
+    use crate::{
+        context::RELAY_GRAPH,
+        ledger::unix_now,
+        network::{send_raw, subscribe_outgoing_relay},
+        test_util::{adjacency, known_relay},
+    };
+
+    use super::*;
+
+    #[test]
+    fn routing_miss_probes_for_route() {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "probe_route_misses": true })).unwrap(),
+        );
+        let neigh_id = known_relay(&ctx);
+        let dest_id = RelayIdentitySecret::generate();
+        let [neigh, dest] = [neigh_id, dest_id].map(|id| id.public().fingerprint());
+        let link = subscribe_outgoing_relay(&ctx, neigh);
+        assert!(one_hop_closer(&ctx, dest).is_err());
+
+        // our neighbor knows the destination, and tells us about it when probed
+        let neighbor = async {
+            let (_, wanted) = wanted_probes(&ctx, 0).await;
+            assert_eq!(wanted, vec![dest]);
+            let mut graph = ctx.get(RELAY_GRAPH).write();
+            graph
+                .insert_identity(IdentityDescriptor::new(&dest_id, &DhSecret::generate()))
+                .unwrap();
+            graph
+                .insert_adjacency(adjacency(&neigh_id, &dest_id, unix_now()))
+                .unwrap();
+            drop(graph);
+            assert!(route_learned(&ctx, dest));
+        };
+        let send = send_raw(&ctx, bytemuck::Zeroable::zeroed(), dest);
+        let ((), sent) = smol::future::block_on(smol::future::zip(neighbor, send));
+        sent.unwrap();
+
+        let (_, next_peeler, _): (RawPacket, _, _) = link.try_recv().unwrap();
+        assert_eq!(next_peeler, dest);
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use earendil_crypt::RelayFingerprint;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol::lock::{Semaphore, SemaphoreGuardArc};
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    stats::STATS,
+};
+
+pub const SEND_WAITED: &str = "send_concurrency.waited";
+pub const SEND_EXPIRED: &str = "send_concurrency.expired";
+
+/// We look for idle destinations at most this often, or as often as they expire if that's sooner.
+const MAX_PURGE_INTERVAL: Duration = Duration::from_secs(10);
+
+struct DestinationLimit {
+    permits: Arc<Semaphore>,
+    in_use: AtomicUsize,
+    waiting: AtomicUsize,
+    last_used: Mutex<Instant>,
+}
+
+/// The send limit of every destination we recently sent to. Destinations left idle for `idle_expiry_secs` are dropped, so that this doesn't grow with every destination ever contacted.
+static SEND_LIMITS: CtxField<DashMap<RelayFingerprint, Arc<DestinationLimit>>> = |_| DashMap::new();
+
+static LAST_PURGE: CtxField<Mutex<Instant>> = |_| Mutex::new(Instant::now());
+
+/// How busy sends to one destination are.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SendConcurrency {
+    pub destination: RelayFingerprint,
+    pub in_use: usize,
+    pub waiting: usize,
+    pub limit: usize,
+}
+
+/// A send to a destination, counted against the destination's limit until dropped.
+pub struct SendPermit {
+    limit: Arc<DestinationLimit>,
+    _guard: SemaphoreGuardArc,
+}
+
+impl Drop for SendPermit {
+    fn drop(&mut self) {
+        self.limit.in_use.fetch_sub(1, Ordering::Relaxed);
+        *self.limit.last_used.lock() = Instant::now();
+    }
+}
+
+/// Waits until fewer than `send_concurrency.per_destination` sends to `destination` are in flight, then counts one more until the returned permit is dropped.
+pub async fn acquire_send(ctx: &DaemonContext, destination: RelayFingerprint) -> SendPermit {
+    purge_idle(ctx);
+    let limit = ctx
+        .get(SEND_LIMITS)
+        .entry(destination)
+        .or_insert_with(|| {
+            Arc::new(DestinationLimit {
+                permits: Arc::new(Semaphore::new(ctx.init().send_concurrency.per_destination)),
+                in_use: AtomicUsize::new(0),
+                waiting: AtomicUsize::new(0),
+                last_used: Mutex::new(Instant::now()),
+            })
+        })
+        .clone();
+    let guard = match limit.permits.try_acquire_arc() {
+        Some(guard) => guard,
+        None => {
+            ctx.get(STATS).incr(SEND_WAITED);
+            limit.waiting.fetch_add(1, Ordering::Relaxed);
+            scopeguard::defer!({
+                limit.waiting.fetch_sub(1, Ordering::Relaxed);
+            });
+            limit.permits.acquire_arc().await
+        }
+    };
+    limit.in_use.fetch_add(1, Ordering::Relaxed);
+    *limit.last_used.lock() = Instant::now();
+    SendPermit {
+        limit,
+        _guard: guard,
+    }
+}
+
+/// Lists the destinations with sends in flight or waiting, the ones with the most waiting first.
+pub fn send_concurrency(ctx: &DaemonContext) -> Vec<SendConcurrency> {
+    let limit = ctx.init().send_concurrency.per_destination;
+    let mut busy: Vec<SendConcurrency> = ctx
+        .get(SEND_LIMITS)
+        .iter()
+        .map(|entry| SendConcurrency {
+            destination: *entry.key(),
+            in_use: entry.in_use.load(Ordering::Relaxed),
+            waiting: entry.waiting.load(Ordering::Relaxed),
+            limit,
+        })
+        .filter(|dest| dest.in_use > 0 || dest.waiting > 0)
+        .collect();
+    busy.sort_by_key(|dest| {
+        (
+            std::cmp::Reverse(dest.waiting),
+            std::cmp::Reverse(dest.in_use),
+        )
+    });
+    busy
+}
+
+/// How many destinations we currently keep a send limit for.
+pub fn tracked_destinations(ctx: &DaemonContext) -> usize {
+    ctx.get(SEND_LIMITS).len()
+}
+
+fn purge_idle(ctx: &DaemonContext) {
+    let expiry = Duration::from_secs(ctx.init().send_concurrency.idle_expiry_secs);
+    {
+        let mut last_purge = ctx.get(LAST_PURGE).lock();
+        if last_purge.elapsed() < expiry.min(MAX_PURGE_INTERVAL) {
+            return;
+        }
+        *last_purge = Instant::now();
+    }
+    ctx.get(SEND_LIMITS).retain(|_, limit| {
+        // whoever else holds the limit is about to use it, even if nothing is counted against it yet
+        let idle = Arc::strong_count(limit) == 1
+            && limit.in_use.load(Ordering::Relaxed) == 0
+            && limit.waiting.load(Ordering::Relaxed) == 0
+            && limit.last_used.lock().elapsed() >= expiry;
+        if idle {
+            ctx.get(STATS).incr(SEND_EXPIRED);
+        }
+        !idle
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use smol_timeout::TimeoutExt;
+
+    use super::*;
+
+    fn ctx(per_destination: usize, idle_expiry_secs: u64) -> DaemonContext {
+        DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "send_concurrency": {
+                    "per_destination": per_destination,
+                    "idle_expiry_secs": idle_expiry_secs,
+                }
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn destination() -> RelayFingerprint {
+        RelayIdentitySecret::generate().public().fingerprint()
+    }
+
+    #[test]
+    fn sends_past_the_limit_wait() {
+        let ctx = ctx(3, 60);
+        let (busy, other) = (destination(), destination());
+        smol::block_on(async {
+            let mut permits = vec![];
+            for _ in 0..3 {
+                permits.push(acquire_send(&ctx, busy).await);
+            }
+            let mut last = Box::pin(acquire_send(&ctx, busy));
+            assert!((&mut last)
+                .timeout(Duration::from_millis(100))
+                .await
+                .is_none());
+            assert_eq!(
+                send_concurrency(&ctx),
+                vec![SendConcurrency {
+                    destination: busy,
+                    in_use: 3,
+                    waiting: 1,
+                    limit: 3,
+                }]
+            );
+            assert_eq!(ctx.get(STATS).snapshot()[SEND_WAITED], 1);
+            // other destinations aren't held up
+            assert!(acquire_send(&ctx, other)
+                .timeout(Duration::from_millis(100))
+                .await
+                .is_some());
+
+            permits.pop();
+            let _last = last.timeout(Duration::from_secs(1)).await.unwrap();
+            assert_eq!(send_concurrency(&ctx)[0].waiting, 0);
+            assert_eq!(send_concurrency(&ctx)[0].in_use, 3);
+        });
+    }
+
+    #[test]
+    fn idle_destinations_expire() {
+        let ctx = ctx(1, 0);
+        let (idle, held) = (destination(), destination());
+        smol::block_on(async {
+            drop(acquire_send(&ctx, idle).await);
+            let _held = acquire_send(&ctx, held).await;
+            // the permit for `idle` is gone by the time `held` is acquired, so only `held` is left
+            assert_eq!(tracked_destinations(&ctx), 1);
+            assert_eq!(send_concurrency(&ctx)[0].destination, held);
+            assert_eq!(ctx.get(STATS).snapshot()[SEND_EXPIRED], 1);
+        });
+    }
+}
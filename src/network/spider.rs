@@ -34,12 +34,30 @@ impl<T: Eq + std::hash::Hash + Clone + std::fmt::Display, U> Spider<T, U> {
         Ok(())
     }
 
+    /// Whether anyone is still subscribed to the destination. Destinations whose subscribers all went away linger until the next subscription, but don't count.
     pub fn contains(&self, val: &T) -> bool {
-        self.inner.read().contains_key(val)
+        self.inner
+            .read()
+            .get(val)
+            .map_or(false, |chan| chan.0.receiver_count() > 1)
     }
 
     pub fn keys(&self) -> Vec<T> {
-        self.inner.read().keys().cloned().collect()
+        self.inner
+            .read()
+            .iter()
+            .filter(|(_, chan)| chan.0.receiver_count() > 1)
+            .map(|(dest, _)| dest.clone())
+            .collect()
+    }
+
+    /// How many values are waiting for each destination.
+    pub fn depths(&self) -> Vec<(T, usize)> {
+        self.inner
+            .read()
+            .iter()
+            .map(|(dest, chan)| (dest.clone(), chan.1.len()))
+            .collect()
     }
 
     fn cleanup(&self) {
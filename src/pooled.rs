@@ -11,7 +11,10 @@ use smol::{
 };
 
 use crate::{
-    context::DaemonContext, stream::HavenStream, HavenEndpoint, HavenListener, HavenPacketConn,
+    context::DaemonContext,
+    stream::HavenStream,
+    throughput::{sink_throughput, THROUGHPUT_METADATA},
+    HavenEndpoint, HavenListener, HavenPacketConn,
 };
 
 /// Since [HavenStream]s are quite expensive to construct, they are not the best choice for representing or proxying TCP connections, which need to be cheap. They also do not come with timeout and keepalive functionality.
@@ -75,11 +78,20 @@ pub struct PooledListener {
 
 impl PooledListener {
     pub fn new(listener: HavenListener) -> Self {
+        Self::spawn(listener, false)
+    }
+
+    /// Like [PooledListener::new], but also answers throughput tests from [crate::measure_throughput], discarding their data. Without this, throughput tests are turned away.
+    pub fn with_throughput_sink(listener: HavenListener) -> Self {
+        Self::spawn(listener, true)
+    }
+
+    fn spawn(listener: HavenListener, throughput_sink: bool) -> Self {
         let (send_incoming, recv_incoming) = smol::channel::bounded(1);
         Self {
             recv_incoming,
             _task: smolscale::spawn(
-                pooled_listener_task(listener, send_incoming).map_err(Arc::new),
+                pooled_listener_task(listener, send_incoming, throughput_sink).map_err(Arc::new),
             )
             .shared(),
         }
@@ -104,18 +116,29 @@ impl PooledListener {
 async fn pooled_listener_task(
     listener: HavenListener,
     send_incoming: Sender<picomux::Stream>,
+    throughput_sink: bool,
 ) -> anyhow::Result<()> {
     nursery!({
         loop {
             let conn = HavenStream::new(listener.accept().await.context("inner listener failed")?);
-            let (read, write) = conn.split();
+            let (read, write) = conn.clone().split();
             let mux = PicoMux::new(read, write);
             let send_incoming = &send_incoming;
             let t: Task<anyhow::Result<()>> = spawn!(async move {
-                loop {
-                    let strm = mux.accept().await?;
-                    let _ = send_incoming.send(strm).await;
-                }
+                // throughput tests live in the connection's own nursery, so they end with it
+                nursery!({
+                    loop {
+                        let strm = mux.accept().await?;
+                        if strm.metadata() == THROUGHPUT_METADATA {
+                            // the application never sees throughput tests, whether we answer them or not
+                            if throughput_sink {
+                                spawn!(sink_throughput(strm, &conn)).detach();
+                            }
+                            continue;
+                        }
+                        let _ = send_incoming.send(strm).await;
+                    }
+                })
             });
             t.detach();
         }
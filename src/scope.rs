@@ -0,0 +1,288 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_event::Event;
+use parking_lot::Mutex;
+use smol::{future::FutureExt as _, Task};
+
+use crate::context::{CtxField, DaemonContext};
+
+mod respawn;
+pub use respawn::{daemon_tasks, respawn_scoped, TaskHealth};
+
+/// When a task stops during shutdown. Stages stop one after another, so that intake stops before the work it feeds, and upkeep such as persisting state outlasts both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Loops that take in new work, such as RPC servers and rendezvous forwarding.
+    Intake,
+    /// Work that was already taken in, such as packets waiting out their mix delays.
+    Inflight,
+    /// Upkeep, such as refreshing our identity or persisting state.
+    Upkeep,
+}
+
+impl Stage {
+    /// What [TaskScope::stopping] holds once this stage is stopping. Zero means nothing is.
+    fn stopping_mark(self) -> u8 {
+        self as u8 + 1
+    }
+}
+
+/// The long-lived tasks of a daemon. A single [shutdown] stops them all, stage by stage, and tasks that outlive the daemon's main loop are stopped when it exits too.
+///
+/// Spawning and finishing tasks only touches atomics, so that even tasks spawned per packet can be scoped. The lock is only taken to look up the counter of a kind of task, which [TaskKind] does once.
+pub struct TaskScope {
+    /// Every stage up to and including the one with this [Stage::stopping_mark] is stopping.
+    stopping: AtomicU8,
+    /// How many tasks of each stage and name are running.
+    live: Mutex<BTreeMap<(Stage, &'static str), Arc<AtomicUsize>>>,
+    changed: Event,
+}
+
+impl TaskScope {
+    fn is_stopping(&self, stage: Stage) -> bool {
+        self.stopping.load(Ordering::SeqCst) >= stage.stopping_mark()
+    }
+
+    fn live_in(&self, stage: Stage) -> usize {
+        self.live
+            .lock()
+            .iter()
+            .filter(|((of, _), _)| *of == stage)
+            .map(|(_, live)| live.load(Ordering::SeqCst))
+            .sum()
+    }
+}
+
+pub static TASKS: CtxField<TaskScope> = |_| TaskScope {
+    stopping: AtomicU8::new(0),
+    live: Default::default(),
+    changed: Event::new(),
+};
+
+/// One kind of scoped task, for spawning many of them without looking up their counter every time, such as one per packet.
+#[derive(Clone)]
+pub struct TaskKind {
+    stage: Stage,
+    name: &'static str,
+    live: Arc<AtomicUsize>,
+}
+
+impl TaskKind {
+    pub fn new(ctx: &DaemonContext, stage: Stage, name: &'static str) -> Self {
+        let live = ctx
+            .get(TASKS)
+            .live
+            .lock()
+            .entry((stage, name))
+            .or_default()
+            .clone();
+        Self { stage, name, live }
+    }
+
+    /// Spawns a task of this kind, handing it back. It's cancelled when dropped, like any other, and finishes with `None` if its stage stops first. Tasks spawned once their stage is stopping never start.
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        ctx: &DaemonContext,
+        fut: impl Future<Output = T> + Send + 'static,
+    ) -> Task<Option<T>> {
+        let scope = ctx.get(TASKS);
+        // counted before checking, so that shutdown either sees the task or the task sees shutdown
+        self.live.fetch_add(1, Ordering::SeqCst);
+        if scope.is_stopping(self.stage) {
+            tracing::debug!(
+                name = self.name,
+                "not spawning a task after its stage stopped"
+            );
+            self.finished(ctx);
+            return smolscale::spawn(async { None });
+        }
+        let ctx = ctx.clone();
+        let kind = self.clone();
+        smolscale::spawn(async move {
+            scopeguard::defer!(kind.finished(&ctx));
+            async { Some(fut.await) }
+                .or(async {
+                    stopped(&ctx, kind.stage).await;
+                    None
+                })
+                .await
+        })
+    }
+
+    fn finished(&self, ctx: &DaemonContext) {
+        self.live.fetch_sub(1, Ordering::SeqCst);
+        let scope = ctx.get(TASKS);
+        // only shutdown waits for tasks to finish
+        if scope.stopping.load(Ordering::SeqCst) != 0 {
+            scope.changed.notify_all();
+        }
+    }
+}
+
+/// Spawns a task that runs until it finishes, or until its stage stops. Tasks spawned once their stage is stopping never start.
+pub fn spawn_scoped<T: Send + 'static>(
+    ctx: &DaemonContext,
+    stage: Stage,
+    name: &'static str,
+    fut: impl Future<Output = T> + Send + 'static,
+) {
+    spawn_owned(ctx, stage, name, fut).detach();
+}
+
+/// Like [spawn_scoped], but hands the task back, for tasks that belong to something that may go away before the daemon does, such as a connection. Dropping the task cancels it.
+pub fn spawn_owned<T: Send + 'static>(
+    ctx: &DaemonContext,
+    stage: Stage,
+    name: &'static str,
+    fut: impl Future<Output = T> + Send + 'static,
+) -> Task<Option<T>> {
+    TaskKind::new(ctx, stage, name).spawn(ctx, fut)
+}
+
+/// Waits until tasks of the given stage should stop.
+pub async fn stopped(ctx: &DaemonContext, stage: Stage) {
+    let scope = ctx.get(TASKS);
+    scope
+        .changed
+        .wait_until(|| scope.is_stopping(stage).then_some(()))
+        .await
+}
+
+/// Stops every scoped task, one stage at a time, giving each stage up to `per_stage` to wind down before moving on. Returns the names of tasks that were still running at the end, which should be none.
+pub async fn shutdown(ctx: &DaemonContext, per_stage: Duration) -> Vec<&'static str> {
+    let scope = ctx.get(TASKS);
+    for stage in [Stage::Intake, Stage::Inflight, Stage::Upkeep] {
+        if scope
+            .stopping
+            .fetch_max(stage.stopping_mark(), Ordering::SeqCst)
+            >= stage.stopping_mark()
+        {
+            continue;
+        }
+        scope.changed.notify_all();
+        let deadline = Instant::now() + per_stage;
+        scope
+            .changed
+            .wait_until(|| (scope.live_in(stage) == 0).then_some(()))
+            .or(async {
+                smol::Timer::at(deadline).await;
+            })
+            .await;
+        tracing::debug!(stage = debug(stage), "stopped scoped tasks");
+    }
+    live_tasks(ctx)
+}
+
+/// Stops every scoped task at once, without waiting. For when the daemon dies, rather than being stopped.
+pub fn abort_all(ctx: &DaemonContext) {
+    let scope = ctx.get(TASKS);
+    scope
+        .stopping
+        .store(Stage::Upkeep.stopping_mark(), Ordering::SeqCst);
+    scope.changed.notify_all();
+}
+
+/// The names of the scoped tasks still running, once for each running copy.
+pub fn live_tasks(ctx: &DaemonContext) -> Vec<&'static str> {
+    ctx.get(TASKS)
+        .live
+        .lock()
+        .iter()
+        .flat_map(|((_, name), live)| std::iter::repeat(*name).take(live.load(Ordering::SeqCst)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn ctx() -> DaemonContext {
+        DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap())
+    }
+
+    #[test]
+    fn shutdown_stops_every_stage_in_order() {
+        let ctx = ctx();
+        let stopped_order = Arc::new(Mutex::new(vec![]));
+        for (stage, name) in [
+            (Stage::Upkeep, "upkeep"),
+            (Stage::Inflight, "inflight"),
+            (Stage::Intake, "intake"),
+        ] {
+            for _ in 0..3 {
+                let stopped_order = stopped_order.clone();
+                spawn_scoped(&ctx, stage, name, async move {
+                    scopeguard::defer!(stopped_order.lock().push(stage));
+                    smol::future::pending::<()>().await
+                });
+            }
+        }
+        respawn_scoped(&ctx, Stage::Intake, "respawning", || async {
+            smol::Timer::after(Duration::from_millis(10)).await;
+            anyhow::Ok(())
+        });
+        smol::block_on(async {
+            // let everything start
+            smol::Timer::after(Duration::from_millis(100)).await;
+            assert_eq!(live_tasks(&ctx).len(), 10);
+
+            let start = Instant::now();
+            let left = shutdown(&ctx, Duration::from_secs(1)).await;
+            assert!(left.is_empty(), "still running: {left:?}");
+            assert!(start.elapsed() < Duration::from_secs(1));
+        });
+        let stopped_order = stopped_order.lock().clone();
+        assert_eq!(stopped_order.len(), 9);
+        let mut sorted = stopped_order.clone();
+        sorted.sort();
+        assert_eq!(stopped_order, sorted);
+
+        // nothing new starts once it's all stopped
+        spawn_scoped(&ctx, Stage::Upkeep, "late", smol::future::pending::<()>());
+        assert!(live_tasks(&ctx).is_empty());
+    }
+
+    #[test]
+    fn task_kinds_count_their_tasks() {
+        let ctx = ctx();
+        let sends = TaskKind::new(&ctx, Stage::Inflight, "per_packet");
+        smol::block_on(async {
+            let finished: Vec<_> = (0..100)
+                .map(|i| sends.spawn(&ctx, async move { i }))
+                .collect();
+            for (i, task) in finished.into_iter().enumerate() {
+                assert_eq!(task.await, Some(i));
+            }
+            assert!(live_tasks(&ctx).is_empty());
+
+            // dropping an owned task cancels it, and it stops being counted
+            let owned = spawn_owned(
+                &ctx,
+                Stage::Inflight,
+                "owned",
+                smol::future::pending::<()>(),
+            );
+            let waiting = sends.spawn(&ctx, smol::future::pending::<()>());
+            smol::Timer::after(Duration::from_millis(10)).await;
+            assert_eq!(live_tasks(&ctx), vec!["owned", "per_packet"]);
+            drop(owned);
+            smol::Timer::after(Duration::from_millis(10)).await;
+            assert_eq!(live_tasks(&ctx), vec!["per_packet"]);
+
+            shutdown(&ctx, Duration::from_secs(1)).await;
+            assert_eq!(waiting.await, None);
+            assert_eq!(sends.spawn(&ctx, async { 1 }).await, None);
+            assert!(live_tasks(&ctx).is_empty());
+        });
+    }
+}
@@ -0,0 +1,270 @@
+use std::{collections::BTreeMap, future::Future, time::Duration};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock,
+    context::{CtxField, DaemonContext},
+    stats::STATS,
+};
+
+use super::{spawn_scoped, Stage};
+
+pub const TASK_FAILED: &str = "scope.task_failed";
+pub const TASK_UNHEALTHY: &str = "scope.task_unhealthy";
+
+/// How long a respawned task first waits after failing. Doubles with every failure in a row.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A task that ran this long before failing was healthy until then, so its backoff starts over.
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+/// How many failures in a row, each before the task got healthy, make a task unhealthy.
+const RAPID_FAILURES: u32 = 8;
+
+/// How long an unhealthy task waits between tries.
+const SLOW_RETRY: Duration = Duration::from_secs(120);
+
+/// How loudly a failure should be logged. Only the failures before a task is found unhealthy get a warning, so a task that keeps failing can't flood the logs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Announce {
+    Warn,
+    /// The task just became unhealthy, which is said once, prominently.
+    Unhealthy,
+    Quiet,
+}
+
+/// The backoff of one respawned task.
+#[derive(Default)]
+struct Backoff {
+    consecutive_failures: u32,
+}
+
+impl Backoff {
+    /// Records that the task failed after running for `ran_for`, returning how long to wait before starting it again, before jitter.
+    fn failed(&mut self, ran_for: Duration) -> (Duration, Announce) {
+        if ran_for >= HEALTHY_AFTER {
+            self.consecutive_failures = 0;
+        }
+        self.consecutive_failures += 1;
+        let announce = match self.consecutive_failures {
+            n if n < RAPID_FAILURES => Announce::Warn,
+            RAPID_FAILURES => Announce::Unhealthy,
+            _ => Announce::Quiet,
+        };
+        (self.delay(), announce)
+    }
+
+    /// Records that the task finished without an error, which says it's healthy.
+    fn succeeded(&mut self) {
+        self.consecutive_failures = 0;
+    }
+
+    fn is_slow(&self) -> bool {
+        self.consecutive_failures >= RAPID_FAILURES
+    }
+
+    fn delay(&self) -> Duration {
+        if self.consecutive_failures == 0 {
+            Duration::ZERO
+        } else if self.is_slow() {
+            SLOW_RETRY
+        } else {
+            INITIAL_BACKOFF
+                .saturating_mul(1 << (self.consecutive_failures - 1))
+                .min(MAX_BACKOFF)
+        }
+    }
+}
+
+/// How a respawned task of the daemon has been doing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TaskHealth {
+    pub name: String,
+    /// How many times the task was started again, after failing or finishing.
+    pub restarts: u64,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// When the task last failed, in seconds since the Unix epoch.
+    pub last_error_at: Option<u64>,
+    /// How long the task waits before it's started again, in milliseconds. Zero while it's running fine.
+    pub backoff_ms: u64,
+    /// Whether the task failed so many times in a row that it's only retried every so often.
+    pub unhealthy: bool,
+}
+
+impl TaskHealth {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            restarts: 0,
+            consecutive_failures: 0,
+            last_error: None,
+            last_error_at: None,
+            backoff_ms: 0,
+            unhealthy: false,
+        }
+    }
+}
+
+static TASK_HEALTH: CtxField<Mutex<BTreeMap<&'static str, TaskHealth>>> =
+    |_| Mutex::new(BTreeMap::new());
+
+/// How every respawned task of the daemon has been doing, by name.
+pub fn daemon_tasks(ctx: &DaemonContext) -> Vec<TaskHealth> {
+    ctx.get(TASK_HEALTH).lock().values().cloned().collect()
+}
+
+/// Spawns a task that is started again whenever it finishes, until its stage stops. Takes the place of an [smolscale::immortal::Immortal] that should stop with the daemon.
+///
+/// A task that fails is started again after an exponential backoff, and one that keeps failing is found unhealthy and only retried every so often. How each task is doing shows up in [daemon_tasks].
+pub fn respawn_scoped<Fut: Future<Output = anyhow::Result<()>> + Send + 'static>(
+    ctx: &DaemonContext,
+    stage: Stage,
+    name: &'static str,
+    make: impl Fn() -> Fut + Send + 'static,
+) {
+    ctx.get(TASK_HEALTH)
+        .lock()
+        .insert(name, TaskHealth::new(name));
+    let ctx_inner = ctx.clone();
+    spawn_scoped(ctx, stage, name, async move {
+        let ctx = ctx_inner;
+        let mut backoff = Backoff::default();
+        loop {
+            let clock = clock::clock(&ctx);
+            let start = clock.now();
+            let result = make().await;
+            let wait = match result {
+                Ok(()) => {
+                    backoff.succeeded();
+                    tracing::trace!(name, "respawning scoped task");
+                    Duration::ZERO
+                }
+                Err(err) => {
+                    ctx.get(STATS).incr(TASK_FAILED);
+                    let (delay, announce) =
+                        backoff.failed(clock.now().saturating_duration_since(start));
+                    // randomized, so that tasks that fail together don't retry together
+                    let delay = delay.mul_f64(rand::thread_rng().gen_range(0.75..1.25));
+                    match announce {
+                        Announce::Warn => tracing::warn!(
+                            name,
+                            backoff_ms = delay.as_millis() as u64,
+                            "{name} restart, error: {err:?}"
+                        ),
+                        Announce::Unhealthy => {
+                            ctx.get(STATS).incr(TASK_UNHEALTHY);
+                            tracing::error!(
+                                name,
+                                failures = RAPID_FAILURES,
+                                "TASK UNHEALTHY: {name} keeps failing, so it will only be retried every {}s. last error: {err:?}",
+                                SLOW_RETRY.as_secs()
+                            )
+                        }
+                        Announce::Quiet => {
+                            tracing::debug!(name, "unhealthy task failed again, error: {err:?}")
+                        }
+                    }
+                    let mut health = ctx.get(TASK_HEALTH).lock();
+                    let health = health.entry(name).or_insert_with(|| TaskHealth::new(name));
+                    health.last_error = Some(format!("{err:#}"));
+                    health.last_error_at = Some(clock.unix_now());
+                    delay
+                }
+            };
+            {
+                let mut health = ctx.get(TASK_HEALTH).lock();
+                let health = health.entry(name).or_insert_with(|| TaskHealth::new(name));
+                health.restarts += 1;
+                health.consecutive_failures = backoff.consecutive_failures;
+                health.backoff_ms = wait.as_millis() as u64;
+                health.unhealthy = backoff.is_slow();
+            }
+            if !wait.is_zero() {
+                clock.sleep(wait).await;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_then_slows_down() {
+        let mut backoff = Backoff::default();
+        let quick = Duration::from_millis(5);
+        let mut delays = vec![];
+        for _ in 0..RAPID_FAILURES - 1 {
+            let (delay, announce) = backoff.failed(quick);
+            assert_eq!(announce, Announce::Warn);
+            delays.push(delay.as_millis());
+        }
+        assert_eq!(delays, [100, 200, 400, 800, 1600, 3200, 6400]);
+        assert_eq!(backoff.failed(quick), (SLOW_RETRY, Announce::Unhealthy));
+        assert_eq!(backoff.failed(quick), (SLOW_RETRY, Announce::Quiet));
+
+        // a healthy run starts the backoff over
+        assert_eq!(
+            backoff.failed(HEALTHY_AFTER),
+            (INITIAL_BACKOFF, Announce::Warn)
+        );
+        backoff.succeeded();
+        assert_eq!(backoff.failed(quick), (INITIAL_BACKOFF, Announce::Warn));
+    }
+
+    #[test]
+    fn always_failing_task_stays_quiet() {
+        // a day of a task that fails right away
+        let mut backoff = Backoff::default();
+        let (mut elapsed, mut tries, mut loud) = (Duration::ZERO, 0, 0);
+        while elapsed < Duration::from_secs(86400) {
+            let (delay, announce) = backoff.failed(Duration::ZERO);
+            assert!(delay <= SLOW_RETRY);
+            if announce != Announce::Quiet {
+                loud += 1;
+            }
+            elapsed += delay;
+            tries += 1;
+        }
+        assert_eq!(loud, RAPID_FAILURES);
+        assert!(tries < 800, "{tries} tries");
+    }
+
+    #[test]
+    fn health_shows_failing_tasks() {
+        let ctx = DaemonContext::new(serde_json::from_value(serde_json::json!({})).unwrap());
+        respawn_scoped(&ctx, Stage::Intake, "always_fails", || async {
+            anyhow::bail!("port already bound")
+        });
+        respawn_scoped(&ctx, Stage::Intake, "fine", || async {
+            smol::Timer::after(Duration::from_millis(10)).await;
+            anyhow::Ok(())
+        });
+        smol::block_on(smol::Timer::after(Duration::from_millis(500)));
+        let tasks = daemon_tasks(&ctx);
+        assert_eq!(tasks.len(), 2);
+
+        let failing = &tasks[0];
+        assert_eq!(failing.name, "always_fails");
+        assert_eq!(failing.last_error.as_deref(), Some("port already bound"));
+        assert!(failing.backoff_ms > 0);
+        // 100 + 200 + 400ms of backoff, give or take jitter, rather than thousands of tries
+        assert!(
+            (2..=5).contains(&failing.restarts),
+            "{} restarts",
+            failing.restarts
+        );
+
+        let fine = &tasks[1];
+        assert_eq!(fine.consecutive_failures, 0);
+        assert!(fine.last_error.is_none());
+        assert!(fine.restarts > 10);
+        smol::block_on(crate::scope::shutdown(&ctx, Duration::from_secs(1)));
+    }
+}
@@ -5,6 +5,8 @@ use std::{
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::Context;
+use async_event::Event;
 use blake3::Hash;
 use bytes::Bytes;
 use dashmap::DashMap;
@@ -13,11 +15,16 @@ use melpow::{HashFunction, SVec};
 use moka::sync::{Cache, CacheBuilder};
 use serde::{Deserialize, Serialize};
 use smol::channel::{Receiver, Sender};
+use sqlx::Row;
 use stdcode::StdcodeSerializeExt;
 
+use crate::audit::{audit, AuditEvent};
 use crate::config::AutoSettle;
 
-use crate::context::DaemonContext;
+use crate::context::{require_relay, CtxField, DaemonContext, DEBTS};
+use crate::db::DATABASE;
+use crate::ledger;
+use crate::micromel::Micromel;
 
 pub struct Hasher;
 
@@ -36,16 +43,17 @@ pub fn onchain_multiplier() -> u64 {
     1
 }
 
-pub fn difficulty_to_micromel(difficulty: usize) -> u64 {
-    let work = (2u64).pow(difficulty as u32);
-    work.saturating_mul(onchain_multiplier())
+pub fn difficulty_to_micromel(difficulty: usize) -> Micromel {
+    let work = 2u64.checked_pow(difficulty as u32).unwrap_or(u64::MAX);
+    Micromel(work).saturating_mul(onchain_multiplier())
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct SettlementRequest {
     timestamp_ms: u64,
-    decrease: u64,
-    pub payment_proof: SettlementProof,
+    decrease: Micromel,
+    /// Every payment making up this settlement, so that the full payment trail can be audited.
+    pub payment_proofs: Vec<SettlementProof>,
     signature: Bytes,
     initiator_pk: Arc<RelayIdentityPublic>,
 }
@@ -53,24 +61,28 @@ pub struct SettlementRequest {
 impl fmt::Display for SettlementRequest {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
-            f, "SettlementRequest {{ timestamp_ms: {}, decrease: {}, payment proof: {:?}, initiator fingerprint: {} }}",
+            f, "SettlementRequest {{ timestamp_ms: {}, decrease: {}, payment proofs: {:?}, initiator fingerprint: {} }}",
             self.timestamp_ms,
             self.decrease,
-            self.payment_proof,
+            self.payment_proofs,
             self.initiator_pk.fingerprint()
         )
     }
 }
 
 impl SettlementRequest {
-    pub fn new(my_sk: RelayIdentitySecret, decrease: u64, payment_proof: SettlementProof) -> Self {
+    pub fn new(
+        my_sk: RelayIdentitySecret,
+        decrease: Micromel,
+        payment_proofs: Vec<SettlementProof>,
+    ) -> Self {
         let mut request = Self {
             timestamp_ms: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
             decrease,
-            payment_proof,
+            payment_proofs,
             signature: Bytes::new(),
             initiator_pk: my_sk.public().into(),
         };
@@ -87,6 +99,11 @@ impl SettlementRequest {
 
         blake3::keyed_hash(b"settlement-request--------------", &this.stdcode())
     }
+
+    /// The relay that asks for the settlement.
+    pub fn initiator(&self) -> RelayFingerprint {
+        self.initiator_pk.fingerprint()
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -142,10 +159,25 @@ impl SettlementResponse {
 
         blake3::keyed_hash(b"settlement-response-------------", &this.stdcode())
     }
+
+    /// Checks that the relay the settlement was sent to signed this response.
+    pub fn verify(&self, responder: &RelayIdentityPublic) -> anyhow::Result<()> {
+        responder.verify(self.to_sign().as_bytes(), &self.signature)?;
+        Ok(())
+    }
 }
 
+/// How long a neighbor's settlement request waits for us to accept or reject it.
+pub const SETTLEMENT_WAIT: Duration = Duration::from_secs(300);
+
+pub static SETTLEMENTS: CtxField<Settlements> = |ctx| Settlements::new(ctx.init().auto_settle);
+
 pub struct Settlements {
+    /// Settlements neighbors asked us to accept, waiting for the operator.
     pending: DashMap<RelayFingerprint, PendingSettlement>,
+    /// Settlements we started, waiting for the link to the neighbor to carry them.
+    outgoing: DashMap<RelayFingerprint, SettlementRequest>,
+    outgoing_added: Event,
     pub seed_cache: Cache<u64, HashSet<Seed>>,
     pub auto_settle: Option<AutoSettle>,
 }
@@ -154,6 +186,8 @@ impl Settlements {
     pub fn new(auto_settle: Option<AutoSettle>) -> Self {
         Settlements {
             pending: DashMap::new(),
+            outgoing: DashMap::new(),
+            outgoing_added: Event::new(),
             seed_cache: CacheBuilder::default()
                 .time_to_live(Duration::from_secs(60))
                 .build(),
@@ -177,10 +211,14 @@ impl Settlements {
         let initiator_pk = request.clone().initiator_pk;
         initiator_pk.verify(request.to_sign().as_bytes(), &request.signature)?;
 
-        match request.payment_proof {
-            SettlementProof::Manual => (),
-            _ => return Err(anyhow::anyhow!("expected manual settlement proof")),
-        };
+        if request.payment_proofs.is_empty()
+            || request
+                .payment_proofs
+                .iter()
+                .any(|proof| proof != &SettlementProof::Manual)
+        {
+            return Err(anyhow::anyhow!("expected manual settlement proofs"));
+        }
 
         let (send_res, recv_res) = smol::channel::bounded(1);
         let pending_settlement = PendingSettlement {
@@ -240,38 +278,56 @@ impl Settlements {
         // Ok(None)
     }
 
+    /// Accepts the settlement the neighbor asked for, deducting it from what they owe us, recording it, and telling them.
     pub async fn accept_response(
         &self,
-        _ctx: &DaemonContext,
-        _neighbor: RelayFingerprint,
-        _request: SettlementRequest,
+        ctx: &DaemonContext,
+        neighbor: RelayFingerprint,
     ) -> anyhow::Result<()> {
-        todo!();
-        // let debts = ctx.get(DEBTS);
-        // let current_debt = debts
-        //     .net_debt_est(&neighbor)
-        //     .context("unable to retrieve net debt")?;
-        // let deduct_amount = request.decrease;
-        // let settled_debt = current_debt.saturating_sub(deduct_amount as i128);
-        // let my_sk = ctx.get(GLOBAL_IDENTITY);
-        // let response = SettlementResponse::new(*my_sk, request, settled_debt);
-
-        // if let Some(settlement) = self.pending.get(&neighbor) {
-        //     settlement.send_res.send(Some(response)).await?;
-        //     debts.deduct_settlement(neighbor, deduct_amount);
-        // }
-
-        // self.pending.remove(&neighbor);
-        // Ok(())
+        let my_sk = require_relay(ctx, "accepting settlements")?;
+        let (_, settlement) = self
+            .pending
+            .remove(&neighbor)
+            .context("no settlement from this neighbor is waiting")?;
+        let request = settlement.request;
+        let debts = ctx.get(DEBTS);
+        debts.deduct_relay_settlement(neighbor, request.decrease);
+        let current_debt = debts.relay_net_debt_est(&neighbor).unwrap_or_default();
+        self.record_proofs(ctx, &neighbor.to_string(), &request)
+            .await?;
+        // the neighbor may have stopped waiting, but the settlement stands
+        let _ = settlement
+            .send_res
+            .send(Some(SettlementResponse::new(my_sk, request, current_debt)))
+            .await;
+        Ok(())
     }
 
     pub async fn reject_response(&self, neighbor: &RelayFingerprint) -> anyhow::Result<()> {
-        if let Some(settlement) = self.pending.get(neighbor) {
-            settlement.send_res.send(None).await?
-        }
+        let (_, settlement) = self
+            .pending
+            .remove(neighbor)
+            .context("no settlement from this neighbor is waiting")?;
+        let _ = settlement.send_res.send(None).await;
+        Ok(())
+    }
 
+    /// Forgets the settlement the neighbor asked for, once they stopped waiting for an answer.
+    pub fn forget_pending(&self, neighbor: &RelayFingerprint) {
         self.pending.remove(neighbor);
-        Ok(())
+    }
+
+    /// Queues a settlement for the link to the neighbor to send, replacing any that hasn't gone out yet.
+    pub fn queue_outgoing(&self, neighbor: RelayFingerprint, request: SettlementRequest) {
+        self.outgoing.insert(neighbor, request);
+        self.outgoing_added.notify_all();
+    }
+
+    /// Waits for a settlement to send to the neighbor, and takes it off the queue.
+    pub async fn wait_outgoing(&self, neighbor: RelayFingerprint) -> SettlementRequest {
+        self.outgoing_added
+            .wait_until(|| self.outgoing.remove(&neighbor).map(|(_, request)| request))
+            .await
     }
 
     pub fn get_request(&self, neighbor: &RelayFingerprint) -> Option<SettlementRequest> {
@@ -284,4 +340,136 @@ impl Settlements {
             .map(|entry| entry.request.to_string())
             .collect()
     }
+
+    /// Persists every payment proof of a settlement with the neighbor to the state cache, keyed by the hash of the request, and records the settlement in the ledger.
+    pub async fn record_proofs(
+        &self,
+        ctx: &DaemonContext,
+        neighbor: &str,
+        request: &SettlementRequest,
+    ) -> anyhow::Result<()> {
+        if let Some(pool) = ctx.get(DATABASE) {
+            let settlement = request.to_sign().to_hex().to_string();
+            let mut txn = pool.begin().await?;
+            for (idx, proof) in request.payment_proofs.iter().enumerate() {
+                sqlx::query(
+                    "INSERT INTO settlement_proofs (settlement, idx, proof) VALUES (?, ?, ?) ON CONFLICT(settlement, idx) DO UPDATE SET proof = excluded.proof",
+                )
+                .bind(&settlement)
+                .bind(idx as i64)
+                .bind(proof.stdcode())
+                .execute(&mut *txn)
+                .await?;
+            }
+            txn.commit().await?;
+            ledger::record_settlement(ctx, neighbor, request.decrease, request.to_sign()).await?;
+            audit(
+                ctx,
+                AuditEvent::SettlementRecorded {
+                    neighbor: neighbor.to_string(),
+                    amount: request.decrease,
+                    settlement,
+                },
+            )
+            .await;
+        }
+        Ok(())
+    }
+
+    /// Retrieves the payment proofs recorded for a settlement, in their original order.
+    pub async fn proofs(
+        &self,
+        ctx: &DaemonContext,
+        settlement: Hash,
+    ) -> anyhow::Result<Vec<SettlementProof>> {
+        let Some(pool) = ctx.get(DATABASE) else {
+            return Ok(vec![]);
+        };
+        let rows =
+            sqlx::query("SELECT proof FROM settlement_proofs WHERE settlement = ? ORDER BY idx")
+                .bind(settlement.to_hex().to_string())
+                .fetch_all(pool)
+                .await?;
+        rows.iter()
+            .map(|row| Ok(stdcode::deserialize(row.get("proof"))?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settlement_with_two_proofs() {
+        let state_cache =
+            std::env::temp_dir().join(format!("earendil-settlement-{}.db", rand::random::<u64>()));
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "state_cache": state_cache })).unwrap(),
+        );
+        let auto = SettlementProof::Automatic(AutoSettleProof {
+            seed: [1; 32],
+            difficulty: 3,
+            proof: vec![1, 2, 3],
+        });
+        let request = SettlementRequest::new(
+            RelayIdentitySecret::generate(),
+            Micromel(100),
+            vec![SettlementProof::Manual, auto.clone()],
+        );
+
+        let settlements = Settlements::new(None);
+        smol::future::block_on(async {
+            settlements
+                .record_proofs(&ctx, "a neighbor", &request)
+                .await
+                .unwrap();
+            let proofs = settlements.proofs(&ctx, request.to_sign()).await.unwrap();
+            assert_eq!(proofs, vec![SettlementProof::Manual, auto]);
+        });
+        let _ = std::fs::remove_file(state_cache);
+    }
+
+    #[test]
+    fn accepted_settlements_reach_the_ledger() {
+        let state_cache =
+            std::env::temp_dir().join(format!("earendil-settlement-{}.db", rand::random::<u64>()));
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({
+                "identity_seed": "settlement responder",
+                "state_cache": state_cache,
+            }))
+            .unwrap(),
+        );
+        let responder = require_relay(&ctx, "tests").unwrap().public();
+        let initiator = RelayIdentitySecret::generate();
+        let request =
+            SettlementRequest::new(initiator, Micromel(250), vec![SettlementProof::Manual]);
+
+        let settlements = ctx.get(SETTLEMENTS);
+        let recv_res = settlements.insert_pending(request.clone()).unwrap();
+        assert_eq!(settlements.list().len(), 1);
+        smol::future::block_on(async {
+            settlements
+                .accept_response(&ctx, request.initiator())
+                .await
+                .unwrap();
+            let response = recv_res.recv().await.unwrap().unwrap();
+            assert_eq!(response.request, request);
+            response.verify(&responder).unwrap();
+
+            let pool = ctx.get(DATABASE).as_ref().unwrap();
+            let (neighbor, amount): (String, i64) =
+                sqlx::query_as("SELECT neighbor, amount FROM settlements")
+                    .fetch_one(pool)
+                    .await
+                    .unwrap();
+            assert_eq!(neighbor, request.initiator().to_string());
+            assert_eq!(amount, 250);
+        });
+        // it's no longer pending, so it can't be answered twice
+        assert!(settlements.list().is_empty());
+        assert!(smol::future::block_on(settlements.reject_response(&request.initiator())).is_err());
+        let _ = std::fs::remove_file(state_cache);
+    }
 }
@@ -0,0 +1,258 @@
+use bytes::Bytes;
+use earendil_crypt::{kdf_from_human, ClientId, HavenFingerprint, RelayFingerprint};
+use earendil_packet::crypt::{box_decrypt, box_encrypt, AeadKey, DhPublic, DhSecret};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+use thiserror::Error;
+
+use crate::{
+    clock,
+    context::{
+        CtxField, DaemonContext, DEBTS, MY_CLIENT_ID, MY_RELAY_IDENTITY, MY_RELAY_ONION_SK,
+        RELAY_GRAPH,
+    },
+    db::encode_graph,
+    dht::{self, DHT_CACHE_TTL},
+    haven::HavenLocator,
+    n2r::ENTRY_GUARDS,
+};
+
+/// The environment variable the command line reads snapshot passphrases from, so that they don't show up in process listings.
+pub const SNAPSHOT_PASSPHRASE_VAR: &str = "EARENDIL_SNAPSHOT_PASSPHRASE";
+
+/// The version of the snapshot format we write. Snapshots of other versions are refused rather than half-restored.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Error, Serialize, Deserialize, Debug)]
+pub enum SnapshotError {
+    #[error("snapshot version {0} is not supported, only version {SNAPSHOT_VERSION} is")]
+    UnsupportedVersion(u32),
+    #[error("wrong passphrase, or the snapshot is corrupt")]
+    Decrypt,
+    #[error("malformed snapshot")]
+    Malformed,
+    #[error("the snapshot belongs to relay {0}, not to this node")]
+    WrongIdentity(RelayFingerprint),
+    #[error("snapshots need a passphrase that isn't empty")]
+    EmptyPassphrase,
+    #[error("{0}")]
+    Error(String),
+}
+
+/// A snapshot as it's written out. Everything but the version is sealed with a key derived from the passphrase, since the snapshot holds our onion secret key, and says which relays we use.
+#[derive(Serialize, Deserialize)]
+struct SealedSnapshot {
+    version: u32,
+    salt: String,
+    nonce: [u8; 12],
+    sealed: Bytes,
+}
+
+/// What a snapshot holds. Each part is taken by the context field it belongs to when that field starts up, so that it starts from the snapshot rather than from the state cache.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct SnapshotContents {
+    created_unix: u64,
+    /// The relay identity of the node the snapshot was taken of, if it was a relay.
+    relay: Option<RelayFingerprint>,
+    pub relay_graph: Option<Vec<u8>>,
+    pub onion_sk: Option<[u8; 32]>,
+    pub client_id: Option<ClientId>,
+    pub entry_guards: Option<Vec<u8>>,
+    pub debts: Option<Vec<u8>>,
+    dht_cache: Vec<(HavenFingerprint, HavenLocator)>,
+}
+
+static RESTORED: CtxField<Mutex<Option<SnapshotContents>>> = |_| Mutex::new(None);
+
+/// Captures the relay graph, onion key, client id, entry guards, cached DHT locators, and debts into one blob, encrypted with a key derived from `passphrase`. A node restored from it with [restore_state] picks up right where this one was, without waiting on gossip.
+pub fn snapshot_state(ctx: &DaemonContext, passphrase: &str) -> Result<Bytes, SnapshotError> {
+    seal_snapshot(&snapshot_contents(ctx)?, passphrase)
+}
+
+/// Like [snapshot_state], but sealed to `recipient` rather than with a passphrase, for handing the snapshot to a control client that seals it with [seal_received_snapshot]. The passphrase never has to leave the client that way.
+pub fn snapshot_state_for(
+    ctx: &DaemonContext,
+    recipient: &DhPublic,
+) -> Result<Bytes, SnapshotError> {
+    let (sealed, _) = box_encrypt(&snapshot_contents(ctx)?, recipient);
+    Ok(sealed.into())
+}
+
+/// Opens a snapshot sealed to us with [snapshot_state_for], and seals it with `passphrase` instead, the way [snapshot_state] would have. Deriving the key from the passphrase takes a while, so it happens on a blocking thread.
+pub async fn seal_received_snapshot(
+    received: &[u8],
+    our_sk: &DhSecret,
+    passphrase: String,
+) -> Result<Bytes, SnapshotError> {
+    let (contents, _) = box_decrypt(received, our_sk).map_err(|_| SnapshotError::Decrypt)?;
+    smol::unblock(move || seal_snapshot(&contents, &passphrase)).await
+}
+
+fn snapshot_contents(ctx: &DaemonContext) -> Result<Vec<u8>, SnapshotError> {
+    let contents = SnapshotContents {
+        created_unix: clock::unix_now(ctx),
+        relay: ctx
+            .get(MY_RELAY_IDENTITY)
+            .map(|identity| identity.public().fingerprint()),
+        relay_graph: Some(
+            encode_graph(&ctx.get(RELAY_GRAPH).read(), ctx.init().relay_graph_format)
+                .map_err(|e| SnapshotError::Error(e.to_string()))?,
+        ),
+        onion_sk: Some(ctx.get(MY_RELAY_ONION_SK).to_bytes()),
+        client_id: Some(*ctx.get(MY_CLIENT_ID)),
+        entry_guards: Some(ctx.get(ENTRY_GUARDS).lock().stdcode()),
+        debts: Some(
+            ctx.get(DEBTS)
+                .as_bytes()
+                .map_err(|e| SnapshotError::Error(e.to_string()))?,
+        ),
+        dht_cache: dht::dht_cache_entries(ctx),
+    };
+    Ok(contents.stdcode())
+}
+
+/// Seals the contents of a snapshot with a key derived from `passphrase`.
+fn seal_snapshot(contents: &[u8], passphrase: &str) -> Result<Bytes, SnapshotError> {
+    if passphrase.is_empty() {
+        return Err(SnapshotError::EmptyPassphrase);
+    }
+    let salt = hex::encode(rand::random::<[u8; 16]>());
+    let nonce = rand::random::<[u8; 12]>();
+    let sealed = snapshot_key(passphrase, &salt).seal(&nonce, contents);
+    Ok(SealedSnapshot {
+        version: SNAPSHOT_VERSION,
+        salt,
+        nonce,
+        sealed: sealed.into(),
+    }
+    .stdcode()
+    .into())
+}
+
+/// Has the context start from a snapshot taken with [snapshot_state]. Must be called on a fresh context, before anything else uses it, since the parts of the snapshot are only picked up as the daemon starts.
+pub fn restore_state(
+    ctx: &DaemonContext,
+    snapshot: &[u8],
+    passphrase: &str,
+) -> Result<(), SnapshotError> {
+    let snapshot: SealedSnapshot =
+        stdcode::deserialize(snapshot).map_err(|_| SnapshotError::Malformed)?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(snapshot.version));
+    }
+    let plain = snapshot_key(passphrase, &snapshot.salt)
+        .open(&snapshot.nonce, &snapshot.sealed)
+        .map_err(|_| SnapshotError::Decrypt)?;
+    let mut contents: SnapshotContents =
+        stdcode::deserialize(&plain).map_err(|_| SnapshotError::Malformed)?;
+    let ours = ctx
+        .init()
+        .identity
+        .as_ref()
+        .and_then(|identity| identity.actualize_relay().ok())
+        .map(|identity| identity.public().fingerprint());
+    if let Some(theirs) = contents.relay.filter(|theirs| Some(*theirs) != ours) {
+        return Err(SnapshotError::WrongIdentity(theirs));
+    }
+    // cached locators are only good for as long as they would have stayed cached
+    let age = clock::unix_now(ctx).saturating_sub(contents.created_unix);
+    if age < DHT_CACHE_TTL.as_secs() {
+        dht::restore_dht_cache(ctx, std::mem::take(&mut contents.dht_cache));
+    }
+    tracing::info!(age, "restoring from a snapshot");
+    *ctx.get(RESTORED).lock() = Some(contents);
+    Ok(())
+}
+
+/// Takes a part of the snapshot the daemon is restoring from, if it is restoring from one that has the part.
+pub(crate) fn take_restored<T>(
+    ctx: &DaemonContext,
+    part: impl FnOnce(&mut SnapshotContents) -> Option<T>,
+) -> Option<T> {
+    ctx.get(RESTORED).lock().as_mut().and_then(part)
+}
+
+fn snapshot_key(passphrase: &str, salt: &str) -> AeadKey {
+    AeadKey::from_bytes(&kdf_from_human(passphrase, salt))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn ctx(cfg: serde_json::Value) -> DaemonContext {
+        DaemonContext::new(serde_json::from_value(cfg).unwrap())
+    }
+
+    #[test]
+    fn secrets_need_the_passphrase() {
+        let relay = ctx(json!({ "identity_seed": "secrets_need_the_passphrase" }));
+        let snapshot = snapshot_state(&relay, "correct horse").unwrap();
+        let onion_sk = relay.get(MY_RELAY_ONION_SK).to_bytes();
+        assert!(!snapshot
+            .windows(onion_sk.len())
+            .any(|window| window == onion_sk));
+
+        let fresh = || ctx(json!({ "identity_seed": "secrets_need_the_passphrase" }));
+        assert!(matches!(
+            restore_state(&fresh(), &snapshot, "battery staple"),
+            Err(SnapshotError::Decrypt)
+        ));
+        assert!(matches!(
+            restore_state(
+                &ctx(json!({ "identity_seed": "someone else" })),
+                &snapshot,
+                "correct horse"
+            ),
+            Err(SnapshotError::WrongIdentity(_))
+        ));
+        let restored = fresh();
+        restore_state(&restored, &snapshot, "correct horse").unwrap();
+        assert_eq!(
+            restored.get(MY_RELAY_ONION_SK).public(),
+            relay.get(MY_RELAY_ONION_SK).public()
+        );
+        assert_eq!(restored.get(MY_CLIENT_ID), relay.get(MY_CLIENT_ID));
+    }
+
+    #[test]
+    fn control_clients_seal_snapshots_themselves() {
+        let relay = ctx(json!({ "identity_seed": "control_clients_seal_snapshots" }));
+        assert!(matches!(
+            snapshot_state(&relay, ""),
+            Err(SnapshotError::EmptyPassphrase)
+        ));
+
+        let client_sk = DhSecret::generate();
+        let received = snapshot_state_for(&relay, &client_sk.public()).unwrap();
+        // only the client can open what it receives
+        assert!(matches!(
+            smol::future::block_on(seal_received_snapshot(
+                &received,
+                &DhSecret::generate(),
+                "correct horse".into()
+            )),
+            Err(SnapshotError::Decrypt)
+        ));
+        assert!(matches!(
+            smol::future::block_on(seal_received_snapshot(&received, &client_sk, "".into())),
+            Err(SnapshotError::EmptyPassphrase)
+        ));
+        let snapshot = smol::future::block_on(seal_received_snapshot(
+            &received,
+            &client_sk,
+            "correct horse".into(),
+        ))
+        .unwrap();
+
+        let restored = ctx(json!({ "identity_seed": "control_clients_seal_snapshots" }));
+        restore_state(&restored, &snapshot, "correct horse").unwrap();
+        assert_eq!(
+            restored.get(MY_RELAY_ONION_SK).public(),
+            relay.get(MY_RELAY_ONION_SK).public()
+        );
+    }
+}
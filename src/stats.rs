@@ -0,0 +1,248 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::context::CtxField;
+
+/// Named monotonic counters and latency histograms, exposed over the control protocol and the status page so that operators can graph and alert on them.
+pub static STATS: CtxField<Stats> = |ctx| Stats::with_buckets(ctx.init().histogram_buckets.clone());
+
+/// Bucket upper bounds, in seconds, of histograms that aren't configured otherwise. The same ones Prometheus client libraries default to.
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+pub struct Stats {
+    counters: DashMap<&'static str, u64>,
+    histograms: DashMap<&'static str, Histogram>,
+    /// Bucket upper bounds, by histogram name, of histograms that don't use [DEFAULT_BUCKETS].
+    buckets: BTreeMap<String, Vec<f64>>,
+}
+
+impl Stats {
+    /// Creates empty stats whose histograms use the given buckets, by histogram name.
+    pub fn with_buckets(buckets: BTreeMap<String, Vec<f64>>) -> Self {
+        Self {
+            buckets,
+            ..Default::default()
+        }
+    }
+
+    /// Increments the given counter by one.
+    pub fn incr(&self, name: &'static str) {
+        self.add(name, 1);
+    }
+
+    /// Increments the given counter by `n`.
+    pub fn add(&self, name: &'static str, n: u64) {
+        *self.counters.entry(name).or_default() += n;
+    }
+
+    /// Records a sample into the given histogram.
+    pub fn observe(&self, name: &'static str, sample: Duration) {
+        self.histograms
+            .entry(name)
+            .or_insert_with(|| {
+                Histogram::new(
+                    self.buckets
+                        .get(name)
+                        .cloned()
+                        .unwrap_or_else(|| DEFAULT_BUCKETS.to_vec()),
+                )
+            })
+            .observe(sample);
+    }
+
+    /// Returns a snapshot of all counters.
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.counters
+            .iter()
+            .map(|entry| (entry.key().to_string(), *entry.value()))
+            .collect()
+    }
+
+    /// Returns a snapshot of all histograms.
+    pub fn histograms(&self) -> BTreeMap<String, HistogramSnapshot> {
+        self.histograms
+            .iter()
+            .map(|entry| (entry.key().to_string(), entry.value().snapshot()))
+            .collect()
+    }
+
+    /// Renders all counters and histograms in the Prometheus text format, with names prefixed by `earendil_` and dots turned into underscores.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in self.snapshot() {
+            let name = format!("{}_total", prometheus_name(&name));
+            out += &format!("# TYPE {name} counter\n{name} {value}\n");
+        }
+        for (name, histogram) in self.histograms() {
+            let name = prometheus_name(&name);
+            out += &format!("# TYPE {name} histogram\n");
+            for (le, count) in histogram.buckets.iter() {
+                out += &format!("{name}_bucket{{le=\"{le}\"}} {count}\n");
+            }
+            out += &format!("{name}_bucket{{le=\"+Inf\"}} {}\n", histogram.count);
+            out += &format!("{name}_sum {}\n", histogram.sum_secs);
+            out += &format!("{name}_count {}\n", histogram.count);
+        }
+        out
+    }
+}
+
+fn prometheus_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("earendil_{name}")
+}
+
+/// Counts samples into buckets by their upper bounds, so that monitoring can compute accurate percentiles over any window with `histogram_quantile`, rather than taking ours.
+struct Histogram {
+    bounds: Vec<f64>,
+    /// How many samples fell into each bucket and no lower one, with one more for samples above every bound.
+    counts: Vec<u64>,
+    sum_secs: f64,
+}
+
+impl Histogram {
+    fn new(bounds: Vec<f64>) -> Self {
+        Self {
+            counts: vec![0; bounds.len() + 1],
+            bounds,
+            sum_secs: 0.0,
+        }
+    }
+
+    fn observe(&mut self, sample: Duration) {
+        let secs = sample.as_secs_f64();
+        let bucket = self.bounds.partition_point(|bound| *bound < secs);
+        self.counts[bucket] += 1;
+        self.sum_secs += secs;
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(bound, count)| {
+                cumulative += count;
+                (*bound, cumulative)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: self.counts.iter().sum(),
+            sum_secs: self.sum_secs,
+        }
+    }
+}
+
+/// A snapshot of a histogram, in seconds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Each bucket's upper bound, with how many samples were at most that. Samples above every bound only count towards `count`.
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum_secs: f64,
+}
+
+/// How many of the most recent samples a [PercentileEstimator] keeps.
+const PERCENTILE_WINDOW: usize = 1024;
+
+/// Estimates percentiles of a stream of durations over a sliding window of the most recent samples, so that memory use stays bounded and old behavior ages out.
+#[derive(Default)]
+pub struct PercentileEstimator {
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl PercentileEstimator {
+    /// Records one sample, forgetting the oldest one if the window is full.
+    pub fn record(&self, sample: Duration) {
+        let mut samples = self.samples.lock();
+        if samples.len() >= PERCENTILE_WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Returns the p50/p90/p99 of the current window, or `None` if nothing was recorded yet.
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        let mut sorted: Vec<Duration> = self.samples.lock().iter().copied().collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_unstable();
+        let at = |p: f64| {
+            let idx = ((sorted.len() as f64 * p).ceil() as usize).saturating_sub(1);
+            sorted[idx.min(sorted.len() - 1)].as_micros() as u64
+        };
+        Some(Percentiles {
+            samples: sorted.len() as u64,
+            p50_us: at(0.5),
+            p90_us: at(0.9),
+            p99_us: at(0.99),
+        })
+    }
+}
+
+/// A snapshot of a [PercentileEstimator], in microseconds.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Percentiles {
+    pub samples: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_export_cumulative_buckets() {
+        let stats =
+            Stats::with_buckets([("link.rtt_seconds".to_string(), vec![0.25, 0.5, 1.0])].into());
+        for ms in [125, 250, 375, 500, 750, 2000] {
+            stats.observe("link.rtt_seconds", Duration::from_millis(ms));
+        }
+        stats.incr("gossip.round_failed");
+
+        let histogram = &stats.histograms()["link.rtt_seconds"];
+        // samples right at a bound count towards its bucket
+        assert_eq!(histogram.buckets, vec![(0.25, 2), (0.5, 4), (1.0, 5)]);
+        assert_eq!(histogram.count, 6);
+        assert_eq!(histogram.sum_secs, 4.0);
+
+        assert_eq!(
+            stats.to_prometheus(),
+            "# TYPE earendil_gossip_round_failed_total counter\n\
+             earendil_gossip_round_failed_total 1\n\
+             # TYPE earendil_link_rtt_seconds histogram\n\
+             earendil_link_rtt_seconds_bucket{le=\"0.25\"} 2\n\
+             earendil_link_rtt_seconds_bucket{le=\"0.5\"} 4\n\
+             earendil_link_rtt_seconds_bucket{le=\"1\"} 5\n\
+             earendil_link_rtt_seconds_bucket{le=\"+Inf\"} 6\n\
+             earendil_link_rtt_seconds_sum 4\n\
+             earendil_link_rtt_seconds_count 6\n"
+        );
+    }
+
+    #[test]
+    fn unconfigured_histograms_use_the_default_buckets() {
+        let stats = Stats::default();
+        stats.observe("forwarding.peel_latency_seconds", Duration::from_millis(3));
+        let histogram = &stats.histograms()["forwarding.peel_latency_seconds"];
+        assert_eq!(histogram.buckets.len(), DEFAULT_BUCKETS.len());
+        assert!(histogram.buckets.iter().all(|(_, count)| *count == 1));
+    }
+}
@@ -1,4 +1,10 @@
-use std::{pin::Pin, sync::Arc};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use clone_macro::clone;
 use futures_util::{AsyncRead, AsyncWrite};
@@ -19,9 +25,16 @@ use crate::haven::HavenPacketConn;
 /// In fact, the de-facto standard protocol used in Earendil to represent TCP channels is [picomux] over [HavenStream]s. The convenience wrappers [crate::PooledListener] and [crate::PooledVisitor] are provided for that.
 pub struct HavenStream {
     inner_stream: virta::Stream,
+    counts: Arc<PacketCounts>,
     _task: Arc<Task<()>>,
 }
 
+#[derive(Default)]
+struct PacketCounts {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
 impl HavenStream {
     /// Creates a reliable stream from the underlying packet connection.
     pub fn new(underlying: HavenPacketConn) -> Self {
@@ -42,6 +55,7 @@ impl HavenStream {
         let (s2_state, s2_stream) = StreamState::new_established(tick_notify);
 
         let wrapped_ss = Arc::new(Mutex::new(s2_state));
+        let counts = Arc::new(PacketCounts::default());
         let ticker_task = clone!([wrapped_ss], async move {
             loop {
                 let maybe = wrapped_ss.lock().tick(&outgoing_callback);
@@ -58,16 +72,18 @@ impl HavenStream {
             }
         });
 
-        let forward_task = clone!([wrapped_ss, underlying], async move {
+        let forward_task = clone!([wrapped_ss, underlying, counts], async move {
             let up_loop = async {
                 loop {
                     let smsg = recv_outgoing.recv().await?;
                     underlying.send_pkt(&smsg.stdcode()).await?;
+                    counts.sent.fetch_add(1, Ordering::Relaxed);
                 }
             };
             let down_loop = async {
                 loop {
                     let msg = underlying.recv_pkt().await?;
+                    counts.received.fetch_add(1, Ordering::Relaxed);
                     let smsg: StreamMessage = stdcode::deserialize(&msg)?;
                     wrapped_ss.lock().inject_incoming(smsg);
                 }
@@ -83,10 +99,21 @@ impl HavenStream {
 
         Self {
             inner_stream: s2_stream,
+            counts,
             _task: Arc::new(task),
         }
     }
 
+    /// How many packets the stream sent so far, retransmissions and acknowledgements included.
+    pub fn packets_sent(&self) -> u64 {
+        self.counts.sent.load(Ordering::Relaxed)
+    }
+
+    /// How many packets the stream received so far.
+    pub fn packets_received(&self) -> u64 {
+        self.counts.received.load(Ordering::Relaxed)
+    }
+
     fn pin_project_inner(self: std::pin::Pin<&mut Self>) -> Pin<&mut virta::Stream> {
         // SAFETY: this is a safe pin-projection, since we never get a &mut sosistab2::Stream from a Pin<&mut Stream> elsewhere.
         // Safety requires that we either consistently lose Pin or keep it.
@@ -0,0 +1,12 @@
+//! Helpers shared by the unit tests of different modules.
+
+use earendil_crypt::RelayIdentitySecret;
+
+use crate::context::{DaemonContext, RELAY_GRAPH};
+
+pub use earendil_topology::test_util::adjacency;
+
+/// Adds a relay with a fresh identity to our relay graph, returning the identity.
+pub fn known_relay(ctx: &DaemonContext) -> RelayIdentitySecret {
+    earendil_topology::test_util::known_relay(&mut ctx.get(RELAY_GRAPH).write())
+}
@@ -0,0 +1,157 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use picomux::PicoMux;
+use serde::{Deserialize, Serialize};
+use smol_timeout::TimeoutExt;
+use stdcode::StdcodeSerializeExt;
+
+use crate::{
+    context::DaemonContext,
+    pascal::{read_pascal, write_pascal},
+    stream::HavenStream,
+    HavenEndpoint, HavenPacketConn,
+};
+
+/// The picomux metadata that marks a stream as a throughput test, which haven listeners sink rather than hand to the application.
+pub const THROUGHPUT_METADATA: &[u8] = b"earendil-throughput";
+
+/// The longest a throughput test may run.
+pub const MAX_THROUGHPUT_DURATION: Duration = Duration::from_secs(60);
+
+/// How much dummy data goes in each frame of a throughput test.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// How long past [MAX_THROUGHPUT_DURATION] a sink waits for a test to wrap up, for the data still on its way when the visitor stopped sending.
+const SINK_GRACE: Duration = Duration::from_secs(30);
+
+/// The most dummy data a sink takes in one test before hanging up.
+const MAX_SINK_BYTES: u64 = 1 << 30;
+
+/// What a throughput test achieved.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThroughputReport {
+    /// How many bytes of dummy data the destination received.
+    pub bytes_delivered: u64,
+    /// How long it took, from connecting to the destination confirming it received everything, in milliseconds.
+    pub elapsed_ms: u64,
+    /// The goodput, in bytes per second.
+    pub bytes_per_sec: u64,
+    /// How many packets carried the test to the destination, retransmissions included.
+    pub packets_sent: u64,
+    /// How many of those the destination received.
+    pub packets_delivered: u64,
+    /// The fraction of packets lost on the way, between 0 and 1.
+    pub loss: f64,
+}
+
+/// What the sink reports once a throughput test is over: how many bytes of dummy data it got, and how many packets it received by the time the visitor asked.
+#[derive(Serialize, Deserialize)]
+struct SinkReport {
+    bytes: u64,
+    packets: u64,
+}
+
+/// Streams dummy data to the haven at `dest` over a fresh reliable stream for `duration`, capped at [MAX_THROUGHPUT_DURATION], and reports how much got through. The destination must be a haven behind a [crate::PooledListener] made with [crate::PooledListener::with_throughput_sink], which discards the data instead of handing it to the application. The connection is torn down afterward.
+pub async fn measure_throughput(
+    ctx: &DaemonContext,
+    dest: HavenEndpoint,
+    duration: Duration,
+) -> anyhow::Result<ThroughputReport> {
+    let duration = duration.min(MAX_THROUGHPUT_DURATION);
+    let start = Instant::now();
+    let stream = HavenStream::new(HavenPacketConn::connect(ctx, dest).await?);
+    let (read, write) = stream.clone().split();
+    let mux = PicoMux::new(read, write);
+    let mut test = mux.open(THROUGHPUT_METADATA).await?;
+
+    let chunk = vec![0u8; CHUNK_SIZE];
+    let until = Instant::now() + duration;
+    while Instant::now() < until {
+        write_pascal(&chunk, &mut test).await?;
+    }
+    // an empty frame ends the test, and the sink answers with another once it has everything before it
+    write_pascal(&[], &mut test).await?;
+    read_end(&mut test).await?;
+    let elapsed = start.elapsed();
+    // only now, with all the data delivered, do both sides count packets, one right after the other
+    let packets_sent = stream.packets_sent();
+    write_pascal(&[], &mut test).await?;
+    let report: SinkReport = stdcode::deserialize(&read_pascal(&mut test).await?)?;
+    // the sink also counted the packet asking it to
+    let packets_delivered = report.packets.saturating_sub(1).min(packets_sent);
+
+    tracing::debug!(
+        dest = display(dest),
+        bytes = report.bytes,
+        elapsed = debug(elapsed),
+        "measured throughput"
+    );
+    Ok(ThroughputReport {
+        bytes_delivered: report.bytes,
+        elapsed_ms: elapsed.as_millis() as u64,
+        bytes_per_sec: (report.bytes as f64 / elapsed.as_secs_f64()) as u64,
+        packets_sent,
+        packets_delivered,
+        loss: if packets_sent == 0 {
+            0.0
+        } else {
+            1.0 - (packets_delivered as f64 / packets_sent as f64)
+        },
+    })
+}
+
+/// Discards a throughput test arriving over `stream`, then tells the visitor how many bytes it got, and how many packets `conn`, the connection the test came over, received. Hangs up on tests that run longer or send more than any visitor of ours would.
+pub(crate) async fn sink_throughput(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    conn: &HavenStream,
+) -> anyhow::Result<()> {
+    async {
+        let mut bytes = 0;
+        loop {
+            let len = read_len(&mut stream).await?;
+            if len == 0 {
+                break;
+            }
+            bytes += len;
+            anyhow::ensure!(
+                bytes <= MAX_SINK_BYTES,
+                "the throughput test sent more than {MAX_SINK_BYTES} bytes"
+            );
+            // never buffered whole, however big the visitor claims the frame is
+            let copied =
+                futures::io::copy((&mut stream).take(len), &mut futures::io::sink()).await?;
+            anyhow::ensure!(copied == len, "the throughput test ended mid-frame");
+        }
+        write_pascal(&[], &mut stream).await?;
+        // the visitor counts what it sent, then asks for our count right after
+        read_end(&mut stream).await?;
+        let report = SinkReport {
+            bytes,
+            packets: conn.packets_received(),
+        };
+        write_pascal(&report.stdcode(), &mut stream).await?;
+        tracing::debug!(bytes, "sank a throughput test");
+        anyhow::Ok(())
+    }
+    .timeout(MAX_THROUGHPUT_DURATION + SINK_GRACE)
+    .await
+    .context("the throughput test ran too long")?
+}
+
+/// Reads the length of the next frame of a throughput test.
+async fn read_len(mut stream: impl AsyncRead + Unpin) -> anyhow::Result<u64> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).await?;
+    Ok(u32::from_be_bytes(len) as u64)
+}
+
+/// Reads the empty frame that marks the end of a step of a throughput test.
+async fn read_end(stream: impl AsyncRead + Unpin) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        read_len(stream).await? == 0,
+        "expected the end of the throughput test"
+    );
+    Ok(())
+}
@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+
+use earendil_packet::RawPacket;
+use serde::{Deserialize, Serialize};
+use smol::lock::Mutex;
+use sqlx::Row;
+
+use crate::{
+    clock,
+    context::{CtxField, DaemonContext},
+    db::DATABASE,
+    network::{all_client_neighs, all_relay_neighs},
+    stats::STATS,
+};
+
+pub const USAGE_TRANSIT_PACKETS: &str = "usage.transit_packets";
+pub const USAGE_TRANSIT_BYTES: &str = "usage.transit_bytes";
+pub const USAGE_TERMINATED_PACKETS: &str = "usage.terminated_packets";
+pub const USAGE_TERMINATED_BYTES: &str = "usage.terminated_bytes";
+
+/// Usage is rolled up into rows of this many seconds.
+pub const USAGE_HOUR_SECS: u64 = 3600;
+
+/// Traffic totals, as counted in the stats.
+#[derive(Clone, Copy, Default)]
+struct Counters {
+    transit_packets: u64,
+    transit_bytes: u64,
+    terminated_packets: u64,
+    terminated_bytes: u64,
+}
+
+impl Counters {
+    fn read(ctx: &DaemonContext) -> Self {
+        let stats = ctx.get(STATS).snapshot();
+        let stat = |name: &str| stats.get(name).copied().unwrap_or(0);
+        Self {
+            transit_packets: stat(USAGE_TRANSIT_PACKETS),
+            transit_bytes: stat(USAGE_TRANSIT_BYTES),
+            terminated_packets: stat(USAGE_TERMINATED_PACKETS),
+            terminated_bytes: stat(USAGE_TERMINATED_BYTES),
+        }
+    }
+
+    fn since(&self, earlier: &Self) -> Self {
+        Self {
+            transit_packets: self.transit_packets.saturating_sub(earlier.transit_packets),
+            transit_bytes: self.transit_bytes.saturating_sub(earlier.transit_bytes),
+            terminated_packets: self
+                .terminated_packets
+                .saturating_sub(earlier.terminated_packets),
+            terminated_bytes: self
+                .terminated_bytes
+                .saturating_sub(earlier.terminated_bytes),
+        }
+    }
+}
+
+/// The counters as of the last rollup that made it into the state cache.
+static ROLLED_UP: CtxField<Mutex<Counters>> = |_| Mutex::new(Counters::default());
+
+/// Traffic, neighbors, and settlements over one period of [usage_history].
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct UsagePeriod {
+    /// When the period starts, in seconds since the Unix epoch.
+    pub start: u64,
+    /// Packets we passed on toward someone else.
+    pub transit_packets: u64,
+    pub transit_bytes: u64,
+    /// Packets addressed to us, or to sockets on this node.
+    pub terminated_packets: u64,
+    pub terminated_bytes: u64,
+    /// How many distinct relays we were linked to at some point in the period.
+    pub relay_peers: u64,
+    /// How many distinct clients were linked to us at some point in the period.
+    pub client_peers: u64,
+    pub settlements: u64,
+    /// The total of those settlements, in micromel.
+    pub settled: u64,
+}
+
+/// Counts one packet we passed on toward someone else.
+pub fn count_transit(ctx: &DaemonContext) {
+    ctx.get(STATS).incr(USAGE_TRANSIT_PACKETS);
+    ctx.get(STATS)
+        .add(USAGE_TRANSIT_BYTES, std::mem::size_of::<RawPacket>() as u64);
+}
+
+/// Counts one packet that ended its journey here.
+pub fn count_terminated(ctx: &DaemonContext) {
+    ctx.get(STATS).incr(USAGE_TERMINATED_PACKETS);
+    ctx.get(STATS).add(
+        USAGE_TERMINATED_BYTES,
+        std::mem::size_of::<RawPacket>() as u64,
+    );
+}
+
+/// Adds the traffic counted since the last rollup to the current hour's row, and drops expired rows.
+pub async fn roll_up_usage(ctx: &DaemonContext) -> anyhow::Result<()> {
+    let Some(pool) = ctx.get(DATABASE) else {
+        return Ok(());
+    };
+    let mut rolled_up = ctx.get(ROLLED_UP).lock().await;
+    let hour = clock::unix_now(ctx) / USAGE_HOUR_SECS * USAGE_HOUR_SECS;
+    let counted = Counters::read(ctx);
+    let delta = counted.since(&rolled_up);
+
+    let mut txn = pool.begin().await?;
+    sqlx::query(
+        "INSERT INTO hourly_stats (hour, transit_packets, transit_bytes, terminated_packets, terminated_bytes) VALUES (?, ?, ?, ?, ?) ON CONFLICT(hour) DO UPDATE SET transit_packets = transit_packets + excluded.transit_packets, transit_bytes = transit_bytes + excluded.transit_bytes, terminated_packets = terminated_packets + excluded.terminated_packets, terminated_bytes = terminated_bytes + excluded.terminated_bytes",
+    )
+    .bind(hour as i64)
+    .bind(delta.transit_packets as i64)
+    .bind(delta.transit_bytes as i64)
+    .bind(delta.terminated_packets as i64)
+    .bind(delta.terminated_bytes as i64)
+    .execute(&mut *txn)
+    .await?;
+    let neighbors = all_relay_neighs(ctx)
+        .into_iter()
+        .map(|fp| (fp.to_string(), true))
+        .chain(
+            all_client_neighs(ctx)
+                .into_iter()
+                .map(|id| (id.to_string(), false)),
+        );
+    for (neighbor, relay) in neighbors {
+        sqlx::query("INSERT OR IGNORE INTO hourly_peers (hour, neighbor, relay) VALUES (?, ?, ?)")
+            .bind(hour as i64)
+            .bind(neighbor)
+            .bind(relay)
+            .execute(&mut *txn)
+            .await?;
+    }
+    // the last hour too, since settlements recorded right before it ended may have missed its last rollup
+    sqlx::query(
+        "UPDATE hourly_stats SET
+            relay_peers = (SELECT COUNT(*) FROM hourly_peers p WHERE p.hour = hourly_stats.hour AND p.relay),
+            client_peers = (SELECT COUNT(*) FROM hourly_peers p WHERE p.hour = hourly_stats.hour AND NOT p.relay),
+            settlements = (SELECT COUNT(*) FROM settlements s WHERE s.unix_secs >= hourly_stats.hour AND s.unix_secs < hourly_stats.hour + ?2),
+            settled = (SELECT COALESCE(SUM(amount), 0) FROM settlements s WHERE s.unix_secs >= hourly_stats.hour AND s.unix_secs < hourly_stats.hour + ?2)
+        WHERE hour >= ?1 - ?2",
+    )
+    .bind(hour as i64)
+    .bind(USAGE_HOUR_SECS as i64)
+    .execute(&mut *txn)
+    .await?;
+    let cutoff = hour.saturating_sub(ctx.init().usage_history_days.saturating_mul(86400));
+    for table in ["hourly_stats", "hourly_peers"] {
+        sqlx::query(&format!("DELETE FROM {table} WHERE hour < ?"))
+            .bind(cutoff as i64)
+            .execute(&mut *txn)
+            .await?;
+    }
+    txn.commit().await?;
+    *rolled_up = counted;
+    Ok(())
+}
+
+/// The usage rolled up between `start` and `end`, in periods of `resolution_secs`.
+pub async fn usage_history(
+    ctx: &DaemonContext,
+    start: u64,
+    end: u64,
+    resolution_secs: u64,
+) -> anyhow::Result<Vec<UsagePeriod>> {
+    if resolution_secs == 0 || resolution_secs % USAGE_HOUR_SECS != 0 {
+        anyhow::bail!(
+            "the resolution must be a whole number of hours, not {resolution_secs} seconds"
+        )
+    }
+    let Some(pool) = ctx.get(DATABASE) else {
+        anyhow::bail!("usage history needs a state cache")
+    };
+    let mut periods: BTreeMap<u64, UsagePeriod> = BTreeMap::new();
+    let rows = sqlx::query(
+        "SELECT hour / ?3 * ?3 AS period, SUM(transit_packets) AS transit_packets, SUM(transit_bytes) AS transit_bytes, SUM(terminated_packets) AS terminated_packets, SUM(terminated_bytes) AS terminated_bytes, SUM(settlements) AS settlements, SUM(settled) AS settled
+        FROM hourly_stats WHERE hour >= ?1 AND hour < ?2 GROUP BY period",
+    )
+    .bind(start as i64)
+    .bind(end as i64)
+    .bind(resolution_secs as i64)
+    .fetch_all(pool)
+    .await?;
+    for row in rows {
+        let start = row.get::<i64, _>("period") as u64;
+        let column = |name: &str| row.get::<i64, _>(name) as u64;
+        periods.insert(
+            start,
+            UsagePeriod {
+                start,
+                transit_packets: column("transit_packets"),
+                transit_bytes: column("transit_bytes"),
+                terminated_packets: column("terminated_packets"),
+                terminated_bytes: column("terminated_bytes"),
+                relay_peers: 0,
+                client_peers: 0,
+                settlements: column("settlements"),
+                settled: column("settled"),
+            },
+        );
+    }
+    // distinct over the whole period, so that a neighbor linked to us all day counts once in a day, not once for every hour
+    let peers = sqlx::query(
+        "SELECT hour / ?3 * ?3 AS period, relay, COUNT(DISTINCT neighbor) AS peers
+        FROM hourly_peers WHERE hour >= ?1 AND hour < ?2 GROUP BY period, relay",
+    )
+    .bind(start as i64)
+    .bind(end as i64)
+    .bind(resolution_secs as i64)
+    .fetch_all(pool)
+    .await?;
+    for row in peers {
+        let Some(period) = periods.get_mut(&(row.get::<i64, _>("period") as u64)) else {
+            continue;
+        };
+        let count = row.get::<i64, _>("peers") as u64;
+        if row.get::<bool, _>("relay") {
+            period.relay_peers = count;
+        } else {
+            period.client_peers = count;
+        }
+    }
+    Ok(periods.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use earendil_crypt::RelayIdentitySecret;
+
+    use super::*;
+    use crate::{
+        clock::{set_clock, Clock, FakeClock},
+        ledger,
+        micromel::Micromel,
+        network::subscribe_outgoing_relay,
+    };
+
+    /// A node on the given state cache, as if it just started.
+    fn start_node(state_cache: &std::path::Path, fake: &FakeClock) -> DaemonContext {
+        let ctx = DaemonContext::new(
+            serde_json::from_value(serde_json::json!({ "state_cache": state_cache })).unwrap(),
+        );
+        set_clock(&ctx, Clock::Fake(fake.clone()));
+        ctx
+    }
+
+    fn forward(ctx: &DaemonContext, transit: usize, terminated: usize) {
+        (0..transit).for_each(|_| count_transit(ctx));
+        (0..terminated).for_each(|_| count_terminated(ctx));
+    }
+
+    #[test]
+    fn hours_survive_restarts_without_duplicates() {
+        let state_cache =
+            std::env::temp_dir().join(format!("earendil-usage-{}.db", rand::random::<u64>()));
+        let neighbor = RelayIdentitySecret::generate().public().fingerprint();
+        let fake = FakeClock::new();
+        let first_hour = Clock::Fake(fake.clone()).unix_now() / USAGE_HOUR_SECS * USAGE_HOUR_SECS;
+        let packet = std::mem::size_of::<RawPacket>() as u64;
+
+        smol::future::block_on(async {
+            let ctx = start_node(&state_cache, &fake);
+            let _link = subscribe_outgoing_relay(&ctx, neighbor);
+            forward(&ctx, 3, 2);
+            roll_up_usage(&ctx).await.unwrap();
+            forward(&ctx, 1, 0);
+            roll_up_usage(&ctx).await.unwrap();
+            ledger::record_settlement(
+                &ctx,
+                &neighbor.to_string(),
+                Micromel(250),
+                blake3::hash(b"s"),
+            )
+            .await
+            .unwrap();
+            drop(ctx);
+
+            // restarted mid-hour, with its stats starting over from zero
+            let ctx = start_node(&state_cache, &fake);
+            let _link = subscribe_outgoing_relay(&ctx, neighbor);
+            forward(&ctx, 2, 0);
+            roll_up_usage(&ctx).await.unwrap();
+
+            fake.jump_wall(Duration::from_secs(USAGE_HOUR_SECS));
+            forward(&ctx, 4, 1);
+            roll_up_usage(&ctx).await.unwrap();
+            // nothing new was counted, so nothing more is added
+            roll_up_usage(&ctx).await.unwrap();
+
+            let hours = usage_history(
+                &ctx,
+                first_hour,
+                first_hour + 2 * USAGE_HOUR_SECS,
+                USAGE_HOUR_SECS,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                hours,
+                vec![
+                    UsagePeriod {
+                        start: first_hour,
+                        transit_packets: 6,
+                        transit_bytes: 6 * packet,
+                        terminated_packets: 2,
+                        terminated_bytes: 2 * packet,
+                        relay_peers: 1,
+                        client_peers: 0,
+                        settlements: 1,
+                        settled: 250,
+                    },
+                    UsagePeriod {
+                        start: first_hour + USAGE_HOUR_SECS,
+                        transit_packets: 4,
+                        transit_bytes: 4 * packet,
+                        terminated_packets: 1,
+                        terminated_bytes: packet,
+                        relay_peers: 1,
+                        client_peers: 0,
+                        settlements: 0,
+                        settled: 0,
+                    },
+                ]
+            );
+
+            // coarser periods add up the hours, but count each neighbor once
+            let both = usage_history(&ctx, first_hour, first_hour + 2 * USAGE_HOUR_SECS, 7200)
+                .await
+                .unwrap();
+            let transit: u64 = both.iter().map(|period| period.transit_packets).sum();
+            assert_eq!(transit, 10);
+            assert!(both.iter().all(|period| period.relay_peers == 1));
+            assert!(usage_history(&ctx, 0, 1, 1800).await.is_err());
+        });
+        let _ = std::fs::remove_file(state_cache);
+    }
+
+    #[test]
+    fn old_hours_are_dropped() {
+        let state_cache =
+            std::env::temp_dir().join(format!("earendil-usage-{}.db", rand::random::<u64>()));
+        let fake = FakeClock::new();
+        let ctx = start_node(&state_cache, &fake);
+        smol::future::block_on(async {
+            forward(&ctx, 1, 0);
+            roll_up_usage(&ctx).await.unwrap();
+            fake.jump_wall(Duration::from_secs(31 * 86400));
+            forward(&ctx, 1, 0);
+            roll_up_usage(&ctx).await.unwrap();
+            let hours = usage_history(&ctx, 0, u32::MAX as u64, USAGE_HOUR_SECS)
+                .await
+                .unwrap();
+            assert_eq!(hours.len(), 1);
+            assert!(hours[0].start > clock::unix_now(&ctx) - USAGE_HOUR_SECS);
+        });
+        let _ = std::fs::remove_file(state_cache);
+    }
+}
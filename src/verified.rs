@@ -0,0 +1,156 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use earendil_crypt::RelayFingerprint;
+use moka::{
+    notification::RemovalCause,
+    policy::EvictionPolicy,
+    sync::{Cache, CacheBuilder},
+};
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    stats::STATS,
+};
+
+pub const VERIFIED_CACHE_HIT: &str = "verified_cache.hit";
+pub const VERIFIED_CACHE_MISS: &str = "verified_cache.miss";
+pub const VERIFIED_CACHE_EVICTED: &str = "verified_cache.evicted";
+
+/// How long we trust something we verified before checking it again.
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+
+/// Something whose signature we checked.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Verified {
+    /// A relay whose identity descriptor we fetched and inserted into the relay graph, which checks its signature.
+    RelayIdentity(RelayFingerprint),
+    /// A haven locator, by the hash of all of it including the signature.
+    HavenLocator(blake3::Hash),
+}
+
+/// Remembers what we verified lately, so that seeing it again doesn't cost another signature check or fetch. Bounded in size, so that a flood of distinct descriptors only pushes older ones out.
+pub struct VerifiedCache {
+    cache: Cache<Verified, ()>,
+    /// Evictions not yet counted in the stats, since the eviction listener has no context to count them in.
+    evicted: Arc<AtomicU64>,
+}
+
+pub static VERIFIED_CACHE: CtxField<VerifiedCache> = |ctx| {
+    let evicted = Arc::new(AtomicU64::new(0));
+    let cache = CacheBuilder::new(ctx.init().verified_cache_capacity)
+        .eviction_policy(EvictionPolicy::lru())
+        .time_to_live(VERIFIED_TTL)
+        .eviction_listener({
+            let evicted = evicted.clone();
+            move |_, _, cause| {
+                if cause == RemovalCause::Size {
+                    evicted.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        })
+        .build();
+    VerifiedCache { cache, evicted }
+};
+
+/// Whether we verified `what` lately.
+pub fn verified_lately(ctx: &DaemonContext, what: Verified) -> bool {
+    let hit = ctx.get(VERIFIED_CACHE).cache.contains_key(&what);
+    ctx.get(STATS).incr(if hit {
+        VERIFIED_CACHE_HIT
+    } else {
+        VERIFIED_CACHE_MISS
+    });
+    hit
+}
+
+/// Remembers that we just verified `what`.
+pub fn remember_verified(ctx: &DaemonContext, what: Verified) {
+    let verified = ctx.get(VERIFIED_CACHE);
+    verified.cache.insert(what, ());
+    count_evictions(ctx, verified);
+}
+
+/// Runs `verify` on `what`, unless we verified it lately, remembering it if it checks out.
+pub fn check_verified<E>(
+    ctx: &DaemonContext,
+    what: Verified,
+    verify: impl FnOnce() -> Result<(), E>,
+) -> Result<(), E> {
+    if verified_lately(ctx, what) {
+        return Ok(());
+    }
+    verify()?;
+    remember_verified(ctx, what);
+    Ok(())
+}
+
+/// How many things we currently remember verifying.
+pub fn verified_cache_size(ctx: &DaemonContext) -> u64 {
+    let verified = ctx.get(VERIFIED_CACHE);
+    verified.cache.run_pending_tasks();
+    count_evictions(ctx, verified);
+    verified.cache.entry_count()
+}
+
+fn count_evictions(ctx: &DaemonContext, verified: &VerifiedCache) {
+    let evicted = verified.evicted.swap(0, Ordering::Relaxed);
+    if evicted > 0 {
+        ctx.get(STATS).add(VERIFIED_CACHE_EVICTED, evicted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use earendil_crypt::RelayIdentitySecret;
+    use serde_json::json;
+
+    use super::*;
+
+    fn ctx(cfg: serde_json::Value) -> DaemonContext {
+        DaemonContext::new(serde_json::from_value(cfg).unwrap())
+    }
+
+    fn stat(ctx: &DaemonContext, name: &str) -> u64 {
+        ctx.get(STATS).snapshot().get(name).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn floods_stay_within_capacity() {
+        let ctx = ctx(json!({ "verified_cache_capacity": 100 }));
+        for _ in 0..1000 {
+            let fingerprint = RelayIdentitySecret::generate().public().fingerprint();
+            remember_verified(&ctx, Verified::RelayIdentity(fingerprint));
+        }
+        assert!(verified_cache_size(&ctx) <= 100);
+        assert!(stat(&ctx, VERIFIED_CACHE_EVICTED) >= 900);
+    }
+
+    #[test]
+    fn hits_skip_verification() {
+        let ctx = ctx(json!({}));
+        let what = Verified::HavenLocator(blake3::hash(b"a locator"));
+        let verifications = Cell::new(0);
+        let verify = || {
+            verifications.set(verifications.get() + 1);
+            Ok::<_, ()>(())
+        };
+        check_verified(&ctx, what, verify).unwrap();
+        check_verified(&ctx, what, verify).unwrap();
+        assert_eq!(verifications.get(), 1);
+        assert_eq!(stat(&ctx, VERIFIED_CACHE_MISS), 1);
+        assert_eq!(stat(&ctx, VERIFIED_CACHE_HIT), 1);
+
+        // what fails verification isn't remembered
+        let forged = Verified::HavenLocator(blake3::hash(b"a forged locator"));
+        assert!(check_verified(&ctx, forged, || Err(())).is_err());
+        assert!(check_verified(&ctx, forged, || Err(())).is_err());
+    }
+}
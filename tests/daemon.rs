@@ -0,0 +1,605 @@
+use std::{
+    net::{SocketAddr, TcpListener, UdpSocket},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use earendil::{
+    control_protocol::{
+        ControlClient, ControlEncoding, GlobalRpcArgs, GraphDumpFormat, RouteState,
+    },
+    ConfigFile, Daemon, Identity, InRouteConfig, LivenessConfig, MessageClass, ObfsConfig,
+    OutRouteConfig, QuicConnectConfig, QuicListenConfig, RouteDirection,
+};
+use earendil_crypt::RelayIdentitySecret;
+use either::Either;
+use serde::Serialize;
+use smol::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    Timer,
+};
+
+mod helpers;
+
+/// A client config whose only out route leads nowhere, so that the daemon runs without needing a network.
+fn isolated_cfg() -> ConfigFile {
+    let out_route = OutRouteConfig::new("127.0.0.1:1", ObfsConfig::None { params: None });
+    helpers::new_cfg(
+        None,
+        free_control_listen(),
+        vec![],
+        vec![("nowhere".into(), out_route)],
+    )
+}
+
+fn free_control_listen() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+async fn wait_until_bound(addr: SocketAddr) {
+    for _ in 0..50 {
+        if TcpListener::bind(addr).is_err() {
+            return;
+        }
+        Timer::after(Duration::from_millis(100)).await;
+    }
+    panic!("nothing ever listened on {addr}");
+}
+
+async fn wait_until_unbound(addr: SocketAddr) {
+    for _ in 0..50 {
+        if TcpListener::bind(addr).is_ok() {
+            return;
+        }
+        Timer::after(Duration::from_millis(100)).await;
+    }
+    panic!("something still listens on {addr}");
+}
+
+#[test]
+fn two_daemons_start_and_stop() {
+    helpers::init_logs();
+
+    let cfgs = [isolated_cfg(), isolated_cfg()];
+    let listens = cfgs.each_ref().map(|cfg| cfg.control_listen);
+    let daemons: Vec<Daemon> = cfgs
+        .into_iter()
+        .map(|cfg| Daemon::start(cfg).unwrap())
+        .collect();
+    smolscale::block_on(async move {
+        for (daemon, listen) in daemons.iter().zip(listens) {
+            wait_until_bound(listen).await;
+            daemon.control_client().stats().await.unwrap();
+            daemon.check_dead().unwrap();
+        }
+        // the two daemons have separate contexts
+        assert_ne!(daemons[0].client_id(), daemons[1].client_id());
+
+        for daemon in daemons {
+            daemon.stop(Duration::from_secs(5)).await.unwrap();
+        }
+        // nothing is left running, so the control ports are free again
+        for listen in listens {
+            wait_until_unbound(listen).await;
+        }
+    });
+}
+
+#[test]
+fn state_cache_cannot_be_shared() {
+    helpers::init_logs();
+
+    let state_cache =
+        std::env::temp_dir().join(format!("earendil-state-{}.db", rand::random::<u64>()));
+    let mut first_cfg = isolated_cfg();
+    first_cfg.state_cache = Some(state_cache.clone());
+    let mut second_cfg = isolated_cfg();
+    second_cfg.state_cache = Some(state_cache.clone());
+
+    let first = Daemon::start(first_cfg).unwrap();
+    assert!(Daemon::start(second_cfg.clone()).is_err());
+    smolscale::block_on(first.stop(Duration::from_secs(5))).unwrap();
+
+    // the state cache is released once its daemon stops
+    let second = Daemon::start(second_cfg).unwrap();
+    smolscale::block_on(second.stop(Duration::from_secs(5))).unwrap();
+    let _ = std::fs::remove_file(state_cache);
+}
+
+#[test]
+fn identity_from_env() {
+    helpers::init_logs();
+
+    let identity = RelayIdentitySecret::generate();
+    let var = format!("EARENDIL_TEST_IDENTITY_{}", rand::random::<u64>());
+    let state_cache =
+        std::env::temp_dir().join(format!("earendil-state-{}.db", rand::random::<u64>()));
+    let in_route_listen = free_control_listen();
+    let yaml = |state_cache: Option<&std::path::Path>| {
+        let mut yaml = format!(
+            "identity_env: {var}\ncontrol_listen: {}\nin_routes:\n  main:\n    listen: {in_route_listen}\n    obfs: none\n",
+            free_control_listen()
+        );
+        if let Some(state_cache) = state_cache {
+            yaml += &format!("state_cache: {}\n", state_cache.display());
+        }
+        yaml
+    };
+
+    // with the identity kept off disk, state must not be either unless asked for
+    let err = ConfigFile::from_yaml(yaml(None).as_bytes()).unwrap_err();
+    assert!(err.to_string().contains("state_cache"), "{err}");
+
+    // a missing identity is an error, rather than a reason to make up a new one
+    let cfg = ConfigFile::from_yaml(yaml(Some(&state_cache)).as_bytes()).unwrap();
+    assert!(Daemon::start(cfg.clone()).is_err());
+
+    std::env::set_var(&var, hex::encode(identity.as_bytes()));
+    let daemon = Daemon::start(cfg).unwrap();
+    assert_eq!(
+        daemon.identity().unwrap().public().fingerprint(),
+        identity.public().fingerprint()
+    );
+    smolscale::block_on(daemon.stop(Duration::from_secs(5))).unwrap();
+    let _ = std::fs::remove_file(state_cache);
+}
+
+/// Asserts that two responses are the same, as far as anything reading them can tell.
+fn assert_same<T: Serialize>(json: T, cbor: T) {
+    assert_eq!(
+        serde_json::to_value(json).unwrap(),
+        serde_json::to_value(cbor).unwrap()
+    );
+}
+
+#[test]
+fn control_protocol_speaks_cbor() {
+    helpers::init_logs();
+
+    let cfg = isolated_cfg();
+    let listen = cfg.control_listen;
+    let daemon = Daemon::start(cfg).unwrap();
+    smolscale::block_on(async move {
+        wait_until_bound(listen).await;
+        let json = ControlClient::connect(listen, ControlEncoding::Json);
+        let cbor = ControlClient::connect(listen, ControlEncoding::Cbor);
+
+        // responses that don't change from one call to the next decode to the same thing either way
+        assert_same(json.whoami().await.unwrap(), cbor.whoami().await.unwrap());
+        assert_same(
+            json.my_routes().await.unwrap(),
+            cbor.my_routes().await.unwrap(),
+        );
+        assert_same(
+            json.havens_info().await.unwrap(),
+            cbor.havens_info().await.unwrap(),
+        );
+        assert_same(
+            json.list_neighbors().await.unwrap(),
+            cbor.list_neighbors().await.unwrap(),
+        );
+        assert_same(
+            json.list_chats().await.unwrap(),
+            cbor.list_chats().await.unwrap(),
+        );
+        assert_same(
+            json.list_unsent().await.unwrap(),
+            cbor.list_unsent().await.unwrap(),
+        );
+        assert_same(
+            json.get_chat("nobody".into()).await.unwrap(),
+            cbor.get_chat("nobody".into()).await.unwrap(),
+        );
+        assert_same(
+            json.learned_routes().await.unwrap(),
+            cbor.learned_routes().await.unwrap(),
+        );
+        assert_same(
+            json.surb_bundles().await.unwrap(),
+            cbor.surb_bundles().await.unwrap(),
+        );
+        assert_same(
+            json.observed_drops().await.unwrap(),
+            cbor.observed_drops().await.unwrap(),
+        );
+        assert_same(
+            json.debt_events(0).await.unwrap(),
+            cbor.debt_events(0).await.unwrap(),
+        );
+        assert_same(
+            json.graph_dump(GraphDumpFormat::Human).await.unwrap(),
+            cbor.graph_dump(GraphDumpFormat::Human).await.unwrap(),
+        );
+        let yaml = "not: [valid".to_string();
+        assert_same(
+            json.preview_config(yaml.clone()).await.unwrap(),
+            cbor.preview_config(yaml).await.unwrap(),
+        );
+
+        // and the ones that do still decode
+        cbor.stats().await.unwrap();
+        cbor.graph_stats().await.unwrap();
+        cbor.relay_graph().await.unwrap();
+        cbor.status().await.unwrap();
+        cbor.network_summary().await.unwrap();
+        cbor.forwarding_latency().await.unwrap();
+        cbor.send_concurrency().await.unwrap();
+        cbor.daemon_tasks().await.unwrap();
+
+        // clients that know nothing of CBOR, and send no Accept header, still get JSON
+        let request = r#"{"jsonrpc":"2.0","method":"whoami","params":[],"id":1}"#;
+        let mut conn = smol::net::TcpStream::connect(listen).await.unwrap();
+        conn.write_all(
+            format!(
+                "POST / HTTP/1.1\r\nhost: {listen}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{request}",
+                request.len()
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head
+            .to_ascii_lowercase()
+            .contains("content-type: application/json"));
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body["result"],
+            serde_json::to_value(cbor.whoami().await.unwrap()).unwrap()
+        );
+
+        daemon.stop(Duration::from_secs(5)).await.unwrap();
+    });
+}
+
+/// Waits until the daemon has `connected` neighbors or not, failing if it takes too long.
+async fn wait_until_connected(control: &ControlClient, connected: bool) {
+    for _ in 0..100 {
+        if control.list_neighbors().await.unwrap().is_empty() != connected {
+            return;
+        }
+        Timer::after(Duration::from_millis(100)).await;
+    }
+    panic!("neighbors never became connected={connected}");
+}
+
+#[test]
+fn pausing_an_out_route() {
+    helpers::init_logs();
+
+    // the second relay's only out route leads to the first, which has none
+    let seed = helpers::gen_seed("pausing_an_out_route");
+    let (relays, _clients) = helpers::spawn_network(2, 0, Some(seed)).unwrap();
+    smolscale::block_on(async move {
+        let dialer = &relays[1];
+        let control = dialer.control_client();
+        let name = dialer
+            .ctx()
+            .init()
+            .out_routes
+            .keys()
+            .next()
+            .unwrap()
+            .clone();
+        let paused = |routes: serde_json::Value| routes["out_routes"][&name]["paused"].clone();
+        wait_until_connected(&control, true).await;
+
+        control
+            .pause_out_route(name.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        wait_until_connected(&control, false).await;
+        assert_eq!(paused(control.my_routes().await.unwrap()), true);
+        // and it isn't dialed again while paused
+        Timer::after(Duration::from_secs(3)).await;
+        assert!(control.list_neighbors().await.unwrap().is_empty());
+        let status = control.status().await.unwrap();
+        let route = status
+            .routes
+            .iter()
+            .find(|route| route.direction == RouteDirection::Out && route.name == name)
+            .unwrap();
+        assert_eq!(route.state, RouteState::Paused);
+
+        control
+            .resume_out_route(name.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        wait_until_connected(&control, true).await;
+        assert_eq!(paused(control.my_routes().await.unwrap()), false);
+
+        assert!(control
+            .pause_out_route("no such route".into())
+            .await
+            .unwrap()
+            .is_err());
+    });
+}
+
+#[test]
+#[cfg_attr(
+    not(feature = "progress-probe"),
+    ignore = "relays only serve progress probes with --features progress-probe"
+)]
+fn global_rpc_reports_progress() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("global_rpc_reports_progress");
+    let (relays, clients) = helpers::spawn_network(2, 1, Some(seed)).unwrap();
+    smolscale::block_on(async move {
+        helpers::sleep(10).await;
+        let control = clients[0].control_client();
+        let destination = relays[0].identity().unwrap().public().fingerprint();
+        let job_id = control
+            .start_global_rpc(GlobalRpcArgs {
+                id: None,
+                destination,
+                method: "progress_probe".into(),
+                args: vec![3.into(), 500.into()],
+                sealed: false,
+                nack: false,
+                class: MessageClass::Normal,
+            })
+            .await
+            .unwrap();
+
+        let mut seen = vec![];
+        let result = loop {
+            let job = control.global_rpc_job(job_id).await.unwrap().unwrap();
+            if let Some(progress) = job.progress {
+                if seen.last() != Some(&progress.seq) {
+                    seen.push(progress.seq);
+                }
+            }
+            if let Some(result) = job.result {
+                break result.unwrap();
+            }
+            Timer::after(Duration::from_millis(100)).await;
+        };
+        assert_eq!(result, serde_json::json!(3));
+        // updates may be lost, but never go backwards or outnumber the steps
+        assert!(seen.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(seen.iter().all(|seq| (1..=3).contains(seq)), "{seen:?}");
+        let job = control.global_rpc_job(job_id).await.unwrap().unwrap();
+        assert!(job.updates <= 3);
+        assert!(control.global_rpc_job(job_id + 1).await.unwrap().is_none());
+    });
+}
+
+/// Waits until the daemon's relay graph has an adjacency signed after `after`, failing if it takes too long.
+async fn wait_for_adjacency(control: &ControlClient, after: u64) {
+    for _ in 0..100 {
+        let graph = control.relay_graph().await.unwrap();
+        if graph.adjacencies.iter().any(|adj| adj.timestamp > after) {
+            return;
+        }
+        Timer::after(Duration::from_millis(100)).await;
+    }
+    panic!("no adjacency was signed after {after}");
+}
+
+#[test]
+fn gossip_over_quic_survives_an_address_change() {
+    helpers::init_logs();
+
+    let listen = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let listener_seed = "quic listener".to_string();
+    let quic = QuicListenConfig::default();
+    let server_fingerprint = quic
+        .fingerprint(Some(&RelayIdentitySecret::from_seed(&listener_seed)))
+        .unwrap();
+    let mut in_route = InRouteConfig::new(
+        listen.to_string().parse().unwrap(),
+        ObfsConfig::None { params: None },
+    );
+    in_route.quic = Some(quic);
+    let listener = Daemon::start(helpers::new_cfg(
+        Some(Identity::IdentitySeed(listener_seed)),
+        free_control_listen(),
+        vec![("quic".into(), in_route)],
+        vec![],
+    ))
+    .unwrap();
+
+    let mut out_route = OutRouteConfig::new(listen.to_string(), ObfsConfig::None { params: None });
+    out_route.fingerprint = Some(listener.identity().unwrap().public().fingerprint());
+    out_route.quic = Some(QuicConnectConfig { server_fingerprint });
+    let dialer = Daemon::start(helpers::new_cfg(
+        Some(Identity::IdentitySeed("quic dialer".into())),
+        free_control_listen(),
+        vec![],
+        vec![("quic".into(), out_route)],
+    ))
+    .unwrap();
+
+    smolscale::block_on(async move {
+        let control = dialer.control_client();
+        let now = || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        wait_for_adjacency(&control, 0).await;
+
+        // our address changes, and the link carries on gossiping from the new one
+        dialer.rebind_quic_out_routes().unwrap();
+        let rebound_at = now();
+        wait_for_adjacency(&control, rebound_at).await;
+        let status = control.status().await.unwrap();
+        let route = status
+            .routes
+            .iter()
+            .find(|route| route.direction == RouteDirection::Out && route.name == "quic")
+            .unwrap();
+        assert_eq!(route.state, RouteState::Up);
+        assert_eq!(route.links, 1);
+        // which it did without ever reconnecting
+        assert_eq!(route.last_error, None);
+
+        dialer.stop(Duration::from_secs(5)).await.unwrap();
+        listener.stop(Duration::from_secs(5)).await.unwrap();
+    });
+}
+
+#[test]
+fn unpinned_out_routes_fail_closed() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("unpinned_out_routes_fail_closed");
+    let (relays, _clients) = helpers::spawn_network(1, 0, Some(seed)).unwrap();
+    let mut out_route = helpers::out_route_to(relays[0].ctx().init()).unwrap();
+    out_route.fingerprint = None;
+    out_route.tofu = false;
+    let dialer = Daemon::start(helpers::new_cfg(
+        None,
+        free_control_listen(),
+        vec![],
+        vec![("unpinned".into(), out_route)],
+    ))
+    .unwrap();
+    smolscale::block_on(async move {
+        let control = dialer.control_client();
+        Timer::after(Duration::from_secs(5)).await;
+        // whoever answers, there is nothing to check them against
+        assert!(control.list_neighbors().await.unwrap().is_empty());
+        let status = control.status().await.unwrap();
+        let route = status
+            .routes
+            .iter()
+            .find(|route| route.direction == RouteDirection::Out && route.name == "unpinned")
+            .unwrap();
+        assert!(route
+            .last_error
+            .as_ref()
+            .is_some_and(|err| err.contains("neither a fingerprint nor tofu")));
+    });
+}
+
+/// Carries TCP connections to `target`, counting them, and stalls the connections that were already open when [Self::freeze] is called, while keeping them open.
+struct FreezingProxy {
+    addr: SocketAddr,
+    accepted: Arc<AtomicUsize>,
+    frozen_below: Arc<AtomicUsize>,
+    _task: smol::Task<()>,
+}
+
+impl FreezingProxy {
+    async fn new(target: SocketAddr) -> Self {
+        let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let frozen_below = Arc::new(AtomicUsize::new(0));
+        let task = smolscale::spawn({
+            let (accepted, frozen_below) = (accepted.clone(), frozen_below.clone());
+            async move {
+                loop {
+                    let (near, _) = listener.accept().await.unwrap();
+                    let index = accepted.fetch_add(1, Ordering::SeqCst);
+                    let Ok(far) = smol::net::TcpStream::connect(target).await else {
+                        continue;
+                    };
+                    let carry = |from: smol::net::TcpStream, to: smol::net::TcpStream| {
+                        let frozen_below = frozen_below.clone();
+                        async move {
+                            let (mut from, mut to) = (from, to);
+                            let mut buf = vec![0u8; 65536];
+                            while let Ok(n @ 1..) = from.read(&mut buf).await {
+                                if index < frozen_below.load(Ordering::SeqCst) {
+                                    smol::future::pending::<()>().await;
+                                }
+                                if to.write_all(&buf[..n]).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    };
+                    smolscale::spawn(carry(near.clone(), far.clone())).detach();
+                    smolscale::spawn(carry(far, near)).detach();
+                }
+            }
+        });
+        Self {
+            addr,
+            accepted,
+            frozen_below,
+            _task: task,
+        }
+    }
+
+    fn accepted(&self) -> usize {
+        self.accepted.load(Ordering::SeqCst)
+    }
+
+    fn freeze(&self) {
+        self.frozen_below.store(self.accepted(), Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn silent_neighbors_are_dropped_and_redialed() {
+    helpers::init_logs();
+
+    // the second relay dials the first through a proxy that can go silent without closing anything
+    let seed = helpers::gen_seed("silent_neighbors_are_dropped_and_redialed");
+    let (mut relay_cfgs, _) = helpers::gen_network(2, 0, Some(seed)).unwrap();
+    let target = relay_cfgs[0].in_routes["obfsudp"].listen.port();
+    let proxy = smolscale::block_on(FreezingProxy::new(SocketAddr::from((
+        [127, 0, 0, 1],
+        target,
+    ))));
+    for cfg in relay_cfgs.iter_mut() {
+        cfg.liveness = LivenessConfig {
+            probe_interval_ms: 200,
+            probe_timeout_ms: 500,
+            max_failed_probes: Some(2),
+        };
+    }
+    for route in relay_cfgs[1].out_routes.values_mut() {
+        route.connect = proxy.addr.to_string();
+    }
+    let relays = helpers::configs_to_daemons(relay_cfgs).unwrap();
+    smolscale::block_on(async move {
+        let first = relays[0].identity().unwrap().public().fingerprint();
+        let control = relays[1].control_client();
+        let neighbors = || async { control.list_neighbors().await.unwrap() };
+        wait_until_connected(&control, true).await;
+        assert_eq!(neighbors().await, vec![Either::Right(first)]);
+        assert_eq!(proxy.accepted(), 1);
+
+        // the link stays open but nothing gets across, so only liveness probes can tell
+        proxy.freeze();
+        wait_until_connected(&control, false).await;
+        let stats = control.stats().await.unwrap();
+        assert!(stats.get("link.declared_dead").copied().unwrap_or(0) >= 1);
+
+        // the out route dials the neighbor again, and it comes back
+        wait_until_connected(&control, true).await;
+        assert_eq!(neighbors().await, vec![Either::Right(first)]);
+        assert!(proxy.accepted() >= 2);
+        let status = control.status().await.unwrap();
+        let route = status
+            .routes
+            .iter()
+            .find(|route| route.direction == RouteDirection::Out)
+            .unwrap();
+        assert_eq!(route.state, RouteState::Up);
+        assert!(route
+            .last_error
+            .as_ref()
+            .is_some_and(|err| err.contains("declared dead")));
+    });
+}
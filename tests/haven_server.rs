@@ -0,0 +1,89 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use earendil::{HavenClient, HavenEndpoint, HavenListener, HavenRequestError, HavenServer};
+use earendil_crypt::HavenIdentitySecret;
+use smol::future::FutureExt as _;
+
+mod helpers;
+
+/// A small service: `echo` sends the body back, and `stats` says how many requests were served before it.
+fn example_service() -> HavenServer {
+    let served = Arc::new(AtomicU64::new(0));
+    let echo_served = served.clone();
+    HavenServer::new()
+        .route("echo", move |body, reply| {
+            echo_served.fetch_add(1, Ordering::SeqCst);
+            async move {
+                reply.reply(body);
+                Ok(())
+            }
+        })
+        .route("stats", move |_, reply| {
+            let served = served.fetch_add(1, Ordering::SeqCst);
+            async move {
+                reply.reply(format!("served {served}"));
+                Ok(())
+            }
+        })
+        .route("broken", |_, _| async { anyhow::bail!("out of order") })
+}
+
+#[test]
+fn haven_server() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("haven server");
+    let (mut relays, mut clients) = helpers::spawn_network(2, 4, Some(seed)).unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        let bob = relays.pop().unwrap();
+        let bob_haven_id = HavenIdentitySecret::generate();
+        let rendezvous = relays
+            .last()
+            .unwrap()
+            .identity()
+            .unwrap()
+            .public()
+            .fingerprint();
+        let bob_listener = HavenListener::bind(&bob.ctx(), bob_haven_id, 1234, rendezvous)
+            .await
+            .unwrap();
+        let bob_process = async {
+            example_service().serve(bob_listener).await.unwrap();
+        };
+
+        let alice_process = async {
+            helpers::sleep(5).await;
+            let alice = clients.pop().unwrap();
+            let client = HavenClient::new(alice.ctx());
+            let bob_haven = HavenEndpoint::new(bob_haven_id.public().fingerprint(), 1234);
+
+            // requests sent at once share one connection
+            let echoes = futures::future::join_all(
+                (0..5).map(|i| client.request(bob_haven, "echo", format!("hi {i}").as_bytes())),
+            )
+            .await;
+            for (i, echo) in echoes.into_iter().enumerate() {
+                assert_eq!(echo.unwrap(), format!("hi {i}").as_bytes());
+            }
+            let stats = client.request(bob_haven, "stats", b"").await.unwrap();
+            assert_eq!(stats, "served 5".as_bytes());
+
+            assert!(matches!(
+                client.request(bob_haven, "nope", b"").await,
+                Err(HavenRequestError::NoSuchVerb(verb)) if verb == "nope"
+            ));
+            assert!(matches!(
+                client.request(bob_haven, "broken", b"").await,
+                Err(HavenRequestError::Failed(msg)) if msg.contains("out of order")
+            ));
+        };
+
+        bob_process.race(alice_process).await
+    });
+}
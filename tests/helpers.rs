@@ -9,7 +9,7 @@ use std::{
 };
 
 use earendil::{
-    Daemon, {ConfigFile, Identity, InRouteConfig, LinkPrice, ObfsConfig, OutRouteConfig},
+    Daemon, Micromel, {ConfigFile, Identity, InRouteConfig, LinkPrice, ObfsConfig, OutRouteConfig},
 };
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use smol::Timer;
@@ -57,26 +57,17 @@ pub fn new_cfg(
     in_routes: InRoutes,
     out_routes: OutRoutes,
 ) -> ConfigFile {
-    let state_cache = None;
-    let in_routes = in_routes.into_iter().collect();
-    let out_routes = out_routes.into_iter().collect();
-    let udp_forwards = vec![];
-    let tcp_forwards = vec![];
-    let socks5 = None;
-    let havens = vec![];
-
-    ConfigFile {
-        identity,
-        state_cache,
-        control_listen,
-        in_routes,
-        out_routes,
-        udp_forwards,
-        tcp_forwards,
-        socks5,
-        havens,
-        auto_settle: None,
+    let mut builder = ConfigFile::builder().control_listen(control_listen);
+    if let Some(identity) = identity {
+        builder = builder.relay(identity);
+    }
+    for (name, route) in in_routes {
+        builder = builder.in_route(name, route);
     }
+    for (name, route) in out_routes {
+        builder = builder.out_route(name, route);
+    }
+    builder.build().unwrap()
 }
 
 // finds a random, unused socket address on localhost
@@ -111,9 +102,9 @@ fn routes(
 ) -> anyhow::Result<(InRoutes, OutRoutes)> {
     let _secret = "secret".to_string();
     let _link_price = LinkPrice {
-        max_outgoing_price: 1,
-        incoming_price: 0,
-        incoming_debt_limit: 100,
+        max_outgoing_price: Micromel(1),
+        incoming_price: Micromel(0),
+        incoming_debt_limit: Micromel(100),
     };
     let mut in_routes = vec![];
 
@@ -122,7 +113,11 @@ fn routes(
             "obfsudp".to_string(),
             InRouteConfig {
                 listen: format!("0.0.0.0:{}", free_port(rng)).parse()?,
-                obfs: ObfsConfig::None,
+                obfs: ObfsConfig::None { params: None },
+                pacing: true,
+                strict_prepay: None,
+                graduated_admission: None,
+                quic: None,
             },
         ))
     }
@@ -153,35 +148,44 @@ fn routes(
                 break;
             }
         }
-        let (connect, obfs) = match relay_cfg.in_routes.get("obfsudp").unwrap() {
-            InRouteConfig { mut listen, obfs } => {
-                listen.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
-                (listen, obfs)
-            }
-        };
         let relay_id = match &relay_cfg.identity {
             Some(Identity::IdentitySeed(seed)) => seed.clone(),
             _ => panic!("no id seed"),
         };
 
-        out_routes.push((
-            relay_id,
-            OutRouteConfig {
-                fingerprint: relay_cfg
-                    .identity
-                    .clone()
-                    .unwrap()
-                    .actualize_relay()?
-                    .public()
-                    .fingerprint(),
-                connect: connect.to_string(),
-                obfs: obfs.clone(),
-            },
-        ));
+        out_routes.push((relay_id, out_route_to(relay_cfg)?));
     }
     Ok((in_routes, out_routes.into_iter().collect()))
 }
 
+// creates an out route that dials the given relay config's in route
+pub fn out_route_to(relay_cfg: &ConfigFile) -> anyhow::Result<OutRouteConfig> {
+    let (connect, obfs) = match relay_cfg.in_routes.get("obfsudp").unwrap() {
+        InRouteConfig { listen, obfs, .. } => (
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), listen.port()),
+            obfs,
+        ),
+    };
+    Ok(OutRouteConfig {
+        fingerprint: Some(
+            relay_cfg
+                .identity
+                .clone()
+                .unwrap()
+                .actualize_relay()?
+                .public()
+                .fingerprint(),
+        ),
+        connect: connect.to_string(),
+        obfs: obfs.clone(),
+        tofu: false,
+        pacing: true,
+        strict_prepay: None,
+        graduated_admission: None,
+        quic: None,
+    })
+}
+
 pub fn gen_seed(phrase: &str) -> [u8; 32] {
     *blake3::hash(phrase.as_bytes()).as_bytes()
 }
@@ -227,7 +231,7 @@ pub fn gen_network(
 }
 
 pub fn configs_to_daemons(configs: Vec<ConfigFile>) -> anyhow::Result<Vec<Daemon>> {
-    configs.into_iter().map(Daemon::init).collect()
+    configs.into_iter().map(Daemon::start).collect()
 }
 
 pub fn spawn_network(
@@ -238,11 +242,11 @@ pub fn spawn_network(
     let (relay_configs, client_configs) = gen_network(num_relays, num_clients, seed)?;
     let relays: Vec<Daemon> = relay_configs
         .into_iter()
-        .map(Daemon::init)
+        .map(Daemon::start)
         .collect::<anyhow::Result<Vec<Daemon>>>()?;
     let clients: Vec<Daemon> = client_configs
         .into_iter()
-        .map(Daemon::init)
+        .map(Daemon::start)
         .collect::<anyhow::Result<Vec<Daemon>>>()?;
 
     Ok((relays, clients))
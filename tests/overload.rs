@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use earendil::{N2rClientSocket, N2rRelaySocket, OverloadConfig};
+use earendil_crypt::AnonEndpoint;
+use smol_timeout::TimeoutExt;
+
+mod helpers;
+
+#[test]
+fn overloaded_relay_sheds_transit_but_serves_its_clients() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("overloaded_relay_sheds_transit_but_serves_its_clients");
+    let (mut relay_cfgs, mut client_cfgs) = helpers::gen_network(4, 1, Some(seed)).unwrap();
+    // relay 0 is always past its red thresholds, so it sheds every transit packet
+    relay_cfgs[0].overload_shedding = Some(OverloadConfig {
+        yellow_backlog: 0,
+        red_backlog: 0,
+        yellow_latency_ms: 0,
+        red_latency_ms: 0,
+        min_shed_ratio: 1.0,
+        max_shed_ratio: 1.0,
+        hysteresis: 0.8,
+    });
+    // relays 0, 1 and 2 form a triangle, relay 3 hangs off relay 0 alone, and the client is relay 0's
+    let link = |cfgs: &mut Vec<earendil::ConfigFile>, from: usize, to: usize| {
+        let route = helpers::out_route_to(&cfgs[to]).unwrap();
+        cfgs[from].out_routes.insert(format!("relay{to}"), route);
+    };
+    for cfg in relay_cfgs.iter_mut() {
+        cfg.out_routes.clear();
+    }
+    link(&mut relay_cfgs, 1, 0);
+    link(&mut relay_cfgs, 2, 0);
+    link(&mut relay_cfgs, 2, 1);
+    link(&mut relay_cfgs, 3, 0);
+    client_cfgs[0].out_routes = [(
+        "relay0".to_string(),
+        helpers::out_route_to(&relay_cfgs[0]).unwrap(),
+    )]
+    .into_iter()
+    .collect();
+
+    let relays = helpers::configs_to_daemons(relay_cfgs).unwrap();
+    let clients = helpers::configs_to_daemons(client_cfgs).unwrap();
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        // everyone else hears that relay 0 is overloaded through gossip
+        let graph = relays[1].control_client().relay_graph().await.unwrap();
+        let relay0 = relays[0].identity().unwrap().public().fingerprint();
+        assert!(graph
+            .relays
+            .iter()
+            .any(|relay| relay.fingerprint == relay0 && relay.overloaded));
+
+        // relay 0's direct client is still served, and promptly
+        let client_skt = N2rClientSocket::bind(clients[0].ctx(), AnonEndpoint::random()).unwrap();
+        for dest in [&relays[1], &relays[2]] {
+            let dest_skt = N2rRelaySocket::bind(dest.ctx(), None).unwrap();
+            for i in 0..10u8 {
+                let msg = Bytes::from(vec![i; 100]);
+                client_skt
+                    .send_to(msg.clone(), dest_skt.local_endpoint())
+                    .await
+                    .unwrap();
+                let (body, _) = dest_skt
+                    .recv_from()
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .expect("a direct client's message was not delivered in time")
+                    .unwrap();
+                assert_eq!(body, msg);
+            }
+        }
+
+        // but the only way from relay 1 to relay 3 is through relay 0, which sheds it
+        let sender_skt = N2rRelaySocket::bind(relays[1].ctx(), None).unwrap();
+        let dest_skt = N2rRelaySocket::bind(relays[3].ctx(), None).unwrap();
+        for _ in 0..10 {
+            let _ = sender_skt
+                .send_to(Bytes::from_static(b"transit"), dest_skt.local_endpoint())
+                .await;
+        }
+        assert!(dest_skt
+            .recv_from()
+            .timeout(Duration::from_secs(5))
+            .await
+            .is_none());
+        let stats = relays[0].control_client().stats().await.unwrap();
+        assert!(stats["overload.shed_transit"] > 0);
+    });
+}
@@ -1,10 +1,16 @@
-use std::time::Duration;
+use std::{sync::atomic::Ordering, time::Duration};
 
 use anyhow::Context;
 use bytes::Bytes;
 
-use earendil::{HavenEndpoint, HavenListener, HavenPacketConn, N2rClientSocket, N2rRelaySocket};
+use earendil::{
+    control_protocol::{ControlClient, SendAndRecvArgs},
+    rendezvous_tap, BeaconStatus, ClientRateLimit, Daemon, DropReason, HavenBeaconConfig,
+    HavenEndpoint, HavenListener, HavenPacketConn, LivenessConfig, N2rClientSocket, N2rRelaySocket,
+    RelayEndpoint, SurbBundle,
+};
 use earendil_crypt::{AnonEndpoint, HavenIdentitySecret};
+use earendil_topology::GraphLimits;
 
 use smol::future::FutureExt as _;
 use smol_timeout::TimeoutExt;
@@ -64,6 +70,204 @@ fn n2r() {
     });
 }
 
+#[test]
+fn send_and_recv_does_not_cross_replies() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("send_and_recv_does_not_cross_replies");
+    let (mut relays, _clients) = helpers::spawn_network(5, 0, Some(seed)).unwrap();
+    smolscale::block_on(async move {
+        let alice = relays.pop().unwrap();
+        let bob = relays.pop().unwrap();
+        let bob_skt = N2rRelaySocket::bind(bob.ctx(), None).unwrap();
+        let bob_ep = bob_skt.local_endpoint();
+
+        helpers::sleep(10).await;
+
+        // bob echoes every request, holding some back so that replies come back out of order
+        let echo = async {
+            loop {
+                let (body, ep) = bob_skt.recv_from().await.unwrap();
+                let bob_skt = bob_skt.clone();
+                smolscale::spawn(async move {
+                    smol::Timer::after(Duration::from_millis(body[0] as u64 * 200)).await;
+                    bob_skt.send_to(body, ep).await
+                })
+                .detach();
+            }
+        };
+        let calls = async {
+            let replies = futures::future::join_all((0..5u8).map(|i| {
+                let ctx = alice.ctx();
+                async move {
+                    let request = Bytes::from(vec![4 - i; 50]);
+                    let reply = N2rClientSocket::send_and_recv(
+                        &ctx,
+                        bob_ep,
+                        request.clone(),
+                        Duration::from_secs(30),
+                    )
+                    .await
+                    .unwrap();
+                    (request, reply)
+                }
+            }))
+            .await;
+            for (request, reply) in replies {
+                assert_eq!(request, reply);
+            }
+
+            let reply = alice
+                .control_client()
+                .send_and_recv(SendAndRecvArgs {
+                    destination: bob_ep,
+                    content: Bytes::from_static(b"over the control protocol"),
+                    timeout_ms: 30_000,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(reply.content.as_ref(), b"over the control protocol");
+        };
+        echo.race(calls).await;
+
+        // nobody answers on a dock nothing is bound to
+        let silent = RelayEndpoint::new(bob_ep.fingerprint, bob_ep.dock.wrapping_add(1));
+        assert!(N2rClientSocket::send_and_recv(
+            &alice.ctx(),
+            silent,
+            Bytes::from_static(b"anyone?"),
+            Duration::from_secs(5),
+        )
+        .await
+        .is_err());
+    });
+}
+
+#[test]
+fn reply_only_client() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("reply_only_client");
+    let (mut relays, mut clients) = helpers::spawn_network(3, 1, Some(seed)).unwrap();
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        // the device provisions a bundle for the sender, and never sends anything itself
+        let device = clients.pop().unwrap();
+        let device_skt = N2rClientSocket::bind(device.ctx(), AnonEndpoint::random()).unwrap();
+        let sender = relays.pop().unwrap();
+        let sender_skt = N2rRelaySocket::bind(sender.ctx(), None).unwrap();
+        let bundle = device_skt
+            .issue_surb_bundle(
+                sender_skt.local_endpoint().fingerprint,
+                3,
+                Duration::from_secs(600),
+            )
+            .unwrap();
+        let delivered = SurbBundle::from_bytes(&bundle.to_bytes()).unwrap();
+        sender_skt.import_surb_bundle(delivered.clone()).unwrap();
+        assert!(sender_skt.import_surb_bundle(delivered).is_err());
+
+        for i in 0..3u8 {
+            let msg = Bytes::from(vec![i; 100]);
+            sender_skt
+                .send_to(msg.clone(), device_skt.local_endpoint())
+                .await
+                .unwrap();
+            let (body, ep) = device_skt
+                .recv_from()
+                .timeout(Duration::from_secs(10))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(body, msg);
+            assert_eq!(ep, sender_skt.local_endpoint());
+        }
+        // the bundle is used up
+        assert!(sender_skt
+            .send_to(
+                Bytes::from_static(b"one too many"),
+                device_skt.local_endpoint()
+            )
+            .await
+            .is_err());
+        let stock = device.control_client().surb_bundles().await.unwrap();
+        assert_eq!(stock.issued[0].remaining, 0);
+        let stats = device.control_client().stats().await.unwrap();
+        assert_eq!(stats.get("surb_bundle.received"), Some(&3));
+    });
+}
+
+#[test]
+fn n2r_message_limits() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("n2r_message_limits");
+    let (mut relays, _clients) = helpers::spawn_network(5, 0, Some(seed)).unwrap();
+    smolscale::block_on(async move {
+        let alice = relays.pop().unwrap();
+        let alice_skt = N2rClientSocket::bind(alice.ctx(), AnonEndpoint::random()).unwrap();
+        let bob = relays.pop().unwrap();
+        let bob_skt = N2rRelaySocket::bind(bob.ctx(), None).unwrap();
+
+        helpers::sleep(10).await;
+
+        let max = alice_skt.max_single_message(bob_skt.local_endpoint());
+        let limits = alice
+            .control_client()
+            .transport_limits(bob_skt.local_endpoint())
+            .await
+            .unwrap();
+        assert_eq!(limits.max_single_message, max);
+        assert!(limits.route_hops.unwrap() <= limits.max_route_hops);
+        // the socket knows which route it would take, not just how long one could be
+        let on_circuit = alice_skt.transport_limits(bob_skt.local_endpoint());
+        assert_eq!(on_circuit.max_single_message, max);
+        assert!(on_circuit.route_hops.unwrap() <= limits.route_hops.unwrap());
+
+        // exactly the maximum goes through, in both directions
+        let alice_msg = Bytes::from(vec![1u8; max]);
+        alice_skt
+            .send_to(alice_msg.clone(), bob_skt.local_endpoint())
+            .await
+            .unwrap();
+        let (body, _) = bob_skt
+            .recv_from()
+            .timeout(Duration::from_secs(10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, alice_msg);
+        let bob_msg = Bytes::from(vec![2u8; bob_skt.max_single_message()]);
+        bob_skt
+            .send_to(bob_msg.clone(), alice_skt.local_endpoint())
+            .await
+            .unwrap();
+        let (body, _) = alice_skt
+            .recv_from()
+            .timeout(Duration::from_secs(10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, bob_msg);
+
+        // but a byte more is refused, since nothing fragments N2R messages
+        let err = alice_skt
+            .send_to(Bytes::from(vec![1u8; max + 1]), bob_skt.local_endpoint())
+            .await
+            .unwrap_err();
+        assert!(format!("{err:?}").contains(&format!("at most {max} bytes")));
+        assert!(bob_skt
+            .send_to(
+                Bytes::from(vec![2u8; bob_skt.max_single_message() + 1]),
+                alice_skt.local_endpoint()
+            )
+            .await
+            .is_err());
+    });
+}
+
 #[test]
 fn haven() {
     helpers::init_logs();
@@ -116,3 +320,660 @@ fn haven() {
         bob_process.race(alice_process).await
     });
 }
+
+#[test]
+#[cfg_attr(
+    not(feature = "rendezvous-tap"),
+    ignore = "rendezvous only show tests what they forward with --features rendezvous-tap"
+)]
+fn rendezvous_only_forwards_ciphertext() {
+    helpers::init_logs();
+
+    // the first relay is the rendezvous between a haven on the second and a client
+    let seed = helpers::gen_seed("rendezvous_only_forwards_ciphertext");
+    let (mut relay_cfgs, client_cfgs) = helpers::gen_network(2, 1, Some(seed)).unwrap();
+    // beacons would go through the rendezvous too, and get tampered with instead
+    relay_cfgs[1].haven_beacon = HavenBeaconConfig {
+        interval_secs: 0,
+        ..Default::default()
+    };
+    let mut relays = helpers::configs_to_daemons(relay_cfgs).unwrap();
+    let alice = helpers::configs_to_daemons(client_cfgs)
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        let bob = relays.pop().unwrap();
+        let rendezvous = relays.pop().unwrap();
+        let rendezvous_ctx = rendezvous.ctx();
+        let tap = rendezvous_tap(&rendezvous_ctx);
+        let bob_haven_id = HavenIdentitySecret::generate();
+        let bob_listener = HavenListener::bind(
+            &bob.ctx(),
+            bob_haven_id,
+            1234,
+            rendezvous.identity().unwrap().public().fingerprint(),
+        )
+        .await
+        .unwrap();
+
+        let secret = b"attack at dawn";
+        let bulky = [b'x'; 5000];
+        let reply = b"at dawn it is";
+        let bob_process = async {
+            let conn = bob_listener.accept().await.unwrap();
+            assert_eq!(&conn.recv_pkt().await.unwrap()[..], secret);
+            assert_eq!(&conn.recv_pkt().await.unwrap()[..], bulky);
+            conn.send_pkt(reply).await.unwrap();
+            // the tampered packet never shows up
+            assert_eq!(&conn.recv_pkt().await.unwrap()[..], b"intact");
+        };
+        let alice_process = async {
+            smol::Timer::after(Duration::from_secs(5)).await;
+            let conn = HavenPacketConn::connect(
+                &alice.ctx(),
+                HavenEndpoint::new(bob_haven_id.public().fingerprint(), 1234),
+            )
+            .await
+            .unwrap();
+            conn.send_pkt(secret).await.unwrap();
+            conn.send_pkt(&bulky).await.unwrap();
+            assert_eq!(&conn.recv_pkt().await.unwrap()[..], reply);
+
+            // all the rendezvous saw was three packets of the same size, none of which it could read
+            let forwarded = tap.forwarded.lock().clone();
+            assert_eq!(forwarded.len(), 3);
+            assert!(forwarded.iter().all(|pkt| pkt.len() == forwarded[0].len()));
+            for pkt in &forwarded {
+                assert!(!pkt.windows(secret.len()).any(|w| w == secret));
+                assert!(!pkt.windows(64).any(|w| w == [b'x'; 64]));
+                assert!(!pkt.windows(reply.len()).any(|w| w == reply));
+            }
+
+            // a packet the rendezvous tampered with is dropped by the haven, which carries on with the rest
+            tap.tamper.store(true, Ordering::SeqCst);
+            conn.send_pkt(b"tampered").await.unwrap();
+            while tap.forwarded.lock().len() == forwarded.len() {
+                smol::Timer::after(Duration::from_millis(50)).await;
+            }
+            tap.tamper.store(false, Ordering::SeqCst);
+            conn.send_pkt(b"intact").await.unwrap();
+            smol::future::pending().await
+        };
+
+        bob_process.race(alice_process).await
+    });
+}
+
+#[test]
+fn unroutable_messages_go_through_the_fallback_rendezvous() {
+    helpers::init_logs();
+
+    // the first relay is the fallback rendezvous of the second, and the only neighbor of the client
+    let seed = helpers::gen_seed("unroutable_messages_go_through_the_fallback_rendezvous");
+    let (mut relay_cfgs, mut client_cfgs) = helpers::gen_network(2, 1, Some(seed)).unwrap();
+    let rendezvous = Daemon::start(relay_cfgs.remove(0)).unwrap();
+    let rendezvous_fp = rendezvous.identity().unwrap().public().fingerprint();
+    let mut dest_cfg = relay_cfgs.remove(0);
+    dest_cfg.fallback_rendezvous = vec![rendezvous_fp];
+    let mut client_cfg = client_cfgs.remove(0);
+    client_cfg.out_routes = dest_cfg.out_routes.clone();
+    // keeping no adjacencies, the client learns of both relays but of no way between them, so in its graph the destination is a component of its own
+    client_cfg.relay_graph_limits = Some(GraphLimits {
+        max_nodes: usize::MAX,
+        max_edges: 0,
+    });
+    // forward routes may pick the destination as their first hop, which the client can't reach either
+    client_cfg.forward_route_retries = 3;
+    let dest = Daemon::start(dest_cfg).unwrap();
+    let client = Daemon::start(client_cfg).unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        let dest_fp = dest.identity().unwrap().public().fingerprint();
+        let graph = client.control_client().graph_stats().await.unwrap();
+        assert_eq!((graph.nodes, graph.edges), (2, 0), "{graph:?}");
+
+        let client_skt = N2rClientSocket::bind(client.ctx(), AnonEndpoint::random()).unwrap();
+        let dest_skt = N2rRelaySocket::bind(dest.ctx(), Some(42)).unwrap();
+        let delivery = async {
+            for _ in 0..10 {
+                client_skt
+                    .send_to(
+                        Bytes::from_static(b"around the partition"),
+                        RelayEndpoint::new(dest_fp, 42),
+                    )
+                    .await
+                    .unwrap();
+                smol::Timer::after(Duration::from_secs(1)).await;
+            }
+            smol::future::pending().await
+        };
+        let (body, src) = dest_skt
+            .recv_from()
+            .race(delivery)
+            .timeout(Duration::from_secs(30))
+            .await
+            .expect("nothing came through the fallback rendezvous")
+            .unwrap();
+        assert_eq!(&body[..], b"around the partition");
+        assert_eq!(src, client_skt.local_endpoint());
+
+        let sent = client.control_client().stats().await.unwrap();
+        assert!(sent.get("n2r.fallback.sent").copied().unwrap_or(0) >= 1);
+        let delivered = dest.control_client().stats().await.unwrap();
+        assert!(
+            delivered
+                .get("n2r.fallback.delivered")
+                .copied()
+                .unwrap_or(0)
+                >= 1
+        );
+    });
+}
+
+#[test]
+fn rendezvous_survives_garbage() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("rendezvous_survives_garbage");
+    let (mut relays, mut clients) = helpers::spawn_network(2, 4, Some(seed)).unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        let bob = relays.pop().unwrap();
+        let bob_haven_id = HavenIdentitySecret::generate();
+        let bob_haven_port = 1234;
+        let rendezvous_daemon = relays.last().unwrap();
+        let rendezvous = rendezvous_daemon.identity().unwrap().public().fingerprint();
+        let bob_listener =
+            HavenListener::bind(&bob.ctx(), bob_haven_id, bob_haven_port, rendezvous)
+                .await
+                .unwrap();
+
+        // anyone can send anything to the rendezvous forward dock
+        let alice = clients.pop().unwrap();
+        let vandal = N2rClientSocket::bind(alice.ctx(), AnonEndpoint::random()).unwrap();
+        let forward_dock = RelayEndpoint::new(rendezvous, 100002);
+        for garbage in [&b""[..], b"\xff\xff\xff\xff\xff\xff\xff\xff", &[0x42; 500]] {
+            vandal
+                .send_to(Bytes::copy_from_slice(garbage), forward_dock)
+                .await
+                .unwrap();
+        }
+        let malformed = || async {
+            rendezvous_daemon
+                .control_client()
+                .stats()
+                .await
+                .unwrap()
+                .get("rendezvous.forward.malformed")
+                .copied()
+                .unwrap_or(0)
+        };
+        for _ in 0..100 {
+            if malformed().await >= 3 {
+                break;
+            }
+            smol::Timer::after(Duration::from_millis(100)).await;
+        }
+        assert_eq!(malformed().await, 3);
+
+        // and the loop still forwards real traffic afterwards
+        let to_alice = b"hey there, allison";
+        let to_bob = b"hello bobert";
+        let bob_process = async {
+            let bob_conn = bob_listener.accept().await.unwrap();
+            bob_conn.send_pkt(to_alice).await.unwrap();
+            let from_alice = bob_conn.recv_pkt().await.unwrap();
+            assert_eq!(to_bob, from_alice.as_ref());
+        };
+        let alice_process = async {
+            let alice_conn = HavenPacketConn::connect(
+                &alice.ctx(),
+                HavenEndpoint::new(bob_haven_id.public().fingerprint(), bob_haven_port),
+            )
+            .await
+            .unwrap();
+            alice_conn.send_pkt(to_bob).await.unwrap();
+            let from_bob = alice_conn.recv_pkt().await.unwrap();
+            assert_eq!(from_bob.as_ref(), to_alice);
+        };
+        bob_process
+            .race(alice_process)
+            .timeout(Duration::from_secs(60))
+            .await
+            .unwrap();
+    });
+}
+
+#[test]
+fn havens_have_their_own_onion_keys() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("havens_have_their_own_onion_keys");
+    let (mut relays, mut clients) = helpers::spawn_network(2, 4, Some(seed)).unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        let bob = relays.pop().unwrap();
+        let rendezvous = relays
+            .last()
+            .unwrap()
+            .identity()
+            .unwrap()
+            .public()
+            .fingerprint();
+        let (first_id, second_id) = (
+            HavenIdentitySecret::generate(),
+            HavenIdentitySecret::generate(),
+        );
+        let first = HavenListener::bind(&bob.ctx(), first_id, 1234, rendezvous)
+            .await
+            .unwrap();
+        let second = HavenListener::bind(&bob.ctx(), second_id, 1234, rendezvous)
+            .await
+            .unwrap();
+        assert_ne!(first.onion_public(), second.onion_public());
+
+        let echo = |listener: HavenListener| async move {
+            loop {
+                let conn = listener.accept().await.unwrap();
+                smolscale::spawn(async move {
+                    let req = conn.recv_pkt().await?;
+                    conn.send_pkt(&req).await?;
+                    conn.recv_pkt().await
+                })
+                .detach();
+            }
+        };
+        let alice = clients.pop().unwrap();
+        let alice_process = async {
+            for (id, msg) in [
+                (first_id, &b"to the first"[..]),
+                (second_id, b"to the second"),
+            ] {
+                let conn = HavenPacketConn::connect_pipelined(
+                    &alice.ctx(),
+                    HavenEndpoint::from_identity(&id.public(), 1234),
+                    msg,
+                )
+                .await
+                .unwrap();
+                assert_eq!(conn.recv_pkt().await.unwrap().as_ref(), msg);
+            }
+        };
+        echo(first)
+            .race(echo(second))
+            .race(alice_process)
+            .timeout(Duration::from_secs(60))
+            .await
+            .unwrap();
+    });
+}
+
+#[test]
+fn haven_pipelined() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("haven_pipelined");
+    let (mut relays, mut clients) = helpers::spawn_network(2, 4, Some(seed)).unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        let bob = relays.pop().unwrap();
+        let bob_haven_id = HavenIdentitySecret::generate();
+        let bob_haven_port = 1234;
+        let rendezvous = relays
+            .last()
+            .unwrap()
+            .identity()
+            .unwrap()
+            .public()
+            .fingerprint();
+        let bob_listener =
+            HavenListener::bind(&bob.ctx(), bob_haven_id, bob_haven_port, rendezvous)
+                .await
+                .unwrap();
+        let bob_ep = HavenEndpoint::new(bob_haven_id.public().fingerprint(), bob_haven_port);
+
+        // bob echoes the first packet on every connection
+        let bob_process = async {
+            loop {
+                let bob_conn = bob_listener.accept().await.unwrap();
+                smolscale::spawn(async move {
+                    let req = bob_conn.recv_pkt().await?;
+                    bob_conn.send_pkt(&req).await?;
+                    // keep the connection open until the visitor is done with it
+                    bob_conn.recv_pkt().await
+                })
+                .detach();
+            }
+        };
+        let alice_process = async {
+            smol::Timer::after(Duration::from_secs(5)).await;
+            let alice = clients.pop().unwrap();
+            // warm up the DHT cache, so that both connections below only count connection setup
+            HavenPacketConn::connect(&alice.ctx(), bob_ep)
+                .await
+                .unwrap();
+            let control = alice.control_client();
+            let count = |stats: &std::collections::BTreeMap<String, u64>, name: &str| {
+                stats.get(name).copied().unwrap_or(0)
+            };
+
+            // connecting and then sending takes a round trip for the handshake and another for the packet
+            let before = control.stats().await.unwrap();
+            let conn = HavenPacketConn::connect(&alice.ctx(), bob_ep)
+                .await
+                .unwrap();
+            conn.send_pkt(b"sequential").await.unwrap();
+            assert_eq!(conn.recv_pkt().await.unwrap().as_ref(), b"sequential");
+            let after = control.stats().await.unwrap();
+            let handshakes =
+                count(&after, "haven.handshakes_sent") - count(&before, "haven.handshakes_sent");
+            let sequential = handshakes + 1;
+
+            // pipelining the packet into the handshake takes only the handshake's
+            let before = after;
+            let conn = HavenPacketConn::connect_pipelined(&alice.ctx(), bob_ep, b"pipelined")
+                .await
+                .unwrap();
+            assert_eq!(conn.recv_pkt().await.unwrap().as_ref(), b"pipelined");
+            let after = control.stats().await.unwrap();
+            assert_eq!(
+                count(&after, "haven.first_pkt_pipelined")
+                    - count(&before, "haven.first_pkt_pipelined"),
+                1
+            );
+            let pipelined =
+                count(&after, "haven.handshakes_sent") - count(&before, "haven.handshakes_sent");
+            assert_eq!(pipelined, 1);
+            assert!(pipelined < sequential);
+        };
+
+        bob_process.race(alice_process).await
+    });
+}
+
+#[test]
+fn ephemeral_haven() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("ephemeral_haven");
+    let (mut relays, mut clients) = helpers::spawn_network(2, 4, Some(seed)).unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        let bob = relays.pop().unwrap();
+        let rendezvous = relays
+            .last()
+            .unwrap()
+            .identity()
+            .unwrap()
+            .public()
+            .fingerprint();
+        let bob_listener = HavenListener::bind_ephemeral(&bob.ctx(), 1234, vec![rendezvous])
+            .await
+            .unwrap();
+        assert!(bob_listener.is_ephemeral());
+        let bob_ep = HavenEndpoint::new(bob_listener.fingerprint(), 1234);
+
+        let bob_process = async {
+            let bob_conn = bob_listener.accept().await.unwrap();
+            let req = bob_conn.recv_pkt().await.unwrap();
+            bob_conn.send_pkt(&req).await.unwrap();
+            // kept open until alice gets the echo back
+            bob_conn
+        };
+        let alice = clients.pop().unwrap();
+        let alice_process = async {
+            smol::Timer::after(Duration::from_secs(5)).await;
+            let conn = HavenPacketConn::connect(&alice.ctx(), bob_ep)
+                .await
+                .unwrap();
+            conn.send_pkt(b"still there?").await.unwrap();
+            assert_eq!(conn.recv_pkt().await.unwrap().as_ref(), b"still there?");
+        };
+        // both sides must finish, so that alice's assert actually runs
+        let (_bob_conn, ()) = bob_process.zip(alice_process).await;
+
+        bob_listener.unbind().await;
+
+        // the tombstone makes visitors fail right away, instead of timing out on a haven that is gone
+        let err = HavenPacketConn::connect(&alice.ctx(), bob_ep)
+            .timeout(Duration::from_secs(10))
+            .await
+            .expect("connecting to a torn down haven should fail fast")
+            .err()
+            .expect("connecting to a torn down haven should fail");
+        assert!(err.to_string().contains("not found"), "{err:?}");
+    });
+}
+
+#[test]
+fn haven_beacon_catches_rendezvous_restart() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("haven_beacon_catches_rendezvous_restart");
+    let (mut relay_cfgs, _) = helpers::gen_network(2, 0, Some(seed)).unwrap();
+    // bob dials the rendezvous, so that he reconnects on his own once it's back
+    relay_cfgs[1].haven_beacon = HavenBeaconConfig {
+        interval_secs: 4,
+        timeout_secs: 10,
+        failures_before_reregister: 2,
+    };
+    let rendezvous_cfg = relay_cfgs[0].clone();
+    let mut relays = helpers::configs_to_daemons(relay_cfgs).unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        let bob = relays.pop().unwrap();
+        let rendezvous = relays.pop().unwrap();
+        let bob_haven_id = HavenIdentitySecret::generate();
+        let _bob_listener = HavenListener::bind(
+            &bob.ctx(),
+            bob_haven_id,
+            1234,
+            rendezvous.identity().unwrap().public().fingerprint(),
+        )
+        .await
+        .unwrap();
+        let control = bob.control_client();
+
+        let status = wait_for_beacon(&control, "through", |s| s.consecutive_failures == 0).await;
+        assert!(status.last_rtt_ms.is_some());
+
+        // the rendezvous forgets every registration when it restarts, without telling anyone
+        rendezvous.stop(Duration::from_secs(1)).await.unwrap();
+        let status = wait_for_beacon(&control, "degraded", |s| s.degraded).await;
+        assert!(status.reregistrations >= 1);
+        assert!(status.last_error.is_some());
+
+        let _rendezvous = Daemon::start(rendezvous_cfg).unwrap();
+        let status = wait_for_beacon(&control, "recovered", |s| {
+            s.consecutive_failures == 0 && !s.degraded
+        })
+        .await;
+        assert!(status.successes >= 2);
+    });
+}
+
+#[test]
+fn roaming_keeps_replies_coming() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("roaming_keeps_replies_coming");
+    let (mut relay_cfgs, mut client_cfgs) = helpers::gen_network(3, 1, Some(seed)).unwrap();
+    // relay0 is the peer, which both relays the client dials connect to
+    relay_cfgs[2].out_routes = [(
+        "relay0".to_string(),
+        helpers::out_route_to(&relay_cfgs[0]).unwrap(),
+    )]
+    .into();
+    let client_cfg = &mut client_cfgs[0];
+    client_cfg.out_routes = [1, 2]
+        .map(|i| {
+            (
+                format!("relay{i}"),
+                helpers::out_route_to(&relay_cfgs[i]).unwrap(),
+            )
+        })
+        .into();
+    client_cfg.liveness = LivenessConfig {
+        probe_interval_ms: 500,
+        probe_timeout_ms: 500,
+        max_failed_probes: Some(2),
+    };
+    client_cfg.roaming_settle_ms = 2000;
+    let mut relays = helpers::configs_to_daemons(relay_cfgs).unwrap();
+    let client = helpers::configs_to_daemons(client_cfgs)
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        let _relay2 = relays.pop().unwrap();
+        let relay_a = relays.pop().unwrap();
+        let peer = relays.pop().unwrap();
+        let peer_skt = N2rRelaySocket::bind(peer.ctx(), None).unwrap();
+        let client_skt = N2rClientSocket::bind(client.ctx(), AnonEndpoint::random()).unwrap();
+
+        // a conversation gets going, anchoring reply blocks at both of the client's relays
+        for i in 0..5u8 {
+            client_skt
+                .send_to(Bytes::from(vec![i; 100]), peer_skt.local_endpoint())
+                .await
+                .unwrap();
+            let (_, ep) = peer_skt
+                .recv_from()
+                .timeout(Duration::from_secs(10))
+                .await
+                .unwrap()
+                .unwrap();
+            peer_skt
+                .send_to(Bytes::from(vec![i; 100]), ep)
+                .await
+                .unwrap();
+            client_skt
+                .recv_from()
+                .timeout(Duration::from_secs(10))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        let relay_a_fp = relay_a.identity().unwrap().public().fingerprint();
+        relay_a.stop(Duration::from_secs(1)).await.unwrap();
+        let events = client
+            .control_client()
+            .roaming_events(0)
+            .timeout(Duration::from_secs(30))
+            .await
+            .expect("the client never roamed away from the dead relay")
+            .unwrap();
+        assert_eq!(events[0].1.from, relay_a_fp);
+        assert!(events[0].1.endpoints.contains(&client_skt.local_endpoint()));
+
+        // the peer keeps reaching us, through the fresh reply blocks anchored at the relay we still have
+        for i in 0..5u8 {
+            let msg = Bytes::from(vec![i; 100]);
+            peer_skt
+                .send_to(msg.clone(), client_skt.local_endpoint())
+                .await
+                .unwrap();
+            let (body, _) = client_skt
+                .recv_from()
+                .timeout(Duration::from_secs(5))
+                .await
+                .expect("the peer lost contact after roaming")
+                .unwrap();
+            assert_eq!(body, msg);
+        }
+        let stats = client.control_client().stats().await.unwrap();
+        assert_eq!(stats.get("n2r.roamed"), Some(&1));
+    });
+}
+
+/// Polls the beacon of the only haven on a daemon until its status satisfies `pred`.
+async fn wait_for_beacon(
+    control: &ControlClient,
+    what: &str,
+    pred: impl Fn(&BeaconStatus) -> bool,
+) -> BeaconStatus {
+    async {
+        loop {
+            let beacons = control.haven_beacons().await.unwrap();
+            if let Some(status) = beacons.into_iter().next().filter(|s| pred(s)) {
+                return status;
+            }
+            smol::Timer::after(Duration::from_millis(500)).await;
+        }
+    }
+    .timeout(Duration::from_secs(90))
+    .await
+    .unwrap_or_else(|| panic!("haven beacon never got {what}"))
+}
+
+#[test]
+fn clients_hear_why_their_relay_drops_them() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("clients_hear_why_their_relay_drops_them");
+    let (mut relay_cfgs, mut client_cfgs) = helpers::gen_network(2, 1, Some(seed)).unwrap();
+    for cfg in relay_cfgs.iter_mut() {
+        cfg.client_rate_limit = Some(ClientRateLimit {
+            per_sec: 1.0,
+            burst: 5,
+        });
+    }
+    client_cfgs[0].drop_reports.request = true;
+    let mut relays = helpers::configs_to_daemons(relay_cfgs).unwrap();
+    let client = helpers::configs_to_daemons(client_cfgs)
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(10).await;
+
+        let relay = relays.pop().unwrap();
+        let relay_skt = N2rRelaySocket::bind(relay.ctx(), None).unwrap();
+        let client_skt = N2rClientSocket::bind(client.ctx(), AnonEndpoint::random()).unwrap();
+        for i in 0..50u8 {
+            let _ = client_skt
+                .send_to(Bytes::from(vec![i; 100]), relay_skt.local_endpoint())
+                .await;
+        }
+
+        let control = client.control_client();
+        let drops = async {
+            loop {
+                let drops = control.observed_drops().await.unwrap();
+                if !drops.is_empty() {
+                    return drops;
+                }
+                smol::Timer::after(Duration::from_millis(500)).await;
+            }
+        }
+        .timeout(Duration::from_secs(30))
+        .await
+        .expect("the client never heard about its drops");
+        assert!(drops
+            .iter()
+            .any(|drop| drop.reason == DropReason::RateLimited));
+        let stats = control.stats().await.unwrap();
+        assert!(stats.get("drop_report.rate_limited").copied().unwrap_or(0) > 0);
+    });
+}
@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use earendil::{measure_throughput, HavenEndpoint, HavenListener, PooledListener};
+use earendil_crypt::HavenIdentitySecret;
+use smol::future::FutureExt as _;
+use smol_timeout::TimeoutExt;
+
+mod helpers;
+
+#[test]
+fn throughput() {
+    helpers::init_logs();
+
+    let seed = helpers::gen_seed("throughput");
+    let (mut relays, mut clients) = helpers::spawn_network(2, 4, Some(seed)).unwrap();
+
+    smolscale::block_on(async move {
+        helpers::sleep(15).await;
+
+        let bob = relays.pop().unwrap();
+        let bob_haven_id = HavenIdentitySecret::generate();
+        let rendezvous = relays
+            .last()
+            .unwrap()
+            .identity()
+            .unwrap()
+            .public()
+            .fingerprint();
+        let bob_listener = PooledListener::with_throughput_sink(
+            HavenListener::bind(&bob.ctx(), bob_haven_id, 1234, rendezvous)
+                .await
+                .unwrap(),
+        );
+        // havens that didn't ask to be measured turn throughput tests away
+        let unmeasured_id = HavenIdentitySecret::generate();
+        let bob_unmeasured = PooledListener::new(
+            HavenListener::bind(&bob.ctx(), unmeasured_id, 1234, rendezvous)
+                .await
+                .unwrap(),
+        );
+        // the test data must never reach the application behind either
+        let bob_process = async {
+            let _conn = bob_listener
+                .accept()
+                .race(bob_unmeasured.accept())
+                .await
+                .unwrap();
+            panic!("the throughput test reached the application");
+        };
+
+        let alice_process = async {
+            helpers::sleep(5).await;
+            let alice = clients.pop().unwrap();
+            let bob_haven = HavenEndpoint::new(bob_haven_id.public().fingerprint(), 1234);
+            let report = measure_throughput(&alice.ctx(), bob_haven, Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert!(report.bytes_delivered > 0, "{report:?}");
+            assert!(report.bytes_per_sec > 0, "{report:?}");
+            assert!(report.elapsed_ms >= 5000, "{report:?}");
+            assert!(report.packets_delivered > 0, "{report:?}");
+            assert!(
+                report.packets_delivered <= report.packets_sent,
+                "{report:?}"
+            );
+            assert!((0.0..1.0).contains(&report.loss), "{report:?}");
+
+            let unmeasured = HavenEndpoint::new(unmeasured_id.public().fingerprint(), 1234);
+            let refused = measure_throughput(&alice.ctx(), unmeasured, Duration::from_secs(1))
+                .timeout(Duration::from_secs(30))
+                .await;
+            assert!(!matches!(refused, Some(Ok(_))), "{refused:?}");
+        };
+
+        bob_process.race(alice_process).await
+    });
+}
@@ -1,8 +1,10 @@
 mod chat;
 mod config;
 mod daemon_wrap;
+mod logs;
 mod modal_state;
 mod refresh_cell;
+mod usage;
 
 use std::{
     cell::Ref,
@@ -21,13 +23,15 @@ use poll_promise::Promise;
 use smol::future::block_on;
 use tap::Tap;
 
-use crate::{app::refresh_cell::RefreshCell, subscriber::LOGS};
+use crate::app::refresh_cell::RefreshCell;
 
 use self::{
     chat::render_chat,
     config::{parse_config_yaml, ConfigState},
     daemon_wrap::DaemonWrap,
+    logs::{render_logs, LogView},
     modal_state::{ModalState, Severity},
+    usage::render_usage,
 };
 
 pub struct App {
@@ -35,6 +39,7 @@ pub struct App {
     daemon_cfg: Arc<Mutex<ConfigState>>,
     selected_tab: TabName,
     modal: Arc<Mutex<Option<ModalState>>>,
+    log_view: LogView,
 
     state: AnyCtx<()>,
 
@@ -75,6 +80,7 @@ impl App {
             daemon_cfg: Arc::new(Mutex::new(ConfigState::load().unwrap_or_default())),
             modal: Default::default(),
             selected_tab: TabName::Dashboard,
+            log_view: LogView::default(),
             last_sync_time: Instant::now(),
             state: AnyCtx::new(()),
         }
@@ -98,7 +104,7 @@ impl eframe::App for App {
             TabName::Dashboard => self.render_dashboard(ctx, ui),
             TabName::Chat => render_chat(self, ctx, ui),
             TabName::Settings => self.render_settings(ctx, ui),
-            TabName::Logs => self.render_logs(ctx, ui),
+            TabName::Logs => render_logs(self, ctx, ui),
         });
 
         // sync if it's been a while since our last sync
@@ -167,6 +173,12 @@ impl App {
                 }
             };
 
+            ui.add_space(20.0);
+            ui.separator();
+            ui.add_space(20.0);
+            ui.heading("Traffic");
+            render_usage(self, ui);
+
             ui.add_space(20.0);
             ui.separator();
             ui.add_space(20.0);
@@ -217,6 +229,14 @@ impl App {
         //         );
         //     });
         // ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Log buffer size (lines)");
+            ui.add(
+                egui::DragValue::new(&mut daemon_cfg.gui_prefs.log_capacity)
+                    .clamp_range(100..=1_000_000),
+            );
+        });
+        ui.separator();
         ui.heading("Earendil config");
         if let Err(err) = daemon_cfg.realize() {
             ui.label(
@@ -290,7 +310,7 @@ impl App {
                     std::env::set_current_dir(config::earendil_config_dir())?;
                     let config_file =
                         parse_config_yaml(&daemon_cfg).context("could not parse config file")?;
-                    let daemon = Daemon::init(config_file).context("cannot start daemon")?;
+                    let daemon = Daemon::start(config_file).context("cannot start daemon")?;
                     smol::future::block_on(daemon.control_client().relay_graphviz())
                         .context("could not get graph dump")?;
                     Ok(DaemonWrap::Embedded(daemon.into()))
@@ -303,21 +323,4 @@ impl App {
             }
         });
     }
-
-    fn render_logs(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
-        ui.heading("Logs");
-        if ui.button("Clear logs").clicked() {
-            LOGS.write().unwrap().clear();
-        }
-
-        let logs = LOGS.read().unwrap();
-        let logs_str = logs.iter().fold(String::new(), |init, x| init + "\n" + x);
-
-        ui.separator();
-        ui.centered_and_justified(|ui| {
-            egui::ScrollArea::vertical()
-                .stick_to_bottom(true)
-                .show(ui, |ui| ui.code_editor(&mut logs_str.as_str()))
-        });
-    }
 }
@@ -6,6 +6,8 @@ use either::Either;
 use serde::{Deserialize, Serialize};
 use tap::Tap;
 
+use crate::subscriber::DEFAULT_LOG_CAPACITY;
+
 #[derive(Default)]
 pub struct ConfigState {
     pub raw_yaml: String,
@@ -18,6 +20,13 @@ pub struct Prefs {
     pub daemon_mode: DaemonMode,
     pub chatting_with: Option<Either<ClientId, RelayFingerprint>>,
     pub chat_msg: String,
+    /// Maximum number of log lines kept in memory for the Logs tab.
+    #[serde(default = "default_log_capacity")]
+    pub log_capacity: usize,
+}
+
+fn default_log_capacity() -> usize {
+    DEFAULT_LOG_CAPACITY
 }
 
 impl Default for Prefs {
@@ -26,6 +35,7 @@ impl Default for Prefs {
             daemon_mode: DaemonMode::Embedded,
             chatting_with: None,
             chat_msg: String::new(),
+            log_capacity: DEFAULT_LOG_CAPACITY,
         }
     }
 }
@@ -86,7 +96,5 @@ impl ConfigState {
 }
 
 pub fn parse_config_yaml(yaml: &str) -> anyhow::Result<ConfigFile> {
-    let json: serde_json::Value = serde_yaml::from_str(yaml)?;
-    let cfg: ConfigFile = serde_json::from_value(json)?;
-    Ok(cfg)
+    ConfigFile::from_yaml(yaml.as_bytes())
 }
@@ -1,6 +1,9 @@
 use std::{net::SocketAddr, sync::Arc};
 
-use earendil::{control_protocol::ControlClient, daemon::Daemon};
+use earendil::{
+    control_protocol::{ControlClient, ControlEncoding},
+    daemon::Daemon,
+};
 use earendil_crypt::RelayFingerprint;
 use either::Either;
 
@@ -14,9 +17,7 @@ impl DaemonWrap {
     /// Obtain the control-protocol handle for this particular kind of daemon.
     pub fn control(&self) -> ControlClient {
         match self {
-            DaemonWrap::Remote(rem) => {
-                ControlClient::from(nanorpc_http::client::HttpRpcTransport::new(*rem))
-            }
+            DaemonWrap::Remote(rem) => ControlClient::connect(*rem, ControlEncoding::Json),
             DaemonWrap::Embedded(emb) => emb.control_client(),
         }
     }
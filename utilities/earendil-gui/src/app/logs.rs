@@ -0,0 +1,129 @@
+use egui::{Color32, RichText};
+use tracing::Level;
+
+use crate::subscriber::{LogLine, LOGS};
+
+use super::App;
+
+/// Session-only state of the Logs tab.
+pub struct LogView {
+    pub max_level: Level,
+    pub filter: String,
+    /// When paused, only lines up to and including this sequence number are shown.
+    pub paused_at: Option<u64>,
+}
+
+impl Default for LogView {
+    fn default() -> Self {
+        Self {
+            max_level: Level::DEBUG,
+            filter: String::new(),
+            paused_at: None,
+        }
+    }
+}
+
+impl LogView {
+    fn shows(&self, line: &LogLine) -> bool {
+        line.level <= self.max_level
+            && self.paused_at.is_none_or(|paused| line.seq <= paused)
+            && (self.filter.is_empty()
+                || line.message.contains(&self.filter)
+                || line.target.contains(&self.filter))
+    }
+}
+
+fn level_color(level: Level) -> Color32 {
+    match level {
+        Level::ERROR => Color32::DARK_RED,
+        Level::WARN => Color32::from_rgb(0xc0, 0x70, 0x00),
+        Level::INFO => Color32::DARK_GREEN,
+        Level::DEBUG => Color32::DARK_BLUE,
+        Level::TRACE => Color32::GRAY,
+    }
+}
+
+pub fn render_logs(app: &mut App, _ctx: &egui::Context, ui: &mut egui::Ui) {
+    let capacity = app.daemon_cfg.lock().gui_prefs.log_capacity;
+    LOGS.write().unwrap().set_capacity(capacity);
+
+    let view = &mut app.log_view;
+    ui.heading("Logs");
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_label("Level")
+            .selected_text(view.max_level.as_str())
+            .show_ui(ui, |ui| {
+                for level in [
+                    Level::ERROR,
+                    Level::WARN,
+                    Level::INFO,
+                    Level::DEBUG,
+                    Level::TRACE,
+                ] {
+                    ui.selectable_value(&mut view.max_level, level, level.as_str());
+                }
+            });
+        ui.label("Filter");
+        ui.text_edit_singleline(&mut view.filter);
+
+        let pause_label = if view.paused_at.is_some() {
+            "Resume"
+        } else {
+            "Pause"
+        };
+        if ui.button(pause_label).clicked() {
+            view.paused_at = match view.paused_at {
+                Some(_) => None,
+                None => Some(LOGS.read().unwrap().iter().last().map_or(0, |l| l.seq)),
+            };
+        }
+        if ui.button("Clear logs").clicked() {
+            LOGS.write().unwrap().clear();
+        }
+    });
+
+    // filter while holding the lock, but render only the rows that are actually on screen
+    let visible: Vec<LogLine> = LOGS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|line| view.shows(line))
+        .cloned()
+        .collect();
+
+    ui.horizontal(|ui| {
+        ui.label(format!("{} lines shown", visible.len()));
+        if ui.button("Copy visible").clicked() {
+            let text = visible
+                .iter()
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            ui.output_mut(|o| o.copied_text = text);
+        }
+    });
+    ui.separator();
+
+    let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+    egui::ScrollArea::both()
+        .stick_to_bottom(view.paused_at.is_none())
+        .auto_shrink([false, false])
+        .show_rows(ui, row_height, visible.len(), |ui, rows| {
+            for line in &visible[rows] {
+                ui.horizontal(|ui| {
+                    ui.label(
+                        RichText::new(format!("{:<5}", line.level.as_str()))
+                            .monospace()
+                            .color(level_color(line.level)),
+                    );
+                    ui.label(
+                        RichText::new(format!(
+                            "{} {} {}",
+                            line.timestamp, line.target, line.message
+                        ))
+                        .monospace(),
+                    );
+                });
+            }
+        });
+}
@@ -0,0 +1,112 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyctx::AnyCtx;
+use earendil::control_protocol::UsagePeriod;
+use egui::{mutex::Mutex, Color32, Rect, Sense};
+use smol::block_on;
+
+use crate::app::refresh_cell::RefreshCell;
+
+use super::App;
+
+/// How far back the dashboard plots traffic.
+const USAGE_WINDOW: Duration = Duration::from_secs(2 * 86400);
+
+const USAGE_HOUR_SECS: u64 = 3600;
+
+const TRANSIT_COLOR: Color32 = Color32::from_rgb(0x33, 0x66, 0xcc);
+const TERMINATED_COLOR: Color32 = Color32::from_rgb(0xdd, 0x99, 0x33);
+
+pub fn render_usage(app: &App, ui: &mut egui::Ui) {
+    let Some(Ok(daemon)) = app.daemon.as_ref().and_then(|d| d.ready()) else {
+        return;
+    };
+    static USAGE: fn(&AnyCtx<()>) -> Mutex<RefreshCell<anyhow::Result<Vec<UsagePeriod>>>> =
+        |_| Mutex::new(RefreshCell::new());
+    let control = daemon.control();
+    let mut usage = app.state.get(USAGE).lock();
+    // rollups only happen every few seconds, so there's no point asking more often
+    let usage = usage.get_or_refresh(Duration::from_secs(10), || {
+        block_on(async move {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let usage = control
+                .usage_history(
+                    now.saturating_sub(USAGE_WINDOW.as_secs()),
+                    now,
+                    USAGE_HOUR_SECS,
+                )
+                .await??;
+            Ok(usage)
+        })
+    });
+    match usage {
+        None => {
+            ui.label("Loading...");
+        }
+        Some(Err(err)) => {
+            ui.colored_label(Color32::DARK_RED, "Loading usage failed:");
+            ui.label(format!("{:?}", err));
+        }
+        Some(Ok(usage)) if usage.is_empty() => {
+            ui.label("No traffic recorded yet.");
+        }
+        Some(Ok(usage)) => {
+            let sum = |f: fn(&UsagePeriod) -> u64| usage.iter().map(f).sum::<u64>();
+            ui.horizontal(|ui| {
+                ui.colored_label(
+                    TRANSIT_COLOR,
+                    format!("{} bytes forwarded", sum(|u| u.transit_bytes)),
+                );
+                ui.colored_label(
+                    TERMINATED_COLOR,
+                    format!("{} bytes terminated", sum(|u| u.terminated_bytes)),
+                );
+            });
+            let latest = usage.last().expect("usage isn't empty");
+            ui.label(format!(
+                "{} relay and {} client peers in the last hour, {} settlements of {} micromel in the last two days",
+                latest.relay_peers,
+                latest.client_peers,
+                sum(|u| u.settlements),
+                sum(|u| u.settled)
+            ));
+            plot_traffic(ui, usage);
+        }
+    }
+}
+
+/// Draws hourly traffic as stacked bars, forwarded below terminated.
+fn plot_traffic(ui: &mut egui::Ui, usage: &[UsagePeriod]) {
+    let (response, painter) =
+        ui.allocate_painter(egui::vec2(ui.available_width(), 120.0), Sense::hover());
+    let rect = response.rect;
+    let first = usage.first().map_or(0, |u| u.start);
+    let last = usage.last().map_or(0, |u| u.start);
+    let hours = ((last - first) / USAGE_HOUR_SECS + 1) as f32;
+    let most = usage
+        .iter()
+        .map(|u| u.transit_bytes + u.terminated_bytes)
+        .max()
+        .unwrap_or(0)
+        .max(1) as f32;
+    let width = rect.width() / hours;
+    for u in usage {
+        let left = rect.left() + ((u.start - first) / USAGE_HOUR_SECS) as f32 * width;
+        let right = left + (width - 1.0).max(1.0);
+        let transit = u.transit_bytes as f32 / most * rect.height();
+        let terminated = u.terminated_bytes as f32 / most * rect.height();
+        painter.rect_filled(
+            Rect::from_x_y_ranges(left..=right, rect.bottom() - transit..=rect.bottom()),
+            0.0,
+            TRANSIT_COLOR,
+        );
+        painter.rect_filled(
+            Rect::from_x_y_ranges(
+                left..=right,
+                rect.bottom() - transit - terminated..=rect.bottom() - transit,
+            ),
+            0.0,
+            TERMINATED_COLOR,
+        );
+    }
+}
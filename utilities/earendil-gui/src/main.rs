@@ -18,7 +18,7 @@ fn main() -> eframe::Result<()> {
                 .from_env_lossy(),
         )
         //
-        .with(VecLayer)
+        .with(VecLayer::new(LOGS.clone()))
         .init();
 
     let native_options = eframe::NativeOptions {
@@ -1,11 +1,115 @@
-use std::{collections::VecDeque, fmt, fmt::Write, sync::RwLock};
+use std::{
+    collections::VecDeque,
+    fmt,
+    fmt::Write,
+    sync::{Arc, RwLock},
+};
 
 use once_cell::sync::Lazy;
-use tracing::field::{Field, Visit};
+use tracing::{
+    field::{Field, Visit},
+    Level,
+};
 use tracing_subscriber::Layer;
 
-pub static LOGS: Lazy<RwLock<VecDeque<String>>> = Lazy::new(|| RwLock::new(VecDeque::new()));
-pub struct VecLayer;
+/// Default number of log lines kept in memory.
+pub const DEFAULT_LOG_CAPACITY: usize = 5000;
+
+pub static LOGS: Lazy<Arc<RwLock<LogRing>>> =
+    Lazy::new(|| Arc::new(RwLock::new(LogRing::new(DEFAULT_LOG_CAPACITY))));
+
+/// A single captured log line.
+#[derive(Clone, Debug)]
+pub struct LogLine {
+    /// Monotonically increasing sequence number. Never reused, even across evictions and clears.
+    pub seq: u64,
+    pub timestamp: String,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {}, {}, {}",
+            self.timestamp, self.level, self.target, self.message
+        )
+    }
+}
+
+/// A bounded, in-memory ring buffer of log lines. The oldest lines are evicted once the capacity is reached.
+pub struct LogRing {
+    lines: VecDeque<LogLine>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+impl LogRing {
+    /// Creates an empty ring buffer holding at most `capacity` lines.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            capacity: capacity.max(1),
+            next_seq: 0,
+        }
+    }
+
+    /// Appends a line, evicting the oldest ones if needed. Returns the sequence number assigned to the line.
+    pub fn push(
+        &mut self,
+        timestamp: String,
+        level: Level,
+        target: String,
+        message: String,
+    ) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.lines.push_back(LogLine {
+            seq,
+            timestamp,
+            level,
+            target,
+            message,
+        });
+        self.evict();
+        seq
+    }
+
+    /// Iterates through all retained lines, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &LogLine> + '_ {
+        self.lines.iter()
+    }
+
+    /// Changes the capacity, evicting the oldest lines if the buffer is now too big.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.evict();
+    }
+
+    /// Drops all retained lines. Sequence numbers keep counting up from where they were.
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    fn evict(&mut self) {
+        while self.lines.len() > self.capacity {
+            self.lines.pop_front();
+        }
+    }
+}
+
+/// A tracing layer that captures every event into a [LogRing].
+pub struct VecLayer {
+    ring: Arc<RwLock<LogRing>>,
+}
+
+impl VecLayer {
+    pub fn new(ring: Arc<RwLock<LogRing>>) -> Self {
+        Self { ring }
+    }
+}
 
 pub struct StringVisitor<'a> {
     string: &'a mut String,
@@ -27,20 +131,76 @@ where
         _ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
         let timestamp = chrono::Local::now().to_string();
-        let level = event.metadata().level().as_str();
-        let target = event.metadata().target();
+        let level = *event.metadata().level();
+        let target = event.metadata().target().to_string();
 
+        let mut message = String::new();
         let mut visitor = StringVisitor {
-            string: &mut String::new(),
+            string: &mut message,
         };
         event.record(&mut visitor);
-        let message = visitor.string;
 
-        let log = format!("{timestamp}, {level}, {target}, {message}");
-        let mut logs_vec = LOGS.write().unwrap();
-        if logs_vec.len() > 2000 {
-            logs_vec.pop_front();
+        self.ring
+            .write()
+            .unwrap()
+            .push(timestamp, level, target, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    fn push_n(ring: &mut LogRing, n: usize) {
+        for i in 0..n {
+            ring.push(
+                String::new(),
+                Level::INFO,
+                "test".into(),
+                format!("line {i}"),
+            );
         }
-        logs_vec.push_back(log);
+    }
+
+    #[test]
+    fn ring_evicts_oldest_and_keeps_seq() {
+        let mut ring = LogRing::new(3);
+        push_n(&mut ring, 5);
+        let seqs: Vec<u64> = ring.iter().map(|l| l.seq).collect();
+        assert_eq!(seqs, vec![2, 3, 4]);
+        assert_eq!(ring.iter().next().unwrap().message, "line 2");
+
+        // sequence numbers continue after a clear
+        ring.clear();
+        push_n(&mut ring, 1);
+        assert_eq!(ring.iter().next().unwrap().seq, 5);
+    }
+
+    #[test]
+    fn ring_shrinks_on_capacity_change() {
+        let mut ring = LogRing::new(10);
+        push_n(&mut ring, 10);
+        ring.set_capacity(4);
+        let seqs: Vec<u64> = ring.iter().map(|l| l.seq).collect();
+        assert_eq!(seqs, vec![6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn layer_captures_events() {
+        let ring = Arc::new(RwLock::new(LogRing::new(2)));
+        let subscriber = tracing_subscriber::registry().with(VecLayer::new(ring.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("first");
+            tracing::warn!(x = 1, "second");
+            tracing::error!("third");
+        });
+        let ring = ring.read().unwrap();
+        let lines: Vec<&LogLine> = ring.iter().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!((lines[0].seq, lines[0].level), (1, Level::WARN));
+        assert!(lines[0].message.contains("x = 1"));
+        assert_eq!((lines[1].seq, lines[1].level), (2, Level::ERROR));
     }
 }